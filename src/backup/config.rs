@@ -0,0 +1,71 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+
+use kvproto::encryptionpb::EncryptionMethod;
+
+use util::config::ReadableSize;
+use super::encryption::parse_method;
+
+pub const MAX_CONCURRENCY: usize = 32;
+const DEFAULT_CONCURRENCY: usize = 4;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The max number of regions backed up at the same time.
+    pub concurrency: usize,
+    /// The max bytes scanned per second by backups, 0 means no limit.
+    pub rate_limit: ReadableSize,
+    /// The method to encrypt the backup files, `plaintext` means no encryption.
+    pub encryption_method: String,
+    /// The file of the hex encoded master key, which encrypts the keys of the
+    /// backup files.
+    pub encryption_key_path: String,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            concurrency: DEFAULT_CONCURRENCY,
+            rate_limit: ReadableSize(0),
+            encryption_method: "plaintext".to_owned(),
+            encryption_key_path: "".to_owned(),
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), Box<Error>> {
+        try!(validate_concurrency(self.concurrency));
+        let method = try!(parse_method(&self.encryption_method));
+        if method != EncryptionMethod::PLAINTEXT && self.encryption_key_path.is_empty() {
+            return Err("backup.encryption-key-path should be set to encrypt backups".into());
+        }
+        Ok(())
+    }
+}
+
+pub fn validate_concurrency(concurrency: usize) -> Result<(), Box<Error>> {
+    if concurrency == 0 || concurrency > MAX_CONCURRENCY {
+        return Err(format!(
+            "backup.concurrency should be in [1, {}], got {}",
+            MAX_CONCURRENCY,
+            concurrency
+        ).into());
+    }
+    Ok(())
+}
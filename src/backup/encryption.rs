@@ -0,0 +1,191 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::File;
+use std::io::{self, Read};
+
+use crypto::aes::{self, KeySize};
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use kvproto::encryptionpb::EncryptionMethod;
+use rand::{OsRng, Rng};
+use rustc_serialize::hex::FromHex;
+
+use super::Result;
+use super::config::Config;
+
+const IV_LEN: usize = 16;
+
+/// Parses the name of an encryption method in the config.
+pub fn parse_method(name: &str) -> ::std::result::Result<EncryptionMethod, String> {
+    match name {
+        "plaintext" => Ok(EncryptionMethod::PLAINTEXT),
+        "aes128-ctr" => Ok(EncryptionMethod::AES128_CTR),
+        "aes192-ctr" => Ok(EncryptionMethod::AES192_CTR),
+        "aes256-ctr" => Ok(EncryptionMethod::AES256_CTR),
+        _ => Err(format!("unknown encryption method {}", name)),
+    }
+}
+
+fn key_size(method: EncryptionMethod) -> Option<(KeySize, usize)> {
+    match method {
+        EncryptionMethod::AES128_CTR => Some((KeySize::KeySize128, 16)),
+        EncryptionMethod::AES192_CTR => Some((KeySize::KeySize192, 24)),
+        EncryptionMethod::AES256_CTR => Some((KeySize::KeySize256, 32)),
+        _ => None,
+    }
+}
+
+fn new_cipher(method: EncryptionMethod, key: &[u8], iv: &[u8]) -> Box<SynchronousStreamCipher> {
+    let (size, _) = key_size(method).unwrap();
+    aes::ctr(size, key, iv)
+}
+
+/// The key of a backup file. It's generated for each file and encrypted by the
+/// master key, the encrypted key and the iv are recorded in the meta of the
+/// file, so the file can be decrypted with the master key only.
+pub struct DataKey {
+    pub method: EncryptionMethod,
+    pub key: Vec<u8>,
+    pub encrypted_key: Vec<u8>,
+    pub iv: Vec<u8>,
+}
+
+/// `MasterKey` encrypts the keys of the backup files. It's read from the hex
+/// encoded file `encryption-key-path` in the config.
+pub struct MasterKey {
+    method: EncryptionMethod,
+    key: Vec<u8>,
+}
+
+impl MasterKey {
+    pub fn new(method: EncryptionMethod, key: Vec<u8>) -> Result<MasterKey> {
+        match key_size(method) {
+            Some((_, len)) if len == key.len() => {}
+            Some((_, len)) => {
+                return Err(box_err!(
+                    "key of {:?} should be {} bytes, got {}",
+                    method,
+                    len,
+                    key.len()
+                ))
+            }
+            None => return Err(box_err!("{:?} can't be used to encrypt", method)),
+        }
+        Ok(MasterKey {
+            method: method,
+            key: key,
+        })
+    }
+
+    /// Loads the master key from the config, returns `None` if backups are not
+    /// encrypted.
+    pub fn from_config(cfg: &Config) -> Result<Option<MasterKey>> {
+        let method = box_try!(parse_method(&cfg.encryption_method));
+        if method == EncryptionMethod::PLAINTEXT {
+            return Ok(None);
+        }
+        let mut content = String::new();
+        let mut f = try!(File::open(&cfg.encryption_key_path));
+        try!(f.read_to_string(&mut content));
+        let key = box_try!(content.trim().from_hex());
+        MasterKey::new(method, key).map(Some)
+    }
+
+    /// Generates a new data key for a file.
+    pub fn new_data_key(&self) -> Result<DataKey> {
+        let mut rng = try!(OsRng::new());
+        let mut key = vec![0; self.key.len()];
+        rng.fill_bytes(&mut key);
+        let mut iv = vec![0; IV_LEN];
+        rng.fill_bytes(&mut iv);
+        let mut encrypted_key = vec![0; key.len()];
+        new_cipher(self.method, &self.key, &iv).process(&key, &mut encrypted_key);
+        Ok(DataKey {
+            method: self.method,
+            key: key,
+            encrypted_key: encrypted_key,
+            iv: iv,
+        })
+    }
+
+    /// Decrypts the data key recorded in the meta of a file.
+    pub fn decrypt_data_key(&self, encrypted_key: &[u8], iv: &[u8]) -> Vec<u8> {
+        let mut key = vec![0; encrypted_key.len()];
+        new_cipher(self.method, &self.key, iv).process(encrypted_key, &mut key);
+        key
+    }
+}
+
+/// `CrypterReader` encrypts or decrypts the content of `reader` with the data
+/// key as it's read. Both are the same in the CTR mode.
+pub struct CrypterReader<R> {
+    reader: R,
+    cipher: Box<SynchronousStreamCipher>,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> CrypterReader<R> {
+    pub fn new(reader: R, data_key: &DataKey) -> CrypterReader<R> {
+        CrypterReader {
+            reader: reader,
+            cipher: new_cipher(data_key.method, &data_key.key, &data_key.iv),
+            buf: vec![],
+        }
+    }
+}
+
+impl<R: Read> Read for CrypterReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.buf.resize(buf.len(), 0);
+        let n = try!(self.reader.read(&mut self.buf));
+        self.cipher.process(&self.buf[..n], &mut buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        assert!(MasterKey::new(EncryptionMethod::AES128_CTR, vec![1; 32]).is_err());
+        assert!(MasterKey::new(EncryptionMethod::PLAINTEXT, vec![]).is_err());
+
+        let master_key = MasterKey::new(EncryptionMethod::AES256_CTR, vec![1; 32]).unwrap();
+        let data_key = master_key.new_data_key().unwrap();
+        assert_eq!(data_key.key.len(), 32);
+        assert_ne!(data_key.key, data_key.encrypted_key);
+        let key = master_key.decrypt_data_key(&data_key.encrypted_key, &data_key.iv);
+        assert_eq!(key, data_key.key);
+
+        let content: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut encrypted = vec![];
+        CrypterReader::new(content.as_slice(), &data_key)
+            .read_to_end(&mut encrypted)
+            .unwrap();
+        assert_eq!(encrypted.len(), content.len());
+        assert_ne!(encrypted, content);
+        let data_key = DataKey {
+            method: EncryptionMethod::AES256_CTR,
+            key: key,
+            encrypted_key: vec![],
+            iv: data_key.iv,
+        };
+        let mut decrypted = vec![];
+        CrypterReader::new(encrypted.as_slice(), &data_key)
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, content);
+    }
+}
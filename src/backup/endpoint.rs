@@ -0,0 +1,512 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp;
+use std::sync::Arc;
+
+use futures::{future, Future};
+use futures_cpupool::{Builder, CpuPool};
+use kvproto::backup::{BackupRequest, BackupResponse, File};
+use kvproto::kvrpcpb::{Context, IsolationLevel};
+use kvproto::metapb::{Peer, Region};
+use kvproto::raft_serverpb::{PeerState, RegionLocalState, StoreIdent};
+use protobuf::{self, RepeatedField};
+use rocksdb::DB;
+use tempdir::TempDir;
+
+use external_storage::{create_storage, ExternalStorage};
+use raftstore::store::keys;
+use raftstore::store::engine::{Iterable, IterOption, Peekable};
+use storage::{CFStatistics, CfName, Engine, EngineError, Key, ScanMode, Snapshot, Statistics,
+              CF_DEFAULT, CF_RAFT, DATA_CFS};
+use storage::mvcc::{Error as MvccError, LockType, MvccReader, WriteType};
+use util::escape;
+use super::{Error, Result};
+use super::config::MAX_CONCURRENCY;
+use super::encryption::MasterKey;
+use super::limiter::Limiter;
+use super::writer::{BackupWriter, RawBackupWriter};
+
+const SCAN_KEYS_BATCH_SIZE: usize = 1024;
+
+// What to back up in a range.
+#[derive(Clone, Copy)]
+enum BackupKind {
+    // The versions of transactional keys visible at `ts`, or the changes
+    // committed in `(start_ts, ts]` if `start_ts` is not 0.
+    Txn { start_ts: u64, ts: u64 },
+    // All the raw keys in the column family.
+    Raw { cf: CfName },
+}
+
+/// `Endpoint` backs up the regions on this store.
+pub struct Endpoint {
+    db: Arc<DB>,
+    engine: Box<Engine>,
+    // Regions are backed up on the pool, the number of which running at the
+    // same time is bounded by the limiter.
+    pool: CpuPool,
+    limiter: Arc<Limiter>,
+    master_key: Option<Arc<MasterKey>>,
+}
+
+impl Clone for Endpoint {
+    fn clone(&self) -> Endpoint {
+        Endpoint {
+            db: self.db.clone(),
+            engine: self.engine.clone(),
+            pool: self.pool.clone(),
+            limiter: self.limiter.clone(),
+            master_key: self.master_key.clone(),
+        }
+    }
+}
+
+impl Endpoint {
+    /// Creates an endpoint. `db` is the kv engine, it's used to find the
+    /// regions on this store, while the data is read from `engine`. The backup
+    /// files are encrypted if `master_key` is set.
+    pub fn new(
+        db: Arc<DB>,
+        engine: Box<Engine>,
+        limiter: Arc<Limiter>,
+        master_key: Option<MasterKey>,
+    ) -> Endpoint {
+        let pool = Builder::new()
+            .name_prefix(thd_name!("backup-region"))
+            .pool_size(MAX_CONCURRENCY)
+            .create();
+        Endpoint {
+            db: db,
+            engine: engine,
+            pool: pool,
+            limiter: limiter,
+            master_key: master_key.map(Arc::new),
+        }
+    }
+
+    pub fn get_limiter(&self) -> Arc<Limiter> {
+        self.limiter.clone()
+    }
+
+    /// Backs up the data in `[start_key, end_key)` of the request at `end_version`
+    /// into the external storage `path`, see `external_storage` for the forms of
+    /// storage urls. There is one response for each region led by this store that
+    /// overlaps the range. Regions led by other stores are skipped, the
+    /// coordinator is expected to find the missing ranges and retry them.
+    ///
+    /// If `start_version` is not 0, it's an incremental backup, which contains all
+    /// the puts and deletes committed in `(start_version, end_version]`.
+    ///
+    /// If `is_raw_kv` is set, the keys of the range are raw keys, and all of them
+    /// in the column family `cf`, or `default` if it's empty, are backed up
+    /// regardless of the versions.
+    ///
+    /// Each file has the checksum of its pairs. If `checksum_only` is set, nothing
+    /// is written, and the files in the responses only have the checksums, which
+    /// can be compared with the ones of a backup to verify it.
+    pub fn backup(&self, req: &BackupRequest) -> Vec<BackupResponse> {
+        match self.backup_regions(req) {
+            Ok(resps) => resps,
+            Err(e) => {
+                error!(
+                    "backup [{}, {}) failed: {:?}",
+                    escape(req.get_start_key()),
+                    escape(req.get_end_key()),
+                    e
+                );
+                let mut resp = BackupResponse::new();
+                resp.set_start_key(req.get_start_key().to_vec());
+                resp.set_end_key(req.get_end_key().to_vec());
+                resp.set_error(e.into());
+                vec![resp]
+            }
+        }
+    }
+
+    fn backup_regions(&self, req: &BackupRequest) -> Result<Vec<BackupResponse>> {
+        let store_id = match box_try!(self.db.get_msg::<StoreIdent>(keys::STORE_IDENT_KEY)) {
+            Some(ident) => ident.get_store_id(),
+            None => return Err(box_err!("store is not bootstrapped")),
+        };
+        let kind = if req.get_is_raw_kv() {
+            BackupKind::Raw {
+                cf: try!(get_raw_cf(req.get_cf())),
+            }
+        } else {
+            let (start_ts, end_ts) = (req.get_start_version(), req.get_end_version());
+            if start_ts > end_ts {
+                return Err(box_err!(
+                    "start version {} is greater than end version {}",
+                    start_ts,
+                    end_ts
+                ));
+            }
+            BackupKind::Txn {
+                start_ts: start_ts,
+                ts: end_ts,
+            }
+        };
+        // Regions of raw keys are split by the raw keys directly.
+        let (start, end) = if req.get_is_raw_kv() {
+            (req.get_start_key().to_vec(), req.get_end_key().to_vec())
+        } else {
+            (encode_key(req.get_start_key()), encode_key(req.get_end_key()))
+        };
+        let storage = if req.get_checksum_only() {
+            None
+        } else {
+            Some(Arc::new(try!(create_storage(req.get_path()))))
+        };
+
+        let mut tasks = vec![];
+        for (region, peer) in try!(self.get_local_regions(store_id, &start, &end)) {
+            let range_start = cmp::max(start.as_slice(), region.get_start_key()).to_vec();
+            let range_end = match (end.is_empty(), region.get_end_key().is_empty()) {
+                (true, _) => region.get_end_key(),
+                (_, true) => end.as_slice(),
+                _ => cmp::min(end.as_slice(), region.get_end_key()),
+            }.to_vec();
+            let mut resp = BackupResponse::new();
+            if req.get_is_raw_kv() {
+                resp.set_start_key(range_start.clone());
+                resp.set_end_key(range_end.clone());
+            } else {
+                resp.set_start_key(try!(decode_key(&range_start)));
+                resp.set_end_key(try!(decode_key(&range_end)));
+            }
+
+            let endpoint = self.clone();
+            let storage = storage.clone();
+            tasks.push(self.pool.spawn_fn(move || {
+                let _token = endpoint.limiter.acquire();
+                let res = endpoint.backup_region(
+                    store_id,
+                    &region,
+                    peer,
+                    &range_start,
+                    &range_end,
+                    kind,
+                    storage.as_ref().map(|s| s.as_ref().as_ref()),
+                );
+                Ok::<_, ()>((region.get_id(), resp, res))
+            }));
+        }
+
+        let mut resps = vec![];
+        for (region_id, mut resp, res) in future::join_all(tasks).wait().unwrap() {
+            match res {
+                Ok(files) => resp.set_files(RepeatedField::from_vec(files)),
+                Err(Error::Engine(EngineError::Request(ref e))) if e.has_not_leader() => continue,
+                Err(e) => {
+                    warn!("backup region {} failed: {:?}", region_id, e);
+                    resp.set_error(e.into());
+                }
+            }
+            resps.push(resp);
+        }
+        Ok(resps)
+    }
+
+    // Gets the normal regions overlapping `[start, end)` which have a peer on
+    // this store. Both keys are encoded, an empty `end` means no upper bound.
+    fn get_local_regions(
+        &self,
+        store_id: u64,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<Vec<(Region, Peer)>> {
+        let mut regions = vec![];
+        box_try!(self.db.scan_cf(
+            CF_RAFT,
+            keys::REGION_META_MIN_KEY,
+            keys::REGION_META_MAX_KEY,
+            false,
+            &mut |key, value| {
+                let (_, suffix) = try!(keys::decode_region_meta_key(key));
+                if suffix != keys::REGION_STATE_SUFFIX {
+                    return Ok(true);
+                }
+                let mut state = try!(protobuf::parse_from_bytes::<RegionLocalState>(value));
+                if state.get_state() != PeerState::Normal {
+                    return Ok(true);
+                }
+                let region = state.take_region();
+                let overlapped = (end.is_empty() || region.get_start_key() < end) &&
+                    (region.get_end_key().is_empty() || start < region.get_end_key());
+                if !overlapped {
+                    return Ok(true);
+                }
+                let peer = region
+                    .get_peers()
+                    .iter()
+                    .find(|p| p.get_store_id() == store_id)
+                    .cloned();
+                if let Some(peer) = peer {
+                    regions.push((region, peer));
+                }
+                Ok(true)
+            },
+        ));
+        Ok(regions)
+    }
+
+    fn backup_region(
+        &self,
+        store_id: u64,
+        region: &Region,
+        peer: Peer,
+        start: &[u8],
+        end: &[u8],
+        kind: BackupKind,
+        storage: Option<&ExternalStorage>,
+    ) -> Result<Vec<File>> {
+        let mut ctx = Context::new();
+        ctx.set_region_id(region.get_id());
+        ctx.set_region_epoch(region.get_region_epoch().clone());
+        ctx.set_peer(peer);
+        let snapshot = try!(self.engine.snapshot(&ctx));
+        let name = format!(
+            "{}_{}_{}",
+            store_id,
+            region.get_id(),
+            region.get_region_epoch().get_version()
+        );
+        let dir = try!(TempDir::new("tikv-backup"));
+        match kind {
+            BackupKind::Txn { start_ts, ts } => {
+                let mut writer = BackupWriter::new(dir.path(), &name);
+                if storage.is_none() {
+                    writer = writer.checksum_only();
+                } else if let Some(ref master_key) = self.master_key {
+                    writer = writer.encrypted(master_key.clone());
+                }
+                try!(self.backup_txn(
+                    snapshot.as_ref(),
+                    &mut writer,
+                    start,
+                    end,
+                    start_ts,
+                    ts
+                ));
+                match storage {
+                    Some(storage) => writer.finish(storage),
+                    None => Ok(writer.checksums()),
+                }
+            }
+            BackupKind::Raw { cf } => {
+                let mut writer = RawBackupWriter::new(dir.path(), &name, cf);
+                if storage.is_none() {
+                    writer = writer.checksum_only();
+                } else if let Some(ref master_key) = self.master_key {
+                    writer = writer.encrypted(master_key.clone());
+                }
+                try!(self.backup_raw(snapshot.as_ref(), &mut writer, start, end, cf));
+                match storage {
+                    Some(storage) => writer.finish(storage),
+                    None => Ok(writer.checksums()),
+                }
+            }
+        }
+    }
+
+    fn backup_txn(
+        &self,
+        snapshot: &Snapshot,
+        writer: &mut BackupWriter,
+        start: &[u8],
+        end: &[u8],
+        start_ts: u64,
+        ts: u64,
+    ) -> Result<()> {
+        let mut statistics = Statistics::default();
+        let mut reader = MvccReader::new(
+            snapshot,
+            &mut statistics,
+            Some(ScanMode::Forward),
+            false,
+            None,
+            IsolationLevel::SI,
+        );
+        let in_range = |key: &Key| end.is_empty() || key.encoded().as_slice() < end;
+
+        // A transaction holding a lock before `ts` may be committed before `ts`,
+        // so the range can't be backed up until the lock is resolved.
+        let start_key = Key::from_encoded(start.to_vec());
+        let (locks, _) = try!(reader.scan_lock(
+            Some(start_key.clone()),
+            |lock| lock.ts <= ts && lock.lock_type != LockType::Lock,
+            Some(1),
+        ));
+        if let Some((key, lock)) = locks.into_iter().next() {
+            if in_range(&key) {
+                return Err(Error::Mvcc(MvccError::KeyIsLocked {
+                    key: try!(key.raw()),
+                    primary: lock.primary,
+                    ts: lock.ts,
+                    ttl: lock.ttl,
+                }));
+            }
+        }
+
+        let mut next_start = Some(start_key);
+        while next_start.is_some() {
+            let (keys, next) = try!(reader.scan_keys(next_start, SCAN_KEYS_BATCH_SIZE));
+            next_start = next;
+            let mut scanned_bytes = 0;
+            for key in keys {
+                if !in_range(&key) {
+                    next_start = None;
+                    break;
+                }
+                scanned_bytes += if start_ts == 0 {
+                    try!(backup_key(&mut reader, writer, &key, ts))
+                } else {
+                    try!(backup_key_incremental(
+                        &mut reader,
+                        writer,
+                        &key,
+                        start_ts,
+                        ts
+                    ))
+                };
+            }
+            self.limiter.consume(scanned_bytes);
+        }
+        Ok(())
+    }
+
+    fn backup_raw(
+        &self,
+        snapshot: &Snapshot,
+        writer: &mut RawBackupWriter,
+        start: &[u8],
+        end: &[u8],
+        cf: CfName,
+    ) -> Result<()> {
+        let mut statistics = CFStatistics::default();
+        let mut cursor = try!(snapshot.iter_cf(cf, IterOption::default(), ScanMode::Forward));
+        let mut valid = try!(cursor.seek(&Key::from_encoded(start.to_vec()), &mut statistics));
+        let (mut scanned_keys, mut scanned_bytes) = (0, 0);
+        while valid {
+            if !end.is_empty() && cursor.key() >= end {
+                break;
+            }
+            let data_key = keys::data_key(cursor.key());
+            try!(writer.put(&data_key, cursor.value()));
+            scanned_keys += 1;
+            scanned_bytes += data_key.len() + cursor.value().len();
+            if scanned_keys % SCAN_KEYS_BATCH_SIZE == 0 {
+                self.limiter.consume(scanned_bytes);
+                scanned_bytes = 0;
+            }
+            valid = cursor.next(&mut statistics);
+        }
+        self.limiter.consume(scanned_bytes);
+        Ok(())
+    }
+}
+
+// Writes the latest version of `key` committed before `ts`, if it's not deleted.
+// Returns the number of bytes written.
+fn backup_key(
+    reader: &mut MvccReader,
+    writer: &mut BackupWriter,
+    key: &Key,
+    mut ts: u64,
+) -> Result<usize> {
+    while let Some((commit_ts, write)) = try!(reader.seek_write(key, ts)) {
+        match write.write_type {
+            WriteType::Put => {
+                let mut bytes = 0;
+                if write.short_value.is_none() {
+                    let value = try!(reader.load_data(key, write.start_ts));
+                    let data_key = keys::data_key(key.append_ts(write.start_ts).encoded());
+                    try!(writer.put_default(&data_key, &value));
+                    bytes += data_key.len() + value.len();
+                }
+                let data_key = keys::data_key(key.append_ts(commit_ts).encoded());
+                let value = write.to_bytes();
+                try!(writer.put_write(&data_key, &value));
+                return Ok(bytes + data_key.len() + value.len());
+            }
+            WriteType::Delete => return Ok(0),
+            WriteType::Lock | WriteType::Rollback => ts = commit_ts - 1,
+        }
+    }
+    Ok(0)
+}
+
+// Writes all the puts and deletes of `key` committed in `(start_ts, ts]`. Returns
+// the number of bytes written.
+fn backup_key_incremental(
+    reader: &mut MvccReader,
+    writer: &mut BackupWriter,
+    key: &Key,
+    start_ts: u64,
+    mut ts: u64,
+) -> Result<usize> {
+    let mut bytes = 0;
+    // Writes are visited in the descending order of commit_ts, which is also the
+    // order of their data keys, but the order of start_ts may be different.
+    let mut defaults = vec![];
+    while let Some((commit_ts, write)) = try!(reader.seek_write(key, ts)) {
+        if commit_ts <= start_ts {
+            break;
+        }
+        ts = commit_ts - 1;
+        match write.write_type {
+            WriteType::Put => if write.short_value.is_none() {
+                let value = try!(reader.load_data(key, write.start_ts));
+                let data_key = keys::data_key(key.append_ts(write.start_ts).encoded());
+                defaults.push((data_key, value));
+            },
+            WriteType::Delete => {}
+            WriteType::Lock | WriteType::Rollback => continue,
+        }
+        let data_key = keys::data_key(key.append_ts(commit_ts).encoded());
+        let value = write.to_bytes();
+        try!(writer.put_write(&data_key, &value));
+        bytes += data_key.len() + value.len();
+    }
+    defaults.sort();
+    for (data_key, value) in defaults {
+        try!(writer.put_default(&data_key, &value));
+        bytes += data_key.len() + value.len();
+    }
+    Ok(bytes)
+}
+
+fn encode_key(raw: &[u8]) -> Vec<u8> {
+    if raw.is_empty() {
+        return vec![];
+    }
+    Key::from_raw(raw).encoded().clone()
+}
+
+fn decode_key(encoded: &[u8]) -> Result<Vec<u8>> {
+    if encoded.is_empty() {
+        return Ok(vec![]);
+    }
+    Key::from_encoded(encoded.to_vec()).raw().map_err(Error::from)
+}
+
+fn get_raw_cf(cf: &str) -> Result<CfName> {
+    if cf.is_empty() {
+        return Ok(CF_DEFAULT);
+    }
+    match DATA_CFS.iter().find(|c| **c == cf) {
+        Some(c) => Ok(*c),
+        None => Err(box_err!("unsupported cf {}", cf)),
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use util::config::ReadableSize;
+use util::dynamic_config::ConfigHandler;
+use util::io_metrics::{self, IOOp, IOType};
+use super::Result;
+use super::config::{validate_concurrency, Config};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// The number of running backups and the max number allowed.
+struct Concurrency {
+    running: usize,
+    limit: usize,
+}
+
+/// `Limiter` bounds the resources taken by backups, so that they don't slow
+/// down foreground requests too much. Both the scan rate and the number of
+/// regions backed up at the same time can be changed while backups are running.
+pub struct Limiter {
+    // Bytes per second, 0 means no limit.
+    rate_limit: AtomicUsize,
+    // The time when the bytes consumed so far are paid off.
+    next_free: Mutex<Instant>,
+    concurrency: Mutex<Concurrency>,
+    cond: Condvar,
+}
+
+/// Holds a slot of the concurrency limit, which is released on drop.
+pub struct Token<'a> {
+    limiter: &'a Limiter,
+}
+
+impl<'a> Drop for Token<'a> {
+    fn drop(&mut self) {
+        let mut concurrency = self.limiter.concurrency.lock().unwrap();
+        concurrency.running -= 1;
+        self.limiter.cond.notify_one();
+    }
+}
+
+impl Limiter {
+    pub fn new(cfg: &Config) -> Limiter {
+        Limiter {
+            rate_limit: AtomicUsize::new(cfg.rate_limit.0 as usize),
+            next_free: Mutex::new(Instant::now()),
+            concurrency: Mutex::new(Concurrency {
+                running: 0,
+                limit: cfg.concurrency,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    pub fn rate_limit(&self) -> u64 {
+        self.rate_limit.load(Ordering::Relaxed) as u64
+    }
+
+    pub fn set_rate_limit(&self, bytes_per_sec: u64) {
+        info!("backup rate limit is changed to {} bytes/s", bytes_per_sec);
+        self.rate_limit.store(bytes_per_sec as usize, Ordering::Relaxed);
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.lock().unwrap().limit
+    }
+
+    pub fn set_concurrency(&self, limit: usize) -> Result<()> {
+        if let Err(e) = validate_concurrency(limit) {
+            return Err(box_err!("{}", e));
+        }
+        info!("backup concurrency is changed to {}", limit);
+        self.concurrency.lock().unwrap().limit = limit;
+        self.cond.notify_all();
+        Ok(())
+    }
+
+    /// Changes a limit by its name in the config, which is used to adjust the
+    /// limits of a running TiKV.
+    pub fn update(&self, name: &str, value: &str) -> Result<()> {
+        match name {
+            "rate-limit" => match ReadableSize::from_str(value) {
+                Ok(size) => {
+                    self.set_rate_limit(size.0);
+                    Ok(())
+                }
+                Err(e) => Err(box_err!(e)),
+            },
+            "concurrency" => match value.parse() {
+                Ok(limit) => self.set_concurrency(limit),
+                Err(e) => Err(box_err!("invalid concurrency {:?}: {:?}", value, e)),
+            },
+            _ => Err(box_err!("unknown backup config {:?}", name)),
+        }
+    }
+
+    /// Blocks until the number of running backups is under the limit.
+    pub fn acquire(&self) -> Token {
+        let mut concurrency = self.concurrency.lock().unwrap();
+        while concurrency.running >= concurrency.limit {
+            concurrency = self.cond.wait(concurrency).unwrap();
+        }
+        concurrency.running += 1;
+        Token { limiter: self }
+    }
+
+    /// Records that `bytes` are scanned, and blocks until the bytes scanned
+    /// before are paid off by the rate limit.
+    pub fn consume(&self, bytes: usize) {
+        io_metrics::record_io(IOType::Backup, IOOp::Read, bytes as u64);
+        let rate = self.rate_limit();
+        if rate == 0 || bytes == 0 {
+            return;
+        }
+        let nanos = bytes as u64 * NANOS_PER_SEC / rate;
+        let cost = Duration::new(nanos / NANOS_PER_SEC, (nanos % NANOS_PER_SEC) as u32);
+        let wait = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let now = Instant::now();
+            if *next_free < now {
+                *next_free = now;
+            }
+            let wait = *next_free - now;
+            *next_free += cost;
+            wait
+        };
+        if wait > Duration::from_millis(0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+impl ConfigHandler for Limiter {
+    fn update(&self, name: &str, value: &str) -> ::std::result::Result<(), String> {
+        Limiter::update(self, name, value).map_err(|e| format!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use super::super::config::Config;
+
+    #[test]
+    fn test_concurrency() {
+        let cfg = Config {
+            concurrency: 1,
+            ..Default::default()
+        };
+        let limiter = Arc::new(Limiter::new(&cfg));
+        let token = limiter.acquire();
+
+        let (tx, rx) = mpsc::channel();
+        let l = limiter.clone();
+        let h = thread::spawn(move || {
+            let _token = l.acquire();
+            tx.send(()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        // Raising the limit wakes up the waiting backup.
+        limiter.set_concurrency(2).unwrap();
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        h.join().unwrap();
+        drop(token);
+        assert_eq!(limiter.concurrency.lock().unwrap().running, 0);
+
+        assert!(limiter.set_concurrency(0).is_err());
+        assert_eq!(limiter.concurrency(), 2);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = Limiter::new(&Config::default());
+        let start = Instant::now();
+        limiter.consume(1024 * 1024);
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        limiter.set_rate_limit(1024 * 1024);
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.consume(100 * 1024);
+        }
+        // The first batch is free, the following two cost about 100ms each.
+        assert!(start.elapsed() >= Duration::from_millis(180));
+    }
+
+    #[test]
+    fn test_update() {
+        let limiter = Limiter::new(&Config::default());
+        limiter.update("rate-limit", "10MB").unwrap();
+        assert_eq!(limiter.rate_limit(), 10 * 1024 * 1024);
+        limiter.update("concurrency", "8").unwrap();
+        assert_eq!(limiter.concurrency(), 8);
+
+        assert!(limiter.update("rate-limit", "10XB").is_err());
+        assert!(limiter.update("concurrency", "-1").is_err());
+        assert!(limiter.update("concurrency", "100").is_err());
+        assert!(limiter.update("threads", "1").is_err());
+    }
+}
@@ -0,0 +1,37 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backup scans the committed data of the regions led by this store at a
+//! given timestamp and writes it into SST files. Raw keys have no version, so
+//! they are backed up as they are. Backups are driven by an external
+//! coordinator, which splits the key space into ranges, sends them to all
+//! stores and retries the ranges that failed.
+//!
+//! If a master key is configured, each backup file is encrypted by a key of its
+//! own, which is encrypted by the master key and recorded in the file's meta.
+
+mod errors;
+mod config;
+mod limiter;
+mod encryption;
+mod writer;
+mod endpoint;
+mod service;
+
+pub use self::errors::{Error, Result};
+pub use self::config::Config;
+pub use self::encryption::{CrypterReader, DataKey, MasterKey};
+pub use self::limiter::Limiter;
+pub use self::endpoint::Endpoint;
+pub use self::service::Service;
+pub use self::writer::{BackupWriter, RawBackupWriter};
@@ -0,0 +1,68 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use grpc::{Error as GrpcError, RpcContext, ServerStreamingSink, WriteFlags};
+use futures::{stream, Future, Sink};
+use futures_cpupool::{Builder, CpuPool};
+use kvproto::backup::{BackupRequest, BackupResponse};
+use kvproto::backup_grpc;
+
+use super::Endpoint;
+
+/// Service handles the RPC messages for the `Backup` service.
+///
+/// Backups scan whole regions, so they run on a dedicated thread pool.
+#[derive(Clone)]
+pub struct Service {
+    pool: CpuPool,
+    endpoint: Endpoint,
+}
+
+impl Service {
+    pub fn new(endpoint: Endpoint) -> Service {
+        let pool = Builder::new()
+            .name_prefix(thd_name!("backup"))
+            .pool_size(1)
+            .create();
+        Service {
+            pool: pool,
+            endpoint: endpoint,
+        }
+    }
+}
+
+impl backup_grpc::Backup for Service {
+    fn backup(
+        &self,
+        ctx: RpcContext,
+        req: BackupRequest,
+        sink: ServerStreamingSink<BackupResponse>,
+    ) {
+        const TAG: &'static str = "backup";
+
+        let endpoint = self.endpoint.clone();
+        let f = self.pool.spawn_fn(move || {
+            let resps: Vec<_> = endpoint
+                .backup(&req)
+                .into_iter()
+                .map(|resp| Ok((resp, WriteFlags::default())))
+                .collect();
+            Ok::<_, GrpcError>(resps)
+        });
+        let future = f.and_then(move |resps| {
+            sink.send_all(stream::iter::<_, _, GrpcError>(resps))
+                .map(|_| ())
+        }).map_err(move |e| error!("{} failed: {:?}", TAG, e));
+        ctx.spawn(future);
+    }
+}
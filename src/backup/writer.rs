@@ -0,0 +1,318 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crc::crc64::{self, Digest, Hasher64};
+use kvproto::backup::File;
+use rocksdb::{ColumnFamilyOptions, DBCompressionType, EnvOptions, SstFileWriter};
+
+use external_storage::ExternalStorage;
+use storage::{CfName, CF_DEFAULT, CF_WRITE};
+use util::file::{calc_crc32, get_file_size};
+use util::io_metrics::{IORecorder, IOType};
+use util::rocksdb::get_fastest_supported_compression_type;
+use super::Result;
+use super::encryption::{CrypterReader, MasterKey};
+
+// Writes the key-value pairs of a column family into a local SST file, which is
+// uploaded to the external storage when finished. The file is created on the
+// first pair, so no empty file is left behind. The checksum of the pairs is the
+// xor of the crc64 of each pair, so it doesn't depend on how they're split.
+struct CfWriter {
+    cf: CfName,
+    name: String,
+    path: PathBuf,
+    writer: Option<SstFileWriter>,
+    // Only the checksum is computed if it's set.
+    checksum_only: bool,
+    // The file is encrypted before being uploaded if it's set.
+    master_key: Option<Arc<MasterKey>>,
+    total_kvs: u64,
+    total_bytes: u64,
+    crc64xor: u64,
+}
+
+impl CfWriter {
+    fn new(dir: &Path, name: &str, cf: CfName) -> CfWriter {
+        let name = format!("{}_{}.sst", name, cf);
+        CfWriter {
+            cf: cf,
+            path: dir.join(&name),
+            name: name,
+            writer: None,
+            checksum_only: false,
+            master_key: None,
+            total_kvs: 0,
+            total_bytes: 0,
+            crc64xor: 0,
+        }
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut digest = Digest::new(crc64::ECMA);
+        digest.write(key);
+        digest.write(value);
+        self.crc64xor ^= digest.sum64();
+        self.total_kvs += 1;
+        self.total_bytes += (key.len() + value.len()) as u64;
+        if self.checksum_only {
+            return Ok(());
+        }
+
+        if self.writer.is_none() {
+            let mut opts = ColumnFamilyOptions::new();
+            opts.compression(get_fastest_supported_compression_type());
+            // Make sure the specified compression type is used, see `Snap::init_for_building`.
+            opts.compression_per_level(&[]);
+            opts.bottommost_compression(DBCompressionType::Disable);
+            let mut writer = SstFileWriter::new(EnvOptions::new(), opts);
+            box_try!(writer.open(self.path.to_str().unwrap()));
+            self.writer = Some(writer);
+        }
+        box_try!(self.writer.as_mut().unwrap().add(key, value));
+        Ok(())
+    }
+
+    // Returns the checksum of the pairs put so far, or `None` if there is none.
+    fn checksum(&self) -> Option<File> {
+        if self.total_kvs == 0 {
+            return None;
+        }
+        let mut file = File::new();
+        file.set_cf(self.cf.to_owned());
+        file.set_total_kvs(self.total_kvs);
+        file.set_total_bytes(self.total_bytes);
+        file.set_crc64xor(self.crc64xor);
+        Some(file)
+    }
+
+    fn finish(&mut self, storage: &ExternalStorage) -> Result<Option<File>> {
+        let mut writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => return Ok(None),
+        };
+        box_try!(writer.finish());
+
+        let mut file = self.checksum().unwrap();
+        file.set_name(self.name.clone());
+        file.set_size(try!(get_file_size(&self.path)));
+        file.set_crc32(try!(calc_crc32(&self.path)));
+        let mut f = IORecorder::new(try!(fs::File::open(&self.path)), IOType::Backup);
+        match self.master_key {
+            Some(ref master_key) => {
+                let data_key = try!(master_key.new_data_key());
+                file.set_encryption_method(data_key.method);
+                file.set_data_key(data_key.encrypted_key.clone());
+                file.set_cipher_iv(data_key.iv.clone());
+                try!(storage.write(&self.name, &mut CrypterReader::new(f, &data_key)));
+            }
+            None => try!(storage.write(&self.name, &mut f)),
+        }
+        Ok(Some(file))
+    }
+}
+
+/// `BackupWriter` writes the backup of a range into a pair of SST files, one
+/// for `CF_WRITE` and one for `CF_DEFAULT`. Keys must be put in ascending
+/// order within each column family. The files are built in a local directory
+/// before being uploaded.
+pub struct BackupWriter {
+    write: CfWriter,
+    default: CfWriter,
+}
+
+impl BackupWriter {
+    /// Creates a writer whose files are named `{name}_{cf}.sst` under `dir`.
+    pub fn new(dir: &Path, name: &str) -> BackupWriter {
+        BackupWriter {
+            write: CfWriter::new(dir, name, CF_WRITE),
+            default: CfWriter::new(dir, name, CF_DEFAULT),
+        }
+    }
+
+    /// Makes the writer compute the checksums only, no file is written.
+    pub fn checksum_only(mut self) -> BackupWriter {
+        self.write.checksum_only = true;
+        self.default.checksum_only = true;
+        self
+    }
+
+    /// Makes the writer encrypt the files with keys encrypted by `master_key`.
+    pub fn encrypted(mut self, master_key: Arc<MasterKey>) -> BackupWriter {
+        self.write.master_key = Some(master_key.clone());
+        self.default.master_key = Some(master_key);
+        self
+    }
+
+    pub fn put_write(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write.put(key, value)
+    }
+
+    pub fn put_default(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.default.put(key, value)
+    }
+
+    /// Finishes the SST files, uploads them to `storage` and returns their meta.
+    /// Column families without any data have no file.
+    pub fn finish(mut self, storage: &ExternalStorage) -> Result<Vec<File>> {
+        let mut files = vec![];
+        for cf_writer in &mut [&mut self.write, &mut self.default] {
+            if let Some(file) = try!(cf_writer.finish(storage)) {
+                files.push(file);
+            }
+        }
+        Ok(files)
+    }
+
+    /// Returns the checksums of the column families with data, whose files have
+    /// no name.
+    pub fn checksums(&self) -> Vec<File> {
+        self.write
+            .checksum()
+            .into_iter()
+            .chain(self.default.checksum())
+            .collect()
+    }
+}
+
+/// `RawBackupWriter` writes the backup of a range of raw keys into a single SST
+/// file of the column family. Raw keys have no version, so the pairs are
+/// written as they are in ascending order.
+pub struct RawBackupWriter {
+    writer: CfWriter,
+}
+
+impl RawBackupWriter {
+    /// Creates a writer whose file is named `{name}_{cf}.sst` under `dir`.
+    pub fn new(dir: &Path, name: &str, cf: CfName) -> RawBackupWriter {
+        RawBackupWriter {
+            writer: CfWriter::new(dir, name, cf),
+        }
+    }
+
+    /// Makes the writer compute the checksum only, no file is written.
+    pub fn checksum_only(mut self) -> RawBackupWriter {
+        self.writer.checksum_only = true;
+        self
+    }
+
+    /// Makes the writer encrypt the file with a key encrypted by `master_key`.
+    pub fn encrypted(mut self, master_key: Arc<MasterKey>) -> RawBackupWriter {
+        self.writer.master_key = Some(master_key);
+        self
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.writer.put(key, value)
+    }
+
+    /// Finishes the SST file, uploads it to `storage` and returns its meta.
+    pub fn finish(mut self, storage: &ExternalStorage) -> Result<Vec<File>> {
+        let file = try!(self.writer.finish(storage));
+        Ok(file.into_iter().collect())
+    }
+
+    /// Returns the checksum of the column family if it has data.
+    pub fn checksums(&self) -> Vec<File> {
+        self.writer.checksum().into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use kvproto::encryptionpb::EncryptionMethod;
+    use tempdir::TempDir;
+
+    use external_storage::LocalStorage;
+    use super::*;
+    use super::super::encryption::DataKey;
+
+    #[test]
+    fn test_checksum() {
+        let dir = TempDir::new("test_backup_checksum").unwrap();
+        let storage = LocalStorage::new(&dir.path().join("storage")).unwrap();
+        let kvs: Vec<_> = (0..10)
+            .map(|i| (format!("zk{}", i).into_bytes(), vec![b'v'; i]))
+            .collect();
+
+        let mut writer = BackupWriter::new(dir.path(), "all");
+        let mut checksum_writer = BackupWriter::new(dir.path(), "none").checksum_only();
+        for &(ref k, ref v) in &kvs {
+            writer.put_default(k, v).unwrap();
+            checksum_writer.put_default(k, v).unwrap();
+        }
+        let checksums = checksum_writer.checksums();
+        let files = writer.finish(&storage).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(checksums.len(), 1);
+        assert!(checksums[0].get_name().is_empty());
+        assert_eq!(checksums[0].get_total_kvs(), 10);
+        assert_eq!(checksums[0].get_total_kvs(), files[0].get_total_kvs());
+        assert_eq!(checksums[0].get_total_bytes(), files[0].get_total_bytes());
+        assert_eq!(checksums[0].get_crc64xor(), files[0].get_crc64xor());
+        assert!(!dir.path().join("none_default.sst").exists());
+
+        // The checksum doesn't depend on how the pairs are split.
+        let (mut w1, mut w2) = (
+            RawBackupWriter::new(dir.path(), "w1", CF_DEFAULT).checksum_only(),
+            RawBackupWriter::new(dir.path(), "w2", CF_DEFAULT).checksum_only(),
+        );
+        for (i, &(ref k, ref v)) in kvs.iter().enumerate() {
+            let w = if i % 2 == 0 { &mut w1 } else { &mut w2 };
+            w.put(k, v).unwrap();
+        }
+        let crc64xor = w1.checksums()[0].get_crc64xor() ^ w2.checksums()[0].get_crc64xor();
+        assert_eq!(crc64xor, files[0].get_crc64xor());
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let dir = TempDir::new("test_backup_encrypted").unwrap();
+        let storage_dir = dir.path().join("storage");
+        let storage = LocalStorage::new(&storage_dir).unwrap();
+        let master_key = MasterKey::new(EncryptionMethod::AES128_CTR, vec![7; 16]).unwrap();
+        let mut writer =
+            RawBackupWriter::new(dir.path(), "raw", CF_DEFAULT).encrypted(Arc::new(master_key));
+        writer.put(b"za", b"v").unwrap();
+        let files = writer.finish(&storage).unwrap();
+        assert_eq!(files.len(), 1);
+        let file = &files[0];
+        assert_eq!(file.get_encryption_method(), EncryptionMethod::AES128_CTR);
+
+        let mut plain = vec![];
+        let mut f = fs::File::open(dir.path().join(file.get_name())).unwrap();
+        f.read_to_end(&mut plain).unwrap();
+        let mut encrypted = vec![];
+        let mut f = fs::File::open(storage_dir.join(file.get_name())).unwrap();
+        f.read_to_end(&mut encrypted).unwrap();
+        assert_ne!(plain, encrypted);
+
+        let master_key = MasterKey::new(EncryptionMethod::AES128_CTR, vec![7; 16]).unwrap();
+        let data_key = DataKey {
+            method: file.get_encryption_method(),
+            key: master_key.decrypt_data_key(file.get_data_key(), file.get_cipher_iv()),
+            encrypted_key: file.get_data_key().to_vec(),
+            iv: file.get_cipher_iv().to_vec(),
+        };
+        let mut decrypted = vec![];
+        CrypterReader::new(encrypted.as_slice(), &data_key)
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plain);
+    }
+}
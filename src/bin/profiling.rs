@@ -65,6 +65,19 @@ mod imp {
         }
     }
 
+    /// Activate the profiling and dump the heap profile to `path`.
+    pub fn dump_heap_profile(path: &str) -> Result<(), String> {
+        unsafe {
+            try!(
+                jemallocator::mallctl_set(PROFILE_ACTIVE, true)
+                    .map_err(|e| format!("failed to activate profiling: {}", e))
+            );
+        }
+        let mut c_path = DumpPathGuard::from_cstring(Some(CString::new(path).unwrap()));
+        unsafe { jemallocator::mallctl_set(PROFILE_DUMP, c_path.get_mut_ptr()) }
+            .map_err(|e| format!("failed to dump the profile to {}: {}", path, e))
+    }
+
     #[cfg(test)]
     mod test {
         use std::fs;
@@ -93,6 +106,10 @@ mod imp {
 #[cfg(not(feature = "mem-profiling"))]
 mod imp {
     pub fn dump_prof(_: Option<&str>) {}
+
+    pub fn dump_heap_profile(_: &str) -> Result<(), String> {
+        Err("heap profiling is not supported, build with feature mem-profiling".to_owned())
+    }
 }
 
 pub use self::imp::*;
@@ -15,17 +15,13 @@
 #[cfg(unix)]
 mod imp {
     use std::{ptr, slice};
-    use std::sync::Arc;
     use libc::{self, c_char, c_void};
 
-    use rocksdb::DB;
-    use prometheus::{self, Encoder, TextEncoder};
     use profiling;
 
     use tikv::raftstore::store::Engines;
-
-    const ROCKSDB_DB_STATS_KEY: &'static str = "rocksdb.dbstats";
-    const ROCKSDB_CF_STATS_KEY: &'static str = "rocksdb.cfstats";
+    use tikv::util::metrics;
+    use tikv::util::rocksdb as rocksdb_util;
 
     extern "C" {
         #[cfg_attr(target_os = "macos", link_name = "je_malloc_stats_print")]
@@ -70,14 +66,9 @@ mod imp {
                 }
                 SIGUSR1 => {
                     // Use SIGUSR1 to log metrics.
-                    let mut buffer = vec![];
-                    let metric_familys = prometheus::gather();
-                    let encoder = TextEncoder::new();
-                    encoder.encode(&metric_familys, &mut buffer).unwrap();
-                    info!("{}", String::from_utf8(buffer).unwrap());
-
-                    print_rocksdb_stats(&engines.kv_engine);
-                    print_rocksdb_stats(&engines.raft_engine);
+                    info!("{}", metrics::dump());
+                    info!("{}", rocksdb_util::dump_stats(&engines.kv_engine));
+                    info!("{}", rocksdb_util::dump_stats(&engines.raft_engine));
                     print_malloc_stats();
                 }
                 SIGUSR2 => profiling::dump_prof(None),
@@ -87,25 +78,6 @@ mod imp {
         }
     }
 
-    fn print_rocksdb_stats(engine: &Arc<DB>) {
-        // Log common rocksdb stats.
-        for name in engine.cf_names() {
-            let handler = engine.cf_handle(name).unwrap();
-            if let Some(v) = engine.get_property_value_cf(handler, ROCKSDB_CF_STATS_KEY) {
-                info!("{}", v)
-            }
-        }
-
-        if let Some(v) = engine.get_property_value(ROCKSDB_DB_STATS_KEY) {
-            info!("{}", v)
-        }
-
-        // Log more stats if enable_statistics is true.
-        if let Some(v) = engine.get_statistics() {
-            info!("{}", v)
-        }
-    }
-
     #[cfg(test)]
     mod tests {
         #[test]
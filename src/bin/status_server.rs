@@ -0,0 +1,509 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+use hyper::{self, Get, Post};
+use hyper::net::Fresh;
+use hyper::server::{Handler, Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use tempdir::TempDir;
+
+use kvproto::raft_serverpb::{RaftApplyState, RegionLocalState};
+use serde_json::{self, Map, Value};
+use tikv::raft::StateRole;
+use tikv::raftstore::coprocessor::RoleObserver;
+use tikv::raftstore::store::Engines;
+use tikv::raftstore::store::util as store_util;
+use tikv::server::debug::{Debugger, Error as DebugError};
+use tikv::storage::hot_keys;
+use tikv::util::{self, metrics};
+use tikv::util::dynamic_config::ConfigManager;
+
+use profiling;
+
+const STATUS_SERVER_THREADS: usize = 2;
+const HEAP_PROFILE_PATH: &'static str = "/debug/pprof/heap";
+const METRICS_PATH: &'static str = "/metrics";
+const HOT_KEYS_PATH: &'static str = "/debug/hot_keys";
+const DEFAULT_HOT_KEYS_LIMIT: usize = 20;
+const REGIONS_PATH: &'static str = "/regions";
+const REGION_PATH_PREFIX: &'static str = "/region/";
+const CONFIG_PATH: &'static str = "/config";
+
+type HandleResult = Result<Vec<u8>, (StatusCode, String)>;
+
+fn role_name(role: StateRole) -> &'static str {
+    match role {
+        StateRole::Leader => "leader",
+        StateRole::Follower => "follower",
+        StateRole::Candidate => "candidate",
+        StateRole::PreCandidate => "pre_candidate",
+    }
+}
+
+/// `RegionMetaReader` reads the metadata of the regions on this store from the
+/// engines directly, and their roles from raftstore.
+pub struct RegionMetaReader {
+    debugger: Debugger,
+    roles: RoleObserver,
+}
+
+impl RegionMetaReader {
+    pub fn new(engines: Engines, roles: RoleObserver) -> RegionMetaReader {
+        RegionMetaReader {
+            debugger: Debugger::new(engines),
+            roles: roles,
+        }
+    }
+
+    fn region_to_json(
+        &self,
+        region_id: u64,
+        state: &RegionLocalState,
+        apply_state: Option<&RaftApplyState>,
+    ) -> Value {
+        let region = state.get_region();
+        let mut m = Map::new();
+        m.insert("id".to_owned(), Value::from(region_id));
+        m.insert(
+            "start_key".to_owned(),
+            Value::String(util::escape(region.get_start_key())),
+        );
+        m.insert(
+            "end_key".to_owned(),
+            Value::String(util::escape(region.get_end_key())),
+        );
+        let mut epoch = Map::new();
+        epoch.insert(
+            "conf_ver".to_owned(),
+            Value::from(region.get_region_epoch().get_conf_ver()),
+        );
+        epoch.insert(
+            "version".to_owned(),
+            Value::from(region.get_region_epoch().get_version()),
+        );
+        m.insert("epoch".to_owned(), Value::Object(epoch));
+        let peers = region
+            .get_peers()
+            .iter()
+            .map(|p| {
+                let mut peer = Map::new();
+                peer.insert("id".to_owned(), Value::from(p.get_id()));
+                peer.insert("store_id".to_owned(), Value::from(p.get_store_id()));
+                Value::Object(peer)
+            })
+            .collect();
+        m.insert("peers".to_owned(), Value::Array(peers));
+        m.insert(
+            "state".to_owned(),
+            Value::String(format!("{:?}", state.get_state())),
+        );
+        m.insert(
+            "role".to_owned(),
+            Value::String(role_name(self.roles.get_role(region_id)).to_owned()),
+        );
+        let apply_index = apply_state.map_or(Value::Null, |s| Value::from(s.get_applied_index()));
+        m.insert("apply_index".to_owned(), apply_index);
+        // The size is estimated by the properties of SST files.
+        let kv_engine = &self.debugger.get_engine().kv_engine;
+        let size = store_util::get_region_approximate_size(kv_engine, region)
+            .map(Value::from)
+            .unwrap_or(Value::Null);
+        m.insert("approximate_size".to_owned(), size);
+        Value::Object(m)
+    }
+
+    fn dump_regions(&self) -> HandleResult {
+        let states = try!(
+            self.debugger
+                .get_all_region_states()
+                .map_err(|e| (StatusCode::InternalServerError, format!("{:?}", e)))
+        );
+        let mut regions = Vec::with_capacity(states.len());
+        for (region_id, state) in states {
+            let apply_state = match self.debugger.region_info(region_id) {
+                Ok(info) => info.raft_apply_state,
+                Err(_) => None,
+            };
+            regions.push(self.region_to_json(region_id, &state, apply_state.as_ref()));
+        }
+        Ok(serde_json::to_vec(&Value::Array(regions)).unwrap())
+    }
+
+    fn dump_region(&self, id: &str) -> HandleResult {
+        let region_id = try!(
+            id.parse::<u64>()
+                .map_err(|e| (StatusCode::BadRequest, format!("invalid region id {}: {}", id, e)))
+        );
+        let info = match self.debugger.region_info(region_id) {
+            Ok(info) => info,
+            Err(DebugError::NotFound(msg)) => return Err((StatusCode::NotFound, msg)),
+            Err(e) => return Err((StatusCode::InternalServerError, format!("{:?}", e))),
+        };
+        match info.region_local_state {
+            Some(ref state) => {
+                let v = self.region_to_json(region_id, state, info.raft_apply_state.as_ref());
+                Ok(serde_json::to_vec(&v).unwrap())
+            }
+            None => Err((
+                StatusCode::NotFound,
+                format!("region state of {}", region_id),
+            )),
+        }
+    }
+}
+
+struct StatusHandler {
+    // jemalloc can only dump one profile at a time.
+    dump_lock: Mutex<()>,
+    // The region endpoints are not served without it.
+    regions: Option<RegionMetaReader>,
+    // The config endpoint is not served without it.
+    config_manager: Option<Arc<ConfigManager>>,
+}
+
+impl StatusHandler {
+    fn dump_heap_profile(&self) -> Result<Vec<u8>, String> {
+        let _guard = self.dump_lock.lock().unwrap();
+        let dir = try!(TempDir::new("tikv_heap_profile").map_err(|e| e.to_string()));
+        let path = dir.path().join("heap.prof");
+        try!(profiling::dump_heap_profile(path.to_str().unwrap()));
+        let mut buf = vec![];
+        try!(
+            File::open(&path)
+                .and_then(|mut f| f.read_to_end(&mut buf))
+                .map_err(|e| format!("failed to read the profile: {}", e))
+        );
+        Ok(buf)
+    }
+}
+
+fn dump_config_changes(config_manager: &ConfigManager) -> HandleResult {
+    Ok(serde_json::to_vec(&config_manager.changes()).unwrap())
+}
+
+/// Applies the change in `body`, which is like
+/// `{"module": "storage", "name": "gc-ratio-threshold", "value": "1.5"}`.
+fn update_config(config_manager: &ConfigManager, body: &[u8]) -> HandleResult {
+    let v: Value = try!(
+        serde_json::from_slice(body)
+            .map_err(|e| (StatusCode::BadRequest, format!("invalid body: {}", e)))
+    );
+    let field = |name| {
+        v.get(name)
+            .and_then(|f| f.as_str())
+            .ok_or_else(|| (StatusCode::BadRequest, format!("missing {}", name)))
+    };
+    let (module, name, value) = (try!(field("module")), try!(field("name")), try!(field("value")));
+    try!(
+        config_manager
+            .update(module, name, value)
+            .map_err(|e| (StatusCode::BadRequest, format!("{}", e)))
+    );
+    Ok(vec![])
+}
+
+fn hot_keys_to_json(keys: Vec<(Vec<u8>, u64)>) -> Value {
+    let keys = keys.into_iter()
+        .map(|(key, count)| {
+            let mut m = Map::new();
+            m.insert("key".to_owned(), Value::String(util::escape(&key)));
+            m.insert("count".to_owned(), Value::from(count));
+            Value::Object(m)
+        })
+        .collect();
+    Value::Array(keys)
+}
+
+/// Dumps the hot keys by read and write traffic in JSON, `query` can specify
+/// the number of keys by `limit=N`.
+fn dump_hot_keys(query: &str) -> Vec<u8> {
+    let limit = query
+        .split('&')
+        .filter_map(|kv| {
+            let mut parts = kv.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("limit"), Some(v)) => v.parse().ok(),
+                _ => None,
+            }
+        })
+        .next()
+        .unwrap_or(DEFAULT_HOT_KEYS_LIMIT);
+    let mut m = Map::new();
+    m.insert(
+        "read".to_owned(),
+        hot_keys_to_json(hot_keys::hot_read_keys(limit)),
+    );
+    m.insert(
+        "write".to_owned(),
+        hot_keys_to_json(hot_keys::hot_write_keys(limit)),
+    );
+    serde_json::to_vec(&Value::Object(m)).unwrap()
+}
+
+impl Handler for StatusHandler {
+    fn handle<'a, 'k>(&'a self, mut req: Request<'a, 'k>, mut res: Response<'a, Fresh>) {
+        let (path, query) = match req.uri {
+            RequestUri::AbsolutePath(ref p) => {
+                let mut parts = p.splitn(2, '?');
+                let path = parts.next().unwrap().to_owned();
+                (path, parts.next().unwrap_or("").to_owned())
+            }
+            _ => (String::new(), String::new()),
+        };
+        let mut body = vec![];
+        if let Err(e) = req.read_to_end(&mut body) {
+            warn!("failed to read request of {}: {}", path, e);
+            return;
+        }
+        let result = match (&req.method, path.as_str(), self.regions.as_ref()) {
+            (&Get, METRICS_PATH, _) => Ok(metrics::dump().into_bytes()),
+            (&Get, HOT_KEYS_PATH, _) => Ok(dump_hot_keys(&query)),
+            (&Get, HEAP_PROFILE_PATH, _) => self.dump_heap_profile().map_err(|e| {
+                error!("failed to dump heap profile: {}", e);
+                (StatusCode::InternalServerError, e)
+            }),
+            (&Get, REGIONS_PATH, Some(regions)) => regions.dump_regions(),
+            (&Get, p, Some(regions)) if p.starts_with(REGION_PATH_PREFIX) => {
+                regions.dump_region(&p[REGION_PATH_PREFIX.len()..])
+            }
+            (&Get, CONFIG_PATH, _) if self.config_manager.is_some() => {
+                dump_config_changes(self.config_manager.as_ref().unwrap())
+            }
+            (&Post, CONFIG_PATH, _) if self.config_manager.is_some() => {
+                update_config(self.config_manager.as_ref().unwrap(), &body)
+            }
+            _ => Err((StatusCode::NotFound, String::new())),
+        };
+        let body = match result {
+            Ok(body) => body,
+            Err((status, msg)) => {
+                *res.status_mut() = status;
+                msg.into_bytes()
+            }
+        };
+        if let Err(e) = res.send(&body) {
+            warn!("failed to send response of {}: {}", path, e);
+        }
+    }
+}
+
+/// `StatusServer` serves the status and debug endpoints of TiKV over HTTP:
+/// - `/metrics` returns the metrics in the prometheus text format, so they can
+///   be scraped besides being pushed to the pushgateway.
+/// - `/debug/hot_keys` returns the hottest keys by read and write traffic.
+/// - `/regions` and `/region/{id}` return the metadata of the regions on this
+///   store, including the range, epoch, role, apply index and size.
+/// - `/config` returns the configs changed online by `GET`, and changes one by
+///   `POST`.
+/// - `/debug/pprof/heap` activates the jemalloc profiling and returns a heap
+///   profile that can be analyzed by `jeprof`.
+pub struct StatusServer {
+    listening: Listening,
+}
+
+impl StatusServer {
+    pub fn start(
+        addr: &str,
+        regions: Option<RegionMetaReader>,
+        config_manager: Option<Arc<ConfigManager>>,
+    ) -> hyper::Result<StatusServer> {
+        let server = try!(Server::http(addr));
+        let handler = StatusHandler {
+            dump_lock: Mutex::new(()),
+            regions: regions,
+            config_manager: config_manager,
+        };
+        let listening = try!(server.handle_threads(handler, STATUS_SERVER_THREADS));
+        info!("status server listening on {}", listening.socket);
+        Ok(StatusServer {
+            listening: listening,
+        })
+    }
+
+    pub fn stop(mut self) {
+        if let Err(e) = self.listening.close() {
+            warn!("failed to stop status server: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use hyper::Client;
+    use kvproto::metapb::Region;
+    use tikv::raftstore::coprocessor::{ObserverContext, RegionObserver};
+    use tikv::raftstore::store::{keys, Mutable};
+    use tikv::storage::{ALL_CFS, CF_RAFT};
+    use tikv::util::rocksdb as rocksdb_util;
+    use tikv::util::dynamic_config::ConfigHandler;
+
+    use super::*;
+
+    fn new_region_meta_reader(dir: &TempDir) -> RegionMetaReader {
+        let path = dir.path().to_str().unwrap();
+        let engine = Arc::new(rocksdb_util::new_engine(path, ALL_CFS).unwrap());
+        let raft_cf = rocksdb_util::get_cf_handle(&engine, CF_RAFT).unwrap();
+        let mut region = Region::new();
+        region.set_id(1);
+        region.set_start_key(b"a".to_vec());
+        region.mut_region_epoch().set_version(2);
+        let mut region_state = RegionLocalState::new();
+        region_state.set_region(region.clone());
+        engine
+            .put_msg_cf(raft_cf, &keys::region_state_key(1), &region_state)
+            .unwrap();
+        let mut apply_state = RaftApplyState::new();
+        apply_state.set_applied_index(42);
+        engine
+            .put_msg_cf(raft_cf, &keys::apply_state_key(1), &apply_state)
+            .unwrap();
+
+        let roles = RoleObserver::new();
+        roles.on_role_change(&mut ObserverContext::new(&region), StateRole::Leader);
+        RegionMetaReader::new(Engines::new(engine.clone(), engine), roles)
+    }
+
+    struct MockConfigHandler {
+        value: RwLock<String>,
+    }
+
+    impl ConfigHandler for MockConfigHandler {
+        fn update(&self, name: &str, value: &str) -> Result<(), String> {
+            if name != "name" {
+                return Err(format!("unknown config {}", name));
+            }
+            *self.value.write().unwrap() = value.to_owned();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_status_server() {
+        let dir = TempDir::new("test_status_server").unwrap();
+        let regions = new_region_meta_reader(&dir);
+        let config_handler = Arc::new(MockConfigHandler {
+            value: RwLock::new(String::new()),
+        });
+        let config_manager = Arc::new(ConfigManager::new());
+        config_manager.register("mock", Box::new(config_handler.clone()));
+        let server =
+            StatusServer::start("127.0.0.1:0", Some(regions), Some(config_manager)).unwrap();
+        let addr = server.listening.socket;
+        let client = Client::new();
+
+        let resp = client.get(&format!("http://{}/foo", addr)).send().unwrap();
+        assert_eq!(resp.status, StatusCode::NotFound);
+
+        metrics::CHANNEL_FULL_COUNTER_VEC
+            .with_label_values(&["status_server_test"])
+            .inc();
+        let mut resp = client
+            .get(&format!("http://{}{}", addr, METRICS_PATH))
+            .send()
+            .unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        let mut body = String::new();
+        resp.read_to_string(&mut body).unwrap();
+        assert!(body.contains("tikv_channel_full_total"));
+
+        hot_keys::record_read_keys(vec![b"k1".as_ref(), b"k1".as_ref(), b"k2".as_ref()]);
+        let mut resp = client
+            .get(&format!("http://{}{}?limit=1", addr, HOT_KEYS_PATH))
+            .send()
+            .unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        let mut body = String::new();
+        resp.read_to_string(&mut body).unwrap();
+        let v: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(v["read"].as_array().unwrap().len(), 1);
+        assert!(v["write"].is_array());
+
+        let mut resp = client
+            .get(&format!("http://{}{}", addr, REGIONS_PATH))
+            .send()
+            .unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        let mut body = String::new();
+        resp.read_to_string(&mut body).unwrap();
+        let v: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(v.as_array().unwrap().len(), 1);
+        assert_eq!(v[0]["id"], 1);
+        assert_eq!(v[0]["start_key"], "a");
+        assert_eq!(v[0]["epoch"]["version"], 2);
+        assert_eq!(v[0]["role"], "leader");
+        assert_eq!(v[0]["apply_index"], 42);
+
+        let mut resp = client
+            .get(&format!("http://{}{}1", addr, REGION_PATH_PREFIX))
+            .send()
+            .unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        let mut body = String::new();
+        resp.read_to_string(&mut body).unwrap();
+        let region: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(region, v[0]);
+        for (id, status) in vec![("2", StatusCode::NotFound), ("x", StatusCode::BadRequest)] {
+            let resp = client
+                .get(&format!("http://{}{}{}", addr, REGION_PATH_PREFIX, id))
+                .send()
+                .unwrap();
+            assert_eq!(resp.status, status);
+        }
+
+        let config_url = format!("http://{}{}", addr, CONFIG_PATH);
+        for body in vec![
+            "{",
+            r#"{"module": "mock", "name": "name"}"#,
+            r#"{"module": "mock", "name": "unknown", "value": "v"}"#,
+            r#"{"module": "unknown", "name": "name", "value": "v"}"#,
+        ] {
+            let resp = client.post(&config_url).body(body).send().unwrap();
+            assert_eq!(resp.status, StatusCode::BadRequest, "{}", body);
+        }
+        let resp = client
+            .post(&config_url)
+            .body(r#"{"module": "mock", "name": "name", "value": "v"}"#)
+            .send()
+            .unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        assert_eq!(*config_handler.value.read().unwrap(), "v");
+        let mut resp = client.get(&config_url).send().unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        let mut body = String::new();
+        resp.read_to_string(&mut body).unwrap();
+        let v: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(v["mock"]["name"], "v");
+
+        let resp = client
+            .get(&format!("http://{}{}", addr, HEAP_PROFILE_PATH))
+            .send()
+            .unwrap();
+        if cfg!(feature = "mem-profiling") {
+            // Profiling may not be enabled by the `prof` option of jemalloc.
+            assert!(
+                resp.status == StatusCode::Ok || resp.status == StatusCode::InternalServerError
+            );
+        } else {
+            assert_eq!(resp.status, StatusCode::InternalServerError);
+        }
+
+        server.stop();
+    }
+}
@@ -24,22 +24,44 @@ extern crate rocksdb;
 #[cfg(test)]
 extern crate tempdir;
 extern crate rustc_serialize;
+extern crate grpcio as grpc;
+extern crate futures;
 
-use std::{str, u64};
+use std::{process, str, u64};
+use std::fmt::Debug;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
 use clap::{App, Arg, SubCommand};
 use rustc_serialize::hex::{FromHex, ToHex};
 use protobuf::Message;
+use futures::{Future, Stream};
+use grpc::{ChannelBuilder, Environment};
+use kvproto::debugpb::{CompactRequest, GetClusterInfoRequest, GetMetricsRequest,
+                       GetRegionPropertiesRequest, GetStoreInfoRequest, InjectFailPointRequest,
+                       ListFailPointsRequest, ModifyTikvConfigRequest, RaftLogRequest,
+                       RecoverFailPointRequest, RegionConsistencyCheckRequest,
+                       RegionInfoRequest, RegionSizeRequest, ScanMvccRequest,
+                       SetLogLevelRequest, DB as DBType, MODULE};
+use kvproto::kvrpcpb::MvccInfo;
+use kvproto::debugpb_grpc::DebugClient;
+use kvproto::metapb::{Region, StoreState};
 use kvproto::raft_cmdpb::RaftCmdRequest;
-use kvproto::raft_serverpb::{PeerState, RaftApplyState, RaftLocalState, RegionLocalState};
-use kvproto::eraftpb::Entry;
+use kvproto::raft_serverpb::{PeerState, RaftApplyState, RegionLocalState};
+use kvproto::eraftpb::{ConfChange, Entry, EntryType};
 use rocksdb::{ReadOptions, SeekKey, DB};
 use tikv::util::{self, escape, unescape};
 use tikv::util::codec::bytes::encode_bytes;
-use tikv::raftstore::store::keys;
-use tikv::raftstore::store::engine::{IterOption, Iterable, Peekable};
-use tikv::storage::{CfName, ALL_CFS, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
+use tikv::util::codec::number::NumberDecoder;
+use tikv::util::rocksdb::manifest::{self, VersionEdit};
+use tikv::raftstore::store::{keys, load_snapshot_metas, verify_snapshot_cf_file, Engines};
+use tikv::raftstore::store::engine::{Iterable, Peekable};
+use tikv::server::debug::{Debugger, RegionInfo};
+use tikv::pd::{PdClient, RpcClient};
+use tikv::storage::{ALL_CFS, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
 use tikv::storage::mvcc::{Lock, Write};
-use tikv::storage::types::Key;
+use tikv::storage::types::{split_encoded_key_on_ts, Key};
 
 fn main() {
     let mut app = App::new("TiKV Ctl")
@@ -59,6 +81,13 @@ fn main() {
                 .takes_value(true)
                 .help("set raft rocksdb path"),
         )
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .takes_value(true)
+                .conflicts_with("db")
+                .help("set the remote host, which must provide the debug service"),
+        )
         .arg(
             Arg::with_name("hex-to-escaped")
                 .short("h")
@@ -73,7 +102,7 @@ fn main() {
         )
         .subcommand(
             SubCommand::with_name("raft")
-                .about("print raft log entry")
+                .about("print raft log entry and region info")
                 .subcommand(
                     SubCommand::with_name("log")
                         .about("print the raft log entry info")
@@ -168,6 +197,44 @@ fn main() {
                         .help("column family name"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("raw-scan")
+                .about("print raw key-value pairs of any column family in the given range")
+                .arg(
+                    Arg::with_name("db")
+                        .short("d")
+                        .takes_value(true)
+                        .default_value("kv")
+                        .possible_values(&["kv", "raft"])
+                        .help("kv or raft"),
+                )
+                .arg(
+                    Arg::with_name("cf")
+                        .short("c")
+                        .takes_value(true)
+                        .default_value(CF_DEFAULT)
+                        .help("column family name"),
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .short("f")
+                        .takes_value(true)
+                        .help("set the start raw key, in hex form"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .short("t")
+                        .takes_value(true)
+                        .help("set the end raw key, in hex form"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .short("l")
+                        .takes_value(true)
+                        .default_value("30")
+                        .help("set the scan limit, 0 means no limit"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("print")
                 .about("print the raw value")
@@ -186,12 +253,14 @@ fn main() {
         )
         .subcommand(
             SubCommand::with_name("mvcc")
-                .about("print the mvcc value")
+                .about("print all mvcc versions of a key")
                 .arg(
                     Arg::with_name("cf")
                         .short("c")
                         .takes_value(true)
-                        .help("column family name, only can be default/lock/write"),
+                        .default_value("all")
+                        .possible_values(&["default", "lock", "write", "all"])
+                        .help("column family name, only can be default/lock/write/all"),
                 )
                 .arg(
                     Arg::with_name("key")
@@ -232,117 +301,1308 @@ fn main() {
                         .takes_value(true)
                         .help("specify region id"),
                 ),
+        )
+        .subcommand(
+            SubCommand::with_name("bad-regions")
+                .about("get all regions with corrupt raft meta, only available in local mode"),
+        )
+        .subcommand(
+            SubCommand::with_name("recover-mvcc")
+                .about("remove inconsistent mvcc records, only available in local mode")
+                .arg(
+                    Arg::with_name("regions")
+                        .short("r")
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .require_delimiter(true)
+                        .value_delimiter(",")
+                        .help("the target regions separated by commas, all regions by default"),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("only report the inconsistent records without removing them"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("unsafe-recover")
+                .about("unsafely recover the cluster when the majority replicas are failed")
+                .subcommand(
+                    SubCommand::with_name("remove-fail-stores")
+                        .about("remove peers on failed stores from all regions on this store")
+                        .arg(
+                            Arg::with_name("stores")
+                                .short("s")
+                                .takes_value(true)
+                                .required(true)
+                                .use_delimiter(true)
+                                .require_delimiter(true)
+                                .value_delimiter(",")
+                                .help("failed store ids, separated by commas"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tombstone")
+                .about("set some regions on this store to tombstone by force")
+                .arg(
+                    Arg::with_name("regions")
+                        .short("r")
+                        .takes_value(true)
+                        .required(true)
+                        .use_delimiter(true)
+                        .require_delimiter(true)
+                        .value_delimiter(",")
+                        .help("the target regions, separated by commas"),
+                )
+                .arg(
+                    Arg::with_name("pd")
+                        .short("p")
+                        .takes_value(true)
+                        .required(true)
+                        .use_delimiter(true)
+                        .require_delimiter(true)
+                        .value_delimiter(",")
+                        .help("PD endpoints, used to confirm the peers are really removed"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("modify-tikv-config")
+                .about("modify tikv config online, the changes are kept across restarts")
+                .arg(
+                    Arg::with_name("module")
+                        .short("m")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&["kvdb", "raftdb", "backup", "storage", "coprocessor"])
+                        .help("module of the config"),
+                )
+                .arg(
+                    Arg::with_name("config_name")
+                        .short("n")
+                        .takes_value(true)
+                        .required(true)
+                        .help(
+                            "config name, like `default.disable_auto_compactions` for rocksdb, \
+                             or `rate-limit` and `concurrency` for backup",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("config_value")
+                        .short("v")
+                        .takes_value(true)
+                        .required(true)
+                        .help("config value"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-log-level")
+                .about("change the log level of a running tikv, globally or for a module")
+                .arg(
+                    Arg::with_name("level")
+                        .short("l")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&["trace", "debug", "info", "warn", "error", "off"])
+                        .help("the log level"),
+                )
+                .arg(
+                    Arg::with_name("module")
+                        .short("m")
+                        .takes_value(true)
+                        .default_value("")
+                        .help(
+                            "the prefix of the log targets, like `tikv::raftstore`, \
+                             empty means the global level",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dump-snap-meta")
+                .about("dump the meta of all snapshots in a snapshot directory")
+                .arg(
+                    Arg::with_name("path")
+                        .short("p")
+                        .takes_value(true)
+                        .required(true)
+                        .help("the snapshot directory"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("region-properties")
+                .about("show the approximate size, keys and mvcc properties of a region")
+                .arg(
+                    Arg::with_name("region")
+                        .short("r")
+                        .takes_value(true)
+                        .required(true)
+                        .help("the target region"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("store").about("print the store id and build info"))
+        .subcommand(SubCommand::with_name("cluster").about("print the cluster id"))
+        .subcommand(
+            SubCommand::with_name("consistency-check")
+                .about(
+                    "force a consistency check of a region, only available in online mode and \
+                     the tikv must hold the leader of the region",
+                )
+                .arg(
+                    Arg::with_name("region")
+                        .short("r")
+                        .takes_value(true)
+                        .required(true)
+                        .help("the target region"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fail")
+                .about("inject or recover fail points of a tikv built with the failpoints feature")
+                .subcommand(
+                    SubCommand::with_name("inject")
+                        .about("inject fail points")
+                        .arg(
+                            Arg::with_name("args")
+                                .multiple(true)
+                                .takes_value(true)
+                                .required(true)
+                                .help("fail points and their actions, like `name=sleep(100)`"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("recover")
+                        .about("recover fail points")
+                        .arg(
+                            Arg::with_name("args")
+                                .multiple(true)
+                                .takes_value(true)
+                                .required(true)
+                                .help("names of the fail points"),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("list all fail points")),
+        )
+        .subcommand(
+            SubCommand::with_name("metrics")
+                .about("print the metrics of a tikv, only available in online mode")
+                .arg(
+                    Arg::with_name("all")
+                        .short("a")
+                        .long("all")
+                        .help("print rocksdb statistics besides prometheus metrics"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("compact a column family in a specified range")
+                .arg(
+                    Arg::with_name("db")
+                        .short("d")
+                        .takes_value(true)
+                        .default_value("kv")
+                        .possible_values(&["kv", "raft"])
+                        .help("kv or raft"),
+                )
+                .arg(
+                    Arg::with_name("cf")
+                        .short("c")
+                        .takes_value(true)
+                        .default_value(CF_DEFAULT)
+                        .help("column family name"),
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .short("f")
+                        .takes_value(true)
+                        .help("set the start raw key, in escaped form"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .short("t")
+                        .takes_value(true)
+                        .help("set the end raw key, in escaped form"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compact-cluster")
+                .about("compact column families in a specified range on all stores of the cluster")
+                .arg(
+                    Arg::with_name("pd")
+                        .short("p")
+                        .takes_value(true)
+                        .required(true)
+                        .use_delimiter(true)
+                        .require_delimiter(true)
+                        .value_delimiter(",")
+                        .help("PD endpoints, used to get all stores of the cluster"),
+                )
+                .arg(
+                    Arg::with_name("db")
+                        .short("d")
+                        .takes_value(true)
+                        .default_value("kv")
+                        .possible_values(&["kv", "raft"])
+                        .help("kv or raft"),
+                )
+                .arg(
+                    Arg::with_name("cf")
+                        .short("c")
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .require_delimiter(true)
+                        .value_delimiter(",")
+                        .default_value(CF_DEFAULT)
+                        .help("column family names, separated by commas"),
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .short("f")
+                        .takes_value(true)
+                        .help("set the start raw key, in escaped form"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .short("t")
+                        .takes_value(true)
+                        .help("set the end raw key, in escaped form"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .short("n")
+                        .long("threads")
+                        .takes_value(true)
+                        .default_value("8")
+                        .help("the number of stores to compact concurrently"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ldb")
+                .about("inspect the SST files of a local rocksdb through its MANIFEST")
+                .arg(
+                    Arg::with_name("db")
+                        .short("d")
+                        .takes_value(true)
+                        .default_value("kv")
+                        .possible_values(&["kv", "raft"])
+                        .help("kv or raft"),
+                )
+                .subcommand(
+                    SubCommand::with_name("manifest").about("dump all records of the MANIFEST"),
+                )
+                .subcommand(
+                    SubCommand::with_name("live-files")
+                        .about("list the live SST files of each column family"),
+                )
+                .subcommand(
+                    SubCommand::with_name("sst-info")
+                        .about("show the meta of live SST files and check them on disk")
+                        .arg(
+                            Arg::with_name("cf")
+                                .short("c")
+                                .takes_value(true)
+                                .help("column family name, if not specified, show all cfs"),
+                        ),
+                ),
+        );
+    let matches = app.clone().get_matches();
+
+    let hex_key = matches.value_of("hex-to-escaped");
+    let escaped_key = matches.value_of("escaped-to-hex");
+    match (hex_key, escaped_key) {
+        (None, None) => {}
+        (Some(_), Some(_)) => panic!("hex and escaped can not be passed together!"),
+        (Some(hex), None) => {
+            println!("{}", escape(&from_hex(hex)));
+            return;
+        }
+        (None, Some(escaped)) => {
+            println!("{}", &unescape(escaped).to_hex().to_uppercase());
+            return;
+        }
+    };
+    if let Some(sub_matches) = matches.subcommand_matches("dump-snap-meta") {
+        let path = sub_matches.value_of("path").unwrap();
+        // The region meta is only available when the kv db is given.
+        let db = matches
+            .value_of("db")
+            .map(|p| util::rocksdb::open(p, ALL_CFS).unwrap());
+        dump_snap_meta(path, db.as_ref());
+        return;
+    }
+
+    // Commands below can work either on a remote TiKV through the debug service,
+    // or on local data directories.
+    let db_path = matches.value_of("db");
+    let raft_db_path = matches.value_of("raftdb");
+    let host = matches.value_of("host");
+    if let Some(matches) = matches.subcommand_matches("compact") {
+        let db = matches.value_of("db").unwrap();
+        let db_type = if db == "kv" { DBType::KV } else { DBType::RAFT };
+        let cf = matches.value_of("cf").unwrap();
+        let from_key = matches.value_of("from").map(|k| unescape(k));
+        let to_key = matches.value_of("to").map(|k| unescape(k));
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        debug_executor.compact(db_type, cf, from_key, to_key);
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("compact-cluster") {
+        let pd_urls: Vec<String> = matches
+            .values_of("pd")
+            .unwrap()
+            .map(ToOwned::to_owned)
+            .collect();
+        let db = matches.value_of("db").unwrap();
+        let db_type = if db == "kv" { DBType::KV } else { DBType::RAFT };
+        let cfs: Vec<String> = matches
+            .values_of("cf")
+            .unwrap()
+            .map(ToOwned::to_owned)
+            .collect();
+        let from_key = matches.value_of("from").map(|k| unescape(k));
+        let to_key = matches.value_of("to").map(|k| unescape(k));
+        let threads: usize = matches
+            .value_of("threads")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|e| perror_and_exit("parse threads", e));
+        if threads == 0 {
+            perror_and_exit("parse threads", "threads must be greater than 0");
+        }
+        compact_whole_cluster(&pd_urls, db_type, cfs, from_key, to_key, threads);
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("raft") {
+        if let Some(matches) = matches.subcommand_matches("log") {
+            let (region_id, log_index) = match matches.value_of("key") {
+                None => {
+                    let region_id = matches
+                        .value_of("region")
+                        .unwrap_or_else(|| perror_and_exit("raft log", "region id is required"));
+                    let log_index = matches
+                        .value_of("index")
+                        .unwrap_or_else(|| perror_and_exit("raft log", "log index is required"));
+                    (
+                        region_id
+                            .parse()
+                            .unwrap_or_else(|e| perror_and_exit("parse region id", e)),
+                        log_index
+                            .parse()
+                            .unwrap_or_else(|e| perror_and_exit("parse log index", e)),
+                    )
+                }
+                Some(k) => keys::decode_raft_log_key(&unescape(k))
+                    .unwrap_or_else(|e| perror_and_exit("decode raft log key", e)),
+            };
+            let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+            debug_executor.dump_raft_log(region_id, log_index);
+        } else if let Some(matches) = matches.subcommand_matches("region") {
+            let skip_tombstone = matches.is_present("skip-tombstone");
+            let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+            match matches.value_of("region") {
+                Some(id) => {
+                    let region_id = id.parse()
+                        .unwrap_or_else(|e| perror_and_exit("parse region id", e));
+                    debug_executor.dump_region_info(region_id, skip_tombstone);
+                }
+                None => debug_executor.dump_all_region_info(skip_tombstone),
+            }
+        } else {
+            let _ = app.print_help();
+        }
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("size") {
+        let cfs = match matches.value_of("cf") {
+            Some(cf) => vec![cf],
+            None => vec![CF_DEFAULT, CF_WRITE, CF_LOCK],
+        };
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        match matches.value_of("region") {
+            Some(id) => {
+                let region_id = id.parse()
+                    .unwrap_or_else(|e| perror_and_exit("parse region id", e));
+                debug_executor.dump_region_size(region_id, cfs);
+            }
+            None => debug_executor.dump_all_region_size(cfs),
+        }
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("raw-scan") {
+        let db = matches.value_of("db").unwrap();
+        let db_type = if db == "kv" { DBType::KV } else { DBType::RAFT };
+        let cf = matches.value_of("cf").unwrap();
+        let from = matches.value_of("from").map_or_else(Vec::new, from_hex);
+        let to = matches.value_of("to").map_or_else(Vec::new, from_hex);
+        let limit = matches
+            .value_of("limit")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|e| perror_and_exit("parse limit", e));
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        debug_executor.dump_raw_scan(db_type, cf, from, to, limit);
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("mvcc") {
+        let cfs = match matches.value_of("cf").unwrap() {
+            "all" => vec![CF_DEFAULT, CF_LOCK, CF_WRITE],
+            cf => vec![cf],
+        };
+        let key = matches.value_of("key").unwrap();
+        let encoded_key = if matches.is_present("encoded") {
+            unescape(key)
+        } else {
+            encode_bytes(&unescape(key))
+        };
+        let start_ts = matches.value_of("start_ts").map(|s| s.parse().unwrap());
+        let commit_ts = matches.value_of("commit_ts").map(|s| s.parse().unwrap());
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        debug_executor.dump_mvcc(&encoded_key, &cfs, start_ts, commit_ts);
+        return;
+    } else if matches.subcommand_matches("bad-regions").is_some() {
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        debug_executor.print_bad_regions();
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("recover-mvcc") {
+        let regions: Vec<u64> = matches
+            .values_of("regions")
+            .map(|rs| {
+                rs.map(|r| r.parse().unwrap_or_else(|e| perror_and_exit("parse region id", e)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        debug_executor.recover_mvcc(regions, matches.is_present("dry_run"));
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("tombstone") {
+        let regions: Vec<u64> = matches
+            .values_of("regions")
+            .unwrap()
+            .map(|r| r.parse().unwrap_or_else(|e| perror_and_exit("parse region id", e)))
+            .collect();
+        let pd_urls: Vec<String> = matches
+            .values_of("pd")
+            .unwrap()
+            .map(ToOwned::to_owned)
+            .collect();
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        debug_executor.set_region_tombstone_after_remove_peer(pd_urls, regions);
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("region-properties") {
+        let region_id = matches
+            .value_of("region")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|e| perror_and_exit("parse region id", e));
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        debug_executor.dump_region_properties(region_id);
+        return;
+    } else if matches.subcommand_matches("store").is_some() {
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        debug_executor.dump_store_info();
+        return;
+    } else if matches.subcommand_matches("cluster").is_some() {
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        debug_executor.dump_cluster_info();
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("consistency-check") {
+        let region_id = matches
+            .value_of("region")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|e| perror_and_exit("parse region id", e));
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        debug_executor.check_region_consistency(region_id);
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("fail") {
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        if let Some(matches) = matches.subcommand_matches("inject") {
+            let fail_points: Vec<(String, String)> = matches
+                .values_of("args")
+                .unwrap()
+                .map(|arg| {
+                    let mut parts = arg.splitn(2, '=');
+                    let name = parts.next().unwrap().to_owned();
+                    let actions = parts
+                        .next()
+                        .unwrap_or_else(|| perror_and_exit("parse fail point", arg));
+                    (name, actions.to_owned())
+                })
+                .collect();
+            debug_executor.inject_fail_points(fail_points);
+        } else if let Some(matches) = matches.subcommand_matches("recover") {
+            let names = matches
+                .values_of("args")
+                .unwrap()
+                .map(ToOwned::to_owned)
+                .collect();
+            debug_executor.recover_fail_points(names);
+        } else if matches.subcommand_matches("list").is_some() {
+            debug_executor.list_fail_points();
+        } else {
+            let _ = app.print_help();
+        }
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("metrics") {
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        debug_executor.dump_metrics(matches.is_present("all"));
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("modify-tikv-config") {
+        let module = match matches.value_of("module").unwrap() {
+            "kvdb" => MODULE::KVDB,
+            "raftdb" => MODULE::RAFTDB,
+            "storage" => MODULE::STORAGE,
+            "coprocessor" => MODULE::COPROCESSOR,
+            _ => MODULE::BACKUP,
+        };
+        let config_name = matches.value_of("config_name").unwrap();
+        let config_value = matches.value_of("config_value").unwrap();
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        debug_executor.modify_tikv_config(module, config_name, config_value);
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("set-log-level") {
+        let level = matches.value_of("level").unwrap();
+        let module = matches.value_of("module").unwrap();
+        let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+        debug_executor.set_log_level(module, level);
+        return;
+    } else if let Some(matches) = matches.subcommand_matches("unsafe-recover") {
+        if let Some(matches) = matches.subcommand_matches("remove-fail-stores") {
+            let store_ids: Vec<u64> = matches
+                .values_of("stores")
+                .unwrap()
+                .map(|s| s.parse().unwrap_or_else(|e| perror_and_exit("parse store id", e)))
+                .collect();
+            let debug_executor = new_debug_executor(db_path, raft_db_path, host);
+            debug_executor.remove_fail_stores(store_ids);
+        } else {
+            let _ = app.print_help();
+        }
+        return;
+    }
+
+    let db_path = db_path.unwrap();
+
+    if let Some(matches) = matches.subcommand_matches("ldb") {
+        let path = if matches.value_of("db").unwrap() == "kv" {
+            db_path.to_owned()
+        } else {
+            raft_db_path.map_or_else(|| db_path.to_owned() + "../raft", ToOwned::to_owned)
+        };
+        if matches.subcommand_matches("manifest").is_some() {
+            dump_manifest(&path);
+        } else if matches.subcommand_matches("live-files").is_some() {
+            dump_live_files(&path, None, false);
+        } else if let Some(matches) = matches.subcommand_matches("sst-info") {
+            dump_live_files(&path, matches.value_of("cf"), true);
+        } else {
+            let _ = app.print_help();
+        }
+        return;
+    }
+
+    let db = util::rocksdb::open(db_path, ALL_CFS).unwrap();
+
+    if let Some(matches) = matches.subcommand_matches("print") {
+        let cf_name = matches.value_of("cf").unwrap_or(CF_DEFAULT);
+        let key = String::from(matches.value_of("key").unwrap());
+        dump_raw_value(db, cf_name, key);
+    } else if let Some(matches) = matches.subcommand_matches("scan") {
+        let from = String::from(matches.value_of("from").unwrap());
+        let to = matches.value_of("to").map(String::from);
+        let limit = matches.value_of("limit").map(|s| s.parse().unwrap());
+        let cf_name = matches.value_of("cf").unwrap_or(CF_DEFAULT);
+        let start_ts = matches.value_of("start_ts").map(|s| s.parse().unwrap());
+        let commit_ts = matches.value_of("commit_ts").map(|s| s.parse().unwrap());
+        if let Some(ref to) = to {
+            if to <= &from {
+                panic!("The region's start pos must greater than the end pos.")
+            }
+        }
+        dump_range(db, from, to, limit, cf_name, start_ts, commit_ts);
+    } else if let Some(matches) = matches.subcommand_matches("diff") {
+        let region_id: u64 = matches.value_of("region").unwrap().parse().unwrap();
+        let db_path2 = matches.value_of("to").unwrap();
+        let db2 = util::rocksdb::open(db_path2, ALL_CFS).unwrap();
+        dump_diff(&db, &db2, region_id);
+    } else {
+        let _ = app.print_help();
+    }
+
+}
+
+fn open_raft_db(db_path: &str, raft_db_path: Option<&str>) -> DB {
+    if let Some(raftdb_path) = raft_db_path {
+        util::rocksdb::open(raftdb_path, &[CF_DEFAULT]).unwrap()
+    } else {
+        let raftdb_path = db_path.to_owned() + "../raft";
+        util::rocksdb::open(&raftdb_path, &[CF_DEFAULT]).unwrap()
+    }
+}
+
+fn new_debug_executor(
+    db: Option<&str>,
+    raft_db: Option<&str>,
+    host: Option<&str>,
+) -> Box<DebugExecutor> {
+    match (host, db) {
+        (None, Some(kv_path)) => {
+            let db = util::rocksdb::open(kv_path, ALL_CFS).unwrap();
+            let raft_db = open_raft_db(kv_path, raft_db);
+            let engines = Engines::new(Arc::new(db), Arc::new(raft_db));
+            Box::new(Debugger::new(engines)) as Box<DebugExecutor>
+        }
+        (Some(remote), None) => {
+            let env = Arc::new(Environment::new(1));
+            let channel = ChannelBuilder::new(env).connect(remote);
+            Box::new(DebugClient::new(channel)) as Box<DebugExecutor>
+        }
+        _ => perror_and_exit("new_debug_executor", "exactly one of --host and -d is required"),
+    }
+}
+
+/// Compacts `cfs` of `db` in the given range on all stores that are not tombstone,
+/// at most `threads` stores at a time.
+fn compact_whole_cluster(
+    pd_urls: &[String],
+    db: DBType,
+    cfs: Vec<String>,
+    from: Option<Vec<u8>>,
+    to: Option<Vec<u8>>,
+    threads: usize,
+) {
+    let pd_client = RpcClient::new(pd_urls)
+        .unwrap_or_else(|e| perror_and_exit("RpcClient::new", e));
+    let addrs: Vec<String> = pd_client
+        .get_all_stores()
+        .unwrap_or_else(|e| perror_and_exit("get_all_stores", e))
+        .into_iter()
+        .filter(|s| s.get_state() != StoreState::Tombstone)
+        .map(|mut s| s.take_address())
+        .collect();
+
+    for batch in addrs.chunks(threads) {
+        let handles: Vec<_> = batch
+            .iter()
+            .map(|addr| {
+                let (addr, cfs, from, to) = (addr.clone(), cfs.clone(), from.clone(), to.clone());
+                thread::Builder::new()
+                    .name(format!("compact-{}", addr))
+                    .spawn(move || {
+                        let debug_executor = new_debug_executor(None, None, Some(&addr));
+                        for cf in &cfs {
+                            debug_executor.compact(db, cf, from.clone(), to.clone());
+                        }
+                        println!("compact store {} success!", addr);
+                    })
+                    .unwrap()
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+    }
+}
+
+fn perror_and_exit<E: Debug>(prefix: &str, e: E) -> ! {
+    eprintln!("{} failed: {:?}", prefix, e);
+    process::exit(-1);
+}
+
+/// `DebugExecutor` runs debug commands either against a remote TiKV through
+/// `DebugClient`, or against local data directories through `Debugger`.
+trait DebugExecutor {
+    fn compact(&self, db: DBType, cf: &str, from: Option<Vec<u8>>, to: Option<Vec<u8>>) {
+        let from = from.unwrap_or_default();
+        let to = to.unwrap_or_default();
+        self.do_compact(db, cf, from.clone(), to.clone());
+        println!(
+            "compact db:{:?} cf:{} range:[{:?}, {:?}) success!",
+            db,
+            cf,
+            escape(&from),
+            escape(&to)
+        );
+    }
+
+    /// Prints all mvcc records of the encoded key `key` in the given column families.
+    fn dump_mvcc(&self, key: &[u8], cfs: &[&str], start_ts: Option<u64>, commit_ts: Option<u64>) {
+        let infos = self.get_mvcc_infos(keys::data_key(key), vec![], 1);
+        match infos.into_iter().next() {
+            Some((ref k, ref info)) if k.as_slice() == key => {
+                dump_mvcc_info(key, info, cfs, start_ts, commit_ts)
+            }
+            _ => println!("no mvcc record found for key {}", escape(key)),
+        }
+    }
+
+    fn do_compact(&self, db: DBType, cf: &str, from: Vec<u8>, to: Vec<u8>);
+
+    fn dump_region_info(&self, region_id: u64, skip_tombstone: bool) {
+        let region_info = self.get_region_info(region_id);
+        if skip_tombstone &&
+            region_info
+                .region_local_state
+                .as_ref()
+                .map_or(false, |s| s.get_state() == PeerState::Tombstone)
+        {
+            return;
+        }
+        println!("region id: {}", region_id);
+        println!("region state: {:?}", region_info.region_local_state);
+        println!("raft state: {:?}", region_info.raft_local_state);
+        println!("apply state: {:?}", region_info.raft_apply_state);
+    }
+
+    fn dump_all_region_info(&self, skip_tombstone: bool) {
+        for region_id in self.get_all_region_ids() {
+            self.dump_region_info(region_id, skip_tombstone);
+        }
+    }
+
+    /// Prints the raft log entry at `log_index` of the region, along with the
+    /// command or conf change decoded from its data.
+    fn dump_raft_log(&self, region_id: u64, log_index: u64) {
+        let idx_key = keys::raft_log_key(region_id, log_index);
+        println!("idx_key: {}", escape(&idx_key));
+        println!("region: {}", region_id);
+        println!("log index: {}", log_index);
+        let mut entry = self.get_raft_log(region_id, log_index);
+        let data = entry.take_data();
+        println!("entry {:?}", entry);
+        println!("msg len: {}", data.len());
+        match entry.get_entry_type() {
+            EntryType::EntryNormal => {
+                let mut msg = RaftCmdRequest::new();
+                msg.merge_from_bytes(&data)
+                    .unwrap_or_else(|e| perror_and_exit("decode raft command", e));
+                println!("{:?}", msg);
+            }
+            EntryType::EntryConfChange => {
+                let mut cc = ConfChange::new();
+                cc.merge_from_bytes(&data)
+                    .unwrap_or_else(|e| perror_and_exit("decode conf change", e));
+                println!("{:?}", cc);
+            }
+        }
+    }
+
+    fn get_all_region_ids(&self) -> Vec<u64>;
+
+    /// Prints the size of the region in each of `cfs`.
+    fn dump_region_size(&self, region_id: u64, cfs: Vec<&str>) {
+        let sizes = self.get_region_size(region_id, cfs);
+        let total_size = sizes.iter().map(|&(_, size)| size).sum();
+        println!("region id: {}", region_id);
+        for (cf, size) in sizes {
+            println!("cf {} region size: {}", cf, convert_gbmb(size));
+        }
+        println!("region size: {}", convert_gbmb(total_size));
+    }
+
+    /// Prints the sizes of all regions in descending order.
+    fn dump_all_region_size(&self, cfs: Vec<&str>) {
+        let mut region_sizes: Vec<(u64, u64)> = self.get_all_region_ids()
+            .into_iter()
+            .map(|region_id| {
+                let size = self.get_region_size(region_id, cfs.clone())
+                    .into_iter()
+                    .map(|(_, size)| size)
+                    .sum();
+                (size, region_id)
+            })
+            .collect();
+        region_sizes.sort();
+        region_sizes.reverse();
+        let total_size = region_sizes.iter().map(|&(size, _)| size).sum();
+        println!("total region number: {}", region_sizes.len());
+        println!("total region size: {}", convert_gbmb(total_size));
+        for (size, region_id) in region_sizes {
+            println!("region id: {}", region_id);
+            println!("region size: {}", convert_gbmb(size));
+        }
+    }
+
+    fn get_region_size(&self, region_id: u64, cfs: Vec<&str>) -> Vec<(String, u64)>;
+
+    fn get_region_info(&self, region_id: u64) -> RegionInfo;
+
+    fn get_raft_log(&self, region_id: u64, log_index: u64) -> Entry;
+
+    fn dump_raw_scan(&self, db: DBType, cf: &str, from: Vec<u8>, to: Vec<u8>, limit: u64) {
+        for (key, value) in self.raw_scan(db, cf, from, to, limit) {
+            println!("key: {}, escaped: {}", key.to_hex().to_uppercase(), escape(&key));
+            if let Some(desc) = decode_raw_key(cf, &key) {
+                println!("\t{}", desc);
+            }
+            println!("\tvalue len: {}, value: {}", value.len(), escape(&value));
+        }
+    }
+
+    fn get_all_region_ids(&self) -> Vec<u64> {
+        self.get_all_region_states()
+            .unwrap_or_else(|e| perror_and_exit("Debugger::get_all_region_states", e))
+            .into_iter()
+            .map(|(region_id, _)| region_id)
+            .collect()
+    }
+
+    fn get_region_size(&self, region_id: u64, cfs: Vec<&str>) -> Vec<(String, u64)> {
+        self.region_size(region_id, cfs)
+            .unwrap_or_else(|e| perror_and_exit("Debugger::region_size", e))
+            .into_iter()
+            .map(|(cf, size)| (cf.to_owned(), size))
+            .collect()
+    }
+
+    fn get_region_info(&self, region_id: u64) -> RegionInfo {
+        self.region_info(region_id)
+            .unwrap_or_else(|e| perror_and_exit("Debugger::region_info", e))
+    }
+
+    fn get_raft_log(&self, region_id: u64, log_index: u64) -> Entry {
+        self.raft_log(region_id, log_index)
+            .unwrap_or_else(|e| perror_and_exit("Debugger::raft_log", e))
+    }
+
+    fn raw_scan(
+        &self,
+        db: DBType,
+        cf: &str,
+        from: Vec<u8>,
+        to: Vec<u8>,
+        limit: u64,
+    ) -> Vec<(Vec<u8>, Vec<u8>)>;
+
+    fn print_bad_regions(&self);
+
+    fn remove_fail_stores(&self, store_ids: Vec<u64>);
+
+    fn recover_mvcc(&self, region_ids: Vec<u64>, dry_run: bool);
+
+    /// Fetches the latest meta of `region_ids` from PD, then sets the local peers
+    /// to tombstone if they have been removed from the regions.
+    fn set_region_tombstone_after_remove_peer(&self, pd_urls: Vec<String>, region_ids: Vec<u64>) {
+        let pd_client = RpcClient::new(&pd_urls)
+            .unwrap_or_else(|e| perror_and_exit("RpcClient::new", e));
+        let regions = region_ids
+            .into_iter()
+            .map(|region_id| {
+                match pd_client.get_region_by_id(region_id).wait() {
+                    Ok(Some(region)) => region,
+                    Ok(None) => perror_and_exit("get_region_by_id", region_id),
+                    Err(e) => perror_and_exit("get_region_by_id", e),
+                }
+            })
+            .collect();
+        self.set_region_tombstone(regions);
+    }
+
+    fn set_region_tombstone(&self, regions: Vec<Region>);
+
+    fn modify_tikv_config(&self, module: MODULE, config_name: &str, config_value: &str);
+
+    fn set_log_level(&self, module: &str, level: &str);
+
+    fn dump_region_properties(&self, region_id: u64) {
+        for (name, value) in self.get_region_properties(region_id) {
+            println!("{}: {}", name, value);
+        }
+    }
+
+    fn get_region_properties(&self, region_id: u64) -> Vec<(String, String)>;
+
+    fn dump_metrics(&self, all: bool);
+
+    fn check_region_consistency(&self, region_id: u64);
+
+    fn dump_store_info(&self);
+
+    fn dump_cluster_info(&self);
+
+    fn inject_fail_points(&self, fail_points: Vec<(String, String)>);
+
+    fn recover_fail_points(&self, names: Vec<String>);
+
+    fn list_fail_points(&self);
+
+    /// Gets mvcc infos of encoded keys in [`from`, `to`) which are data keys.
+    fn get_mvcc_infos(&self, from: Vec<u8>, to: Vec<u8>, limit: u64) -> Vec<(Vec<u8>, MvccInfo)>;
+}
+
+impl DebugExecutor for DebugClient {
+    fn do_compact(&self, db: DBType, cf: &str, from: Vec<u8>, to: Vec<u8>) {
+        let mut req = CompactRequest::new();
+        req.set_db(db);
+        req.set_cf(cf.to_owned());
+        req.set_from_key(from);
+        req.set_to_key(to);
+        self.compact(req)
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::compact", e));
+    }
+
+    fn get_mvcc_infos(&self, from: Vec<u8>, to: Vec<u8>, limit: u64) -> Vec<(Vec<u8>, MvccInfo)> {
+        let mut req = ScanMvccRequest::new();
+        req.set_from_key(from);
+        req.set_to_key(to);
+        req.set_limit(limit);
+        self.scan_mvcc(req)
+            .map(|mut resp| (resp.take_key(), resp.take_info()))
+            .collect()
+            .wait()
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::scan_mvcc", e))
+    }
+
+    fn get_all_region_ids(&self) -> Vec<u64> {
+        perror_and_exit("get_all_region_ids", "only available in local mode");
+    }
+
+    fn get_region_size(&self, region_id: u64, cfs: Vec<&str>) -> Vec<(String, u64)> {
+        let mut req = RegionSizeRequest::new();
+        req.set_region_id(region_id);
+        req.set_cfs(cfs.into_iter().map(ToOwned::to_owned).collect());
+        let mut resp = self.region_size(req)
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::region_size", e));
+        resp.take_entries()
+            .into_iter()
+            .map(|mut entry| (entry.take_cf(), entry.get_size()))
+            .collect()
+    }
+
+    fn get_region_info(&self, region_id: u64) -> RegionInfo {
+        let mut req = RegionInfoRequest::new();
+        req.set_region_id(region_id);
+        let mut resp = self.region_info(req)
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::region_info", e));
+        let raft_local_state = if resp.has_raft_local_state() {
+            Some(resp.take_raft_local_state())
+        } else {
+            None
+        };
+        let raft_apply_state = if resp.has_raft_apply_state() {
+            Some(resp.take_raft_apply_state())
+        } else {
+            None
+        };
+        let region_local_state = if resp.has_region_local_state() {
+            Some(resp.take_region_local_state())
+        } else {
+            None
+        };
+        RegionInfo::new(raft_local_state, raft_apply_state, region_local_state)
+    }
+
+    fn get_raft_log(&self, region_id: u64, log_index: u64) -> Entry {
+        let mut req = RaftLogRequest::new();
+        req.set_region_id(region_id);
+        req.set_log_index(log_index);
+        let mut resp = self.raft_log(req)
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::raft_log", e));
+        resp.take_entry()
+    }
+
+    fn raw_scan(
+        &self,
+        _: DBType,
+        _: &str,
+        _: Vec<u8>,
+        _: Vec<u8>,
+        _: u64,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        perror_and_exit("raw_scan", "only available in local mode");
+    }
+
+    fn print_bad_regions(&self) {
+        perror_and_exit("print_bad_regions", "only available in local mode");
+    }
+
+    fn remove_fail_stores(&self, _: Vec<u64>) {
+        perror_and_exit("remove_fail_stores", "only available in local mode");
+    }
+
+    fn recover_mvcc(&self, _: Vec<u64>, _: bool) {
+        perror_and_exit("recover_mvcc", "only available in local mode");
+    }
+
+    fn set_region_tombstone(&self, _: Vec<Region>) {
+        perror_and_exit("set_region_tombstone", "only available in local mode");
+    }
+
+    fn modify_tikv_config(&self, module: MODULE, config_name: &str, config_value: &str) {
+        let mut req = ModifyTikvConfigRequest::new();
+        req.set_module(module);
+        req.set_config_name(config_name.to_owned());
+        req.set_config_value(config_value.to_owned());
+        self.modify_tikv_config(req)
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::modify_tikv_config", e));
+        println!("success");
+    }
+
+    fn set_log_level(&self, module: &str, level: &str) {
+        let mut req = SetLogLevelRequest::new();
+        req.set_module(module.to_owned());
+        req.set_level(level.to_owned());
+        self.set_log_level(req)
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::set_log_level", e));
+        println!("success");
+    }
+
+    fn dump_store_info(&self) {
+        let resp = self.get_store_info(GetStoreInfoRequest::new())
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::get_store_info", e));
+        println!("store id: {}", resp.get_store_id());
+        println!("version: {}", resp.get_version());
+        println!("git hash: {}", resp.get_git_hash());
+        println!("start time: {}", resp.get_start_time());
+    }
+
+    fn dump_cluster_info(&self) {
+        let resp = self.get_cluster_info(GetClusterInfoRequest::new())
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::get_cluster_info", e));
+        println!("cluster id: {}", resp.get_cluster_id());
+    }
+
+    fn check_region_consistency(&self, region_id: u64) {
+        let mut req = RegionConsistencyCheckRequest::new();
+        req.set_region_id(region_id);
+        self.check_region_consistency(req)
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::check_region_consistency", e));
+        println!(
+            "success! the hash of region {} has been scheduled to compute and verify, \
+             any inconsistency will be reported by the replicas",
+            region_id
         );
-    let matches = app.clone().get_matches();
+    }
 
-    let hex_key = matches.value_of("hex-to-escaped");
-    let escaped_key = matches.value_of("escaped-to-hex");
-    match (hex_key, escaped_key) {
-        (None, None) => {}
-        (Some(_), Some(_)) => panic!("hex and escaped can not be passed together!"),
-        (Some(hex), None) => {
-            println!("{}", escape(&from_hex(hex)));
-            return;
+    fn inject_fail_points(&self, fail_points: Vec<(String, String)>) {
+        for (name, actions) in fail_points {
+            let mut req = InjectFailPointRequest::new();
+            req.set_name(name);
+            req.set_actions(actions);
+            self.inject_fail_point(req)
+                .unwrap_or_else(|e| perror_and_exit("DebugClient::inject_fail_point", e));
         }
-        (None, Some(escaped)) => {
-            println!("{}", &unescape(escaped).to_hex().to_uppercase());
+        println!("success!");
+    }
+
+    fn recover_fail_points(&self, names: Vec<String>) {
+        for name in names {
+            let mut req = RecoverFailPointRequest::new();
+            req.set_name(name);
+            self.recover_fail_point(req)
+                .unwrap_or_else(|e| perror_and_exit("DebugClient::recover_fail_point", e));
+        }
+        println!("success!");
+    }
+
+    fn list_fail_points(&self) {
+        let mut resp = self.list_fail_points(ListFailPointsRequest::new())
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::list_fail_points", e));
+        for entry in resp.take_entries().into_iter() {
+            println!("{}={}", entry.get_name(), entry.get_actions());
+        }
+    }
+
+    fn dump_metrics(&self, all: bool) {
+        let mut req = GetMetricsRequest::new();
+        req.set_all(all);
+        let mut resp = self.get_metrics(req)
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::get_metrics", e));
+        println!("prometheus:\n{}", resp.take_prometheus());
+        if all {
+            println!("rocksdb kv:\n{}", resp.take_rocksdb_kv());
+            println!("rocksdb raft:\n{}", resp.take_rocksdb_raft());
+        }
+    }
+
+    fn get_region_properties(&self, region_id: u64) -> Vec<(String, String)> {
+        let mut req = GetRegionPropertiesRequest::new();
+        req.set_region_id(region_id);
+        let mut resp = self.get_region_properties(req)
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::get_region_properties", e));
+        resp.take_props()
+            .into_iter()
+            .map(|mut p| (p.take_name(), p.take_value()))
+            .collect()
+    }
+}
+
+impl DebugExecutor for Debugger {
+    fn do_compact(&self, db: DBType, cf: &str, from: Vec<u8>, to: Vec<u8>) {
+        self.compact(db, cf, &from, &to)
+            .unwrap_or_else(|e| perror_and_exit("Debugger::compact", e));
+    }
+
+    fn get_mvcc_infos(&self, from: Vec<u8>, to: Vec<u8>, limit: u64) -> Vec<(Vec<u8>, MvccInfo)> {
+        let iter = self.scan_mvcc(&from, &to, limit)
+            .unwrap_or_else(|e| perror_and_exit("Debugger::scan_mvcc", e));
+        iter.map(|r| r.unwrap_or_else(|e| perror_and_exit("Debugger::scan_mvcc", e)))
+            .collect()
+    }
+
+    fn raw_scan(
+        &self,
+        db: DBType,
+        cf: &str,
+        from: Vec<u8>,
+        to: Vec<u8>,
+        limit: u64,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.raw_scan(db, cf, &from, &to, limit)
+            .unwrap_or_else(|e| perror_and_exit("Debugger::raw_scan", e))
+    }
+
+    fn print_bad_regions(&self) {
+        let bad_regions = self.bad_regions()
+            .unwrap_or_else(|e| perror_and_exit("Debugger::bad_regions", e));
+        if bad_regions.is_empty() {
+            println!("all regions are healthy");
             return;
         }
-    };
-    let db_path = matches.value_of("db").unwrap();
-    let db = util::rocksdb::open(db_path, ALL_CFS).unwrap();
-    let raft_db = if let Some(raftdb_path) = matches.value_of("raftdb") {
-        util::rocksdb::open(raftdb_path, &[CF_DEFAULT]).unwrap()
-    } else {
-        let raftdb_path = db_path.to_owned() + "../raft";
-        util::rocksdb::open(&raftdb_path, &[CF_DEFAULT]).unwrap()
-    };
+        println!("{} regions are corrupted:", bad_regions.len());
+        for (region_id, error) in bad_regions {
+            println!("region {}: {}", region_id, error);
+        }
+    }
 
-    if let Some(matches) = matches.subcommand_matches("print") {
-        let cf_name = matches.value_of("cf").unwrap_or(CF_DEFAULT);
-        let key = String::from(matches.value_of("key").unwrap());
-        dump_raw_value(db, cf_name, key);
-    } else if let Some(matches) = matches.subcommand_matches("raft") {
-        if let Some(matches) = matches.subcommand_matches("log") {
-            let key = match matches.value_of("key") {
-                None => {
-                    let region = String::from(matches.value_of("region").unwrap());
-                    let index = String::from(matches.value_of("index").unwrap());
-                    keys::raft_log_key(region.parse().unwrap(), index.parse().unwrap())
-                }
-                Some(k) => unescape(k),
-            };
-            dump_raft_log_entry(raft_db, &key);
-        } else if let Some(matches) = matches.subcommand_matches("region") {
-            let skip_tombstone = matches.is_present("skip-tombstone");
-            match matches.value_of("region") {
-                Some(id) => {
-                    dump_region_info(&db, &raft_db, id.parse().unwrap(), skip_tombstone);
-                }
-                None => {
-                    dump_all_region_info(&db, &raft_db, skip_tombstone);
-                }
-            }
-        } else {
-            panic!("Currently only support raft log entry and scan.")
+    fn remove_fail_stores(&self, store_ids: Vec<u64>) {
+        self.remove_failed_stores(&store_ids)
+            .unwrap_or_else(|e| perror_and_exit("Debugger::remove_fail_stores", e));
+        println!("removing stores {:?} from region configurations success!", store_ids);
+    }
+
+    fn recover_mvcc(&self, region_ids: Vec<u64>, dry_run: bool) {
+        let res = self.recover_mvcc(&region_ids, dry_run)
+            .unwrap_or_else(|e| perror_and_exit("Debugger::recover_mvcc", e));
+        let action = if dry_run { "found" } else { "removed" };
+        for (region_id, stats) in res {
+            println!(
+                "region {}: scanned {} keys, {} {} orphan locks, {} dangling values, \
+                 {} records with missing values",
+                region_id,
+                stats.scanned_keys,
+                action,
+                stats.orphan_locks,
+                stats.dangling_values,
+                stats.missing_values
+            );
         }
-    } else if let Some(matches) = matches.subcommand_matches("size") {
-        let cf_name = matches.value_of("cf");
-        match matches.value_of("region") {
-            Some(id) => {
-                dump_region_size(&db, id.parse().unwrap(), cf_name);
-            }
-            None => dump_all_region_size(&db, cf_name),
+    }
+
+    fn set_region_tombstone(&self, regions: Vec<Region>) {
+        let errors = self.set_region_tombstone(regions)
+            .unwrap_or_else(|e| perror_and_exit("Debugger::set_region_tombstone", e));
+        if errors.is_empty() {
+            println!("success!");
+            return;
         }
-    } else if let Some(matches) = matches.subcommand_matches("scan") {
-        let from = String::from(matches.value_of("from").unwrap());
-        let to = matches.value_of("to").map(String::from);
-        let limit = matches.value_of("limit").map(|s| s.parse().unwrap());
-        let cf_name = matches.value_of("cf").unwrap_or(CF_DEFAULT);
-        let start_ts = matches.value_of("start_ts").map(|s| s.parse().unwrap());
-        let commit_ts = matches.value_of("commit_ts").map(|s| s.parse().unwrap());
-        if let Some(ref to) = to {
-            if to <= &from {
-                panic!("The region's start pos must greater than the end pos.")
-            }
+        for (region_id, e) in errors {
+            eprintln!("region: {}, error: {}", region_id, e);
         }
-        dump_range(db, from, to, limit, cf_name, start_ts, commit_ts);
-    } else if let Some(matches) = matches.subcommand_matches("mvcc") {
-        let cf_name = matches.value_of("cf").unwrap_or(CF_DEFAULT);
-        let key = matches.value_of("key").unwrap();
-        let key_encoded = matches.is_present("encoded");
-        let start_ts = matches.value_of("start_ts").map(|s| s.parse().unwrap());
-        let commit_ts = matches.value_of("commit_ts").map(|s| s.parse().unwrap());
-        println!("You are searching Key {}: ", key);
-        match cf_name {
-            CF_DEFAULT => {
-                dump_mvcc_default(&db, key, key_encoded, start_ts);
-            }
-            CF_LOCK => {
-                dump_mvcc_lock(&db, key, key_encoded, start_ts);
-            }
-            CF_WRITE => {
-                dump_mvcc_write(&db, key, key_encoded, start_ts, commit_ts);
-            }
-            "all" => {
-                dump_mvcc_default(&db, key, key_encoded, start_ts);
-                dump_mvcc_lock(&db, key, key_encoded, start_ts);
-                dump_mvcc_write(&db, key, key_encoded, start_ts, commit_ts);
+        process::exit(-1);
+    }
+
+    fn modify_tikv_config(&self, _: MODULE, _: &str, _: &str) {
+        perror_and_exit("modify_tikv_config", "only available in online mode");
+    }
+
+    fn set_log_level(&self, _: &str, _: &str) {
+        perror_and_exit("set_log_level", "only available in online mode");
+    }
+
+    fn dump_metrics(&self, _: bool) {
+        perror_and_exit("dump_metrics", "only available in online mode");
+    }
+
+    fn check_region_consistency(&self, _: u64) {
+        perror_and_exit("check_region_consistency", "only available in online mode");
+    }
+
+    fn dump_store_info(&self) {
+        let store_id = self.get_store_id()
+            .unwrap_or_else(|e| perror_and_exit("Debugger::get_store_id", e));
+        println!("store id: {}", store_id);
+    }
+
+    fn dump_cluster_info(&self) {
+        let cluster_id = self.get_cluster_id()
+            .unwrap_or_else(|e| perror_and_exit("Debugger::get_cluster_id", e));
+        println!("cluster id: {}", cluster_id);
+    }
+
+    fn inject_fail_points(&self, _: Vec<(String, String)>) {
+        perror_and_exit("inject_fail_points", "only available in online mode");
+    }
+
+    fn recover_fail_points(&self, _: Vec<String>) {
+        perror_and_exit("recover_fail_points", "only available in online mode");
+    }
+
+    fn list_fail_points(&self) {
+        perror_and_exit("list_fail_points", "only available in online mode");
+    }
+
+    fn get_region_properties(&self, region_id: u64) -> Vec<(String, String)> {
+        self.get_region_properties(region_id)
+            .unwrap_or_else(|e| perror_and_exit("Debugger::get_region_properties", e))
+    }
+}
+
+// Values longer than this are truncated when printed.
+const MAX_VALUE_SUMMARY_LEN: usize = 64;
+
+fn summarize_value(value: &[u8]) -> String {
+    if value.len() <= MAX_VALUE_SUMMARY_LEN {
+        return escape(value);
+    }
+    format!(
+        "{}...({} bytes)",
+        escape(&value[..MAX_VALUE_SUMMARY_LEN]),
+        value.len()
+    )
+}
+
+fn dump_mvcc_info(
+    key: &[u8],
+    info: &MvccInfo,
+    cfs: &[&str],
+    start_ts: Option<u64>,
+    commit_ts: Option<u64>,
+) {
+    println!("key: {}", escape(key));
+    if let Ok(raw) = Key::from_encoded(key.to_vec()).raw() {
+        println!("raw key: {}", escape(&raw));
+    }
+    if cfs.contains(&CF_LOCK) && info.has_lock() {
+        let lock = info.get_lock();
+        if start_ts.map_or(true, |ts| ts == lock.get_lock_version()) {
+            println!(
+                "\tlock cf: primary: {}, start_ts: {}, ttl: {}",
+                escape(lock.get_primary_lock()),
+                lock.get_lock_version(),
+                lock.get_lock_ttl()
+            );
+        }
+    }
+    if cfs.contains(&CF_DEFAULT) {
+        for value_info in info.get_values() {
+            if start_ts.map_or(true, |ts| ts == value_info.get_ts()) {
+                println!(
+                    "\tdefault cf: start_ts: {}, value: {}",
+                    value_info.get_ts(),
+                    summarize_value(value_info.get_value())
+                );
             }
-            _ => {
-                println!("The cf: {} cannot be dumped", cf_name);
-                let _ = app.print_help();
+        }
+    }
+    if cfs.contains(&CF_WRITE) {
+        for write_info in info.get_writes() {
+            if start_ts.map_or(true, |ts| ts == write_info.get_start_ts()) &&
+                commit_ts.map_or(true, |ts| ts == write_info.get_commit_ts())
+            {
+                println!(
+                    "\twrite cf: type: {:?}, start_ts: {}, commit_ts: {}",
+                    write_info.get_field_type(),
+                    write_info.get_start_ts(),
+                    write_info.get_commit_ts()
+                );
             }
         }
-    } else if let Some(matches) = matches.subcommand_matches("diff") {
-        let region_id: u64 = matches.value_of("region").unwrap().parse().unwrap();
-        let db_path2 = matches.value_of("to").unwrap();
-        let db2 = util::rocksdb::open(db_path2, ALL_CFS).unwrap();
-        dump_diff(&db, &db2, region_id);
-    } else {
-        let _ = app.print_help();
     }
-
 }
 
 pub trait MvccDeserializable: PartialEq {
@@ -367,103 +1627,108 @@ impl MvccDeserializable for Vec<u8> {
     }
 }
 
-#[derive(PartialEq, Debug)]
-pub struct MvccKv<T> {
-    key: Key,
-    value: T,
-}
-
 fn from_hex(key: &str) -> Vec<u8> {
     const HEX_PREFIX: &str = "0x";
-    let mut s = String::from(key);
-    if s.starts_with(HEX_PREFIX) {
-        let len = s.len();
-        let new_len = len.saturating_sub(HEX_PREFIX.len());
-        s.truncate(new_len);
-    }
-    s.as_str().from_hex().unwrap()
-}
-
-pub fn gen_mvcc_iter<T: MvccDeserializable>(
-    db: &DB,
-    key_prefix: &str,
-    prefix_is_encoded: bool,
-    mvcc_type: CfName,
-) -> Vec<MvccKv<T>> {
-    let encoded_prefix = if prefix_is_encoded {
-        unescape(key_prefix)
+    let s = if key.starts_with(HEX_PREFIX) {
+        &key[HEX_PREFIX.len()..]
     } else {
-        encode_bytes(unescape(key_prefix).as_slice())
+        key
     };
-    let iter_opt = IterOption::new(None, false);
-    let mut iter = db.new_iterator_cf(mvcc_type, iter_opt).unwrap();
-    iter.seek(keys::data_key(&encoded_prefix).as_slice().into());
-    if !iter.valid() {
-        vec![]
-    } else {
-        let kvs = iter.map(|s| {
-            let key = &keys::origin_key(&s.0);
-            MvccKv {
-                key: Key::from_encoded(key.to_vec()),
-                value: T::deserialize(&s.1),
+    s.from_hex()
+        .unwrap_or_else(|e| perror_and_exit("parse hex key", e))
+}
+
+/// Describes what a raw key of column family `cf` stands for, returns `None` if the
+/// key is unrecognized.
+fn decode_raw_key(cf: &str, key: &[u8]) -> Option<String> {
+    if keys::validate_data_key(key) {
+        let origin_key = keys::origin_key(key);
+        let (encoded_key, ts) = if cf == CF_LOCK || cf == CF_RAFT {
+            (origin_key, None)
+        } else {
+            match split_encoded_key_on_ts(origin_key) {
+                Ok((k, ts)) => (k, Some(ts)),
+                Err(_) => return None,
             }
+        };
+        let raw_key = match Key::from_encoded(encoded_key.to_vec()).raw() {
+            Ok(k) => k,
+            Err(_) => return None,
+        };
+        return Some(match ts {
+            Some(ts) => format!("data key: {}, ts: {}", escape(&raw_key), ts),
+            None => format!("data key: {}", escape(&raw_key)),
         });
-        kvs.take_while(|s| s.key.encoded().starts_with(&encoded_prefix))
-            .collect()
     }
-}
-
-
-fn dump_mvcc_default(db: &DB, key: &str, encoded: bool, start_ts: Option<u64>) {
-    let kvs: Vec<MvccKv<Vec<u8>>> = gen_mvcc_iter(db, key, encoded, CF_DEFAULT);
-    for kv in kvs {
-        let ts = kv.key.decode_ts().unwrap();
-        let key = kv.key.truncate_ts().unwrap();
-        if start_ts.is_none() || start_ts.unwrap() == ts {
-            let v = key.encoded();
-            println!("Key: {:?}", escape(v));
-            println!("Value: {:?}", escape(kv.value.as_slice()));
-            println!("Start_ts: {:?}", ts);
-            println!("");
-        }
+    if key == keys::STORE_IDENT_KEY {
+        return Some("store ident".to_owned());
     }
-}
-
-fn dump_mvcc_lock(db: &DB, key: &str, encoded: bool, start_ts: Option<u64>) {
-    let kvs: Vec<MvccKv<Lock>> = gen_mvcc_iter(db, key, encoded, CF_LOCK);
-    for kv in kvs {
-        let lock = &kv.value;
-        if start_ts.is_none() || start_ts.unwrap() == lock.ts {
-            println!("Key: {:?}", escape(kv.key.encoded()));
-            println!("Primary: {:?}", escape(lock.primary.as_slice()));
-            println!("Type: {:?}", lock.lock_type);
-            println!("Start_ts: {:?}", lock.ts);
-            println!("");
-        }
+    if key == keys::PREPARE_BOOTSTRAP_KEY {
+        return Some("prepare bootstrap".to_owned());
+    }
+    if let Ok((region_id, index)) = keys::decode_raft_log_key(key) {
+        return Some(format!("raft log of region {}, index {}", region_id, index));
+    }
+    if let Ok((region_id, keys::REGION_STATE_SUFFIX)) = keys::decode_region_meta_key(key) {
+        return Some(format!("region state of region {}", region_id));
     }
+    if key.len() == keys::region_raft_prefix_len() &&
+        key.starts_with(keys::REGION_RAFT_PREFIX_KEY)
+    {
+        let mut id = &key[keys::REGION_RAFT_PREFIX_KEY.len()..key.len() - 1];
+        let region_id = match id.decode_u64() {
+            Ok(id) => id,
+            Err(_) => return None,
+        };
+        let desc = match key[key.len() - 1] {
+            keys::RAFT_STATE_SUFFIX => "raft state",
+            keys::APPLY_STATE_SUFFIX => "apply state",
+            keys::SNAPSHOT_RAFT_STATE_SUFFIX => "snapshot raft state",
+            _ => return None,
+        };
+        return Some(format!("{} of region {}", desc, region_id));
+    }
+    None
 }
 
-fn dump_mvcc_write(
-    db: &DB,
-    key: &str,
-    encoded: bool,
-    start_ts: Option<u64>,
-    commit_ts: Option<u64>,
-) {
-    let kvs: Vec<MvccKv<Write>> = gen_mvcc_iter(db, key, encoded, CF_WRITE);
-    for kv in kvs {
-        let write = &kv.value;
-        let cmt_ts = kv.key.decode_ts().unwrap();
-        let key = kv.key.truncate_ts().unwrap();
-        if (start_ts.is_none() || start_ts.unwrap() == write.start_ts) &&
-            (commit_ts.is_none() || commit_ts.unwrap() == cmt_ts)
-        {
-            println!("Key: {:?}", escape(key.encoded()));
-            println!("Type: {:?}", write.write_type);
-            println!("Start_ts: {:?}", write.start_ts);
-            println!("Commit_ts: {:?}", cmt_ts);
-            println!("Short value: {:?}", write.short_value);
-            println!("");
+fn dump_snap_meta(path: &str, db: Option<&DB>) {
+    let infos = load_snapshot_metas(path)
+        .unwrap_or_else(|e| perror_and_exit("load_snapshot_metas", e));
+    for info in infos {
+        let key = &info.key;
+        println!(
+            "snapshot {}: region: {}, term: {}, index: {}, generated by this store: {}",
+            key,
+            key.region_id,
+            key.term,
+            key.idx,
+            info.is_sending
+        );
+        if let Some(db) = db {
+            let region_state_key = keys::region_state_key(key.region_id);
+            match db.get_msg_cf::<RegionLocalState>(CF_RAFT, &region_state_key) {
+                Ok(Some(state)) => {
+                    let region = state.get_region();
+                    println!("\tlocal region: {:?}", region);
+                    println!("\tlocal region epoch: {:?}", region.get_region_epoch());
+                }
+                Ok(None) => println!("\tlocal region: not found"),
+                Err(e) => println!("\tlocal region: {:?}", e),
+            }
+        }
+        for (cf_file, path) in info.meta.get_cf_files().iter().zip(&info.cf_file_paths) {
+            let status = match verify_snapshot_cf_file(path, cf_file) {
+                Ok(_) => "ok".to_owned(),
+                Err(e) => format!("{}", e),
+            };
+            println!(
+                "\tcf: {}, size: {}, checksum: {}, file: {}, check: {}",
+                cf_file.get_cf(),
+                convert_gbmb(cf_file.get_size()),
+                cf_file.get_checksum(),
+                path.display(),
+                status
+            );
         }
     }
 }
@@ -474,20 +1739,6 @@ fn dump_raw_value(db: DB, cf: &str, key: String) {
     println!("value: {}", value.map_or("None".to_owned(), |v| escape(&v)));
 }
 
-fn dump_raft_log_entry(raft_db: DB, idx_key: &[u8]) {
-    let (region_id, idx) = keys::decode_raft_log_key(idx_key).unwrap();
-    println!("idx_key: {}", escape(idx_key));
-    println!("region: {}", region_id);
-    println!("log index: {}", idx);
-    let mut ent: Entry = raft_db.get_msg(idx_key).unwrap().unwrap();
-    let data = ent.take_data();
-    println!("entry {:?}", ent);
-    let mut msg = RaftCmdRequest::new();
-    msg.merge_from_bytes(&data).unwrap();
-    println!("msg len: {}", data.len());
-    println!("{:?}", msg);
-}
-
 fn dump_diff(db: &DB, db2: &DB, region_id: u64) {
     println!("region id: {}", region_id);
     let region_state_key = keys::region_state_key(region_id);
@@ -575,28 +1826,48 @@ fn dump_diff(db: &DB, db2: &DB, region_id: u64) {
     }
 }
 
-fn dump_region_info(db: &DB, raft_db: &DB, region_id: u64, skip_tombstone: bool) {
-    let region_state_key = keys::region_state_key(region_id);
-    let region_state: Option<RegionLocalState> = db.get_msg_cf(CF_RAFT, &region_state_key).unwrap();
-    if skip_tombstone &&
-        region_state
-            .as_ref()
-            .map_or(false, |s| s.get_state() == PeerState::Tombstone)
-    {
-        return;
-    }
-    println!("region state key: {}", escape(&region_state_key));
-    println!("region state: {:?}", region_state);
+fn read_manifest(db_path: &str) -> Vec<VersionEdit> {
+    let path = manifest::current_manifest_path(db_path)
+        .unwrap_or_else(|e| perror_and_exit("current_manifest_path", e));
+    println!("manifest: {}", path.display());
+    manifest::read_manifest(&path).unwrap_or_else(|e| perror_and_exit("read_manifest", e))
+}
 
-    let raft_state_key = keys::raft_state_key(region_id);
-    println!("raft state key: {}", escape(&raft_state_key));
-    let raft_state: Option<RaftLocalState> = raft_db.get_msg(&raft_state_key).unwrap();
-    println!("raft state: {:?}", raft_state);
+fn dump_manifest(db_path: &str) {
+    for edit in read_manifest(db_path) {
+        println!("{}", edit);
+    }
+}
 
-    let apply_state_key = keys::apply_state_key(region_id);
-    println!("apply state key: {}", escape(&apply_state_key));
-    let apply_state: Option<RaftApplyState> = db.get_msg_cf(CF_RAFT, &apply_state_key).unwrap();
-    println!("apply state: {:?}", apply_state);
+/// Prints the live SST files of `cf`, or of all column families if `cf` is none.
+/// If `detail` is true, the meta of the files is printed as well, and files
+/// missing on disk or with mismatched size are reported.
+fn dump_live_files(db_path: &str, cf: Option<&str>, detail: bool) {
+    let live_files = manifest::live_files(&read_manifest(db_path));
+    if let Some(cf) = cf {
+        if !live_files.contains_key(cf) {
+            perror_and_exit("dump_live_files", format!("cf {} not found", cf));
+        }
+    }
+    for (cf_name, files) in live_files {
+        if cf.map_or(false, |cf| cf != cf_name) {
+            continue;
+        }
+        println!("cf {}: {} files", cf_name, files.len());
+        for file in files {
+            if !detail {
+                println!("\t{}", file.file_name());
+                continue;
+            }
+            println!("\t{}", file);
+            let path = Path::new(db_path).join(file.file_name());
+            match fs::metadata(&path) {
+                Ok(ref meta) if meta.len() == file.size => {}
+                Ok(meta) => println!("\tsize mismatch, {} on disk", meta.len()),
+                Err(e) => println!("\tfailed to stat {}: {:?}", path.display(), e),
+            }
+        }
+    }
 }
 
 fn convert_gbmb(mut bytes: u64) -> String {
@@ -619,77 +1890,6 @@ fn convert_gbmb(mut bytes: u64) -> String {
     format!("{}{}", gb, mb)
 }
 
-fn dump_region_size(db: &DB, region_id: u64, cf: Option<&str>) {
-    println!("region id: {}", region_id);
-    if let Some(cf_name) = cf {
-        println!("cf_name: {}", cf_name);
-    }
-    let size = get_region_size(db, region_id, cf);
-    println!("region size: {}", convert_gbmb(size));
-}
-
-fn dump_all_region_info(db: &DB, raft_db: &DB, skip_tombstone: bool) {
-    let region_ids = get_all_region_ids(db);
-    for region_id in region_ids {
-        dump_region_info(db, raft_db, region_id, skip_tombstone);
-    }
-}
-
-fn dump_all_region_size(db: &DB, cf: Option<&str>) {
-    let mut region_ids = get_all_region_ids(db);
-    let mut region_sizes: Vec<u64> = region_ids
-        .iter()
-        .map(|&region_id| get_region_size(db, region_id, cf))
-        .collect();
-    let region_number = region_ids.len();
-    let total_size = region_sizes.iter().sum();
-    let mut v: Vec<(u64, u64)> = region_sizes.drain(..).zip(region_ids.drain(..)).collect();
-    v.sort();
-    v.reverse();
-    println!("total region number: {}", region_number);
-    println!("total region size: {}", convert_gbmb(total_size));
-    for (size, id) in v {
-        println!("region_id: {}", id);
-        println!("region size: {}", convert_gbmb(size));
-    }
-}
-
-fn get_all_region_ids(db: &DB) -> Vec<u64> {
-    let start_key = keys::REGION_META_MIN_KEY;
-    let end_key = keys::REGION_META_MAX_KEY;
-    let mut region_ids = vec![];
-    db.scan_cf(CF_RAFT, start_key, end_key, false, &mut |key, _| {
-        let (region_id, suffix) = keys::decode_region_meta_key(key)?;
-        if suffix != keys::REGION_STATE_SUFFIX {
-            return Ok(true);
-        }
-        region_ids.push(region_id);
-        Ok(true)
-    }).unwrap();
-    region_ids
-}
-
-fn get_region_size(db: &DB, region_id: u64, cf: Option<&str>) -> u64 {
-    let region_state_key = keys::region_state_key(region_id);
-    let region_state: RegionLocalState =
-        db.get_msg_cf(CF_RAFT, &region_state_key).unwrap().unwrap();
-    let region = region_state.get_region();
-    let start_key = &keys::data_key(region.get_start_key());
-    let end_key = &keys::data_end_key(region.get_end_key());
-    let mut size: u64 = 0;
-    let cf_arr = match cf {
-        Some(s) => vec![s],
-        None => vec![CF_DEFAULT, CF_WRITE, CF_LOCK],
-    };
-    for cf in &cf_arr {
-        db.scan_cf(cf, start_key, end_key, true, &mut |_, v| {
-            size += v.len() as u64;
-            Ok(true)
-        }).unwrap();
-    }
-    size
-}
-
 fn parse_ts_key_from_key(encode_key: Vec<u8>) -> (u64, Vec<u8>) {
     let item_key = Key::from_encoded(encode_key);
     let ts = item_key.decode_ts().unwrap();
@@ -746,46 +1946,31 @@ fn dump_range(
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use super::*;
     use rocksdb::Writable;
+    use kvproto::kvrpcpb::Op;
     use tikv::util::codec::bytes::encode_bytes;
-    use tikv::util::codec::number::NumberEncoder;
+use tikv::util::codec::number::NumberDecoder;
     use tikv::raftstore::store::keys;
-    use tikv::storage::{ALL_CFS, CF_DEFAULT, CF_LOCK, CF_WRITE};
+    use tikv::storage::{ALL_CFS, CF_LOCK, CF_WRITE};
     use tikv::storage::mvcc::{Lock, LockType, Write, WriteType};
     use tempdir::TempDir;
     use tikv::util::rocksdb::new_engine;
     use tikv::storage::types::Key;
-    use tikv::util::escape;
 
     const PREFIX: &'static [u8] = b"k";
 
     #[test]
     fn test_ctl_mvcc() {
         let tmp_dir = TempDir::new("mvcc_tmp").expect("create mvcc_tmp dir");
-        //        let file_path = tmp_dir.path().join("tmp_db");
-        let db = new_engine(tmp_dir.path().to_str().unwrap(), ALL_CFS).unwrap();
+        let db = Arc::new(new_engine(tmp_dir.path().to_str().unwrap(), ALL_CFS).unwrap());
         let test_data = vec![(PREFIX, b"v", 5), (PREFIX, b"x", 10), (PREFIX, b"y", 15)];
         for &(k, v, ts) in &test_data {
             let key = keys::data_key(Key::from_raw(k).append_ts(ts).encoded().as_slice());
             db.put(key.as_slice(), v).unwrap();
         }
-        let kvs_gen: Vec<MvccKv<Vec<u8>>> = gen_mvcc_iter(&db, "k", false, CF_DEFAULT);
-        assert_eq!(
-            kvs_gen,
-            gen_mvcc_iter(&db, &escape(&encode_bytes(b"k")), true, CF_DEFAULT)
-        );
-        let mut test_iter = test_data.clone();
-        assert_eq!(test_iter.len(), kvs_gen.len());
-        for kv in kvs_gen {
-            let ts = kv.key.decode_ts().unwrap();
-            let key = kv.key.truncate_ts().unwrap().raw().unwrap();
-            let value = kv.value.as_slice();
-            test_iter.retain(|&s| {
-                !(&s.0[..] == key.as_slice() && &s.1[..] == value && s.2 == ts)
-            });
-        }
-        assert_eq!(test_iter.len(), 0);
 
         // Test MVCC Lock
         let test_data_lock = vec![
@@ -793,38 +1978,12 @@ mod tests {
             (b"kx", LockType::Lock, b"x", 10),
             (b"kz", LockType::Delete, b"y", 15),
         ];
-        let keys: Vec<_> = test_data_lock
-            .iter()
-            .map(|data| {
-                let encoded = encode_bytes(&data.0[..]);
-                keys::data_key(encoded.as_slice())
-            })
-            .collect();
-        let lock_value: Vec<_> = test_data_lock
-            .iter()
-            .map(|data| {
-                Lock::new(data.1, data.2.to_vec(), data.3, 0, None).to_bytes()
-            })
-            .collect();
-        let kvs = keys.iter().zip(lock_value.iter());
         let lock_cf = db.cf_handle(CF_LOCK).unwrap();
-        for (k, v) in kvs {
-            db.put_cf(lock_cf, k.as_slice(), v.as_slice()).unwrap();
-        }
-        fn assert_iter(kvs_gen: &[MvccKv<Lock>], test_data: (&[u8; 2], LockType, &[u8; 1], u64)) {
-            assert_eq!(kvs_gen.len(), 1);
-            let kv = &kvs_gen[0];
-            let lock = &kv.value;
-            let key = kv.key.raw().unwrap();
-            assert!(
-                &key[..] == test_data.0 && test_data.1 == lock.lock_type &&
-                    test_data.2 == lock.primary.as_slice() &&
-                    test_data.3 == lock.ts
-            );
+        for &(k, tp, primary, ts) in &test_data_lock {
+            let key = keys::data_key(&encode_bytes(k));
+            let value = Lock::new(tp, primary.to_vec(), ts, 0, None).to_bytes();
+            db.put_cf(lock_cf, &key, &value).unwrap();
         }
-        assert_iter(&gen_mvcc_iter(&db, "kv", false, CF_LOCK), test_data_lock[0]);
-        assert_iter(&gen_mvcc_iter(&db, "kx", false, CF_LOCK), test_data_lock[1]);
-        assert_iter(&gen_mvcc_iter(&db, "kz", false, CF_LOCK), test_data_lock[2]);
 
         // Test MVCC Write
         let test_data_write = vec![
@@ -833,40 +1992,93 @@ mod tests {
             (PREFIX, WriteType::Put, 25, 30),
             (PREFIX, WriteType::Rollback, 35, 40),
         ];
-        let keys: Vec<_> = test_data_write
+        let write_cf = db.cf_handle(CF_WRITE).unwrap();
+        for &(k, tp, start_ts, commit_ts) in &test_data_write {
+            let key = keys::data_key(Key::from_raw(k).append_ts(commit_ts).encoded());
+            let value = Write::new(tp, start_ts, None).to_bytes();
+            db.put_cf(write_cf, &key, &value).unwrap();
+        }
+
+        let debugger = Debugger::new(Engines::new(db.clone(), db.clone()));
+        let executor: &DebugExecutor = &debugger;
+
+        // All versions of `k` are merged into one record.
+        let infos = executor.get_mvcc_infos(keys::data_key(&encode_bytes(PREFIX)), vec![], 1);
+        assert_eq!(infos.len(), 1);
+        let (ref key, ref info) = infos[0];
+        assert_eq!(key, &encode_bytes(PREFIX));
+        assert!(!info.has_lock());
+        let mut values: Vec<_> = info.get_values()
             .iter()
-            .map(|data| {
-                let encoded = encode_bytes(&data.0[..]);
-                let mut d = keys::data_key(encoded.as_slice());
-                let _ = d.encode_u64_desc(data.3);
-                d
-            })
+            .map(|v| (v.get_value().to_vec(), v.get_ts()))
             .collect();
-        let write_value: Vec<_> = test_data_write
+        values.sort_by_key(|v| v.1);
+        let expect_values: Vec<_> = test_data
             .iter()
-            .map(|data| Write::new(data.1, data.2, None).to_bytes())
+            .map(|&(_, v, ts)| (v.to_vec(), ts))
             .collect();
-        let kvs = keys.iter().zip(write_value.iter());
-        let write_cf = db.cf_handle(CF_WRITE).unwrap();
-        for (k, v) in kvs {
-            db.put_cf(write_cf, k.as_slice(), v.as_slice()).unwrap();
+        assert_eq!(values, expect_values);
+        let mut writes: Vec<_> = info.get_writes()
+            .iter()
+            .map(|w| {
+                (w.get_field_type(), w.get_start_ts(), w.get_commit_ts())
+            })
+            .collect();
+        writes.sort_by_key(|w| w.1);
+        assert_eq!(
+            writes,
+            vec![
+                (Op::Del, 5, 10),
+                (Op::Lock, 15, 20),
+                (Op::Put, 25, 30),
+                (Op::Rollback, 35, 40),
+            ]
+        );
+
+        for &(k, _, primary, ts) in &test_data_lock {
+            let encoded = encode_bytes(k);
+            let infos = executor.get_mvcc_infos(keys::data_key(&encoded), vec![], 1);
+            assert_eq!(infos.len(), 1);
+            let (ref key, ref info) = infos[0];
+            assert_eq!(key, &encoded);
+            let lock = info.get_lock();
+            assert_eq!(lock.get_primary_lock(), primary);
+            assert_eq!(lock.get_lock_version(), ts);
         }
-        let kvs_gen: Vec<MvccKv<Write>> = gen_mvcc_iter(&db, "k", false, CF_WRITE);
+
+        // Scan all keys.
+        let infos = executor.get_mvcc_infos(keys::data_key(b""), vec![], 0);
+        assert_eq!(infos.len(), 1 + test_data_lock.len());
+    }
+
+    #[test]
+    fn test_summarize_value() {
+        assert_eq!(summarize_value(b"value"), "value");
+        let long_value = vec![b'a'; MAX_VALUE_SUMMARY_LEN + 1];
+        let summary = summarize_value(&long_value);
+        assert!(summary.ends_with(&format!("...({} bytes)", MAX_VALUE_SUMMARY_LEN + 1)));
+    }
+
+    #[test]
+    fn test_decode_raw_key() {
+        let key = keys::data_key(Key::from_raw(b"k").append_ts(10).encoded());
+        assert_eq!(decode_raw_key(CF_WRITE, &key).unwrap(), "data key: k, ts: 10");
+        let key = keys::data_key(Key::from_raw(b"k").encoded());
+        assert_eq!(decode_raw_key(CF_LOCK, &key).unwrap(), "data key: k");
+        assert_eq!(
+            decode_raw_key(CF_DEFAULT, &keys::raft_log_key(2, 5)).unwrap(),
+            "raft log of region 2, index 5"
+        );
+        assert_eq!(
+            decode_raw_key(CF_RAFT, &keys::apply_state_key(3)).unwrap(),
+            "apply state of region 3"
+        );
         assert_eq!(
-            kvs_gen,
-            gen_mvcc_iter(&db, &escape(&encode_bytes(b"k")), true, CF_WRITE)
+            decode_raw_key(CF_RAFT, &keys::region_state_key(4)).unwrap(),
+            "region state of region 4"
         );
-        let mut test_iter = test_data_write.clone();
-        assert_eq!(test_iter.len(), kvs_gen.len());
-        for kv in kvs_gen {
-            let write = &kv.value;
-            let ts = kv.key.decode_ts().unwrap();
-            let key = kv.key.truncate_ts().unwrap().raw().unwrap();
-            test_iter.retain(|&s| {
-                !(&s.0[..] == key.as_slice() && s.1 == write.write_type &&
-                    s.2 == write.start_ts && s.3 == ts)
-            });
-        }
-        assert_eq!(test_iter.len(), 0);
+        assert!(decode_raw_key(CF_DEFAULT, b"unknown").is_none());
+        assert_eq!(from_hex("0x7A01"), vec![b'z', 1]);
+        assert_eq!(from_hex("7a01"), vec![b'z', 1]);
     }
 }
@@ -37,10 +37,16 @@ extern crate signal;
 extern crate nix;
 extern crate prometheus;
 extern crate serde_json;
+extern crate hyper;
+extern crate kvproto;
+extern crate tempdir;
+extern crate futures;
 
 mod signal_handler;
 #[cfg(unix)]
 mod profiling;
+#[cfg(unix)]
+mod status_server;
 
 use std::error::Error;
 use std::process;
@@ -54,24 +60,46 @@ use std::env;
 use std::time::Duration;
 
 use clap::{App, Arg, ArgMatches};
+use futures::Future;
 use fs2::FileExt;
 
-use tikv::config::{MetricConfig, TiKvConfig};
-use tikv::util::{self, panic_hook, rocksdb as rocksdb_util};
+use tikv::backup::MasterKey;
+use tikv::config::{check_and_persist_critical_config, MetricConfig, TiKvConfig};
+use tikv::util::{self, panic_hook, rocksdb as rocksdb_util, ThreadGroupMonitor};
 use tikv::util::collections::HashMap;
-use tikv::util::logger::{self, StderrLogger};
-use tikv::util::file_log::RotatingFileLogger;
+use tikv::util::dynamic_config::ConfigManager;
+use tikv::util::memory;
+use tikv::util::logger::{self, AsyncLogWriter, LogWriter, StderrLogger};
+use tikv::util::file_log::{RotatingFileLogger, RotationConfig};
 use tikv::util::transport::SendCh;
-use tikv::storage::DEFAULT_ROCKSDB_SUB_DIR;
+use tikv::storage::{BatchTsoProvider, Error as StorageError, GcManager, LockObserver,
+                    MaxTsObserver, DEFAULT_ROCKSDB_SUB_DIR};
 use tikv::server::{create_raft_storage, Node, Server, DEFAULT_CLUSTER_ID};
 use tikv::server::transport::ServerRaftStoreRouter;
 use tikv::server::resolve;
 use tikv::raftstore::store::{self, Engines, SnapManager};
 use tikv::pd::{PdClient, RpcClient};
+use tikv::import::SSTImporter;
+use tikv::cdc::{CdcObserver, Endpoint as CdcEndpoint};
+use tikv::deadlock::{DeadlockObserver, Detector, DetectorScheduler};
+use tikv::resolved_ts::{Endpoint as ResolvedTsEndpoint, ResolvedTsObserver};
+use tikv::raftstore::coprocessor::{CoprocessorHost, RoleObserver};
+use tikv::util::worker::FutureWorker;
 use tikv::util::time::Monitor;
 use tikv::util::rocksdb::metrics_flusher::{MetricsFlusher, DEFAULT_FLUSER_INTERVAL};
 
 const RESERVED_OPEN_FDS: u64 = 1000;
+// Keeps the configs changed online, relative to the data dir.
+const LAST_CONFIG_FILE: &'static str = "last_tikv.toml";
+const DEFAULT_THREAD_GROUP_MONITOR_INTERVAL: u64 = 10000;
+// Observers with smaller priorities run first, the split observer is 100.
+const ROLE_OBSERVER_PRIORITY: u32 = 100;
+const CDC_OBSERVER_PRIORITY: u32 = 200;
+const RESOLVED_TS_OBSERVER_PRIORITY: u32 = 300;
+const LOCK_OBSERVER_PRIORITY: u32 = 400;
+const DEADLOCK_OBSERVER_PRIORITY: u32 = 500;
+const MAX_TS_OBSERVER_PRIORITY: u32 = 600;
+const PESSIMISTIC_LOCKS_OBSERVER_PRIORITY: u32 = 700;
 
 // A workaround for checking if log is initialized.
 static LOG_INITIALIZED: AtomicBool = ATOMIC_BOOL_INIT;
@@ -83,26 +111,38 @@ macro_rules! fatal {
         } else {
             eprintln!($lvl, $($arg)+);
         }
+        logger::flush_log();
         process::exit(1)
     })
 }
 
+fn init_log_with_writer<W: LogWriter + Sync + Send + 'static>(config: &TiKvConfig, writer: W) {
+    let res = if config.log_queue_size > 0 {
+        let writer = AsyncLogWriter::new(writer, config.log_queue_size);
+        logger::init_log_with_format(writer, config.log_level, config.log_format)
+    } else {
+        logger::init_log_with_format(writer, config.log_level, config.log_format)
+    };
+    res.unwrap_or_else(|e| fatal!("failed to initial log: {:?}", e));
+}
+
 fn init_log(config: &TiKvConfig) {
     if config.log_file.is_empty() {
-        logger::init_log(StderrLogger, config.log_level).unwrap_or_else(|e| {
-            fatal!("failed to initial log: {:?}", e);
-        });
+        init_log_with_writer(config, StderrLogger);
     } else {
-        let w = RotatingFileLogger::new(&config.log_file).unwrap_or_else(|e| {
+        let rotation = RotationConfig {
+            max_size: config.log_rotation_size.0,
+            compress: config.log_rotation_compress,
+            max_backups: config.log_max_backups,
+        };
+        let w = RotatingFileLogger::with_rotation(&config.log_file, rotation).unwrap_or_else(|e| {
             fatal!(
                 "failed to initial log with file {:?}: {:?}",
                 config.log_file,
                 e
             );
         });
-        logger::init_log(w, config.log_level).unwrap_or_else(|e| {
-            fatal!("failed to initial log: {:?}", e);
-        });
+        init_log_with_writer(config, w);
     }
     LOG_INITIALIZED.store(true, Ordering::SeqCst);
 }
@@ -122,7 +162,7 @@ fn initial_metric(cfg: &MetricConfig, node_id: Option<u64>) {
     util::monitor_threads("tikv")
         .unwrap_or_else(|e| fatal!("failed to start monitor thread: {:?}", e));
 
-    util::run_prometheus(cfg.interval.0, &cfg.address, &push_job);
+    util::run_prometheus(cfg.interval.0, &cfg.address, &push_job, &cfg.labels);
 }
 
 fn check_system_config(config: &TiKvConfig) {
@@ -147,6 +187,7 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
     let lock_path = store_path.join(Path::new("LOCK"));
     let db_path = store_path.join(Path::new(DEFAULT_ROCKSDB_SUB_DIR));
     let snap_path = store_path.join(Path::new("snap"));
+    let import_path = store_path.join(Path::new("import"));
     let raft_db_path = Path::new(&cfg.raft_store.raftdb_path);
 
     let f = File::create(lock_path.as_path()).unwrap_or_else(|e| {
@@ -158,6 +199,9 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
             store_path
         );
     }
+    if let Err(e) = check_and_persist_critical_config(cfg) {
+        fatal!("critical config check failed: {}", e);
+    }
 
     // Initialize raftstore channels.
     let mut event_loop = store::create_event_loop(&cfg.raft_store)
@@ -176,14 +220,98 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
     let mut storage = create_raft_storage(raft_router.clone(), kv_engine.clone(), &cfg.storage)
         .unwrap_or_else(|e| fatal!("failed to create raft stroage: {:?}", e));
 
+    // Create raft engine.
+    let raft_db_opts = cfg.raftdb.build_opt();
+    let raft_db_cf_opts = cfg.raftdb.build_cf_opts();
+    let raft_engine = Arc::new(
+        rocksdb_util::new_engine_opt(
+            raft_db_path.to_str().unwrap(),
+            raft_db_opts,
+            raft_db_cf_opts,
+        ).unwrap_or_else(|s| fatal!("failed to create raft engine: {:?}", s)),
+    );
+    let engines = Engines::new(kv_engine.clone(), raft_engine.clone());
+
     // Create pd client, snapshot manager, server.
     let pd_client = Arc::new(pd_client);
+    // The raw writes are ordered by the timestamps allocated from pd in batches.
+    if cfg.storage.causal_ts_batch_size > 0 {
+        let pd_client = pd_client.clone();
+        let provider = BatchTsoProvider::new(
+            cfg.storage.causal_ts_batch_size,
+            cfg.storage.causal_ts_renew_interval.0,
+            move |count| {
+                pd_client
+                    .batch_get_tso(count)
+                    .wait()
+                    .map_err(|e| StorageError::Other(format!("failed to get tso: {:?}", e).into()))
+            },
+        );
+        storage.set_causal_ts_provider(Arc::new(provider));
+    }
     let (mut worker, resolver) = resolve::new_resolver(pd_client.clone())
         .unwrap_or_else(|e| fatal!("failed to start address resolver: {:?}", e));
     let snap_mgr = SnapManager::new(
         snap_path.as_path().to_str().unwrap().to_owned(),
         Some(store_sendch),
     );
+    let importer = Arc::new(
+        SSTImporter::new(&import_path)
+            .unwrap_or_else(|e| fatal!("failed to create sst importer: {:?}", e)),
+    );
+
+    // Create cdc worker, its observer captures the changes applied by raftstore.
+    let mut cdc_worker = FutureWorker::new("cdc");
+    let cdc_observer = CdcObserver::new(cdc_worker.scheduler());
+    let mut coprocessor_host = CoprocessorHost::new();
+    coprocessor_host
+        .registry
+        .register_observer(CDC_OBSERVER_PRIORITY, Box::new(cdc_observer.clone()));
+
+    // Record the roles of the peers for the status server.
+    let role_observer = RoleObserver::new();
+    coprocessor_host
+        .registry
+        .register_observer(ROLE_OBSERVER_PRIORITY, Box::new(role_observer.clone()));
+
+    // Create resolved ts worker, which tracks the locks of the leader regions.
+    let mut resolved_ts_worker = FutureWorker::new("resolved-ts");
+    let resolved_ts_observer = ResolvedTsObserver::new(resolved_ts_worker.scheduler());
+    coprocessor_host.registry.register_observer(
+        RESOLVED_TS_OBSERVER_PRIORITY,
+        Box::new(resolved_ts_observer),
+    );
+
+    // Record the locks applied on the store for gc.
+    let lock_observer = LockObserver::new();
+    storage.set_lock_observer(lock_observer.clone());
+    coprocessor_host
+        .registry
+        .register_observer(LOCK_OBSERVER_PRIORITY, Box::new(lock_observer));
+
+    // Create deadlock detector, the leader is elected by the first region.
+    let mut deadlock_worker = FutureWorker::new("deadlock-detector");
+    let deadlock_scheduler = DetectorScheduler::new(deadlock_worker.scheduler());
+    storage.set_deadlock_detector(Arc::new(deadlock_scheduler.clone()));
+    coprocessor_host.registry.register_observer(
+        DEADLOCK_OBSERVER_PRIORITY,
+        Box::new(DeadlockObserver::new(deadlock_scheduler.clone())),
+    );
+
+    // Sync the max ts of the regions from PD when they become the leaders.
+    coprocessor_host.registry.register_observer(
+        MAX_TS_OBSERVER_PRIORITY,
+        Box::new(MaxTsObserver::new(storage.get_max_ts(), pd_client.clone())),
+    );
+
+    // Keep the pessimistic locks of the leader regions in memory.
+    if cfg.storage.in_memory_pessimistic_locks {
+        coprocessor_host.registry.register_observer(
+            PESSIMISTIC_LOCKS_OBSERVER_PRIORITY,
+            Box::new(storage.get_pessimistic_locks()),
+        );
+    }
+
     let mut server = Server::new(
         &cfg.server,
         cfg.raft_store.region_split_size.0 as usize,
@@ -192,27 +320,32 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
         snap_status_sender,
         resolver,
         snap_mgr.clone(),
+        importer.clone(),
+        &cfg.backup,
+        cdc_worker.scheduler(),
+        deadlock_scheduler.clone(),
+        Some(engines.clone()),
     ).unwrap_or_else(|e| fatal!("failed to create server: {:?}", e));
+    let config_manager = server.config_manager();
+    if let Err(e) = config_manager.load_last_config(store_path.join(LAST_CONFIG_FILE)) {
+        error!("failed to load last config, error: {:?}", e);
+    }
     let trans = server.transport();
 
-    // Create raft engine.
-    let raft_db_opts = cfg.raftdb.build_opt();
-    let raft_db_cf_opts = cfg.raftdb.build_cf_opts();
-    let raft_engine = Arc::new(
-        rocksdb_util::new_engine_opt(
-            raft_db_path.to_str().unwrap(),
-            raft_db_opts,
-            raft_db_cf_opts,
-        ).unwrap_or_else(|s| fatal!("failed to create raft engine: {:?}", s)),
-    );
     // Create node.
-    let mut node = Node::new(&mut event_loop, &cfg.server, &cfg.raft_store, pd_client);
-    let engines = Engines::new(kv_engine.clone(), raft_engine.clone());
+    let mut node = Node::new(
+        &mut event_loop,
+        &cfg.server,
+        &cfg.raft_store,
+        pd_client.clone(),
+    );
     node.start(
         event_loop,
         engines.clone(),
         trans,
         snap_mgr,
+        importer,
+        coprocessor_host,
         snap_status_receiver,
     ).unwrap_or_else(|e| fatal!("failed to start node: {:?}", e));
     initial_metric(&cfg.metric, Some(node.id()));
@@ -223,6 +356,55 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
         fatal!("failed to start storage, error: {:?}", e);
     }
 
+    // Start gc manager.
+    let mut gc_manager = GcManager::new(
+        node.id(),
+        cfg.storage.gc_poll_safe_point_interval.0,
+        storage.clone(),
+        pd_client.clone(),
+    );
+    if cfg.storage.gc_poll_safe_point_interval.0 > Duration::from_secs(0) {
+        if let Err(e) = gc_manager.start() {
+            fatal!("failed to start gc manager, error: {:?}", e);
+        }
+    }
+
+    // Start deadlock detector.
+    let detector = Detector::new(
+        node.id(),
+        pd_client.clone(),
+        deadlock_scheduler,
+        cfg.storage.wait_for_lock_timeout.0,
+    );
+    if let Err(e) = deadlock_worker.start(detector) {
+        fatal!("failed to start deadlock detector, error: {:?}", e);
+    }
+
+    // Start resolved ts.
+    let resolved_ts_endpoint = ResolvedTsEndpoint::new(
+        pd_client.clone(),
+        resolved_ts_worker.scheduler(),
+        kv_engine.clone(),
+        storage.get_engine(),
+        storage.get_max_ts(),
+    );
+    if let Err(e) = resolved_ts_worker.start(resolved_ts_endpoint) {
+        fatal!("failed to start resolved ts, error: {:?}", e);
+    }
+
+    // Start cdc.
+    let cdc_endpoint = CdcEndpoint::new(
+        pd_client,
+        cdc_worker.scheduler(),
+        kv_engine.clone(),
+        storage.get_engine(),
+        storage.get_max_ts(),
+        cdc_observer,
+    );
+    if let Err(e) = cdc_worker.start(cdc_endpoint) {
+        fatal!("failed to start cdc, error: {:?}", e);
+    }
+
     let mut metrics_flusher = MetricsFlusher::new(
         engines.clone(),
         Duration::from_millis(DEFAULT_FLUSER_INTERVAL),
@@ -233,18 +415,45 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
         error!("failed to start metrics flusher, error: {:?}", e);
     }
 
+    memory::set_soft_limit(cfg.server.memory_usage_soft_limit.0 as usize);
+
+    let mut thread_group_monitor =
+        ThreadGroupMonitor::new(Duration::from_millis(DEFAULT_THREAD_GROUP_MONITOR_INTERVAL));
+    if let Err(e) = thread_group_monitor.start() {
+        error!("failed to start thread group monitor, error: {:?}", e);
+    }
+
     // Run server.
     server
         .start(&cfg.server)
         .unwrap_or_else(|e| fatal!("failed to start server: {:?}", e));
+    let status_server = start_status_server(
+        &cfg.server.status_addr,
+        engines.clone(),
+        role_observer,
+        config_manager,
+    );
     signal_handler::handle_signal(engines, &cfg.rocksdb.backup_dir);
 
     // Stop.
     server
         .stop()
         .unwrap_or_else(|e| fatal!("failed to stop server: {:?}", e));
+    stop_status_server(status_server);
 
     metrics_flusher.stop();
+    thread_group_monitor.stop();
+    gc_manager.stop();
+
+    if let Some(Err(e)) = cdc_worker.stop().map(|j| j.join()) {
+        info!("ignore failure when stopping cdc: {:?}", e);
+    }
+    if let Some(Err(e)) = resolved_ts_worker.stop().map(|j| j.join()) {
+        info!("ignore failure when stopping resolved ts: {:?}", e);
+    }
+    if let Some(Err(e)) = deadlock_worker.stop().map(|j| j.join()) {
+        info!("ignore failure when stopping deadlock detector: {:?}", e);
+    }
 
     node.stop()
         .unwrap_or_else(|e| fatal!("failed to stop node: {:?}", e));
@@ -253,6 +462,51 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
     }
 }
 
+#[cfg(unix)]
+fn start_status_server(
+    addr: &str,
+    engines: Engines,
+    roles: RoleObserver,
+    config_manager: Arc<ConfigManager>,
+) -> Option<status_server::StatusServer> {
+    if addr.is_empty() {
+        return None;
+    }
+    let regions = status_server::RegionMetaReader::new(engines, roles);
+    let server = status_server::StatusServer::start(addr, Some(regions), Some(config_manager))
+        .unwrap_or_else(|e| fatal!("failed to start status server: {:?}", e));
+    Some(server)
+}
+
+#[cfg(unix)]
+fn stop_status_server(server: Option<status_server::StatusServer>) {
+    if let Some(server) = server {
+        server.stop();
+    }
+}
+
+#[cfg(not(unix))]
+fn start_status_server(_: &str, _: Engines, _: RoleObserver, _: Arc<ConfigManager>) {}
+
+#[cfg(not(unix))]
+fn stop_status_server(_: ()) {}
+
+// Validates the config and loads the materials it refers to, such as the master
+// key of backups, so a bad config is found before any service is started.
+fn check_config(config: &mut TiKvConfig) -> Result<(), String> {
+    if let Err(e) = config.validate() {
+        return Err(format!("invalid configuration: {}", e));
+    }
+    if let Err(e) = MasterKey::from_config(&config.backup) {
+        return Err(format!(
+            "failed to load backup.encryption-key-path {:?}: {}",
+            config.backup.encryption_key_path,
+            e
+        ));
+    }
+    Ok(())
+}
+
 fn overwrite_config_with_cmd_args(config: &mut TiKvConfig, matches: &ArgMatches) {
     if let Some(level) = matches.value_of("log-level") {
         config.log_level = logger::get_level_by_string(level);
@@ -420,6 +674,27 @@ fn main() {
                 .long("print-sample-config")
                 .help("Print a sample config to stdout"),
         )
+        .arg(
+            Arg::with_name("config-check")
+                .long("config-check")
+                .help("Check the config and print the effective config to stdout")
+                .long_help(
+                    "Check the config and the materials it refers to, such as the master key \
+                     of backups, and print the effective config to stdout, with the renamed \
+                     configs migrated and the command line arguments applied, without starting \
+                     any service",
+                ),
+        )
+        .arg(
+            Arg::with_name("dump-config")
+                .long("dump-config")
+                .help("Check the config and print the effective config to stdout")
+                .long_help(
+                    "Check the config and print the effective config to stdout, with the \
+                     renamed configs migrated, the command line arguments applied and the \
+                     derived configs resolved",
+                ),
+        )
         .get_matches();
 
     if matches.is_present("print-sample-config") {
@@ -428,16 +703,15 @@ fn main() {
         process::exit(0);
     }
 
-    let mut config = matches.value_of("config").map_or_else(
-        TiKvConfig::default,
+    let (mut config, migration_notes) = matches.value_of("config").map_or_else(
+        || (TiKvConfig::default(), vec![]),
         |path| {
             File::open(&path)
                 .map_err::<Box<Error>, _>(|e| Box::new(e))
                 .and_then(|mut f| {
                     let mut s = String::new();
                     try!(f.read_to_string(&mut s));
-                    let c = try!(toml::from_str(&s));
-                    Ok(c)
+                    TiKvConfig::from_toml(&s)
                 })
                 .unwrap_or_else(|e| {
                     fatal!("invalid configuration file {:?}: {}", path, e);
@@ -447,16 +721,41 @@ fn main() {
 
     overwrite_config_with_cmd_args(&mut config, &matches);
 
-    if let Err(e) = config.validate() {
-        fatal!("invalid configuration: {:?}", e);
+    if matches.is_present("config-check") || matches.is_present("dump-config") {
+        for note in &migration_notes {
+            eprintln!("warning: {}", note);
+        }
+        // The config is printed by `--config-check` as it's migrated, the derived
+        // configs are resolved by the check.
+        let migrated = toml::to_string_pretty(&config).unwrap();
+        if let Err(e) = check_config(&mut config) {
+            fatal!("{}", e);
+        }
+        if matches.is_present("dump-config") {
+            println!("{}", toml::to_string_pretty(&config).unwrap());
+        } else {
+            println!("{}", migrated);
+            eprintln!("config check passed");
+        }
+        process::exit(0);
+    }
+
+    if let Err(e) = check_config(&mut config) {
+        fatal!("{}", e);
     }
 
     init_log(&config);
+    for note in migration_notes {
+        warn!("{}", note);
+    }
 
     // Print version information.
     util::print_tikv_info();
 
-    panic_hook::set_exit_hook();
+    panic_hook::set_exit_hook(config.abort_on_panic, &config.storage.data_dir);
+    if let Some(mark) = panic_hook::take_panic_mark(&config.storage.data_dir) {
+        warn!("tikv panicked last time:\n{}", mark);
+    }
 
     info!(
         "using config: {}",
@@ -479,4 +778,5 @@ fn main() {
 
     let _m = Monitor::default();
     run_raft_server(pd_client, &config);
+    logger::flush_log();
 }
@@ -0,0 +1,594 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use futures::sync::mpsc::UnboundedSender;
+use kvproto::cdcpb::{ChangeDataEvent, ChangeDataRequest, Error as ErrorPb, Event, Event_Entries,
+                     Event_LogType, Event_Row, Event_Row_OpType};
+use kvproto::errorpb;
+use kvproto::kvrpcpb::{ExtraOp, IsolationLevel};
+use kvproto::raft_cmdpb::{CmdType, Request};
+use protobuf::RepeatedField;
+
+use storage::{Key, Snapshot, Statistics, CF_DEFAULT, CF_LOCK, CF_WRITE};
+use storage::mvcc::{Lock, LockType, MvccReader, Write, WriteType};
+use storage::types::split_encoded_key_on_ts;
+use util::collections::HashMap;
+use resolved_ts::Resolver;
+use super::{Error, Result};
+
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Allocates an id which is unique in the process.
+pub fn next_id() -> usize {
+    NEXT_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+/// `Downstream` is a subscriber of the changes of a region. Subscribers on the
+/// same connection share the sink.
+pub struct Downstream {
+    id: usize,
+    conn_id: usize,
+    request_id: u64,
+    // Only the changes of raw keys in `[start_key, end_key)` are sent, an empty
+    // `end_key` means no upper bound.
+    start_key: Vec<u8>,
+    end_key: Vec<u8>,
+    read_old_value: bool,
+    sink: UnboundedSender<ChangeDataEvent>,
+    // Events are held back until the incremental scan is done, if the request
+    // asks for the changes since a checkpoint.
+    pending_events: Option<Vec<Event>>,
+}
+
+impl Downstream {
+    pub fn new(
+        conn_id: usize,
+        req: &ChangeDataRequest,
+        sink: UnboundedSender<ChangeDataEvent>,
+    ) -> Downstream {
+        Downstream {
+            id: next_id(),
+            conn_id: conn_id,
+            request_id: req.get_request_id(),
+            start_key: req.get_start_key().to_vec(),
+            end_key: req.get_end_key().to_vec(),
+            read_old_value: req.get_extra_op() == ExtraOp::ReadOldValue,
+            sink: sink,
+            pending_events: if req.get_checkpoint_ts() > 0 {
+                Some(vec![])
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn get_id(&self) -> usize {
+        self.id
+    }
+
+    fn in_range(&self, key: &[u8]) -> bool {
+        key >= self.start_key.as_slice() &&
+            (self.end_key.is_empty() || key < self.end_key.as_slice())
+    }
+
+    pub fn sink_event(&mut self, event: Event) {
+        match self.pending_events {
+            Some(ref mut events) => events.push(event),
+            None => self.send_event(event),
+        }
+    }
+
+    /// Sends the rows scanned from the region and a row of `INITIALIZED`,
+    /// followed by the events held back during the scan.
+    pub fn finish_scan(&mut self, region_id: u64, mut rows: Vec<Event_Row>) {
+        let mut initialized = Event_Row::new();
+        initialized.set_field_type(Event_LogType::INITIALIZED);
+        rows.push(initialized);
+        let mut entries = Event_Entries::new();
+        entries.set_entries(RepeatedField::from_vec(rows));
+        let mut event = Event::new();
+        event.set_region_id(region_id);
+        event.set_entries(entries);
+        self.send_event(event);
+        for event in self.pending_events.take().unwrap_or_else(Vec::new) {
+            self.send_event(event);
+        }
+    }
+
+    fn send_event(&self, mut event: Event) {
+        event.set_request_id(self.request_id);
+        let mut change_data = ChangeDataEvent::new();
+        change_data.mut_events().push(event);
+        if UnboundedSender::send(&self.sink, change_data).is_err() {
+            debug!("cdc downstream {} of conn {} is closed", self.id, self.conn_id);
+        }
+    }
+
+    pub fn sink_error(&self, region_id: u64, err: ErrorPb) {
+        let mut event = Event::new();
+        event.set_region_id(region_id);
+        event.set_error(err);
+        self.send_event(event);
+    }
+}
+
+// Changes of the locks seen before the resolver is ready.
+enum LockChange {
+    Track(u64, Vec<u8>),
+    Untrack(u64, Vec<u8>),
+}
+
+/// `Delegate` captures the changes of a region and sends them to all its
+/// downstreams in the order they are applied.
+pub struct Delegate {
+    pub region_id: u64,
+    // Identifies the delegate, a region may be observed again after all the
+    // downstreams of the previous delegate are gone.
+    pub observe_id: usize,
+    downstreams: Vec<Downstream>,
+    // The resolver is built from the locks scanned from a snapshot, the lock
+    // changes applied before it's ready are replayed on it.
+    resolver: Option<Resolver>,
+    pending_changes: Vec<LockChange>,
+}
+
+impl Delegate {
+    pub fn new(region_id: u64) -> Delegate {
+        Delegate {
+            region_id: region_id,
+            observe_id: next_id(),
+            downstreams: vec![],
+            resolver: None,
+            pending_changes: vec![],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.downstreams.is_empty()
+    }
+
+    /// Adds a downstream. Returns false if there is already one on the same
+    /// connection, in which case the downstream is notified and dropped.
+    pub fn subscribe(&mut self, downstream: Downstream) -> bool {
+        if self.downstreams
+            .iter()
+            .any(|d| d.conn_id == downstream.conn_id)
+        {
+            let mut err = errorpb::Error::new();
+            err.set_message(format!("region {} is already subscribed", self.region_id));
+            downstream.sink_error(self.region_id, Error::Region(err).into());
+            return false;
+        }
+        self.downstreams.push(downstream);
+        true
+    }
+
+    /// Returns whether any downstream asks for the old values.
+    pub fn need_old_value(&self) -> bool {
+        self.downstreams.iter().any(|d| d.read_old_value)
+    }
+
+    /// Notifies the downstream of `err` and removes it.
+    pub fn unsubscribe(&mut self, downstream_id: usize, err: Error) {
+        if let Some(pos) = self.downstreams.iter().position(|d| d.id == downstream_id) {
+            let d = self.downstreams.remove(pos);
+            d.sink_error(self.region_id, err.into());
+        }
+    }
+
+    pub fn unsubscribe_conn(&mut self, conn_id: usize) {
+        self.downstreams.retain(|d| d.conn_id != conn_id);
+    }
+
+    /// Notifies all the downstreams of `err` and removes them.
+    pub fn fail(&mut self, err: Error) {
+        info!("cdc region {} failed: {:?}", self.region_id, err);
+        let err = err.into();
+        for d in mem::replace(&mut self.downstreams, vec![]) {
+            d.sink_error(self.region_id, ErrorPb::clone(&err));
+        }
+    }
+
+    pub fn on_resolver_ready(&mut self, mut resolver: Resolver) {
+        for change in self.pending_changes.drain(..) {
+            match change {
+                LockChange::Track(ts, key) => resolver.track_lock(ts, key),
+                LockChange::Untrack(ts, key) => resolver.untrack_lock(ts, &key),
+            }
+        }
+        info!(
+            "cdc region {} resolver is ready with {} locks",
+            self.region_id,
+            resolver.locks_count()
+        );
+        self.resolver = Some(resolver);
+    }
+
+    /// Delivers the rows scanned for the downstream.
+    pub fn on_scan_done(&mut self, downstream_id: usize, rows: Vec<Event_Row>) {
+        let region_id = self.region_id;
+        match self.downstreams.iter_mut().find(|d| d.id == downstream_id) {
+            Some(d) => d.finish_scan(region_id, rows),
+            None => debug!(
+                "cdc downstream {} of region {} is gone",
+                downstream_id,
+                region_id
+            ),
+        }
+    }
+
+    /// Advances the resolved ts with `min_ts` and sends it to the downstreams.
+    /// Nothing is sent before the resolver is ready.
+    pub fn on_min_ts(&mut self, min_ts: u64) {
+        let resolved_ts = match self.resolver {
+            Some(ref mut resolver) => resolver.resolve(min_ts),
+            None => return,
+        };
+        for d in &mut self.downstreams {
+            let mut event = Event::new();
+            event.set_region_id(self.region_id);
+            event.set_resolved_ts(resolved_ts);
+            d.sink_event(event);
+        }
+    }
+
+    /// Sends the changes of the write requests applied at log `index`. The old
+    /// values are read from `snapshot` if it's given.
+    pub fn on_batch(
+        &mut self,
+        index: u64,
+        requests: &[Request],
+        snapshot: Option<&Snapshot>,
+    ) -> Result<()> {
+        let mut rows = try!(self.decode_rows(requests));
+        if rows.is_empty() {
+            return Ok(());
+        }
+        if let Some(snapshot) = snapshot {
+            try!(fill_old_values(&mut rows, snapshot));
+        }
+        for d in &mut self.downstreams {
+            let rows: Vec<_> = rows.iter()
+                .filter(|r| d.in_range(r.get_key()))
+                .map(|r| {
+                    let mut row = r.clone();
+                    if !d.read_old_value {
+                        row.clear_old_value();
+                    }
+                    row
+                })
+                .collect();
+            if rows.is_empty() {
+                continue;
+            }
+            let mut entries = Event_Entries::new();
+            entries.set_entries(RepeatedField::from_vec(rows));
+            let mut event = Event::new();
+            event.set_region_id(self.region_id);
+            event.set_index(index);
+            event.set_entries(entries);
+            d.sink_event(event);
+        }
+        Ok(())
+    }
+
+    fn track_lock(&mut self, start_ts: u64, key: Vec<u8>) {
+        match self.resolver {
+            Some(ref mut resolver) => resolver.track_lock(start_ts, key),
+            None => self.pending_changes.push(LockChange::Track(start_ts, key)),
+        }
+    }
+
+    fn untrack_lock(&mut self, start_ts: u64, key: Vec<u8>) {
+        match self.resolver {
+            Some(ref mut resolver) => resolver.untrack_lock(start_ts, &key),
+            None => self.pending_changes
+                .push(LockChange::Untrack(start_ts, key)),
+        }
+    }
+
+    // Decodes the transaction events from the requests. A prewrite puts a lock,
+    // whose value is either in the lock or put in CF_DEFAULT by the same request,
+    // while a commit or rollback puts a write record.
+    fn decode_rows(&mut self, requests: &[Request]) -> Result<Vec<Event_Row>> {
+        let mut values = HashMap::default();
+        for req in requests {
+            let put = req.get_put();
+            if req.get_cmd_type() == CmdType::Put &&
+                (put.get_cf().is_empty() || put.get_cf() == CF_DEFAULT)
+            {
+                values.insert(put.get_key(), put.get_value());
+            }
+        }
+
+        let mut rows = vec![];
+        for req in requests {
+            if req.get_cmd_type() != CmdType::Put {
+                // Locks are deleted along with write records put, and the other
+                // commands don't change the data of transactions.
+                continue;
+            }
+            let put = req.get_put();
+            let mut row = Event_Row::new();
+            match put.get_cf() {
+                CF_LOCK => {
+                    let lock = try!(Lock::parse(put.get_value()));
+                    self.track_lock(lock.ts, put.get_key().to_vec());
+                    let op_type = match lock.lock_type {
+                        LockType::Put => Event_Row_OpType::PUT,
+                        LockType::Delete => Event_Row_OpType::DELETE,
+                        LockType::Lock | LockType::Pessimistic => continue,
+                    };
+                    let key = Key::from_encoded(put.get_key().to_vec());
+                    let value = match lock.short_value {
+                        Some(value) => value,
+                        None => {
+                            let value_key = key.append_ts(lock.ts);
+                            values
+                                .get(value_key.encoded().as_slice())
+                                .map_or_else(Vec::new, |v| v.to_vec())
+                        }
+                    };
+                    row.set_start_ts(lock.ts);
+                    row.set_field_type(Event_LogType::PREWRITE);
+                    row.set_op_type(op_type);
+                    row.set_key(try!(key.raw()));
+                    row.set_value(value);
+                }
+                CF_WRITE => {
+                    let (key, commit_ts) = try!(split_encoded_key_on_ts(put.get_key()));
+                    let write = try!(Write::parse(put.get_value()));
+                    self.untrack_lock(write.start_ts, key.to_vec());
+                    match write.write_type {
+                        WriteType::Put | WriteType::Delete => {
+                            let op_type = if write.write_type == WriteType::Put {
+                                Event_Row_OpType::PUT
+                            } else {
+                                Event_Row_OpType::DELETE
+                            };
+                            row.set_field_type(Event_LogType::COMMIT);
+                            row.set_op_type(op_type);
+                            row.set_commit_ts(commit_ts);
+                        }
+                        WriteType::Rollback => row.set_field_type(Event_LogType::ROLLBACK),
+                        WriteType::Lock => continue,
+                    }
+                    row.set_start_ts(write.start_ts);
+                    row.set_key(try!(Key::from_encoded(key.to_vec()).raw()));
+                }
+                _ => continue,
+            }
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+}
+
+// The old value of a change is the latest value committed before the transaction
+// starts. It never changes, as no other transaction can commit the key before
+// the lock of the transaction is gone.
+fn fill_old_values(rows: &mut [Event_Row], snapshot: &Snapshot) -> Result<()> {
+    let mut statistics = Statistics::default();
+    let mut reader = MvccReader::new(
+        snapshot,
+        &mut statistics,
+        None,
+        true,
+        None,
+        IsolationLevel::RC,
+    );
+    for row in rows {
+        match row.get_field_type() {
+            Event_LogType::PREWRITE | Event_LogType::COMMIT => {}
+            _ => continue,
+        }
+        let key = Key::from_raw(row.get_key());
+        if let Some(value) = try!(reader.get(&key, row.get_start_ts() - 1)) {
+            row.set_old_value(value);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::Stream;
+    use futures::sync::mpsc;
+    use kvproto::cdcpb::{ChangeDataRequest, Event_LogType, Event_Row_OpType};
+    use kvproto::kvrpcpb::ExtraOp;
+    use kvproto::metapb::Region;
+    use kvproto::raft_cmdpb::{CmdType, PutRequest, Request};
+    use rocksdb::Writable;
+    use tempdir::TempDir;
+
+    use raftstore::coprocessor::RegionSnapshot;
+    use raftstore::store::keys;
+    use storage::{make_key, ALL_CFS, CF_DEFAULT, CF_LOCK, CF_WRITE};
+    use storage::mvcc::{Lock, LockType, Write, WriteType};
+    use util::rocksdb;
+    use super::*;
+    use resolved_ts::Resolver;
+
+    fn new_put(cf: &str, key: Vec<u8>, value: Vec<u8>) -> Request {
+        let mut put = PutRequest::new();
+        put.set_cf(cf.to_owned());
+        put.set_key(key);
+        put.set_value(value);
+        let mut req = Request::new();
+        req.set_cmd_type(CmdType::Put);
+        req.set_put(put);
+        req
+    }
+
+    fn new_prewrite(key: &[u8], value: Option<Vec<u8>>, ts: u64) -> Vec<Request> {
+        let key = make_key(key);
+        let lock = Lock::new(LockType::Put, key.raw().unwrap(), ts, 0, value.clone());
+        let lock_req = new_put(CF_LOCK, key.encoded().clone(), lock.to_bytes());
+        if value.is_some() {
+            return vec![lock_req];
+        }
+        let value_key = key.append_ts(ts).encoded().clone();
+        vec![new_put(CF_DEFAULT, value_key, vec![b'v'; 256]), lock_req]
+    }
+
+    fn new_commit(key: &[u8], write_type: WriteType, start_ts: u64, commit_ts: u64) -> Request {
+        let key = make_key(key).append_ts(commit_ts).encoded().clone();
+        let write = Write::new(write_type, start_ts, None);
+        new_put(CF_WRITE, key, write.to_bytes())
+    }
+
+    #[test]
+    fn test_delegate() {
+        let (tx, rx) = mpsc::unbounded();
+        let mut req = ChangeDataRequest::new();
+        req.set_request_id(7);
+        req.set_start_key(b"a".to_vec());
+        req.set_end_key(b"c".to_vec());
+        let mut delegate = Delegate::new(1);
+        assert!(delegate.subscribe(Downstream::new(1, &req, tx.clone())));
+        assert!(!delegate.subscribe(Downstream::new(1, &req, tx)));
+
+        let mut reqs = new_prewrite(b"a", None, 10);
+        reqs.extend(new_prewrite(b"b", Some(b"v".to_vec()), 10));
+        // Out of the range of the downstream.
+        reqs.extend(new_prewrite(b"c", Some(b"v".to_vec()), 10));
+        delegate.on_batch(1, &reqs, None).unwrap();
+        delegate
+            .on_batch(
+                2,
+                &[
+                    new_commit(b"a", WriteType::Put, 10, 15),
+                    new_commit(b"b", WriteType::Rollback, 10, 10),
+                ],
+                None,
+            )
+            .unwrap();
+        // No resolved ts before the resolver is ready.
+        delegate.on_min_ts(20);
+        let mut resolver = Resolver::new();
+        resolver.track_lock(5, make_key(b"a").encoded().clone());
+        delegate.on_resolver_ready(resolver);
+        // The lock of "c" is left.
+        delegate.on_min_ts(20);
+        drop(delegate);
+
+        let events: Vec<_> = rx.wait()
+            .map(|e| e.unwrap().take_events().into_vec().pop().unwrap())
+            .collect();
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|e| e.get_request_id() == 7));
+        let rows = events[0].get_entries().get_entries();
+        assert_eq!(events[0].get_index(), 1);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get_field_type(), Event_LogType::PREWRITE);
+        assert_eq!(rows[0].get_op_type(), Event_Row_OpType::PUT);
+        assert_eq!(rows[0].get_key(), b"a");
+        assert_eq!(rows[0].get_value(), &[b'v'; 256][..]);
+        assert_eq!(rows[1].get_key(), b"b");
+        assert_eq!(rows[1].get_value(), b"v");
+        let rows = events[1].get_entries().get_entries();
+        assert_eq!(rows[0].get_field_type(), Event_LogType::COMMIT);
+        assert_eq!(rows[0].get_commit_ts(), 15);
+        assert_eq!(rows[1].get_field_type(), Event_LogType::ROLLBACK);
+        assert_eq!(rows[1].get_start_ts(), 10);
+        assert_eq!(events[2].get_resolved_ts(), 10);
+    }
+
+    #[test]
+    fn test_old_value() {
+        let path = TempDir::new("test-cdc-old-value").unwrap();
+        let db = rocksdb::new_engine(path.path().to_str().unwrap(), ALL_CFS).unwrap();
+        let db = Arc::new(db);
+        // "a" is committed twice before, and "b" is deleted.
+        let handle = rocksdb::get_cf_handle(&db, CF_WRITE).unwrap();
+        for &(key, write_type, start_ts, commit_ts, value) in &[
+            (b"a", WriteType::Put, 1, 2, Some(b"v1")),
+            (b"a", WriteType::Put, 3, 4, Some(b"v2")),
+            (b"b", WriteType::Put, 1, 2, Some(b"v1")),
+            (b"b", WriteType::Delete, 3, 4, None),
+        ] {
+            let key = make_key(key).append_ts(commit_ts);
+            let value = value.map(|v| v.to_vec());
+            let write = Write::new(write_type, start_ts, value);
+            db.put_cf(handle, &keys::data_key(key.encoded()), &write.to_bytes())
+                .unwrap();
+        }
+        let snapshot = RegionSnapshot::from_raw(db, Region::new());
+
+        let (tx, rx) = mpsc::unbounded();
+        let mut req = ChangeDataRequest::new();
+        let mut delegate = Delegate::new(1);
+        assert!(delegate.subscribe(Downstream::new(1, &req, tx.clone())));
+        assert!(!delegate.need_old_value());
+        req.set_extra_op(ExtraOp::ReadOldValue);
+        assert!(delegate.subscribe(Downstream::new(2, &req, tx)));
+        assert!(delegate.need_old_value());
+
+        let mut reqs = new_prewrite(b"a", Some(b"v3".to_vec()), 10);
+        reqs.extend(new_prewrite(b"b", Some(b"v3".to_vec()), 10));
+        reqs.push(new_commit(b"a", WriteType::Put, 3, 4));
+        delegate.on_batch(1, &reqs, Some(&snapshot)).unwrap();
+        drop(delegate);
+
+        let events: Vec<_> = rx.wait()
+            .map(|e| e.unwrap().take_events().into_vec().pop().unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        let rows = events[0].get_entries().get_entries();
+        assert!(rows.iter().all(|r| r.get_old_value().is_empty()));
+        let rows = events[1].get_entries().get_entries();
+        assert_eq!(rows[0].get_old_value(), b"v2");
+        assert!(rows[1].get_old_value().is_empty());
+        assert_eq!(rows[2].get_old_value(), b"v1");
+    }
+
+    #[test]
+    fn test_scan_done() {
+        let (tx, rx) = mpsc::unbounded();
+        let mut req = ChangeDataRequest::new();
+        req.set_checkpoint_ts(5);
+        let downstream = Downstream::new(1, &req, tx);
+        let downstream_id = downstream.get_id();
+        let mut delegate = Delegate::new(1);
+        assert!(delegate.subscribe(downstream));
+        delegate.on_resolver_ready(Resolver::new());
+
+        // The changes are held back until the scan is done.
+        delegate
+            .on_batch(1, &new_prewrite(b"a", Some(b"v".to_vec()), 10), None)
+            .unwrap();
+        delegate.on_min_ts(8);
+        let mut row = Event_Row::new();
+        row.set_field_type(Event_LogType::COMMITTED);
+        row.set_key(b"b".to_vec());
+        delegate.on_scan_done(downstream_id, vec![row]);
+        drop(delegate);
+
+        let events: Vec<_> = rx.wait()
+            .map(|e| e.unwrap().take_events().into_vec().pop().unwrap())
+            .collect();
+        assert_eq!(events.len(), 3);
+        let rows = events[0].get_entries().get_entries();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get_key(), b"b");
+        assert_eq!(rows[1].get_field_type(), Event_LogType::INITIALIZED);
+        let rows = events[1].get_entries().get_entries();
+        assert_eq!(rows[0].get_field_type(), Event_LogType::PREWRITE);
+        assert_eq!(events[2].get_resolved_ts(), 8);
+    }
+}
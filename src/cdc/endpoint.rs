@@ -0,0 +1,650 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use std::u64;
+use std::time::Duration;
+
+use futures::Future;
+use futures_cpupool::{Builder, CpuPool};
+use kvproto::cdcpb::{ChangeDataRequest, Event_LogType, Event_Row, Event_Row_OpType};
+use kvproto::kvrpcpb::{Context, IsolationLevel};
+use kvproto::metapb::{Peer, Region};
+use kvproto::raft_cmdpb::Request;
+use kvproto::raft_serverpb::{RegionLocalState, StoreIdent};
+use rocksdb::DB;
+use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
+
+use pd::PdClient;
+use raftstore::coprocessor::RegionSnapshot;
+use raftstore::store::keys;
+use raftstore::store::engine::Peekable;
+use storage::{Engine, Key, MaxTsTracker, ScanMode, Snapshot, Statistics, CF_RAFT};
+use storage::mvcc::{LockType, MvccReader, WriteType};
+use util::collections::HashMap;
+use util::worker::{FutureRunnable as Runnable, FutureScheduler};
+use resolved_ts::Resolver;
+use super::{Error, Result};
+use super::delegate::{Delegate, Downstream};
+use super::observer::CdcObserver;
+
+const MIN_TS_INTERVAL_SECS: u64 = 1;
+const SCAN_POOL_SIZE: usize = 2;
+const SCAN_KEYS_BATCH_SIZE: usize = 256;
+
+pub enum Deregister {
+    /// All the downstreams of the region are gone with `err`.
+    Region {
+        region_id: u64,
+        observe_id: usize,
+        err: Error,
+    },
+    /// The downstream is gone with `err`.
+    Downstream {
+        region_id: u64,
+        downstream_id: usize,
+        err: Error,
+    },
+    /// The connection is closed.
+    Conn(usize),
+}
+
+impl Display for Deregister {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Deregister::Region {
+                region_id,
+                observe_id,
+                ref err,
+            } => write!(
+                f,
+                "deregister region {} observed by {}: {:?}",
+                region_id,
+                observe_id,
+                err
+            ),
+            Deregister::Downstream {
+                region_id,
+                downstream_id,
+                ref err,
+            } => write!(
+                f,
+                "deregister downstream {} of region {}: {:?}",
+                downstream_id,
+                region_id,
+                err
+            ),
+            Deregister::Conn(conn_id) => write!(f, "deregister conn {}", conn_id),
+        }
+    }
+}
+
+pub enum Task {
+    Register {
+        request: ChangeDataRequest,
+        downstream: Downstream,
+    },
+    Deregister(Deregister),
+    ChangeLog {
+        region_id: u64,
+        index: u64,
+        requests: Vec<Request>,
+    },
+    ResolverReady {
+        region_id: u64,
+        observe_id: usize,
+        resolver: Resolver,
+    },
+    ScanDone {
+        region_id: u64,
+        downstream_id: usize,
+        rows: Vec<Event_Row>,
+    },
+    RegisterMinTsEvent,
+    MinTS { min_ts: u64 },
+}
+
+impl Display for Task {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Task::Register { ref request, .. } => write!(
+                f,
+                "register region {} for request {}",
+                request.get_region_id(),
+                request.get_request_id()
+            ),
+            Task::Deregister(ref deregister) => write!(f, "{}", deregister),
+            Task::ChangeLog {
+                region_id,
+                index,
+                ref requests,
+            } => write!(
+                f,
+                "change log of region {} at index {} with {} requests",
+                region_id,
+                index,
+                requests.len()
+            ),
+            Task::ResolverReady {
+                region_id,
+                ref resolver,
+                ..
+            } => write!(
+                f,
+                "resolver of region {} is ready with {} locks",
+                region_id,
+                resolver.locks_count()
+            ),
+            Task::ScanDone {
+                region_id,
+                downstream_id,
+                ref rows,
+            } => write!(
+                f,
+                "scanned {} rows of region {} for downstream {}",
+                rows.len(),
+                region_id,
+                downstream_id
+            ),
+            Task::RegisterMinTsEvent => write!(f, "register min ts event"),
+            Task::MinTS { min_ts } => write!(f, "min ts {}", min_ts),
+        }
+    }
+}
+
+/// `Endpoint` manages the observed regions. It receives the changes of the
+/// regions from the observer and dispatches them to the downstreams, and
+/// advances the resolved ts of the regions periodically.
+pub struct Endpoint<C> {
+    capture_regions: HashMap<u64, Delegate>,
+    scheduler: FutureScheduler<Task>,
+    observer: CdcObserver,
+    db: Arc<DB>,
+    engine: Box<Engine>,
+    // The one-phase commits write no lock, the resolved ts is advanced with the
+    // ts recorded as the max ts.
+    max_ts: MaxTsTracker,
+    pd_client: Arc<C>,
+    timer: Timer,
+    min_ts_interval: Duration,
+    is_min_ts_scheduled: bool,
+    // Locks of newly observed regions and the data since the checkpoints of
+    // the requests are scanned on the pool.
+    scan_pool: CpuPool,
+}
+
+impl<C: PdClient> Endpoint<C> {
+    /// Creates an endpoint. `db` is the kv engine, it's used to find the local
+    /// peers of the regions, while the locks are scanned from `engine`.
+    pub fn new(
+        pd_client: Arc<C>,
+        scheduler: FutureScheduler<Task>,
+        db: Arc<DB>,
+        engine: Box<Engine>,
+        max_ts: MaxTsTracker,
+        observer: CdcObserver,
+    ) -> Endpoint<C> {
+        let scan_pool = Builder::new()
+            .name_prefix(thd_name!("cdc-scan"))
+            .pool_size(SCAN_POOL_SIZE)
+            .create();
+        Endpoint {
+            capture_regions: HashMap::default(),
+            scheduler: scheduler,
+            observer: observer,
+            db: db,
+            engine: engine,
+            max_ts: max_ts,
+            pd_client: pd_client,
+            timer: Timer::default(),
+            min_ts_interval: Duration::from_secs(MIN_TS_INTERVAL_SECS),
+            is_min_ts_scheduled: false,
+            scan_pool: scan_pool,
+        }
+    }
+
+    fn on_register(&mut self, request: ChangeDataRequest, downstream: Downstream) {
+        let region_id = request.get_region_id();
+        info!(
+            "cdc register region {} for request {}",
+            region_id,
+            request.get_request_id()
+        );
+        let downstream_id = downstream.get_id();
+        let is_new = !self.capture_regions.contains_key(&region_id);
+        if is_new {
+            let delegate = Delegate::new(region_id);
+            // The region is observed before the snapshot is taken, so that no
+            // lock is missed, the ones seen twice are tracked again harmlessly.
+            self.observer
+                .subscribe_region(region_id, delegate.observe_id);
+            self.capture_regions.insert(region_id, delegate);
+        }
+        let (observe_id, subscribed) = {
+            let delegate = self.capture_regions.get_mut(&region_id).unwrap();
+            (delegate.observe_id, delegate.subscribe(downstream))
+        };
+        if !subscribed || (!is_new && request.get_checkpoint_ts() == 0) {
+            return;
+        }
+
+        let db = self.db.clone();
+        let engine = self.engine.clone();
+        let scheduler = self.scheduler.clone();
+        self.scan_pool
+            .spawn_fn(move || {
+                let tasks = scan_region(
+                    &db,
+                    engine.as_ref(),
+                    &request,
+                    observe_id,
+                    downstream_id,
+                    is_new,
+                );
+                for task in tasks {
+                    if let Err(e) = scheduler.schedule(task) {
+                        warn!("cdc failed to schedule task: {:?}", e);
+                    }
+                }
+                Ok::<_, ()>(())
+            })
+            .forget();
+    }
+
+    fn on_deregister(&mut self, deregister: Deregister) {
+        info!("cdc {}", deregister);
+        match deregister {
+            Deregister::Region {
+                region_id,
+                observe_id,
+                err,
+            } => {
+                let matched = self.capture_regions
+                    .get(&region_id)
+                    .map_or(false, |d| d.observe_id == observe_id);
+                if matched {
+                    let mut delegate = self.capture_regions.remove(&region_id).unwrap();
+                    delegate.fail(err);
+                    self.observer.unsubscribe_region(region_id, observe_id);
+                }
+            }
+            Deregister::Downstream {
+                region_id,
+                downstream_id,
+                err,
+            } => {
+                let is_empty = match self.capture_regions.get_mut(&region_id) {
+                    Some(delegate) => {
+                        delegate.unsubscribe(downstream_id, err);
+                        delegate.is_empty()
+                    }
+                    None => return,
+                };
+                if is_empty {
+                    let delegate = self.capture_regions.remove(&region_id).unwrap();
+                    self.observer
+                        .unsubscribe_region(region_id, delegate.observe_id);
+                }
+            }
+            Deregister::Conn(conn_id) => {
+                let observer = &self.observer;
+                self.capture_regions.retain(|region_id, delegate| {
+                    delegate.unsubscribe_conn(conn_id);
+                    if delegate.is_empty() {
+                        observer.unsubscribe_region(*region_id, delegate.observe_id);
+                        return false;
+                    }
+                    true
+                });
+            }
+        }
+    }
+
+    fn on_change_log(&mut self, region_id: u64, index: u64, requests: Vec<Request>) {
+        let (observe_id, res) = match self.capture_regions.get_mut(&region_id) {
+            Some(delegate) => {
+                // The changes are applied already, reading the latest data is enough.
+                let snapshot = if delegate.need_old_value() {
+                    Some(RegionSnapshot::from_raw(self.db.clone(), Region::new()))
+                } else {
+                    None
+                };
+                let snapshot = snapshot.as_ref().map(|s| s as &Snapshot);
+                let res = delegate.on_batch(index, &requests, snapshot);
+                (delegate.observe_id, res)
+            }
+            None => return,
+        };
+        if let Err(e) = res {
+            error!("cdc failed to decode changes of region {}: {:?}", region_id, e);
+            self.on_deregister(Deregister::Region {
+                region_id: region_id,
+                observe_id: observe_id,
+                err: e,
+            });
+        }
+    }
+
+    fn on_resolver_ready(&mut self, region_id: u64, observe_id: usize, resolver: Resolver) {
+        match self.capture_regions.get_mut(&region_id) {
+            Some(delegate) if delegate.observe_id == observe_id => {
+                delegate.on_resolver_ready(resolver)
+            }
+            _ => debug!("cdc region {} is not observed by {}", region_id, observe_id),
+        }
+    }
+
+    fn on_scan_done(&mut self, region_id: u64, downstream_id: usize, rows: Vec<Event_Row>) {
+        if let Some(delegate) = self.capture_regions.get_mut(&region_id) {
+            delegate.on_scan_done(downstream_id, rows);
+        }
+    }
+
+    fn on_min_ts(&mut self, min_ts: u64) {
+        for delegate in self.capture_regions.values_mut() {
+            delegate.on_min_ts(min_ts);
+        }
+    }
+
+    // Gets a timestamp from PD periodically to advance the resolved ts. Any
+    // transaction committed at or before the timestamp has put its locks before
+    // the timestamp is taken, so they are either tracked or committed already.
+    // The one-phase commits which put no lock are held by the max ts tracker.
+    fn register_min_ts_event(&self, handle: &Handle) {
+        let pd_client = self.pd_client.clone();
+        let scheduler = self.scheduler.clone();
+        let max_ts = self.max_ts.clone();
+        let f = self.timer
+            .sleep(self.min_ts_interval)
+            .then(move |_| pd_client.get_tso())
+            .then(move |res| {
+                match res {
+                    Ok(ts) => {
+                        let min_ts = max_ts.min_ts_to_resolve(ts);
+                        if let Err(e) = scheduler.schedule(Task::MinTS { min_ts: min_ts }) {
+                            warn!("cdc failed to schedule min ts: {:?}", e);
+                        }
+                    }
+                    Err(e) => warn!("cdc failed to get tso: {:?}", e),
+                }
+                // The loop stops when the worker is stopped.
+                let _ = scheduler.schedule(Task::RegisterMinTsEvent);
+                Ok(())
+            });
+        handle.spawn(f);
+    }
+}
+
+impl<C: PdClient> Runnable<Task> for Endpoint<C> {
+    fn run(&mut self, task: Task, handle: &Handle) {
+        debug!("cdc run task {}", task);
+        if !self.is_min_ts_scheduled {
+            self.is_min_ts_scheduled = true;
+            self.register_min_ts_event(handle);
+        }
+
+        match task {
+            Task::Register {
+                request,
+                downstream,
+            } => self.on_register(request, downstream),
+            Task::Deregister(deregister) => self.on_deregister(deregister),
+            Task::ChangeLog {
+                region_id,
+                index,
+                requests,
+            } => self.on_change_log(region_id, index, requests),
+            Task::ResolverReady {
+                region_id,
+                observe_id,
+                resolver,
+            } => self.on_resolver_ready(region_id, observe_id, resolver),
+            Task::ScanDone {
+                region_id,
+                downstream_id,
+                rows,
+            } => self.on_scan_done(region_id, downstream_id, rows),
+            Task::RegisterMinTsEvent => self.register_min_ts_event(handle),
+            Task::MinTS { min_ts } => self.on_min_ts(min_ts),
+        }
+    }
+
+    fn shutdown(&mut self) {
+        for (region_id, mut delegate) in self.capture_regions.drain() {
+            self.observer
+                .unsubscribe_region(region_id, delegate.observe_id);
+            delegate.fail(box_err!("cdc is stopped"));
+        }
+    }
+}
+
+// Gets the peer of the region on this store.
+fn get_local_peer(db: &DB, region_id: u64) -> Result<Peer> {
+    let store_id = match box_try!(db.get_msg::<StoreIdent>(keys::STORE_IDENT_KEY)) {
+        Some(ident) => ident.get_store_id(),
+        None => return Err(box_err!("store is not bootstrapped")),
+    };
+    let state_key = keys::region_state_key(region_id);
+    let peer = box_try!(db.get_msg_cf::<RegionLocalState>(CF_RAFT, &state_key))
+        .and_then(|state| {
+            state
+                .get_region()
+                .get_peers()
+                .iter()
+                .find(|p| p.get_store_id() == store_id)
+                .cloned()
+        });
+    match peer {
+        Some(peer) => Ok(peer),
+        None => Err(box_err!("region {} is not found on store {}", region_id, store_id)),
+    }
+}
+
+// Scans a snapshot of the region for a new downstream. The locks are scanned to
+// build the resolver if the region is newly observed, and the data committed
+// after the checkpoint ts of the request is scanned if it's set.
+fn scan_region(
+    db: &DB,
+    engine: &Engine,
+    request: &ChangeDataRequest,
+    observe_id: usize,
+    downstream_id: usize,
+    is_new: bool,
+) -> Vec<Task> {
+    let region_id = request.get_region_id();
+    let fail_region = |err: Error| {
+        Task::Deregister(Deregister::Region {
+            region_id: region_id,
+            observe_id: observe_id,
+            err: err,
+        })
+    };
+    let fail_downstream = |err: Error| {
+        Task::Deregister(Deregister::Downstream {
+            region_id: region_id,
+            downstream_id: downstream_id,
+            err: err,
+        })
+    };
+
+    let snapshot = match new_snapshot(db, engine, request) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            let task = if is_new {
+                fail_region(e)
+            } else {
+                fail_downstream(e)
+            };
+            return vec![task];
+        }
+    };
+    let mut tasks = vec![];
+    if is_new {
+        match scan_locks(snapshot.as_ref()) {
+            Ok(resolver) => tasks.push(Task::ResolverReady {
+                region_id: region_id,
+                observe_id: observe_id,
+                resolver: resolver,
+            }),
+            Err(e) => return vec![fail_region(e)],
+        }
+    }
+    if request.get_checkpoint_ts() > 0 {
+        match scan_incremental(snapshot.as_ref(), request) {
+            Ok(rows) => tasks.push(Task::ScanDone {
+                region_id: region_id,
+                downstream_id: downstream_id,
+                rows: rows,
+            }),
+            Err(e) => tasks.push(fail_downstream(e)),
+        }
+    }
+    tasks
+}
+
+// Takes a snapshot of the region through raft, so the region must be led by
+// this store and match the epoch of the request.
+fn new_snapshot(db: &DB, engine: &Engine, request: &ChangeDataRequest) -> Result<Box<Snapshot>> {
+    let mut ctx = Context::new();
+    ctx.set_region_id(request.get_region_id());
+    ctx.set_region_epoch(request.get_region_epoch().clone());
+    ctx.set_peer(try!(get_local_peer(db, request.get_region_id())));
+    engine.snapshot(&ctx).map_err(Error::from)
+}
+
+// Builds the resolver from the locks in the snapshot.
+fn scan_locks(snapshot: &Snapshot) -> Result<Resolver> {
+    let mut statistics = Statistics::default();
+    let mut reader = MvccReader::new(
+        snapshot,
+        &mut statistics,
+        Some(ScanMode::Forward),
+        false,
+        None,
+        IsolationLevel::SI,
+    );
+    let (locks, _) = try!(reader.scan_lock(None, |_| true, None));
+    let mut resolver = Resolver::new();
+    for (key, lock) in locks {
+        resolver.track_lock(lock.ts, key.encoded().clone());
+    }
+    Ok(resolver)
+}
+
+// Scans the puts and deletes committed after the checkpoint ts of the request
+// in the range of the request, followed by the prewrites not committed yet, whose
+// commits are sent as the changes applied later. The changes applied around the
+// snapshot may be sent twice.
+fn scan_incremental(snapshot: &Snapshot, request: &ChangeDataRequest) -> Result<Vec<Event_Row>> {
+    let checkpoint_ts = request.get_checkpoint_ts();
+    let start_key = Key::from_raw(request.get_start_key());
+    let end_key = if request.get_end_key().is_empty() {
+        None
+    } else {
+        Some(Key::from_raw(request.get_end_key()))
+    };
+    let in_range = |key: &Key| end_key.as_ref().map_or(true, |end| key.encoded() < end.encoded());
+
+    let mut statistics = Statistics::default();
+    let mut reader = MvccReader::new(
+        snapshot,
+        &mut statistics,
+        Some(ScanMode::Forward),
+        false,
+        None,
+        IsolationLevel::SI,
+    );
+    let mut rows = vec![];
+    let mut next_start = Some(start_key.clone());
+    while next_start.is_some() {
+        let (keys, next) = try!(reader.scan_keys(next_start, SCAN_KEYS_BATCH_SIZE));
+        next_start = next;
+        for key in keys {
+            if !in_range(&key) {
+                next_start = None;
+                break;
+            }
+            try!(scan_key_changes(&mut reader, &key, checkpoint_ts, &mut rows));
+        }
+    }
+
+    let (locks, _) = try!(reader.scan_lock(
+        Some(start_key),
+        |lock| lock.lock_type != LockType::Lock && lock.lock_type != LockType::Pessimistic,
+        None,
+    ));
+    for (key, lock) in locks {
+        if !in_range(&key) {
+            break;
+        }
+        let (op_type, value) = match lock.lock_type {
+            LockType::Put => {
+                let value = match lock.short_value {
+                    Some(value) => value,
+                    None => try!(reader.load_data(&key, lock.ts)),
+                };
+                (Event_Row_OpType::PUT, value)
+            }
+            _ => (Event_Row_OpType::DELETE, vec![]),
+        };
+        let mut row = Event_Row::new();
+        row.set_start_ts(lock.ts);
+        row.set_field_type(Event_LogType::PREWRITE);
+        row.set_op_type(op_type);
+        row.set_key(try!(key.raw()));
+        row.set_value(value);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+// Appends the puts and deletes of `key` committed after `checkpoint_ts`, in the
+// ascending order of commit_ts.
+fn scan_key_changes(
+    reader: &mut MvccReader,
+    key: &Key,
+    checkpoint_ts: u64,
+    rows: &mut Vec<Event_Row>,
+) -> Result<()> {
+    let mut changes = vec![];
+    let mut ts = u64::MAX;
+    while let Some((commit_ts, write)) = try!(reader.seek_write(key, ts)) {
+        if commit_ts <= checkpoint_ts {
+            break;
+        }
+        ts = commit_ts - 1;
+        let (op_type, value) = match write.write_type {
+            WriteType::Put => {
+                let value = match write.short_value {
+                    Some(value) => value,
+                    None => try!(reader.load_data(key, write.start_ts)),
+                };
+                (Event_Row_OpType::PUT, value)
+            }
+            WriteType::Delete => (Event_Row_OpType::DELETE, vec![]),
+            WriteType::Lock | WriteType::Rollback => continue,
+        };
+        let mut row = Event_Row::new();
+        row.set_start_ts(write.start_ts);
+        row.set_commit_ts(commit_ts);
+        row.set_field_type(Event_LogType::COMMITTED);
+        row.set_op_type(op_type);
+        row.set_key(try!(key.raw()));
+        row.set_value(value);
+        changes.push(row);
+    }
+    rows.extend(changes.into_iter().rev());
+    Ok(())
+}
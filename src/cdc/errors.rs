@@ -0,0 +1,72 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{error, result};
+
+use kvproto::cdcpb::Error as ErrorPb;
+use kvproto::errorpb;
+
+use storage::engine::Error as EngineError;
+use storage::mvcc::Error as MvccError;
+use util::codec::Error as CodecError;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Engine(err: EngineError) {
+            from()
+            cause(err)
+            display("{:?}", err)
+            description(err.description())
+        }
+        Mvcc(err: MvccError) {
+            from()
+            cause(err)
+            display("{:?}", err)
+            description(err.description())
+        }
+        Codec(err: CodecError) {
+            from()
+            cause(err)
+            display("{:?}", err)
+            description(err.description())
+        }
+        Region(err: errorpb::Error) {
+            description("region error")
+            display("{:?}", err)
+        }
+        Other(err: Box<error::Error + Sync + Send>) {
+            from()
+            cause(err.as_ref())
+            description(err.description())
+            display("{:?}", err)
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Into<ErrorPb> for Error {
+    fn into(self) -> ErrorPb {
+        let mut err = ErrorPb::new();
+        match self {
+            Error::Engine(EngineError::Request(e)) | Error::Region(e) => err.set_region_error(e),
+            e => {
+                let mut region_error = errorpb::Error::new();
+                region_error.set_message(format!("{:?}", e));
+                err.set_region_error(region_error);
+            }
+        }
+        err
+    }
+}
@@ -0,0 +1,29 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Change Data Capture captures the transaction events applied on the regions
+//! led by this store, and streams them to the subscribers in the order they
+//! are applied. Besides the prewrites, commits and rollbacks, a resolved ts is
+//! sent periodically, which promises that all the transactions committed at
+//! or before it have been sent.
+
+mod errors;
+mod delegate;
+mod observer;
+mod endpoint;
+mod service;
+
+pub use self::errors::{Error, Result};
+pub use self::observer::CdcObserver;
+pub use self::endpoint::{Endpoint, Task};
+pub use self::service::Service;
@@ -0,0 +1,181 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, RwLock};
+
+use kvproto::errorpb;
+use kvproto::raft_cmdpb::{AdminCmdType, AdminRequest, Request};
+
+use raftstore::coprocessor::{Coprocessor, ObserverContext, RegionObserver};
+use util::collections::HashMap;
+use util::worker::FutureScheduler;
+use super::Error;
+use super::endpoint::{Deregister, Task};
+
+/// `CdcObserver` sends the write requests applied on the observed regions to
+/// the cdc endpoint. It's called in the apply thread, so it only checks
+/// whether a region is observed and leaves the rest to the endpoint.
+#[derive(Clone)]
+pub struct CdcObserver {
+    scheduler: FutureScheduler<Task>,
+    // region_id -> observe_id.
+    observed_regions: Arc<RwLock<HashMap<u64, usize>>>,
+}
+
+impl CdcObserver {
+    pub fn new(scheduler: FutureScheduler<Task>) -> CdcObserver {
+        CdcObserver {
+            scheduler: scheduler,
+            observed_regions: Arc::new(RwLock::new(HashMap::default())),
+        }
+    }
+
+    pub fn subscribe_region(&self, region_id: u64, observe_id: usize) {
+        self.observed_regions
+            .write()
+            .unwrap()
+            .insert(region_id, observe_id);
+    }
+
+    /// Stops observing the region if it's still observed by `observe_id`.
+    pub fn unsubscribe_region(&self, region_id: u64, observe_id: usize) {
+        let mut regions = self.observed_regions.write().unwrap();
+        if regions.get(&region_id) == Some(&observe_id) {
+            regions.remove(&region_id);
+        }
+    }
+
+    fn observe_id(&self, region_id: u64) -> Option<usize> {
+        self.observed_regions
+            .read()
+            .unwrap()
+            .get(&region_id)
+            .cloned()
+    }
+}
+
+impl Coprocessor for CdcObserver {}
+
+impl RegionObserver for CdcObserver {
+    fn post_apply_query(&self, ctx: &mut ObserverContext, index: u64, requests: &[Request]) {
+        let region_id = ctx.region().get_id();
+        if self.observe_id(region_id).is_none() {
+            return;
+        }
+        let task = Task::ChangeLog {
+            region_id: region_id,
+            index: index,
+            requests: requests.to_vec(),
+        };
+        if let Err(e) = self.scheduler.schedule(task) {
+            warn!("cdc failed to schedule change log of region {}: {:?}", region_id, e);
+        }
+    }
+
+    fn post_apply_admin(&self, ctx: &mut ObserverContext, req: &AdminRequest) {
+        let region = ctx.region();
+        if req.get_cmd_type() != AdminCmdType::Split {
+            return;
+        }
+        let observe_id = match self.observe_id(region.get_id()) {
+            Some(id) => id,
+            None => return,
+        };
+        // The range of the region is changed, the downstreams need to subscribe
+        // the new regions again.
+        let mut err = errorpb::Error::new();
+        err.set_message(format!("region {} is split", region.get_id()));
+        err.mut_stale_epoch()
+            .mut_new_regions()
+            .push(region.clone());
+        let task = Task::Deregister(Deregister::Region {
+            region_id: region.get_id(),
+            observe_id: observe_id,
+            err: Error::Region(err),
+        });
+        if let Err(e) = self.scheduler.schedule(task) {
+            warn!("cdc failed to deregister region {}: {:?}", region.get_id(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Sender};
+    use std::time::Duration;
+
+    use kvproto::metapb::Region;
+    use kvproto::raft_cmdpb::{AdminCmdType, AdminRequest, Request};
+    use tokio_core::reactor::Handle;
+
+    use raftstore::coprocessor::{ObserverContext, RegionObserver};
+    use util::worker::{FutureRunnable, FutureWorker};
+    use super::*;
+    use super::super::endpoint::{Deregister, Task};
+
+    struct TaskRunner(Sender<Task>);
+
+    impl FutureRunnable<Task> for TaskRunner {
+        fn run(&mut self, task: Task, _: &Handle) {
+            self.0.send(task).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let mut worker = FutureWorker::new("test-cdc");
+        let (tx, rx) = mpsc::channel();
+        worker.start(TaskRunner(tx)).unwrap();
+        let observer = CdcObserver::new(worker.scheduler());
+        let mut region = Region::new();
+        region.set_id(1);
+        let mut ctx = ObserverContext::new(&region);
+        let mut split = AdminRequest::new();
+        split.set_cmd_type(AdminCmdType::Split);
+
+        // Not observed.
+        observer.post_apply_query(&mut ctx, 1, &[Request::new()]);
+        observer.post_apply_admin(&mut ctx, &split);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        observer.subscribe_region(1, 2);
+        observer.post_apply_query(&mut ctx, 2, &[Request::new()]);
+        match rx.recv_timeout(Duration::from_secs(3)).unwrap() {
+            Task::ChangeLog {
+                region_id: 1,
+                index: 2,
+                ..
+            } => {}
+            t => panic!("unexpected task {}", t),
+        }
+        // Only splits are concerned.
+        observer.post_apply_admin(&mut ctx, &AdminRequest::new());
+        observer.post_apply_admin(&mut ctx, &split);
+        match rx.recv_timeout(Duration::from_secs(3)).unwrap() {
+            Task::Deregister(Deregister::Region {
+                region_id: 1,
+                observe_id: 2,
+                ..
+            }) => {}
+            t => panic!("unexpected task {}", t),
+        }
+
+        // A stale observe id doesn't unsubscribe the region.
+        observer.unsubscribe_region(1, 1);
+        assert_eq!(observer.observe_id(1), Some(2));
+        observer.unsubscribe_region(1, 2);
+        observer.post_apply_query(&mut ctx, 3, &[Request::new()]);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        worker.stop().unwrap().join().unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc;
+use grpc::{DuplexSink, Error as GrpcError, RequestStream, RpcContext, WriteFlags};
+use kvproto::cdcpb::{ChangeDataEvent, ChangeDataRequest};
+use kvproto::cdcpb_grpc;
+
+use util::worker::FutureScheduler;
+use super::delegate::{next_id, Downstream};
+use super::endpoint::{Deregister, Task};
+
+/// Service handles the RPC messages for the `ChangeData` service.
+///
+/// Each `EventFeed` stream is a connection, on which the regions are
+/// subscribed by requests, and the events of all the regions are sent back.
+#[derive(Clone)]
+pub struct Service {
+    scheduler: FutureScheduler<Task>,
+}
+
+impl Service {
+    pub fn new(scheduler: FutureScheduler<Task>) -> Service {
+        Service {
+            scheduler: scheduler,
+        }
+    }
+}
+
+impl cdcpb_grpc::ChangeData for Service {
+    fn event_feed(
+        &self,
+        ctx: RpcContext,
+        stream: RequestStream<ChangeDataRequest>,
+        sink: DuplexSink<ChangeDataEvent>,
+    ) {
+        let conn_id = next_id();
+        let (tx, rx) = mpsc::unbounded();
+
+        let scheduler = self.scheduler.clone();
+        let recv_req = stream.for_each(move |request| {
+            let downstream = Downstream::new(conn_id, &request, tx.clone());
+            let task = Task::Register {
+                request: request,
+                downstream: downstream,
+            };
+            if let Err(e) = scheduler.schedule(task) {
+                error!("cdc failed to register for conn {}: {:?}", conn_id, e);
+            }
+            Ok(())
+        });
+        ctx.spawn(recv_req.map_err(move |e| {
+            warn!("cdc failed to receive requests of conn {}: {:?}", conn_id, e)
+        }));
+
+        // The events are sent until the client goes away, then all the regions
+        // subscribed on the connection are deregistered.
+        let scheduler = self.scheduler.clone();
+        let send_resp = sink.send_all(
+            rx.map(|e| (e, WriteFlags::default()))
+                .map_err(|_| GrpcError::RemoteStopped),
+        );
+        ctx.spawn(send_resp.then(move |res| {
+            if let Err(e) = res {
+                info!("cdc conn {} is closed: {:?}", conn_id, e);
+            }
+            let task = Task::Deregister(Deregister::Conn(conn_id));
+            if let Err(e) = scheduler.schedule(task) {
+                error!("cdc failed to deregister conn {}: {:?}", conn_id, e);
+            }
+            Ok(())
+        }));
+    }
+}
@@ -11,7 +11,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::ascii::AsciiExt;
 use std::error::Error;
+use std::fs::{self, File};
+use std::io::{Read, Write};
 use std::path::Path;
 use std::usize;
 
@@ -19,17 +22,25 @@ use log::LogLevelFilter;
 use rocksdb::{BlockBasedOptions, ColumnFamilyOptions, CompactionPriority, DBCompressionType,
               DBOptions, DBRecoveryMode};
 use sys_info;
+use toml;
 
 use server::Config as ServerConfig;
+use backup::Config as BackupConfig;
 use raftstore::store::Config as RaftstoreConfig;
 use raftstore::store::keys::region_raft_prefix_len;
-use storage::{Config as StorageConfig, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE, DEFAULT_DATA_DIR,
-              DEFAULT_ROCKSDB_SUB_DIR};
+use storage::{Config as StorageConfig, ALL_CFS, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE,
+              DEFAULT_DATA_DIR, DEFAULT_ROCKSDB_SUB_DIR};
+use util::collections::HashMap;
 use util::config::{self, compression_type_level_serde, ReadableDuration, ReadableSize, GB, KB, MB};
+use util::logger::LogFormat;
 use util::properties::{MvccPropertiesCollectorFactory, SizePropertiesCollectorFactory};
+use util::compaction_filter::WriteCompactionFilterFactory;
 use util::rocksdb::{db_exist, CFOptions, EventListener, FixedPrefixSliceTransform,
                     FixedSuffixSliceTransform, NoopSliceTransform};
 
+// Keeps the critical configs of the last run, relative to the data dir.
+const CRITICAL_CONFIG_FILE: &'static str = "critical_config.toml";
+
 const LOCKCF_MIN_MEM: usize = 256 * MB as usize;
 const LOCKCF_MAX_MEM: usize = GB as usize;
 const RAFT_MIN_MEM: usize = 256 * MB as usize;
@@ -58,6 +69,7 @@ macro_rules! cf_config {
         #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
         #[serde(default)]
         #[serde(rename_all = "kebab-case")]
+        #[serde(deny_unknown_fields)]
         pub struct $name {
             pub block_size: ReadableSize,
             pub block_cache_size: ReadableSize,
@@ -81,6 +93,58 @@ macro_rules! cf_config {
             #[serde(with = "config::compaction_pri_serde")]
             pub compaction_pri: CompactionPriority,
         }
+
+        impl $name {
+            // `path` is the path of the config in the toml file, like
+            // `rocksdb.defaultcf`.
+            fn validate(&self, path: &str) -> Result<(), Box<Error>> {
+                if self.block_size.0 == 0 {
+                    return Err(format!("{}.block-size should not be 0", path).into());
+                }
+                if self.use_bloom_filter && self.bloom_filter_bits_per_key <= 0 {
+                    return Err(format!(
+                        "{}.bloom-filter-bits-per-key should be greater than 0, got {}",
+                        path,
+                        self.bloom_filter_bits_per_key
+                    ).into());
+                }
+                if self.write_buffer_size.0 == 0 {
+                    return Err(format!("{}.write-buffer-size should not be 0", path).into());
+                }
+                if self.max_write_buffer_number <= 0 {
+                    return Err(format!(
+                        "{}.max-write-buffer-number should be greater than 0, got {}",
+                        path,
+                        self.max_write_buffer_number
+                    ).into());
+                }
+                if self.min_write_buffer_number_to_merge <= 0 ||
+                    self.min_write_buffer_number_to_merge > self.max_write_buffer_number
+                {
+                    return Err(format!(
+                        "{}.min-write-buffer-number-to-merge should be in [1, {}], got {}",
+                        path,
+                        self.max_write_buffer_number,
+                        self.min_write_buffer_number_to_merge
+                    ).into());
+                }
+                if self.level0_file_num_compaction_trigger <= 0 ||
+                    self.level0_file_num_compaction_trigger > self.level0_slowdown_writes_trigger ||
+                    self.level0_slowdown_writes_trigger > self.level0_stop_writes_trigger
+                {
+                    return Err(format!(
+                        "{0}.level0-file-num-compaction-trigger {1}, \
+                         {0}.level0-slowdown-writes-trigger {2} and \
+                         {0}.level0-stop-writes-trigger {3} should be positive and ascending",
+                        path,
+                        self.level0_file_num_compaction_trigger,
+                        self.level0_slowdown_writes_trigger,
+                        self.level0_stop_writes_trigger
+                    ).into());
+                }
+                Ok(())
+            }
+        }
     }
 }
 
@@ -210,6 +274,11 @@ impl WriteCfConfig {
         cf_opts.add_table_properties_collector_factory("tikv.mvcc-properties-collector", f);
         let f = Box::new(SizePropertiesCollectorFactory::default());
         cf_opts.add_table_properties_collector_factory("tikv.size-properties-collector", f);
+        // Drops the stale versions once the gc safe point is known.
+        let f = Box::new(WriteCompactionFilterFactory::default());
+        cf_opts
+            .set_compaction_filter_factory("tikv.write-compaction-filter-factory", f)
+            .unwrap();
         cf_opts
     }
 }
@@ -297,11 +366,12 @@ impl RaftCfConfig {
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
 pub struct DbConfig {
     #[serde(with = "config::recovery_mode_serde")]
     pub wal_recovery_mode: DBRecoveryMode,
     pub wal_dir: String,
-    pub wal_ttl_seconds: u64,
+    pub wal_ttl: ReadableDuration,
     pub wal_size_limit: ReadableSize,
     pub max_total_wal_size: ReadableSize,
     pub max_background_jobs: i32,
@@ -331,7 +401,7 @@ impl Default for DbConfig {
         DbConfig {
             wal_recovery_mode: DBRecoveryMode::PointInTime,
             wal_dir: "".to_owned(),
-            wal_ttl_seconds: 0,
+            wal_ttl: ReadableDuration::secs(0),
             wal_size_limit: ReadableSize::kb(0),
             max_total_wal_size: ReadableSize::gb(4),
             max_background_jobs: 6,
@@ -365,7 +435,7 @@ impl DbConfig {
         if !self.wal_dir.is_empty() {
             opts.set_wal_dir(&self.wal_dir);
         }
-        opts.set_wal_ttl_seconds(self.wal_ttl_seconds);
+        opts.set_wal_ttl_seconds(self.wal_ttl.as_secs());
         opts.set_wal_size_limit_mb(self.wal_size_limit.as_mb());
         opts.set_max_total_wal_size(self.max_total_wal_size.0);
         opts.set_max_background_jobs(self.max_background_jobs);
@@ -416,6 +486,16 @@ impl DbConfig {
         if !self.backup_dir.is_empty() {
             self.backup_dir = try!(config::canonicalize_path(&self.backup_dir));
         }
+        if self.max_background_jobs <= 0 {
+            return Err(format!(
+                "rocksdb.max-background-jobs should be greater than 0, got {}",
+                self.max_background_jobs
+            ).into());
+        }
+        try!(self.defaultcf.validate("rocksdb.defaultcf"));
+        try!(self.writecf.validate("rocksdb.writecf"));
+        try!(self.lockcf.validate("rocksdb.lockcf"));
+        try!(self.raftcf.validate("rocksdb.raftcf"));
         Ok(())
     }
 }
@@ -474,11 +554,12 @@ impl RaftDefaultCfConfig {
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
 pub struct RaftDbConfig {
     #[serde(with = "config::recovery_mode_serde")]
     pub wal_recovery_mode: DBRecoveryMode,
     pub wal_dir: String,
-    pub wal_ttl_seconds: u64,
+    pub wal_ttl: ReadableDuration,
     pub wal_size_limit: ReadableSize,
     pub max_total_wal_size: ReadableSize,
     pub max_manifest_file_size: ReadableSize,
@@ -503,7 +584,7 @@ impl Default for RaftDbConfig {
         RaftDbConfig {
             wal_recovery_mode: DBRecoveryMode::PointInTime,
             wal_dir: "".to_owned(),
-            wal_ttl_seconds: 0,
+            wal_ttl: ReadableDuration::secs(0),
             wal_size_limit: ReadableSize::kb(0),
             max_total_wal_size: ReadableSize::gb(4),
             max_manifest_file_size: ReadableSize::mb(20),
@@ -532,7 +613,7 @@ impl RaftDbConfig {
         if !self.wal_dir.is_empty() {
             opts.set_wal_dir(&self.wal_dir);
         }
-        opts.set_wal_ttl_seconds(self.wal_ttl_seconds);
+        opts.set_wal_ttl_seconds(self.wal_ttl.as_secs());
         opts.set_wal_size_limit_mb(self.wal_size_limit.as_mb());
         opts.set_max_total_wal_size(self.max_total_wal_size.0);
         opts.set_max_manifest_file_size(self.max_manifest_file_size.0);
@@ -570,11 +651,16 @@ impl RaftDbConfig {
     pub fn build_cf_opts(&self) -> Vec<CFOptions> {
         vec![CFOptions::new(CF_DEFAULT, self.defaultcf.build_opt())]
     }
+
+    fn validate(&self) -> Result<(), Box<Error>> {
+        self.defaultcf.validate("raftdb.defaultcf")
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Default, PartialEq, Debug)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
 pub struct PdConfig {
     pub endpoints: Vec<String>,
 }
@@ -594,10 +680,14 @@ impl PdConfig {
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
 pub struct MetricConfig {
     pub interval: ReadableDuration,
     pub address: String,
     pub job: String,
+    // Extra grouping labels of the pushed metrics besides job and instance.
+    #[serde(with = "config::order_map_serde")]
+    pub labels: HashMap<String, String>,
 }
 
 impl Default for MetricConfig {
@@ -606,10 +696,31 @@ impl Default for MetricConfig {
             interval: ReadableDuration::secs(15),
             address: "".to_owned(),
             job: "tikv".to_owned(),
+            labels: HashMap::default(),
         }
     }
 }
 
+impl MetricConfig {
+    fn validate(&self) -> Result<(), Box<Error>> {
+        if !self.address.is_empty() && self.interval.as_millis() == 0 {
+            return Err("metric.interval should not be 0 when metric.address is set".into());
+        }
+        for name in self.labels.keys() {
+            if name == "job" || name == "instance" {
+                return Err(format!("metric.labels can't contain reserved label {}", name).into());
+            }
+            let valid = name.chars().enumerate().all(|(i, c)| {
+                c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit())
+            });
+            if name.is_empty() || !valid {
+                return Err(format!("metric.labels has invalid label name {:?}", name).into());
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(remote = "LogLevelFilter")]
 #[serde(rename_all = "kebab-case")]
@@ -625,10 +736,24 @@ pub enum LogLevel {
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
 pub struct TiKvConfig {
     #[serde(with = "LogLevel")]
     pub log_level: LogLevelFilter,
     pub log_file: String,
+    pub log_format: LogFormat,
+    // The log file is rotated when it's larger than it, besides daily.
+    pub log_rotation_size: ReadableSize,
+    // Compresses the rotated log files by gzip.
+    pub log_rotation_compress: bool,
+    // The number of rotated log files to keep, 0 means all of them are kept.
+    pub log_max_backups: usize,
+    // Logs are written in a dedicated thread through a queue of this size, and
+    // dropped if the queue is full. 0 means logs are written synchronously.
+    pub log_queue_size: usize,
+    // Aborts the process on panic instead of exiting, so a core dump can be
+    // generated.
+    pub abort_on_panic: bool,
     pub server: ServerConfig,
     pub storage: StorageConfig,
     pub pd: PdConfig,
@@ -637,6 +762,7 @@ pub struct TiKvConfig {
     pub raft_store: RaftstoreConfig,
     pub rocksdb: DbConfig,
     pub raftdb: RaftDbConfig,
+    pub backup: BackupConfig,
 }
 
 impl Default for TiKvConfig {
@@ -644,6 +770,12 @@ impl Default for TiKvConfig {
         TiKvConfig {
             log_level: LogLevelFilter::Info,
             log_file: "".to_owned(),
+            log_format: LogFormat::Text,
+            log_rotation_size: ReadableSize::mb(300),
+            log_rotation_compress: false,
+            log_max_backups: 0,
+            log_queue_size: 10240,
+            abort_on_panic: false,
             server: ServerConfig::default(),
             metric: MetricConfig::default(),
             raft_store: RaftstoreConfig::default(),
@@ -651,6 +783,7 @@ impl Default for TiKvConfig {
             rocksdb: DbConfig::default(),
             raftdb: RaftDbConfig::default(),
             storage: StorageConfig::default(),
+            backup: BackupConfig::default(),
         }
     }
 }
@@ -692,9 +825,337 @@ impl TiKvConfig {
         }
 
         try!(self.rocksdb.validate());
+        try!(self.raftdb.validate());
         try!(self.server.validate());
         try!(self.raft_store.validate());
+        if self.storage.max_key_size.0 + self.storage.max_value_size.0 >=
+            self.raft_store.raft_entry_max_size.0
+        {
+            return Err(format!(
+                "the sum of storage.max-key-size {} and storage.max-value-size {} should be \
+                 less than raftstore.raft-entry-max-size {}",
+                self.storage.max_key_size.0,
+                self.storage.max_value_size.0,
+                self.raft_store.raft_entry_max_size.0
+            ).into());
+        }
         try!(self.pd.validate());
+        try!(self.backup.validate());
+        try!(self.metric.validate());
+        Ok(())
+    }
+}
+
+// How the value of a renamed config is converted.
+#[derive(Clone, Copy)]
+enum Conversion {
+    Keep,
+    // An integer of seconds to a readable duration.
+    SecsToDuration,
+}
+
+// The renamed configs, by the old paths and the new paths.
+const RENAMED_CONFIGS: &'static [(&'static str, &'static str, Conversion)] = &[
+    (
+        "rocksdb.wal-ttl-seconds",
+        "rocksdb.wal-ttl",
+        Conversion::SecsToDuration,
+    ),
+    (
+        "raftdb.wal-ttl-seconds",
+        "raftdb.wal-ttl",
+        Conversion::SecsToDuration,
+    ),
+];
+
+fn convert(value: toml::Value, conversion: Conversion) -> Result<toml::Value, String> {
+    match (conversion, value) {
+        (Conversion::Keep, v) => Ok(v),
+        (Conversion::SecsToDuration, toml::Value::Integer(secs)) if secs >= 0 => {
+            Ok(toml::Value::String(format!("{}s", secs)))
+        }
+        (Conversion::SecsToDuration, v) => Err(format!("expect seconds, got {}", v)),
+    }
+}
+
+fn take_config(root: &mut toml::Value, path: &str) -> Option<toml::Value> {
+    let mut names: Vec<_> = path.split('.').collect();
+    let last = names.pop().unwrap();
+    let mut table = root;
+    for name in names {
+        let t = table;
+        table = match *t {
+            toml::Value::Table(ref mut t) => match t.get_mut(name) {
+                Some(v) => v,
+                None => return None,
+            },
+            _ => return None,
+        };
+    }
+    match *table {
+        toml::Value::Table(ref mut t) => t.remove(last),
+        _ => None,
+    }
+}
+
+fn put_config(root: &mut toml::Value, path: &str, value: toml::Value) -> Result<(), String> {
+    let mut names: Vec<_> = path.split('.').collect();
+    let last = names.pop().unwrap();
+    let mut table = root;
+    for name in names {
+        let t = table;
+        table = match *t {
+            toml::Value::Table(ref mut t) => {
+                let empty = || toml::Value::Table(toml::value::Table::new());
+                t.entry(name.to_owned()).or_insert_with(empty)
+            }
+            _ => return Err(format!("{} is not a table", name)),
+        };
+    }
+    match *table {
+        toml::Value::Table(ref mut t) => {
+            if t.contains_key(last) {
+                return Err(format!("{} is already set", path));
+            }
+            t.insert(last.to_owned(), value);
+            Ok(())
+        }
+        _ => Err(format!("the parent of {} is not a table", path)),
+    }
+}
+
+/// Moves the renamed configs in `root` to their current names, so the config
+/// files of older versions can still be used. Returns the notes of the moved
+/// configs, which should be logged as warnings.
+pub fn migrate_config(root: &mut toml::Value) -> Result<Vec<String>, Box<Error>> {
+    let mut notes = vec![];
+    for &(old, new, conversion) in RENAMED_CONFIGS {
+        let value = match take_config(root, old) {
+            Some(v) => v,
+            None => continue,
+        };
+        let value = try!(convert(value, conversion).map_err(|e| format!("{}: {}", old, e)));
+        try!(
+            put_config(root, new, value)
+                .map_err(|e| format!("{} is renamed to {}: {}", old, new, e))
+        );
+        notes.push(format!("{} is deprecated, please use {} instead", old, new));
+    }
+    Ok(notes)
+}
+
+impl TiKvConfig {
+    /// Parses the content of a config file, the renamed configs are migrated
+    /// and the notes of them are returned too.
+    pub fn from_toml(content: &str) -> Result<(TiKvConfig, Vec<String>), Box<Error>> {
+        let mut root: toml::Value = try!(content.parse());
+        let notes = try!(migrate_config(&mut root));
+        let cfg: TiKvConfig = try!(root.try_into());
+        Ok((cfg, notes))
+    }
+}
+
+/// `CriticalConfig` is the part of the config which the existing data depends
+/// on, it can't be changed once the data is written.
+#[derive(Clone, Serialize, Deserialize, Default, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct CriticalConfig {
+    pub data_dir: String,
+    pub raftdb_path: String,
+    pub kv_wal_dir: String,
+    pub raft_wal_dir: String,
+    pub cluster_id: u64,
+    pub kv_cfs: Vec<String>,
+    pub raft_cfs: Vec<String>,
+}
+
+impl CriticalConfig {
+    pub fn from_config(cfg: &TiKvConfig) -> CriticalConfig {
+        CriticalConfig {
+            data_dir: cfg.storage.data_dir.clone(),
+            raftdb_path: cfg.raft_store.raftdb_path.clone(),
+            kv_wal_dir: cfg.rocksdb.wal_dir.clone(),
+            raft_wal_dir: cfg.raftdb.wal_dir.clone(),
+            cluster_id: cfg.server.cluster_id,
+            kv_cfs: ALL_CFS.iter().map(|cf| cf.to_string()).collect(),
+            raft_cfs: vec![CF_DEFAULT.to_owned()],
+        }
+    }
+
+    /// Checks whether the data written with `last` can be used with `self`.
+    pub fn check_compatible(&self, last: &CriticalConfig) -> Result<(), Box<Error>> {
+        let checks = vec![
+            ("storage.data-dir", &self.data_dir, &last.data_dir),
+            ("raftstore.raftdb-path", &self.raftdb_path, &last.raftdb_path),
+            ("rocksdb.wal-dir", &self.kv_wal_dir, &last.kv_wal_dir),
+            ("raftdb.wal-dir", &self.raft_wal_dir, &last.raft_wal_dir),
+        ];
+        for (name, current, last) in checks {
+            if current != last {
+                return Err(format!("{} is changed from {:?} to {:?}", name, last, current).into());
+            }
+        }
+        if self.cluster_id != last.cluster_id {
+            return Err(format!(
+                "cluster id is changed from {} to {}, the data belongs to another cluster",
+                last.cluster_id,
+                self.cluster_id
+            ).into());
+        }
+        if self.kv_cfs != last.kv_cfs || self.raft_cfs != last.raft_cfs {
+            return Err(format!(
+                "column families are changed from {:?} and {:?} to {:?} and {:?}",
+                last.kv_cfs,
+                last.raft_cfs,
+                self.kv_cfs,
+                self.raft_cfs
+            ).into());
+        }
         Ok(())
     }
 }
+
+/// Checks the critical configs against the ones of the last run kept in the
+/// data dir, and persists them for the next run. It refuses to start with a
+/// data dir written by another store or with another layout, the critical
+/// config file should be removed manually if the data dir is moved on purpose.
+pub fn check_and_persist_critical_config(cfg: &TiKvConfig) -> Result<(), Box<Error>> {
+    let current = CriticalConfig::from_config(cfg);
+    let path = Path::new(&cfg.storage.data_dir).join(CRITICAL_CONFIG_FILE);
+    if path.exists() {
+        let mut content = String::new();
+        try!(File::open(&path).and_then(|mut f| f.read_to_string(&mut content)));
+        let last: CriticalConfig = try!(toml::from_str(&content));
+        if let Err(e) = current.check_compatible(&last) {
+            return Err(format!(
+                "incompatible with the existing data in {}: {}",
+                path.display(),
+                e
+            ).into());
+        }
+        return Ok(());
+    }
+    let content = try!(toml::to_string(&current));
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut f = try!(File::create(&tmp_path));
+        try!(f.write_all(content.as_bytes()));
+        try!(f.sync_all());
+    }
+    try!(fs::rename(&tmp_path, &path));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+    use toml;
+
+    use super::*;
+
+    #[test]
+    fn test_migrate_config() {
+        let content = "[rocksdb]\nwal-ttl-seconds = 60\n[raftdb]\nwal-ttl-seconds = 1\n";
+        let (cfg, notes) = TiKvConfig::from_toml(content).unwrap();
+        assert_eq!(cfg.rocksdb.wal_ttl, ReadableDuration::minutes(1));
+        assert_eq!(cfg.raftdb.wal_ttl, ReadableDuration::secs(1));
+        assert_eq!(notes.len(), 2);
+        assert!(notes[0].contains("rocksdb.wal-ttl"), "{:?}", notes);
+
+        let (cfg, notes) = TiKvConfig::from_toml("[rocksdb]\nwal-ttl = \"1h\"\n").unwrap();
+        assert_eq!(cfg.rocksdb.wal_ttl, ReadableDuration::hours(1));
+        assert!(notes.is_empty());
+
+        for content in &[
+            "[rocksdb]\nwal-ttl-seconds = 60\nwal-ttl = \"1h\"\n",
+            "[rocksdb]\nwal-ttl-seconds = \"60\"\n",
+            "[rocksdb]\nwal-ttl-seconds = -1\n",
+        ] {
+            assert!(TiKvConfig::from_toml(content).is_err(), "{}", content);
+        }
+    }
+
+    #[test]
+    fn test_check_critical_config() {
+        let dir = TempDir::new("test_check_critical_config").unwrap();
+        let mut cfg = TiKvConfig::default();
+        cfg.storage.data_dir = format!("{}", dir.path().display());
+        cfg.server.cluster_id = 1;
+        // The first run.
+        check_and_persist_critical_config(&cfg).unwrap();
+        assert!(dir.path().join(CRITICAL_CONFIG_FILE).exists());
+        check_and_persist_critical_config(&cfg).unwrap();
+
+        let mut other_cluster = cfg.clone();
+        other_cluster.server.cluster_id = 2;
+        assert!(check_and_persist_critical_config(&other_cluster).is_err());
+
+        let mut other_wal_dir = cfg.clone();
+        other_wal_dir.rocksdb.wal_dir = "/tmp/wal".to_owned();
+        let e = check_and_persist_critical_config(&other_wal_dir).unwrap_err();
+        assert!(e.to_string().contains("rocksdb.wal-dir"), "{}", e);
+
+        // The data dir is copied to another place.
+        let current = CriticalConfig::from_config(&cfg);
+        let mut last = current.clone();
+        last.data_dir = "/tmp/another".to_owned();
+        assert!(current.check_compatible(&last).is_err());
+    }
+
+    #[test]
+    fn test_reject_unknown_fields() {
+        let cfg: TiKvConfig = toml::from_str("[server]\ngrpc-concurrency = 8\n").unwrap();
+        assert_eq!(cfg.server.grpc_concurrency, 8);
+
+        for content in &[
+            "log-lvl = \"info\"\n",
+            "[server]\ngrpc-concurency = 8\n",
+            "[rocksdb.defaultcf]\nblock-sise = \"64KB\"\n",
+        ] {
+            let e = toml::from_str::<TiKvConfig>(content).unwrap_err();
+            assert!(e.to_string().contains("unknown field"), "{}: {}", content, e);
+        }
+        let e = toml::from_str::<TiKvConfig>("[server]\ngrpc-concurency = 8\n").unwrap_err();
+        assert!(e.to_string().contains("server"), "{}", e);
+    }
+
+    #[test]
+    fn test_validate_cf_config() {
+        let cfg = DefaultCfConfig::default();
+        cfg.validate("rocksdb.defaultcf").unwrap();
+
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.max_write_buffer_number = 0;
+        let e = invalid_cfg.validate("rocksdb.defaultcf").unwrap_err();
+        assert!(e.to_string().contains("rocksdb.defaultcf.max-write-buffer-number"));
+
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.min_write_buffer_number_to_merge = cfg.max_write_buffer_number + 1;
+        assert!(invalid_cfg.validate("rocksdb.defaultcf").is_err());
+
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.level0_slowdown_writes_trigger = cfg.level0_stop_writes_trigger + 1;
+        assert!(invalid_cfg.validate("rocksdb.defaultcf").is_err());
+
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.use_bloom_filter = true;
+        invalid_cfg.bloom_filter_bits_per_key = 0;
+        assert!(invalid_cfg.validate("rocksdb.defaultcf").is_err());
+    }
+
+    #[test]
+    fn test_validate_metric_config() {
+        let mut cfg = MetricConfig::default();
+        cfg.validate().unwrap();
+
+        cfg.labels.insert("zone_1".to_owned(), "us-west-1".to_owned());
+        cfg.validate().unwrap();
+
+        for name in &["job", "instance", "", "1zone", "zone-1"] {
+            let mut invalid_cfg = cfg.clone();
+            invalid_cfg.labels.insert(name.to_string(), "v".to_owned());
+            assert!(invalid_cfg.validate().is_err(), "{}", name);
+        }
+    }
+}
@@ -25,6 +25,7 @@ use coprocessor::select::xeval::EvalContext;
 use coprocessor::{Error, Result};
 use coprocessor::endpoint::{get_chunk, get_pk, to_pb_error, ReqContext};
 use storage::{Snapshot, SnapshotStore, Statistics};
+use util::memory::{MemoryTrace, COPROCESSOR_MEMORY};
 
 use super::executor::{AggregationExecutor, Executor as DAGExecutor, IndexScanExecutor,
                       LimitExecutor, Row, SelectionExecutor, TableScanExecutor, TopNExecutor};
@@ -62,10 +63,12 @@ impl<'s> DAGContext<'s> {
         try!(self.validate_dag());
         let mut exec = try!(self.build_dag(statistics));
         let mut chunks = vec![];
+        let mut mem_trace = MemoryTrace::new(&COPROCESSOR_MEMORY);
         loop {
             match exec.next() {
                 Ok(Some(row)) => {
                     try!(self.req_ctx.check_if_outdated());
+                    mem_trace.consume(row.data.value.len());
                     let chunk = get_chunk(&mut chunks);
                     let length = chunk.get_rows_data().len();
                     if self.has_aggr {
@@ -14,6 +14,8 @@
 use std::usize;
 use std::time::Duration;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::fmt::{self, Debug, Display, Formatter};
 
 use tipb::select::{self, Chunk, DAGRequest, SelectRequest};
@@ -22,15 +24,21 @@ use tipb::schema::ColumnInfo;
 use protobuf::Message as PbMsg;
 use kvproto::coprocessor::{KeyRange, Request, Response};
 use kvproto::errorpb::{self, ServerIsBusy};
-use kvproto::kvrpcpb::{CommandPri, IsolationLevel};
+use kvproto::kvrpcpb::{CommandPri, ExecDetails, IsolationLevel, TimeDetail};
 
-use util::time::{duration_to_sec, Instant};
+use util::time::{duration_to_sec, thread_cpu_time, Instant};
+use util::memory;
+use util::metrics::REQUEST_ERROR_COUNTER_VEC;
+use util::tracker::{self, Tracker};
+use util::dynamic_config::ConfigHandler;
 use util::worker::{BatchRunnable, Scheduler};
 use util::collections::HashMap;
 use util::threadpool::{Context, ThreadPool, ThreadPoolBuilder};
 use server::{Config, OnResponse};
-use storage::{self, engine, Engine, Snapshot, Statistics, StatisticsSummary};
+use storage::{self, engine, hot_keys, Engine, Key, MaxTsTracker, Snapshot, Statistics,
+              StatisticsSummary};
 use storage::engine::Error as EngineError;
+use storage::txn::Error as TxnError;
 
 use super::codec::mysql;
 use super::codec::datum::Datum;
@@ -49,6 +57,7 @@ pub const BATCH_ROW_COUNT: usize = 64;
 // be timeout already, so it can be safely aborted.
 const REQUEST_MAX_HANDLE_SECS: u64 = 60;
 // If handle time is larger than the lower bound, the query is considered as slow query.
+// It's replaced by `end-point-slow-log-threshold` when the request is scheduled.
 const SLOW_QUERY_LOWER_BOUND: f64 = 1.0; // 1 second.
 
 const DEFAULT_ERROR_CODE: i32 = 1;
@@ -61,13 +70,59 @@ const ENDPOINT_IS_BUSY: &'static str = "endpoint is busy";
 
 pub struct Host {
     engine: Box<Engine>,
+    // The reads are recorded, so the transactions committed without the commit
+    // ts from the client won't be committed before them.
+    max_ts: MaxTsTracker,
     sched: Scheduler<Task>,
     reqs: HashMap<u64, Vec<RequestTask>>,
     last_req_id: u64,
     pool: ThreadPool<CopContext>,
     low_priority_pool: ThreadPool<CopContext>,
     high_priority_pool: ThreadPool<CopContext>,
-    max_running_task_count: usize,
+    // It can be changed by `EndPointConfigHandler`.
+    max_running_task_count: Arc<AtomicUsize>,
+    slow_log_threshold: f64,
+}
+
+/// `EndPointConfigHandler` changes the limits of a running end point.
+pub struct EndPointConfigHandler {
+    max_running_task_count: Arc<AtomicUsize>,
+    // The pools are resized by the end point itself.
+    sched: Mutex<Scheduler<Task>>,
+}
+
+impl ConfigHandler for EndPointConfigHandler {
+    fn update(&self, name: &str, value: &str) -> ::std::result::Result<(), String> {
+        match name {
+            "end-point-max-tasks" => {
+                let count: usize = try!(
+                    value
+                        .parse()
+                        .map_err(|e| format!("invalid max tasks {:?}: {}", value, e))
+                );
+                if count == 0 {
+                    return Err("end-point-max-tasks should be greater than 0".to_owned());
+                }
+                self.max_running_task_count.store(count, Ordering::Relaxed);
+                Ok(())
+            }
+            "end-point-concurrency" => {
+                let count: usize = try!(
+                    value
+                        .parse()
+                        .map_err(|e| format!("invalid concurrency {:?}: {}", value, e))
+                );
+                if count == 0 {
+                    return Err("end-point-concurrency should be greater than 0".to_owned());
+                }
+                let sched = self.sched.lock().unwrap();
+                sched
+                    .schedule(Task::ResizePools(count))
+                    .map_err(|e| format!("failed to resize end point pools: {}", e))
+            }
+            _ => Err(format!("unknown coprocessor config {:?}", name)),
+        }
+    }
 }
 
 #[derive(Default)]
@@ -113,13 +168,20 @@ impl Context for CopContext {
 }
 
 impl Host {
-    pub fn new(engine: Box<Engine>, scheduler: Scheduler<Task>, cfg: &Config) -> Host {
+    pub fn new(
+        engine: Box<Engine>,
+        max_ts: MaxTsTracker,
+        scheduler: Scheduler<Task>,
+        cfg: &Config,
+    ) -> Host {
         Host {
             engine: engine,
+            max_ts: max_ts,
             sched: scheduler,
             reqs: HashMap::default(),
             last_req_id: 0,
-            max_running_task_count: cfg.end_point_max_tasks,
+            max_running_task_count: Arc::new(AtomicUsize::new(cfg.end_point_max_tasks)),
+            slow_log_threshold: duration_to_sec(cfg.end_point_slow_log_threshold.0),
             pool: ThreadPoolBuilder::with_default_factory(thd_name!("endpoint-normal-pool"))
                 .thread_count(cfg.end_point_concurrency)
                 .build(),
@@ -134,6 +196,31 @@ impl Host {
         }
     }
 
+    pub fn config_handler(&self) -> EndPointConfigHandler {
+        EndPointConfigHandler {
+            max_running_task_count: self.max_running_task_count.clone(),
+            sched: Mutex::new(self.sched.clone()),
+        }
+    }
+
+    // All the pools are resized even if some fail, so they stay the same size.
+    fn resize_pools(&mut self, count: usize) {
+        let mut failed = false;
+        for pool in &mut [
+            &mut self.pool,
+            &mut self.low_priority_pool,
+            &mut self.high_priority_pool,
+        ] {
+            if let Err(e) = pool.resize(count) {
+                error!("failed to resize end point pool: {}", e);
+                failed = true;
+            }
+        }
+        if !failed {
+            info!("end point pools are resized to {} threads", count);
+        }
+    }
+
     fn running_task_count(&self) -> usize {
         self.pool.get_task_count() + self.low_priority_pool.get_task_count() +
             self.high_priority_pool.get_task_count()
@@ -149,11 +236,16 @@ impl Host {
             }
         };
 
-        if self.running_task_count() >= self.max_running_task_count {
-            notify_batch_failed(Error::Full(self.max_running_task_count), reqs);
+        let max_running_task_count = self.max_running_task_count.load(Ordering::Relaxed);
+        if self.running_task_count() >= max_running_task_count {
+            notify_batch_failed(Error::Full(max_running_task_count), reqs);
             return;
         }
 
+        if memory::is_exceeded() {
+            notify_batch_failed(Error::MemoryExceeded(memory::soft_limit()), reqs);
+            return;
+        }
 
         for req in reqs {
             let pri = req.priority();
@@ -185,6 +277,7 @@ pub enum Task {
     SnapRes(u64, engine::Result<Box<Snapshot>>),
     BatchSnapRes(Vec<(u64, engine::Result<Box<Snapshot>>)>),
     RetryRequests(Vec<u64>),
+    ResizePools(usize),
 }
 
 impl Display for Task {
@@ -194,6 +287,7 @@ impl Display for Task {
             Task::SnapRes(req_id, _) => write!(f, "snapres [{}]", req_id),
             Task::BatchSnapRes(_) => write!(f, "batch snapres"),
             Task::RetryRequests(ref retry) => write!(f, "retry on task ids: {:?}", retry),
+            Task::ResizePools(count) => write!(f, "resize pools to {}", count),
         }
     }
 }
@@ -240,12 +334,16 @@ pub struct RequestTask {
     on_resp: OnResponse,
     cop_req: Option<Result<CopRequest>>,
     ctx: ReqContext,
+    slow_log_threshold: f64,
+    tracker: Arc<Tracker>,
 }
 
 impl RequestTask {
     pub fn new(req: Request, on_resp: OnResponse) -> RequestTask {
         let timer = Instant::now_coarse();
         let deadline = timer + Duration::from_secs(REQUEST_MAX_HANDLE_SECS);
+        // The ranges are recorded by their start keys.
+        hot_keys::record_read_keys(req.get_ranges().iter().map(|r| r.get_start()));
         let mut start_ts = None;
         let tp = req.get_tp();
         let mut table_scan = false;
@@ -284,6 +382,8 @@ impl RequestTask {
             fill_cache: !req.get_context().get_not_fill_cache(),
             table_scan: table_scan,
         };
+        let tracker = tracker::current().unwrap_or_else(|| Tracker::new("coprocessor"));
+        tracker.record_event("coprocessor_wait");
         RequestTask {
             req: req,
             start_ts: start_ts,
@@ -293,6 +393,8 @@ impl RequestTask {
             on_resp: on_resp,
             cop_req: Some(cop_req),
             ctx: req_ctx,
+            slow_log_threshold: SLOW_QUERY_LOWER_BOUND,
+            tracker: tracker,
         }
     }
 
@@ -305,6 +407,7 @@ impl RequestTask {
         if self.wait_time.is_some() {
             return;
         }
+        self.tracker.record_event("coprocessor_handle");
         let wait_time = duration_to_sec(self.timer.elapsed());
         COPR_REQ_WAIT_TIME
             .with_label_values(&[self.ctx.get_scan_tag()])
@@ -331,19 +434,20 @@ impl RequestTask {
             .observe(self.statistics.total_op_count() as f64);
 
 
-        if handle_time > SLOW_QUERY_LOWER_BOUND {
+        if handle_time > self.slow_log_threshold {
             info!(
-                "[region {}] handle {:?} [{}] takes {:?} [waiting: {:?}, keys: {}, hit: {}, \
-                 ranges: {} ({:?})]",
+                "[region {}] slow coprocessor request, type: {}, start_ts: {:?}, takes: {:?}, \
+                 waiting: {:?}, keys: {}, hit: {}, ranges: {} ({:?}), {}",
                 self.req.get_context().get_region_id(),
-                self.start_ts,
                 type_str,
+                self.start_ts,
                 handle_time,
                 wait_time,
                 self.statistics.total_op_count(),
                 self.statistics.total_processed(),
                 self.req.get_ranges().len(),
-                self.req.get_ranges().get(0)
+                self.req.get_ranges().get(0),
+                self.tracker
             );
         }
     }
@@ -351,6 +455,20 @@ impl RequestTask {
     pub fn priority(&self) -> CommandPri {
         self.req.get_context().get_priority()
     }
+
+    // Returns the time and the keys taken by the request, it's called after the
+    // handling is recorded.
+    fn exec_details(&self) -> ExecDetails {
+        let wait_ms = (self.wait_time.unwrap_or_default() * 1000.0) as u64;
+        let handle_ms = (duration_to_sec(self.timer.elapsed()) * 1000.0) as u64;
+        let mut time_detail = TimeDetail::new();
+        time_detail.set_wait_wall_time_ms(wait_ms);
+        time_detail.set_process_wall_time_ms(handle_ms.saturating_sub(wait_ms));
+        let mut exec_details = ExecDetails::new();
+        exec_details.set_time_detail(time_detail);
+        exec_details.set_scan_detail(self.statistics.scan_detail());
+        exec_details
+    }
 }
 
 impl Display for RequestTask {
@@ -373,11 +491,20 @@ impl BatchRunnable<Task> for Host {
         let mut grouped_reqs = map![];
         for task in tasks.drain(..) {
             match task {
-                Task::Request(req) => {
+                Task::Request(mut req) => {
+                    req.slow_log_threshold = self.slow_log_threshold;
                     if let Err(e) = req.check_outdated() {
                         on_error(e, req);
                         continue;
                     }
+                    // The ranges are checked before the snapshots are taken, which
+                    // may miss the prewrites being written.
+                    if let Some(start_ts) = req.start_ts {
+                        if let Err(e) = read_memory_locks(&self.max_ts, &req.req, start_ts) {
+                            on_error(e, req);
+                            continue;
+                        }
+                    }
                     let key = {
                         let ctx = req.req.get_context();
                         (
@@ -407,6 +534,7 @@ impl BatchRunnable<Task> for Host {
                         self.reqs.insert(id, reqs);
                     }
                 },
+                Task::ResizePools(count) => self.resize_pools(count),
             }
         }
 
@@ -479,16 +607,25 @@ fn err_resp(e: Error) -> Response {
         Error::Region(e) => {
             let tag = storage::get_tag_from_header(&e);
             COPR_REQ_ERROR.with_label_values(&[tag]).inc();
+            REQUEST_ERROR_COUNTER_VEC
+                .with_label_values(&["raftstore", tag])
+                .inc();
             resp.set_region_error(e);
         }
         Error::Locked(info) => {
             resp.set_locked(info);
             COPR_REQ_ERROR.with_label_values(&["lock"]).inc();
+            REQUEST_ERROR_COUNTER_VEC
+                .with_label_values(&["coprocessor", "key_is_locked"])
+                .inc();
         }
         Error::Outdated(deadline, now, scan_tag) => {
             let elapsed =
                 now.duration_since(deadline) + Duration::from_secs(REQUEST_MAX_HANDLE_SECS);
             COPR_REQ_ERROR.with_label_values(&["outdated"]).inc();
+            REQUEST_ERROR_COUNTER_VEC
+                .with_label_values(&["coprocessor", "outdated"])
+                .inc();
             OUTDATED_REQ_WAIT_TIME
                 .with_label_values(&[scan_tag])
                 .observe(elapsed.as_secs() as f64);
@@ -497,6 +634,9 @@ fn err_resp(e: Error) -> Response {
         }
         Error::Full(allow) => {
             COPR_REQ_ERROR.with_label_values(&["full"]).inc();
+            REQUEST_ERROR_COUNTER_VEC
+                .with_label_values(&["coprocessor", "server_is_busy"])
+                .inc();
             let mut errorpb = errorpb::Error::new();
             errorpb.set_message(format!("running batches reach limit {}", allow));
             let mut server_is_busy_err = ServerIsBusy::new();
@@ -504,14 +644,51 @@ fn err_resp(e: Error) -> Response {
             errorpb.set_server_is_busy(server_is_busy_err);
             resp.set_region_error(errorpb);
         }
+        Error::MemoryExceeded(limit) => {
+            COPR_REQ_ERROR.with_label_values(&["memory"]).inc();
+            REQUEST_ERROR_COUNTER_VEC
+                .with_label_values(&["coprocessor", "server_is_busy"])
+                .inc();
+            let mut errorpb = errorpb::Error::new();
+            errorpb.set_message(format!("memory usage exceeds the soft limit {}", limit));
+            let mut server_is_busy_err = ServerIsBusy::new();
+            server_is_busy_err.set_reason(ENDPOINT_IS_BUSY.to_owned());
+            errorpb.set_server_is_busy(server_is_busy_err);
+            resp.set_region_error(errorpb);
+        }
         Error::Other(_) => {
             resp.set_other_error(format!("{}", e));
             COPR_REQ_ERROR.with_label_values(&["other"]).inc();
+            REQUEST_ERROR_COUNTER_VEC
+                .with_label_values(&["coprocessor", "other"])
+                .inc();
         }
     }
     resp
 }
 
+/// Records the ts of a request, it fails if the ranges it reads are locked in
+/// memory by the prewrites being written.
+fn read_memory_locks(max_ts: &MaxTsTracker, req: &Request, start_ts: u64) -> Result<()> {
+    max_ts.update(start_ts);
+    // The locks are ignored by the reads of RC.
+    if req.get_context().get_isolation_level() == IsolationLevel::RC {
+        return Ok(());
+    }
+    for range in req.get_ranges() {
+        let start = Key::from_raw(range.get_start());
+        let end = if range.get_end().is_empty() {
+            None
+        } else {
+            Some(Key::from_raw(range.get_end()))
+        };
+        if let Err(e) = max_ts.read_range(start_ts, Some(&start), end.as_ref(), &[]) {
+            return Err(Error::from(TxnError::from(e)));
+        }
+    }
+    Ok(())
+}
+
 fn on_error(e: Error, req: RequestTask) -> Statistics {
     let resp = err_resp(e);
     respond(resp, req)
@@ -525,8 +702,11 @@ fn notify_batch_failed<E: Into<Error> + Debug>(e: E, reqs: Vec<RequestTask>) {
     }
 }
 
-fn respond(resp: Response, mut t: RequestTask) -> Statistics {
+fn respond(mut resp: Response, mut t: RequestTask) -> Statistics {
+    t.tracker.add_read_bytes(resp.get_data().len() as u64);
+    t.tracker.record_event("coprocessor_finish");
     t.stop_record_handling();
+    resp.set_exec_details(t.exec_details());
     (t.on_resp)(resp);
     t.statistics
 }
@@ -547,11 +727,13 @@ impl TiDbEndPoint {
         if let Err(e) = t.check_outdated() {
             return on_error(e, t);
         }
+        let cpu_start = thread_cpu_time();
         let resp = match t.cop_req.take().unwrap() {
             Ok(CopRequest::Select(sel)) => self.handle_select(sel, &mut t),
             Ok(CopRequest::DAG(dag)) => self.handle_dag(dag, &mut t),
             Err(err) => Err(err),
         };
+        t.tracker.add_cpu_time(thread_cpu_time() - cpu_start);
         match resp {
             Ok(r) => respond(r, t),
             Err(e) => on_error(e, t),
@@ -685,7 +867,7 @@ mod tests {
         let engine = engine::new_local_engine(TEMP_DIR, &[]).unwrap();
         let mut cfg = Config::default();
         cfg.end_point_concurrency = 1;
-        let end_point = Host::new(engine, worker.scheduler(), &cfg);
+        let end_point = Host::new(engine, MaxTsTracker::new(), worker.scheduler(), &cfg);
         worker.start_batch(end_point, 30).unwrap();
         let (tx, rx) = mpsc::channel();
         let mut task = RequestTask::new(Request::new(), box move |msg| { tx.send(msg).unwrap(); });
@@ -702,8 +884,11 @@ mod tests {
         let engine = engine::new_local_engine(TEMP_DIR, &[]).unwrap();
         let mut cfg = Config::default();
         cfg.end_point_concurrency = 1;
-        let mut end_point = Host::new(engine, worker.scheduler(), &cfg);
-        end_point.max_running_task_count = 3;
+        let mut end_point = Host::new(engine, MaxTsTracker::new(), worker.scheduler(), &cfg);
+        end_point
+            .config_handler()
+            .update("end-point-max-tasks", "3")
+            .unwrap();
         worker.start_batch(end_point, 30).unwrap();
         let (tx, rx) = mpsc::channel();
         for pos in 0..30 * 4 {
@@ -732,4 +917,23 @@ mod tests {
         }
         panic!("suppose to get ServerIsBusy error.");
     }
+
+    #[test]
+    fn test_resize_pools() {
+        let worker = Worker::new("test-endpoint");
+        let engine = engine::new_local_engine(TEMP_DIR, &[]).unwrap();
+        let mut cfg = Config::default();
+        cfg.end_point_concurrency = 1;
+        let mut end_point = Host::new(engine, MaxTsTracker::new(), worker.scheduler(), &cfg);
+        let handler = end_point.config_handler();
+        assert!(handler.update("end-point-concurrency", "0").is_err());
+        assert!(handler.update("end-point-concurrency", "x").is_err());
+
+        end_point.run_batch(&mut vec![Task::ResizePools(3)]);
+        assert_eq!(end_point.pool.thread_count(), 3);
+        assert_eq!(end_point.low_priority_pool.thread_count(), 3);
+        assert_eq!(end_point.high_priority_pool.thread_count(), 3);
+        end_point.run_batch(&mut vec![Task::ResizePools(2)]);
+        assert_eq!(end_point.pool.thread_count(), 2);
+    }
 }
@@ -43,6 +43,9 @@ quick_error! {
         Full(allow: usize) {
             description("running queue is full")
         }
+        MemoryExceeded(limit: usize) {
+            description("memory usage exceeds the soft limit")
+        }
         Other(err: Box<error::Error + Send + Sync>) {
             from()
             cause(err.as_ref())
@@ -31,6 +31,7 @@ use util::{escape, Either};
 use util::time::{duration_to_ms, Instant};
 use util::collections::{HashMap, HashMapEntry as Entry, HashSet};
 use util::codec::number::NumberDecoder;
+use util::memory::{MemoryTrace, COPROCESSOR_MEMORY};
 use storage::{Key, ScanMode, Snapshot, SnapshotStore, Statistics};
 
 use super::xeval::{EvalContext, Evaluator};
@@ -322,6 +323,7 @@ struct SelectContextCore {
     gks: Vec<Rc<Vec<u8>>>,
     gk_aggrs: HashMap<Rc<Vec<u8>>, Vec<Box<AggrFunc>>>,
     chunks: Vec<Chunk>,
+    mem_trace: MemoryTrace,
 }
 
 impl SelectContextCore {
@@ -442,6 +444,7 @@ impl SelectContextCore {
             gks: vec![],
             gk_aggrs: map![],
             chunks: vec![],
+            mem_trace: MemoryTrace::new(&COPROCESSOR_MEMORY),
             topn: topn,
             topn_heap: {
                 if topn {
@@ -550,9 +553,11 @@ impl SelectContextCore {
                 ));
             }
         }
+        let row_len = chunk.get_rows_data().len() - last_len;
+        self.mem_trace.consume(row_len);
         let mut meta = RowMeta::new();
         meta.set_handle(h);
-        meta.set_length((chunk.get_rows_data().len() - last_len) as i64);
+        meta.set_length(row_len as i64);
         chunk.mut_rows_meta().push(meta);
         Ok(())
     }
@@ -0,0 +1,233 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+use kvproto::deadlock::WaitForEntry;
+
+use util::collections::{HashMap, HashSet};
+
+// The keys a transaction waits for of another one.
+struct Locks {
+    hashes: Vec<u64>,
+    last_detect_time: Instant,
+}
+
+impl Locks {
+    fn new(key_hash: u64, now: Instant) -> Locks {
+        Locks {
+            hashes: vec![key_hash],
+            last_detect_time: now,
+        }
+    }
+
+    fn push(&mut self, key_hash: u64, now: Instant) {
+        if !self.hashes.contains(&key_hash) {
+            self.hashes.push(key_hash);
+        }
+        self.last_detect_time = now;
+    }
+
+    // Returns whether the locks are empty after `key_hash` is removed.
+    fn remove(&mut self, key_hash: u64) -> bool {
+        self.hashes.retain(|h| *h != key_hash);
+        self.hashes.is_empty()
+    }
+
+    fn is_expired(&self, now: Instant, ttl: Duration) -> bool {
+        now.duration_since(self.last_detect_time) >= ttl
+    }
+}
+
+/// `DetectTable` is the wait-for graph of the transactions. An edge is added
+/// when a transaction waits for a lock of another one, unless it causes a
+/// cycle. The edges not detected again within `ttl` are expired, so the ones
+/// whose clean ups are lost don't stay forever.
+pub struct DetectTable {
+    // txn_ts -> wait_for_ts -> locks.
+    wait_for_map: HashMap<u64, HashMap<u64, Locks>>,
+    ttl: Duration,
+    last_active_expire: Instant,
+}
+
+impl DetectTable {
+    pub fn new(ttl: Duration) -> DetectTable {
+        DetectTable {
+            wait_for_map: HashMap::default(),
+            ttl: ttl,
+            last_active_expire: Instant::now(),
+        }
+    }
+
+    /// Returns the key hash in the deadlock if `txn_ts` waiting for the lock
+    /// of `lock_ts` on the key of `key_hash` causes one, otherwise the wait is
+    /// added to the graph.
+    pub fn detect(&mut self, txn_ts: u64, lock_ts: u64, key_hash: u64) -> Option<u64> {
+        let now = Instant::now();
+        self.active_expire(now);
+
+        if let Some(locks) = self.wait_for_map
+            .get_mut(&txn_ts)
+            .and_then(|m| m.get_mut(&lock_ts))
+        {
+            // The edge exists, no cycle is added.
+            locks.push(key_hash, now);
+            return None;
+        }
+        if let Some(deadlock_key_hash) = self.do_detect(txn_ts, lock_ts, now) {
+            return Some(deadlock_key_hash);
+        }
+        self.wait_for_map
+            .entry(txn_ts)
+            .or_insert_with(HashMap::default)
+            .insert(lock_ts, Locks::new(key_hash, now));
+        None
+    }
+
+    // Searches the transactions reachable from `wait_for_ts`, it's a deadlock
+    // if `txn_ts` is one of them.
+    fn do_detect(&mut self, txn_ts: u64, wait_for_ts: u64, now: Instant) -> Option<u64> {
+        let ttl = self.ttl;
+        let mut stack = vec![wait_for_ts];
+        let mut pushed = HashSet::default();
+        pushed.insert(wait_for_ts);
+        while let Some(ts) = stack.pop() {
+            let wait_for = match self.wait_for_map.get_mut(&ts) {
+                Some(wait_for) => wait_for,
+                None => continue,
+            };
+            wait_for.retain(|_, locks| !locks.is_expired(now, ttl));
+            for (&next_ts, locks) in wait_for.iter() {
+                if next_ts == txn_ts {
+                    return Some(locks.hashes[0]);
+                }
+                if pushed.insert(next_ts) {
+                    stack.push(next_ts);
+                }
+            }
+        }
+        None
+    }
+
+    /// Removes the wait of `txn_ts` for the lock of `lock_ts` on the key of
+    /// `key_hash`.
+    pub fn clean_up_wait_for(&mut self, txn_ts: u64, lock_ts: u64, key_hash: u64) {
+        let empty = match self.wait_for_map.get_mut(&txn_ts) {
+            Some(wait_for) => {
+                let removed = wait_for
+                    .get_mut(&lock_ts)
+                    .map_or(false, |locks| locks.remove(key_hash));
+                if removed {
+                    wait_for.remove(&lock_ts);
+                }
+                wait_for.is_empty()
+            }
+            None => return,
+        };
+        if empty {
+            self.wait_for_map.remove(&txn_ts);
+        }
+    }
+
+    /// Removes all the waits of `txn_ts`.
+    pub fn clean_up(&mut self, txn_ts: u64) {
+        self.wait_for_map.remove(&txn_ts);
+    }
+
+    pub fn clear(&mut self) {
+        self.wait_for_map.clear();
+    }
+
+    /// Returns all the edges of the graph.
+    pub fn wait_for_entries(&self) -> Vec<WaitForEntry> {
+        let mut entries = vec![];
+        for (&txn_ts, wait_for) in &self.wait_for_map {
+            for (&lock_ts, locks) in wait_for {
+                for &key_hash in &locks.hashes {
+                    let mut entry = WaitForEntry::new();
+                    entry.set_txn(txn_ts);
+                    entry.set_wait_for_txn(lock_ts);
+                    entry.set_key_hash(key_hash);
+                    entries.push(entry);
+                }
+            }
+        }
+        entries
+    }
+
+    // Removes the expired edges once in a ttl.
+    fn active_expire(&mut self, now: Instant) {
+        if now.duration_since(self.last_active_expire) < self.ttl {
+            return;
+        }
+        let ttl = self.ttl;
+        for wait_for in self.wait_for_map.values_mut() {
+            wait_for.retain(|_, locks| !locks.is_expired(now, ttl));
+        }
+        self.wait_for_map.retain(|_, wait_for| !wait_for.is_empty());
+        self.last_active_expire = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let mut table = DetectTable::new(Duration::from_secs(10));
+        assert_eq!(table.detect(1, 2, 1), None);
+        assert_eq!(table.detect(1, 2, 2), None);
+        assert_eq!(table.detect(2, 3, 3), None);
+        // 3 -> 1 -> 2 -> 3
+        assert_eq!(table.detect(3, 1, 4), Some(3));
+        // 3 -> 2 -> 3
+        assert_eq!(table.detect(3, 2, 5), Some(3));
+        assert_eq!(table.wait_for_entries().len(), 3);
+
+        // The cycle is broken once 2 doesn't wait for 3.
+        table.clean_up_wait_for(2, 3, 3);
+        assert_eq!(table.detect(3, 1, 4), None);
+        assert_eq!(table.detect(2, 3, 3), Some(1));
+        table.clean_up(1);
+        assert_eq!(table.detect(2, 3, 3), None);
+    }
+
+    #[test]
+    fn test_clean_up_wait_for() {
+        let mut table = DetectTable::new(Duration::from_secs(10));
+        table.detect(1, 2, 1);
+        table.detect(1, 2, 2);
+        table.clean_up_wait_for(1, 2, 1);
+        table.clean_up_wait_for(1, 3, 2);
+        table.clean_up_wait_for(3, 2, 2);
+        assert_eq!(table.detect(2, 1, 3), Some(2));
+        table.clean_up_wait_for(1, 2, 2);
+        assert!(table.wait_for_map.is_empty());
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut table = DetectTable::new(Duration::from_millis(50));
+        table.detect(1, 2, 1);
+        table.detect(2, 3, 2);
+        thread::sleep(Duration::from_millis(60));
+        // The expired edges don't cause deadlocks.
+        table.detect(3, 4, 3);
+        assert!(table.wait_for_map.get(&1).is_none());
+        assert_eq!(table.detect(4, 3, 4), Some(3));
+        assert_eq!(table.detect(2, 1, 5), None);
+    }
+}
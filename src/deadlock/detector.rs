@@ -0,0 +1,435 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc::{self, UnboundedSender};
+use futures::sync::oneshot;
+use grpc::{ChannelBuilder, EnvBuilder, Environment, Error as GrpcError, WriteFlags};
+use kvproto::deadlock::{DeadlockRequest, DeadlockRequestType, DeadlockResponse, WaitForEntry};
+use kvproto::deadlock_grpc::DeadlockClient;
+use tokio_core::reactor::Handle;
+
+use pd::PdClient;
+use storage::{DeadlockCallback, DeadlockDetector};
+use util::collections::HashMap;
+use util::worker::{FutureRunnable as Runnable, FutureScheduler};
+use super::detect_table::DetectTable;
+
+pub enum Task {
+    /// A wait, or a clean up of the waits, of the local scheduler. `cb` is
+    /// only set for `Detect`.
+    Detect {
+        tp: DeadlockRequestType,
+        txn_ts: u64,
+        lock_ts: u64,
+        key_hash: u64,
+        cb: Option<DeadlockCallback>,
+    },
+    /// A request sent from the other stores to the leader, the deadlock found
+    /// is sent back by `sender`.
+    DetectRpc {
+        req: DeadlockRequest,
+        sender: UnboundedSender<DeadlockResponse>,
+    },
+    /// The deadlock found by the leader.
+    DetectResponse(DeadlockResponse),
+    /// The stream of `conn_id` to the leader is closed.
+    LeaderLost { conn_id: u64 },
+    GetWaitForEntries(oneshot::Sender<Vec<WaitForEntry>>),
+    /// The store becomes the leader of the detector or steps down.
+    ChangeRole(bool),
+}
+
+impl Display for Task {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Task::Detect {
+                tp,
+                txn_ts,
+                lock_ts,
+                key_hash,
+                ..
+            } => write!(
+                f,
+                "{:?} txn {} waiting for {} on key {}",
+                tp,
+                txn_ts,
+                lock_ts,
+                key_hash
+            ),
+            Task::DetectRpc { ref req, .. } => write!(f, "detect rpc {:?}", req),
+            Task::DetectResponse(ref resp) => write!(f, "detect response {:?}", resp),
+            Task::LeaderLost { conn_id } => write!(f, "leader lost on conn {}", conn_id),
+            Task::GetWaitForEntries(_) => write!(f, "get wait for entries"),
+            Task::ChangeRole(is_leader) => write!(f, "change role, is leader {}", is_leader),
+        }
+    }
+}
+
+/// `DetectorScheduler` sends the waits of the local scheduler to the
+/// detector, and tells whether the store is the leader of the detector.
+#[derive(Clone)]
+pub struct DetectorScheduler {
+    scheduler: FutureScheduler<Task>,
+    is_leader: Arc<AtomicBool>,
+}
+
+impl DetectorScheduler {
+    pub fn new(scheduler: FutureScheduler<Task>) -> DetectorScheduler {
+        DetectorScheduler {
+            scheduler: scheduler,
+            is_leader: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn schedule(&self, task: Task) {
+        if let Err(e) = self.scheduler.schedule(task) {
+            error!("failed to schedule deadlock detector task {}", e.0);
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.is_leader.load(Ordering::SeqCst)
+    }
+
+    fn detect_task(
+        &self,
+        tp: DeadlockRequestType,
+        txn_ts: u64,
+        lock_ts: u64,
+        key_hash: u64,
+        cb: Option<DeadlockCallback>,
+    ) {
+        self.schedule(Task::Detect {
+            tp: tp,
+            txn_ts: txn_ts,
+            lock_ts: lock_ts,
+            key_hash: key_hash,
+            cb: cb,
+        });
+    }
+}
+
+impl DeadlockDetector for DetectorScheduler {
+    fn detect(&self, txn_ts: u64, lock_ts: u64, key_hash: u64, cb: DeadlockCallback) {
+        self.detect_task(DeadlockRequestType::Detect, txn_ts, lock_ts, key_hash, Some(cb));
+    }
+
+    fn clean_up_wait_for(&self, txn_ts: u64, lock_ts: u64, key_hash: u64) {
+        self.detect_task(
+            DeadlockRequestType::CleanUpWaitFor,
+            txn_ts,
+            lock_ts,
+            key_hash,
+            None,
+        );
+    }
+
+    fn clean_up(&self, txn_ts: u64) {
+        self.detect_task(DeadlockRequestType::CleanUp, txn_ts, 0, 0, None);
+    }
+}
+
+// The stream to the leader of the detector.
+struct LeaderClient {
+    conn_id: u64,
+    sender: UnboundedSender<DeadlockRequest>,
+    _client: DeadlockClient,
+}
+
+fn new_entry(txn_ts: u64, lock_ts: u64, key_hash: u64) -> WaitForEntry {
+    let mut entry = WaitForEntry::new();
+    entry.set_txn(txn_ts);
+    entry.set_wait_for_txn(lock_ts);
+    entry.set_key_hash(key_hash);
+    entry
+}
+
+/// `Detector` finds the deadlocks with the wait-for graph if the store is the
+/// leader of the detector, otherwise it forwards the waits to the leader and
+/// calls the callbacks of the deadlocks found by the leader.
+///
+/// The waits sent while the leader is unknown or changing are dropped, the
+/// transactions in the deadlocks they cause wait until their timeouts.
+pub struct Detector<C> {
+    store_id: u64,
+    pd_client: Arc<C>,
+    env: Arc<Environment>,
+    scheduler: DetectorScheduler,
+    is_leader: bool,
+    detect_table: DetectTable,
+    leader_client: Option<LeaderClient>,
+    next_conn_id: u64,
+    // (txn_ts, lock_ts, key_hash) -> callback, of the waits sent to the leader.
+    callbacks: HashMap<(u64, u64, u64), DeadlockCallback>,
+}
+
+impl<C: PdClient> Detector<C> {
+    /// Creates a detector, the waits are expired in the graph after `ttl`,
+    /// which should be the timeout of the waits.
+    pub fn new(
+        store_id: u64,
+        pd_client: Arc<C>,
+        scheduler: DetectorScheduler,
+        ttl: Duration,
+    ) -> Detector<C> {
+        let env = Arc::new(
+            EnvBuilder::new()
+                .cq_count(1)
+                .name_prefix(thd_name!("deadlock-client"))
+                .build(),
+        );
+        Detector {
+            store_id: store_id,
+            pd_client: pd_client,
+            env: env,
+            scheduler: scheduler,
+            is_leader: false,
+            detect_table: DetectTable::new(ttl),
+            leader_client: None,
+            next_conn_id: 0,
+            callbacks: HashMap::default(),
+        }
+    }
+
+    fn change_role(&mut self, is_leader: bool) {
+        if self.is_leader == is_leader {
+            return;
+        }
+        info!(
+            "store {} {} the leader of deadlock detector",
+            self.store_id,
+            if is_leader { "becomes" } else { "is no longer" }
+        );
+        self.is_leader = is_leader;
+        self.scheduler.is_leader.store(is_leader, Ordering::SeqCst);
+        self.detect_table.clear();
+        self.reset_leader_client();
+    }
+
+    fn reset_leader_client(&mut self) {
+        self.leader_client = None;
+        self.callbacks.clear();
+    }
+
+    // Connects to the store leading the first region.
+    fn connect_leader(&mut self) -> bool {
+        if self.leader_client.is_some() {
+            return true;
+        }
+        let leader = match self.pd_client.get_region_leader(b"") {
+            Ok(Some(leader)) => leader,
+            Ok(None) => {
+                warn!("the leader of deadlock detector is unknown");
+                return false;
+            }
+            Err(e) => {
+                error!("failed to get the leader of deadlock detector: {:?}", e);
+                return false;
+            }
+        };
+        if leader.get_store_id() == self.store_id {
+            // The role change is not applied on the store yet.
+            return false;
+        }
+        let addr = match self.pd_client.get_store(leader.get_store_id()) {
+            Ok(store) => store.get_address().to_owned(),
+            Err(e) => {
+                error!("failed to get store {}: {:?}", leader.get_store_id(), e);
+                return false;
+            }
+        };
+        info!("connect to the leader of deadlock detector {}", addr);
+        let channel = ChannelBuilder::new(self.env.clone()).connect(&addr);
+        let client = DeadlockClient::new(channel);
+        let (sink, receiver) = client.detect();
+        let (tx, rx) = mpsc::unbounded();
+        let conn_id = self.next_conn_id;
+        self.next_conn_id += 1;
+        let send_req = sink.send_all(
+            rx.map(|r| (r, WriteFlags::default()))
+                .map_err(|_| GrpcError::RemoteStopped),
+        );
+        client.spawn(send_req.map(|_| ()).map_err(|e| {
+            warn!("failed to send requests to deadlock detector leader: {:?}", e)
+        }));
+        let scheduler = self.scheduler.clone();
+        let recv_resp = receiver
+            .for_each({
+                let scheduler = scheduler.clone();
+                move |resp| {
+                    scheduler.schedule(Task::DetectResponse(resp));
+                    Ok(())
+                }
+            })
+            .then(move |res| {
+                if let Err(e) = res {
+                    warn!("failed to receive responses of deadlock detector leader: {:?}", e);
+                }
+                scheduler.schedule(Task::LeaderLost { conn_id: conn_id });
+                Ok(())
+            });
+        client.spawn(recv_resp);
+        self.leader_client = Some(LeaderClient {
+            conn_id: conn_id,
+            sender: tx,
+            _client: client,
+        });
+        true
+    }
+
+    fn send_to_leader(&mut self, tp: DeadlockRequestType, entry: WaitForEntry) -> bool {
+        if !self.connect_leader() {
+            return false;
+        }
+        let mut req = DeadlockRequest::new();
+        req.set_tp(tp);
+        req.set_entry(entry);
+        let sent = UnboundedSender::send(&self.leader_client.as_ref().unwrap().sender, req);
+        if sent.is_err() {
+            warn!("the stream to deadlock detector leader is closed");
+            self.reset_leader_client();
+            return false;
+        }
+        true
+    }
+
+    fn handle_detect(
+        &mut self,
+        tp: DeadlockRequestType,
+        txn_ts: u64,
+        lock_ts: u64,
+        key_hash: u64,
+        cb: Option<DeadlockCallback>,
+    ) {
+        if self.is_leader {
+            if let Some(deadlock_key_hash) = self.detect_local(tp, txn_ts, lock_ts, key_hash) {
+                if let Some(cb) = cb {
+                    cb(deadlock_key_hash);
+                }
+            }
+            return;
+        }
+
+        match tp {
+            DeadlockRequestType::Detect => {
+                let entry = new_entry(txn_ts, lock_ts, key_hash);
+                if self.send_to_leader(tp, entry) {
+                    if let Some(cb) = cb {
+                        self.callbacks.insert((txn_ts, lock_ts, key_hash), cb);
+                    }
+                }
+            }
+            DeadlockRequestType::CleanUpWaitFor => {
+                self.callbacks.remove(&(txn_ts, lock_ts, key_hash));
+                self.send_to_leader(tp, new_entry(txn_ts, lock_ts, key_hash));
+            }
+            DeadlockRequestType::CleanUp => {
+                self.callbacks.retain(|k, _| k.0 != txn_ts);
+                self.send_to_leader(tp, new_entry(txn_ts, lock_ts, key_hash));
+            }
+        }
+    }
+
+    // Returns the key hash of the deadlock found.
+    fn detect_local(
+        &mut self,
+        tp: DeadlockRequestType,
+        txn_ts: u64,
+        lock_ts: u64,
+        key_hash: u64,
+    ) -> Option<u64> {
+        match tp {
+            DeadlockRequestType::Detect => self.detect_table.detect(txn_ts, lock_ts, key_hash),
+            DeadlockRequestType::CleanUpWaitFor => {
+                self.detect_table
+                    .clean_up_wait_for(txn_ts, lock_ts, key_hash);
+                None
+            }
+            DeadlockRequestType::CleanUp => {
+                self.detect_table.clean_up(txn_ts);
+                None
+            }
+        }
+    }
+
+    fn handle_detect_rpc(
+        &mut self,
+        mut req: DeadlockRequest,
+        sender: UnboundedSender<DeadlockResponse>,
+    ) {
+        if !self.is_leader {
+            // The stream is closed by the service, the client connects to the
+            // new leader.
+            debug!("deadlock detector is not the leader, drop request {:?}", req);
+            return;
+        }
+        let entry = req.take_entry();
+        let res = self.detect_local(
+            req.get_tp(),
+            entry.get_txn(),
+            entry.get_wait_for_txn(),
+            entry.get_key_hash(),
+        );
+        if let Some(deadlock_key_hash) = res {
+            let mut resp = DeadlockResponse::new();
+            resp.set_entry(entry);
+            resp.set_deadlock_key_hash(deadlock_key_hash);
+            if UnboundedSender::send(&sender, resp).is_err() {
+                warn!("failed to send deadlock response, the stream is closed");
+            }
+        }
+    }
+
+    fn handle_detect_response(&mut self, resp: DeadlockResponse) {
+        let entry = resp.get_entry();
+        let key = (entry.get_txn(), entry.get_wait_for_txn(), entry.get_key_hash());
+        if let Some(cb) = self.callbacks.remove(&key) {
+            cb(resp.get_deadlock_key_hash());
+        }
+    }
+}
+
+impl<C: PdClient> Runnable<Task> for Detector<C> {
+    fn run(&mut self, task: Task, _: &Handle) {
+        match task {
+            Task::Detect {
+                tp,
+                txn_ts,
+                lock_ts,
+                key_hash,
+                cb,
+            } => self.handle_detect(tp, txn_ts, lock_ts, key_hash, cb),
+            Task::DetectRpc { req, sender } => self.handle_detect_rpc(req, sender),
+            Task::DetectResponse(resp) => self.handle_detect_response(resp),
+            Task::LeaderLost { conn_id } => {
+                let is_current = self.leader_client
+                    .as_ref()
+                    .map_or(false, |c| c.conn_id == conn_id);
+                if is_current {
+                    info!("the stream to deadlock detector leader is closed");
+                    self.reset_leader_client();
+                }
+            }
+            Task::GetWaitForEntries(tx) => {
+                let _ = tx.send(self.detect_table.wait_for_entries());
+            }
+            Task::ChangeRole(is_leader) => self.change_role(is_leader),
+        }
+    }
+}
@@ -0,0 +1,28 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Deadlock detection finds the pessimistic transactions waiting for the locks
+//! of each other. The waits on all the stores are sent to the detector leader,
+//! which is the store leading the first region, so the cycles across the
+//! stores can be found in one wait-for graph. One of the transactions in a
+//! cycle is woken up with the deadlock error, instead of waiting until its
+//! timeout.
+
+mod detect_table;
+mod detector;
+mod observer;
+mod service;
+
+pub use self::detector::{Detector, DetectorScheduler, Task};
+pub use self::observer::DeadlockObserver;
+pub use self::service::Service;
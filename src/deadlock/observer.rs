@@ -0,0 +1,91 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use raft::StateRole;
+
+use raftstore::coprocessor::{Coprocessor, ObserverContext, RegionObserver};
+use super::detector::{DetectorScheduler, Task};
+
+/// `DeadlockObserver` elects the leader of the deadlock detector, which is the
+/// store leading the first region.
+#[derive(Clone)]
+pub struct DeadlockObserver {
+    scheduler: DetectorScheduler,
+}
+
+impl DeadlockObserver {
+    pub fn new(scheduler: DetectorScheduler) -> DeadlockObserver {
+        DeadlockObserver {
+            scheduler: scheduler,
+        }
+    }
+}
+
+impl Coprocessor for DeadlockObserver {}
+
+impl RegionObserver for DeadlockObserver {
+    fn on_role_change(&self, ctx: &mut ObserverContext, role: StateRole) {
+        if !ctx.region().get_start_key().is_empty() {
+            return;
+        }
+        let is_leader = role == StateRole::Leader;
+        if is_leader != self.scheduler.is_leader() {
+            self.scheduler.schedule(Task::ChangeRole(is_leader));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Sender};
+    use std::time::Duration;
+
+    use kvproto::metapb::Region;
+    use tokio_core::reactor::Handle;
+
+    use util::worker::{FutureRunnable, FutureWorker};
+    use super::*;
+
+    struct TaskRunner(Sender<Task>);
+
+    impl FutureRunnable<Task> for TaskRunner {
+        fn run(&mut self, task: Task, _: &Handle) {
+            self.0.send(task).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let mut worker = FutureWorker::new("test-deadlock");
+        let (tx, rx) = mpsc::channel();
+        worker.start(TaskRunner(tx)).unwrap();
+        let observer = DeadlockObserver::new(DetectorScheduler::new(worker.scheduler()));
+
+        // Only the first region elects the leader.
+        let mut region = Region::new();
+        region.set_start_key(b"k".to_vec());
+        observer.on_role_change(&mut ObserverContext::new(&region), StateRole::Leader);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        let region = Region::new();
+        let mut ctx = ObserverContext::new(&region);
+        observer.on_role_change(&mut ctx, StateRole::Follower);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        observer.on_role_change(&mut ctx, StateRole::Leader);
+        match rx.recv_timeout(Duration::from_secs(3)).unwrap() {
+            Task::ChangeRole(true) => {}
+            t => panic!("unexpected task {}", t),
+        }
+        worker.stop().unwrap().join().unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::{Future, Sink, Stream};
+use futures::sync::{mpsc, oneshot};
+use grpc::{DuplexSink, Error as GrpcError, RequestStream, RpcContext, RpcStatus, RpcStatusCode,
+           UnarySink, WriteFlags};
+use kvproto::deadlock::{DeadlockRequest, DeadlockResponse, WaitForEntriesRequest,
+                        WaitForEntriesResponse};
+use kvproto::deadlock_grpc;
+use protobuf::RepeatedField;
+
+use super::detector::{DetectorScheduler, Task};
+
+/// Service handles the RPC messages for the `Deadlock` service.
+///
+/// Each `Detect` stream is opened by another store, the waits on it are
+/// detected if the store is the leader of the detector, otherwise the stream
+/// is closed so that the client finds the new leader.
+#[derive(Clone)]
+pub struct Service {
+    scheduler: DetectorScheduler,
+}
+
+impl Service {
+    pub fn new(scheduler: DetectorScheduler) -> Service {
+        Service {
+            scheduler: scheduler,
+        }
+    }
+}
+
+impl deadlock_grpc::Deadlock for Service {
+    fn get_wait_for_entries(
+        &self,
+        ctx: RpcContext,
+        _: WaitForEntriesRequest,
+        sink: UnarySink<WaitForEntriesResponse>,
+    ) {
+        let (tx, rx) = oneshot::channel();
+        self.scheduler.schedule(Task::GetWaitForEntries(tx));
+        let future = rx.then(move |res| match res {
+            Ok(entries) => {
+                let mut resp = WaitForEntriesResponse::new();
+                resp.set_entries(RepeatedField::from_vec(entries));
+                sink.success(resp)
+            }
+            Err(_) => {
+                let msg = "deadlock detector is stopped".to_owned();
+                sink.fail(RpcStatus::new(RpcStatusCode::Internal, Some(msg)))
+            }
+        }).map_err(|e| warn!("failed to send wait for entries: {:?}", e));
+        ctx.spawn(future);
+    }
+
+    fn detect(
+        &self,
+        ctx: RpcContext,
+        stream: RequestStream<DeadlockRequest>,
+        sink: DuplexSink<DeadlockResponse>,
+    ) {
+        let (tx, rx) = mpsc::unbounded();
+
+        let scheduler = self.scheduler.clone();
+        let recv_req = stream.for_each(move |req| {
+            if !scheduler.is_leader() {
+                let status = RpcStatus::new(
+                    RpcStatusCode::FailedPrecondition,
+                    Some("not the leader of deadlock detector".to_owned()),
+                );
+                return Err(GrpcError::RpcFailure(status));
+            }
+            scheduler.schedule(Task::DetectRpc {
+                req: req,
+                sender: tx.clone(),
+            });
+            Ok(())
+        });
+        ctx.spawn(recv_req.map_err(|e| {
+            warn!("deadlock detector failed to receive requests: {:?}", e)
+        }));
+
+        // The responses are sent until the requests end or fail.
+        let send_resp = sink.send_all(
+            rx.map(|r| (r, WriteFlags::default()))
+                .map_err(|_| GrpcError::RemoteStopped),
+        );
+        ctx.spawn(send_resp.map(|_| ()).map_err(|e| {
+            info!("deadlock detector stream is closed: {:?}", e)
+        }));
+    }
+}
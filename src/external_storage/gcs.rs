@@ -0,0 +1,219 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{self, Read};
+use std::mem;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::Client;
+use hyper::client::RequestBuilder;
+use hyper::header::Headers;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use serde_json::{self, Value};
+use url::Url;
+
+use super::{get_param, parse_bucket, read_full, retry, ExternalStorage};
+use super::http::{self, get_header, set_header, uri_encode};
+
+const DEFAULT_ENDPOINT: &'static str = "https://storage.googleapis.com";
+const METADATA_TOKEN_URL: &'static str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+// Objects larger than one chunk are uploaded in a resumable upload, whose chunks
+// except the last one must be multiples of 256KiB.
+const CHUNK_SIZE: usize = 32 * 256 * 1024;
+// Tokens from the metadata server are refreshed a while before they expire.
+const TOKEN_EXPIRE_MARGIN_SECS: u64 = 60;
+
+/// `GcsStorage` stores objects in a Google Cloud Storage bucket through the
+/// JSON API.
+pub struct GcsStorage {
+    client: Client,
+    endpoint: String,
+    bucket: String,
+    prefix: String,
+    // The token given by the user, tokens are fetched from the metadata server
+    // if it's absent.
+    access_token: Option<String>,
+    cached_token: Mutex<Option<(String, Instant)>>,
+}
+
+impl GcsStorage {
+    /// Creates a storage from `gcs://bucket/prefix?access-token=..`.
+    pub fn new(url: &Url) -> io::Result<GcsStorage> {
+        let (bucket, prefix) = try!(parse_bucket(url));
+        let endpoint = get_param(url, "endpoint", "GCS_ENDPOINT")
+            .unwrap_or_else(|| DEFAULT_ENDPOINT.to_owned());
+        Ok(GcsStorage {
+            client: http::new_client(),
+            endpoint: endpoint.trim_right_matches('/').to_owned(),
+            bucket: bucket,
+            prefix: prefix,
+            access_token: get_param(url, "access-token", "GCS_ACCESS_TOKEN"),
+            cached_token: Mutex::new(None),
+        })
+    }
+
+    fn get_token(&self) -> io::Result<String> {
+        if let Some(ref token) = self.access_token {
+            return Ok(token.clone());
+        }
+        let mut cached_token = self.cached_token.lock().unwrap();
+        if let Some((ref token, expire_at)) = *cached_token {
+            if Instant::now() < expire_at {
+                return Ok(token.clone());
+            }
+        }
+
+        let mut headers = Headers::new();
+        set_header(&mut headers, "Metadata-Flavor", "Google");
+        let mut resp = try!(retry("gcs fetch token", || {
+            http::send(self.client.get(METADATA_TOKEN_URL).headers(headers.clone()))
+        }));
+        let mut body = String::new();
+        try!(resp.read_to_string(&mut body));
+        let v: Value = match serde_json::from_str(&body) {
+            Ok(v) => v,
+            Err(e) => return Err(invalid_data(format!("invalid token {}: {:?}", body, e))),
+        };
+        let token = match v["access_token"].as_str() {
+            Some(token) => token.to_owned(),
+            None => return Err(invalid_data(format!("access token is missing in {}", body))),
+        };
+        let expires_in = v["expires_in"].as_u64().unwrap_or(0);
+        let valid_secs = expires_in.saturating_sub(TOKEN_EXPIRE_MARGIN_SECS);
+        let expire_at = Instant::now() + Duration::from_secs(valid_secs);
+        *cached_token = Some((token.clone(), expire_at));
+        Ok(token)
+    }
+
+    fn request<'a>(
+        &'a self,
+        method: Method,
+        url: &str,
+        mut headers: Headers,
+        body: &'a [u8],
+    ) -> io::Result<RequestBuilder<'a>> {
+        let token = try!(self.get_token());
+        set_header(&mut headers, "Authorization", &format!("Bearer {}", token));
+        Ok(self.client.request(method, url).headers(headers).body(body))
+    }
+
+    fn upload_url(&self, name: &str, upload_type: &str) -> String {
+        format!(
+            "{}/upload/storage/v1/b/{}/o?uploadType={}&name={}",
+            self.endpoint,
+            uri_encode(&self.bucket, false),
+            upload_type,
+            uri_encode(&format!("{}{}", self.prefix, name), false)
+        )
+    }
+
+    // Uploads `buf[..n]` as the first chunk, and the rest of `reader` as the
+    // following chunks.
+    fn resumable_upload(
+        &self,
+        name: &str,
+        reader: &mut Read,
+        mut buf: Vec<u8>,
+        mut n: usize,
+    ) -> io::Result<()> {
+        let url = self.upload_url(name, "resumable");
+        let resp = try!(retry("gcs start resumable upload", || {
+            http::send(try!(self.request(Method::Post, &url, Headers::new(), &[])))
+        }));
+        let session_url = match get_header(&resp.headers, "Location") {
+            Some(url) => url,
+            None => return Err(invalid_data("session url is missing".to_owned())),
+        };
+
+        let mut next_buf = vec![0; CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            // Read ahead to know whether the current chunk is the last one, whose
+            // range must carry the total size.
+            let next_n = try!(read_full(reader, &mut next_buf));
+            let end = offset + n as u64;
+            let range = if next_n == 0 {
+                format!("bytes {}-{}/{}", offset, end - 1, end)
+            } else {
+                format!("bytes {}-{}/*", offset, end - 1)
+            };
+            try!(retry("gcs upload chunk", || {
+                let mut headers = Headers::new();
+                set_header(&mut headers, "Content-Range", &range);
+                let req = try!(self.request(Method::Put, &session_url, headers, &buf[..n]));
+                // Chunks except the last one are responded with 308 Resume Incomplete.
+                http::send_expect(req, StatusCode::PermanentRedirect).map(|_| ())
+            }));
+            if next_n == 0 {
+                return Ok(());
+            }
+            offset = end;
+            mem::swap(&mut buf, &mut next_buf);
+            n = next_n;
+        }
+    }
+}
+
+impl ExternalStorage for GcsStorage {
+    fn write(&self, name: &str, reader: &mut Read) -> io::Result<()> {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let n = try!(read_full(reader, &mut buf));
+        if n == CHUNK_SIZE {
+            return self.resumable_upload(name, reader, buf, n);
+        }
+        let url = self.upload_url(name, "media");
+        retry("gcs upload object", || {
+            http::send(try!(self.request(Method::Post, &url, Headers::new(), &buf[..n])))
+                .map(|_| ())
+        })
+    }
+
+    fn read(&self, name: &str) -> io::Result<Box<Read + Send>> {
+        let url = format!(
+            "{}/storage/v1/b/{}/o/{}?alt=media",
+            self.endpoint,
+            uri_encode(&self.bucket, false),
+            uri_encode(&format!("{}{}", self.prefix, name), false)
+        );
+        let resp = try!(retry("gcs download object", || {
+            http::send(try!(self.request(Method::Get, &url, Headers::new(), &[])))
+        }));
+        Ok(box resp)
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+
+    #[test]
+    fn test_new_gcs_storage() {
+        let url = "gcs://bucket/a/b?access-token=token&endpoint=http://127.0.0.1:4443/";
+        let storage = GcsStorage::new(&Url::parse(url).unwrap()).unwrap();
+        assert_eq!(storage.endpoint, "http://127.0.0.1:4443");
+        assert_eq!(storage.get_token().unwrap(), "token");
+        assert_eq!(
+            storage.upload_url("c d", "media"),
+            "http://127.0.0.1:4443/upload/storage/v1/b/bucket/o?uploadType=media&name=a%2Fb%2Fc%20d"
+        );
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{self, Read};
+use std::time::Duration;
+
+use hyper::{self, Client};
+use hyper::client::{RedirectPolicy, RequestBuilder, Response};
+use hyper::header::Headers;
+use hyper::status::StatusCode;
+
+const TIMEOUT_SECS: u64 = 60;
+
+pub fn new_client() -> Client {
+    let mut client = Client::new();
+    // Redirected requests need to be signed again, so they are not followed.
+    client.set_redirect_policy(RedirectPolicy::FollowNone);
+    client.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+    client.set_write_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+    client
+}
+
+/// Sends the request, non-successful responses are turned into errors. Client
+/// errors other than timeouts and throttling are not retryable, see `retry`.
+pub fn send(req: RequestBuilder) -> io::Result<Response> {
+    send_expect(req, StatusCode::Ok)
+}
+
+/// Like `send`, but `expected` is also treated as successful.
+pub fn send_expect(req: RequestBuilder, expected: StatusCode) -> io::Result<Response> {
+    let mut resp = match req.send() {
+        Ok(resp) => resp,
+        Err(hyper::Error::Io(e)) => return Err(e),
+        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", e))),
+    };
+    if resp.status.is_success() || resp.status == expected {
+        return Ok(resp);
+    }
+    let mut body = String::new();
+    let _ = resp.read_to_string(&mut body);
+    let kind = match resp.status {
+        StatusCode::NotFound => io::ErrorKind::NotFound,
+        StatusCode::Unauthorized | StatusCode::Forbidden => io::ErrorKind::PermissionDenied,
+        StatusCode::RequestTimeout | StatusCode::TooManyRequests => io::ErrorKind::TimedOut,
+        s if s.is_client_error() => io::ErrorKind::InvalidInput,
+        _ => io::ErrorKind::Other,
+    };
+    Err(io::Error::new(kind, format!("{}: {}", resp.status, body)))
+}
+
+pub fn set_header(headers: &mut Headers, name: &str, value: &str) {
+    headers.set_raw(name.to_owned(), vec![value.as_bytes().to_vec()]);
+}
+
+/// Gets the first value of the header `name` as a string.
+pub fn get_header(headers: &Headers, name: &str) -> Option<String> {
+    headers
+        .get_raw(name)
+        .and_then(|values| values.first())
+        .map(|v| String::from_utf8_lossy(v).into_owned())
+}
+
+/// Percent-encodes `s` as required by both S3 and GCS, only the unreserved
+/// characters are kept. `/` is kept as well if `keep_slash` is true.
+pub fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!(uri_encode("a-Z_0.~", false), "a-Z_0.~");
+        assert_eq!(uri_encode("a b/c", false), "a%20b%2Fc");
+        assert_eq!(uri_encode("a b/c", true), "a%20b/c");
+        assert_eq!(uri_encode("=+", true), "%3D%2B");
+    }
+}
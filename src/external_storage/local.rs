@@ -0,0 +1,84 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use super::ExternalStorage;
+
+const TMP_FILE_SUFFIX: &'static str = ".tmp";
+
+/// `LocalStorage` stores objects as files in a local directory.
+pub struct LocalStorage {
+    base: PathBuf,
+}
+
+impl LocalStorage {
+    /// Creates a storage in `base`, the directory is created if missing.
+    pub fn new(base: &Path) -> io::Result<LocalStorage> {
+        try!(fs::create_dir_all(base));
+        Ok(LocalStorage {
+            base: base.to_owned(),
+        })
+    }
+}
+
+impl ExternalStorage for LocalStorage {
+    fn write(&self, name: &str, reader: &mut Read) -> io::Result<()> {
+        // Write into a temporary file first, so a partial object is never seen.
+        let path = self.base.join(name);
+        let tmp_path = self.base.join(format!("{}{}", name, TMP_FILE_SUFFIX));
+        let mut f = try!(File::create(&tmp_path));
+        try!(io::copy(reader, &mut f));
+        try!(f.sync_all());
+        fs::rename(tmp_path, path)
+    }
+
+    fn read(&self, name: &str) -> io::Result<Box<Read + Send>> {
+        let f = try!(File::open(self.base.join(name)));
+        Ok(box f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read};
+
+    use tempdir::TempDir;
+
+    use super::*;
+    use super::super::ExternalStorage;
+
+    #[test]
+    fn test_local_storage() {
+        let dir = TempDir::new("test_local_storage").unwrap();
+        let storage = LocalStorage::new(&dir.path().join("a/b")).unwrap();
+
+        let content = b"abcdefg";
+        storage.write("f", &mut &content[..]).unwrap();
+        let mut read = vec![];
+        storage.read("f").unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, content);
+        assert!(!dir.path().join("a/b/f.tmp").exists());
+
+        // Objects are overwritten.
+        storage.write("f", &mut &b"h"[..]).unwrap();
+        let mut read = vec![];
+        storage.read("f").unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"h");
+
+        let e = storage.read("g").err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
+}
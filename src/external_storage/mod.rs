@@ -0,0 +1,223 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! External storages are where backups are written to and restored from.
+//!
+//! A storage is specified by an URL:
+//!
+//! - `local:///path/to/dir` or simply `/path/to/dir`, a directory on the local
+//!   file system, which is usually a mounted network file system.
+//! - `s3://bucket/prefix?region=us-east-1&endpoint=http://127.0.0.1:9000`, an
+//!   Amazon S3 or S3 compatible object storage. The credentials are taken from
+//!   `access-key` and `secret-access-key` in the query, or from the environment
+//!   variables `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+//! - `gcs://bucket/prefix`, a Google Cloud Storage bucket. The OAuth2 access token
+//!   is taken from `access-token` in the query or the environment variable
+//!   `GCS_ACCESS_TOKEN`, or else fetched from the GCE metadata server.
+
+use std::io::{self, Read};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use url::{ParseError, Url};
+
+mod http;
+mod local;
+mod s3;
+mod gcs;
+
+pub use self::local::LocalStorage;
+pub use self::s3::S3Storage;
+pub use self::gcs::GcsStorage;
+
+const MAX_RETRY_TIMES: usize = 5;
+const RETRY_INIT_BACKOFF_MILLIS: u64 = 200;
+
+/// `ExternalStorage` stores and loads named objects.
+pub trait ExternalStorage: Sync + Send {
+    /// Writes all the contents of `reader` into the object `name`. The object is
+    /// only visible after the whole content is written.
+    fn write(&self, name: &str, reader: &mut Read) -> io::Result<()>;
+
+    /// Opens the object `name` for reading.
+    fn read(&self, name: &str) -> io::Result<Box<Read + Send>>;
+}
+
+/// Creates the storage specified by `url`, see the module document for the
+/// supported forms.
+pub fn create_storage(url: &str) -> io::Result<Box<ExternalStorage>> {
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(ParseError::RelativeUrlWithoutBase) => {
+            return Ok(box try!(LocalStorage::new(Path::new(url))) as Box<ExternalStorage>);
+        }
+        Err(e) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid storage url {}: {:?}", url, e),
+            ))
+        }
+    };
+    let storage: Box<ExternalStorage> = match url.scheme() {
+        "local" | "file" => box try!(LocalStorage::new(Path::new(url.path()))),
+        "s3" => box try!(S3Storage::new(&url)),
+        "gcs" | "gs" => box try!(GcsStorage::new(&url)),
+        scheme => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported storage scheme {}", scheme),
+            ))
+        }
+    };
+    Ok(storage)
+}
+
+// Gets the value of `key` in the query of `url`, or else from the environment
+// variable `env`.
+fn get_param(url: &Url, key: &str, env: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|&(ref k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+        .or_else(|| ::std::env::var(env).ok())
+}
+
+// Splits `bucket/prefix` URLs into the bucket and the object name prefix, which
+// is either empty or ends with a '/'.
+fn parse_bucket(url: &Url) -> io::Result<(String, String)> {
+    let bucket = match url.host_str() {
+        Some(bucket) if !bucket.is_empty() => bucket.to_owned(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bucket is missing in {}", url),
+            ))
+        }
+    };
+    let mut prefix = url.path().trim_matches('/').to_owned();
+    if !prefix.is_empty() {
+        prefix.push('/');
+    }
+    Ok((bucket, prefix))
+}
+
+fn is_retryable(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::NotFound |
+        io::ErrorKind::PermissionDenied |
+        io::ErrorKind::InvalidInput |
+        io::ErrorKind::InvalidData => false,
+        _ => true,
+    }
+}
+
+// Calls `f` until it succeeds, fails with an unretryable error, or has been
+// retried too many times. The backoff doubles after each retry.
+fn retry<T, F>(tag: &str, mut f: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    let mut backoff = Duration::from_millis(RETRY_INIT_BACKOFF_MILLIS);
+    let mut retry_times = 0;
+    loop {
+        match f() {
+            Ok(t) => return Ok(t),
+            Err(e) => {
+                retry_times += 1;
+                if retry_times >= MAX_RETRY_TIMES || !is_retryable(&e) {
+                    return Err(e);
+                }
+                warn!("{} failed, retry after {:?}: {:?}", tag, backoff, e);
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+    }
+}
+
+// Reads until `buf` is full or the reader reaches the end, returns the number
+// of bytes read.
+fn read_full(reader: &mut Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tempdir::TempDir;
+    use url::Url;
+
+    use super::*;
+
+    #[test]
+    fn test_create_storage() {
+        let dir = TempDir::new("test_create_storage").unwrap();
+        let path = dir.path().join("backup");
+        let storage = create_storage(path.to_str().unwrap()).unwrap();
+        storage.write("a", &mut &b"abc"[..]).unwrap();
+        assert!(path.join("a").exists());
+
+        let url = format!("local://{}", dir.path().join("local").display());
+        create_storage(&url).unwrap();
+        assert!(dir.path().join("local").exists());
+
+        let e = create_storage("hdfs://bucket/prefix").err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_parse_bucket() {
+        let cases = vec![
+            ("s3://bucket", "bucket", ""),
+            ("s3://bucket/", "bucket", ""),
+            ("s3://bucket/a/b", "bucket", "a/b/"),
+            ("s3://bucket/a/b/", "bucket", "a/b/"),
+        ];
+        for (url, bucket, prefix) in cases {
+            let url = Url::parse(url).unwrap();
+            assert_eq!(parse_bucket(&url).unwrap(), (bucket.to_owned(), prefix.to_owned()));
+        }
+        assert!(parse_bucket(&Url::parse("s3:///a").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut times = 0;
+        let res: io::Result<()> = retry("test", || {
+            times += 1;
+            Err(io::Error::new(io::ErrorKind::NotFound, "not found"))
+        });
+        assert!(res.is_err());
+        assert_eq!(times, 1);
+
+        let mut times = 0;
+        let res = retry("test", || {
+            times += 1;
+            if times < 3 {
+                return Err(io::Error::new(io::ErrorKind::Other, "busy"));
+            }
+            Ok(times)
+        });
+        assert_eq!(res.unwrap(), 3);
+    }
+}
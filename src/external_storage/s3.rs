@@ -0,0 +1,383 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{self, Read};
+
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use hyper::Client;
+use hyper::client::Response;
+use hyper::header::Headers;
+use hyper::method::Method;
+use time;
+use url::Url;
+
+use super::{get_param, parse_bucket, read_full, retry, ExternalStorage};
+use super::http::{self, get_header, set_header, uri_encode};
+
+const DEFAULT_REGION: &'static str = "us-east-1";
+const SIGN_ALGORITHM: &'static str = "AWS4-HMAC-SHA256";
+const SERVICE: &'static str = "s3";
+// Objects larger than one part are uploaded in multiple parts, S3 requires all
+// parts except the last one to be at least 5MiB.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// `S3Storage` stores objects in an Amazon S3 or S3 compatible bucket. Requests
+/// are signed with AWS signature version 4.
+pub struct S3Storage {
+    client: Client,
+    endpoint: Url,
+    region: String,
+    bucket: String,
+    prefix: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl S3Storage {
+    /// Creates a storage from `s3://bucket/prefix?region=..&endpoint=..`.
+    pub fn new(url: &Url) -> io::Result<S3Storage> {
+        let (bucket, prefix) = try!(parse_bucket(url));
+        let region =
+            get_param(url, "region", "AWS_REGION").unwrap_or_else(|| DEFAULT_REGION.to_owned());
+        let endpoint = get_param(url, "endpoint", "AWS_ENDPOINT")
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+        let endpoint = match Url::parse(&endpoint) {
+            Ok(endpoint) => endpoint,
+            Err(e) => return Err(invalid_input(format!("invalid endpoint {}: {:?}", endpoint, e))),
+        };
+        let access_key = match get_param(url, "access-key", "AWS_ACCESS_KEY_ID") {
+            Some(key) => key,
+            None => return Err(invalid_input("access key is missing".to_owned())),
+        };
+        let secret_key = match get_param(url, "secret-access-key", "AWS_SECRET_ACCESS_KEY") {
+            Some(key) => key,
+            None => return Err(invalid_input("secret access key is missing".to_owned())),
+        };
+        Ok(S3Storage {
+            client: http::new_client(),
+            endpoint: endpoint,
+            region: region,
+            bucket: bucket,
+            prefix: prefix,
+            access_key: access_key,
+            secret_key: secret_key,
+        })
+    }
+
+    // Sends a signed request on the object `name`. Objects are addressed in the
+    // path style, which is supported by S3 compatible storages as well.
+    fn request(
+        &self,
+        method: Method,
+        name: &str,
+        query: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<Response> {
+        let path = format!(
+            "{}/{}/{}{}",
+            self.endpoint.path().trim_right_matches('/'),
+            self.bucket,
+            self.prefix,
+            name
+        );
+        let uri = uri_encode(&path, true);
+        let mut query: Vec<_> = query
+            .iter()
+            .map(|&(k, v)| format!("{}={}", uri_encode(k, false), uri_encode(v, false)))
+            .collect();
+        query.sort();
+        let query = query.join("&");
+        let mut url = self.endpoint.clone();
+        url.set_path(&uri);
+        url.set_query(if query.is_empty() { None } else { Some(&query) });
+
+        let mut host = self.endpoint.host_str().unwrap_or_default().to_owned();
+        if let Some(port) = self.endpoint.port() {
+            host = format!("{}:{}", host, port);
+        }
+        let amz_date = time::strftime("%Y%m%dT%H%M%SZ", &time::now_utc()).unwrap();
+        let payload_hash = sha256_hex(body);
+        let (canonical_request, signed_headers) = canonical_request(
+            method.as_ref(),
+            &uri,
+            &query,
+            &[
+                ("host", host.as_str()),
+                ("x-amz-content-sha256", payload_hash.as_str()),
+                ("x-amz-date", amz_date.as_str()),
+            ],
+            &payload_hash,
+        );
+        let authorization = format!(
+            "{} Credential={}/{}/{}/{}/aws4_request, SignedHeaders={}, Signature={}",
+            SIGN_ALGORITHM,
+            self.access_key,
+            &amz_date[..8],
+            self.region,
+            SERVICE,
+            signed_headers,
+            signature(&self.secret_key, &self.region, &amz_date, &canonical_request)
+        );
+
+        let mut headers = Headers::new();
+        set_header(&mut headers, "Host", &host);
+        set_header(&mut headers, "x-amz-content-sha256", &payload_hash);
+        set_header(&mut headers, "x-amz-date", &amz_date);
+        set_header(&mut headers, "Authorization", &authorization);
+        http::send(self.client.request(method, url).headers(headers).body(body))
+    }
+
+    fn create_multipart_upload(&self, name: &str) -> io::Result<String> {
+        let mut resp = try!(retry("s3 create multipart upload", || {
+            self.request(Method::Post, name, &[("uploads", "")], &[])
+        }));
+        let mut body = String::new();
+        try!(resp.read_to_string(&mut body));
+        match get_xml_value(&body, "UploadId") {
+            Some(upload_id) => Ok(upload_id),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("upload id is missing in {}", body),
+            )),
+        }
+    }
+
+    // Uploads `buf[..n]` as the first part, and the rest of `reader` as the
+    // following parts, then completes the upload.
+    fn upload_parts(
+        &self,
+        name: &str,
+        upload_id: &str,
+        reader: &mut Read,
+        mut buf: Vec<u8>,
+        mut n: usize,
+    ) -> io::Result<()> {
+        let mut etags = vec![];
+        while n > 0 {
+            let part_number = (etags.len() + 1).to_string();
+            let resp = try!(retry("s3 upload part", || {
+                let query = [("partNumber", part_number.as_str()), ("uploadId", upload_id)];
+                self.request(Method::Put, name, &query, &buf[..n])
+            }));
+            match get_header(&resp.headers, "ETag") {
+                Some(etag) => etags.push(etag),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("etag of part {} is missing", part_number),
+                    ))
+                }
+            }
+            n = try!(read_full(reader, &mut buf));
+        }
+
+        let mut body = "<CompleteMultipartUpload>".to_owned();
+        for (i, etag) in etags.iter().enumerate() {
+            body.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                i + 1,
+                etag
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let mut resp = try!(retry("s3 complete multipart upload", || {
+            self.request(Method::Post, name, &[("uploadId", upload_id)], body.as_bytes())
+        }));
+        // The completion may fail after the response header is sent, in which case
+        // the error is in the body.
+        let mut resp_body = String::new();
+        try!(resp.read_to_string(&mut resp_body));
+        if resp_body.contains("<Error>") {
+            return Err(io::Error::new(io::ErrorKind::Other, resp_body));
+        }
+        Ok(())
+    }
+}
+
+impl ExternalStorage for S3Storage {
+    fn write(&self, name: &str, reader: &mut Read) -> io::Result<()> {
+        let mut buf = vec![0; PART_SIZE];
+        let n = try!(read_full(reader, &mut buf));
+        if n < PART_SIZE {
+            return retry("s3 put object", || {
+                self.request(Method::Put, name, &[], &buf[..n]).map(|_| ())
+            });
+        }
+
+        let upload_id = try!(self.create_multipart_upload(name));
+        let res = self.upload_parts(name, &upload_id, reader, buf, n);
+        if res.is_err() {
+            // Abort the upload, or the uploaded parts are kept and charged.
+            let query = [("uploadId", upload_id.as_str())];
+            if let Err(e) = self.request(Method::Delete, name, &query, &[]) {
+                warn!("abort multipart upload of {} failed: {:?}", name, e);
+            }
+        }
+        res
+    }
+
+    fn read(&self, name: &str) -> io::Result<Box<Read + Send>> {
+        let resp = try!(retry("s3 get object", || {
+            self.request(Method::Get, name, &[], &[])
+        }));
+        Ok(box resp)
+    }
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn get_xml_value(xml: &str, tag: &str) -> Option<String> {
+    let start_tag = format!("<{}>", tag);
+    let end_tag = format!("</{}>", tag);
+    let start = match xml.find(&start_tag) {
+        Some(pos) => pos + start_tag.len(),
+        None => return None,
+    };
+    xml[start..]
+        .find(&end_tag)
+        .map(|len| xml[start..start + len].to_owned())
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+// Builds the canonical request, returns it together with the signed header
+// names. `headers` must be sorted by their names in lower case.
+fn canonical_request(
+    method: &str,
+    uri: &str,
+    query: &str,
+    headers: &[(&str, &str)],
+    payload_hash: &str,
+) -> (String, String) {
+    let mut canonical_headers = String::new();
+    for &(name, value) in headers {
+        canonical_headers.push_str(&format!("{}:{}\n", name, value.trim()));
+    }
+    let signed_headers = headers
+        .iter()
+        .map(|&(name, _)| name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        uri,
+        query,
+        canonical_headers,
+        signed_headers,
+        payload_hash
+    );
+    (canonical_request, signed_headers)
+}
+
+// Calculates the signature of the request, `amz_date` is in the form of
+// `20130524T000000Z`.
+fn signature(secret_key: &str, region: &str, amz_date: &str, canonical_request: &str) -> String {
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, SERVICE);
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        SIGN_ALGORITHM,
+        amz_date,
+        scope,
+        sha256_hex(canonical_request.as_bytes())
+    );
+    let key = format!("AWS4{}", secret_key);
+    let key = hmac_sha256(key.as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, SERVICE.as_bytes());
+    let key = hmac_sha256(&key, b"aws4_request");
+    hmac_sha256(&key, string_to_sign.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // The example of GET Object in the AWS signature version 4 document.
+        let empty_hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(sha256_hex(b""), empty_hash);
+        let (canonical_request, signed_headers) = canonical_request(
+            "GET",
+            "/test.txt",
+            "",
+            &[
+                ("host", "examplebucket.s3.amazonaws.com"),
+                ("range", "bytes=0-9"),
+                ("x-amz-content-sha256", empty_hash),
+                ("x-amz-date", "20130524T000000Z"),
+            ],
+            empty_hash,
+        );
+        assert_eq!(signed_headers, "host;range;x-amz-content-sha256;x-amz-date");
+        let sig = signature(
+            "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+            "us-east-1",
+            "20130524T000000Z",
+            &canonical_request,
+        );
+        assert_eq!(
+            sig,
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+    }
+
+    #[test]
+    fn test_new_s3_storage() {
+        let url = "s3://bucket/backup?region=cn-north-1&access-key=ak&secret-access-key=sk";
+        let storage = S3Storage::new(&Url::parse(url).unwrap()).unwrap();
+        assert_eq!(storage.bucket, "bucket");
+        assert_eq!(storage.prefix, "backup/");
+        assert_eq!(storage.region, "cn-north-1");
+        assert_eq!(storage.access_key, "ak");
+        assert_eq!(storage.secret_key, "sk");
+        assert_eq!(
+            storage.endpoint.as_str(),
+            "https://s3.cn-north-1.amazonaws.com/"
+        );
+
+        let url = "s3://bucket?endpoint=http://127.0.0.1:9000&access-key=ak&secret-access-key=sk";
+        let storage = S3Storage::new(&Url::parse(url).unwrap()).unwrap();
+        assert_eq!(storage.endpoint.port(), Some(9000));
+        assert_eq!(storage.prefix, "");
+    }
+
+    #[test]
+    fn test_get_xml_value() {
+        let xml = "<Result><Bucket>b</Bucket><UploadId>abc</UploadId></Result>";
+        assert_eq!(get_xml_value(xml, "UploadId").unwrap(), "abc");
+        assert!(get_xml_value(xml, "Key").is_none());
+    }
+}
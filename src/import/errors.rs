@@ -0,0 +1,75 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{error, result};
+use std::io::Error as IoError;
+
+use kvproto::import_sstpb::Error as ErrorPb;
+
+use storage::EngineError;
+use storage::mvcc::Error as MvccError;
+use util::codec::Error as CodecError;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: IoError) {
+            from()
+            cause(err)
+            display("{:?}", err)
+            description(err.description())
+        }
+        Codec(err: CodecError) {
+            from()
+            cause(err)
+            display("{:?}", err)
+            description(err.description())
+        }
+        Engine(err: EngineError) {
+            from()
+            cause(err)
+            display("{:?}", err)
+            description(err.description())
+        }
+        Mvcc(err: MvccError) {
+            from()
+            cause(err)
+            display("{:?}", err)
+            description(err.description())
+        }
+        RocksDB(msg: String) {
+            display("RocksDB {}", msg)
+            description("RocksDB error")
+        }
+        FileCorrupted(msg: String) {
+            display("file corrupted {}", msg)
+            description("file corrupted")
+        }
+        Other(err: Box<error::Error + Sync + Send>) {
+            from()
+            cause(err.as_ref())
+            description(err.description())
+            display("{:?}", err)
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Into<ErrorPb> for Error {
+    fn into(self) -> ErrorPb {
+        let mut err = ErrorPb::new();
+        err.set_message(format!("{:?}", self));
+        err
+    }
+}
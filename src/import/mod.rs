@@ -0,0 +1,34 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Import loads SST files, such as the ones produced by backup, into the
+//! cluster. A restore tool first asks every store holding a peer of the target
+//! region to download the file from the external storage, rewriting its keys
+//! for the target cluster, then asks the leader to ingest it. The ingestion is
+//! proposed as a raft command, so all the peers ingest their local copies.
+//!
+//! Before a large import, the tool may ask the leader of the target region to
+//! split it at the boundaries of the files and scatter the new regions, so that
+//! the files are ingested into many regions led by different stores.
+//!
+//! When importing into a range with existing data, the leader can be asked to
+//! detect the keys of the file which already exist with different values, and
+//! report them instead of ingesting the file.
+
+mod errors;
+mod sst_importer;
+mod service;
+
+pub use self::errors::{Error, Result};
+pub use self::sst_importer::{check_sst_for_ingestion, SSTImporter};
+pub use self::service::Service;
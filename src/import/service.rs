@@ -0,0 +1,273 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use grpc::{Error as GrpcError, RpcContext, UnarySink};
+use futures::Future;
+use futures_cpupool::{Builder, CpuPool};
+use kvproto::errorpb;
+use kvproto::import_sstpb::{DownloadRequest, DownloadResponse, IngestRequest, IngestResponse,
+                             SplitAndScatterRequest, SplitAndScatterResponse};
+use kvproto::import_sstpb_grpc::ImportSst;
+use kvproto::raft_cmdpb::{CmdType, RaftCmdRequest, Request};
+use protobuf::RepeatedField;
+
+use raftstore::store::Msg as StoreMsg;
+use server::transport::RaftStoreRouter;
+use storage::{Engine, EngineError};
+use super::SSTImporter;
+
+// Ingesting a file only links it into the engine, so it's applied quickly.
+const INGEST_TIMEOUT_SECS: u64 = 30;
+// A split asks pd for the new region id first, then goes through raft.
+const SPLIT_TIMEOUT_SECS: u64 = 30;
+
+/// Service handles the RPC messages for the `ImportSST` service.
+///
+/// Downloads may take a long time, so requests are served on a dedicated
+/// thread pool.
+pub struct Service<T: RaftStoreRouter> {
+    pool: CpuPool,
+    router: T,
+    engine: Box<Engine>,
+    importer: Arc<SSTImporter>,
+}
+
+impl<T: RaftStoreRouter> Service<T> {
+    pub fn new(router: T, engine: Box<Engine>, importer: Arc<SSTImporter>) -> Service<T> {
+        let pool = Builder::new()
+            .name_prefix(thd_name!("sst-importer"))
+            .pool_size(4)
+            .create();
+        Service {
+            pool: pool,
+            router: router,
+            engine: engine,
+            importer: importer,
+        }
+    }
+}
+
+impl<T: RaftStoreRouter> Clone for Service<T> {
+    fn clone(&self) -> Service<T> {
+        Service {
+            pool: self.pool.clone(),
+            router: self.router.clone(),
+            engine: self.engine.clone(),
+            importer: self.importer.clone(),
+        }
+    }
+}
+
+fn new_region_error(msg: String) -> errorpb::Error {
+    let mut err = errorpb::Error::new();
+    err.set_message(msg);
+    err
+}
+
+// Proposes the ingestion of `req` and waits until it's applied on the leader.
+//
+// If `detect_duplicate` is set, the file is checked against a snapshot of the
+// region first, and it's not ingested if any of its keys exists with a
+// different value. The duplicated pairs are returned instead.
+fn ingest<T: RaftStoreRouter>(
+    router: &T,
+    engine: &Engine,
+    importer: &SSTImporter,
+    mut req: IngestRequest,
+) -> IngestResponse {
+    let mut resp = IngestResponse::new();
+    let sst = req.take_sst();
+    // Followers are expected to have downloaded the file as well, there is no
+    // way to check that here.
+    if !importer.exist(&sst) {
+        resp.set_error(new_region_error(format!("{:?} is not downloaded", sst)));
+        return resp;
+    }
+
+    let context = req.take_context();
+    if req.get_detect_duplicate() {
+        let snapshot = match engine.snapshot(&context) {
+            Ok(snapshot) => snapshot,
+            Err(EngineError::Request(e)) => {
+                resp.set_error(e);
+                return resp;
+            }
+            Err(e) => {
+                resp.set_error(new_region_error(format!("{:?}", e)));
+                return resp;
+            }
+        };
+        match importer.detect_duplicates(&sst, snapshot.as_ref(), req.get_is_raw_kv()) {
+            Ok(ref pairs) if pairs.is_empty() => {}
+            Ok(pairs) => {
+                warn!("{} duplicated keys found in {:?}", pairs.len(), sst);
+                resp.set_duplicated_pairs(RepeatedField::from_vec(pairs));
+                return resp;
+            }
+            Err(e) => {
+                resp.set_error(new_region_error(format!("{:?}", e)));
+                return resp;
+            }
+        }
+    }
+
+    let mut ingest = Request::new();
+    ingest.set_cmd_type(CmdType::IngestSST);
+    ingest.mut_ingest_sst().set_sst(sst);
+    let mut cmd = RaftCmdRequest::new();
+    cmd.mut_header().set_region_id(context.get_region_id());
+    cmd.mut_header().set_peer(context.get_peer().clone());
+    cmd.mut_header()
+        .set_region_epoch(context.get_region_epoch().clone());
+    cmd.mut_requests().push(ingest);
+
+    let (tx, rx) = mpsc::channel();
+    if let Err(e) = router.send_command(cmd, box move |r| { let _ = tx.send(r); }) {
+        resp.set_error(e.into());
+        return resp;
+    }
+    match rx.recv_timeout(Duration::from_secs(INGEST_TIMEOUT_SECS)) {
+        Ok(mut r) => if r.get_header().has_error() {
+            resp.set_error(r.mut_header().take_error());
+        },
+        Err(e) => resp.set_error(new_region_error(format!("{:?}", e))),
+    }
+    resp
+}
+
+// Splits the region of the request at all the split keys, from the largest one,
+// so that the remaining keys are always in the left region, which stays on this
+// store. The resulting regions are scattered by pd, so the files of a large
+// import are downloaded and ingested by the leaders of the regions across the
+// stores, instead of all going through a single region.
+fn split_and_scatter<T: RaftStoreRouter>(
+    router: &T,
+    mut req: SplitAndScatterRequest,
+) -> SplitAndScatterResponse {
+    let mut resp = SplitAndScatterResponse::new();
+    let context = req.take_context();
+    let mut split_keys = req.take_split_keys().into_vec();
+    split_keys.sort();
+    split_keys.dedup();
+
+    let mut region_id = context.get_region_id();
+    let mut region_epoch = context.get_region_epoch().clone();
+    let mut regions = vec![];
+    let mut left = None;
+    for split_key in split_keys.into_iter().rev() {
+        let (tx, rx) = mpsc::channel();
+        let msg = StoreMsg::SplitRegion {
+            region_id: region_id,
+            region_epoch: region_epoch.clone(),
+            split_key: split_key,
+            callback: box move |r| { let _ = tx.send(r); },
+        };
+        if let Err(e) = router.send(msg) {
+            resp.set_error(e.into());
+            return resp;
+        }
+        let mut r = match rx.recv_timeout(Duration::from_secs(SPLIT_TIMEOUT_SECS)) {
+            Ok(r) => r,
+            Err(e) => {
+                resp.set_error(new_region_error(format!("{:?}", e)));
+                return resp;
+            }
+        };
+        if r.get_header().has_error() {
+            resp.set_error(r.mut_header().take_error());
+            return resp;
+        }
+        let mut split = r.mut_admin_response().take_split();
+        let region = split.take_left();
+        region_id = region.get_id();
+        region_epoch = region.get_region_epoch().clone();
+        regions.push(split.take_right());
+        left = Some(region);
+    }
+    regions.extend(left);
+    regions.reverse();
+
+    for region in &regions {
+        let msg = StoreMsg::ScatterRegion {
+            region: region.clone(),
+        };
+        if let Err(e) = router.send(msg) {
+            warn!("failed to scatter region {}: {:?}", region.get_id(), e);
+        }
+    }
+    resp.set_regions(RepeatedField::from_vec(regions));
+    resp
+}
+
+impl<T: RaftStoreRouter + 'static> ImportSst for Service<T> {
+    fn download(&self, ctx: RpcContext, req: DownloadRequest, sink: UnarySink<DownloadResponse>) {
+        const TAG: &'static str = "import_download";
+
+        let importer = self.importer.clone();
+        let f = self.pool.spawn_fn(move || {
+            let mut resp = DownloadResponse::new();
+            let res = importer.download(
+                req.get_sst(),
+                req.get_url(),
+                req.get_name(),
+                req.get_rewrite_rule(),
+                req.get_is_raw_kv(),
+            );
+            match res {
+                Ok(Some(meta)) => resp.set_sst(meta),
+                Ok(None) => resp.set_is_empty(true),
+                Err(e) => {
+                    error!("download {} from {} failed: {:?}", req.get_name(), req.get_url(), e);
+                    resp.set_error(e.into());
+                }
+            }
+            Ok::<_, GrpcError>(resp)
+        });
+        let future = f.and_then(|resp| sink.success(resp))
+            .map_err(|e| error!("{} failed: {:?}", TAG, e));
+        ctx.spawn(future);
+    }
+
+    fn ingest(&self, ctx: RpcContext, req: IngestRequest, sink: UnarySink<IngestResponse>) {
+        const TAG: &'static str = "import_ingest";
+
+        let router = self.router.clone();
+        let engine = self.engine.clone();
+        let importer = self.importer.clone();
+        let f = self.pool.spawn_fn(move || {
+            Ok::<_, GrpcError>(ingest(&router, engine.as_ref(), &importer, req))
+        });
+        let future = f.and_then(|resp| sink.success(resp))
+            .map_err(|e| error!("{} failed: {:?}", TAG, e));
+        ctx.spawn(future);
+    }
+
+    fn split_and_scatter(
+        &self,
+        ctx: RpcContext,
+        req: SplitAndScatterRequest,
+        sink: UnarySink<SplitAndScatterResponse>,
+    ) {
+        const TAG: &'static str = "import_split_and_scatter";
+
+        let router = self.router.clone();
+        let f = self.pool
+            .spawn_fn(move || Ok::<_, GrpcError>(split_and_scatter(&router, req)));
+        let future = f.and_then(|resp| sink.success(resp))
+            .map_err(|e| error!("{} failed: {:?}", TAG, e));
+        ctx.spawn(future);
+    }
+}
@@ -0,0 +1,709 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::u64;
+
+use kvproto::import_sstpb::{DuplicatedPair, Range, RewriteRule, SSTMeta};
+use kvproto::kvrpcpb::IsolationLevel;
+use kvproto::metapb::Region;
+use rocksdb::{ColumnFamilyOptions, DBCompressionType, EnvOptions, IngestExternalFileOptions,
+              ReadOptions, SeekKey, SstFileWriter, DB};
+
+use external_storage::create_storage;
+use raftstore::store::keys;
+use storage::{Key, Snapshot, Statistics, CF_DEFAULT, CF_WRITE, DATA_CFS};
+use storage::mvcc::{MvccReader, Write};
+use storage::types::split_encoded_key_on_ts;
+use util::escape;
+use util::file::{calc_crc32, get_file_size};
+use util::rocksdb::{self as rocksdb_util, get_cf_handle, get_fastest_supported_compression_type};
+use super::{Error, Result};
+
+const TEMP_DIR: &'static str = ".temp";
+
+/// `SSTImporter` manages the SST files downloaded for ingestion.
+///
+/// A file is identified by its meta, so that the same file can be found on all
+/// the stores when the ingestion is applied.
+#[derive(Debug)]
+pub struct SSTImporter {
+    root: PathBuf,
+    temp: PathBuf,
+}
+
+impl SSTImporter {
+    /// Creates an importer in the directory `root`. Files of unfinished downloads
+    /// are cleaned up.
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<SSTImporter> {
+        let root = root.as_ref().to_owned();
+        let temp = root.join(TEMP_DIR);
+        if temp.exists() {
+            try!(fs::remove_dir_all(&temp));
+        }
+        try!(fs::create_dir_all(&temp));
+        Ok(SSTImporter {
+            root: root,
+            temp: temp,
+        })
+    }
+
+    fn get_path(&self, meta: &SSTMeta) -> PathBuf {
+        let uuid: String = meta.get_uuid()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let epoch = meta.get_region_epoch();
+        self.root.join(format!(
+            "{}_{}_{}_{}_{}.sst",
+            uuid,
+            meta.get_region_id(),
+            epoch.get_conf_ver(),
+            epoch.get_version(),
+            meta.get_cf_name()
+        ))
+    }
+
+    pub fn exist(&self, meta: &SSTMeta) -> bool {
+        self.get_path(meta).exists()
+    }
+
+    pub fn delete(&self, meta: &SSTMeta) -> Result<()> {
+        match fs::remove_file(self.get_path(meta)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res.map_err(Error::from),
+        }
+    }
+
+    /// Downloads the backup file `name` from the external storage `url` and
+    /// rewrites its keys with `rewrite_rule`. Only the rewritten keys within the
+    /// range `[start, end)` of `meta` are kept. Returns the meta of the file with
+    /// the range of the kept keys, whose end is inclusive, or `None` if there is
+    /// no key to ingest.
+    ///
+    /// If `is_raw_kv` is set, the file is a backup of raw keys, whose keys are
+    /// rewritten without versions, and the range of `meta` is in raw keys.
+    pub fn download(
+        &self,
+        meta: &SSTMeta,
+        url: &str,
+        name: &str,
+        rewrite_rule: &RewriteRule,
+        is_raw_kv: bool,
+    ) -> Result<Option<SSTMeta>> {
+        let path = self.get_path(meta);
+        let file_name = path.file_name().unwrap().to_str().unwrap().to_owned();
+        let download_path = self.temp.join(format!("{}.download", file_name));
+        let db_path = self.temp.join(format!("{}.db", file_name));
+        let sst_path = self.temp.join(&file_name);
+
+        {
+            let storage = try!(create_storage(url));
+            let mut reader = try!(storage.read(name));
+            let mut f = try!(File::create(&download_path));
+            try!(io::copy(&mut reader, &mut f));
+            try!(f.sync_all());
+        }
+        let res = rewrite(
+            meta,
+            rewrite_rule,
+            is_raw_kv,
+            &download_path,
+            &db_path,
+            &sst_path,
+        );
+        let _ = fs::remove_file(&download_path);
+        let _ = fs::remove_dir_all(&db_path);
+        let range = match res {
+            Ok(Some(range)) => range,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                let _ = fs::remove_file(&sst_path);
+                return Err(e);
+            }
+        };
+
+        let mut meta = meta.clone();
+        meta.set_range(range);
+        meta.set_length(try!(get_file_size(&sst_path)));
+        meta.set_crc32(try!(calc_crc32(&sst_path)));
+        try!(fs::rename(&sst_path, &path));
+        info!("downloaded {} from {} to {}", name, url, path.display());
+        Ok(Some(meta))
+    }
+
+    /// Finds the keys in the downloaded file of `meta` which already exist in
+    /// `snapshot` with different values, so that the ingestion doesn't overwrite
+    /// them silently. For transactional keys, only the latest version in the
+    /// file is compared with the latest committed value. The keys of the
+    /// returned pairs are raw keys.
+    pub fn detect_duplicates(
+        &self,
+        meta: &SSTMeta,
+        snapshot: &Snapshot,
+        is_raw_kv: bool,
+    ) -> Result<Vec<DuplicatedPair>> {
+        let path = self.get_path(meta);
+        let file_name = path.file_name().unwrap().to_str().unwrap().to_owned();
+        let db_path = self.temp.join(format!("{}.detect", file_name));
+        let res = detect_duplicates(meta, is_raw_kv, &path, &db_path, snapshot);
+        let _ = fs::remove_dir_all(&db_path);
+        res
+    }
+
+    /// Ingests the downloaded file of `meta` into `engine`. The length and
+    /// checksum are verified if they are set in `meta`.
+    pub fn ingest(&self, meta: &SSTMeta, engine: &DB) -> Result<()> {
+        let path = self.get_path(meta);
+        if meta.get_length() != 0 {
+            let length = try!(get_file_size(&path));
+            if length != meta.get_length() {
+                return Err(Error::FileCorrupted(format!(
+                    "{} length {}, expect {}",
+                    path.display(),
+                    length,
+                    meta.get_length()
+                )));
+            }
+        }
+        if meta.get_crc32() != 0 {
+            let crc32 = try!(calc_crc32(&path));
+            if crc32 != meta.get_crc32() {
+                return Err(Error::FileCorrupted(format!(
+                    "{} crc32 {}, expect {}",
+                    path.display(),
+                    crc32,
+                    meta.get_crc32()
+                )));
+            }
+        }
+
+        let handle = try!(get_cf_handle(engine, meta.get_cf_name()).map_err(Error::RocksDB));
+        let mut opts = IngestExternalFileOptions::new();
+        opts.move_files(true);
+        try!(
+            engine
+                .ingest_external_file_cf(handle, &opts, &[path.to_str().unwrap()])
+                .map_err(Error::RocksDB)
+        );
+        // The file is linked into the engine, the original one is useless now.
+        try!(fs::remove_file(&path));
+        Ok(())
+    }
+}
+
+/// Checks whether the file of `sst` can be ingested into `region`. `sst` is the
+/// meta returned by `download`, whose range is the range of its keys.
+pub fn check_sst_for_ingestion(sst: &SSTMeta, region: &Region) -> Result<()> {
+    if sst.get_region_id() != region.get_id() {
+        return Err(box_err!(
+            "region id mismatch, sst {}, region {}",
+            sst.get_region_id(),
+            region.get_id()
+        ));
+    }
+    let epoch = sst.get_region_epoch();
+    let region_epoch = region.get_region_epoch();
+    if epoch.get_conf_ver() != region_epoch.get_conf_ver() ||
+        epoch.get_version() != region_epoch.get_version()
+    {
+        return Err(box_err!(
+            "epoch mismatch, sst {:?}, region {:?}",
+            epoch,
+            region_epoch
+        ));
+    }
+    let range = sst.get_range();
+    let region_end = region.get_end_key();
+    if range.get_start() < region.get_start_key() ||
+        (!region_end.is_empty() && range.get_end() >= region_end)
+    {
+        return Err(box_err!(
+            "range [{}, {}] is not in region {:?}",
+            escape(range.get_start()),
+            escape(range.get_end()),
+            region
+        ));
+    }
+    Ok(())
+}
+
+// Rewrites the data key `key`, which is `z` + encoded user key + ts. The old
+// prefix and the new prefix of `rule` are in raw user keys. Returns the encoded
+// user key and the ts after rewriting, or `None` if the key doesn't match the
+// old prefix.
+fn rewrite_key(key: &[u8], rule: &RewriteRule) -> Result<Option<(Vec<u8>, u64)>> {
+    let (encoded, ts) = try!(split_encoded_key_on_ts(keys::origin_key(key)));
+    let raw = try!(Key::from_encoded(encoded.to_vec()).raw());
+    let old_prefix = rule.get_old_key_prefix();
+    if !raw.starts_with(old_prefix) {
+        return Ok(None);
+    }
+    let mut new_raw = rule.get_new_key_prefix().to_vec();
+    new_raw.extend_from_slice(&raw[old_prefix.len()..]);
+    let new_ts = if rule.get_new_timestamp() != 0 {
+        rule.get_new_timestamp()
+    } else {
+        ts
+    };
+    Ok(Some((Key::from_raw(&new_raw).encoded().to_owned(), new_ts)))
+}
+
+// Rewrites the data key `key` of a raw key, which is `z` + raw key. Returns the
+// raw key after rewriting, or `None` if the key doesn't match the old prefix.
+fn rewrite_raw_key(key: &[u8], rule: &RewriteRule) -> Option<Vec<u8>> {
+    let raw = keys::origin_key(key);
+    let old_prefix = rule.get_old_key_prefix();
+    if !raw.starts_with(old_prefix) {
+        return None;
+    }
+    let mut new_raw = rule.get_new_key_prefix().to_vec();
+    new_raw.extend_from_slice(&raw[old_prefix.len()..]);
+    Some(new_raw)
+}
+
+// Reads the downloaded file `input` through a scratch engine in `db_path`, and
+// writes the rewritten pairs into `output`. Returns the range of the written keys.
+fn rewrite(
+    meta: &SSTMeta,
+    rule: &RewriteRule,
+    is_raw_kv: bool,
+    input: &Path,
+    db_path: &Path,
+    output: &Path,
+) -> Result<Option<Range>> {
+    let cf = meta.get_cf_name();
+    // Raw keys may be in any cf, while the backup of transactions has no lock.
+    let supported = if is_raw_kv {
+        DATA_CFS.contains(&cf)
+    } else {
+        cf == CF_DEFAULT || cf == CF_WRITE
+    };
+    if !supported {
+        return Err(box_err!("unsupported cf {}", cf));
+    }
+    let db = try!(
+        rocksdb_util::new_engine(db_path.to_str().unwrap(), DATA_CFS).map_err(Error::RocksDB)
+    );
+    let handle = try!(get_cf_handle(&db, cf).map_err(Error::RocksDB));
+    let mut opts = IngestExternalFileOptions::new();
+    opts.move_files(true);
+    try!(
+        db.ingest_external_file_cf(handle, &opts, &[input.to_str().unwrap()])
+            .map_err(Error::RocksDB)
+    );
+
+    let range = meta.get_range();
+    let mut writer: Option<SstFileWriter> = None;
+    let mut new_range = Range::new();
+    let mut iter = db.iter_cf_opt(handle, ReadOptions::new());
+    iter.seek(SeekKey::Start);
+    while iter.valid() {
+        let res = if is_raw_kv {
+            rewrite_raw_key(iter.key(), rule).map(|key| (key, 0))
+        } else {
+            try!(rewrite_key(iter.key(), rule))
+        };
+        let (new_key, new_ts) = match res {
+            Some(res) => res,
+            None => {
+                iter.next();
+                continue;
+            }
+        };
+        if new_key.as_slice() < range.get_start() ||
+            (!range.get_end().is_empty() && new_key.as_slice() >= range.get_end())
+        {
+            iter.next();
+            continue;
+        }
+
+        let value = if !is_raw_kv && cf == CF_WRITE && rule.get_new_timestamp() != 0 {
+            let mut write = try!(Write::parse(iter.value()));
+            write.start_ts = new_ts;
+            write.to_bytes()
+        } else {
+            iter.value().to_vec()
+        };
+        if writer.is_none() {
+            let mut cf_opts = ColumnFamilyOptions::new();
+            cf_opts.compression(get_fastest_supported_compression_type());
+            cf_opts.compression_per_level(&[]);
+            cf_opts.bottommost_compression(DBCompressionType::Disable);
+            let mut w = SstFileWriter::new(EnvOptions::new(), cf_opts);
+            try!(w.open(output.to_str().unwrap()).map_err(Error::RocksDB));
+            writer = Some(w);
+            new_range.set_start(new_key.clone());
+        }
+        let data_key = if is_raw_kv {
+            keys::data_key(&new_key)
+        } else {
+            let new_data_key = Key::from_encoded(new_key.clone()).append_ts(new_ts);
+            keys::data_key(new_data_key.encoded())
+        };
+        try!(
+            writer
+                .as_mut()
+                .unwrap()
+                .add(&data_key, &value)
+                .map_err(Error::RocksDB)
+        );
+        new_range.set_end(new_key);
+        iter.next();
+    }
+
+    match writer {
+        Some(mut w) => {
+            try!(w.finish().map_err(Error::RocksDB));
+            Ok(Some(new_range))
+        }
+        None => Ok(None),
+    }
+}
+
+// Reads the downloaded file `input` through a scratch engine in `db_path`, and
+// compares its pairs with the existing ones in `snapshot`.
+fn detect_duplicates(
+    meta: &SSTMeta,
+    is_raw_kv: bool,
+    input: &Path,
+    db_path: &Path,
+    snapshot: &Snapshot,
+) -> Result<Vec<DuplicatedPair>> {
+    let cf = meta.get_cf_name();
+    let db = try!(
+        rocksdb_util::new_engine(db_path.to_str().unwrap(), DATA_CFS).map_err(Error::RocksDB)
+    );
+    let handle = try!(get_cf_handle(&db, cf).map_err(Error::RocksDB));
+    // The file is still to be ingested, so it's copied instead of moved.
+    try!(
+        db.ingest_external_file_cf(handle, &IngestExternalFileOptions::new(), &[
+            input.to_str().unwrap(),
+        ]).map_err(Error::RocksDB)
+    );
+
+    let mut statistics = Statistics::default();
+    let mut reader = MvccReader::new(
+        snapshot,
+        &mut statistics,
+        None,
+        true,
+        None,
+        IsolationLevel::RC,
+    );
+    let mut duplicates = vec![];
+    let mut last_key = vec![];
+    let mut iter = db.iter_cf_opt(handle, ReadOptions::new());
+    iter.seek(SeekKey::Start);
+    while iter.valid() {
+        let (key, value, existing) = if is_raw_kv {
+            let key = keys::origin_key(iter.key()).to_vec();
+            let existing = try!(snapshot.get_cf(cf, &Key::from_encoded(key.clone())));
+            (key, iter.value().to_vec(), existing)
+        } else {
+            let encoded = try!(split_encoded_key_on_ts(keys::origin_key(iter.key())))
+                .0
+                .to_vec();
+            // Versions of a key are sorted from the latest one.
+            if encoded == last_key {
+                iter.next();
+                continue;
+            }
+            last_key = encoded.clone();
+            let value = if cf == CF_WRITE {
+                // Long values are compared by the file of CF_DEFAULT.
+                let write = try!(Write::parse(iter.value()));
+                match write.short_value {
+                    Some(v) => v,
+                    None => {
+                        iter.next();
+                        continue;
+                    }
+                }
+            } else {
+                iter.value().to_vec()
+            };
+            let key = Key::from_encoded(encoded);
+            let existing = try!(reader.get(&key, u64::MAX));
+            (try!(key.raw()), value, existing)
+        };
+        if let Some(existing) = existing {
+            if existing != value {
+                let mut pair = DuplicatedPair::new();
+                pair.set_key(key);
+                pair.set_value(value);
+                pair.set_existing_value(existing);
+                duplicates.push(pair);
+            }
+        }
+        iter.next();
+    }
+    Ok(duplicates)
+}
+
+#[cfg(test)]
+mod tests {
+    use kvproto::import_sstpb::{RewriteRule, SSTMeta};
+    use std::sync::Arc;
+
+    use kvproto::metapb::Region;
+    use rocksdb::{DBIterator, SeekKey, Writable};
+    use tempdir::TempDir;
+
+    use backup::{BackupWriter, RawBackupWriter};
+    use external_storage::LocalStorage;
+    use raftstore::coprocessor::RegionSnapshot;
+    use raftstore::store::keys;
+    use storage::{Key, CF_DEFAULT, CF_WRITE, DATA_CFS};
+    use storage::mvcc::{Write, WriteType};
+    use util::rocksdb::{get_cf_handle, new_engine};
+    use super::*;
+
+    fn new_rule(old_prefix: &[u8], new_prefix: &[u8], new_ts: u64) -> RewriteRule {
+        let mut rule = RewriteRule::new();
+        rule.set_old_key_prefix(old_prefix.to_vec());
+        rule.set_new_key_prefix(new_prefix.to_vec());
+        rule.set_new_timestamp(new_ts);
+        rule
+    }
+
+    fn collect(mut iter: DBIterator) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut kvs = vec![];
+        iter.seek(SeekKey::Start);
+        while iter.valid() {
+            kvs.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+        kvs
+    }
+
+    #[test]
+    fn test_rewrite_key() {
+        let key = keys::data_key(Key::from_raw(b"t1_r1").append_ts(10).encoded());
+        let rule = new_rule(b"t1", b"t22", 0);
+        let (new_key, ts) = rewrite_key(&key, &rule).unwrap().unwrap();
+        assert_eq!(Key::from_encoded(new_key).raw().unwrap(), b"t22_r1");
+        assert_eq!(ts, 10);
+
+        let rule = new_rule(b"t", b"t", 20);
+        let (new_key, ts) = rewrite_key(&key, &rule).unwrap().unwrap();
+        assert_eq!(Key::from_encoded(new_key).raw().unwrap(), b"t1_r1");
+        assert_eq!(ts, 20);
+
+        let rule = new_rule(b"t2", b"t3", 0);
+        assert!(rewrite_key(&key, &rule).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_rewrite_raw_key() {
+        let key = keys::data_key(b"t1_r1");
+        let rule = new_rule(b"t1", b"t22", 10);
+        assert_eq!(rewrite_raw_key(&key, &rule).unwrap(), b"t22_r1");
+        let rule = new_rule(b"t2", b"t3", 0);
+        assert!(rewrite_raw_key(&key, &rule).is_none());
+    }
+
+    #[test]
+    fn test_check_sst_for_ingestion() {
+        let mut region = Region::new();
+        region.set_id(1);
+        region.set_start_key(b"b".to_vec());
+        region.set_end_key(b"d".to_vec());
+        region.mut_region_epoch().set_conf_ver(2);
+        region.mut_region_epoch().set_version(3);
+
+        let mut sst = SSTMeta::new();
+        sst.set_region_id(1);
+        sst.set_region_epoch(region.get_region_epoch().clone());
+        sst.mut_range().set_start(b"b".to_vec());
+        sst.mut_range().set_end(b"c".to_vec());
+        check_sst_for_ingestion(&sst, &region).unwrap();
+
+        let mut s = sst.clone();
+        s.set_region_id(2);
+        assert!(check_sst_for_ingestion(&s, &region).is_err());
+        let mut s = sst.clone();
+        s.mut_region_epoch().set_version(4);
+        assert!(check_sst_for_ingestion(&s, &region).is_err());
+        let mut s = sst.clone();
+        s.mut_range().set_start(b"a".to_vec());
+        assert!(check_sst_for_ingestion(&s, &region).is_err());
+        let mut s = sst.clone();
+        s.mut_range().set_end(b"d".to_vec());
+        assert!(check_sst_for_ingestion(&s, &region).is_err());
+    }
+
+    #[test]
+    fn test_download_and_ingest() {
+        let dir = TempDir::new("test_download_and_ingest").unwrap();
+        let storage_dir = dir.path().join("storage");
+        let storage = LocalStorage::new(&storage_dir).unwrap();
+
+        // Build a backup file of CF_WRITE.
+        let mut writer = BackupWriter::new(dir.path(), "backup");
+        for k in &[b"a1", b"a2", b"b1"] {
+            let key = keys::data_key(Key::from_raw(*k).append_ts(10).encoded());
+            let write = Write::new(WriteType::Put, 5, Some(b"v".to_vec()));
+            writer.put_write(&key, &write.to_bytes()).unwrap();
+        }
+        let files = writer.finish(&storage).unwrap();
+        assert_eq!(files.len(), 1);
+
+        let importer = SSTImporter::new(dir.path().join("import")).unwrap();
+        let mut meta = SSTMeta::new();
+        meta.set_uuid(vec![1, 2, 3]);
+        meta.set_region_id(1);
+        meta.set_cf_name(CF_WRITE.to_owned());
+        let url = storage_dir.to_str().unwrap();
+
+        // No key matches the rule.
+        let rule = new_rule(b"c", b"d", 0);
+        assert!(
+            importer
+                .download(&meta, url, files[0].get_name(), &rule, false)
+                .unwrap()
+                .is_none()
+        );
+        assert!(!importer.exist(&meta));
+
+        // Keys out of the range are dropped.
+        meta.mut_range().set_end(Key::from_raw(b"x2").encoded().to_owned());
+        let rule = new_rule(b"a", b"x", 20);
+        let new_meta = importer
+            .download(&meta, url, files[0].get_name(), &rule, false)
+            .unwrap()
+            .unwrap();
+        assert!(importer.exist(&meta));
+        let range = new_meta.get_range();
+        assert_eq!(range.get_start(), Key::from_raw(b"x1").encoded().as_slice());
+        assert_eq!(range.get_end(), Key::from_raw(b"x1").encoded().as_slice());
+
+        let db_dir = dir.path().join("db");
+        let db = new_engine(db_dir.to_str().unwrap(), &[CF_DEFAULT, CF_WRITE]).unwrap();
+        importer.ingest(&new_meta, &db).unwrap();
+        assert!(!importer.exist(&meta));
+        let handle = get_cf_handle(&db, CF_WRITE).unwrap();
+        let kvs = collect(db.iter_cf_opt(handle, ReadOptions::new()));
+        let key = keys::data_key(Key::from_raw(b"x1").append_ts(20).encoded());
+        let write = Write::new(WriteType::Put, 20, Some(b"v".to_vec()));
+        assert_eq!(kvs, vec![(key, write.to_bytes())]);
+    }
+
+    #[test]
+    fn test_download_and_ingest_raw() {
+        let dir = TempDir::new("test_download_and_ingest_raw").unwrap();
+        let storage_dir = dir.path().join("storage");
+        let storage = LocalStorage::new(&storage_dir).unwrap();
+
+        let mut writer = RawBackupWriter::new(dir.path(), "backup", CF_DEFAULT);
+        for k in &[b"a1", b"a2", b"b1"] {
+            writer.put(&keys::data_key(*k), b"v").unwrap();
+        }
+        let files = writer.finish(&storage).unwrap();
+        assert_eq!(files.len(), 1);
+
+        let importer = SSTImporter::new(dir.path().join("import")).unwrap();
+        let mut meta = SSTMeta::new();
+        meta.set_uuid(vec![1, 2, 3]);
+        meta.set_region_id(1);
+        meta.set_cf_name(CF_DEFAULT.to_owned());
+        meta.mut_range().set_end(b"x2".to_vec());
+        let url = storage_dir.to_str().unwrap();
+        let rule = new_rule(b"a", b"x", 20);
+        let new_meta = importer
+            .download(&meta, url, files[0].get_name(), &rule, true)
+            .unwrap()
+            .unwrap();
+        let range = new_meta.get_range();
+        assert_eq!(range.get_start(), b"x1");
+        assert_eq!(range.get_end(), b"x1");
+
+        let db_dir = dir.path().join("db");
+        let db = new_engine(db_dir.to_str().unwrap(), &[CF_DEFAULT, CF_WRITE]).unwrap();
+        importer.ingest(&new_meta, &db).unwrap();
+        let handle = get_cf_handle(&db, CF_DEFAULT).unwrap();
+        let kvs = collect(db.iter_cf_opt(handle, ReadOptions::new()));
+        assert_eq!(kvs, vec![(keys::data_key(b"x1"), b"v".to_vec())]);
+    }
+
+    #[test]
+    fn test_detect_duplicates() {
+        let dir = TempDir::new("test_detect_duplicates").unwrap();
+        let storage_dir = dir.path().join("storage");
+        let storage = LocalStorage::new(&storage_dir).unwrap();
+        let url = storage_dir.to_str().unwrap();
+        let importer = SSTImporter::new(dir.path().join("import")).unwrap();
+        let rule = new_rule(b"", b"", 0);
+
+        let db_dir = dir.path().join("db");
+        let db = Arc::new(new_engine(db_dir.to_str().unwrap(), DATA_CFS).unwrap());
+        let handle = get_cf_handle(&db, CF_DEFAULT).unwrap();
+        db.put_cf(handle, &keys::data_key(b"a1"), b"v").unwrap();
+        db.put_cf(handle, &keys::data_key(b"a2"), b"w").unwrap();
+        let handle = get_cf_handle(&db, CF_WRITE).unwrap();
+        for &(k, v) in &[(b"b1", b"v"), (b"b2", b"w")] {
+            let key = keys::data_key(Key::from_raw(k).append_ts(10).encoded());
+            let write = Write::new(WriteType::Put, 5, Some(v.to_vec()));
+            db.put_cf(handle, &key, &write.to_bytes()).unwrap();
+        }
+        let snapshot = RegionSnapshot::from_raw(db, Region::new());
+
+        // Raw keys: a1 has the same value, a2 has a different one, a3 is new.
+        let mut writer = RawBackupWriter::new(dir.path(), "raw", CF_DEFAULT);
+        for k in &[b"a1", b"a2", b"a3"] {
+            writer.put(&keys::data_key(*k), b"v").unwrap();
+        }
+        let files = writer.finish(&storage).unwrap();
+        let mut meta = SSTMeta::new();
+        meta.set_uuid(vec![1]);
+        meta.set_cf_name(CF_DEFAULT.to_owned());
+        let new_meta = importer
+            .download(&meta, url, files[0].get_name(), &rule, true)
+            .unwrap()
+            .unwrap();
+        let pairs = importer
+            .detect_duplicates(&new_meta, &snapshot, true)
+            .unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].get_key(), b"a2");
+        assert_eq!(pairs[0].get_value(), b"v");
+        assert_eq!(pairs[0].get_existing_value(), b"w");
+        // The file is kept for the ingestion.
+        assert!(importer.exist(&new_meta));
+
+        // Only the latest version of b1 in the file is compared.
+        let mut writer = BackupWriter::new(dir.path(), "txn");
+        for &(k, ts, v) in &[(b"b1", 30, b"x"), (b"b1", 20, b"v"), (b"b2", 20, b"w")] {
+            let key = keys::data_key(Key::from_raw(k).append_ts(ts).encoded());
+            let write = Write::new(WriteType::Put, ts - 5, Some(v.to_vec()));
+            writer.put_write(&key, &write.to_bytes()).unwrap();
+        }
+        let files = writer.finish(&storage).unwrap();
+        let mut meta = SSTMeta::new();
+        meta.set_uuid(vec![2]);
+        meta.set_cf_name(CF_WRITE.to_owned());
+        let new_meta = importer
+            .download(&meta, url, files[0].get_name(), &rule, false)
+            .unwrap()
+            .unwrap();
+        let pairs = importer
+            .detect_duplicates(&new_meta, &snapshot, false)
+            .unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].get_key(), b"b1");
+        assert_eq!(pairs[0].get_value(), b"x");
+        assert_eq!(pairs[0].get_existing_value(), b"v");
+    }
+}
@@ -61,6 +61,7 @@ extern crate grpcio as grpc;
 extern crate fnv;
 extern crate flat_map;
 extern crate futures;
+extern crate futures_cpupool;
 extern crate tokio_core;
 extern crate tokio_timer;
 extern crate serde_json;
@@ -70,8 +71,15 @@ extern crate serde_derive;
 #[cfg(test)]
 extern crate toml;
 extern crate sys_info;
+extern crate hyper;
+extern crate crypto;
+extern crate rustc_serialize;
+extern crate flate2;
 #[cfg(test)]
 extern crate utime;
+#[cfg(feature = "failpoints")]
+#[macro_use]
+extern crate fail;
 
 #[macro_use]
 pub mod util;
@@ -82,5 +90,11 @@ pub mod raftstore;
 pub mod pd;
 pub mod server;
 pub mod coprocessor;
+pub mod external_storage;
+pub mod backup;
+pub mod import;
+pub mod resolved_ts;
+pub mod cdc;
+pub mod deadlock;
 
 pub use storage::Storage;
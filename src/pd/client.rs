@@ -29,6 +29,14 @@ use super::util::{check_resp_header, sync_request, validate_endpoints, Inner, Le
 
 const CQ_COUNT: usize = 1;
 const CLIENT_PREFIX: &'static str = "pd";
+// A timestamp is composed of a physical part in milliseconds and a logical
+// counter in the low bits.
+const TSO_PHYSICAL_SHIFT_BITS: u64 = 18;
+
+/// Extracts the physical part in milliseconds from a timestamp.
+pub fn extract_physical(ts: u64) -> u64 {
+    ts >> TSO_PHYSICAL_SHIFT_BITS
+}
 
 pub struct RpcClient {
     cluster_id: u64,
@@ -166,6 +174,23 @@ impl PdClient for RpcClient {
         Ok(resp.take_store())
     }
 
+    fn get_all_stores(&self) -> Result<Vec<metapb::Store>> {
+        let mut req = pdpb::GetAllStoresRequest::new();
+        req.set_header(self.header());
+
+        let mut resp = try!(sync_request(
+            &self.leader_client,
+            LEADER_CHANGE_RETRY,
+            |client| {
+                let option = CallOption::default().timeout(Duration::from_secs(REQUEST_TIMEOUT));
+                client.get_all_stores_opt(req.clone(), option)
+            }
+        ));
+        try!(check_resp_header(resp.get_header()));
+
+        Ok(resp.take_stores().into_vec())
+    }
+
     fn get_cluster_config(&self) -> Result<metapb::Cluster> {
         let mut req = pdpb::GetClusterConfigRequest::new();
         req.set_header(self.header());
@@ -201,6 +226,28 @@ impl PdClient for RpcClient {
         Ok(resp.take_region())
     }
 
+    fn get_region_leader(&self, key: &[u8]) -> Result<Option<metapb::Peer>> {
+        let mut req = pdpb::GetRegionRequest::new();
+        req.set_header(self.header());
+        req.set_region_key(key.to_vec());
+
+        let mut resp = try!(sync_request(
+            &self.leader_client,
+            LEADER_CHANGE_RETRY,
+            |client| {
+                let option = CallOption::default().timeout(Duration::from_secs(REQUEST_TIMEOUT));
+                client.get_region_opt(req.clone(), option)
+            }
+        ));
+        try!(check_resp_header(resp.get_header()));
+
+        if resp.has_leader() {
+            Ok(Some(resp.take_leader()))
+        } else {
+            Ok(None)
+        }
+    }
+
     fn get_region_by_id(&self, region_id: u64) -> PdFuture<Option<metapb::Region>> {
         let mut req = pdpb::GetRegionByIDRequest::new();
         req.set_header(self.header());
@@ -351,4 +398,89 @@ impl PdClient for RpcClient {
             .request(req, executor, LEADER_CHANGE_RETRY)
             .execute()
     }
+
+    fn scatter_region(&self, region: metapb::Region) -> PdFuture<()> {
+        let mut req = pdpb::ScatterRegionRequest::new();
+        req.set_header(self.header());
+        req.set_region_id(region.get_id());
+        req.set_region(region);
+
+        let executor = |client: &RwLock<Inner>, req: pdpb::ScatterRegionRequest| {
+            let option = CallOption::default().timeout(Duration::from_secs(REQUEST_TIMEOUT));
+            let handler = client.rl().client.scatter_region_async_opt(req, option);
+            handler
+                .map_err(Error::Grpc)
+                .and_then(|resp| {
+                    try!(check_resp_header(resp.get_header()));
+                    Ok(())
+                })
+                .boxed()
+        };
+
+        self.leader_client
+            .request(req, executor, LEADER_CHANGE_RETRY)
+            .execute()
+    }
+
+    fn get_tso(&self) -> PdFuture<u64> {
+        self.batch_get_tso(1)
+    }
+
+    fn batch_get_tso(&self, count: u32) -> PdFuture<u64> {
+        let mut req = pdpb::TsoRequest::new();
+        req.set_header(self.header());
+        req.set_count(count);
+
+        let executor = |client: &RwLock<Inner>, req: pdpb::TsoRequest| {
+            // Tso is a bidirectional stream, the sender must be kept until the
+            // response is received, otherwise the call is canceled.
+            let (tx, rx) = client.rl().client.tso();
+            tx.send((req, WriteFlags::default()))
+                .map_err(Error::Grpc)
+                .and_then(|tx| {
+                    rx.into_future()
+                        .map(move |(resp, _)| {
+                            drop(tx);
+                            resp
+                        })
+                        .map_err(|(e, _)| Error::Grpc(e))
+                })
+                .and_then(|resp| {
+                    let resp = match resp {
+                        Some(resp) => resp,
+                        None => return Err(box_err!("tso stream is closed")),
+                    };
+                    try!(check_resp_header(resp.get_header()));
+                    let ts = resp.get_timestamp();
+                    Ok(((ts.get_physical() as u64) << TSO_PHYSICAL_SHIFT_BITS) +
+                        ts.get_logical() as u64)
+                })
+                .boxed()
+        };
+
+        self.leader_client
+            .request(req, executor, LEADER_CHANGE_RETRY)
+            .execute()
+    }
+
+    fn get_gc_safe_point(&self) -> PdFuture<u64> {
+        let mut req = pdpb::GetGCSafePointRequest::new();
+        req.set_header(self.header());
+
+        let executor = |client: &RwLock<Inner>, req: pdpb::GetGCSafePointRequest| {
+            let option = CallOption::default().timeout(Duration::from_secs(REQUEST_TIMEOUT));
+            let handler = client.rl().client.get_gc_safe_point_async_opt(req, option);
+            handler
+                .map_err(Error::Grpc)
+                .and_then(|resp| {
+                    try!(check_resp_header(resp.get_header()));
+                    Ok(resp.get_safe_point())
+                })
+                .boxed()
+        };
+
+        self.leader_client
+            .request(req, executor, LEADER_CHANGE_RETRY)
+            .execute()
+    }
 }
@@ -19,7 +19,7 @@ mod util;
 
 pub mod errors;
 pub use self::errors::{Error, Result};
-pub use self::client::RpcClient;
+pub use self::client::{extract_physical, RpcClient};
 pub use self::util::validate_endpoints;
 
 use kvproto::metapb;
@@ -103,6 +103,9 @@ pub trait PdClient: Send + Sync {
     // Get store information.
     fn get_store(&self, store_id: u64) -> Result<metapb::Store>;
 
+    // Get all stores of the cluster, including tombstone ones.
+    fn get_all_stores(&self) -> Result<Vec<metapb::Store>>;
+
     // Get cluster meta information.
     fn get_cluster_config(&self) -> Result<metapb::Cluster>;
 
@@ -110,6 +113,10 @@ pub trait PdClient: Send + Sync {
     // Get region which the key belong to.
     fn get_region(&self, key: &[u8]) -> Result<metapb::Region>;
 
+    // Get the leader of the region which the key belongs to, it's `None` if
+    // pd doesn't know the leader yet.
+    fn get_region_leader(&self, key: &[u8]) -> Result<Option<metapb::Peer>>;
+
     // Get region by region id.
     fn get_region_by_id(&self, region_id: u64) -> PdFuture<Option<metapb::Region>>;
 
@@ -136,6 +143,22 @@ pub trait PdClient: Send + Sync {
 
     // Report pd the split region.
     fn report_split(&self, left: metapb::Region, right: metapb::Region) -> PdFuture<()>;
+
+    // Ask pd to scatter the peers and the leader of the region across the stores,
+    // which is useful after splitting a range into many empty regions.
+    fn scatter_region(&self, region: metapb::Region) -> PdFuture<()>;
+
+    // Get a timestamp from the timestamp oracle of pd, which is greater than
+    // all the timestamps allocated before.
+    fn get_tso(&self) -> PdFuture<u64>;
+
+    // Get `count` continuous timestamps from the timestamp oracle of pd, the
+    // last one of them is returned.
+    fn batch_get_tso(&self, count: u32) -> PdFuture<u64>;
+
+    // Get the gc safe point of the cluster, the versions older than it can be
+    // dropped. It's 0 if the safe point has never been updated.
+    fn get_gc_safe_point(&self) -> PdFuture<u64>;
 }
 
 const REQUEST_TIMEOUT: u64 = 2; // 2s
@@ -28,7 +28,7 @@
 
 use std::cmp;
 
-use rand::{self, Rng};
+use rand::{self, Rng, SeedableRng, XorShiftRng};
 use kvproto::eraftpb::{Entry, EntryType, HardState, Message, MessageType, Snapshot};
 use protobuf::repeated::RepeatedField;
 
@@ -128,6 +128,11 @@ pub struct Config {
     // May affect proposal forwarding and follower read.
     pub skip_bcast_commit: bool,
 
+    /// election_seed makes the randomized election timeout reproducible, it's
+    /// mixed with the id so that peers sharing the seed still time out differently.
+    /// Only used for testing.
+    pub election_seed: Option<u64>,
+
     /// tag is only used for logging
     pub tag: String,
 }
@@ -223,6 +228,7 @@ pub struct Raft<T: Storage> {
     // [election_timeout, 2 * election_timeout - 1]. It gets reset
     // when raft changes its state to follower or candidate.
     randomized_election_timeout: usize,
+    election_rng: Option<XorShiftRng>,
 
     /// Will be called when step** is about to be called.
     /// return false will skip step**.
@@ -259,6 +265,16 @@ pub fn vote_resp_msg_type(t: MessageType) -> MessageType {
     }
 }
 
+fn new_election_rng(seed: u64, id: u64) -> XorShiftRng {
+    // XorShiftRng panics on an all zero seed.
+    XorShiftRng::from_seed([
+        seed as u32,
+        (seed >> 32) as u32,
+        id as u32,
+        (id >> 32) as u32 | 1,
+    ])
+}
+
 // Calculate the quorum of a Raft cluster with the specified total nodes.
 pub fn quorum(total: usize) -> usize {
     total / 2 + 1
@@ -306,6 +322,7 @@ impl<T: Storage> Raft<T> {
             vote: Default::default(),
             heartbeat_elapsed: Default::default(),
             randomized_election_timeout: 0,
+            election_rng: c.election_seed.map(|seed| new_election_rng(seed, c.id)),
             skip_bcast_commit: c.skip_bcast_commit,
             tag: c.tag.to_owned(),
         };
@@ -1815,8 +1832,10 @@ impl<T: Storage> Raft<T> {
 
     pub fn reset_randomized_election_timeout(&mut self) {
         let prev_timeout = self.randomized_election_timeout;
-        let timeout =
-            self.election_timeout + rand::thread_rng().gen_range(0, self.election_timeout);
+        let timeout = self.election_timeout + match self.election_rng {
+            Some(ref mut rng) => rng.gen_range(0, self.election_timeout),
+            None => rand::thread_rng().gen_range(0, self.election_timeout),
+        };
         debug!(
             "{} reset election timeout {} -> {} at {}",
             self.tag,
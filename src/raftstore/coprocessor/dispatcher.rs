@@ -13,8 +13,9 @@
 
 use super::{ObserverContext, RegionObserver, Result};
 
-use kvproto::raft_cmdpb::RaftCmdRequest;
+use kvproto::raft_cmdpb::{RaftCmdRequest, RaftCmdResponse, Request};
 use kvproto::metapb::Region;
+use raft::StateRole;
 
 struct ObserverEntry {
     priority: u32,
@@ -98,6 +99,60 @@ impl CoprocessorHost {
         }
     }
 
+    /// Call all post apply hook until bypass is set to true. Failed commands are
+    /// skipped.
+    pub fn post_apply(
+        &self,
+        region: &Region,
+        index: u64,
+        req: &RaftCmdRequest,
+        resp: &RaftCmdResponse,
+    ) {
+        if resp.get_header().has_error() {
+            return;
+        }
+        let mut ctx = ObserverContext::new(region);
+        for entry in &self.registry.observers {
+            if req.has_admin_request() {
+                entry
+                    .observer
+                    .post_apply_admin(&mut ctx, req.get_admin_request());
+            } else {
+                entry
+                    .observer
+                    .post_apply_query(&mut ctx, index, req.get_requests());
+            }
+            if ctx.bypass {
+                break;
+            }
+        }
+    }
+
+    /// Call all role change hook until bypass is set to true.
+    pub fn on_role_change(&self, region: &Region, role: StateRole) {
+        let mut ctx = ObserverContext::new(region);
+        for entry in &self.registry.observers {
+            entry.observer.on_role_change(&mut ctx, role);
+            if ctx.bypass {
+                break;
+            }
+        }
+    }
+
+    /// Call all pre transfer leader hook until bypass is set to true, and
+    /// returns the requests to be proposed before the transfer.
+    pub fn pre_transfer_leader(&self, region: &Region) -> Vec<Request> {
+        let mut ctx = ObserverContext::new(region);
+        let mut requests = vec![];
+        for entry in &self.registry.observers {
+            requests.extend(entry.observer.pre_transfer_leader(&mut ctx));
+            if ctx.bypass {
+                break;
+            }
+        }
+        requests
+    }
+
     pub fn shutdown(&self) {
         for entry in &self.registry.observers {
             entry.observer.stop();
@@ -113,7 +168,9 @@ mod test {
     use protobuf::RepeatedField;
 
     use kvproto::metapb::Region;
-    use kvproto::raft_cmdpb::{AdminRequest, RaftCmdRequest, Request};
+    use kvproto::errorpb;
+    use kvproto::raft_cmdpb::{AdminRequest, RaftCmdRequest, RaftCmdResponse, Request};
+    use raft::StateRole;
 
     struct TestCoprocessor {
         bypass: Arc<AtomicBool>,
@@ -165,6 +222,27 @@ mod test {
             self.called.fetch_add(3, Ordering::SeqCst);
             ctx.bypass = self.bypass.load(Ordering::SeqCst);
         }
+
+        fn post_apply_query(&self, ctx: &mut ObserverContext, _: u64, _: &[Request]) {
+            self.called.fetch_add(4, Ordering::SeqCst);
+            ctx.bypass = self.bypass.load(Ordering::SeqCst);
+        }
+
+        fn post_apply_admin(&self, ctx: &mut ObserverContext, _: &AdminRequest) {
+            self.called.fetch_add(5, Ordering::SeqCst);
+            ctx.bypass = self.bypass.load(Ordering::SeqCst);
+        }
+
+        fn on_role_change(&self, ctx: &mut ObserverContext, _: StateRole) {
+            self.called.fetch_add(6, Ordering::SeqCst);
+            ctx.bypass = self.bypass.load(Ordering::SeqCst);
+        }
+
+        fn pre_transfer_leader(&self, ctx: &mut ObserverContext) -> Vec<Request> {
+            self.called.fetch_add(7, Ordering::SeqCst);
+            ctx.bypass = self.bypass.load(Ordering::SeqCst);
+            vec![Request::new()]
+        }
     }
 
     fn share_bool() -> Arc<AtomicBool> {
@@ -247,4 +325,74 @@ mod test {
         assert!(host.pre_propose(&region, &mut admin_req).is_err());
         assert_all!(&[&called1, &called2], &[0, 1]);
     }
+
+    #[test]
+    fn test_post_apply() {
+        let (bypass1, called1, r1) = (share_bool(), share_usize(), share_bool());
+        let observer1 = TestCoprocessor::new(bypass1.clone(), called1.clone(), r1.clone());
+        let (bypass2, called2, r2) = (share_bool(), share_usize(), share_bool());
+        let observer2 = TestCoprocessor::new(bypass2.clone(), called2.clone(), r2.clone());
+        let mut host = CoprocessorHost::default();
+        host.registry.register_observer(1, Box::new(observer1));
+        host.registry.register_observer(2, Box::new(observer2));
+        let region = Region::new();
+        let mut admin_req = RaftCmdRequest::new();
+        admin_req.set_admin_request(AdminRequest::new());
+        let mut query_req = RaftCmdRequest::new();
+        query_req.set_requests(RepeatedField::from_vec(vec![Request::new()]));
+        let resp = RaftCmdResponse::new();
+
+        host.post_apply(&region, 1, &query_req, &resp);
+        assert_all!(&[&called1, &called2], &[4, 4]);
+        host.post_apply(&region, 2, &admin_req, &resp);
+        assert_all!(&[&called1, &called2], &[9, 9]);
+
+        set_all!(&[&bypass1], true);
+        host.post_apply(&region, 3, &query_req, &resp);
+        assert_all!(&[&called1, &called2], &[13, 9]);
+
+        // Failed commands are not observed.
+        let mut err_resp = RaftCmdResponse::new();
+        err_resp.mut_header().set_error(errorpb::Error::new());
+        host.post_apply(&region, 4, &query_req, &err_resp);
+        assert_all!(&[&called1, &called2], &[13, 9]);
+    }
+
+    #[test]
+    fn test_on_role_change() {
+        let (bypass1, called1, r1) = (share_bool(), share_usize(), share_bool());
+        let observer1 = TestCoprocessor::new(bypass1.clone(), called1.clone(), r1.clone());
+        let (bypass2, called2, r2) = (share_bool(), share_usize(), share_bool());
+        let observer2 = TestCoprocessor::new(bypass2.clone(), called2.clone(), r2.clone());
+        let mut host = CoprocessorHost::default();
+        host.registry.register_observer(1, Box::new(observer1));
+        host.registry.register_observer(2, Box::new(observer2));
+        let region = Region::new();
+
+        host.on_role_change(&region, StateRole::Leader);
+        assert_all!(&[&called1, &called2], &[6, 6]);
+
+        set_all!(&[&bypass1], true);
+        host.on_role_change(&region, StateRole::Follower);
+        assert_all!(&[&called1, &called2], &[12, 6]);
+    }
+
+    #[test]
+    fn test_pre_transfer_leader() {
+        let (bypass1, called1, r1) = (share_bool(), share_usize(), share_bool());
+        let observer1 = TestCoprocessor::new(bypass1.clone(), called1.clone(), r1.clone());
+        let (bypass2, called2, r2) = (share_bool(), share_usize(), share_bool());
+        let observer2 = TestCoprocessor::new(bypass2.clone(), called2.clone(), r2.clone());
+        let mut host = CoprocessorHost::default();
+        host.registry.register_observer(1, Box::new(observer1));
+        host.registry.register_observer(2, Box::new(observer2));
+        let region = Region::new();
+
+        assert_eq!(host.pre_transfer_leader(&region).len(), 2);
+        assert_all!(&[&called1, &called2], &[7, 7]);
+
+        set_all!(&[&bypass1], true);
+        assert_eq!(host.pre_transfer_leader(&region).len(), 1);
+        assert_all!(&[&called1, &called2], &[14, 7]);
+    }
 }
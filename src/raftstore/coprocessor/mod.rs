@@ -14,14 +14,17 @@
 mod region_snapshot;
 pub mod dispatcher;
 pub mod split_observer;
+mod role_observer;
 mod error;
 
 pub use self::region_snapshot::{RegionIterator, RegionSnapshot};
 pub use self::dispatcher::{CoprocessorHost, Registry};
+pub use self::role_observer::RoleObserver;
 
 use kvproto::raft_cmdpb::{AdminRequest, Request};
 use kvproto::metapb::Region;
 use protobuf::RepeatedField;
+use raft::StateRole;
 
 pub use self::error::{Error, Result};
 
@@ -69,4 +72,23 @@ pub trait RegionObserver: Coprocessor {
     ///
     /// Please note that improper implementation can lead to data inconsistency.
     fn pre_apply_query(&self, _: &mut ObserverContext, _: &mut RepeatedField<Request>) {}
+
+    /// Hook to call after read/write request at log index `index` is applied
+    /// successfully. The changes may not be persisted yet.
+    fn post_apply_query(&self, _: &mut ObserverContext, _: u64, _: &[Request]) {}
+
+    /// Hook to call after admin request is applied successfully, the region in
+    /// context is the one after applying.
+    fn post_apply_admin(&self, _: &mut ObserverContext, _: &AdminRequest) {}
+
+    /// Hook to call when the raft role of the peer of the region on this
+    /// store is changed.
+    fn on_role_change(&self, _: &mut ObserverContext, _: StateRole) {}
+
+    /// Hook to call before the leadership of the region is transferred. The
+    /// returned requests are proposed before the transfer, so the states kept
+    /// only by the leader can be replicated to the new one.
+    fn pre_transfer_leader(&self, _: &mut ObserverContext) -> Vec<Request> {
+        vec![]
+    }
 }
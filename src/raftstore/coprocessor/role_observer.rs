@@ -0,0 +1,79 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, RwLock};
+
+use raft::StateRole;
+
+use util::collections::HashMap;
+use super::{Coprocessor, ObserverContext, RegionObserver};
+
+/// `RoleObserver` records the raft roles of the peers on this store, so they
+/// can be read outside of raftstore. Peers which have never changed their
+/// roles are followers.
+#[derive(Clone, Default)]
+pub struct RoleObserver {
+    // Only the peers which are not followers are recorded.
+    roles: Arc<RwLock<HashMap<u64, StateRole>>>,
+}
+
+impl RoleObserver {
+    pub fn new() -> RoleObserver {
+        RoleObserver::default()
+    }
+
+    pub fn get_role(&self, region_id: u64) -> StateRole {
+        self.roles
+            .read()
+            .unwrap()
+            .get(&region_id)
+            .cloned()
+            .unwrap_or(StateRole::Follower)
+    }
+}
+
+impl Coprocessor for RoleObserver {}
+
+impl RegionObserver for RoleObserver {
+    fn on_role_change(&self, ctx: &mut ObserverContext, role: StateRole) {
+        let region_id = ctx.region().get_id();
+        let mut roles = self.roles.write().unwrap();
+        if role == StateRole::Follower {
+            roles.remove(&region_id);
+        } else {
+            roles.insert(region_id, role);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kvproto::metapb::Region;
+
+    use super::*;
+
+    #[test]
+    fn test_role_observer() {
+        let observer = RoleObserver::new();
+        let mut region = Region::new();
+        region.set_id(1);
+        assert_eq!(observer.get_role(1), StateRole::Follower);
+
+        let o = observer.clone();
+        o.on_role_change(&mut ObserverContext::new(&region), StateRole::Leader);
+        assert_eq!(observer.get_role(1), StateRole::Leader);
+        assert_eq!(observer.get_role(2), StateRole::Follower);
+        o.on_role_change(&mut ObserverContext::new(&region), StateRole::Follower);
+        assert_eq!(observer.get_role(1), StateRole::Follower);
+    }
+}
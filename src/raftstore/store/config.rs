@@ -21,6 +21,7 @@ use util::config::{ReadableDuration, ReadableSize};
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     // true for high reliability, prevent data loss when power failure.
     pub sync_log: bool,
@@ -29,7 +30,7 @@ pub struct Config {
     // store capacity. 0 means no limit.
     pub capacity: ReadableSize,
 
-    // raft_base_tick_interval is a base tick interval (ms).
+    // raft_base_tick_interval is a base tick interval.
     pub raft_base_tick_interval: ReadableDuration,
     pub raft_heartbeat_ticks: usize,
     pub raft_election_timeout_ticks: usize,
@@ -38,7 +39,7 @@ pub struct Config {
     // When the entry exceed the max size, reject to propose it.
     pub raft_entry_max_size: ReadableSize,
 
-    // Interval to gc unnecessary raft log (ms).
+    // Interval to gc unnecessary raft log.
     pub raft_log_gc_tick_interval: ReadableDuration,
     // A threshold to gc stale raft log, must >= 1.
     pub raft_log_gc_threshold: u64,
@@ -48,7 +49,7 @@ pub struct Config {
     // gc will be forced trigger.
     pub raft_log_gc_size_limit: ReadableSize,
 
-    // Interval (ms) to check region whether need to be split or not.
+    // Interval to check region whether need to be split or not.
     pub split_region_check_tick_interval: ReadableDuration,
     /// When region [a, b) size meets region_max_size, it will be split
     /// into two region into [a, c), [c, b). And the size of [a, c) will
@@ -58,7 +59,7 @@ pub struct Config {
     /// When size change of region exceed the diff since last check, it
     /// will be checked again whether it should be split.
     pub region_split_check_diff: ReadableSize,
-    /// Interval (ms) to check whether start compaction for a region.
+    /// Interval to check whether start compaction for a region.
     pub region_compact_check_interval: ReadableDuration,
     /// When delete keys of a region exceeds the size, a compaction will
     /// be started.
@@ -84,18 +85,25 @@ pub struct Config {
 
     pub snap_apply_batch_size: ReadableSize,
 
-    // Interval (ms) to check region whether the data is consistent.
+    // Interval to check region whether the data is consistent.
     pub consistency_check_interval: ReadableDuration,
 
     pub report_region_flow_interval: ReadableDuration,
 
     // The lease provided by a successfully proposed and applied entry.
     pub raft_store_max_leader_lease: ReadableDuration,
+    // Don't serve reads with the leader lease if the system time jumped within
+    // a lease, fall back to read index instead.
+    pub block_lease_read_on_clock_jump: bool,
 
     // Right region derive origin region id when split.
     pub right_derive_when_split: bool,
 
     pub allow_remove_leader: bool,
+
+    // Seed of the randomized election timeouts, only used to reproduce tests.
+    #[serde(skip)]
+    pub raft_election_seed: Option<u64>,
 }
 
 impl Default for Config {
@@ -139,8 +147,10 @@ impl Default for Config {
             consistency_check_interval: ReadableDuration::secs(0),
             report_region_flow_interval: ReadableDuration::minutes(1),
             raft_store_max_leader_lease: ReadableDuration::secs(9),
+            block_lease_read_on_clock_jump: false,
             right_derive_when_split: true,
             allow_remove_leader: false,
+            raft_election_seed: None,
         }
     }
 }
@@ -156,7 +166,7 @@ impl Config {
 
     pub fn validate(&self) -> Result<()> {
         if self.raft_heartbeat_ticks == 0 {
-            return Err(box_err!("heartbeat tick must greater than 0"));
+            return Err(box_err!("raftstore.raft-heartbeat-ticks must greater than 0"));
         }
 
         if self.raft_election_timeout_ticks != 10 {
@@ -168,24 +178,27 @@ impl Config {
 
         if self.raft_election_timeout_ticks <= self.raft_heartbeat_ticks {
             return Err(box_err!(
-                "election tick must be greater than heartbeat tick"
+                "raftstore.raft-election-timeout-ticks must be greater than \
+                 raftstore.raft-heartbeat-ticks"
             ));
         }
 
         if self.raft_log_gc_threshold < 1 {
             return Err(box_err!(
-                "raft log gc threshold must >= 1, not {}",
+                "raftstore.raft-log-gc-threshold must >= 1, not {}",
                 self.raft_log_gc_threshold
             ));
         }
 
         if self.raft_log_gc_size_limit.0 == 0 {
-            return Err(box_err!("raft log gc size limit should large than 0."));
+            return Err(box_err!(
+                "raftstore.raft-log-gc-size-limit should large than 0."
+            ));
         }
 
         if self.region_max_size.0 < self.region_split_size.0 {
             return Err(box_err!(
-                "region max size {} must >= split size {}",
+                "raftstore.region-max-size {} must >= raftstore.region-split-size {}",
                 self.region_max_size.0,
                 self.region_split_size.0
             ));
@@ -196,7 +209,7 @@ impl Config {
         let lease = self.raft_store_max_leader_lease.as_millis() as u64;
         if election_timeout < lease {
             return Err(box_err!(
-                "election timeout {} ms is less than lease {} ms",
+                "election timeout {} ms is less than raftstore.raft-store-max-leader-lease {} ms",
                 election_timeout,
                 lease
             ));
@@ -17,7 +17,7 @@ use std::fmt;
 
 use kvproto::raft_serverpb::RaftMessage;
 use kvproto::raft_cmdpb::{RaftCmdRequest, RaftCmdResponse};
-use kvproto::metapb::RegionEpoch;
+use kvproto::metapb::{Region, RegionEpoch};
 use raft::SnapshotStatus;
 
 use util::escape;
@@ -70,6 +70,17 @@ pub enum Msg {
         split_key: Vec<u8>,
     },
 
+    // Splits the region at `split_key` on demand, `callback` is called with the
+    // response of the split command.
+    SplitRegion {
+        region_id: u64,
+        region_epoch: RegionEpoch,
+        split_key: Vec<u8>,
+        callback: Callback,
+    },
+    // Asks pd to scatter the region across the stores.
+    ScatterRegion { region: Region },
+
     ReportUnreachable { region_id: u64, to_peer_id: u64 },
 
     // For snapshot stats.
@@ -91,6 +102,17 @@ impl fmt::Debug for Msg {
             Msg::RaftCmd { .. } => write!(fmt, "Raft Command"),
             Msg::BatchRaftSnapCmds { .. } => write!(fmt, "Batch Raft Commands"),
             Msg::SplitCheckResult { .. } => write!(fmt, "Split Check Result"),
+            Msg::SplitRegion {
+                region_id,
+                ref split_key,
+                ..
+            } => write!(
+                fmt,
+                "Split region {} at key {}",
+                region_id,
+                escape(split_key)
+            ),
+            Msg::ScatterRegion { ref region } => write!(fmt, "Scatter region {}", region.get_id()),
             Msg::ReportUnreachable {
                 ref region_id,
                 ref to_peer_id,
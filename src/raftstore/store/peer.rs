@@ -38,7 +38,7 @@ use raftstore::store::worker::apply::ExecResult;
 use util::worker::{FutureWorker, Scheduler};
 use raftstore::store::worker::{Apply, ApplyRes, ApplyTask};
 use util::Either;
-use util::time::monotonic_raw_now;
+use util::time::{clock_jumped_within, monotonic_raw_now};
 use util::collections::{FlatMap, FlatMapValues as Values, HashSet};
 
 use pd::INVALID_ID;
@@ -325,6 +325,7 @@ impl Peer {
             check_quorum: true,
             tag: tag.clone(),
             skip_bcast_commit: true,
+            election_seed: cfg.raft_election_seed,
             ..Default::default()
         };
 
@@ -632,6 +633,8 @@ impl Peer {
     fn on_role_changed(&mut self, ready: &Ready, worker: &FutureWorker<PdTask>) {
         // Update leader lease when the Raft state changes.
         if let Some(ref ss) = ready.ss {
+            self.coprocessor_host
+                .on_role_change(self.region(), ss.raft_state);
             match ss.raft_state {
                 StateRole::Leader => {
                     // The local read can only be performed after a new leader has applied
@@ -1102,7 +1105,10 @@ impl Peer {
         for r in req.get_requests() {
             match r.get_cmd_type() {
                 CmdType::Get | CmdType::Snap => is_read = true,
-                CmdType::Delete | CmdType::Put | CmdType::DeleteRange => is_write = true,
+                CmdType::Delete |
+                CmdType::Put |
+                CmdType::DeleteRange |
+                CmdType::IngestSST => is_write = true,
                 CmdType::Prewrite | CmdType::Invalid => {
                     return Err(box_err!(
                         "invalid cmd type {:?}, message maybe currupted",
@@ -1138,6 +1144,13 @@ impl Peer {
             return Ok(RequestPolicy::ReadIndex);
         }
 
+        if self.cfg.block_lease_read_on_clock_jump &&
+            clock_jumped_within(self.cfg.raft_store_max_leader_lease.0)
+        {
+            debug!("{} system time jumped recently, skip lease read", self.tag);
+            return Ok(RequestPolicy::ReadIndex);
+        }
+
         if let Some(Either::Left(safe_expired_time)) = self.leader_lease_expired_time {
             if monotonic_raw_now() <= safe_expired_time {
                 return Ok(RequestPolicy::ReadLocal);
@@ -1381,6 +1394,7 @@ impl Peer {
         let peer = transfer_leader.get_peer();
 
         let transfered = if self.is_transfer_leader_allowed(peer) {
+            self.propose_before_transfer_leader(&req, metrics);
             self.transfer_leader(peer);
             true
         } else {
@@ -1399,6 +1413,38 @@ impl Peer {
         transfered
     }
 
+    // Proposes the requests returned by the coprocessors, which carry the states
+    // kept only by the leader to the new one. They are dropped if the proposal
+    // fails, the transfer goes on anyway.
+    fn propose_before_transfer_leader(
+        &mut self,
+        transfer_req: &RaftCmdRequest,
+        metrics: &mut RaftProposeMetrics,
+    ) {
+        let requests = self.coprocessor_host.pre_transfer_leader(self.region());
+        if requests.is_empty() {
+            return;
+        }
+        let mut req = RaftCmdRequest::new();
+        req.set_header(transfer_req.get_header().clone());
+        req.set_requests(protobuf::RepeatedField::from_vec(requests));
+        match self.propose_normal(req, metrics) {
+            Ok(idx) => {
+                let meta = ProposalMeta {
+                    index: idx,
+                    term: self.term(),
+                    renew_lease_time: None,
+                };
+                self.post_propose(meta, false, box |_| {});
+            }
+            Err(e) => warn!(
+                "{} failed to propose before transferring leader: {:?}",
+                self.tag,
+                e
+            ),
+        }
+    }
+
     fn propose_conf_change(
         &mut self,
         req: RaftCmdRequest,
@@ -1669,6 +1715,7 @@ impl Peer {
                 CmdType::Put |
                 CmdType::Delete |
                 CmdType::DeleteRange |
+                CmdType::IngestSST |
                 CmdType::Invalid => unreachable!(),
             };
 
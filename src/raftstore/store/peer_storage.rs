@@ -28,6 +28,7 @@ use kvproto::eraftpb::{ConfState, Entry, HardState, Snapshot};
 use kvproto::raft_serverpb::{PeerState, RaftApplyState, RaftLocalState, RaftSnapshotData,
                              RegionLocalState};
 use util::worker::Scheduler;
+use util::memory::{MemoryTrace, RAFT_ENTRIES_MEMORY};
 use util::{self, rocksdb};
 use raft::{self, Error as RaftError, RaftState, Ready, Storage, StorageError};
 use raftstore::{Error, Result};
@@ -118,9 +119,18 @@ pub fn last_index(state: &RaftLocalState) -> u64 {
     state.get_last_index()
 }
 
-#[derive(Default)]
 struct EntryCache {
     cache: VecDeque<Entry>,
+    mem_trace: MemoryTrace,
+}
+
+impl Default for EntryCache {
+    fn default() -> EntryCache {
+        EntryCache {
+            cache: VecDeque::default(),
+            mem_trace: MemoryTrace::new(&RAFT_ENTRIES_MEMORY),
+        }
+    }
 }
 
 impl EntryCache {
@@ -180,9 +190,14 @@ impl EntryCache {
             let first_index = entries[0].get_index();
             if cache_last_index >= first_index {
                 if self.cache.front().unwrap().get_index() >= first_index {
-                    self.cache.clear();
+                    self.clear();
                 } else {
                     let left = self.cache.len() - (cache_last_index - first_index + 1) as usize;
+                    let size = self.cache
+                        .iter()
+                        .skip(left)
+                        .fold(0, |size, e| size + e.compute_size() as usize);
+                    self.mem_trace.release(size);
                     self.cache.truncate(left);
                 }
                 if self.cache.len() + entries.len() < SHRINK_CACHE_CAPACITY &&
@@ -202,17 +217,31 @@ impl EntryCache {
         let mut start_idx = 0;
         if let Some(len) = (self.cache.len() + entries.len()).checked_sub(MAX_CACHE_CAPACITY) {
             if len < self.cache.len() {
-                self.cache.drain(..len);
+                self.drain_to(len);
             } else {
                 start_idx = len - self.cache.len();
-                self.cache.clear();
+                self.clear();
             }
         }
         for e in &entries[start_idx..] {
+            self.mem_trace.consume(e.compute_size() as usize);
             self.cache.push_back(e.to_owned());
         }
     }
 
+    fn drain_to(&mut self, end: usize) {
+        let size = self.cache
+            .drain(..end)
+            .fold(0, |size, e| size + e.compute_size() as usize);
+        self.mem_trace.release(size);
+    }
+
+    fn clear(&mut self) {
+        self.cache.clear();
+        let size = self.mem_trace.consumed();
+        self.mem_trace.release(size);
+    }
+
     pub fn compact_to(&mut self, idx: u64) {
         let cache_first_idx = match self.cache.front() {
             None => return,
@@ -223,8 +252,7 @@ impl EntryCache {
             return;
         }
         let cache_last_idx = self.cache.back().unwrap().get_index();
-        self.cache
-            .drain(..(cmp::min(cache_last_idx, idx) - cache_first_idx) as usize);
+        self.drain_to((cmp::min(cache_last_idx, idx) - cache_first_idx) as usize);
         if self.cache.len() < SHRINK_CACHE_CAPACITY &&
             self.cache.capacity() > SHRINK_CACHE_CAPACITY
         {
@@ -1818,7 +1846,7 @@ mod test {
         let worker = Worker::new("snap_manager");
         let sched = worker.scheduler();
         let mut store = new_storage_from_ents(sched, &td, &ents);
-        store.cache.cache.clear();
+        store.cache.clear();
         // empty cache should fetch data from rocksdb directly.
         let mut res = store.entries(4, 6, u64::max_value()).unwrap();
         assert_eq!(*res, ents[1..]);
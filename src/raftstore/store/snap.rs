@@ -36,6 +36,7 @@ use raftstore::store::util::check_key_in_region;
 use storage::{CfName, CF_DEFAULT, CF_LOCK, CF_WRITE};
 use util::transport::SendCh;
 use util::HandyRwLock;
+use util::io_metrics::{record_io, IOOp, IOType};
 use util::collections::{HashMap, HashMapEntry as Entry};
 use util::codec::bytes::{BytesEncoder, CompactBytesDecoder};
 
@@ -224,31 +225,11 @@ use kvproto::raft_serverpb::{SnapshotCFFile, SnapshotMeta};
 use rocksdb::{DBCompressionType, EnvOptions, IngestExternalFileOptions, SstFileWriter};
 use util::rocksdb;
 use util::time::duration_to_sec;
-use util::file::{delete_file_if_exist, file_exists, get_file_size};
+use util::file::{calc_crc32, delete_file_if_exist, file_exists, get_file_size};
 use util::rocksdb::get_fastest_supported_compression_type;
 
 pub const SNAPSHOT_VERSION: u64 = 2;
 const META_FILE_SUFFIX: &'static str = ".meta";
-const DIGEST_BUFFER_SIZE: usize = 10240;
-
-
-fn calc_crc32(p: &PathBuf) -> io::Result<u32> {
-    let mut digest = Digest::new(crc32::IEEE);
-    let mut f = try!(OpenOptions::new().read(true).open(&p));
-    let mut buf = vec![0; DIGEST_BUFFER_SIZE];
-    loop {
-        match f.read(&mut buf[..]) {
-            Ok(0) => {
-                return Ok(digest.sum32());
-            }
-            Ok(n) => {
-                digest.write(&buf[..n]);
-            }
-            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
-            Err(err) => return Err(err),
-        }
-    }
-}
 
 fn gen_snapshot_meta(cf_files: &[CfFile]) -> RaftStoreResult<SnapshotMeta> {
     let mut meta = Vec::with_capacity(cf_files.len());
@@ -305,6 +286,79 @@ fn check_file_size_and_checksum(
     check_file_size(path, expected_size).and_then(|_| check_file_checksum(path, expected_checksum))
 }
 
+// Parses the snap key from the name of a snapshot file, and returns whether the
+// snapshot is generated by this store.
+fn parse_snap_file_name(name: &str) -> Option<(SnapKey, bool)> {
+    let is_sending = name.starts_with(SNAP_GEN_PREFIX);
+    let numbers: Vec<u64> = name.split('.').next().map_or_else(
+        || vec![],
+        |s| {
+            s.split('_')
+                .skip(1)
+                .filter_map(|s| s.parse().ok())
+                .collect()
+        },
+    );
+    if numbers.len() != 3 {
+        return None;
+    }
+    Some((SnapKey::new(numbers[0], numbers[1], numbers[2]), is_sending))
+}
+
+/// Meta of a snapshot on disk, only used for diagnosis.
+pub struct SnapshotMetaInfo {
+    pub key: SnapKey,
+    pub is_sending: bool,
+    pub meta: SnapshotMeta,
+    /// Paths of cf files, in the same order as `meta.get_cf_files()`.
+    pub cf_file_paths: Vec<PathBuf>,
+}
+
+/// Loads the meta of all snapshots in `dir`, ordered by snap key.
+pub fn load_snapshot_metas<P: AsRef<Path>>(dir: P) -> RaftStoreResult<Vec<SnapshotMetaInfo>> {
+    let dir = dir.as_ref();
+    let mut infos = vec![];
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) if name.ends_with(META_FILE_SUFFIX) => name.to_owned(),
+            _ => continue,
+        };
+        let (key, is_sending) = match parse_snap_file_name(&name) {
+            Some(res) => res,
+            None => return Err(box_err!("failed to parse snapkey from {}", name)),
+        };
+        let mut buf = vec![];
+        try!(try!(File::open(&path)).read_to_end(&mut buf));
+        let mut meta = SnapshotMeta::new();
+        try!(meta.merge_from_bytes(&buf));
+        let prefix = &name[..name.len() - META_FILE_SUFFIX.len()];
+        let cf_file_paths = meta.get_cf_files()
+            .iter()
+            .map(|cf_file| {
+                dir.join(format!("{}_{}{}", prefix, cf_file.get_cf(), SST_FILE_SUFFIX))
+            })
+            .collect();
+        infos.push(SnapshotMetaInfo {
+            key: key,
+            is_sending: is_sending,
+            meta: meta,
+            cf_file_paths: cf_file_paths,
+        });
+    }
+    infos.sort_by(|a, b| (&a.key, a.is_sending).cmp(&(&b.key, b.is_sending)));
+    Ok(infos)
+}
+
+/// Checks whether the cf file at `path` matches the size and checksum in `meta`.
+pub fn verify_snapshot_cf_file(path: &PathBuf, meta: &SnapshotCFFile) -> RaftStoreResult<()> {
+    if meta.get_size() == 0 {
+        // Empty cf files are not created at all.
+        return Ok(());
+    }
+    check_file_size_and_checksum(path, meta.get_size(), meta.get_checksum())
+}
+
 #[derive(Default)]
 struct CfFile {
     pub cf: CfName,
@@ -982,6 +1036,7 @@ impl Read for Snap {
                     self.cf_index += 1;
                 }
                 Ok(n) => {
+                    record_io(IOType::Snapshot, IOOp::Read, n as u64);
                     return Ok(n);
                 }
                 e => return e,
@@ -1023,11 +1078,13 @@ impl Write for Snap {
                 try!(file.write_all(next_buf));
                 digest.write(next_buf);
                 cf_file.written_size += next_buf.len() as u64;
+                record_io(IOType::Snapshot, IOOp::Write, buf.len() as u64);
                 return Ok(buf.len());
             }
         }
 
         let n = buf.len() - next_buf.len();
+        record_io(IOType::Snapshot, IOOp::Write, n as u64);
         Ok(n)
     }
 
@@ -1163,21 +1220,13 @@ impl SnapManager {
                     None => return None,
                     Some(n) => n,
                 };
-                let is_sending = name.starts_with(SNAP_GEN_PREFIX);
-                let numbers: Vec<u64> = name.split('.').next().map_or_else(
-                    || vec![],
-                    |s| {
-                        s.split('_')
-                            .skip(1)
-                            .filter_map(|s| s.parse().ok())
-                            .collect()
-                    },
-                );
-                if numbers.len() != 3 {
-                    error!("failed to parse snapkey from {}", name);
-                    return None;
-                }
-                let snap_key = SnapKey::new(numbers[0], numbers[1], numbers[2]);
+                let (snap_key, is_sending) = match parse_snap_file_name(name) {
+                    Some(res) => res,
+                    None => {
+                        error!("failed to parse snapkey from {}", name);
+                        return None;
+                    }
+                };
                 if core.registry.contains_key(&snap_key) {
                     // Skip those registered snapshot.
                     return None;
@@ -1369,8 +1418,9 @@ mod test {
     use tempdir::TempDir;
     use protobuf::Message;
 
-    use super::{ApplyOptions, Snap, SnapEntry, SnapKey, SnapManager, Snapshot, SnapshotDeleter,
-                SnapshotStatistics, META_FILE_SUFFIX, SNAPSHOT_CFS, SNAP_GEN_PREFIX};
+    use super::{load_snapshot_metas, verify_snapshot_cf_file, ApplyOptions, Snap, SnapEntry,
+                SnapKey, SnapManager, Snapshot, SnapshotDeleter, SnapshotStatistics,
+                META_FILE_SUFFIX, SNAPSHOT_CFS, SNAP_GEN_PREFIX};
 
     use std::path::PathBuf;
     use kvproto::metapb::{Peer, Region};
@@ -1643,6 +1693,55 @@ mod test {
         assert_eq_db(db, dst_db.as_ref());
     }
 
+    #[test]
+    fn test_load_snapshot_metas() {
+        let region_id = 1;
+        let region = get_test_region(region_id, 1, 1);
+        let db_dir = TempDir::new("test-load-snapshot-metas-db").unwrap();
+        let db = get_test_db(&db_dir).unwrap();
+        let snapshot = DbSnapshot::new(db);
+
+        let dir = TempDir::new("test-load-snapshot-metas").unwrap();
+        assert!(load_snapshot_metas(dir.path()).unwrap().is_empty());
+
+        let key = SnapKey::new(region_id, 2, 3);
+        let size_track = Arc::new(RwLock::new(0));
+        let deleter = Box::new(DummyDeleter {});
+        let mut s = Snap::new_for_building(
+            dir.path(),
+            &key,
+            &snapshot,
+            size_track.clone(),
+            deleter.clone(),
+        ).unwrap();
+        let mut snap_data = RaftSnapshotData::new();
+        snap_data.set_region(region.clone());
+        let mut stat = SnapshotStatistics::new();
+        s.build(&snapshot, &region, &mut snap_data, &mut stat, deleter)
+            .unwrap();
+
+        let infos = load_snapshot_metas(dir.path()).unwrap();
+        assert_eq!(infos.len(), 1);
+        let info = &infos[0];
+        assert_eq!(info.key, key);
+        assert!(info.is_sending);
+        assert_eq!(info.meta.get_cf_files().len(), SNAPSHOT_CFS.len());
+        for (cf_file, path) in info.meta.get_cf_files().iter().zip(&info.cf_file_paths) {
+            verify_snapshot_cf_file(path, cf_file).unwrap();
+        }
+
+        corrupt_snapshot_size_in(dir.path());
+        let infos = load_snapshot_metas(dir.path()).unwrap();
+        let info = &infos[0];
+        assert!(
+            info.meta
+                .get_cf_files()
+                .iter()
+                .zip(&info.cf_file_paths)
+                .any(|(cf_file, path)| verify_snapshot_cf_file(path, cf_file).is_err())
+        );
+    }
+
     #[test]
     fn test_empty_snap_validation() {
         test_snap_validation(get_test_empty_db);
@@ -31,8 +31,9 @@ use kvproto::raft_serverpb::{PeerState, RaftMessage, RaftSnapshotData, RaftTrunc
                              RegionLocalState};
 use kvproto::eraftpb::{ConfChangeType, MessageType};
 use kvproto::pdpb::StoreStats;
-use util::{escape, rocksdb};
+use util::{escape, panic_hook, rocksdb};
 use util::time::{duration_to_sec, SlowTimer};
+use util::io_metrics::{record_io, IOOp, IOType};
 use pd::PdClient;
 use kvproto::raft_cmdpb::{AdminCmdType, AdminRequest, RaftCmdRequest, RaftCmdResponse,
                           StatusCmdType, StatusResponse};
@@ -40,13 +41,14 @@ use protobuf::Message;
 use raft::{self, SnapshotStatus, INVALID_INDEX};
 use raftstore::{Error, Result};
 use kvproto::metapb;
-use util::worker::{FutureWorker, Scheduler, Worker};
+use util::worker::{FutureWorker, Scheduler, Stopped, Worker};
 use util::transport::SendCh;
 use util::RingQueue;
 use util::collections::{HashMap, HashSet};
 use storage::{CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
 use raftstore::coprocessor::CoprocessorHost;
 use raftstore::coprocessor::split_observer::SplitObserver;
+use import::SSTImporter;
 use super::worker::{ApplyRunner, ApplyTask, ApplyTaskRes, CompactRunner, CompactTask,
                     ConsistencyCheckRunner, ConsistencyCheckTask, PdRunner, PdTask,
                     RaftlogGcRunner, RaftlogGcTask, RegionRunner, RegionTask, SplitCheckRunner,
@@ -143,6 +145,8 @@ pub struct Store<T, C: 'static> {
 
     pub coprocessor_host: Arc<CoprocessorHost>,
 
+    pub importer: Arc<SSTImporter>,
+
     snap_mgr: SnapManager,
 
     raft_metrics: RaftMetrics,
@@ -181,6 +185,8 @@ impl<T, C> Store<T, C> {
         trans: T,
         pd_client: Arc<C>,
         mgr: SnapManager,
+        importer: Arc<SSTImporter>,
+        mut coprocessor_host: CoprocessorHost,
     ) -> Result<Store<T, C>> {
         // TODO: we can get cluster meta regularly too later.
         try!(cfg.validate());
@@ -188,8 +194,6 @@ impl<T, C> Store<T, C> {
         let sendch = SendCh::new(ch.sender, "raftstore");
         let tag = format!("[store {}]", meta.get_id());
 
-        let mut coprocessor_host = CoprocessorHost::new();
-        // TODO load coprocessors from configuration
         coprocessor_host
             .registry
             .register_observer(100, box SplitObserver);
@@ -217,6 +221,7 @@ impl<T, C> Store<T, C> {
             trans: trans,
             pd_client: pd_client,
             coprocessor_host: Arc::new(coprocessor_host),
+            importer: importer,
             snap_mgr: mgr,
             raft_metrics: RaftMetrics::default(),
             entry_cache_metries: Rc::new(RefCell::new(CacheQueryStats::default())),
@@ -760,6 +765,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
 
     fn on_raft_message(&mut self, mut msg: RaftMessage) -> Result<()> {
         let region_id = msg.get_region_id();
+        panic_hook::set_context(region_id, "raft message");
         if !self.is_raft_msg_valid(&msg) {
             return Ok(());
         }
@@ -1087,6 +1093,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             // RaftLocalState, Raft Log Entry
             let mut write_opts = WriteOptions::new();
             write_opts.set_sync(self.cfg.sync_log);
+            record_io(IOType::RaftLog, IOOp::Write, raft_wb.data_size() as u64);
             self.raft_engine
                 .write_opt(raft_wb, &write_opts)
                 .unwrap_or_else(|e| {
@@ -1426,6 +1433,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         let prev_region = apply_result.prev_region;
         let region = apply_result.region;
         let region_id = region.get_id();
+        panic_hook::set_context(region_id, "apply snapshot");
 
         info!(
             "[region {}] snapshot for region {:?} is applied",
@@ -1500,6 +1508,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
     }
 
     fn propose_raft_command(&mut self, msg: RaftCmdRequest, cb: Callback) {
+        panic_hook::set_context(msg.get_header().get_region_id(), "propose");
         match self.pre_propose_raft_command(&msg) {
             Ok(Some(resp)) => {
                 cb.call_box((resp,));
@@ -1868,6 +1877,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             split_key: key.to_vec(),
             peer: peer.peer.clone(),
             right_derive: self.cfg.right_derive_when_split,
+            callback: None,
         };
 
         if let Err(e) = self.pd_worker.schedule(task) {
@@ -1880,6 +1890,77 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         }
     }
 
+    // Splits the region at `split_key`, which is not a data key, on demand. Unlike
+    // the split triggered by the size, the result is sent back by `cb`.
+    fn on_split_region(
+        &mut self,
+        region_id: u64,
+        epoch: metapb::RegionEpoch,
+        split_key: Vec<u8>,
+        cb: Callback,
+    ) {
+        let task = match self.validate_split_region(region_id, &epoch, &split_key) {
+            Ok(peer) => PdTask::AskSplit {
+                region: peer.region().clone(),
+                split_key: split_key,
+                peer: peer.peer.clone(),
+                right_derive: self.cfg.right_derive_when_split,
+                callback: Some(cb),
+            },
+            Err(e) => {
+                cb.call_box((new_error(e),));
+                return;
+            }
+        };
+        if let Err(Stopped(PdTask::AskSplit { callback, .. })) = self.pd_worker.schedule(task) {
+            let e = box_err!("[region {}] failed to notify pd to split", region_id);
+            callback.unwrap().call_box((new_error(e),));
+        }
+    }
+
+    fn validate_split_region(
+        &self,
+        region_id: u64,
+        epoch: &metapb::RegionEpoch,
+        split_key: &[u8],
+    ) -> Result<&Peer> {
+        if split_key.is_empty() {
+            return Err(box_err!("[region {}] split key should not be empty", region_id));
+        }
+        let peer = match self.region_peers.get(&region_id) {
+            Some(peer) => peer,
+            None => return Err(Error::RegionNotFound(region_id)),
+        };
+        if !peer.is_leader() {
+            return Err(Error::NotLeader(
+                region_id,
+                peer.get_peer_from_cache(peer.leader_id()),
+            ));
+        }
+        let region = peer.region();
+        if region.get_region_epoch().get_version() != epoch.get_version() {
+            return Err(Error::StaleEpoch(
+                format!(
+                    "epoch changed {:?} != {:?}",
+                    region.get_region_epoch(),
+                    epoch
+                ),
+                vec![region.clone()],
+            ));
+        }
+        // The key must be inside the region, and the split can't produce an
+        // empty region.
+        try!(util::check_key_in_region(split_key, region));
+        if split_key == region.get_start_key() {
+            return Err(box_err!(
+                "[region {}] split key {} is the start key",
+                region_id,
+                escape(split_key)
+            ));
+        }
+        Ok(peer)
+    }
+
     fn on_pd_heartbeat_tick(&mut self, event_loop: &mut EventLoop<Self>) {
         for peer in self.region_peers.values_mut() {
             peer.check_peers();
@@ -2397,6 +2478,21 @@ impl<T: Transport, C: PdClient> mio::Handler for Store<T, C> {
                 info!("[region {}] split check complete.", region_id);
                 self.on_split_check_result(region_id, epoch, split_key);
             }
+            Msg::SplitRegion {
+                region_id,
+                region_epoch,
+                split_key,
+                callback,
+            } => {
+                info!("[region {}] on split region at {}", region_id, escape(&split_key));
+                self.on_split_region(region_id, region_epoch, split_key, callback);
+            }
+            Msg::ScatterRegion { region } => {
+                let region_id = region.get_id();
+                if let Err(e) = self.pd_worker.schedule(PdTask::ScatterRegion { region: region }) {
+                    error!("[region {}] failed to notify pd to scatter: {}", region_id, e);
+                }
+            }
             Msg::ReportUnreachable {
                 region_id,
                 to_peer_id,
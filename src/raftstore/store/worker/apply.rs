@@ -26,9 +26,12 @@ use kvproto::raft_serverpb::{PeerState, RaftApplyState, RaftTruncatedState};
 use kvproto::raft_cmdpb::{AdminCmdType, AdminRequest, AdminResponse, ChangePeerRequest, CmdType,
                           RaftCmdRequest, RaftCmdResponse, Request, Response};
 
+use import::{check_sst_for_ingestion, SSTImporter};
 use util::worker::Runnable;
-use util::{escape, rocksdb};
+use util::{escape, panic_hook, rocksdb};
 use util::time::SlowTimer;
+use util::io_metrics::{record_io, IOOp, IOType};
+use util::memory::{MemoryTrace, APPLY_MEMORY};
 use util::collections::{HashMap, HashMapEntry as MapEntry};
 use storage::{ALL_CFS, CF_DEFAULT, CF_LOCK, CF_RAFT};
 use raftstore::{Error, Result};
@@ -170,6 +173,8 @@ struct ApplyContext<'a> {
     pub cbs: Vec<(Callback, RaftCmdResponse)>,
     pub wb_last_bytes: u64,
     pub wb_last_keys: u64,
+    // Tracks the memory of the write batch which is not flushed yet.
+    pub mem_trace: MemoryTrace,
 }
 
 impl<'a> ApplyContext<'a> {
@@ -180,6 +185,7 @@ impl<'a> ApplyContext<'a> {
             cbs: vec![],
             wb_last_bytes: 0,
             wb_last_keys: 0,
+            mem_trace: MemoryTrace::new(&APPLY_MEMORY),
         }
     }
 
@@ -194,6 +200,8 @@ impl<'a> ApplyContext<'a> {
     pub fn mark_last_bytes_and_keys(&mut self) {
         self.wb_last_bytes = self.wb_ref().data_size() as u64;
         self.wb_last_keys = self.wb_ref().count() as u64;
+        let size = self.wb_last_bytes as usize;
+        self.mem_trace.set(size);
     }
 
     pub fn delta_bytes(&self) -> u64 {
@@ -291,6 +299,7 @@ pub struct ApplyDelegate {
     term: u64,
     pending_cmds: PendingCmdQueue,
     metrics: ApplyMetrics,
+    importer: Arc<SSTImporter>,
 }
 
 impl ApplyDelegate {
@@ -302,12 +311,16 @@ impl ApplyDelegate {
         self.id
     }
 
-    fn from_peer(peer: &Peer) -> ApplyDelegate {
+    fn from_peer(peer: &Peer, importer: Arc<SSTImporter>) -> ApplyDelegate {
         let reg = Registration::new(peer);
-        ApplyDelegate::from_registration(peer.kv_engine(), reg)
+        ApplyDelegate::from_registration(peer.kv_engine(), importer, reg)
     }
 
-    fn from_registration(db: Arc<DB>, reg: Registration) -> ApplyDelegate {
+    fn from_registration(
+        db: Arc<DB>,
+        importer: Arc<SSTImporter>,
+        reg: Registration,
+    ) -> ApplyDelegate {
         ApplyDelegate {
             id: reg.id,
             tag: format!("[region {}] {}", reg.region.get_id(), reg.id),
@@ -319,6 +332,7 @@ impl ApplyDelegate {
             term: reg.term,
             pending_cmds: Default::default(),
             metrics: Default::default(),
+            importer: importer,
         }
     }
 
@@ -420,8 +434,10 @@ impl ApplyDelegate {
                 self.update_metrics(apply_ctx);
 
                 // flush to engine
+                let wb = apply_ctx.wb.take().unwrap();
+                record_io(IOType::ForegroundWrite, IOOp::Write, wb.data_size() as u64);
                 self.engine
-                    .write(apply_ctx.wb.take().unwrap())
+                    .write(wb)
                     .unwrap_or_else(|e| {
                         panic!("{} failed to write to engine, error: {:?}", self.tag, e)
                     });
@@ -529,12 +545,13 @@ impl ApplyDelegate {
 
         debug!("{} applied command at log index {}", self.tag, index);
 
+        apply_ctx.host.post_apply(&self.region, index, &cmd, &resp);
+
         let cb = match cmd_cb {
             None => return exec_result,
             Some(cb) => cb,
         };
 
-        // TODO: Involve post apply hook.
         // TODO: if we have exec_result, maybe we should return this callback too. Outer
         // store will call it after handing exec result.
         cmd_resp::bind_term(&mut resp, self.term);
@@ -559,6 +576,8 @@ impl ApplyDelegate {
         // if pending remove, apply should be aborted already.
         assert!(!self.pending_remove);
 
+        fail_point!("apply_before_exec_raft_cmd");
+
         let mut ctx = self.new_ctx(wb, index, term, req);
         ctx.wb.set_save_point();
         let (resp, exec_result) = self.exec_raft_cmd(&mut ctx).unwrap_or_else(|e| {
@@ -677,8 +696,10 @@ impl ApplyDelegate {
     ) -> Result<(RaftCmdResponse, Option<ExecResult>)> {
         try!(check_epoch(&self.region, ctx.req));
         if ctx.req.has_admin_request() {
+            panic_hook::set_context(self.region.get_id(), "apply admin command");
             self.exec_admin_cmd(ctx)
         } else {
+            panic_hook::set_context(self.region.get_id(), "apply write command");
             self.exec_write_cmd(ctx)
         }
     }
@@ -1019,6 +1040,7 @@ impl ApplyDelegate {
                 CmdType::Put => self.handle_put(ctx, req),
                 CmdType::Delete => self.handle_delete(ctx, req),
                 CmdType::DeleteRange => self.handle_delete_range(req, &mut ranges),
+                CmdType::IngestSST => self.handle_ingest_sst(req),
                 // Readonly commands are handled in raftstore directly.
                 // Don't panic here in case there are old entries need to be applied.
                 // It's also safe to skip them here, because a restart must have happened,
@@ -1182,6 +1204,31 @@ impl ApplyDelegate {
 
         Ok(resp)
     }
+
+    fn handle_ingest_sst(&mut self, req: &Request) -> Result<Response> {
+        let sst = req.get_ingest_sst().get_sst();
+        if let Err(e) = check_sst_for_ingestion(sst, &self.region) {
+            error!("{} ingest {:?}: {:?}", self.tag, sst, e);
+            // The file is useless for this region.
+            let _ = self.importer.delete(sst);
+            return Err(box_err!("{:?}", e));
+        }
+        if !self.importer.exist(sst) {
+            // The file is removed once ingested, so it has been ingested before a
+            // restart, or it was never downloaded to this store.
+            warn!("{} skip ingesting {:?}, the file doesn't exist", self.tag, sst);
+            return Ok(Response::new());
+        }
+        self.importer
+            .ingest(sst, &self.engine)
+            .unwrap_or_else(|e| {
+                // The file is verified, so either it's corrupted after being verified
+                // or something is wrong with the engine, neither can be recovered.
+                panic!("{} failed to ingest {:?}: {:?}", self.tag, sst, e)
+            });
+        self.metrics.size_diff_hint += sst.get_length() as i64;
+        Ok(Response::new())
+    }
 }
 
 pub fn get_change_peer_cmd(msg: &RaftCmdRequest) -> Option<&ChangePeerRequest> {
@@ -1424,6 +1471,7 @@ pub enum TaskRes {
 pub struct Runner {
     db: Arc<DB>,
     host: Arc<CoprocessorHost>,
+    importer: Arc<SSTImporter>,
     delegates: HashMap<u64, ApplyDelegate>,
     notifier: Sender<TaskRes>,
 }
@@ -1433,11 +1481,15 @@ impl Runner {
         let mut delegates =
             HashMap::with_capacity_and_hasher(store.get_peers().len(), Default::default());
         for (&region_id, p) in store.get_peers() {
-            delegates.insert(region_id, ApplyDelegate::from_peer(p));
+            delegates.insert(
+                region_id,
+                ApplyDelegate::from_peer(p, store.importer.clone()),
+            );
         }
         Runner {
             db: store.kv_engine(),
             host: store.coprocessor_host.clone(),
+            importer: store.importer.clone(),
             delegates: delegates,
             notifier: notifier,
         }
@@ -1483,8 +1535,10 @@ impl Runner {
         }
 
         // Write to engine
+        let wb = apply_ctx.wb.take().unwrap();
+        record_io(IOType::ForegroundWrite, IOOp::Write, wb.data_size() as u64);
         self.db
-            .write(apply_ctx.wb.take().unwrap())
+            .write(wb)
             .unwrap_or_else(|e| panic!("failed to write to engine, error: {:?}", e));
 
         // Call callbacks
@@ -1535,7 +1589,8 @@ impl Runner {
         let peer_id = s.id;
         let region_id = s.region.get_id();
         let term = s.term;
-        let delegate = ApplyDelegate::from_registration(self.db.clone(), s);
+        let delegate =
+            ApplyDelegate::from_registration(self.db.clone(), self.importer.clone(), s);
         info!(
             "{} register to apply delegates at term {}",
             delegate.tag,
@@ -1582,6 +1637,7 @@ impl Runnable<Task> for Runner {
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
     use std::sync::*;
 
     use tempdir::TempDir;
@@ -1603,9 +1659,11 @@ mod tests {
     }
 
     fn new_runner(db: Arc<DB>, host: Arc<CoprocessorHost>, tx: Sender<TaskRes>) -> Runner {
+        let importer = SSTImporter::new(Path::new(db.path()).join("import")).unwrap();
         Runner {
             db: db,
             host: host,
+            importer: Arc::new(importer),
             delegates: HashMap::default(),
             notifier: tx,
         }
@@ -1881,7 +1939,8 @@ mod tests {
         let mut reg = Registration::default();
         reg.region.set_end_key(b"k5".to_vec());
         reg.region.mut_region_epoch().set_version(3);
-        let mut delegate = ApplyDelegate::from_registration(db.clone(), reg);
+        let importer = Arc::new(SSTImporter::new(_path.path().join("import")).unwrap());
+        let mut delegate = ApplyDelegate::from_registration(db.clone(), importer, reg);
         let (tx, rx) = mpsc::channel();
 
         let put_entry = EntryBuilder::new(1, 1)
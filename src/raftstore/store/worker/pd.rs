@@ -27,7 +27,8 @@ use util::worker::FutureRunnable as Runnable;
 use util::escape;
 use util::transport::SendCh;
 use pd::{PdClient, RegionStat};
-use raftstore::store::Msg;
+use raftstore::store::{Callback, Msg};
+use raftstore::store::cmd_resp::new_error;
 use raftstore::store::util::{get_region_approximate_size, is_epoch_stale};
 use raftstore::store::metrics::*;
 use rocksdb::DB;
@@ -44,6 +45,8 @@ pub enum Task {
         peer: metapb::Peer,
         // If true, right region derive origin region_id.
         right_derive: bool,
+        // Called with the response of the split command, if any.
+        callback: Option<Callback>,
     },
     Heartbeat {
         region: metapb::Region,
@@ -65,6 +68,9 @@ pub enum Task {
         region: metapb::Region,
         peer: metapb::Peer,
     },
+    ScatterRegion {
+        region: metapb::Region,
+    },
 }
 
 impl Display for Task {
@@ -101,6 +107,7 @@ impl Display for Task {
                 ref region,
                 ref peer,
             } => write!(f, "validate peer {:?} with region {:?}", peer, region),
+            Task::ScatterRegion { ref region } => write!(f, "scatter region {:?}", region),
         }
     }
 }
@@ -131,6 +138,7 @@ impl<T: PdClient> Runner<T> {
         split_key: Vec<u8>,
         peer: metapb::Peer,
         right_derive: bool,
+        callback: Option<Callback>,
     ) {
         PD_REQ_COUNTER_VEC
             .with_label_values(&["ask split", "all"])
@@ -156,10 +164,13 @@ impl<T: PdClient> Runner<T> {
                         resp.take_new_peer_ids(),
                         right_derive,
                     );
-                    send_admin_request(ch, region, peer, req);
+                    send_admin_request(ch, region, peer, req, callback);
                 }
                 Err(e) => {
                     debug!("[region {}] failed to ask split: {:?}", region.get_id(), e);
+                    if let Some(callback) = callback {
+                        callback.call_box((new_error(e.into()),));
+                    }
                 }
             }
             Ok(())
@@ -270,6 +281,28 @@ impl<T: PdClient> Runner<T> {
         handle.spawn(f);
     }
 
+    fn handle_scatter_region(&self, handle: &Handle, region: metapb::Region) {
+        PD_REQ_COUNTER_VEC
+            .with_label_values(&["scatter region", "all"])
+            .inc();
+
+        let region_id = region.get_id();
+        let f = self.pd_client.scatter_region(region).then(move |resp| {
+            match resp {
+                Ok(_) => {
+                    PD_REQ_COUNTER_VEC
+                        .with_label_values(&["scatter region", "success"])
+                        .inc();
+                }
+                Err(e) => {
+                    error!("[region {}] scatter region failed {:?}", region_id, e);
+                }
+            }
+            Ok(())
+        });
+        handle.spawn(f);
+    }
+
     fn handle_validate_peer(
         &self,
         handle: &Handle,
@@ -361,7 +394,7 @@ impl<T: PdClient> Runner<T> {
                         change_peer.get_change_type().into(),
                         change_peer.take_peer(),
                     );
-                    send_admin_request_raw(&ch, region_id, epoch, peer, req);
+                    send_admin_request_raw(&ch, region_id, epoch, peer, req, None);
                 } else if resp.has_transfer_leader() {
                     PD_HEARTBEAT_COUNTER_VEC
                         .with_label_values(&["transfer leader"])
@@ -375,7 +408,7 @@ impl<T: PdClient> Runner<T> {
                         transfer_leader.get_peer()
                     );
                     let req = new_transfer_leader_request(transfer_leader.take_peer());
-                    send_admin_request_raw(&ch, region_id, epoch, peer, req)
+                    send_admin_request_raw(&ch, region_id, epoch, peer, req, None)
                 }
             })
             .map_err(|e| panic!("unexpected error: {:?}", e))
@@ -404,7 +437,8 @@ impl<T: PdClient> Runnable<Task> for Runner<T> {
                 split_key,
                 peer,
                 right_derive,
-            } => self.handle_ask_split(handle, region, split_key, peer, right_derive),
+                callback,
+            } => self.handle_ask_split(handle, region, split_key, peer, right_derive, callback),
             Task::Heartbeat {
                 region,
                 peer,
@@ -432,6 +466,7 @@ impl<T: PdClient> Runnable<Task> for Runner<T> {
             }
             Task::ReportSplit { left, right } => self.handle_report_split(handle, left, right),
             Task::ValidatePeer { region, peer } => self.handle_validate_peer(handle, region, peer),
+            Task::ScatterRegion { region } => self.handle_scatter_region(handle, region),
         };
     }
 }
@@ -471,10 +506,11 @@ fn send_admin_request(
     mut region: metapb::Region,
     peer: metapb::Peer,
     request: AdminRequest,
+    callback: Option<Callback>,
 ) {
     let region_id = region.get_id();
     let epoch = region.take_region_epoch();
-    send_admin_request_raw(&ch, region_id, epoch, peer, request)
+    send_admin_request_raw(&ch, region_id, epoch, peer, request, callback)
 }
 
 fn send_admin_request_raw(
@@ -483,6 +519,7 @@ fn send_admin_request_raw(
     epoch: metapb::RegionEpoch,
     peer: metapb::Peer,
     request: AdminRequest,
+    callback: Option<Callback>,
 ) {
     let cmd_type = request.get_cmd_type();
 
@@ -493,7 +530,8 @@ fn send_admin_request_raw(
 
     req.set_admin_request(request);
 
-    if let Err(e) = ch.try_send(Msg::new_raft_cmd(req, Box::new(|_| {}))) {
+    let callback = callback.unwrap_or_else(|| Box::new(|_| {}));
+    if let Err(e) = ch.try_send(Msg::new_raft_cmd(req, callback)) {
         error!(
             "[region {}] send {:?} request err {:?}",
             region_id,
@@ -99,6 +99,9 @@ struct SnapContext {
 
 impl SnapContext {
     fn generate_snap(&self, region_id: u64, notifier: SyncSender<RaftSnapshot>) -> Result<()> {
+        fail_point!("region_gen_snap", |_| {
+            Err(box_err!("generating snapshot is failed by fail point"))
+        });
         // do we need to check leader here?
         let raft_db = self.raft_db.clone();
         let raw_snap = Snapshot::new(self.kv_db.clone());
@@ -140,6 +143,7 @@ impl SnapContext {
 
     fn apply_snap(&self, region_id: u64, abort: Arc<AtomicUsize>) -> Result<()> {
         info!("[region {}] begin apply snap data", region_id);
+        fail_point!("region_apply_snap");
         try!(check_abort(&abort));
         let region_key = keys::region_state_key(region_id);
         let mut region_state: RegionLocalState =
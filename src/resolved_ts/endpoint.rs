@@ -0,0 +1,694 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::Duration;
+
+use futures::Future;
+use futures_cpupool::{Builder, CpuPool};
+use kvproto::kvrpcpb::{Context, IsolationLevel};
+use kvproto::raft_cmdpb::Request;
+use kvproto::raft_serverpb::{PeerState, RegionLocalState, StoreIdent};
+use rocksdb::DB;
+use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
+
+use pd::PdClient;
+use raftstore::store::keys;
+use raftstore::store::engine::Peekable;
+use storage::{Engine, MaxTsTracker, ScanMode, Snapshot, Statistics, CF_LOCK, CF_RAFT, CF_WRITE};
+use storage::engine::BatchResults;
+use storage::mvcc::{Lock, MvccReader, Write};
+use storage::types::split_encoded_key_on_ts;
+use util::collections::HashMap;
+use util::worker::{FutureRunnable as Runnable, FutureScheduler};
+use super::Result;
+use super::resolver::Resolver;
+
+const ADVANCE_TS_INTERVAL_SECS: u64 = 1;
+const POOL_SIZE: usize = 2;
+
+static NEXT_OBSERVE_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// `RegionsResolvedTs` holds the resolved ts of the regions led by this store.
+/// All the transactions committed at or before the resolved ts of a region
+/// have been applied on it, so a read at such a ts sees no lock and no more
+/// changes, which is the basis of stale reads.
+#[derive(Default)]
+pub struct RegionsResolvedTs {
+    regions: RwLock<HashMap<u64, u64>>,
+}
+
+impl RegionsResolvedTs {
+    pub fn new() -> RegionsResolvedTs {
+        RegionsResolvedTs::default()
+    }
+
+    pub fn get(&self, region_id: u64) -> Option<u64> {
+        self.regions.read().unwrap().get(&region_id).cloned()
+    }
+
+    /// Returns whether the data of the region at `ts` is settled.
+    pub fn is_resolved(&self, region_id: u64, ts: u64) -> bool {
+        self.get(region_id).map_or(false, |resolved_ts| ts <= resolved_ts)
+    }
+
+    fn update(&self, region_id: u64, resolved_ts: u64) {
+        self.regions
+            .write()
+            .unwrap()
+            .insert(region_id, resolved_ts);
+    }
+
+    fn update_batch(&self, resolved_ts: Vec<(u64, u64)>) {
+        let mut regions = self.regions.write().unwrap();
+        for (region_id, ts) in resolved_ts {
+            regions.insert(region_id, ts);
+        }
+    }
+
+    fn remove(&self, region_id: u64) {
+        self.regions.write().unwrap().remove(&region_id);
+    }
+}
+
+pub enum Task {
+    RoleChange { region_id: u64, is_leader: bool },
+    ChangeLog {
+        region_id: u64,
+        requests: Vec<Request>,
+    },
+    ResolverReady {
+        region_id: u64,
+        observe_id: usize,
+        resolver: Option<Resolver>,
+    },
+    RegisterAdvanceEvent,
+    AdvanceResolvedTs { min_ts: u64 },
+    LeaderConfirmed {
+        region_id: u64,
+        observe_id: usize,
+        min_ts: u64,
+    },
+    // The results of checking the leaderships of many regions at once. The
+    // regions in `unconfirmed` can't be confirmed by their leases, and need to
+    // be confirmed one by one.
+    LeadersChecked {
+        min_ts: u64,
+        confirmed: Vec<(u64, usize)>,
+        unconfirmed: Vec<(u64, usize)>,
+    },
+}
+
+impl Display for Task {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Task::RoleChange {
+                region_id,
+                is_leader,
+            } => write!(f, "region {} role change, is leader {}", region_id, is_leader),
+            Task::ChangeLog {
+                region_id,
+                ref requests,
+            } => write!(
+                f,
+                "region {} applies {} lock changes",
+                region_id,
+                requests.len()
+            ),
+            Task::ResolverReady {
+                region_id,
+                ref resolver,
+                ..
+            } => write!(
+                f,
+                "region {} resolver is ready: {}",
+                region_id,
+                resolver.is_some()
+            ),
+            Task::RegisterAdvanceEvent => write!(f, "register advance resolved ts event"),
+            Task::AdvanceResolvedTs { min_ts } => write!(f, "advance resolved ts to {}", min_ts),
+            Task::LeaderConfirmed {
+                region_id, min_ts, ..
+            } => write!(f, "region {} leader is confirmed at {}", region_id, min_ts),
+            Task::LeadersChecked {
+                min_ts,
+                ref confirmed,
+                ref unconfirmed,
+            } => write!(
+                f,
+                "{} leaders are confirmed at {}, {} are not",
+                confirmed.len(),
+                min_ts,
+                unconfirmed.len()
+            ),
+        }
+    }
+}
+
+enum LockChange {
+    Track(u64, Vec<u8>),
+    Untrack(u64, Vec<u8>),
+}
+
+fn apply_lock_change(resolver: &mut Resolver, change: LockChange) {
+    match change {
+        LockChange::Track(ts, key) => resolver.track_lock(ts, key),
+        LockChange::Untrack(ts, key) => resolver.untrack_lock(ts, &key),
+    }
+}
+
+// A prewrite puts a lock, while a commit or rollback puts a write record and
+// removes the lock of the same transaction.
+fn decode_lock_changes(requests: &[Request]) -> Result<Vec<LockChange>> {
+    let mut changes = Vec::with_capacity(requests.len());
+    for req in requests {
+        let put = req.get_put();
+        match put.get_cf() {
+            CF_LOCK => {
+                let lock = try!(Lock::parse(put.get_value()));
+                changes.push(LockChange::Track(lock.ts, put.get_key().to_vec()));
+            }
+            CF_WRITE => {
+                let (key, _) = try!(split_encoded_key_on_ts(put.get_key()));
+                let write = try!(Write::parse(put.get_value()));
+                changes.push(LockChange::Untrack(write.start_ts, key.to_vec()));
+            }
+            _ => {}
+        }
+    }
+    Ok(changes)
+}
+
+// Tracks the locks of a region led by this store.
+struct RegionResolver {
+    // A region may be led again after its leadership is lost, the results of
+    // the previous leadership are discarded by the id.
+    observe_id: usize,
+    resolver: Option<Resolver>,
+    // The lock changes applied before the resolver is ready.
+    pending_changes: Vec<LockChange>,
+    scanning: bool,
+}
+
+impl RegionResolver {
+    fn new() -> RegionResolver {
+        RegionResolver {
+            observe_id: NEXT_OBSERVE_ID.fetch_add(1, Ordering::SeqCst),
+            resolver: None,
+            pending_changes: vec![],
+            scanning: false,
+        }
+    }
+
+    fn apply_lock_changes(&mut self, changes: Vec<LockChange>) {
+        match self.resolver {
+            Some(ref mut resolver) => for change in changes {
+                apply_lock_change(resolver, change);
+            },
+            None => self.pending_changes.extend(changes),
+        }
+    }
+
+    fn on_resolver_ready(&mut self, mut resolver: Resolver) {
+        for change in self.pending_changes.drain(..) {
+            apply_lock_change(&mut resolver, change);
+        }
+        self.resolver = Some(resolver);
+    }
+}
+
+/// `Endpoint` maintains the resolved ts of the regions led by this store. The
+/// locks of a region are scanned when it becomes the leader, and tracked as
+/// they are applied. A timestamp is taken from PD periodically, and once the
+/// leadership is confirmed after that, the resolved ts of the region advances
+/// to the timestamp or the smallest start ts of the locks. The leaderships of
+/// all the regions are checked in one batch, so the cost of advancing doesn't
+/// grow much with the number of regions.
+pub struct Endpoint<C> {
+    regions: HashMap<u64, RegionResolver>,
+    resolved_ts: Arc<RegionsResolvedTs>,
+    scheduler: FutureScheduler<Task>,
+    db: Arc<DB>,
+    engine: Box<Engine>,
+    // The one-phase commits write no lock, the resolved ts is advanced with the
+    // ts recorded as the max ts.
+    max_ts: MaxTsTracker,
+    pd_client: Arc<C>,
+    timer: Timer,
+    advance_interval: Duration,
+    is_advance_scheduled: bool,
+    // Locks are scanned and leaderships are confirmed on the pool, both of
+    // which need to read through raft.
+    pool: CpuPool,
+}
+
+impl<C: PdClient> Endpoint<C> {
+    /// Creates an endpoint. `db` is the kv engine, it's used to find the local
+    /// peers of the regions, while the locks are scanned from `engine`.
+    pub fn new(
+        pd_client: Arc<C>,
+        scheduler: FutureScheduler<Task>,
+        db: Arc<DB>,
+        engine: Box<Engine>,
+        max_ts: MaxTsTracker,
+    ) -> Endpoint<C> {
+        let pool = Builder::new()
+            .name_prefix(thd_name!("resolved-ts"))
+            .pool_size(POOL_SIZE)
+            .create();
+        Endpoint {
+            regions: HashMap::default(),
+            resolved_ts: Arc::new(RegionsResolvedTs::new()),
+            scheduler: scheduler,
+            db: db,
+            engine: engine,
+            max_ts: max_ts,
+            pd_client: pd_client,
+            timer: Timer::default(),
+            advance_interval: Duration::from_secs(ADVANCE_TS_INTERVAL_SECS),
+            is_advance_scheduled: false,
+            pool: pool,
+        }
+    }
+
+    pub fn resolved_ts(&self) -> Arc<RegionsResolvedTs> {
+        self.resolved_ts.clone()
+    }
+
+    fn on_role_change(&mut self, region_id: u64, is_leader: bool) {
+        if is_leader {
+            self.regions.insert(region_id, RegionResolver::new());
+            self.scan_locks(region_id);
+        } else if self.regions.remove(&region_id).is_some() {
+            self.resolved_ts.remove(region_id);
+        }
+    }
+
+    fn scan_locks(&mut self, region_id: u64) {
+        let observe_id = match self.regions.get_mut(&region_id) {
+            Some(region) => {
+                region.scanning = true;
+                region.observe_id
+            }
+            None => return,
+        };
+        let db = self.db.clone();
+        let engine = self.engine.clone();
+        let scheduler = self.scheduler.clone();
+        self.pool
+            .spawn_fn(move || {
+                let resolver = match scan_locks(&db, engine.as_ref(), region_id) {
+                    Ok(resolver) => Some(resolver),
+                    Err(e) => {
+                        warn!("failed to scan locks of region {}: {:?}", region_id, e);
+                        None
+                    }
+                };
+                let task = Task::ResolverReady {
+                    region_id: region_id,
+                    observe_id: observe_id,
+                    resolver: resolver,
+                };
+                if let Err(e) = scheduler.schedule(task) {
+                    warn!("failed to schedule resolver of region {}: {:?}", region_id, e);
+                }
+                Ok::<_, ()>(())
+            })
+            .forget();
+    }
+
+    fn on_change_log(&mut self, region_id: u64, requests: Vec<Request>) {
+        let region = match self.regions.get_mut(&region_id) {
+            Some(region) => region,
+            None => return,
+        };
+        match decode_lock_changes(&requests) {
+            Ok(changes) => region.apply_lock_changes(changes),
+            Err(e) => {
+                // The locks are unknown, track them from scratch.
+                error!("failed to decode lock changes of region {}: {:?}", region_id, e);
+                *region = RegionResolver::new();
+                self.resolved_ts.remove(region_id);
+            }
+        }
+    }
+
+    fn on_resolver_ready(&mut self, region_id: u64, observe_id: usize, resolver: Option<Resolver>) {
+        if let Some(region) = self.regions.get_mut(&region_id) {
+            if region.observe_id != observe_id {
+                return;
+            }
+            region.scanning = false;
+            // The locks are scanned again in the next round if it fails.
+            if let Some(resolver) = resolver {
+                region.on_resolver_ready(resolver);
+            }
+        }
+    }
+
+    fn on_advance(&mut self, min_ts: u64) {
+        let mut to_scan = vec![];
+        let mut to_check = vec![];
+        for (region_id, region) in &self.regions {
+            if region.resolver.is_none() {
+                if !region.scanning {
+                    to_scan.push(*region_id);
+                }
+                continue;
+            }
+            to_check.push((*region_id, region.observe_id));
+        }
+        for region_id in to_scan {
+            self.scan_locks(region_id);
+        }
+        if !to_check.is_empty() {
+            self.check_leaders(to_check, min_ts);
+        }
+    }
+
+    // Checks the leaderships of all the regions with one batch of snapshots,
+    // most of which are served by the leases on the raftstore thread without
+    // going through raft. The rest are confirmed one by one later.
+    fn check_leaders(&self, regions: Vec<(u64, usize)>, min_ts: u64) {
+        let db = self.db.clone();
+        let engine = self.engine.clone();
+        let scheduler = self.scheduler.clone();
+        let resolved_ts = self.resolved_ts.clone();
+        self.pool
+            .spawn_fn(move || {
+                let mut ctxs = Vec::with_capacity(regions.len());
+                let mut to_check = Vec::with_capacity(regions.len());
+                for (region_id, observe_id) in regions {
+                    match new_context(&db, region_id) {
+                        Ok(ctx) => {
+                            ctxs.push(ctx);
+                            to_check.push((region_id, observe_id));
+                        }
+                        Err(e) => {
+                            debug!("failed to check leader of region {}: {:?}", region_id, e);
+                            resolved_ts.remove(region_id);
+                        }
+                    }
+                }
+                if to_check.is_empty() {
+                    return Ok(());
+                }
+
+                let cb_scheduler = scheduler.clone();
+                let cb_regions = to_check.clone();
+                let on_finished = box move |results: BatchResults<Box<Snapshot>>| {
+                    let mut confirmed = vec![];
+                    let mut unconfirmed = vec![];
+                    for (region, res) in cb_regions.into_iter().zip(results) {
+                        match res {
+                            Some((_, Ok(_))) => confirmed.push(region),
+                            Some((_, Err(e))) => {
+                                debug!("failed to check leader of region {}: {:?}", region.0, e);
+                                resolved_ts.remove(region.0);
+                            }
+                            None => unconfirmed.push(region),
+                        }
+                    }
+                    let task = Task::LeadersChecked {
+                        min_ts: min_ts,
+                        confirmed: confirmed,
+                        unconfirmed: unconfirmed,
+                    };
+                    if let Err(e) = cb_scheduler.schedule(task) {
+                        warn!("failed to schedule leaders checked: {:?}", e);
+                    }
+                };
+                if let Err(e) = engine.async_batch_snapshot(ctxs, on_finished) {
+                    warn!("failed to check leaders of {} regions: {:?}", to_check.len(), e);
+                    let task = Task::LeadersChecked {
+                        min_ts: min_ts,
+                        confirmed: vec![],
+                        unconfirmed: to_check,
+                    };
+                    if let Err(e) = scheduler.schedule(task) {
+                        warn!("failed to schedule leaders checked: {:?}", e);
+                    }
+                }
+                Ok::<_, ()>(())
+            })
+            .forget();
+    }
+
+    fn on_leaders_checked(
+        &mut self,
+        min_ts: u64,
+        confirmed: Vec<(u64, usize)>,
+        unconfirmed: Vec<(u64, usize)>,
+    ) {
+        let mut resolved_ts = Vec::with_capacity(confirmed.len());
+        for (region_id, observe_id) in confirmed {
+            if let Some(ts) = self.resolve(region_id, observe_id, min_ts) {
+                resolved_ts.push((region_id, ts));
+            }
+        }
+        self.resolved_ts.update_batch(resolved_ts);
+        for (region_id, observe_id) in unconfirmed {
+            self.confirm_leader(region_id, observe_id, min_ts);
+        }
+    }
+
+    // A stale leader may miss the locks put by the new leader, so the leadership
+    // is confirmed by reading through raft after `min_ts` is taken.
+    fn confirm_leader(&self, region_id: u64, observe_id: usize, min_ts: u64) {
+        let db = self.db.clone();
+        let engine = self.engine.clone();
+        let scheduler = self.scheduler.clone();
+        let resolved_ts = self.resolved_ts.clone();
+        self.pool
+            .spawn_fn(move || {
+                let res = new_context(&db, region_id)
+                    .and_then(|ctx| engine.snapshot(&ctx).map_err(From::from));
+                if let Err(e) = res {
+                    debug!("failed to confirm leader of region {}: {:?}", region_id, e);
+                    resolved_ts.remove(region_id);
+                    return Ok(());
+                }
+                let task = Task::LeaderConfirmed {
+                    region_id: region_id,
+                    observe_id: observe_id,
+                    min_ts: min_ts,
+                };
+                if let Err(e) = scheduler.schedule(task) {
+                    warn!("failed to schedule leader confirmed: {:?}", e);
+                }
+                Ok::<_, ()>(())
+            })
+            .forget();
+    }
+
+    fn on_leader_confirmed(&mut self, region_id: u64, observe_id: usize, min_ts: u64) {
+        if let Some(ts) = self.resolve(region_id, observe_id, min_ts) {
+            self.resolved_ts.update(region_id, ts);
+        }
+    }
+
+    // Returns the resolved ts of the region after its leadership is confirmed
+    // at `min_ts`, or `None` if it's not led by this store any more.
+    fn resolve(&mut self, region_id: u64, observe_id: usize, min_ts: u64) -> Option<u64> {
+        let region = match self.regions.get_mut(&region_id) {
+            Some(region) => region,
+            None => return None,
+        };
+        if region.observe_id != observe_id {
+            return None;
+        }
+        region
+            .resolver
+            .as_mut()
+            .map(|resolver| resolver.resolve(min_ts))
+    }
+
+    fn register_advance_event(&self, handle: &Handle) {
+        let pd_client = self.pd_client.clone();
+        let scheduler = self.scheduler.clone();
+        let max_ts = self.max_ts.clone();
+        let f = self.timer
+            .sleep(self.advance_interval)
+            .then(move |_| pd_client.get_tso())
+            .then(move |res| {
+                match res {
+                    Ok(ts) => {
+                        let min_ts = max_ts.min_ts_to_resolve(ts);
+                        let task = Task::AdvanceResolvedTs { min_ts: min_ts };
+                        if let Err(e) = scheduler.schedule(task) {
+                            warn!("failed to schedule advance resolved ts: {:?}", e);
+                        }
+                    }
+                    Err(e) => warn!("failed to get tso to advance resolved ts: {:?}", e),
+                }
+                // The loop stops when the worker is stopped.
+                let _ = scheduler.schedule(Task::RegisterAdvanceEvent);
+                Ok(())
+            });
+        handle.spawn(f);
+    }
+}
+
+impl<C: PdClient> Runnable<Task> for Endpoint<C> {
+    fn run(&mut self, task: Task, handle: &Handle) {
+        debug!("resolved ts run task {}", task);
+        if !self.is_advance_scheduled {
+            self.is_advance_scheduled = true;
+            self.register_advance_event(handle);
+        }
+
+        match task {
+            Task::RoleChange {
+                region_id,
+                is_leader,
+            } => self.on_role_change(region_id, is_leader),
+            Task::ChangeLog {
+                region_id,
+                requests,
+            } => self.on_change_log(region_id, requests),
+            Task::ResolverReady {
+                region_id,
+                observe_id,
+                resolver,
+            } => self.on_resolver_ready(region_id, observe_id, resolver),
+            Task::RegisterAdvanceEvent => self.register_advance_event(handle),
+            Task::AdvanceResolvedTs { min_ts } => self.on_advance(min_ts),
+            Task::LeaderConfirmed {
+                region_id,
+                observe_id,
+                min_ts,
+            } => self.on_leader_confirmed(region_id, observe_id, min_ts),
+            Task::LeadersChecked {
+                min_ts,
+                confirmed,
+                unconfirmed,
+            } => self.on_leaders_checked(min_ts, confirmed, unconfirmed),
+        }
+    }
+}
+
+// Builds the context to read the region through raft with the local peer.
+fn new_context(db: &DB, region_id: u64) -> Result<Context> {
+    let store_id = match box_try!(db.get_msg::<StoreIdent>(keys::STORE_IDENT_KEY)) {
+        Some(ident) => ident.get_store_id(),
+        None => return Err(box_err!("store is not bootstrapped")),
+    };
+    let state_key = keys::region_state_key(region_id);
+    let mut state = match box_try!(db.get_msg_cf::<RegionLocalState>(CF_RAFT, &state_key)) {
+        Some(state) => state,
+        None => return Err(box_err!("region {} is not found", region_id)),
+    };
+    if state.get_state() != PeerState::Normal {
+        return Err(box_err!("region {} is {:?}", region_id, state.get_state()));
+    }
+    let mut region = state.take_region();
+    let peer = match region
+        .get_peers()
+        .iter()
+        .find(|p| p.get_store_id() == store_id)
+    {
+        Some(peer) => peer.clone(),
+        None => return Err(box_err!("region {} has no peer on store {}", region_id, store_id)),
+    };
+    let mut ctx = Context::new();
+    ctx.set_region_id(region_id);
+    ctx.set_region_epoch(region.take_region_epoch());
+    ctx.set_peer(peer);
+    Ok(ctx)
+}
+
+fn scan_locks(db: &DB, engine: &Engine, region_id: u64) -> Result<Resolver> {
+    let ctx = try!(new_context(db, region_id));
+    let snapshot = try!(engine.snapshot(&ctx));
+    let mut statistics = Statistics::default();
+    let mut reader = MvccReader::new(
+        snapshot.as_ref(),
+        &mut statistics,
+        Some(ScanMode::Forward),
+        false,
+        None,
+        IsolationLevel::SI,
+    );
+    let (locks, _) = try!(reader.scan_lock(None, |_| true, None));
+    let mut resolver = Resolver::new();
+    for (key, lock) in locks {
+        resolver.track_lock(lock.ts, key.encoded().clone());
+    }
+    Ok(resolver)
+}
+
+#[cfg(test)]
+mod tests {
+    use kvproto::raft_cmdpb::{CmdType, PutRequest, Request};
+
+    use storage::{make_key, CF_LOCK, CF_WRITE};
+    use storage::mvcc::{Lock, LockType, Write, WriteType};
+    use super::*;
+
+    fn new_put(cf: &str, key: Vec<u8>, value: Vec<u8>) -> Request {
+        let mut put = PutRequest::new();
+        put.set_cf(cf.to_owned());
+        put.set_key(key);
+        put.set_value(value);
+        let mut req = Request::new();
+        req.set_cmd_type(CmdType::Put);
+        req.set_put(put);
+        req
+    }
+
+    fn new_lock(key: &[u8], ts: u64) -> Request {
+        let key = make_key(key);
+        let lock = Lock::new(LockType::Put, key.raw().unwrap(), ts, 0, None);
+        new_put(CF_LOCK, key.encoded().clone(), lock.to_bytes())
+    }
+
+    fn new_write(key: &[u8], start_ts: u64, commit_ts: u64) -> Request {
+        let key = make_key(key).append_ts(commit_ts);
+        let write = Write::new(WriteType::Put, start_ts, None);
+        new_put(CF_WRITE, key.encoded().clone(), write.to_bytes())
+    }
+
+    #[test]
+    fn test_region_resolver() {
+        let mut region = RegionResolver::new();
+        let changes = decode_lock_changes(&[new_lock(b"a", 5), new_lock(b"b", 6)]).unwrap();
+        region.apply_lock_changes(changes);
+        let changes = decode_lock_changes(&[new_write(b"a", 5, 7)]).unwrap();
+        region.apply_lock_changes(changes);
+        assert_eq!(region.pending_changes.len(), 3);
+
+        // The scanned locks may overlap the pending changes.
+        let mut resolver = Resolver::new();
+        resolver.track_lock(5, make_key(b"a").encoded().clone());
+        resolver.track_lock(3, make_key(b"c").encoded().clone());
+        region.on_resolver_ready(resolver);
+        assert!(region.pending_changes.is_empty());
+        let resolver = region.resolver.as_mut().unwrap();
+        assert_eq!(resolver.locks_count(), 2);
+        assert_eq!(resolver.resolve(10), 3);
+
+        let resolved_ts = RegionsResolvedTs::new();
+        assert!(!resolved_ts.is_resolved(1, 1));
+        resolved_ts.update(1, 3);
+        assert!(resolved_ts.is_resolved(1, 3));
+        assert!(!resolved_ts.is_resolved(1, 4));
+        resolved_ts.remove(1);
+        assert_eq!(resolved_ts.get(1), None);
+        resolved_ts.update_batch(vec![(1, 5), (2, 6)]);
+        assert!(resolved_ts.is_resolved(1, 5));
+        assert!(resolved_ts.is_resolved(2, 6));
+    }
+}
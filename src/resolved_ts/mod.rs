@@ -0,0 +1,29 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resolved ts of a region is a timestamp before which all the transactions
+//! have been committed or rolled back and applied on the region. It's the
+//! smaller one of the smallest start ts of the locks in the region and the
+//! latest timestamp at which the leadership of the region is confirmed.
+//! Reads at or before the resolved ts don't meet any lock and never change,
+//! so they can be served without going through raft in the future.
+
+mod errors;
+mod resolver;
+mod observer;
+mod endpoint;
+
+pub use self::errors::{Error, Result};
+pub use self::resolver::Resolver;
+pub use self::observer::ResolvedTsObserver;
+pub use self::endpoint::{Endpoint, RegionsResolvedTs, Task};
@@ -0,0 +1,101 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, RwLock};
+
+use kvproto::raft_cmdpb::{CmdType, Request};
+use raft::StateRole;
+
+use raftstore::coprocessor::{Coprocessor, ObserverContext, RegionObserver};
+use storage::{CF_LOCK, CF_WRITE};
+use util::collections::HashSet;
+use util::worker::FutureScheduler;
+use super::endpoint::Task;
+
+/// `ResolvedTsObserver` tracks the regions led by this store, and sends the
+/// lock changes applied on them to the resolved ts endpoint.
+#[derive(Clone)]
+pub struct ResolvedTsObserver {
+    scheduler: FutureScheduler<Task>,
+    leader_regions: Arc<RwLock<HashSet<u64>>>,
+}
+
+impl ResolvedTsObserver {
+    pub fn new(scheduler: FutureScheduler<Task>) -> ResolvedTsObserver {
+        ResolvedTsObserver {
+            scheduler: scheduler,
+            leader_regions: Arc::new(RwLock::new(HashSet::default())),
+        }
+    }
+
+    fn is_leader(&self, region_id: u64) -> bool {
+        self.leader_regions.read().unwrap().contains(&region_id)
+    }
+}
+
+// Only the puts of locks and write records change the locks.
+fn is_lock_change(req: &Request) -> bool {
+    if req.get_cmd_type() != CmdType::Put {
+        return false;
+    }
+    let cf = req.get_put().get_cf();
+    cf == CF_LOCK || cf == CF_WRITE
+}
+
+impl Coprocessor for ResolvedTsObserver {}
+
+impl RegionObserver for ResolvedTsObserver {
+    fn post_apply_query(&self, ctx: &mut ObserverContext, _: u64, requests: &[Request]) {
+        let region_id = ctx.region().get_id();
+        if !self.is_leader(region_id) {
+            return;
+        }
+        let requests: Vec<_> = requests
+            .iter()
+            .filter(|r| is_lock_change(r))
+            .cloned()
+            .collect();
+        if requests.is_empty() {
+            return;
+        }
+        let task = Task::ChangeLog {
+            region_id: region_id,
+            requests: requests,
+        };
+        if let Err(e) = self.scheduler.schedule(task) {
+            warn!("failed to schedule lock changes of region {}: {:?}", region_id, e);
+        }
+    }
+
+    fn on_role_change(&self, ctx: &mut ObserverContext, role: StateRole) {
+        let region_id = ctx.region().get_id();
+        let changed = {
+            let mut regions = self.leader_regions.write().unwrap();
+            if role == StateRole::Leader {
+                regions.insert(region_id)
+            } else {
+                regions.remove(&region_id)
+            }
+        };
+        if !changed {
+            return;
+        }
+        let task = Task::RoleChange {
+            region_id: region_id,
+            is_leader: role == StateRole::Leader,
+        };
+        if let Err(e) = self.scheduler.schedule(task) {
+            warn!("failed to schedule role change of region {}: {:?}", region_id, e);
+        }
+    }
+}
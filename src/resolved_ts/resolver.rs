@@ -0,0 +1,124 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp;
+use std::collections::BTreeMap;
+
+use util::collections::{HashMap, HashSet};
+
+/// `Resolver` tracks the locks of a region to calculate its resolved ts. All
+/// the transactions committed at or before the resolved ts have been seen, and
+/// no more changes before it will come.
+#[derive(Default)]
+pub struct Resolver {
+    // start_ts -> locked keys.
+    locks_by_ts: BTreeMap<u64, HashSet<Vec<u8>>>,
+    // key -> start_ts of its lock.
+    lock_ts: HashMap<Vec<u8>, u64>,
+    resolved_ts: u64,
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        Resolver::default()
+    }
+
+    pub fn resolved_ts(&self) -> u64 {
+        self.resolved_ts
+    }
+
+    pub fn locks_count(&self) -> usize {
+        self.lock_ts.len()
+    }
+
+    pub fn track_lock(&mut self, start_ts: u64, key: Vec<u8>) {
+        if let Some(old_ts) = self.lock_ts.insert(key.clone(), start_ts) {
+            self.remove_from_ts(old_ts, &key);
+        }
+        self.locks_by_ts
+            .entry(start_ts)
+            .or_insert_with(HashSet::default)
+            .insert(key);
+    }
+
+    /// Removes the lock of `key` if it's locked by the transaction `start_ts`.
+    pub fn untrack_lock(&mut self, start_ts: u64, key: &[u8]) {
+        if self.lock_ts.get(key) != Some(&start_ts) {
+            return;
+        }
+        self.lock_ts.remove(key);
+        self.remove_from_ts(start_ts, key);
+    }
+
+    fn remove_from_ts(&mut self, start_ts: u64, key: &[u8]) {
+        let empty = match self.locks_by_ts.get_mut(&start_ts) {
+            Some(keys) => {
+                keys.remove(key);
+                keys.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.locks_by_ts.remove(&start_ts);
+        }
+    }
+
+    /// Advances the resolved ts with `min_ts`, which must be a timestamp taken
+    /// after all the changes before it are tracked. A pending transaction may
+    /// still be committed after its start_ts, so the resolved ts can't exceed
+    /// the smallest start_ts of the locks. The resolved ts never goes back.
+    pub fn resolve(&mut self, min_ts: u64) -> u64 {
+        let min_lock_ts = self.locks_by_ts
+            .keys()
+            .next()
+            .cloned()
+            .unwrap_or(min_ts);
+        let new_resolved_ts = cmp::min(min_ts, min_lock_ts);
+        self.resolved_ts = cmp::max(self.resolved_ts, new_resolved_ts);
+        self.resolved_ts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let mut resolver = Resolver::new();
+        assert_eq!(resolver.resolve(5), 5);
+
+        resolver.track_lock(6, b"a".to_vec());
+        resolver.track_lock(8, b"b".to_vec());
+        assert_eq!(resolver.resolve(10), 6);
+        // Committing a lock of another transaction changes nothing.
+        resolver.untrack_lock(7, b"a");
+        assert_eq!(resolver.resolve(10), 6);
+        resolver.untrack_lock(6, b"a");
+        assert_eq!(resolver.resolve(10), 8);
+        assert_eq!(resolver.locks_count(), 1);
+
+        // The resolved ts never goes back.
+        assert_eq!(resolver.resolve(7), 8);
+        resolver.untrack_lock(8, b"b");
+        assert_eq!(resolver.resolve(12), 12);
+        assert_eq!(resolver.locks_count(), 0);
+
+        // A key locked again replaces the old lock.
+        resolver.track_lock(13, b"c".to_vec());
+        resolver.track_lock(15, b"c".to_vec());
+        assert_eq!(resolver.resolve(20), 15);
+        resolver.untrack_lock(15, b"c");
+        assert_eq!(resolver.resolve(20), 20);
+    }
+}
@@ -16,7 +16,7 @@ use std::ascii::AsciiExt;
 use sys_info;
 
 use util::collections::HashMap;
-use util::config::{self, ReadableSize};
+use util::config::{self, ReadableDuration, ReadableSize};
 
 use super::Result;
 
@@ -26,12 +26,15 @@ pub use storage::Config as StorageConfig;
 pub const DEFAULT_CLUSTER_ID: u64 = 0;
 pub const DEFAULT_LISTENING_ADDR: &'static str = "127.0.0.1:20160";
 const DEFAULT_ADVERTISE_LISTENING_ADDR: &'static str = "";
+const DEFAULT_STATUS_ADDR: &'static str = "127.0.0.1:20180";
 const DEFAULT_NOTIFY_CAPACITY: usize = 40960;
 const DEFAULT_GRPC_CONCURRENCY: usize = 4;
 const DEFAULT_GRPC_CONCURRENT_STREAM: usize = 1024;
 const DEFAULT_GRPC_RAFT_CONN_NUM: usize = 10;
 const DEFAULT_GRPC_STREAM_INITIAL_WINDOW_SIZE: u64 = 2 * 1024 * 1024;
 const DEFAULT_MESSAGES_PER_TICK: usize = 4096;
+const DEFAULT_SNAP_STREAM_INITIAL_WINDOW_SIZE: u64 = 2 * 1024 * 1024;
+const DEFAULT_SNAP_MAX_WRITE_BYTES_PER_SEC: u64 = 100 * 1024 * 1024;
 
 // Assume a request can be finished in 1ms, a request at position x will wait about
 // 0.001 * x secs to be actual started. A server-is-busy error will trigger 2 seconds
@@ -39,9 +42,12 @@ const DEFAULT_MESSAGES_PER_TICK: usize = 4096;
 // larger latency.
 pub const DEFAULT_MAX_RUNNING_TASK_COUNT: usize = 2 as usize * 1000;
 
+const DEFAULT_QUOTA_MAX_DELAY_MS: u64 = 500;
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     #[serde(skip)]
     pub cluster_id: u64,
@@ -52,14 +58,33 @@ pub struct Config {
     // Server advertise listening address for outer communication.
     // If not set, we will use listening address instead.
     pub advertise_addr: String,
+    // Status server listening address, it serves the debug endpoints such as
+    // heap profiling. Empty means the status server is disabled.
+    pub status_addr: String,
     pub notify_capacity: usize,
     pub messages_per_tick: usize,
     pub grpc_concurrency: usize,
     pub grpc_concurrent_stream: usize,
     pub grpc_raft_conn_num: usize,
     pub grpc_stream_initial_window_size: ReadableSize,
+    // Snapshots are sent on their own connections, with the window and the
+    // bandwidth limit below, 0 means no limit.
+    pub snap_stream_initial_window_size: ReadableSize,
+    pub snap_max_write_bytes_per_sec: ReadableSize,
     pub end_point_concurrency: usize,
     pub end_point_max_tasks: usize,
+    // Coprocessor requests taking longer than it are logged as slow.
+    pub end_point_slow_log_threshold: ReadableDuration,
+    // When the memory tracked by TiKV exceeds it, new coprocessor requests and
+    // writes are rejected as busy. 0 means no limit.
+    pub memory_usage_soft_limit: ReadableSize,
+    // The quotas of the cpu time in millicores and the write bandwidth taken
+    // by the foreground requests, 0 means no limit.
+    pub foreground_cpu_time: usize,
+    pub foreground_write_bandwidth: ReadableSize,
+    // The responses are delayed for at most it when the quotas are exceeded,
+    // new requests are rejected as busy beyond it.
+    pub foreground_quota_max_delay: ReadableDuration,
     // Server labels to specify some attributes about this server.
     #[serde(with = "config::order_map_serde")]
     pub labels: HashMap<String, String>,
@@ -78,14 +103,22 @@ impl Default for Config {
             addr: DEFAULT_LISTENING_ADDR.to_owned(),
             labels: HashMap::default(),
             advertise_addr: DEFAULT_ADVERTISE_LISTENING_ADDR.to_owned(),
+            status_addr: DEFAULT_STATUS_ADDR.to_owned(),
             notify_capacity: DEFAULT_NOTIFY_CAPACITY,
             messages_per_tick: DEFAULT_MESSAGES_PER_TICK,
             grpc_concurrency: DEFAULT_GRPC_CONCURRENCY,
             grpc_concurrent_stream: DEFAULT_GRPC_CONCURRENT_STREAM,
             grpc_raft_conn_num: DEFAULT_GRPC_RAFT_CONN_NUM,
             grpc_stream_initial_window_size: ReadableSize(DEFAULT_GRPC_STREAM_INITIAL_WINDOW_SIZE),
+            snap_stream_initial_window_size: ReadableSize(DEFAULT_SNAP_STREAM_INITIAL_WINDOW_SIZE),
+            snap_max_write_bytes_per_sec: ReadableSize(DEFAULT_SNAP_MAX_WRITE_BYTES_PER_SEC),
             end_point_concurrency: concurrency,
             end_point_max_tasks: DEFAULT_MAX_RUNNING_TASK_COUNT,
+            end_point_slow_log_threshold: ReadableDuration::secs(1),
+            memory_usage_soft_limit: ReadableSize(0),
+            foreground_cpu_time: 0,
+            foreground_write_bandwidth: ReadableSize(0),
+            foreground_quota_max_delay: ReadableDuration::millis(DEFAULT_QUOTA_MAX_DELAY_MS),
         }
     }
 }
@@ -105,6 +138,21 @@ impl Config {
                 self.advertise_addr
             ));
         }
+        if !self.status_addr.is_empty() {
+            box_try!(config::check_addr(&self.status_addr));
+        }
+
+        for &(name, value) in &[
+            ("notify-capacity", self.notify_capacity),
+            ("messages-per-tick", self.messages_per_tick),
+            ("grpc-concurrency", self.grpc_concurrency),
+            ("grpc-concurrent-stream", self.grpc_concurrent_stream),
+            ("grpc-raft-conn-num", self.grpc_raft_conn_num),
+        ] {
+            if value == 0 {
+                return Err(box_err!("server.{} should not be 0.", name));
+            }
+        }
 
         if self.end_point_concurrency == 0 {
             return Err(box_err!("server.end-point-concurrency should not be 0."));
@@ -173,12 +221,22 @@ mod tests {
         invalid_cfg.end_point_max_tasks = 0;
         assert!(invalid_cfg.validate().is_err());
 
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.grpc_concurrency = 0;
+        assert!(invalid_cfg.validate().is_err());
+
         invalid_cfg = Config::default();
         invalid_cfg.addr = "0.0.0.0:1000".to_owned();
         assert!(invalid_cfg.validate().is_err());
         invalid_cfg.advertise_addr = "127.0.0.1:1000".to_owned();
         invalid_cfg.validate().unwrap();
 
+        invalid_cfg = Config::default();
+        invalid_cfg.status_addr = "127.0.0.1".to_owned();
+        assert!(invalid_cfg.validate().is_err());
+        invalid_cfg.status_addr = String::new();
+        invalid_cfg.validate().unwrap();
+
         cfg.labels.insert("k1".to_owned(), "v1".to_owned());
         cfg.validate().unwrap();
         cfg.labels.insert("k2".to_owned(), "v2?".to_owned());
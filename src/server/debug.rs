@@ -0,0 +1,1534 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{error, result};
+
+use rocksdb::{CompactOptions, DBIterator, SeekKey, Writable, WriteBatch, DB};
+use protobuf::{self, RepeatedField};
+use kvproto::debugpb::{DB as DBType, MODULE};
+use kvproto::eraftpb::Entry;
+use kvproto::kvrpcpb::{LockInfo, MvccInfo, Op, ValueInfo, WriteInfo};
+use kvproto::metapb::Region;
+use kvproto::raft_serverpb::{PeerState, RaftApplyState, RaftLocalState, RegionLocalState,
+                             StoreIdent};
+
+use raftstore::store::{keys, util, write_peer_state, Engines};
+use raftstore::store::engine::{IterOption, Iterable, Mutable, Peekable};
+use storage::{CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE, LARGE_CFS};
+use storage::types::split_encoded_key_on_ts;
+use storage::mvcc::{Lock, LockType, Write, WriteType};
+use util::escape;
+use util::dynamic_config::ConfigHandler;
+use util::properties::MvccProperties;
+use util::rocksdb::get_cf_handle;
+
+pub type Result<T> = result::Result<T, Error>;
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum Error {
+        InvalidArgument(msg: String) {
+            description(msg)
+            display("Invalid Argument {:?}", msg)
+        }
+        NotFound(msg: String) {
+            description(msg)
+            display("Not Found {:?}", msg)
+        }
+        Other(err: Box<error::Error + Sync + Send>) {
+            from()
+            cause(err.as_ref())
+            description(err.description())
+            display("{:?}", err)
+        }
+    }
+}
+
+/// Local raft meta of a region, any of which may be missing on a damaged store.
+#[derive(PartialEq, Debug, Default)]
+pub struct RegionInfo {
+    pub raft_local_state: Option<RaftLocalState>,
+    pub raft_apply_state: Option<RaftApplyState>,
+    pub region_local_state: Option<RegionLocalState>,
+}
+
+impl RegionInfo {
+    pub fn new(
+        raft_local: Option<RaftLocalState>,
+        raft_apply: Option<RaftApplyState>,
+        region_local: Option<RegionLocalState>,
+    ) -> Self {
+        RegionInfo {
+            raft_local_state: raft_local,
+            raft_apply_state: raft_apply,
+            region_local_state: region_local,
+        }
+    }
+}
+
+/// `DBConfigHandler` changes the mutable rocksdb options of the kv or raft db
+/// through the config manager.
+pub struct DBConfigHandler {
+    debugger: Debugger,
+    module: MODULE,
+}
+
+impl DBConfigHandler {
+    pub fn new(engines: Engines, module: MODULE) -> DBConfigHandler {
+        DBConfigHandler {
+            debugger: Debugger::new(engines),
+            module: module,
+        }
+    }
+}
+
+impl ConfigHandler for DBConfigHandler {
+    fn update(&self, name: &str, value: &str) -> result::Result<(), String> {
+        self.debugger
+            .modify_tikv_config(self.module, name, value)
+            .map_err(|e| format!("{}", e))
+    }
+}
+
+/// `Debugger` reads (and in a few cases, modifies) the engines of a store directly,
+/// bypassing raft. It backs both the debug service and the local mode of tikv-ctl.
+#[derive(Clone)]
+pub struct Debugger {
+    engines: Engines,
+}
+
+impl Debugger {
+    pub fn new(engines: Engines) -> Debugger {
+        Debugger { engines: engines }
+    }
+
+    pub fn get_engine(&self) -> &Engines {
+        &self.engines
+    }
+
+    fn get_db_from_type(&self, db: DBType) -> Result<&DB> {
+        match db {
+            DBType::KV => Ok(&self.engines.kv_engine),
+            DBType::RAFT => Ok(&self.engines.raft_engine),
+            _ => Err(box_err!("invalid DBType type")),
+        }
+    }
+
+    fn get_store_ident(&self) -> Result<StoreIdent> {
+        let kv_engine = &self.engines.kv_engine;
+        match box_try!(kv_engine.get_msg::<StoreIdent>(keys::STORE_IDENT_KEY)) {
+            Some(ident) => Ok(ident),
+            None => Err(Error::NotFound("store ident".to_owned())),
+        }
+    }
+
+    /// Gets the id of this store from the store ident in the kv engine.
+    pub fn get_store_id(&self) -> Result<u64> {
+        self.get_store_ident().map(|ident| ident.get_store_id())
+    }
+
+    /// Gets the id of the cluster this store belongs to.
+    pub fn get_cluster_id(&self) -> Result<u64> {
+        self.get_store_ident().map(|ident| ident.get_cluster_id())
+    }
+
+    /// Gets the raw value of `key` in column family `cf` of the given db.
+    pub fn get(&self, db: DBType, cf: &str, key: &[u8]) -> Result<Vec<u8>> {
+        try!(validate_db_and_cf(db, cf));
+        let engine = try!(self.get_db_from_type(db));
+        match engine.get_value_cf(cf, key) {
+            Ok(Some(v)) => Ok(v.to_vec()),
+            Ok(None) => Err(Error::NotFound(format!(
+                "value for key {:?} in db {:?}",
+                key,
+                db
+            ))),
+            Err(e) => Err(box_err!(e)),
+        }
+    }
+
+    pub fn raft_log(&self, region_id: u64, log_index: u64) -> Result<Entry> {
+        let key = keys::raft_log_key(region_id, log_index);
+        match self.engines.raft_engine.get_msg(&key) {
+            Ok(Some(entry)) => Ok(entry),
+            Ok(None) => Err(Error::NotFound(format!(
+                "raft log for region {} at index {}",
+                region_id,
+                log_index
+            ))),
+            Err(e) => Err(box_err!(e)),
+        }
+    }
+
+    pub fn region_info(&self, region_id: u64) -> Result<RegionInfo> {
+        let raft_state_key = keys::raft_state_key(region_id);
+        let raft_state = box_try!(
+            self.engines
+                .raft_engine
+                .get_msg::<RaftLocalState>(&raft_state_key)
+        );
+
+        let apply_state_key = keys::apply_state_key(region_id);
+        let apply_state = box_try!(
+            self.engines
+                .kv_engine
+                .get_msg_cf::<RaftApplyState>(CF_RAFT, &apply_state_key)
+        );
+
+        let region_state_key = keys::region_state_key(region_id);
+        let region_state = box_try!(
+            self.engines
+                .kv_engine
+                .get_msg_cf::<RegionLocalState>(CF_RAFT, &region_state_key)
+        );
+
+        match (raft_state, apply_state, region_state) {
+            (None, None, None) => Err(Error::NotFound(format!("info for region {}", region_id))),
+            (raft_state, apply_state, region_state) => {
+                Ok(RegionInfo::new(raft_state, apply_state, region_state))
+            }
+        }
+    }
+
+    /// Scans all `RegionLocalState`s on this store, ordered by region id.
+    pub fn get_all_region_states(&self) -> Result<Vec<(u64, RegionLocalState)>> {
+        let mut region_states = vec![];
+        box_try!(self.engines.kv_engine.scan_cf(
+            CF_RAFT,
+            keys::REGION_META_MIN_KEY,
+            keys::REGION_META_MAX_KEY,
+            false,
+            &mut |key, value| {
+                let (region_id, suffix) = try!(keys::decode_region_meta_key(key));
+                if suffix != keys::REGION_STATE_SUFFIX {
+                    return Ok(true);
+                }
+                let state = try!(protobuf::parse_from_bytes::<RegionLocalState>(value));
+                region_states.push((region_id, state));
+                Ok(true)
+            },
+        ));
+        Ok(region_states)
+    }
+
+    /// Checks the raft meta of all non-tombstone regions on this store, returns the
+    /// damaged ones together with the reason.
+    pub fn bad_regions(&self) -> Result<Vec<(u64, Error)>> {
+        let mut res = vec![];
+        let mut alive_regions = vec![];
+        for (region_id, region_state) in try!(self.get_all_region_states()) {
+            if region_state.get_state() == PeerState::Tombstone {
+                continue;
+            }
+            if let Err(e) = self.check_region_state(region_id) {
+                res.push((region_id, e));
+            }
+            alive_regions.push(region_state.get_region().clone());
+        }
+
+        // Ranges of alive regions on one store should never overlap.
+        alive_regions.sort_by(|a, b| a.get_start_key().cmp(b.get_start_key()));
+        for pair in alive_regions.windows(2) {
+            let (prev, next) = (&pair[0], &pair[1]);
+            if prev.get_end_key().is_empty() || prev.get_end_key() > next.get_start_key() {
+                let msg = format!("range overlaps with region {}", next.get_id());
+                res.push((prev.get_id(), box_err!(msg)));
+                let msg = format!("range overlaps with region {}", prev.get_id());
+                res.push((next.get_id(), box_err!(msg)));
+            }
+        }
+        Ok(res)
+    }
+
+    fn check_region_state(&self, region_id: u64) -> Result<()> {
+        let raft_state = box_try!(
+            self.engines
+                .raft_engine
+                .get_msg::<RaftLocalState>(&keys::raft_state_key(region_id))
+        );
+        let apply_state = box_try!(
+            self.engines
+                .kv_engine
+                .get_msg_cf::<RaftApplyState>(CF_RAFT, &keys::apply_state_key(region_id))
+        );
+        let (raft_state, apply_state) = match (raft_state, apply_state) {
+            (None, _) => return Err(box_err!("raft state not found")),
+            (_, None) => return Err(box_err!("apply state not found")),
+            (Some(r), Some(a)) => (r, a),
+        };
+
+        let last_index = raft_state.get_last_index();
+        let commit_index = raft_state.get_hard_state().get_commit();
+        let applied_index = apply_state.get_applied_index();
+        let truncated_index = apply_state.get_truncated_state().get_index();
+        if commit_index > last_index {
+            return Err(box_err!(
+                "commit index {} > last index {}",
+                commit_index,
+                last_index
+            ));
+        }
+        if applied_index > commit_index {
+            return Err(box_err!(
+                "applied index {} > commit index {}",
+                applied_index,
+                commit_index
+            ));
+        }
+        if truncated_index > applied_index {
+            return Err(box_err!(
+                "truncated index {} > applied index {}",
+                truncated_index,
+                applied_index
+            ));
+        }
+        Ok(())
+    }
+
+    /// Removes peers located on `store_ids` from the region meta of all non-tombstone
+    /// regions on this store. It's used to restore quorum after some stores are
+    /// permanently lost, and must only be run when the store is offline.
+    pub fn remove_failed_stores(&self, store_ids: &[u64]) -> Result<()> {
+        let kv_engine = &self.engines.kv_engine;
+        let raft_cf = box_try!(get_cf_handle(kv_engine, CF_RAFT));
+        let wb = WriteBatch::new();
+        for (region_id, mut region_state) in try!(self.get_all_region_states()) {
+            if region_state.get_state() == PeerState::Tombstone {
+                continue;
+            }
+            let old_peers = region_state.mut_region().take_peers().into_vec();
+            let (removed, kept): (Vec<_>, Vec<_>) = old_peers
+                .into_iter()
+                .partition(|p| store_ids.contains(&p.get_store_id()));
+            if removed.is_empty() {
+                continue;
+            }
+            info!(
+                "region {} removes peers {:?}, remaining {:?}",
+                region_id,
+                removed,
+                kept
+            );
+            region_state
+                .mut_region()
+                .set_peers(RepeatedField::from_vec(kept));
+            let key = keys::region_state_key(region_id);
+            box_try!(wb.put_msg_cf(raft_cf, &key, &region_state));
+        }
+        box_try!(kv_engine.write(wb));
+        Ok(())
+    }
+
+    /// Sets the local peers of `regions` to tombstone. `regions` must be the latest
+    /// region meta fetched from PD, local peers still present in them are rejected.
+    /// Returns regions which fail to be set.
+    pub fn set_region_tombstone(&self, regions: Vec<Region>) -> Result<Vec<(u64, Error)>> {
+        let kv_engine = &self.engines.kv_engine;
+        let store_id = try!(self.get_store_id());
+
+        let wb = WriteBatch::new();
+        let mut errors = vec![];
+        for region in regions {
+            let region_id = region.get_id();
+            if let Err(e) = self.set_region_tombstone_in_wb(store_id, &region, &wb) {
+                errors.push((region_id, e));
+            }
+        }
+
+        if errors.is_empty() {
+            box_try!(kv_engine.write(wb));
+        }
+        Ok(errors)
+    }
+
+    fn set_region_tombstone_in_wb(
+        &self,
+        store_id: u64,
+        region: &Region,
+        wb: &WriteBatch,
+    ) -> Result<()> {
+        let kv_engine = &self.engines.kv_engine;
+        let key = keys::region_state_key(region.get_id());
+        let region_state = match box_try!(kv_engine.get_msg_cf::<RegionLocalState>(CF_RAFT, &key)) {
+            Some(state) => state,
+            None => return Err(Error::NotFound(format!("region {}", region.get_id()))),
+        };
+        if region_state.get_state() == PeerState::Tombstone {
+            return Ok(());
+        }
+        if util::find_peer(region, store_id).is_some() {
+            return Err(box_err!("the peer is still in the region meta from PD"));
+        }
+        let local_region = region_state.get_region();
+        if util::is_epoch_stale(region.get_region_epoch(), local_region.get_region_epoch()) {
+            return Err(box_err!(
+                "local region epoch {:?} is newer than PD's {:?}",
+                local_region.get_region_epoch(),
+                region.get_region_epoch()
+            ));
+        }
+        box_try!(write_peer_state(
+            kv_engine,
+            wb,
+            local_region,
+            PeerState::Tombstone
+        ));
+        Ok(())
+    }
+
+    /// Scans raw key-value pairs of column family `cf` in the given db within
+    /// [`start`, `end`). An empty `end` means no upper bound, and a zero `limit`
+    /// means no limit.
+    pub fn raw_scan(
+        &self,
+        db: DBType,
+        cf: &str,
+        start: &[u8],
+        end: &[u8],
+        limit: u64,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        try!(validate_db_and_cf(db, cf));
+        if !end.is_empty() && start >= end {
+            return Err(Error::InvalidArgument(
+                "`start` must be smaller than `end`".to_owned(),
+            ));
+        }
+        let engine = try!(self.get_db_from_type(db));
+        let upper_bound = if end.is_empty() {
+            None
+        } else {
+            Some(end.to_vec())
+        };
+        let mut iter = box_try!(engine.new_iterator_cf(cf, IterOption::new(upper_bound, false)));
+        iter.seek(SeekKey::Key(start));
+        let mut res = vec![];
+        while iter.valid() && (limit == 0 || (res.len() as u64) < limit) {
+            res.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+        Ok(res)
+    }
+
+    /// Compacts the given range of column family `cf` in the given db. Empty `start`
+    /// or `end` means unbounded on that side.
+    pub fn compact(&self, db: DBType, cf: &str, start: &[u8], end: &[u8]) -> Result<()> {
+        try!(validate_db_and_cf(db, cf));
+        let engine = try!(self.get_db_from_type(db));
+        let handle = box_try!(get_cf_handle(engine, cf));
+        let start = if start.is_empty() { None } else { Some(start) };
+        let end = if end.is_empty() { None } else { Some(end) };
+        let mut compact_opts = CompactOptions::new();
+        compact_opts.set_exclusive_manual_compaction(false);
+        engine.compact_range_cf_opt(handle, &compact_opts, start, end);
+        Ok(())
+    }
+
+    /// Gets the approximate on-disk size of a region in each of `cfs`. The size of
+    /// large column families is estimated by SST properties, others are scanned.
+    pub fn region_size<T: AsRef<str>>(
+        &self,
+        region_id: u64,
+        cfs: Vec<T>,
+    ) -> Result<Vec<(T, u64)>> {
+        let kv_engine = &self.engines.kv_engine;
+        let region_state_key = keys::region_state_key(region_id);
+        let region = match box_try!(
+            kv_engine.get_msg_cf::<RegionLocalState>(CF_RAFT, &region_state_key)
+        ) {
+            Some(mut state) => state.take_region(),
+            None => return Err(Error::NotFound(format!("region {}", region_id))),
+        };
+        let start_key = keys::enc_start_key(&region);
+        let end_key = keys::enc_end_key(&region);
+        let mut sizes = vec![];
+        for cf in cfs {
+            let mut size = 0;
+            {
+                let cf_name = cf.as_ref();
+                try!(validate_db_and_cf(DBType::KV, cf_name));
+                if LARGE_CFS.iter().any(|c| *c == cf_name) {
+                    size = box_try!(util::get_region_approximate_size_cf(
+                        kv_engine,
+                        cf_name,
+                        &region
+                    ));
+                } else {
+                    let mut f = |k: &[u8], v: &[u8]| {
+                        size += (k.len() + v.len()) as u64;
+                        Ok(true)
+                    };
+                    box_try!(kv_engine.scan_cf(cf_name, &start_key, &end_key, false, &mut f));
+                }
+            }
+            sizes.push((cf, size));
+        }
+        Ok(sizes)
+    }
+
+    /// Gets the approximate size and keys of each large column family in the region,
+    /// as well as the mvcc properties, all collected from SST properties.
+    pub fn get_region_properties(&self, region_id: u64) -> Result<Vec<(String, String)>> {
+        let kv_engine = &self.engines.kv_engine;
+        let key = keys::region_state_key(region_id);
+        let region_state = match box_try!(kv_engine.get_msg_cf::<RegionLocalState>(CF_RAFT, &key)) {
+            Some(state) => state,
+            None => return Err(Error::NotFound(format!("region {}", region_id))),
+        };
+        let region = region_state.get_region();
+
+        let mut res = vec![];
+        for cf in LARGE_CFS {
+            let size = box_try!(util::get_region_approximate_size_cf(kv_engine, cf, region));
+            let collection = box_try!(util::get_region_properties_cf(kv_engine, cf, region));
+            let mut num_entries = 0;
+            for (_, v) in &*collection {
+                num_entries += v.num_entries();
+            }
+            res.push((format!("{}.approximate_size", cf), size.to_string()));
+            res.push((format!("{}.num_entries", cf), num_entries.to_string()));
+        }
+
+        let collection = box_try!(util::get_region_properties_cf(kv_engine, CF_WRITE, region));
+        let mut mvcc_props = MvccProperties::new();
+        for (_, v) in &*collection {
+            let props = box_try!(MvccProperties::decode(v.user_collected_properties()));
+            mvcc_props.add(&props);
+        }
+        res.push(("mvcc.min_ts".to_owned(), mvcc_props.min_ts.to_string()));
+        res.push(("mvcc.max_ts".to_owned(), mvcc_props.max_ts.to_string()));
+        res.push(("mvcc.num_rows".to_owned(), mvcc_props.num_rows.to_string()));
+        res.push(("mvcc.num_puts".to_owned(), mvcc_props.num_puts.to_string()));
+        res.push(("mvcc.num_deletes".to_owned(), mvcc_props.num_deletes.to_string()));
+        res.push((
+            "mvcc.num_versions".to_owned(),
+            mvcc_props.num_versions.to_string(),
+        ));
+        res.push((
+            "mvcc.max_row_versions".to_owned(),
+            mvcc_props.max_row_versions.to_string(),
+        ));
+        Ok(res)
+    }
+
+    /// Changes a mutable rocksdb option of a running TiKV. `config_name` is either
+    /// `<cf>.<option>` for column family options or `<option>` for db options.
+    pub fn modify_tikv_config(
+        &self,
+        module: MODULE,
+        config_name: &str,
+        config_value: &str,
+    ) -> Result<()> {
+        let db = match module {
+            MODULE::KVDB => DBType::KV,
+            MODULE::RAFTDB => DBType::RAFT,
+            _ => {
+                return Err(Error::InvalidArgument(
+                    format!("unsupported module: {:?}", module),
+                ))
+            }
+        };
+        let engine = try!(self.get_db_from_type(db));
+        let names: Vec<&str> = config_name.split('.').collect();
+        match names.len() {
+            1 => box_try!(engine.set_db_options(&[(config_name, config_value)])),
+            2 => {
+                let (cf, name) = (names[0], names[1]);
+                try!(validate_db_and_cf(db, cf));
+                let handle = box_try!(get_cf_handle(engine, cf));
+                box_try!(engine.set_options_cf(handle, &[(name, config_value)]));
+            }
+            _ => {
+                return Err(Error::InvalidArgument(
+                    format!("bad argument: {}", config_name),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Scans mvcc records of all keys in [`start`, `end`), both of which must be data keys.
+    /// An empty `end` means scanning to the end of the data area, and a zero `limit` means
+    /// no limit.
+    pub fn scan_mvcc(&self, start: &[u8], end: &[u8], limit: u64) -> Result<MvccInfoIterator> {
+        if !keys::validate_data_key(start) || (!end.is_empty() && !keys::validate_data_key(end)) {
+            return Err(Error::InvalidArgument(format!(
+                "scan range [{:?}, {:?}) is not in the data area",
+                start,
+                end
+            )));
+        }
+        if !end.is_empty() && start >= end {
+            return Err(Error::InvalidArgument(
+                "`start` must be smaller than `end`".to_owned(),
+            ));
+        }
+        MvccInfoIterator::new(&self.engines.kv_engine, start, end, limit)
+    }
+
+    /// Checks the mvcc records of the given regions, or all regions if `region_ids` is
+    /// empty, and removes the inconsistent ones: locks whose transaction is already
+    /// committed or rolled back, locks and put records whose value is missing, and
+    /// values referenced by neither a lock nor a write record. Nothing is written if
+    /// `dry_run` is true. It must only be run when the store is offline.
+    pub fn recover_mvcc(
+        &self,
+        region_ids: &[u64],
+        dry_run: bool,
+    ) -> Result<Vec<(u64, MvccRecoverStats)>> {
+        let kv_engine = &self.engines.kv_engine;
+        let region_states = if region_ids.is_empty() {
+            try!(self.get_all_region_states())
+        } else {
+            let mut states = Vec::with_capacity(region_ids.len());
+            for &region_id in region_ids {
+                let key = keys::region_state_key(region_id);
+                match box_try!(kv_engine.get_msg_cf::<RegionLocalState>(CF_RAFT, &key)) {
+                    Some(state) => states.push((region_id, state)),
+                    None => return Err(Error::NotFound(format!("region {}", region_id))),
+                }
+            }
+            states
+        };
+
+        let mut res = vec![];
+        for (region_id, region_state) in region_states {
+            if region_state.get_state() == PeerState::Tombstone {
+                continue;
+            }
+            let region = region_state.get_region();
+            let start = keys::enc_start_key(region);
+            let end = keys::enc_end_key(region);
+            let wb = WriteBatch::new();
+            let stats = {
+                let mut checker = try!(MvccChecker::new(kv_engine, &start, &end));
+                try!(checker.check(&wb))
+            };
+            if !dry_run && wb.count() > 0 {
+                box_try!(kv_engine.write(wb));
+            }
+            res.push((region_id, stats));
+        }
+        Ok(res)
+    }
+}
+
+pub struct MvccInfoIterator<'a> {
+    limit: u64,
+    count: u64,
+    lock_iter: DBIterator<'a>,
+    default_iter: DBIterator<'a>,
+    write_iter: DBIterator<'a>,
+}
+
+// Creates an iterator of `cf` seeked to `start`. An empty `end` means no upper bound
+// inside the data area.
+fn new_mvcc_iter<'a>(db: &'a DB, cf: &str, start: &[u8], end: &[u8]) -> Result<DBIterator<'a>> {
+    let upper_bound = if end.is_empty() {
+        keys::DATA_MAX_KEY.to_vec()
+    } else {
+        end.to_vec()
+    };
+    let iter_opt = IterOption::new(Some(upper_bound), false);
+    let mut iter = box_try!(db.new_iterator_cf(cf, iter_opt));
+    iter.seek(SeekKey::Key(start));
+    Ok(iter)
+}
+
+// Returns the user key (data prefix and timestamp stripped) the iterator points to.
+fn current_key(iter: &DBIterator, with_ts: bool) -> Result<Option<Vec<u8>>> {
+    if !iter.valid() {
+        return Ok(None);
+    }
+    let key = keys::origin_key(iter.key());
+    if !with_ts {
+        return Ok(Some(key.to_vec()));
+    }
+    let (key, _) = box_try!(split_encoded_key_on_ts(key));
+    Ok(Some(key.to_vec()))
+}
+
+impl<'a> MvccInfoIterator<'a> {
+    fn new(db: &'a DB, start: &[u8], end: &[u8], limit: u64) -> Result<MvccInfoIterator<'a>> {
+        Ok(MvccInfoIterator {
+            limit: limit,
+            count: 0,
+            lock_iter: try!(new_mvcc_iter(db, CF_LOCK, start, end)),
+            default_iter: try!(new_mvcc_iter(db, CF_DEFAULT, start, end)),
+            write_iter: try!(new_mvcc_iter(db, CF_WRITE, start, end)),
+        })
+    }
+
+    fn next_lock(&mut self, key: &[u8]) -> Result<Option<LockInfo>> {
+        if try!(current_key(&self.lock_iter, false)).as_ref().map(|k| k.as_slice()) !=
+            Some(key)
+        {
+            return Ok(None);
+        }
+        let lock = box_try!(Lock::parse(self.lock_iter.value()));
+        let mut lock_info = LockInfo::new();
+        lock_info.set_key(key.to_vec());
+        lock_info.set_primary_lock(lock.primary);
+        lock_info.set_lock_version(lock.ts);
+        lock_info.set_lock_ttl(lock.ttl);
+        self.lock_iter.next();
+        Ok(Some(lock_info))
+    }
+
+    fn next_values(&mut self, key: &[u8]) -> Result<Vec<ValueInfo>> {
+        let mut values = vec![];
+        while try!(current_key(&self.default_iter, true))
+            .as_ref()
+            .map(|k| k.as_slice()) == Some(key)
+        {
+            let (_, start_ts) =
+                box_try!(split_encoded_key_on_ts(keys::origin_key(self.default_iter.key())));
+            let mut value_info = ValueInfo::new();
+            value_info.set_ts(start_ts);
+            value_info.set_value(self.default_iter.value().to_vec());
+            value_info.set_is_short_value(false);
+            values.push(value_info);
+            self.default_iter.next();
+        }
+        Ok(values)
+    }
+
+    fn next_writes(&mut self, key: &[u8]) -> Result<Vec<WriteInfo>> {
+        let mut writes = vec![];
+        while try!(current_key(&self.write_iter, true))
+            .as_ref()
+            .map(|k| k.as_slice()) == Some(key)
+        {
+            let (_, commit_ts) =
+                box_try!(split_encoded_key_on_ts(keys::origin_key(self.write_iter.key())));
+            let write = box_try!(Write::parse(self.write_iter.value()));
+            let mut write_info = WriteInfo::new();
+            write_info.set_start_ts(write.start_ts);
+            write_info.set_field_type(match write.write_type {
+                WriteType::Put => Op::Put,
+                WriteType::Delete => Op::Del,
+                WriteType::Lock => Op::Lock,
+                WriteType::Rollback => Op::Rollback,
+            });
+            write_info.set_commit_ts(commit_ts);
+            writes.push(write_info);
+            self.write_iter.next();
+        }
+        Ok(writes)
+    }
+
+    fn next_item(&mut self) -> Result<Option<(Vec<u8>, MvccInfo)>> {
+        if self.limit != 0 && self.count >= self.limit {
+            return Ok(None);
+        }
+
+        let candidates = vec![
+            try!(current_key(&self.lock_iter, false)),
+            try!(current_key(&self.default_iter, true)),
+            try!(current_key(&self.write_iter, true)),
+        ];
+        let min_key = match candidates.into_iter().filter_map(|k| k).min() {
+            Some(k) => k,
+            None => return Ok(None),
+        };
+
+        let mut mvcc_info = MvccInfo::new();
+        if let Some(lock_info) = try!(self.next_lock(&min_key)) {
+            mvcc_info.set_lock(lock_info);
+        }
+        mvcc_info.set_values(RepeatedField::from_vec(try!(self.next_values(&min_key))));
+        mvcc_info.set_writes(RepeatedField::from_vec(try!(self.next_writes(&min_key))));
+        self.count += 1;
+        Ok(Some((min_key, mvcc_info)))
+    }
+}
+
+impl<'a> Iterator for MvccInfoIterator<'a> {
+    type Item = Result<(Vec<u8>, MvccInfo)>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, MvccInfo)>> {
+        match self.next_item() {
+            Ok(Some(item)) => Some(Ok(item)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Inconsistent mvcc records found by `Debugger::recover_mvcc` in a region.
+#[derive(PartialEq, Debug, Default)]
+pub struct MvccRecoverStats {
+    pub scanned_keys: u64,
+    pub orphan_locks: u64,
+    pub dangling_values: u64,
+    pub missing_values: u64,
+}
+
+struct MvccChecker<'a> {
+    db: &'a DB,
+    lock_iter: DBIterator<'a>,
+    default_iter: DBIterator<'a>,
+    write_iter: DBIterator<'a>,
+}
+
+impl<'a> MvccChecker<'a> {
+    fn new(db: &'a DB, start: &[u8], end: &[u8]) -> Result<MvccChecker<'a>> {
+        Ok(MvccChecker {
+            db: db,
+            lock_iter: try!(new_mvcc_iter(db, CF_LOCK, start, end)),
+            default_iter: try!(new_mvcc_iter(db, CF_DEFAULT, start, end)),
+            write_iter: try!(new_mvcc_iter(db, CF_WRITE, start, end)),
+        })
+    }
+
+    // Puts deletions of all inconsistent records into `wb`.
+    fn check(&mut self, wb: &WriteBatch) -> Result<MvccRecoverStats> {
+        let mut stats = MvccRecoverStats::default();
+        loop {
+            let candidates = vec![
+                try!(current_key(&self.lock_iter, false)),
+                try!(current_key(&self.default_iter, true)),
+                try!(current_key(&self.write_iter, true)),
+            ];
+            let key = match candidates.into_iter().filter_map(|k| k).min() {
+                Some(k) => k,
+                None => return Ok(stats),
+            };
+            stats.scanned_keys += 1;
+            try!(self.check_key(&key, wb, &mut stats));
+        }
+    }
+
+    fn check_key(
+        &mut self,
+        key: &[u8],
+        wb: &WriteBatch,
+        stats: &mut MvccRecoverStats,
+    ) -> Result<()> {
+        let mut lock = None;
+        if try!(current_key(&self.lock_iter, false)).as_ref().map(|k| k.as_slice()) == Some(key) {
+            let l = box_try!(Lock::parse(self.lock_iter.value()));
+            lock = Some((self.lock_iter.key().to_vec(), l));
+            self.lock_iter.next();
+        }
+
+        let mut values = vec![];
+        while try!(current_key(&self.default_iter, true))
+            .as_ref()
+            .map(|k| k.as_slice()) == Some(key)
+        {
+            let (_, start_ts) =
+                box_try!(split_encoded_key_on_ts(keys::origin_key(self.default_iter.key())));
+            values.push((self.default_iter.key().to_vec(), start_ts));
+            self.default_iter.next();
+        }
+
+        let mut writes = vec![];
+        while try!(current_key(&self.write_iter, true))
+            .as_ref()
+            .map(|k| k.as_slice()) == Some(key)
+        {
+            let write = box_try!(Write::parse(self.write_iter.value()));
+            writes.push((self.write_iter.key().to_vec(), write));
+            self.write_iter.next();
+        }
+
+        let value_ts: Vec<u64> = values.iter().map(|&(_, ts)| ts).collect();
+
+        // A lock is an orphan if its transaction has been resolved, or the value it
+        // refers to is missing, in which case the transaction can never be committed.
+        let mut lock_ts = None;
+        if let Some((lock_key, lock)) = lock {
+            let resolved = writes.iter().any(|&(_, ref w)| w.start_ts == lock.ts);
+            let missing = lock.lock_type == LockType::Put && lock.short_value.is_none() &&
+                !value_ts.contains(&lock.ts);
+            if resolved || missing {
+                warn!("orphan lock {:?} on key {}", lock, escape(key));
+                stats.orphan_locks += 1;
+                let lock_cf = box_try!(get_cf_handle(self.db, CF_LOCK));
+                box_try!(wb.delete_cf(lock_cf, &lock_key));
+            } else {
+                lock_ts = Some(lock.ts);
+            }
+        }
+
+        let write_cf = box_try!(get_cf_handle(self.db, CF_WRITE));
+        let mut referenced = vec![];
+        for (write_key, write) in writes {
+            if write.write_type != WriteType::Put || write.short_value.is_some() {
+                continue;
+            }
+            if value_ts.contains(&write.start_ts) {
+                referenced.push(write.start_ts);
+                continue;
+            }
+            warn!("value of {:?} on key {} is missing", write, escape(key));
+            stats.missing_values += 1;
+            box_try!(wb.delete_cf(write_cf, &write_key));
+        }
+
+        let default_cf = box_try!(get_cf_handle(self.db, CF_DEFAULT));
+        for (value_key, start_ts) in values {
+            if lock_ts == Some(start_ts) || referenced.contains(&start_ts) {
+                continue;
+            }
+            warn!("dangling value at {} on key {}", start_ts, escape(key));
+            stats.dangling_values += 1;
+            box_try!(wb.delete_cf(default_cf, &value_key));
+        }
+        Ok(())
+    }
+}
+
+pub fn validate_db_and_cf(db: DBType, cf: &str) -> Result<()> {
+    match (db, cf) {
+        (DBType::KV, CF_DEFAULT) |
+        (DBType::KV, CF_WRITE) |
+        (DBType::KV, CF_LOCK) |
+        (DBType::KV, CF_RAFT) |
+        (DBType::RAFT, CF_DEFAULT) => Ok(()),
+        _ => Err(Error::InvalidArgument(
+            format!("invalid cf {:?} for db {:?}", cf, db),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use kvproto::metapb::Peer;
+    use kvproto::raft_serverpb::PeerState;
+    use tempdir::TempDir;
+
+    use storage::{ALL_CFS, CF_DEFAULT};
+    use storage::types::Key;
+    use rocksdb::{ColumnFamilyOptions, DBOptions};
+    use util::properties::{MvccPropertiesCollectorFactory, SizePropertiesCollectorFactory};
+    use util::rocksdb::{self as rocksdb_util, get_cf_handle, CFOptions};
+    use super::*;
+
+    fn new_debugger(dir: &TempDir) -> Debugger {
+        let path = dir.path().to_str().unwrap();
+        let engine = Arc::new(rocksdb_util::new_engine(path, ALL_CFS).unwrap());
+        let engines = Engines::new(engine.clone(), engine);
+        Debugger::new(engines)
+    }
+
+    #[test]
+    fn test_validate_db_and_cf() {
+        let valid_cases = vec![
+            (DBType::KV, CF_DEFAULT),
+            (DBType::KV, CF_WRITE),
+            (DBType::KV, CF_LOCK),
+            (DBType::KV, CF_RAFT),
+            (DBType::RAFT, CF_DEFAULT),
+        ];
+        for (db, cf) in valid_cases {
+            validate_db_and_cf(db, cf).unwrap();
+        }
+
+        let invalid_cases = vec![
+            (DBType::RAFT, CF_WRITE),
+            (DBType::RAFT, CF_LOCK),
+            (DBType::RAFT, CF_RAFT),
+            (DBType::INVALID, CF_DEFAULT),
+            (DBType::INVALID, "BAD_CF"),
+        ];
+        for (db, cf) in invalid_cases {
+            validate_db_and_cf(db, cf).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_get_store_ident() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let debugger = new_debugger(&dir);
+        match debugger.get_store_id() {
+            Err(Error::NotFound(_)) => (),
+            res => panic!("expect Error::NotFound, got {:?}", res),
+        }
+
+        let mut ident = StoreIdent::new();
+        ident.set_store_id(3);
+        ident.set_cluster_id(100);
+        debugger
+            .engines
+            .kv_engine
+            .put_msg(keys::STORE_IDENT_KEY, &ident)
+            .unwrap();
+        assert_eq!(debugger.get_store_id().unwrap(), 3);
+        assert_eq!(debugger.get_cluster_id().unwrap(), 100);
+    }
+
+    #[test]
+    fn test_get() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let debugger = new_debugger(&dir);
+        let engine = &debugger.engines.kv_engine;
+        let (k, v) = (b"k", b"v");
+        engine.put(k, v).unwrap();
+        assert_eq!(&*engine.get(k).unwrap().unwrap(), v);
+
+        match debugger.get(DBType::KV, CF_DEFAULT, k) {
+            Ok(v1) => assert_eq!(v1, v),
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+
+        match debugger.get(DBType::KV, CF_DEFAULT, b"foo") {
+            Err(Error::NotFound(_)) => (),
+            res => panic!("expect Error::NotFound, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_raft_log() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let debugger = new_debugger(&dir);
+        let engine = &debugger.engines.raft_engine;
+        let (region_id, log_index) = (1, 1);
+        let key = keys::raft_log_key(region_id, log_index);
+        let mut entry = Entry::new();
+        entry.set_term(1);
+        entry.set_index(1);
+        entry.set_entry_type(::kvproto::eraftpb::EntryType::EntryNormal);
+        entry.set_data(vec![42]);
+        engine.put_msg(&key, &entry).unwrap();
+        assert_eq!(
+            engine.get_msg::<Entry>(&key).unwrap().unwrap(),
+            entry
+        );
+
+        assert_eq!(debugger.raft_log(region_id, log_index).unwrap(), entry);
+        match debugger.raft_log(region_id + 1, log_index + 1) {
+            Err(Error::NotFound(_)) => (),
+            res => panic!("expect Error::NotFound, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_region_info() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let debugger = new_debugger(&dir);
+        let raft_engine = &debugger.engines.raft_engine;
+        let kv_engine = &debugger.engines.kv_engine;
+        let raft_cf = get_cf_handle(kv_engine, CF_RAFT).unwrap();
+        let region_id = 1;
+
+        let raft_state_key = keys::raft_state_key(region_id);
+        let mut raft_state = RaftLocalState::new();
+        raft_state.set_last_index(42);
+        raft_engine.put_msg(&raft_state_key, &raft_state).unwrap();
+        assert_eq!(
+            raft_engine
+                .get_msg::<RaftLocalState>(&raft_state_key)
+                .unwrap()
+                .unwrap(),
+            raft_state
+        );
+
+        let apply_state_key = keys::apply_state_key(region_id);
+        let mut apply_state = RaftApplyState::new();
+        apply_state.set_applied_index(42);
+        kv_engine
+            .put_msg_cf(raft_cf, &apply_state_key, &apply_state)
+            .unwrap();
+        assert_eq!(
+            kv_engine
+                .get_msg_cf::<RaftApplyState>(CF_RAFT, &apply_state_key)
+                .unwrap()
+                .unwrap(),
+            apply_state
+        );
+
+        let region_state_key = keys::region_state_key(region_id);
+        let mut region_state = RegionLocalState::new();
+        region_state.set_state(::kvproto::raft_serverpb::PeerState::Tombstone);
+        let mut region = Region::new();
+        region.set_id(region_id);
+        region_state.set_region(region);
+        kv_engine
+            .put_msg_cf(raft_cf, &region_state_key, &region_state)
+            .unwrap();
+        assert_eq!(
+            kv_engine
+                .get_msg_cf::<RegionLocalState>(CF_RAFT, &region_state_key)
+                .unwrap()
+                .unwrap(),
+            region_state
+        );
+
+        assert_eq!(
+            debugger.region_info(region_id).unwrap(),
+            RegionInfo::new(Some(raft_state), Some(apply_state), Some(region_state))
+        );
+        match debugger.region_info(region_id + 1) {
+            Err(Error::NotFound(_)) => (),
+            res => panic!("expect Error::NotFound, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let debugger = new_debugger(&dir);
+        let compact = |db, cf| debugger.compact(db, cf, &[0], &[0xFF]);
+        compact(DBType::KV, CF_DEFAULT).unwrap();
+        compact(DBType::KV, CF_LOCK).unwrap();
+        compact(DBType::RAFT, CF_DEFAULT).unwrap();
+        compact(DBType::RAFT, CF_LOCK).unwrap_err();
+        debugger.compact(DBType::KV, CF_WRITE, &[], &[]).unwrap();
+    }
+
+    #[test]
+    fn test_raw_scan() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let debugger = new_debugger(&dir);
+        let engine = &debugger.engines.kv_engine;
+        let write_cf = get_cf_handle(engine, CF_WRITE).unwrap();
+        for k in &[b"k1", b"k2", b"k3", b"k4"] {
+            engine.put_cf(write_cf, *k, b"v").unwrap();
+        }
+
+        let scan = |start: &[u8], end: &[u8], limit| {
+            debugger
+                .raw_scan(DBType::KV, CF_WRITE, start, end, limit)
+                .unwrap()
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(scan(b"k2", b"", 0), vec![b"k2".to_vec(), b"k3".to_vec(), b"k4".to_vec()]);
+        assert_eq!(scan(b"", b"k3", 0), vec![b"k1".to_vec(), b"k2".to_vec()]);
+        assert_eq!(scan(b"", b"", 1), vec![b"k1".to_vec()]);
+        assert!(scan(b"k5", b"", 0).is_empty());
+
+        debugger
+            .raw_scan(DBType::KV, CF_WRITE, b"k3", b"k2", 0)
+            .unwrap_err();
+        debugger
+            .raw_scan(DBType::RAFT, CF_WRITE, b"", b"", 0)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_region_size() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let debugger = new_debugger(&dir);
+        let engine = &debugger.engines.kv_engine;
+
+        let mut region = Region::new();
+        region.set_id(1);
+        region.set_start_key(b"a".to_vec());
+        region.set_end_key(b"z".to_vec());
+        let mut region_state = RegionLocalState::new();
+        region_state.set_region(region);
+        let raft_cf = get_cf_handle(engine, CF_RAFT).unwrap();
+        engine
+            .put_msg_cf(raft_cf, &keys::region_state_key(1), &region_state)
+            .unwrap();
+
+        let lock_cf = get_cf_handle(engine, CF_LOCK).unwrap();
+        for &(k, v) in &[(b"k1", b"v1"), (b"k2", b"v2"), (b"zz", b"v3")] {
+            engine.put_cf(lock_cf, &keys::data_key(k), v).unwrap();
+        }
+
+        let sizes = debugger.region_size(1, vec![CF_LOCK, CF_WRITE]).unwrap();
+        assert_eq!(sizes.len(), 2);
+        // Only k1 and k2 are in the region, each is 3 bytes key plus 2 bytes value.
+        assert_eq!(sizes[0], (CF_LOCK, 10));
+        assert_eq!(sizes[1], (CF_WRITE, 0));
+
+        debugger.region_size(1, vec!["unknown"]).unwrap_err();
+        match debugger.region_size(2, vec![CF_LOCK]) {
+            Err(Error::NotFound(_)) => (),
+            res => panic!("expect Error::NotFound, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_bad_regions() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let debugger = new_debugger(&dir);
+        let kv_engine = &debugger.engines.kv_engine;
+        let raft_engine = &debugger.engines.raft_engine;
+        let raft_cf = get_cf_handle(kv_engine, CF_RAFT).unwrap();
+
+        let put_region = |id: u64, start: &[u8], end: &[u8], state: PeerState| {
+            let mut region = Region::new();
+            region.set_id(id);
+            region.set_start_key(start.to_vec());
+            region.set_end_key(end.to_vec());
+            let mut region_state = RegionLocalState::new();
+            region_state.set_state(state);
+            region_state.set_region(region);
+            let key = keys::region_state_key(id);
+            kv_engine.put_msg_cf(raft_cf, &key, &region_state).unwrap();
+        };
+        let put_raft_meta = |id: u64, last: u64, commit: u64, applied: u64| {
+            let mut raft_state = RaftLocalState::new();
+            raft_state.set_last_index(last);
+            raft_state.mut_hard_state().set_commit(commit);
+            raft_engine
+                .put_msg(&keys::raft_state_key(id), &raft_state)
+                .unwrap();
+            let mut apply_state = RaftApplyState::new();
+            apply_state.set_applied_index(applied);
+            kv_engine
+                .put_msg_cf(raft_cf, &keys::apply_state_key(id), &apply_state)
+                .unwrap();
+        };
+
+        // A healthy region.
+        put_region(1, b"", b"b", PeerState::Normal);
+        put_raft_meta(1, 10, 10, 10);
+        // Applied index is larger than commit index.
+        put_region(2, b"b", b"c", PeerState::Normal);
+        put_raft_meta(2, 10, 5, 8);
+        // Raft meta is missing.
+        put_region(3, b"c", b"d", PeerState::Normal);
+        // Overlaps with region 5.
+        put_region(4, b"d", b"f", PeerState::Normal);
+        put_raft_meta(4, 10, 10, 10);
+        put_region(5, b"e", b"", PeerState::Normal);
+        put_raft_meta(5, 10, 10, 10);
+        // Tombstone regions are ignored.
+        put_region(6, b"a", b"", PeerState::Tombstone);
+
+        let mut bad_regions: Vec<_> = debugger
+            .bad_regions()
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        bad_regions.sort();
+        assert_eq!(bad_regions, vec![2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_remove_failed_stores() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let debugger = new_debugger(&dir);
+        let kv_engine = &debugger.engines.kv_engine;
+        let raft_cf = get_cf_handle(kv_engine, CF_RAFT).unwrap();
+
+        for &(region_id, state) in &[(1, PeerState::Normal), (2, PeerState::Tombstone)] {
+            let mut region = Region::new();
+            region.set_id(region_id);
+            for store_id in 1..4 {
+                let mut peer = Peer::new();
+                peer.set_id(region_id * 10 + store_id);
+                peer.set_store_id(store_id);
+                region.mut_peers().push(peer);
+            }
+            let mut region_state = RegionLocalState::new();
+            region_state.set_state(state);
+            region_state.set_region(region);
+            let key = keys::region_state_key(region_id);
+            kv_engine.put_msg_cf(raft_cf, &key, &region_state).unwrap();
+        }
+
+        debugger.remove_failed_stores(&[2, 3]).unwrap();
+
+        let get_store_ids = |region_id| {
+            let key = keys::region_state_key(region_id);
+            let state: RegionLocalState = kv_engine.get_msg_cf(CF_RAFT, &key).unwrap().unwrap();
+            state
+                .get_region()
+                .get_peers()
+                .iter()
+                .map(|p| p.get_store_id())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(get_store_ids(1), vec![1]);
+        // Tombstone regions are untouched.
+        assert_eq!(get_store_ids(2), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_tombstone_regions() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let debugger = new_debugger(&dir);
+        let kv_engine = &debugger.engines.kv_engine;
+        let raft_cf = get_cf_handle(kv_engine, CF_RAFT).unwrap();
+
+        let mut ident = StoreIdent::new();
+        ident.set_store_id(1);
+        kv_engine.put_msg(keys::STORE_IDENT_KEY, &ident).unwrap();
+
+        let new_region = |region_id: u64, store_ids: &[u64], version: u64| {
+            let mut region = Region::new();
+            region.set_id(region_id);
+            region.mut_region_epoch().set_version(version);
+            for &store_id in store_ids {
+                let mut peer = Peer::new();
+                peer.set_id(region_id * 10 + store_id);
+                peer.set_store_id(store_id);
+                region.mut_peers().push(peer);
+            }
+            region
+        };
+        for region_id in 1..4 {
+            let mut region_state = RegionLocalState::new();
+            region_state.set_region(new_region(region_id, &[1, 2, 3], 2));
+            let key = keys::region_state_key(region_id);
+            kv_engine.put_msg_cf(raft_cf, &key, &region_state).unwrap();
+        }
+        let get_state = |region_id| {
+            let key = keys::region_state_key(region_id);
+            kv_engine
+                .get_msg_cf::<RegionLocalState>(CF_RAFT, &key)
+                .unwrap()
+                .unwrap()
+                .get_state()
+        };
+
+        // The peer is still valid in PD.
+        let errors = debugger
+            .set_region_tombstone(vec![new_region(1, &[1, 2, 3], 3)])
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        // The local region is newer than PD's.
+        let errors = debugger
+            .set_region_tombstone(vec![new_region(2, &[2, 3], 1)])
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        // Nothing is written if any region fails.
+        let errors = debugger
+            .set_region_tombstone(vec![new_region(3, &[2, 3], 3), new_region(4, &[2, 3], 3)])
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(get_state(3), PeerState::Normal);
+
+        let errors = debugger
+            .set_region_tombstone(vec![new_region(3, &[2, 3], 3)])
+            .unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(get_state(1), PeerState::Normal);
+        assert_eq!(get_state(3), PeerState::Tombstone);
+    }
+
+    #[test]
+    fn test_get_region_properties() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let cfs_opts = ALL_CFS
+            .iter()
+            .map(|cf| {
+                let mut cf_opts = ColumnFamilyOptions::new();
+                let f = Box::new(MvccPropertiesCollectorFactory::default());
+                cf_opts.add_table_properties_collector_factory("tikv.mvcc-collector", f);
+                let f = Box::new(SizePropertiesCollectorFactory::default());
+                cf_opts.add_table_properties_collector_factory("tikv.size-collector", f);
+                CFOptions::new(cf, cf_opts)
+            })
+            .collect();
+        let engine =
+            Arc::new(rocksdb_util::new_engine_opt(path, DBOptions::new(), cfs_opts).unwrap());
+        let debugger = Debugger::new(Engines::new(engine.clone(), engine.clone()));
+
+        let mut region = Region::new();
+        region.set_id(1);
+        let mut region_state = RegionLocalState::new();
+        region_state.set_region(region);
+        let raft_cf = get_cf_handle(&engine, CF_RAFT).unwrap();
+        engine
+            .put_msg_cf(raft_cf, &keys::region_state_key(1), &region_state)
+            .unwrap();
+
+        let write_cf = get_cf_handle(&engine, CF_WRITE).unwrap();
+        let default_cf = get_cf_handle(&engine, CF_DEFAULT).unwrap();
+        let cases = [
+            (b"k1", 2, WriteType::Put),
+            (b"k1", 4, WriteType::Delete),
+            (b"k2", 6, WriteType::Put),
+        ];
+        for &(k, ts, tp) in &cases {
+            let key = keys::data_key(Key::from_raw(k).append_ts(ts).encoded());
+            engine.put_cf(default_cf, &key, b"v").unwrap();
+            let value = Write::new(tp, ts - 1, None).to_bytes();
+            engine.put_cf(write_cf, &key, &value).unwrap();
+        }
+        engine.flush_cf(write_cf, true).unwrap();
+        engine.flush_cf(default_cf, true).unwrap();
+
+        let props = debugger.get_region_properties(1).unwrap();
+        let get_prop = |name: &str| {
+            props
+                .iter()
+                .find(|&&(ref n, _)| n == name)
+                .map(|&(_, ref v)| v.clone())
+                .unwrap()
+        };
+        assert_eq!(get_prop("write.num_entries"), "3");
+        assert_eq!(get_prop("default.num_entries"), "3");
+        assert_eq!(get_prop("mvcc.min_ts"), "2");
+        assert_eq!(get_prop("mvcc.max_ts"), "6");
+        assert_eq!(get_prop("mvcc.num_rows"), "2");
+        assert_eq!(get_prop("mvcc.num_puts"), "2");
+        assert_eq!(get_prop("mvcc.num_deletes"), "1");
+
+        match debugger.get_region_properties(2) {
+            Err(Error::NotFound(_)) => {}
+            res => panic!("expect NotFound, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_modify_tikv_config() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let debugger = new_debugger(&dir);
+
+        debugger
+            .modify_tikv_config(MODULE::KVDB, "default.disable_auto_compactions", "true")
+            .unwrap();
+        debugger
+            .modify_tikv_config(MODULE::RAFTDB, "default.disable_auto_compactions", "false")
+            .unwrap();
+        debugger
+            .modify_tikv_config(MODULE::KVDB, "max_background_compactions", "8")
+            .unwrap();
+
+        // Invalid cf.
+        debugger
+            .modify_tikv_config(MODULE::RAFTDB, "write.disable_auto_compactions", "true")
+            .unwrap_err();
+        // Invalid name.
+        debugger
+            .modify_tikv_config(MODULE::KVDB, "a.b.c", "true")
+            .unwrap_err();
+        // Unsupported module.
+        match debugger.modify_tikv_config(MODULE::STORAGE, "scheduler_concurrency", "1") {
+            Err(Error::InvalidArgument(_)) => {}
+            res => panic!("expect InvalidArgument, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_recover_mvcc() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let debugger = new_debugger(&dir);
+        let engine = &debugger.engines.kv_engine;
+
+        let mut region_state = RegionLocalState::new();
+        region_state.mut_region().set_id(1);
+        let raft_cf = get_cf_handle(engine, CF_RAFT).unwrap();
+        engine
+            .put_msg_cf(raft_cf, &keys::region_state_key(1), &region_state)
+            .unwrap();
+
+        let default_cf = get_cf_handle(engine, CF_DEFAULT).unwrap();
+        for &(k, ts) in &[(b"k2", 10), (b"k4", 20), (b"k5", 25)] {
+            let key = keys::data_key(Key::from_raw(k).append_ts(ts).encoded());
+            engine.put_cf(default_cf, &key, b"v").unwrap();
+        }
+        let lock_cf = get_cf_handle(engine, CF_LOCK).unwrap();
+        for &(k, ts) in &[(b"k1", 5), (b"k2", 10), (b"k5", 25)] {
+            let key = keys::data_key(Key::from_raw(k).encoded());
+            let value = Lock::new(LockType::Put, k.to_vec(), ts, 0, None).to_bytes();
+            engine.put_cf(lock_cf, &key, &value).unwrap();
+        }
+        let write_cf = get_cf_handle(engine, CF_WRITE).unwrap();
+        for &(k, start_ts, commit_ts) in &[(b"k3", 15, 18), (b"k5", 25, 28)] {
+            let key = keys::data_key(Key::from_raw(k).append_ts(commit_ts).encoded());
+            let value = Write::new(WriteType::Put, start_ts, None).to_bytes();
+            engine.put_cf(write_cf, &key, &value).unwrap();
+        }
+
+        // k1: the value of the lock is missing.
+        // k3: the value of the put record is missing.
+        // k4: the value is referenced by nothing.
+        // k5: the lock is left after committing.
+        let expected = MvccRecoverStats {
+            scanned_keys: 5,
+            orphan_locks: 2,
+            dangling_values: 1,
+            missing_values: 1,
+        };
+        let res = debugger.recover_mvcc(&[1], true).unwrap();
+        assert_eq!(res, vec![(1, expected)]);
+        // Nothing is removed in dry run mode.
+        let res = debugger.recover_mvcc(&[], true).unwrap();
+        assert_eq!(res[0].1.scanned_keys, 5);
+
+        debugger.recover_mvcc(&[], false).unwrap();
+        let res = debugger.recover_mvcc(&[], true).unwrap();
+        let expected = MvccRecoverStats {
+            scanned_keys: 2,
+            ..Default::default()
+        };
+        assert_eq!(res, vec![(1, expected)]);
+
+        match debugger.recover_mvcc(&[2], true) {
+            Err(Error::NotFound(_)) => (),
+            res => panic!("expect Error::NotFound, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_scan_mvcc() {
+        let dir = TempDir::new("test_debug").unwrap();
+        let debugger = new_debugger(&dir);
+        let engine = &debugger.engines.kv_engine;
+
+        let cf_default_data = vec![(b"k1", 5), (b"k2", 10), (b"k3", 15)];
+        let default_cf = get_cf_handle(engine, CF_DEFAULT).unwrap();
+        for &(k, ts) in &cf_default_data {
+            let key = keys::data_key(Key::from_raw(k).append_ts(ts).encoded());
+            engine.put_cf(default_cf, &key, b"v").unwrap();
+        }
+
+        let cf_lock_data = vec![(b"k1", LockType::Put, 5), (b"k4", LockType::Lock, 20)];
+        let lock_cf = get_cf_handle(engine, CF_LOCK).unwrap();
+        for &(k, tp, ts) in &cf_lock_data {
+            let key = keys::data_key(Key::from_raw(k).encoded());
+            let value = Lock::new(tp, k.to_vec(), ts, 0, None).to_bytes();
+            engine.put_cf(lock_cf, &key, &value).unwrap();
+        }
+
+        let cf_write_data = vec![(b"k2", WriteType::Put, 10, 12), (b"k3", WriteType::Put, 15, 18)];
+        let write_cf = get_cf_handle(engine, CF_WRITE).unwrap();
+        for &(k, tp, start_ts, commit_ts) in &cf_write_data {
+            let key = keys::data_key(Key::from_raw(k).append_ts(commit_ts).encoded());
+            let value = Write::new(tp, start_ts, None).to_bytes();
+            engine.put_cf(write_cf, &key, &value).unwrap();
+        }
+
+        let infos: Vec<_> = debugger
+            .scan_mvcc(keys::DATA_MIN_KEY, &[], 0)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(infos.len(), 4);
+        let expected_keys: Vec<_> = vec![b"k1", b"k2", b"k3", b"k4"]
+            .into_iter()
+            .map(|k| Key::from_raw(k).encoded().to_owned())
+            .collect();
+        let keys: Vec<_> = infos.iter().map(|&(ref k, _)| k.clone()).collect();
+        assert_eq!(keys, expected_keys);
+        assert!(infos[0].1.has_lock());
+        assert_eq!(infos[0].1.get_values().len(), 1);
+        assert_eq!(infos[1].1.get_writes().len(), 1);
+        assert_eq!(infos[1].1.get_writes()[0].get_commit_ts(), 12);
+        assert_eq!(infos[3].1.get_lock().get_lock_version(), 20);
+
+        // Test limit.
+        let count = debugger
+            .scan_mvcc(keys::DATA_MIN_KEY, &[], 2)
+            .unwrap()
+            .count();
+        assert_eq!(count, 2);
+
+        // Test invalid range.
+        assert!(debugger.scan_mvcc(b"k", &[], 0).is_err());
+    }
+}
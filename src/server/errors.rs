@@ -103,6 +103,10 @@ quick_error!{
         Sink {
             description("failed to poll from mpsc receiver")
         }
+        QuotaExceeded(backoff_ms: u64) {
+            description("foreground quota is exceeded")
+            display("foreground quota is exceeded, backoff {}ms", backoff_ms)
+        }
         Canceled(err: Canceled) {
             from()
             cause(err)
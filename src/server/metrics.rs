@@ -11,7 +11,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use prometheus::{Counter, CounterVec, Histogram, HistogramVec};
+use prometheus::{exponential_buckets, Counter, CounterVec, Histogram, HistogramVec};
 
 lazy_static! {
     pub static ref SEND_SNAP_HISTOGRAM: Histogram =
@@ -41,6 +41,44 @@ lazy_static! {
             &["type"]
         ).unwrap();
 
+    pub static ref GRPC_REQ_DURATION_HISTOGRAM_VEC: HistogramVec =
+        register_histogram_vec!(
+            "tikv_grpc_request_duration_seconds",
+            "Bucketed histogram of grpc request duration by result",
+            &["type", "result"]
+        ).unwrap();
+
+    pub static ref GRPC_REQ_SIZE_HISTOGRAM_VEC: HistogramVec =
+        register_histogram_vec!(
+            "tikv_grpc_request_size_bytes",
+            "Bucketed histogram of grpc request size",
+            &["type"],
+            exponential_buckets(64.0, 4.0, 12).unwrap()
+        ).unwrap();
+
+    pub static ref GRPC_RESP_SIZE_HISTOGRAM_VEC: HistogramVec =
+        register_histogram_vec!(
+            "tikv_grpc_response_size_bytes",
+            "Bucketed histogram of grpc response size by result",
+            &["type", "result"],
+            exponential_buckets(64.0, 4.0, 12).unwrap()
+        ).unwrap();
+
+    pub static ref GRPC_QUOTA_DELAY_HISTOGRAM_VEC: HistogramVec =
+        register_histogram_vec!(
+            "tikv_grpc_quota_delay_seconds",
+            "Bucketed histogram of grpc response delay by the foreground quotas",
+            &["type"],
+            exponential_buckets(0.001, 2.0, 12).unwrap()
+        ).unwrap();
+
+    pub static ref GRPC_QUOTA_EXCEEDED_COUNTER_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_grpc_quota_exceeded_total",
+            "Total number of grpc requests rejected by the foreground quotas",
+            &["type"]
+        ).unwrap();
+
     pub static ref RAFT_MESSAGE_RECV_COUNTER: Counter =
         register_counter!(
             "tikv_server_raft_message_recv_total",
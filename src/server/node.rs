@@ -25,12 +25,15 @@ use kvproto::raft_serverpb::StoreIdent;
 use kvproto::metapb;
 use protobuf::RepeatedField;
 use util::transport::SendCh;
+use util::logger;
+use raftstore::coprocessor::CoprocessorHost;
 use raftstore::store::{self, keys, Config as StoreConfig, Engines, Msg, Peekable, SnapManager,
                        SnapshotStatusMsg, Store, StoreChannel, Transport};
 use super::Result;
 use server::Config as ServerConfig;
 use storage::{Config as StorageConfig, RaftKv, Storage};
 use super::transport::RaftStoreRouter;
+use import::SSTImporter;
 
 const MAX_CHECK_CLUSTER_BOOTSTRAPPED_RETRY_COUNT: u64 = 60;
 const CHECK_CLUSTER_BOOTSTRAPPED_RETRY_SECONDS: u64 = 3;
@@ -123,6 +126,8 @@ where
         engines: Engines,
         trans: T,
         snap_mgr: SnapManager,
+        importer: Arc<SSTImporter>,
+        coprocessor_host: CoprocessorHost,
         snap_status_receiver: Receiver<SnapshotStatusMsg>,
     ) -> Result<()>
     where
@@ -160,6 +165,8 @@ where
             engines,
             trans,
             snap_mgr,
+            importer,
+            coprocessor_host,
             snap_status_receiver
         ));
         Ok(())
@@ -193,6 +200,7 @@ where
                 ident.get_cluster_id(),
                 self.cluster_id
             );
+            logger::flush_log();
             process::exit(1);
         }
 
@@ -323,6 +331,8 @@ where
         engines: Engines,
         trans: T,
         snap_mgr: SnapManager,
+        importer: Arc<SSTImporter>,
+        coprocessor_host: CoprocessorHost,
         snapshot_status_receiver: Receiver<SnapshotStatusMsg>,
     ) -> Result<()>
     where
@@ -346,7 +356,18 @@ where
                 sender: sender,
                 snapshot_status_receiver: snapshot_status_receiver,
             };
-            let mut store = match Store::new(ch, store, cfg, engines, trans, pd_client, snap_mgr) {
+            let res = Store::new(
+                ch,
+                store,
+                cfg,
+                engines,
+                trans,
+                pd_client,
+                snap_mgr,
+                importer,
+                coprocessor_host,
+            );
+            let mut store = match res {
                 Err(e) => panic!("construct store {} err {:?}", store_id, e),
                 Ok(s) => s,
             };
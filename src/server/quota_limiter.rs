@@ -0,0 +1,227 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use serde::de::value::{Error as ValueError, StrDeserializer};
+use tokio_timer::{self, Sleep, Timer};
+
+use util::config::{ReadableDuration, ReadableSize};
+use util::dynamic_config::ConfigHandler;
+use util::time::duration_to_ms;
+use super::Config;
+
+const MICROS_PER_SEC: u64 = 1_000_000;
+// The delays are rounded up to the ticks of the timer.
+const TIMER_TICK_MS: u64 = 10;
+
+// `RateLimiter` never blocks the consumers. Instead, it tells how long it
+// takes to pay off what's consumed so far, which is the delay the consumers
+// should take.
+struct RateLimiter {
+    // Units per second, 0 means no limit.
+    limit: AtomicUsize,
+    // The time when the units consumed so far are paid off.
+    next_free: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(limit: u64) -> RateLimiter {
+        RateLimiter {
+            limit: AtomicUsize::new(limit as usize),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn limit(&self) -> u64 {
+        self.limit.load(Ordering::Relaxed) as u64
+    }
+
+    fn set_limit(&self, limit: u64) {
+        self.limit.store(limit as usize, Ordering::Relaxed);
+    }
+
+    fn debt(&self, now: Instant) -> Duration {
+        let next_free = self.next_free.lock().unwrap();
+        if *next_free > now {
+            *next_free - now
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    /// Consumes `amount` units, returns the debt including them.
+    fn consume(&self, amount: u64, now: Instant) -> Duration {
+        let limit = self.limit();
+        if limit == 0 {
+            return Duration::from_secs(0);
+        }
+        let secs = amount as f64 / limit as f64;
+        let cost = Duration::new(secs as u64, (secs.fract() * 1e9) as u32);
+        let mut next_free = self.next_free.lock().unwrap();
+        if *next_free < now {
+            *next_free = now;
+        }
+        *next_free += cost;
+        *next_free - now
+    }
+}
+
+/// `QuotaLimiter` limits the cpu time and the write bandwidth taken by the
+/// foreground requests. The requests are charged after they are handled, and
+/// their responses are delayed until what's consumed is paid off, so a client
+/// sending requests too fast is slowed down. When the debt exceeds the max
+/// delay, new requests are rejected as busy.
+pub struct QuotaLimiter {
+    // Cpu microseconds per second.
+    cpu_time_limiter: RateLimiter,
+    // Bytes per second.
+    write_bandwidth_limiter: RateLimiter,
+    max_delay_ms: AtomicUsize,
+    timer: Timer,
+}
+
+impl QuotaLimiter {
+    pub fn new(cfg: &Config) -> QuotaLimiter {
+        QuotaLimiter {
+            cpu_time_limiter: RateLimiter::new(cfg.foreground_cpu_time as u64 * 1000),
+            write_bandwidth_limiter: RateLimiter::new(cfg.foreground_write_bandwidth.0),
+            max_delay_ms: AtomicUsize::new(cfg.foreground_quota_max_delay.as_millis() as usize),
+            timer: tokio_timer::wheel()
+                .tick_duration(Duration::from_millis(TIMER_TICK_MS))
+                .build(),
+        }
+    }
+
+    fn max_delay(&self) -> Duration {
+        Duration::from_millis(self.max_delay_ms.load(Ordering::Relaxed) as u64)
+    }
+
+    /// Checks whether a new request can be handled, returns the backoff
+    /// suggested to the client if not.
+    pub fn check(&self) -> Option<u64> {
+        let now = Instant::now();
+        let debt = ::std::cmp::max(
+            self.cpu_time_limiter.debt(now),
+            self.write_bandwidth_limiter.debt(now),
+        );
+        if debt > self.max_delay() {
+            Some(duration_to_ms(debt))
+        } else {
+            None
+        }
+    }
+
+    /// Charges a request with the resources it takes, returns how long its
+    /// response should be delayed.
+    pub fn consume(&self, cpu_time: Duration, write_bytes: u64) -> Duration {
+        let now = Instant::now();
+        let cpu_micros =
+            cpu_time.as_secs() * MICROS_PER_SEC + u64::from(cpu_time.subsec_nanos()) / 1000;
+        let delay = ::std::cmp::max(
+            self.cpu_time_limiter.consume(cpu_micros, now),
+            self.write_bandwidth_limiter.consume(write_bytes, now),
+        );
+        ::std::cmp::min(delay, self.max_delay())
+    }
+
+    pub fn sleep(&self, delay: Duration) -> Sleep {
+        self.timer.sleep(delay)
+    }
+
+    /// Changes a quota by its name in the config.
+    pub fn update(&self, name: &str, value: &str) -> ::std::result::Result<(), String> {
+        match name {
+            "foreground-cpu-time" => {
+                let millicores: u64 = try!(value.parse().map_err(|e| format!("{:?}", e)));
+                self.cpu_time_limiter.set_limit(millicores * 1000);
+            }
+            "foreground-write-bandwidth" => {
+                let size = try!(ReadableSize::from_str(value));
+                self.write_bandwidth_limiter.set_limit(size.0);
+            }
+            "foreground-quota-max-delay" => {
+                let de: StrDeserializer<ValueError> = value.into_deserializer();
+                let d = try!(ReadableDuration::deserialize(de).map_err(|e| format!("{}", e)));
+                self.max_delay_ms.store(d.as_millis() as usize, Ordering::Relaxed);
+            }
+            _ => return Err(format!("unknown quota config {:?}", name)),
+        }
+        info!("quota {} is changed to {}", name, value);
+        Ok(())
+    }
+}
+
+impl ConfigHandler for QuotaLimiter {
+    fn update(&self, name: &str, value: &str) -> ::std::result::Result<(), String> {
+        QuotaLimiter::update(self, name, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let now = Instant::now();
+        let limiter = RateLimiter::new(0);
+        assert_eq!(limiter.consume(1000, now), Duration::from_secs(0));
+
+        limiter.set_limit(1000);
+        assert_eq!(limiter.consume(500, now), Duration::from_millis(500));
+        assert_eq!(limiter.consume(1000, now), Duration::from_millis(1500));
+        assert_eq!(
+            limiter.debt(now + Duration::from_secs(1)),
+            Duration::from_millis(500)
+        );
+        assert_eq!(limiter.debt(now + Duration::from_secs(2)), Duration::from_secs(0));
+        // The time before is not accumulated as credit.
+        let later = now + Duration::from_secs(10);
+        assert_eq!(limiter.consume(100, later), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_quota_limiter() {
+        let mut cfg = Config::default();
+        let limiter = QuotaLimiter::new(&cfg);
+        assert_eq!(limiter.consume(Duration::from_secs(10), 1 << 30), Duration::from_secs(0));
+        assert_eq!(limiter.check(), None);
+
+        // 1 core and 1MB/s.
+        cfg.foreground_cpu_time = 1000;
+        cfg.foreground_write_bandwidth = ReadableSize::mb(1);
+        cfg.foreground_quota_max_delay = ReadableDuration::secs(1);
+        let limiter = QuotaLimiter::new(&cfg);
+        let delay = limiter.consume(Duration::from_millis(200), 1024 * 1024 / 2);
+        assert!(delay > Duration::from_millis(400) && delay <= Duration::from_millis(500));
+        assert_eq!(limiter.check(), None);
+        // The delay is capped, and new requests are rejected then.
+        assert_eq!(limiter.consume(Duration::from_secs(2), 0), Duration::from_secs(1));
+        assert!(limiter.check().unwrap() > 1000);
+
+        limiter.update("foreground-cpu-time", "2000").unwrap();
+        assert_eq!(limiter.cpu_time_limiter.limit(), 2_000_000);
+        limiter.update("foreground-write-bandwidth", "10MB").unwrap();
+        assert_eq!(limiter.write_bandwidth_limiter.limit(), 10 * 1024 * 1024);
+        limiter.update("foreground-quota-max-delay", "3s").unwrap();
+        assert_eq!(limiter.max_delay(), Duration::from_secs(3));
+        assert!(limiter.update("foreground-cpu-time", "x").is_err());
+        assert!(limiter.update("unknown", "1").is_err());
+    }
+}
@@ -195,6 +195,9 @@ mod tests {
             store.set_address(format!("{}:{}", sock.ip(), sock.port()));
             Ok(store)
         }
+        fn get_all_stores(&self) -> Result<Vec<metapb::Store>> {
+            unimplemented!();
+        }
         fn get_cluster_config(&self) -> Result<metapb::Cluster> {
             unimplemented!();
         }
@@ -229,6 +232,21 @@ mod tests {
         fn report_split(&self, _: metapb::Region, _: metapb::Region) -> PdFuture<()> {
             unimplemented!();
         }
+        fn scatter_region(&self, _: metapb::Region) -> PdFuture<()> {
+            unimplemented!();
+        }
+        fn get_tso(&self) -> PdFuture<u64> {
+            unimplemented!();
+        }
+        fn batch_get_tso(&self, _: u32) -> PdFuture<u64> {
+            unimplemented!();
+        }
+        fn get_gc_safe_point(&self) -> PdFuture<u64> {
+            unimplemented!();
+        }
+        fn get_region_leader(&self, _: &[u8]) -> Result<Option<metapb::Peer>> {
+            unimplemented!();
+        }
     }
 
     fn new_store(addr: &str, state: metapb::StoreState) -> metapb::Store {
@@ -18,13 +18,28 @@ use std::str::FromStr;
 
 use grpc::{ChannelBuilder, EnvBuilder, Environment, Server as GrpcServer, ServerBuilder};
 use kvproto::tikvpb_grpc::*;
-use util::worker::Worker;
+use util::worker::{FutureScheduler, Worker};
 use storage::Storage;
-use raftstore::store::{SnapManager, SnapshotStatusMsg};
+use kvproto::debugpb_grpc::create_debug;
+use kvproto::backup_grpc::create_backup;
+use kvproto::import_sstpb_grpc::create_import_sst;
+use kvproto::cdcpb_grpc::create_change_data;
+use kvproto::deadlock_grpc::create_deadlock;
+use kvproto::debugpb::MODULE;
+use raftstore::store::{Engines, SnapManager, SnapshotStatusMsg};
+
+use util::dynamic_config::ConfigManager;
 
 use super::{Config, Result};
 use coprocessor::{EndPointHost, EndPointTask};
-use super::service::KvService as Service;
+use backup::{Config as BackupConfig, Endpoint as BackupEndpoint, Limiter as BackupLimiter,
+             MasterKey as BackupMasterKey, Service as BackupService};
+use import::{SSTImporter, Service as ImportSSTService};
+use cdc::{Service as CdcService, Task as CdcTask};
+use deadlock::{DetectorScheduler, Service as DeadlockService};
+use super::service::*;
+use super::debug::DBConfigHandler;
+use super::quota_limiter::QuotaLimiter;
 use super::transport::{RaftStoreRouter, ServerTransport};
 use super::resolve::StoreAddrResolver;
 use super::snap::{Runner as SnapHandler, Task as SnapTask};
@@ -45,6 +60,9 @@ pub struct Server<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static>
     storage: Storage,
     // For handling coprocessor requests.
     end_point_worker: Worker<EndPointTask>,
+    // Taken when the server is started.
+    end_point: Option<EndPointHost>,
+    config_manager: Arc<ConfigManager>,
     // For sending/receiving snapshots.
     snap_mgr: SnapManager,
     snap_worker: Worker<SnapTask>,
@@ -59,6 +77,11 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
         snapshot_status_sender: Sender<SnapshotStatusMsg>,
         resolver: S,
         snap_mgr: SnapManager,
+        importer: Arc<SSTImporter>,
+        backup_cfg: &BackupConfig,
+        cdc_scheduler: FutureScheduler<CdcTask>,
+        deadlock_scheduler: DetectorScheduler,
+        debug_engines: Option<Engines>,
     ) -> Result<Server<T, S>> {
         let env = Arc::new(
             EnvBuilder::new()
@@ -68,14 +91,32 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
         );
         let raft_client = Arc::new(RwLock::new(RaftClient::new(env.clone(), cfg.clone())));
         let end_point_worker = Worker::new("end-point-worker");
+        let end_point = EndPointHost::new(
+            storage.get_engine(),
+            storage.get_max_ts(),
+            end_point_worker.scheduler(),
+            cfg,
+        );
         let snap_worker = Worker::new("snap-handler");
 
-        let h = Service::new(
+        let config_manager = Arc::new(ConfigManager::new());
+        config_manager.register("storage", box storage.config_handler());
+        config_manager.register("coprocessor", box end_point.config_handler());
+        let quota_limiter = Arc::new(QuotaLimiter::new(cfg));
+        config_manager.register("quota", box quota_limiter.clone());
+
+        let kv_service = KvService::new(
             storage.clone(),
             end_point_worker.scheduler(),
             raft_router.clone(),
             snap_worker.scheduler(),
+            quota_limiter,
+            debug_engines.as_ref().map(|e| e.kv_engine.clone()),
         );
+        let import_service =
+            ImportSSTService::new(raft_router.clone(), storage.get_engine(), importer);
+        let cdc_service = CdcService::new(cdc_scheduler);
+        let deadlock_service = DeadlockService::new(deadlock_scheduler);
         let addr = try!(SocketAddr::from_str(&cfg.addr));
         let ip = format!("{}", addr.ip());
         let channel_args = ChannelBuilder::new(env.clone())
@@ -84,13 +125,38 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
             .max_receive_message_len(MAX_GRPC_RECV_MSG_LEN)
             .max_send_message_len(region_split_size as usize * 4)
             .build_args();
-        let grpc_server = try!(
-            ServerBuilder::new(env.clone())
-                .register_service(create_tikv(h))
+        let grpc_server = {
+            let mut sb = ServerBuilder::new(env.clone())
+                .register_service(create_tikv(kv_service))
+                .register_service(create_import_sst(import_service))
+                .register_service(create_change_data(cdc_service))
+                .register_service(create_deadlock(deadlock_service))
                 .bind(ip, addr.port())
-                .channel_args(channel_args)
-                .build()
-        );
+                .channel_args(channel_args);
+            if let Some(engines) = debug_engines {
+                let backup_limiter = Arc::new(BackupLimiter::new(backup_cfg));
+                config_manager.register("backup", box backup_limiter.clone());
+                config_manager.register(
+                    "kvdb",
+                    box DBConfigHandler::new(engines.clone(), MODULE::KVDB),
+                );
+                config_manager.register(
+                    "raftdb",
+                    box DBConfigHandler::new(engines.clone(), MODULE::RAFTDB),
+                );
+                let backup_endpoint = BackupEndpoint::new(
+                    engines.kv_engine.clone(),
+                    storage.get_engine(),
+                    backup_limiter.clone(),
+                    box_try!(BackupMasterKey::from_config(backup_cfg)),
+                );
+                sb = sb.register_service(create_backup(BackupService::new(backup_endpoint)));
+                let debug_service =
+                    DebugService::new(engines, raft_router.clone(), config_manager.clone());
+                sb = sb.register_service(create_debug(debug_service));
+            }
+            try!(sb.build())
+        };
 
         let addr = {
             let (ref host, port) = grpc_server.bind_addrs()[0];
@@ -113,6 +179,8 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
             raft_router: raft_router,
             storage: storage,
             end_point_worker: end_point_worker,
+            end_point: Some(end_point),
+            config_manager: config_manager,
             snap_mgr: snap_mgr,
             snap_worker: snap_worker,
         };
@@ -124,12 +192,13 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
         self.trans.clone()
     }
 
+    /// Gets the manager of the configs which can be changed online.
+    pub fn config_manager(&self) -> Arc<ConfigManager> {
+        self.config_manager.clone()
+    }
+
     pub fn start(&mut self, cfg: &Config) -> Result<()> {
-        let end_point = EndPointHost::new(
-            self.storage.get_engine(),
-            self.end_point_worker.scheduler(),
-            cfg,
-        );
+        let end_point = self.end_point.take().unwrap();
         box_try!(
             self.end_point_worker
                 .start_batch(end_point, DEFAULT_COPROCESSOR_BATCH)
@@ -138,6 +207,7 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
             self.env.clone(),
             self.snap_mgr.clone(),
             self.raft_router.clone(),
+            cfg,
         );
         box_try!(self.snap_worker.start(snap_runner));
         self.grpc_server.start();
@@ -171,6 +241,8 @@ mod tests {
     use std::net::SocketAddr;
     use std::sync::atomic::{AtomicUsize, Ordering};
 
+    use tempdir::TempDir;
+
     use super::*;
     use super::super::{Config, Result};
     use super::super::transport::RaftStoreRouter;
@@ -180,6 +252,7 @@ mod tests {
     use raftstore::Result as RaftStoreResult;
     use raftstore::store::Msg as StoreMsg;
     use raftstore::store::transport::Transport;
+    use util::worker::FutureWorker;
 
     #[derive(Clone)]
     struct MockResolver {
@@ -240,6 +313,11 @@ mod tests {
         let report_unreachable_count = router.report_unreachable_count.clone();
         let (snapshot_status_sender, _) = mpsc::channel();
 
+        let import_dir = TempDir::new("test_peer_resolve_import").unwrap();
+        let importer = Arc::new(SSTImporter::new(import_dir.path()).unwrap());
+        let cdc_worker = FutureWorker::new("test-cdc");
+        let deadlock_worker = FutureWorker::new("test-deadlock-detector");
+
         let addr = Arc::new(Mutex::new(None));
         let mut server = Server::new(
             &cfg,
@@ -249,6 +327,11 @@ mod tests {
             snapshot_status_sender,
             MockResolver { addr: addr.clone() },
             SnapManager::new("", None),
+            importer,
+            &BackupConfig::default(),
+            cdc_worker.scheduler(),
+            DetectorScheduler::new(deadlock_worker.scheduler()),
+            None,
         ).unwrap();
         *addr.lock().unwrap() = Some(server.listening_addr());
 
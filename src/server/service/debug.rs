@@ -0,0 +1,620 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use grpc::{Error as GrpcError, WriteFlags};
+use grpc::{RpcContext, RpcStatus, RpcStatusCode, ServerStreamingSink, UnarySink};
+use futures::{future, stream, Future, Sink};
+use futures_cpupool::{Builder, CpuPool};
+use kvproto::debugpb_grpc;
+use kvproto::debugpb::*;
+use kvproto::raft_cmdpb::{AdminCmdType, AdminRequest, RaftCmdRequest, RaftCmdResponse,
+                          RegionDetailResponse, StatusCmdType, StatusRequest};
+use protobuf::RepeatedField;
+
+use raftstore::store::Engines;
+use server::debug::{Debugger, Error, Result};
+use server::transport::RaftStoreRouter;
+use storage::hot_keys;
+use util::{self, metrics};
+use util::dynamic_config::{ConfigManager, Error as ConfigError};
+use util::logger::{self, LogLevelFilter};
+use util::rocksdb as rocksdb_util;
+
+// Raft commands issued by the debug service are expected to be applied quickly.
+const RAFT_CMD_TIMEOUT_SECS: u64 = 10;
+
+fn error_to_status(e: Error) -> RpcStatus {
+    let (code, msg) = match e {
+        Error::NotFound(msg) => (RpcStatusCode::NotFound, Some(msg)),
+        Error::InvalidArgument(msg) => (RpcStatusCode::InvalidArgument, Some(msg)),
+        Error::Other(e) => (RpcStatusCode::Unknown, Some(format!("{:?}", e))),
+    };
+    RpcStatus::new(code, msg)
+}
+
+fn on_grpc_error(tag: &'static str, e: &GrpcError) {
+    error!("{} failed: {:?}", tag, e);
+}
+
+/// Service handles the RPC messages for the `Debug` service.
+///
+/// All requests are served on a dedicated thread pool so that slow debug
+/// operations never block the grpc completion queues.
+#[derive(Clone)]
+pub struct Service<T: RaftStoreRouter> {
+    pool: CpuPool,
+    debugger: Debugger,
+    raft_router: T,
+    // Applies the changes of `modify_tikv_config`.
+    config_manager: Arc<ConfigManager>,
+    // Seconds since the unix epoch when the service is started.
+    start_time: u64,
+}
+
+impl<T: RaftStoreRouter> Service<T> {
+    /// Constructs a new `Service` with `Engines`, a `RaftStoreRouter` and the
+    /// config manager of the server.
+    pub fn new(
+        engines: Engines,
+        raft_router: T,
+        config_manager: Arc<ConfigManager>,
+    ) -> Service<T> {
+        let pool = Builder::new()
+            .name_prefix(thd_name!("debugger"))
+            .pool_size(1)
+            .create();
+        let debugger = Debugger::new(engines);
+        let start_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Service {
+            pool: pool,
+            debugger: debugger,
+            raft_router: raft_router,
+            config_manager: config_manager,
+            start_time: start_time,
+        }
+    }
+
+    fn handle_response<F, P>(&self, ctx: RpcContext, sink: UnarySink<P>, resp: F, tag: &'static str)
+    where
+        P: Send + 'static,
+        F: Future<Item = P, Error = Error> + Send + 'static,
+    {
+        let f = resp.then(|v| match v {
+            Ok(resp) => sink.success(resp),
+            Err(e) => sink.fail(error_to_status(e)),
+        });
+        ctx.spawn(
+            f.map(|_| ())
+                .map_err(move |e| on_grpc_error(tag, &e)),
+        );
+    }
+}
+
+// Sends `req` to the raftstore and waits for the response.
+fn send_raft_command<T: RaftStoreRouter>(
+    raft_router: &T,
+    req: RaftCmdRequest,
+) -> Result<RaftCmdResponse> {
+    let (tx, rx) = mpsc::channel();
+    box_try!(raft_router.send_command(req, box move |resp| { let _ = tx.send(resp); }));
+    let mut resp = box_try!(rx.recv_timeout(Duration::from_secs(RAFT_CMD_TIMEOUT_SECS)));
+    if resp.get_header().has_error() {
+        return Err(box_err!("{:?}", resp.mut_header().take_error()));
+    }
+    Ok(resp)
+}
+
+fn region_detail<T: RaftStoreRouter>(
+    raft_router: &T,
+    store_id: u64,
+    region_id: u64,
+) -> Result<RegionDetailResponse> {
+    let mut status_request = StatusRequest::new();
+    status_request.set_cmd_type(StatusCmdType::RegionDetail);
+    let mut req = RaftCmdRequest::new();
+    req.mut_header().set_region_id(region_id);
+    req.mut_header().mut_peer().set_store_id(store_id);
+    req.set_status_request(status_request);
+
+    let mut resp = try!(send_raft_command(raft_router, req));
+    Ok(resp.mut_status_response().take_region_detail())
+}
+
+// Proposes a `ComputeHash` admin command on the leader of the region. It returns
+// once the command is applied on the leader, the hashes are then computed and
+// verified by every replica asynchronously.
+fn consistency_check<T: RaftStoreRouter>(
+    raft_router: &T,
+    store_id: u64,
+    mut detail: RegionDetailResponse,
+) -> Result<()> {
+    let region_id = detail.get_region().get_id();
+    if !detail.has_leader() {
+        return Err(box_err!("leader of region {} is unknown", region_id));
+    }
+    let leader = detail.take_leader();
+    if leader.get_store_id() != store_id {
+        return Err(Error::InvalidArgument(format!(
+            "leader of region {} is on store {}",
+            region_id,
+            leader.get_store_id()
+        )));
+    }
+
+    let mut admin_request = AdminRequest::new();
+    admin_request.set_cmd_type(AdminCmdType::ComputeHash);
+    let mut req = RaftCmdRequest::new();
+    req.mut_header().set_region_id(region_id);
+    req.mut_header().set_peer(leader);
+    req.mut_header()
+        .set_region_epoch(detail.take_region().take_region_epoch());
+    req.set_admin_request(admin_request);
+
+    try!(send_raft_command(raft_router, req));
+    Ok(())
+}
+
+#[cfg(feature = "failpoints")]
+mod fail_points {
+    use fail;
+
+    use server::debug::{Error, Result};
+
+    pub fn cfg(name: String, actions: &str) -> Result<()> {
+        fail::cfg(name, actions).map_err(Error::InvalidArgument)
+    }
+
+    pub fn remove(name: &str) -> Result<()> {
+        fail::remove(name);
+        Ok(())
+    }
+
+    pub fn list() -> Result<Vec<(String, String)>> {
+        Ok(fail::list())
+    }
+}
+
+#[cfg(not(feature = "failpoints"))]
+mod fail_points {
+    use server::debug::Result;
+
+    const DISABLED: &'static str = "fail points are disabled, build with `failpoints` feature";
+
+    pub fn cfg(_: String, _: &str) -> Result<()> {
+        Err(box_err!(DISABLED))
+    }
+
+    pub fn remove(_: &str) -> Result<()> {
+        Err(box_err!(DISABLED))
+    }
+
+    pub fn list() -> Result<Vec<(String, String)>> {
+        Err(box_err!(DISABLED))
+    }
+}
+
+impl<T: RaftStoreRouter + 'static> debugpb_grpc::Debug for Service<T> {
+    fn get(&self, ctx: RpcContext, mut req: GetRequest, sink: UnarySink<GetResponse>) {
+        const TAG: &'static str = "debug_get";
+
+        let db = req.get_db();
+        let cf = req.take_cf();
+        let key = req.take_key();
+        let debugger = self.debugger.clone();
+
+        let f = self.pool.spawn_fn(move || {
+            debugger.get(db, &cf, key.as_slice()).map(|value| {
+                let mut resp = GetResponse::new();
+                resp.set_value(value);
+                resp
+            })
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn raft_log(&self, ctx: RpcContext, req: RaftLogRequest, sink: UnarySink<RaftLogResponse>) {
+        const TAG: &'static str = "debug_raft_log";
+
+        let region_id = req.get_region_id();
+        let log_index = req.get_log_index();
+        let debugger = self.debugger.clone();
+
+        let f = self.pool.spawn_fn(move || {
+            debugger.raft_log(region_id, log_index).map(|entry| {
+                let mut resp = RaftLogResponse::new();
+                resp.set_entry(entry);
+                resp
+            })
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn region_info(
+        &self,
+        ctx: RpcContext,
+        req: RegionInfoRequest,
+        sink: UnarySink<RegionInfoResponse>,
+    ) {
+        const TAG: &'static str = "debug_region_info";
+
+        let region_id = req.get_region_id();
+        let debugger = self.debugger.clone();
+
+        let f = self.pool.spawn_fn(move || {
+            debugger.region_info(region_id).map(|region_info| {
+                let mut resp = RegionInfoResponse::new();
+                if let Some(raft_local_state) = region_info.raft_local_state {
+                    resp.set_raft_local_state(raft_local_state);
+                }
+                if let Some(raft_apply_state) = region_info.raft_apply_state {
+                    resp.set_raft_apply_state(raft_apply_state);
+                }
+                if let Some(region_local_state) = region_info.region_local_state {
+                    resp.set_region_local_state(region_local_state);
+                }
+                resp
+            })
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn compact(&self, ctx: RpcContext, req: CompactRequest, sink: UnarySink<CompactResponse>) {
+        const TAG: &'static str = "debug_compact";
+
+        let debugger = self.debugger.clone();
+
+        let f = self.pool.spawn_fn(move || {
+            debugger
+                .compact(
+                    req.get_db(),
+                    req.get_cf(),
+                    req.get_from_key(),
+                    req.get_to_key(),
+                )
+                .map(|_| CompactResponse::new())
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn get_region_properties(
+        &self,
+        ctx: RpcContext,
+        req: GetRegionPropertiesRequest,
+        sink: UnarySink<GetRegionPropertiesResponse>,
+    ) {
+        const TAG: &'static str = "get_region_properties";
+
+        let region_id = req.get_region_id();
+        let debugger = self.debugger.clone();
+
+        let f = self.pool.spawn_fn(move || {
+            debugger.get_region_properties(region_id).map(|props| {
+                let mut resp = GetRegionPropertiesResponse::new();
+                for (name, value) in props {
+                    let mut prop = Property::new();
+                    prop.set_name(name);
+                    prop.set_value(value);
+                    resp.mut_props().push(prop);
+                }
+                resp
+            })
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn get_metrics(
+        &self,
+        ctx: RpcContext,
+        req: GetMetricsRequest,
+        sink: UnarySink<GetMetricsResponse>,
+    ) {
+        const TAG: &'static str = "debug_get_metrics";
+
+        let debugger = self.debugger.clone();
+
+        let f = self.pool.spawn_fn(move || {
+            let mut resp = GetMetricsResponse::new();
+            resp.set_prometheus(metrics::dump());
+            if req.get_all() {
+                let engines = debugger.get_engine();
+                resp.set_rocksdb_kv(rocksdb_util::dump_stats(&engines.kv_engine));
+                resp.set_rocksdb_raft(rocksdb_util::dump_stats(&engines.raft_engine));
+            }
+            Ok(resp)
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn get_store_info(
+        &self,
+        ctx: RpcContext,
+        _: GetStoreInfoRequest,
+        sink: UnarySink<GetStoreInfoResponse>,
+    ) {
+        const TAG: &'static str = "debug_get_store_info";
+
+        let debugger = self.debugger.clone();
+        let start_time = self.start_time;
+
+        let f = self.pool.spawn_fn(move || {
+            let (git_hash, _, _, _) = util::build_info();
+            let mut resp = GetStoreInfoResponse::new();
+            resp.set_store_id(try!(debugger.get_store_id()));
+            resp.set_version(env!("CARGO_PKG_VERSION").to_owned());
+            resp.set_git_hash(git_hash);
+            resp.set_start_time(start_time);
+            Ok(resp)
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn get_cluster_info(
+        &self,
+        ctx: RpcContext,
+        _: GetClusterInfoRequest,
+        sink: UnarySink<GetClusterInfoResponse>,
+    ) {
+        const TAG: &'static str = "debug_get_cluster_info";
+
+        let debugger = self.debugger.clone();
+
+        let f = self.pool.spawn_fn(move || {
+            let mut resp = GetClusterInfoResponse::new();
+            resp.set_cluster_id(try!(debugger.get_cluster_id()));
+            Ok(resp)
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn check_region_consistency(
+        &self,
+        ctx: RpcContext,
+        req: RegionConsistencyCheckRequest,
+        sink: UnarySink<RegionConsistencyCheckResponse>,
+    ) {
+        const TAG: &'static str = "check_region_consistency";
+
+        let region_id = req.get_region_id();
+        let debugger = self.debugger.clone();
+        let raft_router = self.raft_router.clone();
+
+        let f = self.pool.spawn_fn(move || {
+            let store_id = try!(debugger.get_store_id());
+            let detail = try!(region_detail(&raft_router, store_id, region_id));
+            try!(consistency_check(&raft_router, store_id, detail));
+            Ok(RegionConsistencyCheckResponse::new())
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn modify_tikv_config(
+        &self,
+        ctx: RpcContext,
+        req: ModifyTikvConfigRequest,
+        sink: UnarySink<ModifyTikvConfigResponse>,
+    ) {
+        const TAG: &'static str = "modify_tikv_config";
+
+        let config_manager = self.config_manager.clone();
+
+        let f = self.pool.spawn_fn(move || {
+            let module = format!("{:?}", req.get_module()).to_lowercase();
+            config_manager
+                .update(&module, req.get_config_name(), req.get_config_value())
+                .map(|_| ModifyTikvConfigResponse::new())
+                .map_err(|e| match e {
+                    e @ ConfigError::UnknownModule(_) | e @ ConfigError::Invalid(_) => {
+                        Error::InvalidArgument(format!("{}", e))
+                    }
+                    e => Error::Other(box e),
+                })
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn set_log_level(
+        &self,
+        ctx: RpcContext,
+        req: SetLogLevelRequest,
+        sink: UnarySink<SetLogLevelResponse>,
+    ) {
+        const TAG: &'static str = "set_log_level";
+
+        let f = self.pool.spawn_fn(move || {
+            let level: LogLevelFilter = match req.get_level().parse() {
+                Ok(level) => level,
+                Err(_) => {
+                    return Err(Error::InvalidArgument(format!(
+                        "invalid log level {:?}",
+                        req.get_level()
+                    )))
+                }
+            };
+            logger::set_log_level(req.get_module(), level);
+            Ok(SetLogLevelResponse::new())
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn get_hot_keys(
+        &self,
+        ctx: RpcContext,
+        req: GetHotKeysRequest,
+        sink: UnarySink<GetHotKeysResponse>,
+    ) {
+        const TAG: &'static str = "get_hot_keys";
+
+        let f = self.pool.spawn_fn(move || {
+            let limit = req.get_limit() as usize;
+            let to_pb = |keys: Vec<(Vec<u8>, u64)>| -> RepeatedField<HotKey> {
+                keys.into_iter()
+                    .map(|(key, count)| {
+                        let mut hot_key = HotKey::new();
+                        hot_key.set_key(key);
+                        hot_key.set_count(count);
+                        hot_key
+                    })
+                    .collect()
+            };
+            let mut resp = GetHotKeysResponse::new();
+            resp.set_read_keys(to_pb(hot_keys::hot_read_keys(limit)));
+            resp.set_write_keys(to_pb(hot_keys::hot_write_keys(limit)));
+            Ok(resp)
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn inject_fail_point(
+        &self,
+        ctx: RpcContext,
+        mut req: InjectFailPointRequest,
+        sink: UnarySink<InjectFailPointResponse>,
+    ) {
+        const TAG: &'static str = "debug_inject_fail_point";
+
+        let f = self.pool.spawn_fn(move || {
+            let name = req.take_name();
+            if name.is_empty() {
+                return Err(Error::InvalidArgument("fail point name is empty".to_owned()));
+            }
+            try!(fail_points::cfg(name, req.get_actions()));
+            Ok(InjectFailPointResponse::new())
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn recover_fail_point(
+        &self,
+        ctx: RpcContext,
+        req: RecoverFailPointRequest,
+        sink: UnarySink<RecoverFailPointResponse>,
+    ) {
+        const TAG: &'static str = "debug_recover_fail_point";
+
+        let f = self.pool.spawn_fn(move || {
+            try!(fail_points::remove(req.get_name()));
+            Ok(RecoverFailPointResponse::new())
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn list_fail_points(
+        &self,
+        ctx: RpcContext,
+        _: ListFailPointsRequest,
+        sink: UnarySink<ListFailPointsResponse>,
+    ) {
+        const TAG: &'static str = "debug_list_fail_points";
+
+        let f = self.pool.spawn_fn(move || {
+            let mut resp = ListFailPointsResponse::new();
+            for (name, actions) in try!(fail_points::list()) {
+                let mut entry = ListFailPointsResponse_Entry::new();
+                entry.set_name(name);
+                entry.set_actions(actions);
+                resp.mut_entries().push(entry);
+            }
+            Ok(resp)
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn region_size(
+        &self,
+        ctx: RpcContext,
+        mut req: RegionSizeRequest,
+        sink: UnarySink<RegionSizeResponse>,
+    ) {
+        const TAG: &'static str = "debug_region_size";
+
+        let region_id = req.get_region_id();
+        let cfs = req.take_cfs().into_vec();
+        let debugger = self.debugger.clone();
+
+        let f = self.pool.spawn_fn(move || {
+            debugger.region_size(region_id, cfs).map(|sizes| {
+                let mut resp = RegionSizeResponse::new();
+                for (cf, size) in sizes {
+                    let mut entry = RegionSizeResponse_Entry::new();
+                    entry.set_region_id(region_id);
+                    entry.set_cf(cf);
+                    entry.set_size(size);
+                    resp.mut_entries().push(entry);
+                }
+                resp
+            })
+        });
+
+        self.handle_response(ctx, sink, f, TAG);
+    }
+
+    fn scan_mvcc(
+        &self,
+        ctx: RpcContext,
+        mut req: ScanMvccRequest,
+        sink: ServerStreamingSink<ScanMvccResponse>,
+    ) {
+        const TAG: &'static str = "debug_scan_mvcc";
+
+        let from = req.take_from_key();
+        let to = req.take_to_key();
+        let limit = req.get_limit();
+        let debugger = self.debugger.clone();
+
+        // The whole scan is done in the pool, results are collected before being
+        // streamed back, which is acceptable for the bounded scans used in debugging.
+        let f = self.pool.spawn_fn(move || {
+            let iter = try!(debugger.scan_mvcc(&from, &to, limit));
+            let mut resps = vec![];
+            for item in iter {
+                let (key, mvcc_info) = try!(item);
+                let mut resp = ScanMvccResponse::new();
+                resp.set_key(key);
+                resp.set_info(mvcc_info);
+                resps.push((resp, WriteFlags::default()));
+            }
+            Ok(resps)
+        });
+
+        let future = f.then(move |v| match v {
+            Ok(resps) => {
+                let resps = stream::iter::<_, _, GrpcError>(resps.into_iter().map(Ok));
+                let send = sink.send_all(resps);
+                future::Either::A(send.map(|_| ()))
+            }
+            Err(e) => future::Either::B(sink.fail(error_to_status(e))),
+        }).map_err(move |e| on_grpc_error(TAG, &e));
+        ctx.spawn(future);
+    }
+}
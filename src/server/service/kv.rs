@@ -16,23 +16,34 @@ use std::fmt::Debug;
 use std::io::Write;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use mio::Token;
 use grpc::{ClientStreamingSink, RequestStream, RpcContext, RpcStatus, RpcStatusCode, UnarySink};
 use futures::{future, Future, Stream};
 use futures::sync::oneshot;
-use protobuf::RepeatedField;
+use futures_cpupool::{Builder, CpuPool};
+use rocksdb::DB;
+use protobuf::{Message, RepeatedField};
 use kvproto::tikvpb_grpc;
 use kvproto::raft_serverpb::*;
 use kvproto::kvrpcpb::*;
+use kvproto::kvrpcpb::TimeDetail as KvTimeDetail;
 use kvproto::coprocessor::*;
 use kvproto::errorpb::{Error as RegionError, ServerIsBusy};
 
 use util::worker::Scheduler;
 use util::buf::PipeBuffer;
-use storage::{self, Key, Mutation, Options, Storage, Value};
+use util::time::{duration_to_ms, duration_to_sec, Instant};
+use util::memory::{MemoryTrace, GRPC_MEMORY};
+use util::metrics::REQUEST_ERROR_COUNTER_VEC;
+use util::tracker::{self, TimeDetail, Tracker};
+use storage::{self, Key, Mutation, Options, SecondaryLocksStatus, Storage, StorageFuture,
+              Value};
+use storage::gc_worker;
 use storage::txn::Error as TxnError;
-use storage::mvcc::{Error as MvccError, Write as MvccWrite, WriteType};
+use storage::mvcc::{Error as MvccError, TxnStatus, Write as MvccWrite, WriteType};
 use storage::engine::Error as EngineError;
+use server::quota_limiter::QuotaLimiter;
 use server::transport::RaftStoreRouter;
 use server::snap::Task as SnapTask;
 use server::metrics::*;
@@ -51,6 +62,12 @@ pub struct Service<T: RaftStoreRouter + 'static> {
     ch: T,
     // For handling snapshot.
     snap_scheduler: Scheduler<SnapTask>,
+    // For limiting the resources taken by the foreground requests.
+    quota_limiter: Arc<QuotaLimiter>,
+    // For the requests served by the local kv engine directly, `None` if it's
+    // not exposed to the service.
+    kv_db: Option<Arc<DB>>,
+    local_pool: CpuPool,
     token: Arc<AtomicUsize>, // TODO: remove it.
 }
 
@@ -60,12 +77,21 @@ impl<T: RaftStoreRouter + 'static> Service<T> {
         end_point_scheduler: Scheduler<EndPointTask>,
         ch: T,
         snap_scheduler: Scheduler<SnapTask>,
+        quota_limiter: Arc<QuotaLimiter>,
+        kv_db: Option<Arc<DB>>,
     ) -> Service<T> {
+        let local_pool = Builder::new()
+            .name_prefix(thd_name!("kv-local"))
+            .pool_size(1)
+            .create();
         Service {
             storage: storage,
             end_point_scheduler: end_point_scheduler,
             ch: ch,
             snap_scheduler: snap_scheduler,
+            quota_limiter: quota_limiter,
+            kv_db: kv_db,
+            local_pool: local_pool,
             token: Arc::new(AtomicUsize::new(1)),
         }
     }
@@ -88,28 +114,206 @@ fn make_callback<T: Debug + Send + 'static>() -> (Box<FnBox(T) + Send>, oneshot:
     (box callback, rx)
 }
 
+type ResultFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
+
+/// Resolves to the result of the storage command, the errors of the command are
+/// returned to the client in the response instead of failing the RPC.
+fn result_future<T: Send + 'static>(f: StorageFuture<T>) -> ResultFuture<storage::Result<T>> {
+    box f.then(Ok)
+}
+
+/// Like `result_future`, but the time detail of the command in the scheduler
+/// is read from `tracker` and returned along with the result.
+fn with_time_detail<T: Send + 'static>(
+    f: StorageFuture<T>,
+    tracker: &Arc<Tracker>,
+) -> ResultFuture<(storage::Result<T>, Option<(TimeDetail, ScanDetail)>)> {
+    let tracker = tracker.clone();
+    box f.then(move |res| {
+        let detail = tracker.time_detail().map(|d| (d, tracker.scan_detail()));
+        Ok((res, detail))
+    })
+}
+
+trait RegionErrorResponse {
+    fn has_region_error(&self) -> bool;
+}
+
+macro_rules! impl_region_error_response {
+    ($($resp:ty),*) => {
+        $(
+            impl RegionErrorResponse for $resp {
+                fn has_region_error(&self) -> bool {
+                    <$resp>::has_region_error(self)
+                }
+            }
+        )*
+    }
+}
+
+impl_region_error_response!(
+    GetResponse,
+    ScanResponse,
+    PrewriteResponse,
+    CommitResponse,
+    CleanupResponse,
+    BatchGetResponse,
+    BatchRollbackResponse,
+    ScanLockResponse,
+    ResolveLockResponse,
+    GCResponse,
+    DeleteRangeResponse,
+    UnsafeDestroyRangeResponse,
+    RawGetResponse,
+    RawScanResponse,
+    RawPutResponse,
+    RawDeleteResponse,
+    RawBatchGetResponse,
+    RawBatchPutResponse,
+    RawBatchDeleteResponse,
+    RawDeleteRangeResponse,
+    Response,
+    MvccGetByKeyResponse,
+    MvccGetByStartTsResponse
+);
+
+// The responses of the requests served by the store rather than the regions.
+macro_rules! impl_no_region_error_response {
+    ($($resp:ty),*) => {
+        $(
+            impl RegionErrorResponse for $resp {
+                fn has_region_error(&self) -> bool {
+                    false
+                }
+            }
+        )*
+    }
+}
+
+impl_no_region_error_response!(
+    PhysicalScanLockResponse,
+    RegisterLockObserverResponse,
+    CheckLockObserverResponse,
+    RemoveLockObserverResponse
+);
+
+/// `MsgObserver` records the duration and sizes of a grpc message labeled by
+/// its result. The message is regarded as failed if the observer is dropped
+/// before the response is sent.
+struct MsgObserver {
+    label: &'static str,
+    start: Instant,
+    result: &'static str,
+    // Tracks the buffers of the request and the response until it's sent.
+    mem_trace: MemoryTrace,
+    // Follows the request to the threads handling it.
+    tracker: Arc<Tracker>,
+    quota_limiter: Arc<QuotaLimiter>,
+}
+
+impl MsgObserver {
+    fn new<M: Message>(
+        label: &'static str,
+        req: &M,
+        quota_limiter: &Arc<QuotaLimiter>,
+    ) -> MsgObserver {
+        let size = req.compute_size();
+        GRPC_REQ_SIZE_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .observe(f64::from(size));
+        let mut mem_trace = MemoryTrace::new(&GRPC_MEMORY);
+        mem_trace.consume(size as usize);
+        MsgObserver {
+            label: label,
+            start: Instant::now_coarse(),
+            result: "other",
+            mem_trace: mem_trace,
+            tracker: Tracker::new(label),
+            quota_limiter: quota_limiter.clone(),
+        }
+    }
+
+    /// Rejects the request if the foreground quotas are exhausted.
+    fn check_quota(&mut self) -> Result<(), Error> {
+        match self.quota_limiter.check() {
+            None => Ok(()),
+            Some(backoff_ms) => {
+                GRPC_QUOTA_EXCEEDED_COUNTER_VEC
+                    .with_label_values(&[self.label])
+                    .inc();
+                self.result = "quota_exceeded";
+                Err(Error::QuotaExceeded(backoff_ms))
+            }
+        }
+    }
+
+    /// Records the size of the response and sends it by `sink`. The response
+    /// is delayed if the request exceeds the foreground quotas.
+    fn send<M>(
+        mut self,
+        sink: UnarySink<M>,
+        resp: M,
+    ) -> Box<Future<Item = (), Error = Error> + Send>
+    where
+        M: Message + RegionErrorResponse,
+    {
+        let result = if resp.has_region_error() {
+            "region_error"
+        } else {
+            "ok"
+        };
+        let size = resp.compute_size();
+        GRPC_RESP_SIZE_HISTOGRAM_VEC
+            .with_label_values(&[self.label, result])
+            .observe(f64::from(size));
+        self.mem_trace.consume(size as usize);
+        let delay = self.quota_limiter
+            .consume(self.tracker.cpu_time(), self.tracker.write_bytes());
+        self.tracker.record_event("respond");
+        if delay == Duration::from_secs(0) {
+            return box sink.success(resp)
+                .map_err(Error::from)
+                .map(move |_| self.result = result);
+        }
+        GRPC_QUOTA_DELAY_HISTOGRAM_VEC
+            .with_label_values(&[self.label])
+            .observe(duration_to_sec(delay));
+        box self.quota_limiter
+            .sleep(delay)
+            .then(move |_| sink.success(resp))
+            .map_err(Error::from)
+            .map(move |_| self.result = result)
+    }
+}
+
+impl Drop for MsgObserver {
+    fn drop(&mut self) {
+        GRPC_REQ_DURATION_HISTOGRAM_VEC
+            .with_label_values(&[self.label, self.result])
+            .observe(duration_to_sec(self.start.elapsed()));
+    }
+}
+
 impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
     fn kv_get(&self, ctx: RpcContext, mut req: GetRequest, sink: UnarySink<GetResponse>) {
         let label = "kv_get";
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
-        let (cb, future) = make_callback();
-        let res = self.storage.async_get(
+        let future = self.storage.async_get(
             req.take_context(),
             Key::from_raw(req.get_key()),
             req.get_version(),
-            cb,
         );
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
-            return;
-        }
-
-        let future = future
-            .map_err(Error::from)
-            .map(|v| {
+        let future = with_time_detail(future, &observer.tracker)
+            .map(|(v, time_detail)| {
                 let mut res = GetResponse::new();
                 if let Some(err) = extract_region_error(&v) {
                     res.set_region_error(err);
@@ -120,9 +324,12 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                         Err(e) => res.set_error(extract_key_error(&e)),
                     }
                 }
+                if let Some(d) = time_detail {
+                    res.set_exec_details(extract_exec_details(d));
+                }
                 res
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -137,37 +344,39 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
         let storage = self.storage.clone();
         let mut options = Options::default();
         options.key_only = req.get_key_only();
+        options.reverse_scan = req.get_reverse();
 
-        let (cb, future) = make_callback();
-        let res = storage.async_scan(
+        let future = storage.async_scan(
             req.take_context(),
             Key::from_raw(req.get_start_key()),
             req.get_limit() as usize,
             req.get_version(),
             options,
-            cb,
         );
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
-            return;
-        }
-
-        let future = future
-            .map_err(Error::from)
-            .map(|v| {
+        let future = with_time_detail(future, &observer.tracker)
+            .map(|(v, time_detail)| {
                 let mut resp = ScanResponse::new();
                 if let Some(err) = extract_region_error(&v) {
                     resp.set_region_error(err);
                 } else {
                     resp.set_pairs(RepeatedField::from_vec(extract_kv_pairs(v)));
                 }
+                if let Some(d) = time_detail {
+                    resp.set_exec_details(extract_exec_details(d));
+                }
                 resp
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -187,6 +396,12 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
         let mutations = req.take_mutations()
             .into_iter()
@@ -194,39 +409,44 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                 Op::Put => Mutation::Put((Key::from_raw(x.get_key()), x.take_value())),
                 Op::Del => Mutation::Delete(Key::from_raw(x.get_key())),
                 Op::Lock => Mutation::Lock(Key::from_raw(x.get_key())),
+                Op::Insert => Mutation::Insert((Key::from_raw(x.get_key()), x.take_value())),
                 _ => panic!("mismatch Op in prewrite mutations"),
             })
             .collect();
         let mut options = Options::default();
         options.lock_ttl = req.get_lock_ttl();
         options.skip_constraint_check = req.get_skip_constraint_check();
+        options.use_async_commit = req.get_use_async_commit();
+        options.secondary_keys = req.take_secondaries().into_vec();
+        options.min_commit_ts = req.get_min_commit_ts();
+        options.try_one_pc = req.get_try_one_pc();
 
-        let (cb, future) = make_callback();
-        let res = self.storage.async_prewrite(
+        let future = self.storage.async_prewrite_with_result(
             req.take_context(),
             mutations,
             req.take_primary_lock(),
             req.get_start_version(),
             options,
-            cb,
         );
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
-            return;
-        }
-
-        let future = future
-            .map_err(Error::from)
-            .map(|v| {
+        let future = with_time_detail(future, &observer.tracker)
+            .map(|(v, time_detail)| {
                 let mut resp = PrewriteResponse::new();
                 if let Some(err) = extract_region_error(&v) {
                     resp.set_region_error(err);
                 } else {
-                    resp.set_errors(RepeatedField::from_vec(extract_key_errors(v)));
+                    let locks = v.map(|res| {
+                        resp.set_min_commit_ts(res.min_commit_ts);
+                        resp.set_one_pc_commit_ts(res.one_pc_commit_ts);
+                        res.locks
+                    });
+                    resp.set_errors(RepeatedField::from_vec(extract_key_errors(locks)));
+                }
+                if let Some(d) = time_detail {
+                    resp.set_exec_details(extract_exec_details(d));
                 }
                 resp
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -241,34 +461,35 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
         let keys = req.get_keys().iter().map(|x| Key::from_raw(x)).collect();
 
-        let (cb, future) = make_callback();
-        let res = self.storage.async_commit(
+        let future = self.storage.async_commit(
             req.take_context(),
             keys,
             req.get_start_version(),
             req.get_commit_version(),
-            cb,
         );
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
-            return;
-        }
-
-        let future = future
-            .map_err(Error::from)
-            .map(|v| {
+        let future = with_time_detail(future, &observer.tracker)
+            .map(|(v, time_detail)| {
                 let mut resp = CommitResponse::new();
                 if let Some(err) = extract_region_error(&v) {
                     resp.set_region_error(err);
                 } else if let Err(e) = v {
                     resp.set_error(extract_key_error(&e));
                 }
+                if let Some(d) = time_detail {
+                    resp.set_exec_details(extract_exec_details(d));
+                }
                 resp
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -292,22 +513,20 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
-        let (cb, future) = make_callback();
-        let res = self.storage.async_cleanup(
+        let future = self.storage.async_cleanup(
             req.take_context(),
             Key::from_raw(req.get_key()),
             req.get_start_version(),
-            cb,
         );
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
-            return;
-        }
-
-        let future = future
-            .map_err(Error::from)
-            .map(|v| {
+        let future = with_time_detail(future, &observer.tracker)
+            .map(|(v, time_detail)| {
                 let mut resp = CleanupResponse::new();
                 if let Some(err) = extract_region_error(&v) {
                     resp.set_region_error(err);
@@ -318,9 +537,671 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                         resp.set_error(extract_key_error(&e));
                     }
                 }
-                resp
+                if let Some(d) = time_detail {
+                    resp.set_exec_details(extract_exec_details(d));
+                }
+                resp
+            })
+            .and_then(|res| observer.send(sink, res))
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn kv_check_txn_status(
+        &self,
+        ctx: RpcContext,
+        mut req: CheckTxnStatusRequest,
+        sink: UnarySink<CheckTxnStatusResponse>,
+    ) {
+        let label = "kv_check_txn_status";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let primary_key = req.get_primary_key().to_vec();
+        let lock_ts = req.get_lock_ts();
+        let future = self.storage.async_check_txn_status(
+            req.take_context(),
+            Key::from_raw(&primary_key),
+            lock_ts,
+            req.get_caller_start_ts(),
+            req.get_current_ts(),
+        );
+        let future = result_future(future)
+            .map(move |v| {
+                let mut resp = CheckTxnStatusResponse::new();
+                if let Some(err) = extract_region_error(&v) {
+                    resp.set_region_error(err);
+                } else {
+                    match v {
+                        Ok(TxnStatus::Alive { ttl }) => resp.set_lock_ttl(ttl),
+                        Ok(TxnStatus::AsyncCommitLocked {
+                            min_commit_ts,
+                            secondaries,
+                        }) => {
+                            let mut lock_info = LockInfo::new();
+                            lock_info.set_key(primary_key.clone());
+                            lock_info.set_primary_lock(primary_key);
+                            lock_info.set_lock_version(lock_ts);
+                            lock_info.set_use_async_commit(true);
+                            lock_info.set_min_commit_ts(min_commit_ts);
+                            lock_info.set_secondaries(RepeatedField::from_vec(secondaries));
+                            resp.set_lock_info(lock_info);
+                        }
+                        Ok(TxnStatus::Committed { commit_ts }) => {
+                            resp.set_commit_version(commit_ts)
+                        }
+                        Ok(TxnStatus::RolledBack) => {}
+                        Err(e) => resp.set_error(extract_key_error(&e)),
+                    }
+                }
+                resp
+            })
+            .and_then(|res| observer.send(sink, res))
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn kv_txn_heart_beat(
+        &self,
+        ctx: RpcContext,
+        mut req: TxnHeartBeatRequest,
+        sink: UnarySink<TxnHeartBeatResponse>,
+    ) {
+        let label = "kv_txn_heart_beat";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let future = self.storage.async_txn_heart_beat(
+            req.take_context(),
+            Key::from_raw(req.get_primary_lock()),
+            req.get_start_version(),
+            req.get_advise_lock_ttl(),
+        );
+        let future = result_future(future)
+            .map(|v| {
+                let mut resp = TxnHeartBeatResponse::new();
+                if let Some(err) = extract_region_error(&v) {
+                    resp.set_region_error(err);
+                } else {
+                    match v {
+                        Ok(ttl) => resp.set_lock_ttl(ttl),
+                        Err(e) => resp.set_error(extract_key_error(&e)),
+                    }
+                }
+                resp
+            })
+            .and_then(|res| observer.send(sink, res))
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn kv_check_secondary_locks(
+        &self,
+        ctx: RpcContext,
+        mut req: CheckSecondaryLocksRequest,
+        sink: UnarySink<CheckSecondaryLocksResponse>,
+    ) {
+        let label = "kv_check_secondary_locks";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let keys = req.get_keys().iter().map(|x| Key::from_raw(x)).collect();
+        let future = self.storage.async_check_secondary_locks(
+            req.take_context(),
+            keys,
+            req.get_start_version(),
+        );
+        let future = result_future(future)
+            .map(|v| {
+                let mut resp = CheckSecondaryLocksResponse::new();
+                if let Some(err) = extract_region_error(&v) {
+                    resp.set_region_error(err);
+                } else {
+                    match v {
+                        Ok(SecondaryLocksStatus::Locked(locks)) => {
+                            let locks = locks
+                                .into_iter()
+                                .map(|(key, lock)| {
+                                    let mut lock_info = LockInfo::new();
+                                    lock_info.set_key(key.raw().unwrap());
+                                    lock_info.set_primary_lock(lock.primary);
+                                    lock_info.set_lock_version(lock.ts);
+                                    lock_info.set_lock_ttl(lock.ttl);
+                                    lock_info.set_use_async_commit(lock.use_async_commit);
+                                    lock_info.set_min_commit_ts(lock.min_commit_ts);
+                                    lock_info
+                                })
+                                .collect();
+                            resp.set_locks(RepeatedField::from_vec(locks));
+                        }
+                        Ok(SecondaryLocksStatus::Committed(commit_ts)) => {
+                            resp.set_commit_ts(commit_ts)
+                        }
+                        Ok(SecondaryLocksStatus::RolledBack) => {}
+                        Err(e) => resp.set_error(extract_key_error(&e)),
+                    }
+                }
+                resp
+            })
+            .and_then(|res| observer.send(sink, res))
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn kv_batch_get(
+        &self,
+        ctx: RpcContext,
+        mut req: BatchGetRequest,
+        sink: UnarySink<BatchGetResponse>,
+    ) {
+        let label = "kv_batchget";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let keys = req.get_keys()
+            .into_iter()
+            .map(|x| Key::from_raw(x))
+            .collect();
+
+        let future = self.storage.async_batch_get(req.take_context(), keys, req.get_version());
+        let future = with_time_detail(future, &observer.tracker)
+            .map(|(v, time_detail)| {
+                let mut resp = BatchGetResponse::new();
+                if let Some(err) = extract_region_error(&v) {
+                    resp.set_region_error(err);
+                } else {
+                    resp.set_pairs(RepeatedField::from_vec(extract_kv_pairs(v)))
+                }
+                if let Some(d) = time_detail {
+                    resp.set_exec_details(extract_exec_details(d));
+                }
+                resp
+            })
+            .and_then(|res| observer.send(sink, res))
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn kv_batch_rollback(
+        &self,
+        ctx: RpcContext,
+        mut req: BatchRollbackRequest,
+        sink: UnarySink<BatchRollbackResponse>,
+    ) {
+        let label = "kv_batch_rollback";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let keys = req.get_keys()
+            .into_iter()
+            .map(|x| Key::from_raw(x))
+            .collect();
+
+        let future = self.storage.async_rollback(req.take_context(), keys, req.get_start_version());
+        let future = with_time_detail(future, &observer.tracker)
+            .map(|(v, time_detail)| {
+                let mut resp = BatchRollbackResponse::new();
+                if let Some(err) = extract_region_error(&v) {
+                    resp.set_region_error(err);
+                } else if let Err(e) = v {
+                    resp.set_error(extract_key_error(&e));
+                }
+                if let Some(d) = time_detail {
+                    resp.set_exec_details(extract_exec_details(d));
+                }
+                resp
+            })
+            .and_then(|res| observer.send(sink, res))
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn kv_pessimistic_lock(
+        &self,
+        ctx: RpcContext,
+        mut req: PessimisticLockRequest,
+        sink: UnarySink<PessimisticLockResponse>,
+    ) {
+        let label = "kv_pessimistic_lock";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let keys = req.get_mutations()
+            .into_iter()
+            .map(|x| match x.get_op() {
+                Op::PessimisticLock => Key::from_raw(x.get_key()),
+                _ => panic!("mismatch Op in pessimistic lock mutations"),
+            })
+            .collect();
+        let mut options = Options::default();
+        options.lock_ttl = req.get_lock_ttl();
+        options.return_values = req.get_return_values();
+
+        let future = self.storage.async_acquire_pessimistic_lock_with_result(
+            req.take_context(),
+            keys,
+            req.take_primary_lock(),
+            req.get_start_version(),
+            req.get_for_update_ts(),
+            options,
+        );
+        let future = result_future(future)
+            .map(|v| {
+                let mut resp = PessimisticLockResponse::new();
+                if let Some(err) = extract_region_error(&v) {
+                    resp.set_region_error(err);
+                } else {
+                    let locks = v.map(|res| {
+                        // A key which doesn't exist is returned as an empty value.
+                        let values = res.values
+                            .into_iter()
+                            .map(|v| v.unwrap_or_default())
+                            .collect();
+                        resp.set_values(RepeatedField::from_vec(values));
+                        res.locks
+                    });
+                    resp.set_errors(RepeatedField::from_vec(extract_key_errors(locks)));
+                }
+                resp
+            })
+            .and_then(|res| observer.send(sink, res))
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn kv_pessimistic_rollback(
+        &self,
+        ctx: RpcContext,
+        mut req: PessimisticRollbackRequest,
+        sink: UnarySink<PessimisticRollbackResponse>,
+    ) {
+        let label = "kv_pessimistic_rollback";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let keys = req.get_keys()
+            .into_iter()
+            .map(|x| Key::from_raw(x))
+            .collect();
+
+        let future = self.storage.async_pessimistic_rollback(
+            req.take_context(),
+            keys,
+            req.get_start_version(),
+            req.get_for_update_ts(),
+        );
+        let future = result_future(future)
+            .map(|v| {
+                let mut resp = PessimisticRollbackResponse::new();
+                if let Some(err) = extract_region_error(&v) {
+                    resp.set_region_error(err);
+                } else if let Err(e) = v {
+                    resp.set_errors(RepeatedField::from_vec(vec![extract_key_error(&e)]));
+                }
+                resp
+            })
+            .and_then(|res| observer.send(sink, res))
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn kv_scan_lock(
+        &self,
+        ctx: RpcContext,
+        mut req: ScanLockRequest,
+        sink: UnarySink<ScanLockResponse>,
+    ) {
+        let label = "kv_scan_lock";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let future = self.storage.async_scan_lock(req.take_context(), req.get_max_version());
+        let future = with_time_detail(future, &observer.tracker)
+            .map(|(v, time_detail)| {
+                let mut resp = ScanLockResponse::new();
+                if let Some(err) = extract_region_error(&v) {
+                    resp.set_region_error(err);
+                } else {
+                    match v {
+                        Ok(locks) => resp.set_locks(RepeatedField::from_vec(locks)),
+                        Err(e) => resp.set_error(extract_key_error(&e)),
+                    }
+                }
+                if let Some(d) = time_detail {
+                    resp.set_exec_details(extract_exec_details(d));
+                }
+                resp
+            })
+            .and_then(|res| observer.send(sink, res))
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn kv_resolve_lock(
+        &self,
+        ctx: RpcContext,
+        mut req: ResolveLockRequest,
+        sink: UnarySink<ResolveLockResponse>,
+    ) {
+        let label = "kv_resolve_lock";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let commit_ts = match req.get_commit_version() {
+            0 => None,
+            x => Some(x),
+        };
+
+        let future = if req.get_keys().is_empty() {
+            self.storage
+                .async_resolve_lock(req.take_context(), req.get_start_version(), commit_ts)
+        } else {
+            let keys = req.get_keys().iter().map(|x| Key::from_raw(x)).collect();
+            self.storage.async_resolve_lock_lite(
+                req.take_context(),
+                req.get_start_version(),
+                commit_ts,
+                keys,
+            )
+        };
+        let future = with_time_detail(future, &observer.tracker)
+            .map(|(v, time_detail)| {
+                let mut resp = ResolveLockResponse::new();
+                if let Some(err) = extract_region_error(&v) {
+                    resp.set_region_error(err);
+                } else if let Err(e) = v {
+                    resp.set_error(extract_key_error(&e));
+                }
+                if let Some(d) = time_detail {
+                    resp.set_exec_details(extract_exec_details(d));
+                }
+                resp
+            })
+            .and_then(|res| observer.send(sink, res))
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn kv_gc(&self, ctx: RpcContext, mut req: GCRequest, sink: UnarySink<GCResponse>) {
+        let label = "kv_gc";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let future = self.storage.async_gc(req.take_context(), req.get_safe_point());
+        let future = with_time_detail(future, &observer.tracker)
+            .map(|(v, time_detail)| {
+                let mut resp = GCResponse::new();
+                if let Some(err) = extract_region_error(&v) {
+                    resp.set_region_error(err);
+                } else if let Err(e) = v {
+                    resp.set_error(extract_key_error(&e));
+                }
+                if let Some(d) = time_detail {
+                    resp.set_exec_details(extract_exec_details(d));
+                }
+                resp
+            })
+            .and_then(|res| observer.send(sink, res))
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn kv_delete_range(
+        &self,
+        ctx: RpcContext,
+        mut req: DeleteRangeRequest,
+        sink: UnarySink<DeleteRangeResponse>,
+    ) {
+        let label = "kv_delete_range";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let future = self.storage.async_delete_range(
+            req.take_context(),
+            Key::from_raw(req.get_start_key()),
+            Key::from_raw(req.get_end_key()),
+        );
+        let future = result_future(future)
+            .map(|v| {
+                let mut resp = DeleteRangeResponse::new();
+                if let Some(err) = extract_region_error(&v) {
+                    resp.set_region_error(err);
+                } else if let Err(e) = v {
+                    resp.set_error(format!("{}", e));
+                }
+                resp
+            })
+            .and_then(|res| observer.send(sink, res))
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn unsafe_destroy_range(
+        &self,
+        ctx: RpcContext,
+        req: UnsafeDestroyRangeRequest,
+        sink: UnarySink<UnsafeDestroyRangeResponse>,
+    ) {
+        let label = "unsafe_destroy_range";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let start_key = Key::from_raw(req.get_start_key());
+        // An empty end key means the end of the keys.
+        let end_key = if req.get_end_key().is_empty() {
+            Key::from_encoded(vec![])
+        } else {
+            Key::from_raw(req.get_end_key())
+        };
+        let kv_db = self.kv_db.clone();
+        // Destroying a large range takes a while, it's done out of the grpc threads.
+        let future = self.local_pool
+            .spawn_fn(move || {
+                let mut resp = UnsafeDestroyRangeResponse::new();
+                let res = match kv_db {
+                    Some(db) => gc_worker::unsafe_destroy_range(&db, &start_key, &end_key),
+                    None => Err(box_err!("unsafe destroy range is not supported")),
+                };
+                if let Err(e) = res {
+                    resp.set_error(format!("{}", e));
+                }
+                Ok::<_, Error>(resp)
+            })
+            .and_then(|res| observer.send(sink, res))
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn physical_scan_lock(
+        &self,
+        ctx: RpcContext,
+        req: PhysicalScanLockRequest,
+        sink: UnarySink<PhysicalScanLockResponse>,
+    ) {
+        let label = "physical_scan_lock";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let start_key = Key::from_raw(req.get_start_key());
+        let (max_ts, limit) = (req.get_max_ts(), req.get_limit() as usize);
+        let kv_db = self.kv_db.clone();
+        let pessimistic_locks = self.storage.get_pessimistic_locks();
+        let future = self.local_pool
+            .spawn_fn(move || {
+                let mut resp = PhysicalScanLockResponse::new();
+                let res = match kv_db {
+                    Some(db) => gc_worker::physical_scan_lock(
+                        &db,
+                        &pessimistic_locks,
+                        max_ts,
+                        &start_key,
+                        limit,
+                    ),
+                    None => Err(box_err!("physical scan lock is not supported")),
+                };
+                match res {
+                    Ok(locks) => resp.set_locks(RepeatedField::from_vec(locks)),
+                    Err(e) => resp.set_error(format!("{}", e)),
+                }
+                Ok::<_, Error>(resp)
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -330,42 +1211,29 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         ctx.spawn(future);
     }
 
-    fn kv_batch_get(
+    fn register_lock_observer(
         &self,
         ctx: RpcContext,
-        mut req: BatchGetRequest,
-        sink: UnarySink<BatchGetResponse>,
+        req: RegisterLockObserverRequest,
+        sink: UnarySink<RegisterLockObserverResponse>,
     ) {
-        let label = "kv_batchget";
+        let label = "register_lock_observer";
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
-
-        let keys = req.get_keys()
-            .into_iter()
-            .map(|x| Key::from_raw(x))
-            .collect();
-
-        let (cb, future) = make_callback();
-        let res = self.storage
-            .async_batch_get(req.take_context(), keys, req.get_version(), cb);
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
             return;
         }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
-        let future = future
-            .map_err(Error::from)
-            .map(|v| {
-                let mut resp = BatchGetResponse::new();
-                if let Some(err) = extract_region_error(&v) {
-                    resp.set_region_error(err);
-                } else {
-                    resp.set_pairs(RepeatedField::from_vec(extract_kv_pairs(v)))
-                }
-                resp
-            })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+        let mut resp = RegisterLockObserverResponse::new();
+        if let Err(e) = self.storage.register_lock_observer(req.get_max_ts()) {
+            resp.set_error(format!("{}", e));
+        }
+        let future = observer
+            .send(sink, resp)
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -375,42 +1243,33 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         ctx.spawn(future);
     }
 
-    fn kv_batch_rollback(
+    fn check_lock_observer(
         &self,
         ctx: RpcContext,
-        mut req: BatchRollbackRequest,
-        sink: UnarySink<BatchRollbackResponse>,
+        req: CheckLockObserverRequest,
+        sink: UnarySink<CheckLockObserverResponse>,
     ) {
-        let label = "kv_batch_rollback";
+        let label = "check_lock_observer";
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
-
-        let keys = req.get_keys()
-            .into_iter()
-            .map(|x| Key::from_raw(x))
-            .collect();
-
-        let (cb, future) = make_callback();
-        let res = self.storage
-            .async_rollback(req.take_context(), keys, req.get_start_version(), cb);
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
             return;
         }
-
-        let future = future
-            .map_err(Error::from)
-            .map(|v| {
-                let mut resp = BatchRollbackResponse::new();
-                if let Some(err) = extract_region_error(&v) {
-                    resp.set_region_error(err);
-                } else if let Err(e) = v {
-                    resp.set_error(extract_key_error(&e));
-                }
-                resp
-            })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+        let _tracker_guard = tracker::enter(&observer.tracker);
+
+        let mut resp = CheckLockObserverResponse::new();
+        match self.storage.check_lock_observer(req.get_max_ts()) {
+            Ok((is_clean, locks)) => {
+                resp.set_is_clean(is_clean);
+                resp.set_locks(RepeatedField::from_vec(locks));
+            }
+            Err(e) => resp.set_error(format!("{}", e)),
+        }
+        let future = observer
+            .send(sink, resp)
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -420,40 +1279,66 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         ctx.spawn(future);
     }
 
-    fn kv_scan_lock(
+    fn remove_lock_observer(
         &self,
         ctx: RpcContext,
-        mut req: ScanLockRequest,
-        sink: UnarySink<ScanLockResponse>,
+        req: RemoveLockObserverRequest,
+        sink: UnarySink<RemoveLockObserverResponse>,
     ) {
-        let label = "kv_scan_lock";
+        let label = "remove_lock_observer";
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
-        let (cb, future) = make_callback();
-        let res = self.storage
-            .async_scan_lock(req.take_context(), req.get_max_version(), cb);
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
+        let mut resp = RemoveLockObserverResponse::new();
+        if let Err(e) = self.storage.remove_lock_observer(req.get_max_ts()) {
+            resp.set_error(format!("{}", e));
+        }
+        let future = observer
+            .send(sink, resp)
+            .map(|_| timer.observe_duration())
+            .map_err(move |e| {
+                debug!("{} failed: {:?}", label, e);
+                GRPC_MSG_FAIL_COUNTER.with_label_values(&[label]).inc();
+            });
+
+        ctx.spawn(future);
+    }
+
+    fn raw_get(&self, ctx: RpcContext, mut req: RawGetRequest, sink: UnarySink<RawGetResponse>) {
+        let label = "raw_get";
+        let timer = GRPC_MSG_HISTOGRAM_VEC
+            .with_label_values(&[label])
+            .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
             return;
         }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
-        let future = future
-            .map_err(Error::from)
+        let future = self.storage.async_raw_get(req.take_context(), req.take_key());
+        let future = result_future(future)
             .map(|v| {
-                let mut resp = ScanLockResponse::new();
+                let mut resp = RawGetResponse::new();
                 if let Some(err) = extract_region_error(&v) {
                     resp.set_region_error(err);
                 } else {
                     match v {
-                        Ok(locks) => resp.set_locks(RepeatedField::from_vec(locks)),
-                        Err(e) => resp.set_error(extract_key_error(&e)),
+                        Ok(Some(val)) => resp.set_value(val),
+                        Ok(None) => {}
+                        Err(e) => resp.set_error(format!("{}", e)),
                     }
                 }
                 resp
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -463,42 +1348,35 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         ctx.spawn(future);
     }
 
-    fn kv_resolve_lock(
-        &self,
-        ctx: RpcContext,
-        mut req: ResolveLockRequest,
-        sink: UnarySink<ResolveLockResponse>,
-    ) {
-        let label = "kv_resolve_lock";
+    fn raw_scan(&self, ctx: RpcContext, mut req: RawScanRequest, sink: UnarySink<RawScanResponse>) {
+        let label = "raw_scan";
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
-
-        let commit_ts = match req.get_commit_version() {
-            0 => None,
-            x => Some(x),
-        };
-
-        let (cb, future) = make_callback();
-        let res = self.storage
-            .async_resolve_lock(req.take_context(), req.get_start_version(), commit_ts, cb);
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
             return;
         }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
-        let future = future
-            .map_err(Error::from)
+        let future = self.storage.async_raw_scan(
+            req.take_context(),
+            req.take_start_key(),
+            req.get_limit() as usize,
+            req.get_key_only(),
+        );
+        let future = result_future(future)
             .map(|v| {
-                let mut resp = ResolveLockResponse::new();
+                let mut resp = RawScanResponse::new();
                 if let Some(err) = extract_region_error(&v) {
                     resp.set_region_error(err);
-                } else if let Err(e) = v {
-                    resp.set_error(extract_key_error(&e));
+                } else {
+                    resp.set_kvs(RepeatedField::from_vec(extract_kv_pairs(v)));
                 }
                 resp
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -508,32 +1386,31 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         ctx.spawn(future);
     }
 
-    fn kv_gc(&self, ctx: RpcContext, mut req: GCRequest, sink: UnarySink<GCResponse>) {
-        let label = "kv_gc";
+    fn raw_put(&self, ctx: RpcContext, mut req: RawPutRequest, sink: UnarySink<RawPutResponse>) {
+        let label = "raw_put";
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
-
-        let (cb, future) = make_callback();
-        let res = self.storage
-            .async_gc(req.take_context(), req.get_safe_point(), cb);
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
             return;
         }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
-        let future = future
-            .map_err(Error::from)
+        let future = self.storage
+            .async_raw_put(req.take_context(), req.take_key(), req.take_value());
+        let future = result_future(future)
             .map(|v| {
-                let mut resp = GCResponse::new();
+                let mut resp = RawPutResponse::new();
                 if let Some(err) = extract_region_error(&v) {
                     resp.set_region_error(err);
                 } else if let Err(e) = v {
-                    resp.set_error(extract_key_error(&e));
+                    resp.set_error(format!("{}", e));
                 }
                 resp
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -543,33 +1420,27 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         ctx.spawn(future);
     }
 
-    fn kv_delete_range(
+    fn raw_delete(
         &self,
         ctx: RpcContext,
-        mut req: DeleteRangeRequest,
-        sink: UnarySink<DeleteRangeResponse>,
+        mut req: RawDeleteRequest,
+        sink: UnarySink<RawDeleteResponse>,
     ) {
-        let label = "kv_delete_range";
+        let label = "raw_delete";
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
-
-        let (cb, future) = make_callback();
-        let res = self.storage.async_delete_range(
-            req.take_context(),
-            Key::from_raw(req.get_start_key()),
-            Key::from_raw(req.get_end_key()),
-            cb,
-        );
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
             return;
         }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
-        let future = future
-            .map_err(Error::from)
+        let future = self.storage.async_raw_delete(req.take_context(), req.take_key());
+        let future = result_future(future)
             .map(|v| {
-                let mut resp = DeleteRangeResponse::new();
+                let mut resp = RawDeleteResponse::new();
                 if let Some(err) = extract_region_error(&v) {
                     resp.set_region_error(err);
                 } else if let Err(e) = v {
@@ -577,7 +1448,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                 }
                 resp
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -587,36 +1458,36 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         ctx.spawn(future);
     }
 
-    fn raw_get(&self, ctx: RpcContext, mut req: RawGetRequest, sink: UnarySink<RawGetResponse>) {
-        let label = "raw_get";
+    fn raw_batch_get(
+        &self,
+        ctx: RpcContext,
+        mut req: RawBatchGetRequest,
+        sink: UnarySink<RawBatchGetResponse>,
+    ) {
+        let label = "raw_batch_get";
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
-
-        let (cb, future) = make_callback();
-        let res = self.storage
-            .async_raw_get(req.take_context(), req.take_key(), cb);
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
             return;
         }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
-        let future = future
-            .map_err(Error::from)
+        let keys = req.take_keys().into_vec();
+        let future = self.storage.async_raw_batch_get(req.take_context(), keys);
+        let future = result_future(future)
             .map(|v| {
-                let mut resp = RawGetResponse::new();
+                let mut resp = RawBatchGetResponse::new();
                 if let Some(err) = extract_region_error(&v) {
                     resp.set_region_error(err);
                 } else {
-                    match v {
-                        Ok(Some(val)) => resp.set_value(val),
-                        Ok(None) => {}
-                        Err(e) => resp.set_error(format!("{}", e)),
-                    }
+                    resp.set_pairs(RepeatedField::from_vec(extract_kv_pairs(v)));
                 }
                 resp
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -626,36 +1497,39 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         ctx.spawn(future);
     }
 
-    fn raw_scan(&self, ctx: RpcContext, mut req: RawScanRequest, sink: UnarySink<RawScanResponse>) {
-        let label = "raw_scan";
+    fn raw_batch_put(
+        &self,
+        ctx: RpcContext,
+        mut req: RawBatchPutRequest,
+        sink: UnarySink<RawBatchPutResponse>,
+    ) {
+        let label = "raw_batch_put";
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
-
-        let (cb, future) = make_callback();
-        let res = self.storage.async_raw_scan(
-            req.take_context(),
-            req.take_start_key(),
-            req.get_limit() as usize,
-            cb,
-        );
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
             return;
         }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
-        let future = future
-            .map_err(Error::from)
+        let pairs = req.take_pairs()
+            .into_iter()
+            .map(|mut x| (x.take_key(), x.take_value()))
+            .collect();
+        let future = self.storage.async_raw_batch_put(req.take_context(), pairs);
+        let future = result_future(future)
             .map(|v| {
-                let mut resp = RawScanResponse::new();
+                let mut resp = RawBatchPutResponse::new();
                 if let Some(err) = extract_region_error(&v) {
                     resp.set_region_error(err);
-                } else {
-                    resp.set_kvs(RepeatedField::from_vec(extract_kv_pairs(v)));
+                } else if let Err(e) = v {
+                    resp.set_error(format!("{}", e));
                 }
                 resp
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -665,24 +1539,28 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         ctx.spawn(future);
     }
 
-    fn raw_put(&self, ctx: RpcContext, mut req: RawPutRequest, sink: UnarySink<RawPutResponse>) {
-        let label = "raw_put";
+    fn raw_batch_delete(
+        &self,
+        ctx: RpcContext,
+        mut req: RawBatchDeleteRequest,
+        sink: UnarySink<RawBatchDeleteResponse>,
+    ) {
+        let label = "raw_batch_delete";
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
-
-        let (cb, future) = make_callback();
-        let res = self.storage
-            .async_raw_put(req.take_context(), req.take_key(), req.take_value(), cb);
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
             return;
         }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
-        let future = future
-            .map_err(Error::from)
+        let keys = req.take_keys().into_vec();
+        let future = self.storage.async_raw_batch_delete(req.take_context(), keys);
+        let future = result_future(future)
             .map(|v| {
-                let mut resp = RawPutResponse::new();
+                let mut resp = RawBatchDeleteResponse::new();
                 if let Some(err) = extract_region_error(&v) {
                     resp.set_region_error(err);
                 } else if let Err(e) = v {
@@ -690,7 +1568,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                 }
                 resp
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -700,29 +1578,31 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         ctx.spawn(future);
     }
 
-    fn raw_delete(
+    fn raw_delete_range(
         &self,
         ctx: RpcContext,
-        mut req: RawDeleteRequest,
-        sink: UnarySink<RawDeleteResponse>,
+        mut req: RawDeleteRangeRequest,
+        sink: UnarySink<RawDeleteRangeResponse>,
     ) {
-        let label = "raw_delete";
+        let label = "raw_delete_range";
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
-
-        let (cb, future) = make_callback();
-        let res = self.storage
-            .async_raw_delete(req.take_context(), req.take_key(), cb);
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
             return;
         }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
-        let future = future
-            .map_err(Error::from)
+        let future = self.storage.async_raw_delete_range(
+            req.take_context(),
+            req.take_start_key(),
+            req.take_end_key(),
+        );
+        let future = result_future(future)
             .map(|v| {
-                let mut resp = RawDeleteResponse::new();
+                let mut resp = RawDeleteRangeResponse::new();
                 if let Some(err) = extract_region_error(&v) {
                     resp.set_region_error(err);
                 } else if let Err(e) = v {
@@ -730,7 +1610,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                 }
                 resp
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -745,6 +1625,12 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
         let (cb, future) = make_callback();
         let res = self.end_point_scheduler
@@ -756,7 +1642,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
 
         let future = future
             .map_err(Error::from)
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -839,19 +1725,18 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
+            return;
+        }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
         let storage = self.storage.clone();
 
         let key = Key::from_raw(req.get_key());
-        let (cb, future) = make_callback();
-        let res = storage.async_mvcc_by_key(req.take_context(), key.clone(), cb);
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
-            return;
-        }
-
-        let future = future
-            .map_err(Error::from)
+        let future = storage.async_mvcc_by_key(req.take_context(), key.clone());
+        let future = result_future(future)
             .map(|v| {
                 let mut resp = MvccGetByKeyResponse::new();
                 if let Some(err) = extract_region_error(&v) {
@@ -866,7 +1751,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                 }
                 resp
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -886,19 +1771,17 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .with_label_values(&[label])
             .start_coarse_timer();
-
-        let storage = self.storage.clone();
-
-        let (cb, future) = make_callback();
-
-        let res = storage.async_mvcc_by_start_ts(req.take_context(), req.get_start_ts(), cb);
-        if let Err(e) = res {
-            self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
+        let mut observer = MsgObserver::new(label, &req, &self.quota_limiter);
+        if let Err(e) = observer.check_quota() {
+            self.send_fail_status(ctx, sink, e, RpcStatusCode::ResourceExhausted);
             return;
         }
+        let _tracker_guard = tracker::enter(&observer.tracker);
 
-        let future = future
-            .map_err(Error::from)
+        let storage = self.storage.clone();
+
+        let future = storage.async_mvcc_by_start_ts(req.take_context(), req.get_start_ts());
+        let future = result_future(future)
             .map(|v| {
                 let mut resp = MvccGetByStartTsResponse::new();
                 if let Some(err) = extract_region_error(&v) {
@@ -917,7 +1800,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                 }
                 resp
             })
-            .and_then(|res| sink.success(res).map_err(Error::from))
+            .and_then(|res| observer.send(sink, res))
             .map(|_| timer.observe_duration())
             .map_err(move |e| {
                 debug!("{} failed: {:?}", label, e);
@@ -927,6 +1810,17 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
     }
 }
 
+fn extract_exec_details((d, scan_detail): (TimeDetail, ScanDetail)) -> ExecDetails {
+    let mut time_detail = KvTimeDetail::new();
+    time_detail.set_wait_wall_time_ms(duration_to_ms(d.wait));
+    time_detail.set_process_wall_time_ms(duration_to_ms(d.process));
+    time_detail.set_commit_wall_time_ms(duration_to_ms(d.commit));
+    let mut exec_details = ExecDetails::new();
+    exec_details.set_time_detail(time_detail);
+    exec_details.set_scan_detail(scan_detail);
+    exec_details
+}
+
 fn extract_region_error<T>(res: &storage::Result<T>) -> Option<RegionError> {
     use storage::Error;
     match *res {
@@ -934,12 +1828,19 @@ fn extract_region_error<T>(res: &storage::Result<T>) -> Option<RegionError> {
         Err(Error::Engine(EngineError::Request(ref e))) |
         Err(Error::Txn(TxnError::Engine(EngineError::Request(ref e)))) |
         Err(Error::Txn(TxnError::Mvcc(MvccError::Engine(EngineError::Request(ref e))))) => {
+            REQUEST_ERROR_COUNTER_VEC
+                .with_label_values(&["raftstore", storage::get_tag_from_header(e)])
+                .inc();
             Some(e.to_owned())
         }
-        Err(Error::SchedTooBusy) => {
+        Err(Error::SchedTooBusy(backoff_ms)) => {
+            REQUEST_ERROR_COUNTER_VEC
+                .with_label_values(&["storage", "server_is_busy"])
+                .inc();
             let mut err = RegionError::new();
             let mut server_is_busy_err = ServerIsBusy::new();
             server_is_busy_err.set_reason(SCHEDULER_IS_BUSY.to_owned());
+            server_is_busy_err.set_backoff_ms(backoff_ms);
             err.set_server_is_busy(server_is_busy_err);
             Some(err)
         }
@@ -954,7 +1855,32 @@ fn extract_committed(err: &storage::Error) -> Option<u64> {
     }
 }
 
+// Gets the error code of the errors which are returned as key errors.
+fn get_key_error_code(err: &storage::Error) -> &'static str {
+    match *err {
+        storage::Error::Txn(TxnError::Mvcc(ref e)) => match *e {
+            MvccError::KeyIsLocked { .. } => "key_is_locked",
+            MvccError::WriteConflict { .. } => "write_conflict",
+            MvccError::TxnLockNotFound { .. } => "txn_lock_not_found",
+            MvccError::Committed { .. } => "committed",
+            MvccError::CommitTsExpired { .. } => "commit_ts_expired",
+            MvccError::AlreadyExist { .. } => "already_exist",
+            MvccError::PessimisticLockRolledBack { .. } => "pessimistic_lock_rolled_back",
+            MvccError::Deadlock { .. } => "deadlock",
+            _ => "mvcc",
+        },
+        storage::Error::Txn(_) => "txn",
+        storage::Error::Engine(_) => "engine",
+        storage::Error::KeyTooLarge(..) => "key_too_large",
+        storage::Error::ValueTooLarge(..) => "value_too_large",
+        _ => "other",
+    }
+}
+
 fn extract_key_error(err: &storage::Error) -> KeyError {
+    REQUEST_ERROR_COUNTER_VEC
+        .with_label_values(&["txn", get_key_error_code(err)])
+        .inc();
     let mut key_error = KeyError::new();
     match *err {
         storage::Error::Txn(
@@ -972,11 +1898,36 @@ fn extract_key_error(err: &storage::Error) -> KeyError {
             lock_info.set_lock_ttl(ttl);
             key_error.set_locked(lock_info);
         }
+        storage::Error::Txn(TxnError::Mvcc(MvccError::AlreadyExist { ref key })) => {
+            let mut exist = AlreadyExist::new();
+            exist.set_key(key.clone());
+            key_error.set_already_exist(exist);
+        }
+        storage::Error::Txn(
+            TxnError::Mvcc(MvccError::Deadlock {
+                lock_ts,
+                ref key,
+                deadlock_key_hash,
+                ..
+            }),
+        ) => {
+            warn!("txn deadlocks: {:?}", err);
+            let mut deadlock = Deadlock::new();
+            deadlock.set_lock_ts(lock_ts);
+            deadlock.set_lock_key(key.to_owned());
+            deadlock.set_deadlock_key_hash(deadlock_key_hash);
+            key_error.set_deadlock(deadlock);
+        }
         storage::Error::Txn(TxnError::Mvcc(MvccError::WriteConflict { .. })) |
-        storage::Error::Txn(TxnError::Mvcc(MvccError::TxnLockNotFound { .. })) => {
+        storage::Error::Txn(TxnError::Mvcc(MvccError::TxnLockNotFound { .. })) |
+        storage::Error::Txn(TxnError::Mvcc(MvccError::CommitTsExpired { .. })) => {
             warn!("txn conflicts: {:?}", err);
             key_error.set_retryable(format!("{:?}", err));
         }
+        storage::Error::KeyTooLarge(..) | storage::Error::ValueTooLarge(..) => {
+            warn!("txn aborts: {}", err);
+            key_error.set_abort(format!("{}", err));
+        }
         _ => {
             error!("txn aborts: {:?}", err);
             key_error.set_abort(format!("{:?}", err));
@@ -12,5 +12,7 @@
 // limitations under the License.
 
 mod kv;
+mod debug;
 
 pub use self::kv::Service as KvService;
+pub use self::debug::Service as DebugService;
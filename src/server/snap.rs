@@ -16,9 +16,10 @@ use std::io;
 use std::iter::{self, Once};
 use std::net::SocketAddr;
 use std::boxed::FnBox;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::result;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
 
 use mio::Token;
 use futures::{stream, Future, Stream};
@@ -35,12 +36,13 @@ use util::collections::{HashMap, HashMapEntry as Entry};
 use util::HandyRwLock;
 
 use super::metrics::*;
-use super::{Error, Result};
+use super::{Config, Error, Result};
 use super::transport::RaftStoreRouter;
 
 pub type Callback = Box<FnBox(Result<()>) + Send>;
 
 const DEFAULT_SENDER_POOL_SIZE: usize = 3;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
 
 /// `Task` that `Runner` can handle.
 ///
@@ -75,9 +77,53 @@ impl Display for Task {
     }
 }
 
+/// `SendLimiter` bounds the bandwidth taken by all the snapshots being sent,
+/// so that a large snapshot can't starve the raft messages to the same store.
+struct SendLimiter {
+    // Bytes per second, 0 means no limit.
+    rate_limit: u64,
+    // The time when the bytes sent so far are paid off.
+    next_free: Mutex<Instant>,
+}
+
+impl SendLimiter {
+    fn new(rate_limit: u64) -> SendLimiter {
+        SendLimiter {
+            rate_limit: rate_limit,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Records that `bytes` are going to be sent, and returns how long the caller
+    /// should wait until the bytes sent before are paid off.
+    fn reserve(&self, bytes: usize) -> Duration {
+        if self.rate_limit == 0 || bytes == 0 {
+            return Duration::from_millis(0);
+        }
+        let nanos = bytes as u64 * NANOS_PER_SEC / self.rate_limit;
+        let cost = Duration::new(nanos / NANOS_PER_SEC, (nanos % NANOS_PER_SEC) as u32);
+        let mut next_free = self.next_free.lock().unwrap();
+        let now = Instant::now();
+        if *next_free < now {
+            *next_free = now;
+        }
+        let wait = *next_free - now;
+        *next_free += cost;
+        wait
+    }
+
+    fn consume(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+        if wait > Duration::from_millis(0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
 struct SnapChunk {
     snap: Arc<RwLock<Box<Snapshot>>>,
     remain_bytes: usize,
+    limiter: Arc<SendLimiter>,
 }
 
 const SNAP_CHUNK_LEN: usize = 1024 * 1024;
@@ -94,6 +140,7 @@ impl Iterator for SnapChunk {
         match self.snap.wl().read_exact(buf.as_mut_slice()) {
             Ok(_) => {
                 self.remain_bytes -= buf.len();
+                self.limiter.consume(buf.len());
                 Some(Ok(buf))
             }
             Err(e) => Some(Err(e)),
@@ -107,10 +154,13 @@ impl Iterator for SnapChunk {
 fn send_snap(
     env: Arc<Environment>,
     mgr: SnapManager,
+    limiter: Arc<SendLimiter>,
+    window_size: usize,
     addr: SocketAddr,
     msg: RaftMessage,
 ) -> Result<()> {
     assert!(msg.get_message().has_snapshot());
+    fail_point!("send_snapshot_drop", |_| Err(box_err!("snapshot is dropped by fail point")));
     let timer = Instant::now();
 
     let send_timer = SEND_SNAP_HISTOGRAM.start_coarse_timer();
@@ -136,6 +186,7 @@ fn send_snap(
         let snap_chunk = SnapChunk {
             snap: s.clone(),
             remain_bytes: total_size as usize,
+            limiter: limiter,
         };
         let first: Once<Result<(SnapshotChunk, _)>> = iter::once({
             let mut chunk = SnapshotChunk::new();
@@ -152,7 +203,11 @@ fn send_snap(
         first.chain(rests)
     };
 
-    let channel = ChannelBuilder::new(env).connect(&format!("{}", addr));
+    // The args differ from the ones of the raft client, so the snapshot is sent on its
+    // own connection and a large transfer can't hold back the heartbeats.
+    let channel = ChannelBuilder::new(env)
+        .stream_initial_window_size(window_size)
+        .connect(&format!("{}", addr));
     let client = TikvClient::new(channel);
     let (sink, receiver) = client.snapshot();
     let send = stream::iter(chunks.into_iter()).forward(sink);
@@ -180,11 +235,13 @@ pub struct Runner<R: RaftStoreRouter + 'static> {
     snap_mgr: SnapManager,
     files: HashMap<Token, (Box<Snapshot>, RaftMessage)>,
     pool: ThreadPool<DefaultContext>,
+    limiter: Arc<SendLimiter>,
+    window_size: usize,
     raft_router: R,
 }
 
 impl<R: RaftStoreRouter + 'static> Runner<R> {
-    pub fn new(env: Arc<Environment>, snap_mgr: SnapManager, r: R) -> Runner<R> {
+    pub fn new(env: Arc<Environment>, snap_mgr: SnapManager, r: R, cfg: &Config) -> Runner<R> {
         Runner {
             env: env,
             snap_mgr: snap_mgr,
@@ -192,6 +249,8 @@ impl<R: RaftStoreRouter + 'static> Runner<R> {
             pool: ThreadPoolBuilder::with_default_factory(thd_name!("snap sender"))
                 .thread_count(DEFAULT_SENDER_POOL_SIZE)
                 .build(),
+            limiter: Arc::new(SendLimiter::new(cfg.snap_max_write_bytes_per_sec.0)),
+            window_size: cfg.snap_stream_initial_window_size.0 as usize,
             raft_router: r,
         }
     }
@@ -296,8 +355,10 @@ impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
                 SNAP_TASK_COUNTER.with_label_values(&["send"]).inc();
                 let env = self.env.clone();
                 let mgr = self.snap_mgr.clone();
+                let limiter = self.limiter.clone();
+                let window_size = self.window_size;
                 self.pool.execute(move |_| {
-                    let res = send_snap(env, mgr, addr, msg);
+                    let res = send_snap(env, mgr, limiter, window_size, addr, msg);
                     if res.is_err() {
                         error!("failed to send snap to {}: {:?}", addr, res);
                     }
@@ -307,3 +368,25 @@ impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::SendLimiter;
+
+    #[test]
+    fn test_send_limiter() {
+        let limiter = SendLimiter::new(0);
+        assert_eq!(limiter.reserve(1024), Duration::from_millis(0));
+
+        let limiter = SendLimiter::new(1024);
+        assert_eq!(limiter.reserve(0), Duration::from_millis(0));
+        assert_eq!(limiter.reserve(512), Duration::from_millis(0));
+        // The first 512 bytes take half a second to be paid off.
+        let wait = limiter.reserve(1024);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+        let wait = limiter.reserve(1024);
+        assert!(wait > Duration::from_millis(1400) && wait <= Duration::from_millis(1500));
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use util::time::Instant;
+use super::Result;
+
+/// `CausalTsProvider` provides the timestamps ordering the raw writes. The
+/// timestamps are monotonic, and greater than all the ones provided before
+/// by any store, so a write is ordered after the writes it depends on.
+pub trait CausalTsProvider: Send + Sync {
+    fn get_ts(&self) -> Result<u64>;
+}
+
+// The timestamps in `[next, end]` are not provided yet.
+struct TsBatch {
+    next: u64,
+    end: u64,
+    // When the batch is allocated from the oracle.
+    allocated: Instant,
+}
+
+/// `BatchTsoProvider` allocates the timestamps from the timestamp oracle in
+/// batches, so most of them are provided without asking the oracle. Others
+/// keep taking larger timestamps from the oracle, so a batch is renewed once
+/// it's older than `renew_interval`, or a write acknowledged by another store
+/// long before could be ordered after the ones provided from it.
+pub struct BatchTsoProvider {
+    batch_size: u32,
+    renew_interval: Duration,
+    // Allocates `count` continuous timestamps, returns the last one of them.
+    tso: Box<Fn(u32) -> Result<u64> + Send + Sync>,
+    batch: Mutex<TsBatch>,
+}
+
+impl BatchTsoProvider {
+    pub fn new<F>(batch_size: u32, renew_interval: Duration, tso: F) -> BatchTsoProvider
+    where
+        F: Fn(u32) -> Result<u64> + Send + Sync + 'static,
+    {
+        assert!(batch_size > 0);
+        BatchTsoProvider {
+            batch_size: batch_size,
+            renew_interval: renew_interval,
+            tso: box tso,
+            batch: Mutex::new(TsBatch {
+                next: 1,
+                end: 0,
+                allocated: Instant::now_coarse(),
+            }),
+        }
+    }
+}
+
+impl CausalTsProvider for BatchTsoProvider {
+    fn get_ts(&self) -> Result<u64> {
+        let mut batch = self.batch.lock().unwrap();
+        if batch.next > batch.end || batch.allocated.elapsed() >= self.renew_interval {
+            let last = try!((self.tso)(self.batch_size));
+            batch.next = last + 1 - u64::from(self.batch_size);
+            batch.end = last;
+            batch.allocated = Instant::now_coarse();
+        }
+        let ts = batch.next;
+        batch.next += 1;
+        Ok(ts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn test_batch_tso_provider() {
+        let tso = Arc::new(AtomicUsize::new(100));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (tso1, requests1) = (tso.clone(), requests.clone());
+        let provider = BatchTsoProvider::new(10, Duration::from_secs(60), move |count| {
+            requests1.fetch_add(1, Ordering::SeqCst);
+            Ok((tso1.fetch_add(count as usize, Ordering::SeqCst) + count as usize) as u64)
+        });
+
+        for ts in 101..111 {
+            assert_eq!(provider.get_ts().unwrap(), ts);
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        // Others take timestamps from the oracle too.
+        tso.fetch_add(5, Ordering::SeqCst);
+        assert_eq!(provider.get_ts().unwrap(), 116);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_renew_batch() {
+        let tso = Arc::new(AtomicUsize::new(100));
+        let tso1 = tso.clone();
+        let provider = BatchTsoProvider::new(10, Duration::from_millis(0), move |count| {
+            Ok((tso1.fetch_add(count as usize, Ordering::SeqCst) + count as usize) as u64)
+        });
+
+        // The batch is renewed once it's too old, the timestamps taken by others
+        // from the oracle before are never provided later.
+        assert_eq!(provider.get_ts().unwrap(), 101);
+        tso.fetch_add(5, Ordering::SeqCst);
+        assert_eq!(provider.get_ts().unwrap(), 116);
+        assert_eq!(provider.get_ts().unwrap(), 126);
+    }
+}
@@ -15,27 +15,72 @@ use std::error::Error;
 
 use sys_info;
 
-use util::config;
+use util::config::{self, ReadableDuration, ReadableSize};
 
 pub const DEFAULT_DATA_DIR: &'static str = "";
 pub const DEFAULT_ROCKSDB_SUB_DIR: &'static str = "db";
 const DEFAULT_GC_RATIO_THRESHOLD: f64 = 1.1;
+const DEFAULT_GC_POLL_SAFE_POINT_INTERVAL_SECS: u64 = 60;
 const DEFAULT_SCHED_CAPACITY: usize = 10240;
 const DEFAULT_SCHED_MSG_PER_TICK: usize = 1024;
 const DEFAULT_SCHED_CONCURRENCY: usize = 102400;
 const DEFAULT_SCHED_TOO_BUSY_THRESHOLD: usize = 1000;
+const DEFAULT_SCHED_PENDING_WRITE_MB: u64 = 100;
+const DEFAULT_SLOW_LOG_THRESHOLD_SECS: u64 = 1;
+const DEFAULT_WAKE_UP_DELAY_MS: u64 = 20;
+const DEFAULT_MAX_WAITERS_PER_KEY: usize = 16;
+const DEFAULT_MAX_KEY_SIZE_KB: u64 = 4;
+const DEFAULT_MAX_VALUE_SIZE_MB: u64 = 6;
+const DEFAULT_CAUSAL_TS_RENEW_INTERVAL_MS: u64 = 100;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub data_dir: String,
     pub gc_ratio_threshold: f64,
+    // The stale versions in the write cf are dropped by the compactions after
+    // the gc safe point, instead of being deleted by the gc commands.
+    pub gc_by_compaction_filter: bool,
+    // The gc safe point is polled from pd at the interval, and the regions are
+    // gc'ed by the store itself once it's advanced, 0 disables it.
+    pub gc_poll_safe_point_interval: ReadableDuration,
     pub scheduler_notify_capacity: usize,
     pub scheduler_messages_per_tick: usize,
     pub scheduler_concurrency: usize,
     pub scheduler_worker_pool_size: usize,
     pub scheduler_too_busy_threshold: usize,
+    // New writes are rejected as busy when the bytes of the pending writes
+    // exceed it.
+    pub scheduler_pending_write_threshold: ReadableSize,
+    // The bytes per second written by the foreground writes, 0 means no
+    // limit.
+    pub scheduler_write_bandwidth_limit: ReadableSize,
+    // Commands taking longer than the thresholds are logged as slow.
+    pub slow_point_read_threshold: ReadableDuration,
+    pub slow_scan_threshold: ReadableDuration,
+    pub slow_write_threshold: ReadableDuration,
+    // Prewrites wait for the conflicting locks to be released for at most
+    // the timeout before returning the locks, 0 disables waiting.
+    pub wait_for_lock_timeout: ReadableDuration,
+    // The waiters except the first one are woken up after the delay and a
+    // random jitter when a lock is released.
+    pub wake_up_delay_duration: ReadableDuration,
+    pub max_waiters_per_key: usize,
+    // The pessimistic locks of the leader regions are kept in memory instead
+    // of being written through raft, they are lost if the leader crashes.
+    pub in_memory_pessimistic_locks: bool,
+    // Writes with larger keys or values are rejected, the keys of the
+    // transactional writes are counted after being encoded.
+    pub max_key_size: ReadableSize,
+    pub max_value_size: ReadableSize,
+    // The timestamps ordering the raw writes are allocated from pd in batches
+    // of the size, 0 disables them. A batch is renewed once it's older than
+    // the interval, so a timestamp taken by another store before is never
+    // provided later.
+    pub causal_ts_batch_size: u32,
+    pub causal_ts_renew_interval: ReadableDuration,
 }
 
 impl Default for Config {
@@ -44,11 +89,30 @@ impl Default for Config {
         Config {
             data_dir: DEFAULT_DATA_DIR.to_owned(),
             gc_ratio_threshold: DEFAULT_GC_RATIO_THRESHOLD,
+            gc_by_compaction_filter: false,
+            gc_poll_safe_point_interval: ReadableDuration::secs(
+                DEFAULT_GC_POLL_SAFE_POINT_INTERVAL_SECS,
+            ),
             scheduler_notify_capacity: DEFAULT_SCHED_CAPACITY,
             scheduler_messages_per_tick: DEFAULT_SCHED_MSG_PER_TICK,
             scheduler_concurrency: DEFAULT_SCHED_CONCURRENCY,
             scheduler_worker_pool_size: if total_cpu >= 16 { 8 } else { 4 },
             scheduler_too_busy_threshold: DEFAULT_SCHED_TOO_BUSY_THRESHOLD,
+            scheduler_pending_write_threshold: ReadableSize::mb(DEFAULT_SCHED_PENDING_WRITE_MB),
+            scheduler_write_bandwidth_limit: ReadableSize(0),
+            slow_point_read_threshold: ReadableDuration::secs(DEFAULT_SLOW_LOG_THRESHOLD_SECS),
+            slow_scan_threshold: ReadableDuration::secs(DEFAULT_SLOW_LOG_THRESHOLD_SECS),
+            slow_write_threshold: ReadableDuration::secs(DEFAULT_SLOW_LOG_THRESHOLD_SECS),
+            wait_for_lock_timeout: ReadableDuration::secs(0),
+            wake_up_delay_duration: ReadableDuration::millis(DEFAULT_WAKE_UP_DELAY_MS),
+            max_waiters_per_key: DEFAULT_MAX_WAITERS_PER_KEY,
+            in_memory_pessimistic_locks: false,
+            max_key_size: ReadableSize::kb(DEFAULT_MAX_KEY_SIZE_KB),
+            max_value_size: ReadableSize::mb(DEFAULT_MAX_VALUE_SIZE_MB),
+            causal_ts_batch_size: 0,
+            causal_ts_renew_interval: ReadableDuration::millis(
+                DEFAULT_CAUSAL_TS_RENEW_INTERVAL_MS,
+            ),
         }
     }
 }
@@ -58,6 +122,30 @@ impl Config {
         if self.data_dir != DEFAULT_DATA_DIR {
             self.data_dir = try!(config::canonicalize_path(&self.data_dir))
         }
+        if self.gc_ratio_threshold.is_nan() || self.gc_ratio_threshold < 0.0 {
+            return Err(format!(
+                "storage.gc-ratio-threshold should be non-negative, got {}",
+                self.gc_ratio_threshold
+            ).into());
+        }
+        for &(name, value) in &[
+            ("scheduler-notify-capacity", self.scheduler_notify_capacity),
+            ("scheduler-messages-per-tick", self.scheduler_messages_per_tick),
+            ("scheduler-concurrency", self.scheduler_concurrency),
+            ("scheduler-worker-pool-size", self.scheduler_worker_pool_size),
+            ("scheduler-too-busy-threshold", self.scheduler_too_busy_threshold),
+            (
+                "scheduler-pending-write-threshold",
+                self.scheduler_pending_write_threshold.0 as usize,
+            ),
+            ("max-waiters-per-key", self.max_waiters_per_key),
+            ("max-key-size", self.max_key_size.0 as usize),
+            ("max-value-size", self.max_value_size.0 as usize),
+        ] {
+            if value == 0 {
+                return Err(format!("storage.{} should not be 0", name).into());
+            }
+        }
         Ok(())
     }
 }
@@ -20,7 +20,7 @@ use std::time::Duration;
 pub use self::rocksdb::EngineRocksdb;
 use rocksdb::TablePropertiesCollection;
 use storage::{CfName, Key, Value, CF_DEFAULT, CF_LOCK, CF_WRITE};
-use kvproto::kvrpcpb::Context;
+use kvproto::kvrpcpb::{Context, ScanDetail, ScanInfo};
 use kvproto::errorpb::Error as ErrorHeader;
 
 mod rocksdb;
@@ -206,6 +206,13 @@ impl CFStatistics {
         ]
     }
 
+    pub fn scan_info(&self) -> ScanInfo {
+        let mut info = ScanInfo::new();
+        info.set_processed(self.processed as i64);
+        info.set_total(self.total_op_count() as i64);
+        info
+    }
+
     pub fn add(&mut self, other: &Self) {
         self.processed = self.processed.saturating_add(other.processed);
         self.get = self.get.saturating_add(other.get);
@@ -240,6 +247,16 @@ impl Statistics {
         ]
     }
 
+    /// Returns the key counts of the column families, which are sent back to
+    /// the client for diagnosis.
+    pub fn scan_detail(&self) -> ScanDetail {
+        let mut detail = ScanDetail::new();
+        detail.set_data(self.data.scan_info());
+        detail.set_lock(self.lock.scan_info());
+        detail.set_write(self.write.scan_info());
+        detail
+    }
+
     pub fn add(&mut self, other: &Self) {
         self.lock.add(&other.lock);
         self.write.add(&other.write);
@@ -0,0 +1,377 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
+
+use futures::Future;
+use kvproto::kvrpcpb::{Context, LockInfo};
+use kvproto::metapb;
+use rocksdb::{ReadOptions, SeekKey, Writable, WriteBatch, DB};
+
+use pd::PdClient;
+use raftstore::store::keys;
+use util::rocksdb as rocksdb_util;
+use super::{Error, Key, PessimisticLockTable, Result, Storage, CF_LOCK, DATA_CFS};
+use super::mvcc::{Error as MvccError, Lock};
+use super::pessimistic_locks::merge_locks;
+
+/// `GcManager` polls the gc safe point from pd in the background. Once the
+/// safe point is advanced, it walks all the regions and runs gc on the ones
+/// with a peer on the store, so gc keeps progressing even if no client sends
+/// the gc requests.
+///
+/// The gc of a region whose peer on the store isn't the leader fails and is
+/// skipped, the leader's store takes care of it.
+pub struct GcManager<C: PdClient + 'static> {
+    store_id: u64,
+    interval: Duration,
+    storage: Storage,
+    pd_client: Arc<C>,
+    handle: Option<JoinHandle<()>>,
+    sender: Option<Sender<bool>>,
+}
+
+impl<C: PdClient + 'static> GcManager<C> {
+    pub fn new(
+        store_id: u64,
+        interval: Duration,
+        storage: Storage,
+        pd_client: Arc<C>,
+    ) -> GcManager<C> {
+        GcManager {
+            store_id: store_id,
+            interval: interval,
+            storage: storage,
+            pd_client: pd_client,
+            handle: None,
+            sender: None,
+        }
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let mut runner = GcRunner {
+            store_id: self.store_id,
+            storage: self.storage.clone(),
+            pd_client: self.pd_client.clone(),
+            stop: rx,
+            safe_point: 0,
+        };
+        let interval = self.interval;
+        self.sender = Some(tx);
+        let h = try!(
+            Builder::new()
+                .name(thd_name!("gc-manager"))
+                .spawn(move || {
+                    while let Err(mpsc::RecvTimeoutError::Timeout) =
+                        runner.stop.recv_timeout(interval)
+                    {
+                        runner.poll();
+                    }
+                })
+        );
+
+        self.handle = Some(h);
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {
+        let h = self.handle.take();
+        if h.is_none() {
+            return;
+        }
+        drop(self.sender.take().unwrap());
+        if let Err(e) = h.unwrap().join() {
+            error!("join gc manager failed {:?}", e);
+        }
+    }
+}
+
+struct GcRunner<C: PdClient + 'static> {
+    store_id: u64,
+    storage: Storage,
+    pd_client: Arc<C>,
+    stop: Receiver<bool>,
+    // The safe point that all the regions have been gc'ed with.
+    safe_point: u64,
+}
+
+impl<C: PdClient + 'static> GcRunner<C> {
+    fn poll(&mut self) {
+        let safe_point = match self.pd_client.get_gc_safe_point().wait() {
+            Ok(safe_point) => safe_point,
+            Err(e) => {
+                error!("failed to get gc safe point: {:?}", e);
+                return;
+            }
+        };
+        if safe_point <= self.safe_point {
+            return;
+        }
+        info!(
+            "gc safe point is advanced from {} to {}, start to gc regions",
+            self.safe_point, safe_point
+        );
+        match self.gc_regions(safe_point) {
+            Ok(Some(count)) => {
+                info!("gc {} regions with safe point {}", count, safe_point);
+                self.safe_point = safe_point;
+            }
+            // Stopped before all the regions are gc'ed.
+            Ok(None) => {}
+            // The regions are walked again with the safe point in the next poll.
+            Err(e) => error!("failed to gc regions with safe point {}: {:?}", safe_point, e),
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        match self.stop.try_recv() {
+            Err(TryRecvError::Empty) => false,
+            _ => true,
+        }
+    }
+
+    // Returns the number of the regions gc'ed, or `None` if it's stopped.
+    fn gc_regions(&self, safe_point: u64) -> Result<Option<usize>> {
+        let mut count = 0;
+        let mut key = vec![];
+        loop {
+            if self.stopped() {
+                return Ok(None);
+            }
+            let region = try!(
+                self.pd_client
+                    .get_region(&key)
+                    .map_err(|e| Error::Other(box e))
+            );
+            if self.gc_region(&region, safe_point) {
+                count += 1;
+            }
+            if region.get_end_key().is_empty() {
+                return Ok(Some(count));
+            }
+            key = region.get_end_key().to_vec();
+        }
+    }
+
+    fn gc_region(&self, region: &metapb::Region, safe_point: u64) -> bool {
+        let peer = match region
+            .get_peers()
+            .iter()
+            .find(|p| p.get_store_id() == self.store_id)
+        {
+            Some(peer) => peer.clone(),
+            None => return false,
+        };
+        let mut ctx = Context::new();
+        ctx.set_region_id(region.get_id());
+        ctx.set_region_epoch(region.get_region_epoch().clone());
+        ctx.set_peer(peer);
+        match self.storage.async_gc(ctx, safe_point).wait() {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("skip gc of region {}: {:?}", region.get_id(), e);
+                false
+            }
+        }
+    }
+}
+
+/// Removes all the data in `[start_key, end_key)` on the local store
+/// physically, without going through raft or the mvcc layer. The sst files in
+/// the range are dropped directly, the rest is removed by delete range. It's
+/// only safe if the range won't be read or written any more, like the data of
+/// a dropped table.
+pub fn unsafe_destroy_range(db: &DB, start_key: &Key, end_key: &Key) -> Result<()> {
+    let start_key = keys::data_key(start_key.encoded());
+    let end_key = keys::data_end_key(end_key.encoded());
+    if start_key >= end_key {
+        return Ok(());
+    }
+
+    for cf in DATA_CFS {
+        try!(
+            destroy_range_cf(db, cf, &start_key, &end_key).map_err(|e| Error::Other(e.into()))
+        );
+    }
+    Ok(())
+}
+
+fn destroy_range_cf(
+    db: &DB,
+    cf: &str,
+    start_key: &[u8],
+    end_key: &[u8],
+) -> ::std::result::Result<(), String> {
+    let handle = try!(rocksdb_util::get_cf_handle(db, cf));
+    // The files are dropped with `end_key` included. The keys in the other cfs
+    // are appended with timestamps so none of them equals `end_key`, while a
+    // lock of `end_key` may be dropped by mistake.
+    if cf != CF_LOCK {
+        try!(db.delete_file_in_range_cf(handle, start_key, end_key));
+    }
+    let wb = WriteBatch::new();
+    try!(wb.delete_range_cf(handle, start_key, end_key));
+    db.write(wb)
+}
+
+/// Scans the locks from `start_key` in the lock cf of the local store
+/// directly, and returns the ones whose `ts <= max_ts`. Unlike `scan_lock`,
+/// it covers all the regions on the store in one pass, the locks of the
+/// regions whose peers on the store aren't up to date may be missing or stale.
+/// The pessimistic locks kept in memory by the leader regions are merged.
+/// `limit` is the max number of the locks returned, 0 means no limit.
+pub fn physical_scan_lock(
+    db: &DB,
+    pessimistic_locks: &PessimisticLockTable,
+    max_ts: u64,
+    start_key: &Key,
+    limit: usize,
+) -> Result<Vec<LockInfo>> {
+    let handle = try!(rocksdb_util::get_cf_handle(db, CF_LOCK).map_err(|e| Error::Other(e.into())));
+    let mut opts = ReadOptions::new();
+    opts.fill_cache(false);
+    opts.set_iterate_upper_bound(keys::DATA_MAX_KEY);
+    let mut it = db.iter_cf_opt(handle, opts);
+    let mut locks = vec![];
+    it.seek(SeekKey::Key(&keys::data_key(start_key.encoded())));
+    while it.valid() {
+        if limit > 0 && locks.len() >= limit {
+            break;
+        }
+        let lock = try!(Lock::parse(it.value()));
+        if lock.ts <= max_ts {
+            let key = Key::from_encoded(keys::origin_key(it.key()).to_vec());
+            locks.push((key, lock));
+        }
+        it.next();
+    }
+    let in_memory = try!(pessimistic_locks.scan_locks(
+        None,
+        start_key.encoded(),
+        b"",
+        |lock| lock.ts <= max_ts,
+    ));
+    let mut locks = merge_locks(locks, in_memory);
+    if limit > 0 {
+        locks.truncate(limit);
+    }
+    let mut lock_infos = Vec::with_capacity(locks.len());
+    for (key, lock) in locks {
+        let mut lock_info = LockInfo::new();
+        lock_info.set_primary_lock(lock.primary);
+        lock_info.set_lock_version(lock.ts);
+        lock_info.set_key(try!(key.raw().map_err(MvccError::from)));
+        lock_infos.push(lock_info);
+    }
+    Ok(lock_infos)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use kvproto::metapb::Region;
+    use raft::StateRole;
+
+    use raftstore::coprocessor::{ObserverContext, RegionObserver};
+    use storage::{Modify, ALL_CFS, CF_DEFAULT, CF_WRITE};
+    use storage::mvcc::LockType;
+    use super::*;
+
+    #[test]
+    fn test_unsafe_destroy_range() {
+        let path = TempDir::new("test_unsafe_destroy_range").unwrap();
+        let db = rocksdb_util::new_engine(path.path().to_str().unwrap(), ALL_CFS).unwrap();
+        let raw_keys: Vec<&[u8]> = vec![b"a", b"b", b"c", b"d"];
+        for cf in &[CF_DEFAULT, CF_WRITE] {
+            let handle = rocksdb_util::get_cf_handle(&db, cf).unwrap();
+            for k in &raw_keys {
+                let k = keys::data_key(Key::from_raw(k).append_ts(10).encoded());
+                db.put_cf(handle, &k, b"v").unwrap();
+            }
+            db.flush_cf(handle, true).unwrap();
+        }
+        let handle = rocksdb_util::get_cf_handle(&db, CF_LOCK).unwrap();
+        for k in &raw_keys {
+            let k = keys::data_key(Key::from_raw(k).encoded());
+            db.put_cf(handle, &k, b"v").unwrap();
+        }
+
+        unsafe_destroy_range(&db, &Key::from_raw(b"b"), &Key::from_raw(b"d")).unwrap();
+        for cf in &[CF_DEFAULT, CF_WRITE] {
+            let handle = rocksdb_util::get_cf_handle(&db, cf).unwrap();
+            for k in &raw_keys {
+                let exist = *k == b"a" || *k == b"d";
+                let k = keys::data_key(Key::from_raw(k).append_ts(10).encoded());
+                assert_eq!(db.get_cf(handle, &k).unwrap().is_some(), exist);
+            }
+        }
+        for k in &raw_keys {
+            let exist = *k == b"a" || *k == b"d";
+            let k = keys::data_key(Key::from_raw(k).encoded());
+            assert_eq!(db.get_cf(handle, &k).unwrap().is_some(), exist);
+        }
+
+        // An empty end key means the end of the keys.
+        unsafe_destroy_range(&db, &Key::from_raw(b""), &Key::from_encoded(vec![])).unwrap();
+        for k in &raw_keys {
+            let k = keys::data_key(Key::from_raw(k).encoded());
+            assert!(db.get_cf(handle, &k).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_physical_scan_lock() {
+        let path = TempDir::new("test_physical_scan_lock").unwrap();
+        let db = rocksdb_util::new_engine(path.path().to_str().unwrap(), ALL_CFS).unwrap();
+        let handle = rocksdb_util::get_cf_handle(&db, CF_LOCK).unwrap();
+        // (key, ts)
+        let locks = vec![(b"a", 10), (b"b", 20), (b"c", 10), (b"d", 5)];
+        for &(key, ts) in &locks {
+            let lock = Lock::new(LockType::Put, b"a".to_vec(), ts, 0, None);
+            let k = keys::data_key(Key::from_raw(key).encoded());
+            db.put_cf(handle, &k, &lock.to_bytes()).unwrap();
+        }
+
+        let table = PessimisticLockTable::new();
+        let check = |max_ts, start_key: &[u8], limit, expect: Vec<&[u8]>| {
+            let start_key = Key::from_raw(start_key);
+            let res = physical_scan_lock(&db, &table, max_ts, &start_key, limit).unwrap();
+            let keys: Vec<_> = res.iter().map(|l| l.get_key()).collect();
+            assert_eq!(keys, expect);
+            for l in &res {
+                assert!(l.get_lock_version() <= max_ts);
+                assert_eq!(l.get_primary_lock(), b"a");
+            }
+        };
+        check(10, b"", 0, vec![&b"a"[..], b"c", b"d"]);
+        check(10, b"b", 0, vec![&b"c"[..], b"d"]);
+        check(20, b"", 2, vec![&b"a"[..], b"b"]);
+        check(1, b"", 0, vec![]);
+
+        // The pessimistic locks kept in memory are merged.
+        let region = Region::new();
+        table.on_role_change(&mut ObserverContext::new(&region), StateRole::Leader);
+        let lock = Lock::new(LockType::Pessimistic, b"a".to_vec(), 10, 0, None);
+        let modifies = vec![Modify::Put(CF_LOCK, Key::from_raw(b"bb"), lock.to_bytes())];
+        assert!(table.apply_modifies(0, table.generation(0), modifies).is_empty());
+        check(10, b"", 0, vec![&b"a"[..], b"bb", b"c", b"d"]);
+        check(10, b"b", 2, vec![&b"bb"[..], b"c"]);
+        check(5, b"", 0, vec![&b"d"[..]]);
+    }
+}
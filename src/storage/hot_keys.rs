@@ -0,0 +1,188 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use util::collections::HashMap;
+
+use super::Key;
+
+// The number of keys tracked for each kind of traffic.
+const HOT_KEY_CAPACITY: usize = 256;
+// Counts are halved periodically so that the keys which are not hot any more
+// can be evicted.
+const HOT_KEY_DECAY_INTERVAL_SECS: u64 = 60;
+
+lazy_static! {
+    static ref HOT_READ_KEYS: Mutex<HotKeyCounter> =
+        Mutex::new(HotKeyCounter::new(HOT_KEY_CAPACITY));
+    static ref HOT_WRITE_KEYS: Mutex<HotKeyCounter> =
+        Mutex::new(HotKeyCounter::new(HOT_KEY_CAPACITY));
+}
+
+/// `HotKeyCounter` finds the most frequent keys approximately with the
+/// space-saving algorithm: at most `capacity` keys are tracked, and a new key
+/// replaces the one with the minimal count and inherits the count.
+pub struct HotKeyCounter {
+    counts: HashMap<Vec<u8>, u64>,
+    capacity: usize,
+    decay_interval: Duration,
+    last_decay: Instant,
+}
+
+impl HotKeyCounter {
+    pub fn new(capacity: usize) -> HotKeyCounter {
+        HotKeyCounter::with_decay_interval(
+            capacity,
+            Duration::from_secs(HOT_KEY_DECAY_INTERVAL_SECS),
+        )
+    }
+
+    pub fn with_decay_interval(capacity: usize, decay_interval: Duration) -> HotKeyCounter {
+        HotKeyCounter {
+            counts: HashMap::default(),
+            capacity: capacity,
+            decay_interval: decay_interval,
+            last_decay: Instant::now(),
+        }
+    }
+
+    fn maybe_decay(&mut self) {
+        if self.last_decay.elapsed() < self.decay_interval {
+            return;
+        }
+        self.last_decay = Instant::now();
+        for count in self.counts.values_mut() {
+            *count /= 2;
+        }
+        self.counts.retain(|_, count| *count > 0);
+    }
+
+    pub fn record(&mut self, key: &[u8]) {
+        self.maybe_decay();
+        if let Some(count) = self.counts.get_mut(key) {
+            *count += 1;
+            return;
+        }
+        if self.counts.len() < self.capacity {
+            self.counts.insert(key.to_vec(), 1);
+            return;
+        }
+        let (min_key, min_count) = match self.counts.iter().min_by_key(|&(_, c)| *c) {
+            Some((k, c)) => (k.clone(), *c),
+            None => return,
+        };
+        self.counts.remove(&min_key);
+        self.counts.insert(key.to_vec(), min_count + 1);
+    }
+
+    /// Gets the top `limit` keys and their counts in descending order.
+    pub fn top(&self, limit: usize) -> Vec<(Vec<u8>, u64)> {
+        let mut keys: Vec<_> = self.counts
+            .iter()
+            .map(|(k, c)| (k.clone(), *c))
+            .collect();
+        keys.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        keys.truncate(limit);
+        keys
+    }
+}
+
+/// Records the reads of raw keys.
+pub fn record_read_keys<'a, I: IntoIterator<Item = &'a [u8]>>(keys: I) {
+    let mut counter = HOT_READ_KEYS.lock().unwrap();
+    for key in keys {
+        counter.record(key);
+    }
+}
+
+/// Records the writes of raw keys.
+pub fn record_write_keys<'a, I: IntoIterator<Item = &'a [u8]>>(keys: I) {
+    let mut counter = HOT_WRITE_KEYS.lock().unwrap();
+    for key in keys {
+        counter.record(key);
+    }
+}
+
+/// Records the reads of the keys of the transactional API by their raw keys.
+pub fn record_txn_read_keys<'a, I: IntoIterator<Item = &'a Key>>(keys: I) {
+    let keys = decode_keys(keys);
+    record_read_keys(keys.iter().map(|k| k.as_slice()));
+}
+
+/// Records the writes of the keys of the transactional API by their raw keys.
+pub fn record_txn_write_keys<'a, I: IntoIterator<Item = &'a Key>>(keys: I) {
+    let keys = decode_keys(keys);
+    record_write_keys(keys.iter().map(|k| k.as_slice()));
+}
+
+fn decode_keys<'a, I: IntoIterator<Item = &'a Key>>(keys: I) -> Vec<Vec<u8>> {
+    keys.into_iter().filter_map(|k| k.raw().ok()).collect()
+}
+
+/// Gets the top `limit` hot keys by read traffic.
+pub fn hot_read_keys(limit: usize) -> Vec<(Vec<u8>, u64)> {
+    HOT_READ_KEYS.lock().unwrap().top(limit)
+}
+
+/// Gets the top `limit` hot keys by write traffic.
+pub fn hot_write_keys(limit: usize) -> Vec<(Vec<u8>, u64)> {
+    HOT_WRITE_KEYS.lock().unwrap().top(limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_hot_key_counter() {
+        let mut counter = HotKeyCounter::new(3);
+        for _ in 0..5 {
+            counter.record(b"a");
+        }
+        for _ in 0..3 {
+            counter.record(b"b");
+        }
+        counter.record(b"c");
+        assert_eq!(
+            counter.top(2),
+            vec![(b"a".to_vec(), 5), (b"b".to_vec(), 3)]
+        );
+
+        // "d" replaces "c" and inherits its count.
+        counter.record(b"d");
+        assert_eq!(
+            counter.top(10),
+            vec![(b"a".to_vec(), 5), (b"b".to_vec(), 3), (b"d".to_vec(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_hot_key_counter_decay() {
+        let mut counter = HotKeyCounter::with_decay_interval(10, Duration::from_millis(10));
+        for _ in 0..4 {
+            counter.record(b"a");
+        }
+        counter.record(b"b");
+        thread::sleep(Duration::from_millis(20));
+        counter.record(b"c");
+        assert_eq!(
+            counter.top(10),
+            vec![(b"a".to_vec(), 2), (b"c".to_vec(), 1)]
+        );
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, Mutex};
+
+use kvproto::kvrpcpb::LockInfo;
+use kvproto::raft_cmdpb::{CmdType, Request};
+
+use raftstore::coprocessor::{Coprocessor, ObserverContext, RegionObserver};
+use util::collections::HashMap;
+use super::{Key, Result, CF_LOCK};
+use super::mvcc::Lock;
+
+// The observer becomes dirty if more locks are written, the caller has to fall
+// back to resolving the locks by scanning the regions.
+const MAX_OBSERVED_LOCKS: usize = 32 * 1024;
+
+#[derive(Default)]
+struct State {
+    // 0 means no observer is registered.
+    max_ts: u64,
+    is_clean: bool,
+    // key -> lock.
+    locks: HashMap<Vec<u8>, LockInfo>,
+}
+
+/// `LockObserver` records the locks applied on the store whose `ts <= max_ts`
+/// after it's registered with `max_ts`. The locks written before that are
+/// found by scanning the lock cf physically, so gc can collect all the locks
+/// before the safe point from the stores without scanning every region.
+///
+/// It only sees the locks written by raft commands, the locks brought by
+/// snapshots aren't recorded.
+#[derive(Clone, Default)]
+pub struct LockObserver {
+    state: Arc<Mutex<State>>,
+}
+
+impl LockObserver {
+    pub fn new() -> LockObserver {
+        LockObserver::default()
+    }
+
+    /// Starts recording the locks with `max_ts`. The recorded locks are
+    /// cleared if it's larger than the current one.
+    pub fn register(&self, max_ts: u64) {
+        let mut state = self.state.lock().unwrap();
+        if max_ts > state.max_ts {
+            state.max_ts = max_ts;
+            state.is_clean = true;
+            state.locks.clear();
+        }
+    }
+
+    /// Returns whether the observer is clean and the locks recorded.
+    pub fn check(&self, max_ts: u64) -> Result<(bool, Vec<LockInfo>)> {
+        let state = self.state.lock().unwrap();
+        if state.max_ts != max_ts {
+            return Err(box_err!(
+                "lock observer is registered with max_ts {}, but got {}",
+                state.max_ts,
+                max_ts
+            ));
+        }
+        Ok((state.is_clean, state.locks.values().cloned().collect()))
+    }
+
+    pub fn remove(&self, max_ts: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.max_ts != max_ts {
+            return Err(box_err!(
+                "lock observer is registered with max_ts {}, but got {}",
+                state.max_ts,
+                max_ts
+            ));
+        }
+        *state = State::default();
+        Ok(())
+    }
+
+    fn observe_lock(&self, key: &[u8], lock: Lock) {
+        let mut state = self.state.lock().unwrap();
+        if !state.is_clean || lock.ts > state.max_ts {
+            return;
+        }
+        if state.locks.len() >= MAX_OBSERVED_LOCKS && !state.locks.contains_key(key) {
+            state.is_clean = false;
+            state.locks.clear();
+            return;
+        }
+        let raw_key = match Key::from_encoded(key.to_vec()).raw() {
+            Ok(k) => k,
+            Err(e) => {
+                error!("failed to decode the key of lock {:?}: {:?}", key, e);
+                state.is_clean = false;
+                return;
+            }
+        };
+        let mut lock_info = LockInfo::new();
+        lock_info.set_primary_lock(lock.primary);
+        lock_info.set_lock_version(lock.ts);
+        lock_info.set_key(raw_key);
+        state.locks.insert(key.to_vec(), lock_info);
+    }
+
+    fn is_registered(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.max_ts > 0 && state.is_clean
+    }
+}
+
+impl Coprocessor for LockObserver {}
+
+impl RegionObserver for LockObserver {
+    fn post_apply_query(&self, _: &mut ObserverContext, _: u64, requests: &[Request]) {
+        if !self.is_registered() {
+            return;
+        }
+        for req in requests {
+            if req.get_cmd_type() != CmdType::Put || req.get_put().get_cf() != CF_LOCK {
+                continue;
+            }
+            let put = req.get_put();
+            match Lock::parse(put.get_value()) {
+                Ok(lock) => self.observe_lock(put.get_key(), lock),
+                Err(e) => error!("failed to parse lock {:?}: {:?}", put.get_value(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kvproto::metapb::Region;
+    use kvproto::raft_cmdpb::PutRequest;
+
+    use storage::make_key;
+    use storage::mvcc::LockType;
+    use super::*;
+
+    fn new_lock(key: &[u8], ts: u64) -> Request {
+        let key = make_key(key);
+        let lock = Lock::new(LockType::Put, key.raw().unwrap(), ts, 0, None);
+        let mut put = PutRequest::new();
+        put.set_cf(CF_LOCK.to_owned());
+        put.set_key(key.encoded().clone());
+        put.set_value(lock.to_bytes());
+        let mut req = Request::new();
+        req.set_cmd_type(CmdType::Put);
+        req.set_put(put);
+        req
+    }
+
+    fn must_locked(observer: &LockObserver, max_ts: u64, keys: &[&str]) {
+        let (is_clean, locks) = observer.check(max_ts).unwrap();
+        assert!(is_clean);
+        let mut locked: Vec<_> = locks.iter().map(|l| l.get_key().to_vec()).collect();
+        locked.sort();
+        let keys: Vec<_> = keys.iter().map(|k| k.as_bytes().to_vec()).collect();
+        assert_eq!(locked, keys);
+    }
+
+    #[test]
+    fn test_lock_observer() {
+        let observer = LockObserver::new();
+        let region = Region::new();
+        let mut ctx = ObserverContext::new(&region);
+
+        // Nothing is recorded before it's registered.
+        observer.post_apply_query(&mut ctx, 1, &[new_lock(b"a", 10)]);
+        assert!(observer.check(10).is_err());
+
+        observer.register(10);
+        observer.post_apply_query(&mut ctx, 2, &[new_lock(b"b", 10), new_lock(b"c", 11)]);
+        must_locked(&observer, 10, &["b"]);
+        assert!(observer.check(11).is_err());
+
+        // Registering with a smaller max_ts keeps the locks.
+        observer.register(5);
+        must_locked(&observer, 10, &["b"]);
+        observer.register(20);
+        must_locked(&observer, 20, &[]);
+        observer.post_apply_query(&mut ctx, 3, &[new_lock(b"c", 11)]);
+        must_locked(&observer, 20, &["c"]);
+
+        assert!(observer.remove(10).is_err());
+        observer.remove(20).unwrap();
+        assert!(observer.check(20).is_err());
+        observer.post_apply_query(&mut ctx, 4, &[new_lock(b"d", 11)]);
+        observer.register(20);
+        must_locked(&observer, 20, &[]);
+    }
+}
@@ -0,0 +1,425 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::Bound::{Excluded, Included, Unbounded};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use std::u64;
+
+use futures::Future;
+use futures_cpupool::{Builder, CpuPool};
+use raft::StateRole;
+
+use pd::PdClient;
+use raftstore::coprocessor::{Coprocessor, ObserverContext, RegionObserver};
+use storage::Key;
+use storage::mvcc::{Error as MvccError, Lock, Result as MvccResult};
+use util::collections::HashMap;
+
+const SYNC_RETRY_INTERVAL_MILLIS: u64 = 500;
+
+#[derive(Default)]
+struct State {
+    max_ts: u64,
+    // region id -> the id of the sync running for the region.
+    syncing: HashMap<u64, u64>,
+    next_sync_id: u64,
+    // encoded key -> the id of the prewrite which has locked it in memory.
+    memory_locks: BTreeMap<Vec<u8>, u64>,
+    // prewrite id -> its lock and the keys it has locked.
+    prewrites: HashMap<u64, (Lock, Vec<Vec<u8>>)>,
+}
+
+impl State {
+    // Same as the locks written, the memory locks don't block the reads before
+    // the min commit ts, or the reads which have pushed the transactions.
+    fn check_memory_lock(
+        &self,
+        ts: u64,
+        key: &[u8],
+        id: u64,
+        bypass_locks: &[u64],
+    ) -> MvccResult<()> {
+        let lock = &self.prewrites[&id].0;
+        if lock.ts <= ts && lock.min_commit_ts <= ts && !bypass_locks.contains(&lock.ts) {
+            return Err(MvccError::KeyIsLocked {
+                key: try!(Key::from_encoded(key.to_vec()).raw()),
+                primary: lock.primary.clone(),
+                ts: lock.ts,
+                ttl: lock.ttl,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// `MaxTsTracker` records the max ts of the reads served by the store. A
+/// transaction which may be committed without the client asking for a commit
+/// ts, like an async commit or a one-phase commit, has to be committed after
+/// it, or the reads which have missed its writes would see them later.
+///
+/// The reads served by the previous leader of a region are unknown to the
+/// store, so after a region becomes the leader, e.g. after it's elected or
+/// created by a split, its max ts is unknown until a ts taken from PD later
+/// is recorded. The regions are considered synced by default, which is the
+/// case of a standalone engine without raft.
+///
+/// The max ts is taken by a prewrite before its locks are written, a read
+/// recorded after that could miss them with an earlier snapshot. So the keys
+/// are locked in memory first, and the reads check the memory locks when the
+/// ts are recorded, before the snapshots are taken. The memory locks are
+/// released after the prewrite is written.
+///
+/// A one-phase commit writes no lock, so the resolved ts has to be advanced
+/// with a ts recorded as the max ts, and is held before the min commit ts of
+/// the memory locks, which is the commit ts of a one-phase commit.
+#[derive(Clone, Default)]
+pub struct MaxTsTracker {
+    state: Arc<Mutex<State>>,
+}
+
+impl MaxTsTracker {
+    pub fn new() -> MaxTsTracker {
+        MaxTsTracker::default()
+    }
+
+    /// Records a read at `ts`. `u64::MAX` is used by the reads which don't
+    /// care about the locks, so it's ignored.
+    pub fn update(&self, ts: u64) {
+        if ts == u64::MAX {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.max_ts = cmp::max(state.max_ts, ts);
+    }
+
+    /// Returns the max ts, or `None` if it's unknown to the region.
+    pub fn get(&self, region_id: u64) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        if state.syncing.contains_key(&region_id) {
+            return None;
+        }
+        Some(state.max_ts)
+    }
+
+    /// Records a read of `keys` at `ts`, it fails if a key is locked in memory
+    /// by a prewrite which may be committed at or before `ts`.
+    pub fn read_keys(&self, ts: u64, keys: &[Key], bypass_locks: &[u64]) -> MvccResult<()> {
+        if ts == u64::MAX {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap();
+        state.max_ts = cmp::max(state.max_ts, ts);
+        for key in keys {
+            if let Some(&id) = state.memory_locks.get(key.encoded()) {
+                try!(state.check_memory_lock(ts, key.encoded(), id, bypass_locks));
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a read of the range `[start, end)` at `ts`, `None` means unbounded.
+    /// It fails if a key in the range is locked in memory by a prewrite which may
+    /// be committed at or before `ts`.
+    pub fn read_range(
+        &self,
+        ts: u64,
+        start: Option<&Key>,
+        end: Option<&Key>,
+        bypass_locks: &[u64],
+    ) -> MvccResult<()> {
+        if ts == u64::MAX {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap();
+        state.max_ts = cmp::max(state.max_ts, ts);
+        if let (Some(start), Some(end)) = (start, end) {
+            if start.encoded() >= end.encoded() {
+                return Ok(());
+            }
+        }
+        let start = start.map_or(Unbounded, |k| Included(k.encoded().clone()));
+        let end = end.map_or(Unbounded, |k| Excluded(k.encoded().clone()));
+        for (key, &id) in state.memory_locks.range((start, end)) {
+            try!(state.check_memory_lock(ts, key, id, bypass_locks));
+        }
+        Ok(())
+    }
+
+    /// Locks `keys` in memory for the prewrite `id` with `lock`, and returns its
+    /// min commit ts, which is after the max ts, `lock.ts` and `lock.min_commit_ts`,
+    /// or `None` if the max ts is unknown to the region. It's also the commit ts
+    /// of a one-phase commit. The keys are locked, and the resolved ts is held
+    /// before the min commit ts, until `unlock_keys` is called after the prewrite
+    /// is written. The keys are held by the latches of the prewrite, so no one
+    /// else has locked them.
+    pub fn lock_keys(
+        &self,
+        region_id: u64,
+        id: u64,
+        keys: &[Key],
+        mut lock: Lock,
+    ) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        if state.syncing.contains_key(&region_id) {
+            return None;
+        }
+        let min_commit_ts = cmp::max(cmp::max(state.max_ts, lock.ts) + 1, lock.min_commit_ts);
+        lock.min_commit_ts = min_commit_ts;
+        let keys: Vec<_> = keys.iter().map(|k| k.encoded().clone()).collect();
+        for key in &keys {
+            state.memory_locks.insert(key.clone(), id);
+        }
+        state.prewrites.insert(id, (lock, keys));
+        Some(min_commit_ts)
+    }
+
+    pub fn unlock_keys(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        let keys = match state.prewrites.remove(&id) {
+            Some((_, keys)) => keys,
+            None => return,
+        };
+        for key in keys {
+            state.memory_locks.remove(&key);
+        }
+    }
+
+    /// Records `ts` taken from PD to advance the resolved ts, and returns the
+    /// ts the resolved ts can advance to. The prewrites which lock their keys
+    /// later are committed after `ts`, and those being written hold it before
+    /// their min commit ts.
+    pub fn min_ts_to_resolve(&self, ts: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.max_ts = cmp::max(state.max_ts, ts);
+        state
+            .prewrites
+            .values()
+            .map(|&(ref lock, _)| lock.min_commit_ts - 1)
+            .fold(ts, cmp::min)
+    }
+
+    /// Makes the max ts unknown to the region until the sync with the returned
+    /// id finishes.
+    pub fn start_sync(&self, region_id: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.next_sync_id += 1;
+        let sync_id = state.next_sync_id;
+        state.syncing.insert(region_id, sync_id);
+        sync_id
+    }
+
+    /// Records the ts taken from PD after the sync started, the region is synced
+    /// if no other sync has started since then.
+    pub fn finish_sync(&self, region_id: u64, sync_id: u64, ts: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.max_ts = cmp::max(state.max_ts, ts);
+        if state.syncing.get(&region_id) == Some(&sync_id) {
+            state.syncing.remove(&region_id);
+        }
+    }
+
+    fn is_syncing(&self, region_id: u64, sync_id: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        state.syncing.get(&region_id) == Some(&sync_id)
+    }
+
+    // The followers don't serve the writes, they are synced again once they
+    // become the leaders.
+    fn stop_sync(&self, region_id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.syncing.remove(&region_id);
+    }
+}
+
+/// `MaxTsObserver` syncs the max ts of a region from PD when it becomes the
+/// leader.
+pub struct MaxTsObserver<C: PdClient + 'static> {
+    tracker: MaxTsTracker,
+    pd_client: Arc<C>,
+    // The ts are taken from PD on the pool, out of the raftstore thread.
+    pool: CpuPool,
+}
+
+impl<C: PdClient + 'static> MaxTsObserver<C> {
+    pub fn new(tracker: MaxTsTracker, pd_client: Arc<C>) -> MaxTsObserver<C> {
+        let pool = Builder::new()
+            .name_prefix(thd_name!("max-ts-sync"))
+            .pool_size(1)
+            .create();
+        MaxTsObserver {
+            tracker: tracker,
+            pd_client: pd_client,
+            pool: pool,
+        }
+    }
+
+    fn sync(&self, region_id: u64) {
+        let sync_id = self.tracker.start_sync(region_id);
+        let tracker = self.tracker.clone();
+        let pd_client = self.pd_client.clone();
+        self.pool
+            .spawn_fn(move || {
+                // Retries until it succeeds or another sync takes over.
+                while tracker.is_syncing(region_id, sync_id) {
+                    match pd_client.get_tso().wait() {
+                        Ok(ts) => {
+                            tracker.finish_sync(region_id, sync_id, ts);
+                            break;
+                        }
+                        Err(e) => {
+                            warn!("failed to sync max ts of region {}: {:?}", region_id, e);
+                            thread::sleep(Duration::from_millis(SYNC_RETRY_INTERVAL_MILLIS));
+                        }
+                    }
+                }
+                Ok::<_, ()>(())
+            })
+            .forget();
+    }
+}
+
+impl<C: PdClient + 'static> Coprocessor for MaxTsObserver<C> {}
+
+impl<C: PdClient + 'static> RegionObserver for MaxTsObserver<C> {
+    fn on_role_change(&self, ctx: &mut ObserverContext, role: StateRole) {
+        let region_id = ctx.region().get_id();
+        if role == StateRole::Leader {
+            self.sync(region_id);
+        } else {
+            self.tracker.stop_sync(region_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::mvcc::LockType;
+
+    #[test]
+    fn test_max_ts_tracker() {
+        let tracker = MaxTsTracker::new();
+        assert_eq!(tracker.get(1), Some(0));
+        tracker.update(10);
+        tracker.update(5);
+        tracker.update(u64::MAX);
+        assert_eq!(tracker.get(1), Some(10));
+
+        // The max ts is unknown to the region until the sync finishes.
+        let sync_id = tracker.start_sync(1);
+        assert_eq!(tracker.get(1), None);
+        assert_eq!(tracker.get(2), Some(10));
+        tracker.update(15);
+        tracker.finish_sync(1, sync_id, 12);
+        assert_eq!(tracker.get(1), Some(15));
+
+        // A sync taken over by a later one doesn't make the region synced.
+        let sync_id = tracker.start_sync(1);
+        let new_sync_id = tracker.start_sync(1);
+        tracker.finish_sync(1, sync_id, 20);
+        assert_eq!(tracker.get(1), None);
+        assert!(!tracker.is_syncing(1, sync_id));
+        tracker.finish_sync(1, new_sync_id, 18);
+        assert_eq!(tracker.get(1), Some(20));
+
+        // The sync stops once the region isn't the leader.
+        let sync_id = tracker.start_sync(2);
+        tracker.stop_sync(2);
+        assert!(!tracker.is_syncing(2, sync_id));
+        assert_eq!(tracker.get(2), Some(20));
+    }
+
+    fn new_lock(ts: u64, min_commit_ts: u64) -> Lock {
+        Lock::new(LockType::Put, b"p".to_vec(), ts, 3000, None).with_min_commit_ts(min_commit_ts)
+    }
+
+    #[test]
+    fn test_min_commit_ts() {
+        let tracker = MaxTsTracker::new();
+        tracker.update(10);
+        let (k1, k2, k3, k4) = (
+            Key::from_raw(b"k1"),
+            Key::from_raw(b"k2"),
+            Key::from_raw(b"k3"),
+            Key::from_raw(b"k4"),
+        );
+        assert_eq!(tracker.lock_keys(1, 1, &[k1], new_lock(5, 0)), Some(11));
+        assert_eq!(tracker.lock_keys(1, 2, &[k2], new_lock(20, 0)), Some(21));
+        assert_eq!(tracker.lock_keys(1, 3, &[k3], new_lock(5, 30)), Some(30));
+        assert_eq!(tracker.lock_keys(1, 4, &[k4.clone()], new_lock(5, 0)), Some(11));
+        let sync_id = tracker.start_sync(2);
+        assert_eq!(tracker.lock_keys(2, 5, &[k4.clone()], new_lock(5, 0)), None);
+        tracker.finish_sync(2, sync_id, 8);
+
+        // The resolved ts is held before the prewrites being written.
+        assert_eq!(tracker.min_ts_to_resolve(25), 10);
+        assert_eq!(tracker.get(1), Some(25));
+        tracker.unlock_keys(1);
+        assert_eq!(tracker.min_ts_to_resolve(25), 10);
+        tracker.unlock_keys(4);
+        assert_eq!(tracker.min_ts_to_resolve(25), 20);
+        tracker.unlock_keys(2);
+        tracker.unlock_keys(3);
+        assert_eq!(tracker.min_ts_to_resolve(25), 25);
+        // The later ones are committed after the ts.
+        assert_eq!(tracker.lock_keys(1, 6, &[k4], new_lock(5, 0)), Some(26));
+    }
+
+    #[test]
+    fn test_memory_locks() {
+        let tracker = MaxTsTracker::new();
+        let (k1, k2, k3, k4) = (
+            Key::from_raw(b"k1"),
+            Key::from_raw(b"k2"),
+            Key::from_raw(b"k3"),
+            Key::from_raw(b"k4"),
+        );
+        tracker.update(10);
+        let keys = vec![k2.clone(), k3.clone()];
+        assert_eq!(tracker.lock_keys(1, 1, &keys, new_lock(5, 0)), Some(11));
+
+        // The reads before the min commit ts aren't blocked.
+        tracker.read_keys(10, &[k2.clone()], &[]).unwrap();
+        tracker.read_range(10, None, None, &[]).unwrap();
+        match tracker.read_keys(11, &[k1.clone(), k3.clone()], &[]) {
+            Err(MvccError::KeyIsLocked { key, primary, ts, .. }) => {
+                assert_eq!(key, b"k3".to_vec());
+                assert_eq!(primary, b"p".to_vec());
+                assert_eq!(ts, 5);
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(tracker.read_keys(11, &[k1.clone(), k4.clone()], &[]).is_ok());
+        assert!(tracker.read_keys(11, &[k3.clone()], &[5]).is_ok());
+        assert!(tracker.read_keys(u64::MAX, &[k3.clone()], &[]).is_ok());
+        assert!(tracker.read_range(12, Some(&k1), Some(&k2), &[]).is_ok());
+        assert!(tracker.read_range(12, Some(&k1), Some(&k3), &[]).is_err());
+        assert!(tracker.read_range(12, Some(&k4), None, &[]).is_ok());
+        assert!(tracker.read_range(12, None, Some(&k2), &[]).is_ok());
+        assert!(tracker.read_range(12, Some(&k3), Some(&k1), &[]).is_ok());
+        // The reads are recorded even if they fail.
+        assert_eq!(tracker.get(1), Some(12));
+        // The resolved ts is held before the min commit ts.
+        assert_eq!(tracker.min_ts_to_resolve(20), 10);
+
+        tracker.unlock_keys(1);
+        // Unlocking a prewrite twice is harmless.
+        tracker.unlock_keys(1);
+        assert!(tracker.read_range(20, None, None, &[]).is_ok());
+        assert_eq!(tracker.min_ts_to_resolve(20), 20);
+    }
+}
@@ -57,6 +57,14 @@ lazy_static! {
             exponential_buckets(0.0005, 2.0, 20).unwrap()
         ).unwrap();
 
+    pub static ref SCHED_STAGE_HISTOGRAM_VEC: HistogramVec =
+        register_histogram_vec!(
+            "tikv_scheduler_stage_duration_seconds",
+            "Bucketed histogram of command duration on each stage",
+            &["type", "stage"],
+            exponential_buckets(0.0005, 2.0, 20).unwrap()
+        ).unwrap();
+
     pub static ref SCHED_TOO_BUSY_COUNTER_VEC: CounterVec =
         register_counter_vec!(
             "tikv_scheduler_too_busy_total",
@@ -64,6 +72,33 @@ lazy_static! {
             &["type"]
         ).unwrap();
 
+    pub static ref SCHED_TOO_BUSY_REASON_COUNTER_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_scheduler_too_busy_reason_total",
+            "Total count of scheduler too busy by the reasons",
+            &["reason"]
+        ).unwrap();
+
+    pub static ref SCHED_WRITING_BYTES_GAUGE: Gauge =
+        register_gauge!(
+            "tikv_scheduler_writing_bytes",
+            "Total bytes of the pending writes in scheduler"
+        ).unwrap();
+
+    pub static ref SCHED_THROTTLE_HISTOGRAM_VEC: HistogramVec =
+        register_histogram_vec!(
+            "tikv_scheduler_throttle_duration_seconds",
+            "Bucketed histogram of the time writes are throttled by the write bandwidth limit",
+            &["type"],
+            exponential_buckets(0.0005, 2.0, 20).unwrap()
+        ).unwrap();
+
+    pub static ref SCHED_LOCK_WAITERS_GAUGE: Gauge =
+        register_gauge!(
+            "tikv_scheduler_lock_waiters",
+            "Total number of the writes waiting for locks in scheduler"
+        ).unwrap();
+
     pub static ref SCHED_COMMANDS_PRI_COUNTER_VEC: CounterVec =
         register_counter_vec!(
             "tikv_scheduler_commands_pri_total",
@@ -16,27 +16,58 @@ use std::boxed::FnBox;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::sync::mpsc::{self, Receiver};
 use std::error;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use std::str::FromStr;
 use std::io::Error as IoError;
 use std::u64;
+use futures::{future, Future};
+use futures::sync::oneshot;
 use kvproto::kvrpcpb::{CommandPri, LockInfo};
 use kvproto::errorpb;
 use self::metrics::*;
+use self::mvcc::TxnStatus;
 
 pub mod engine;
 pub mod mvcc;
 pub mod txn;
 pub mod config;
 pub mod types;
+pub mod hot_keys;
+pub mod causal_ts;
+pub mod gc_worker;
+pub mod lock_observer;
+pub mod max_ts;
+pub mod pessimistic_locks;
 mod metrics;
 
 pub use self::config::{Config, DEFAULT_DATA_DIR, DEFAULT_ROCKSDB_SUB_DIR};
 pub use self::engine::{new_local_engine, CFStatistics, Cursor, Engine, Error as EngineError,
                        Modify, ScanMode, Snapshot, Statistics, StatisticsSummary, TEMP_DIR};
 pub use self::engine::raftkv::RaftKv;
-pub use self::txn::{Msg, Scheduler, SnapshotStore, StoreScanner};
-pub use self::types::{make_key, Key, KvPair, MvccInfo, Value};
+pub use self::txn::{DeadlockCallback, DeadlockDetector, Msg, Scheduler, SlowLogThresholds,
+                    SnapshotStore, StoreScanner, WaitPolicy, WriteLimiter};
+pub use self::causal_ts::{BatchTsoProvider, CausalTsProvider};
+pub use self::gc_worker::GcManager;
+pub use self::lock_observer::LockObserver;
+pub use self::max_ts::{MaxTsObserver, MaxTsTracker};
+pub use self::pessimistic_locks::PessimisticLockTable;
+pub use self::types::{make_key, Key, KvPair, MvccInfo, SecondaryLocksStatus, Value};
 pub type Callback<T> = Box<FnBox(Result<T>) + Send>;
+/// `StorageFuture` resolves to the result of a command. The command is sent
+/// when the future is created, dropping the future doesn't cancel it.
+pub type StorageFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
+
+/// Returns a callback and a future resolved by the callback.
+pub fn paired_future_callback<T: Send + 'static>() -> (Callback<T>, StorageFuture<T>) {
+    let (tx, rx) = oneshot::channel();
+    let callback = box move |res: Result<T>| {
+        // The receiver may be dropped if the result is not needed.
+        let _ = tx.send(res);
+    };
+    // The sender is dropped without sending only when the storage is closed.
+    let future = rx.map_err(|_| Error::Closed).and_then(|res| res);
+    (callback, box future)
+}
 
 pub type CfName = &'static str;
 pub const CF_DEFAULT: CfName = "default";
@@ -61,6 +92,8 @@ pub enum Mutation {
     Put((Key, Value)),
     Delete(Key),
     Lock(Key),
+    // Puts the value only if the key doesn't exist.
+    Insert((Key, Value)),
 }
 
 #[allow(match_same_arms)]
@@ -70,6 +103,7 @@ impl Mutation {
             Mutation::Put((ref key, _)) => key,
             Mutation::Delete(ref key) => key,
             Mutation::Lock(ref key) => key,
+            Mutation::Insert((ref key, _)) => key,
         }
     }
 }
@@ -84,6 +118,11 @@ pub enum StorageCb {
     MvccInfoByKey(Callback<MvccInfo>),
     MvccInfoByStartTs(Callback<Option<(Key, MvccInfo)>>),
     Locks(Callback<Vec<LockInfo>>),
+    TxnStatus(Callback<TxnStatus>),
+    Ttl(Callback<u64>),
+    SecondaryLocks(Callback<SecondaryLocksStatus>),
+    Prewrite(Callback<PrewriteResult>),
+    PessimisticLock(Callback<PessimisticLockResult>),
 }
 
 pub enum Command {
@@ -127,6 +166,38 @@ pub enum Command {
         keys: Vec<Key>,
         start_ts: u64,
     },
+    AcquirePessimisticLock {
+        ctx: Context,
+        keys: Vec<Key>,
+        primary: Vec<u8>,
+        start_ts: u64,
+        for_update_ts: u64,
+        options: Options,
+    },
+    PessimisticRollback {
+        ctx: Context,
+        keys: Vec<Key>,
+        start_ts: u64,
+        for_update_ts: u64,
+    },
+    CheckTxnStatus {
+        ctx: Context,
+        primary_key: Key,
+        lock_ts: u64,
+        caller_start_ts: u64,
+        current_ts: u64,
+    },
+    TxnHeartBeat {
+        ctx: Context,
+        primary_key: Key,
+        start_ts: u64,
+        advise_ttl: u64,
+    },
+    CheckSecondaryLocks {
+        ctx: Context,
+        keys: Vec<Key>,
+        start_ts: u64,
+    },
     ScanLock { ctx: Context, max_ts: u64 },
     ResolveLock {
         ctx: Context,
@@ -135,6 +206,12 @@ pub enum Command {
         scan_key: Option<Key>,
         keys: Vec<Key>,
     },
+    ResolveLockLite {
+        ctx: Context,
+        start_ts: u64,
+        commit_ts: Option<u64>,
+        resolve_keys: Vec<Key>,
+    },
     Gc {
         ctx: Context,
         safe_point: u64,
@@ -143,10 +220,12 @@ pub enum Command {
         keys: Vec<Key>,
     },
     RawGet { ctx: Context, key: Key },
+    RawBatchGet { ctx: Context, keys: Vec<Key> },
     RawScan {
         ctx: Context,
         start_key: Key,
         limit: usize,
+        key_only: bool,
     },
     DeleteRange {
         ctx: Context,
@@ -237,6 +316,72 @@ impl Display for Command {
                 start_ts,
                 ctx
             ),
+            Command::AcquirePessimisticLock {
+                ref ctx,
+                ref keys,
+                start_ts,
+                for_update_ts,
+                ..
+            } => write!(
+                f,
+                "kv::command::acquire_pessimistic_lock keys({}) @ {} {} | {:?}",
+                keys.len(),
+                start_ts,
+                for_update_ts,
+                ctx
+            ),
+            Command::PessimisticRollback {
+                ref ctx,
+                ref keys,
+                start_ts,
+                for_update_ts,
+            } => write!(
+                f,
+                "kv::command::pessimistic_rollback keys({}) @ {} {} | {:?}",
+                keys.len(),
+                start_ts,
+                for_update_ts,
+                ctx
+            ),
+            Command::CheckTxnStatus {
+                ref ctx,
+                ref primary_key,
+                lock_ts,
+                caller_start_ts,
+                current_ts,
+            } => write!(
+                f,
+                "kv::command::check_txn_status {} @ {} caller({}) curr({}) | {:?}",
+                primary_key,
+                lock_ts,
+                caller_start_ts,
+                current_ts,
+                ctx
+            ),
+            Command::TxnHeartBeat {
+                ref ctx,
+                ref primary_key,
+                start_ts,
+                advise_ttl,
+            } => write!(
+                f,
+                "kv::command::txn_heart_beat {} @ {} ttl({}) | {:?}",
+                primary_key,
+                start_ts,
+                advise_ttl,
+                ctx
+            ),
+            Command::CheckSecondaryLocks {
+                ref ctx,
+                ref keys,
+                start_ts,
+            } => write!(
+                f,
+                "kv::command::check_secondary_locks keys({}) @ {} | {:?}",
+                keys.len(),
+                start_ts,
+                ctx
+            ),
             Command::ScanLock {
                 ref ctx, max_ts, ..
             } => write!(f, "kv::scan_lock {} | {:?}", max_ts, ctx),
@@ -252,6 +397,19 @@ impl Display for Command {
                 commit_ts,
                 ctx
             ),
+            Command::ResolveLockLite {
+                ref ctx,
+                start_ts,
+                commit_ts,
+                ref resolve_keys,
+            } => write!(
+                f,
+                "kv::resolve_txn_lite {} -> {:?} keys({}) | {:?}",
+                start_ts,
+                commit_ts,
+                resolve_keys.len(),
+                ctx
+            ),
             Command::Gc {
                 ref ctx,
                 safe_point,
@@ -267,10 +425,14 @@ impl Display for Command {
             Command::RawGet { ref ctx, ref key } => {
                 write!(f, "kv::command::rawget {:?} | {:?}", key, ctx)
             }
+            Command::RawBatchGet { ref ctx, ref keys } => {
+                write!(f, "kv::command::raw_batch_get {} keys | {:?}", keys.len(), ctx)
+            }
             Command::RawScan {
                 ref ctx,
                 ref start_key,
                 limit,
+                ..
             } => write!(
                 f,
                 "kv::command::rawscan {:?} {} | {:?}",
@@ -319,6 +481,7 @@ impl Command {
             Command::Scan { .. } |
             Command::ScanLock { .. } |
             Command::RawGet { .. } |
+            Command::RawBatchGet { .. } |
             Command::RawScan { .. } |
             // DeleteRange only called by DDL bg thread after table is dropped and
             // must guarantee that there is no other read or write on these keys, so
@@ -333,18 +496,59 @@ impl Command {
         }
     }
 
+    /// Gets the priority of the command, the maintenance commands always run
+    /// at low priority so they won't delay the transactions of users.
     pub fn priority(&self) -> CommandPri {
-        self.get_context().get_priority()
+        match *self {
+            Command::Gc { .. } | Command::ResolveLock { .. } => CommandPri::Low,
+            _ => self.get_context().get_priority(),
+        }
     }
 
     pub fn priority_tag(&self) -> &'static str {
-        match self.get_context().get_priority() {
+        match self.priority() {
             CommandPri::Low => "low",
             CommandPri::Normal => "normal",
             CommandPri::High => "high",
         }
     }
 
+    /// Gets the approximate bytes the command writes, it's used to control the
+    /// flow of the pending writes.
+    pub fn write_bytes(&self) -> usize {
+        match *self {
+            Command::Prewrite { ref mutations, .. } => mutations
+                .iter()
+                .map(|m| match *m {
+                    Mutation::Put((ref key, ref value)) |
+                    Mutation::Insert((ref key, ref value)) => key.encoded().len() + value.len(),
+                    Mutation::Delete(ref key) | Mutation::Lock(ref key) => key.encoded().len(),
+                })
+                .sum(),
+            Command::Commit { ref keys, .. } |
+            Command::Rollback { ref keys, .. } |
+            Command::AcquirePessimisticLock { ref keys, .. } |
+            Command::PessimisticRollback { ref keys, .. } |
+            Command::CheckSecondaryLocks { ref keys, .. } |
+            Command::ResolveLock { ref keys, .. } |
+            Command::ResolveLockLite {
+                resolve_keys: ref keys,
+                ..
+            } |
+            Command::Gc { ref keys, .. } => keys.iter().map(|k| k.encoded().len()).sum(),
+            Command::Cleanup { ref key, .. } |
+            Command::CheckTxnStatus {
+                primary_key: ref key,
+                ..
+            } |
+            Command::TxnHeartBeat {
+                primary_key: ref key,
+                ..
+            } => key.encoded().len(),
+            _ => 0,
+        }
+    }
+
     pub fn need_flow_control(&self) -> bool {
         !self.readonly() && self.priority() != CommandPri::High
     }
@@ -358,10 +562,17 @@ impl Command {
             Command::Commit { .. } => "commit",
             Command::Cleanup { .. } => "cleanup",
             Command::Rollback { .. } => "rollback",
+            Command::AcquirePessimisticLock { .. } => "acquire_pessimistic_lock",
+            Command::PessimisticRollback { .. } => "pessimistic_rollback",
+            Command::CheckTxnStatus { .. } => "check_txn_status",
+            Command::TxnHeartBeat { .. } => "txn_heart_beat",
+            Command::CheckSecondaryLocks { .. } => "check_secondary_locks",
             Command::ScanLock { .. } => "scan_lock",
             Command::ResolveLock { .. } => "resolve_lock",
+            Command::ResolveLockLite { .. } => "resolve_lock_lite",
             Command::Gc { .. } => CMD_TAG_GC,
             Command::RawGet { .. } => "raw_get",
+            Command::RawBatchGet { .. } => "raw_batch_get",
             Command::RawScan { .. } => "raw_scan",
             Command::DeleteRange { .. } => "delete_range",
             Command::Pause { .. } => "pause",
@@ -378,12 +589,18 @@ impl Command {
             Command::Prewrite { start_ts, .. } |
             Command::Cleanup { start_ts, .. } |
             Command::Rollback { start_ts, .. } |
+            Command::AcquirePessimisticLock { start_ts, .. } |
+            Command::PessimisticRollback { start_ts, .. } |
+            Command::TxnHeartBeat { start_ts, .. } |
+            Command::CheckSecondaryLocks { start_ts, .. } |
             Command::ResolveLock { start_ts, .. } |
+            Command::ResolveLockLite { start_ts, .. } |
             Command::MvccByStartTs { start_ts, .. } => start_ts,
-            Command::Commit { lock_ts, .. } => lock_ts,
+            Command::Commit { lock_ts, .. } | Command::CheckTxnStatus { lock_ts, .. } => lock_ts,
             Command::ScanLock { max_ts, .. } => max_ts,
             Command::Gc { safe_point, .. } => safe_point,
             Command::RawGet { .. } |
+            Command::RawBatchGet { .. } |
             Command::RawScan { .. } |
             Command::DeleteRange { .. } |
             Command::Pause { .. } |
@@ -400,10 +617,17 @@ impl Command {
             Command::Commit { ref ctx, .. } |
             Command::Cleanup { ref ctx, .. } |
             Command::Rollback { ref ctx, .. } |
+            Command::AcquirePessimisticLock { ref ctx, .. } |
+            Command::PessimisticRollback { ref ctx, .. } |
+            Command::CheckTxnStatus { ref ctx, .. } |
+            Command::TxnHeartBeat { ref ctx, .. } |
+            Command::CheckSecondaryLocks { ref ctx, .. } |
             Command::ScanLock { ref ctx, .. } |
             Command::ResolveLock { ref ctx, .. } |
+            Command::ResolveLockLite { ref ctx, .. } |
             Command::Gc { ref ctx, .. } |
             Command::RawGet { ref ctx, .. } |
+            Command::RawBatchGet { ref ctx, .. } |
             Command::RawScan { ref ctx, .. } |
             Command::DeleteRange { ref ctx, .. } |
             Command::Pause { ref ctx, .. } |
@@ -421,10 +645,17 @@ impl Command {
             Command::Commit { ref mut ctx, .. } |
             Command::Cleanup { ref mut ctx, .. } |
             Command::Rollback { ref mut ctx, .. } |
+            Command::AcquirePessimisticLock { ref mut ctx, .. } |
+            Command::PessimisticRollback { ref mut ctx, .. } |
+            Command::CheckTxnStatus { ref mut ctx, .. } |
+            Command::TxnHeartBeat { ref mut ctx, .. } |
+            Command::CheckSecondaryLocks { ref mut ctx, .. } |
             Command::ScanLock { ref mut ctx, .. } |
             Command::ResolveLock { ref mut ctx, .. } |
+            Command::ResolveLockLite { ref mut ctx, .. } |
             Command::Gc { ref mut ctx, .. } |
             Command::RawGet { ref mut ctx, .. } |
+            Command::RawBatchGet { ref mut ctx, .. } |
             Command::RawScan { ref mut ctx, .. } |
             Command::DeleteRange { ref mut ctx, .. } |
             Command::Pause { ref mut ctx, .. } |
@@ -435,12 +666,50 @@ impl Command {
 }
 
 use util::transport::SyncSendCh;
+use util::escape;
+use util::tracker::{self, Tracker};
+use util::dynamic_config::ConfigHandler;
+use util::config::ReadableSize;
+use util::compaction_filter;
+use util::collections::HashSet;
 
 #[derive(Clone, Default)]
 pub struct Options {
     pub lock_ttl: u64,
+    // Skips the write conflict and existence checks of the prewrite, it's only for
+    // the importers which know that the keys don't conflict.
     pub skip_constraint_check: bool,
     pub key_only: bool,
+    // The transaction is committed once all its keys are prewritten, with a commit
+    // ts no smaller than `min_commit_ts`. The primary lock records `secondary_keys`.
+    pub use_async_commit: bool,
+    pub min_commit_ts: u64,
+    pub secondary_keys: Vec<Vec<u8>>,
+    // Commits the transaction in the prewrite if all its keys are in the region.
+    pub try_one_pc: bool,
+    // Scans backward from the start key, which is excluded.
+    pub reverse_scan: bool,
+    // Returns the latest committed values of the keys locked by a pessimistic lock.
+    pub return_values: bool,
+}
+
+/// The result of a prewrite.
+#[derive(Debug)]
+pub struct PrewriteResult {
+    pub locks: Vec<Result<()>>,
+    /// The min commit ts of an async commit, 0 if it falls back to 2PC.
+    pub min_commit_ts: u64,
+    /// The commit ts of a one-phase commit, 0 if the transaction isn't committed.
+    pub one_pc_commit_ts: u64,
+}
+
+/// The result of a pessimistic lock.
+#[derive(Debug)]
+pub struct PessimisticLockResult {
+    pub locks: Vec<Result<()>>,
+    /// The latest committed values of the keys in order, only set if all the keys
+    /// are locked and `return_values` is set.
+    pub values: Vec<Option<Value>>,
 }
 
 impl Options {
@@ -449,6 +718,12 @@ impl Options {
             lock_ttl: lock_ttl,
             skip_constraint_check: skip_constraint_check,
             key_only: key_only,
+            use_async_commit: false,
+            min_commit_ts: 0,
+            secondary_keys: vec![],
+            try_one_pc: false,
+            reverse_scan: false,
+            return_values: false,
         }
     }
 }
@@ -463,8 +738,72 @@ pub struct Storage {
     sendch: SyncSendCh<Msg>,
     handle: Arc<Mutex<StorageHandle>>,
 
-    // Storage configurations.
-    gc_ratio_threshold: f64,
+    // Storage configurations, which can be changed by `StorageConfigHandler`.
+    gc_ratio_threshold: Arc<RwLock<f64>>,
+    write_limiter: Arc<WriteLimiter>,
+
+    max_key_size: usize,
+    max_value_size: usize,
+
+    gc_by_compaction_filter: bool,
+
+    // Orders the raw writes, it's only set in raw mode.
+    causal_ts: Option<Arc<CausalTsProvider>>,
+    // Records the locks applied on the store for gc.
+    lock_observer: Option<LockObserver>,
+    // Records the max ts of the reads, it's shared with the coprocessor.
+    max_ts: MaxTsTracker,
+    // Keeps the pessimistic locks of the leader regions in memory once it's
+    // registered to the raftstore.
+    pessimistic_locks: PessimisticLockTable,
+    // Finds the deadlocks of the pessimistic locks waiting in the scheduler.
+    detector: Option<Arc<DeadlockDetector>>,
+}
+
+/// `StorageConfigHandler` changes the configs of a running storage.
+pub struct StorageConfigHandler {
+    gc_ratio_threshold: Arc<RwLock<f64>>,
+    write_limiter: Arc<WriteLimiter>,
+    // The worker pool is resized by the scheduler itself.
+    sendch: SyncSendCh<Msg>,
+}
+
+impl ConfigHandler for StorageConfigHandler {
+    fn update(&self, name: &str, value: &str) -> ::std::result::Result<(), String> {
+        match name {
+            "gc-ratio-threshold" => {
+                let ratio: f64 = try!(
+                    value
+                        .parse()
+                        .map_err(|e| format!("invalid gc ratio threshold {:?}: {}", value, e))
+                );
+                if ratio.is_nan() || ratio < 0.0 {
+                    return Err(format!("gc ratio threshold {} should be non-negative", ratio));
+                }
+                *self.gc_ratio_threshold.write().unwrap() = ratio;
+                Ok(())
+            }
+            "scheduler-write-bandwidth-limit" => {
+                let limit = try!(ReadableSize::from_str(value));
+                self.write_limiter.set_bytes_per_sec(limit.0);
+                Ok(())
+            }
+            "scheduler-worker-pool-size" => {
+                let size: usize = try!(
+                    value
+                        .parse()
+                        .map_err(|e| format!("invalid worker pool size {:?}: {}", value, e))
+                );
+                if size == 0 {
+                    return Err("scheduler-worker-pool-size should be greater than 0".to_owned());
+                }
+                self.sendch
+                    .send(Msg::ResizeWorkerPool(size))
+                    .map_err(|e| format!("failed to resize scheduler worker pool: {:?}", e))
+            }
+            _ => Err(format!("unknown storage config {:?}", name)),
+        }
+    }
 }
 
 impl Storage {
@@ -480,10 +819,95 @@ impl Storage {
                 handle: None,
                 receiver: Some(rx),
             })),
-            gc_ratio_threshold: config.gc_ratio_threshold,
+            gc_ratio_threshold: Arc::new(RwLock::new(config.gc_ratio_threshold)),
+            write_limiter: Arc::new(WriteLimiter::new(
+                config.scheduler_write_bandwidth_limit.0,
+            )),
+            max_key_size: config.max_key_size.0 as usize,
+            max_value_size: config.max_value_size.0 as usize,
+            gc_by_compaction_filter: config.gc_by_compaction_filter,
+            causal_ts: None,
+            lock_observer: None,
+            max_ts: MaxTsTracker::new(),
+            pessimistic_locks: PessimisticLockTable::new(),
+            detector: None,
         })
     }
 
+    pub fn set_causal_ts_provider(&mut self, provider: Arc<CausalTsProvider>) {
+        self.causal_ts = Some(provider);
+    }
+
+    /// Gets a causal timestamp, which is greater than the ones of all the raw
+    /// writes acknowledged before.
+    pub fn raw_get_causal_ts(&self) -> Result<u64> {
+        match self.causal_ts {
+            Some(ref provider) => provider.get_ts(),
+            None => Err(box_err!("causal timestamp is not enabled")),
+        }
+    }
+
+    /// Sets the observer registered to the raftstore, which records the locks
+    /// applied on the store.
+    pub fn set_lock_observer(&mut self, observer: LockObserver) {
+        self.lock_observer = Some(observer);
+    }
+
+    /// Sets the deadlock detector used by the scheduler, it must be set before
+    /// the storage is started.
+    pub fn set_deadlock_detector(&mut self, detector: Arc<DeadlockDetector>) {
+        self.detector = Some(detector);
+    }
+
+    fn get_lock_observer(&self) -> Result<&LockObserver> {
+        match self.lock_observer {
+            Some(ref observer) => Ok(observer),
+            None => Err(box_err!("lock observer is not enabled")),
+        }
+    }
+
+    pub fn register_lock_observer(&self, max_ts: u64) -> Result<()> {
+        let observer = try!(self.get_lock_observer());
+        observer.register(max_ts);
+        Ok(())
+    }
+
+    /// Returns whether all the locks applied since the observer is registered
+    /// are recorded, and the recorded locks whose `ts <= max_ts`. The locks
+    /// kept in memory aren't applied, they are always returned.
+    pub fn check_lock_observer(&self, max_ts: u64) -> Result<(bool, Vec<LockInfo>)> {
+        let (is_clean, mut locks) = try!(try!(self.get_lock_observer()).check(max_ts));
+        let in_memory = try!(
+            self.pessimistic_locks
+                .scan_locks(None, b"", b"", |lock| lock.ts <= max_ts)
+        );
+        let recorded: HashSet<Vec<u8>> = locks.iter().map(|l| l.get_key().to_vec()).collect();
+        for (key, lock) in in_memory {
+            let raw_key = try!(key.raw().map_err(mvcc::Error::from));
+            if recorded.contains(&raw_key) {
+                continue;
+            }
+            let mut lock_info = LockInfo::new();
+            lock_info.set_primary_lock(lock.primary);
+            lock_info.set_lock_version(lock.ts);
+            lock_info.set_key(raw_key);
+            locks.push(lock_info);
+        }
+        Ok((is_clean, locks))
+    }
+
+    pub fn remove_lock_observer(&self, max_ts: u64) -> Result<()> {
+        try!(self.get_lock_observer()).remove(max_ts)
+    }
+
+    pub fn config_handler(&self) -> StorageConfigHandler {
+        StorageConfigHandler {
+            gc_ratio_threshold: self.gc_ratio_threshold.clone(),
+            write_limiter: self.write_limiter.clone(),
+            sendch: self.sendch.clone(),
+        }
+    }
+
     pub fn new(config: &Config) -> Result<Storage> {
         let engine = try!(engine::new_local_engine(&config.data_dir, ALL_CFS));
         Storage::from_engine(engine, config)
@@ -501,6 +925,13 @@ impl Storage {
         let sched_concurrency = config.scheduler_concurrency;
         let sched_worker_pool_size = config.scheduler_worker_pool_size;
         let sched_too_busy_threshold = config.scheduler_too_busy_threshold;
+        let sched_pending_write_threshold = config.scheduler_pending_write_threshold.0 as usize;
+        let slow_log_thresholds = SlowLogThresholds::new(config);
+        let wait_policy = WaitPolicy::new(config);
+        let write_limiter = self.write_limiter.clone();
+        let detector = self.detector.clone();
+        let max_ts = self.max_ts.clone();
+        let pessimistic_locks = self.pessimistic_locks.clone();
         let ch = self.sendch.clone();
         let h = try!(builder.spawn(move || {
             let mut sched = Scheduler::new(
@@ -509,6 +940,13 @@ impl Storage {
                 sched_concurrency,
                 sched_worker_pool_size,
                 sched_too_busy_threshold,
+                sched_pending_write_threshold,
+                slow_log_thresholds,
+                wait_policy,
+                write_limiter,
+                detector,
+                max_ts,
+                pessimistic_locks,
             );
             if let Err(e) = sched.run(rx) {
                 panic!("scheduler run err:{:?}", e);
@@ -544,27 +982,102 @@ impl Storage {
         self.engine.clone()
     }
 
+    /// Returns the tracker of the max ts of the reads, the reads served out of
+    /// the storage have to be recorded to it as well.
+    pub fn get_max_ts(&self) -> MaxTsTracker {
+        self.max_ts.clone()
+    }
+
+    /// Returns the table of the pessimistic locks kept in memory, it keeps no
+    /// lock until it's registered to the raftstore as an observer.
+    pub fn get_pessimistic_locks(&self) -> PessimisticLockTable {
+        self.pessimistic_locks.clone()
+    }
+
+    fn check_key_size(&self, key: &[u8]) -> Result<()> {
+        if key.len() > self.max_key_size {
+            return Err(Error::KeyTooLarge(key.len(), self.max_key_size));
+        }
+        Ok(())
+    }
+
+    fn check_value_size(&self, value: &[u8]) -> Result<()> {
+        if value.len() > self.max_value_size {
+            return Err(Error::ValueTooLarge(value.len(), self.max_value_size));
+        }
+        Ok(())
+    }
+
+    fn check_mutations(&self, mutations: &[Mutation]) -> Result<()> {
+        for m in mutations {
+            try!(self.check_key_size(m.key().encoded()));
+            match *m {
+                Mutation::Put((_, ref value)) | Mutation::Insert((_, ref value)) => {
+                    try!(self.check_value_size(value))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
     fn send(&self, cmd: Command, cb: StorageCb) -> Result<()> {
-        box_try!(self.sendch.try_send(Msg::RawCmd { cmd: cmd, cb: cb }));
+        // Requests which are not tracked from their entries get new trackers.
+        let tracker = tracker::current().unwrap_or_else(|| Tracker::new(cmd.tag()));
+        box_try!(self.sendch.try_send(Msg::RawCmd {
+            cmd: cmd,
+            cb: cb,
+            tracker: tracker,
+        }));
         Ok(())
     }
 
-    pub fn async_get(
-        &self,
-        ctx: Context,
-        key: Key,
-        start_ts: u64,
-        callback: Callback<Option<Value>>,
-    ) -> Result<()> {
+    fn send_future<T, F>(&self, cmd: Command, make_cb: F) -> StorageFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(Callback<T>) -> StorageCb,
+    {
+        let tag = cmd.tag();
+        let (cb, future) = paired_future_callback();
+        if let Err(e) = self.send(cmd, make_cb(cb)) {
+            return box future::err(e);
+        }
+        KV_COMMAND_COUNTER_VEC.with_label_values(&[tag]).inc();
+        future
+    }
+
+    // A raw write is proposed after its causal timestamp is taken, so it's ordered
+    // before the raw writes which take timestamps after it's acknowledged.
+    fn raw_write_future(&self, ctx: &Context, modifies: Vec<Modify>) -> StorageFuture<()> {
+        if let Some(ref provider) = self.causal_ts {
+            if let Err(e) = provider.get_ts() {
+                return box future::err(e);
+            }
+        }
+        self.write_future(ctx, modifies)
+    }
+
+    fn write_future(&self, ctx: &Context, modifies: Vec<Modify>) -> StorageFuture<()> {
+        let (cb, future) = paired_future_callback();
+        let res = self.engine.async_write(
+            ctx,
+            modifies,
+            box |(_, res): (_, engine::Result<_>)| cb(res.map_err(Error::from)),
+        );
+        if let Err(e) = res {
+            return box future::err(Error::from(e));
+        }
+        future
+    }
+
+    pub fn async_get(&self, ctx: Context, key: Key, start_ts: u64) -> StorageFuture<Option<Value>> {
+        hot_keys::record_txn_read_keys(Some(&key));
         let cmd = Command::Get {
             ctx: ctx,
             key: key,
             start_ts: start_ts,
         };
-        let tag = cmd.tag();
-        try!(self.send(cmd, StorageCb::SingleValue(callback)));
-        KV_COMMAND_COUNTER_VEC.with_label_values(&[tag]).inc();
-        Ok(())
+        self.send_future(cmd, StorageCb::SingleValue)
     }
 
     pub fn async_batch_get(
@@ -572,17 +1085,14 @@ impl Storage {
         ctx: Context,
         keys: Vec<Key>,
         start_ts: u64,
-        callback: Callback<Vec<Result<KvPair>>>,
-    ) -> Result<()> {
+    ) -> StorageFuture<Vec<Result<KvPair>>> {
+        hot_keys::record_txn_read_keys(&keys);
         let cmd = Command::BatchGet {
             ctx: ctx,
             keys: keys,
             start_ts: start_ts,
         };
-        let tag = cmd.tag();
-        try!(self.send(cmd, StorageCb::KvPairs(callback)));
-        KV_COMMAND_COUNTER_VEC.with_label_values(&[tag]).inc();
-        Ok(())
+        self.send_future(cmd, StorageCb::KvPairs)
     }
 
     pub fn async_scan(
@@ -592,8 +1102,8 @@ impl Storage {
         limit: usize,
         start_ts: u64,
         options: Options,
-        callback: Callback<Vec<Result<KvPair>>>,
-    ) -> Result<()> {
+    ) -> StorageFuture<Vec<Result<KvPair>>> {
+        hot_keys::record_txn_read_keys(Some(&start_key));
         let cmd = Command::Scan {
             ctx: ctx,
             start_key: start_key,
@@ -601,19 +1111,15 @@ impl Storage {
             start_ts: start_ts,
             options: options,
         };
-        let tag = cmd.tag();
-        try!(self.send(cmd, StorageCb::KvPairs(callback)));
-        KV_COMMAND_COUNTER_VEC.with_label_values(&[tag]).inc();
-        Ok(())
+        self.send_future(cmd, StorageCb::KvPairs)
     }
 
-    pub fn async_pause(&self, ctx: Context, duration: u64, callback: Callback<()>) -> Result<()> {
+    pub fn async_pause(&self, ctx: Context, duration: u64) -> StorageFuture<()> {
         let cmd = Command::Pause {
             ctx: ctx,
             duration: duration,
         };
-        try!(self.send(cmd, StorageCb::Boolean(callback)));
-        Ok(())
+        self.send_future(cmd, StorageCb::Boolean)
     }
 
     pub fn async_prewrite(
@@ -623,8 +1129,12 @@ impl Storage {
         primary: Vec<u8>,
         start_ts: u64,
         options: Options,
-        callback: Callback<Vec<Result<()>>>,
-    ) -> Result<()> {
+    ) -> StorageFuture<Vec<Result<()>>> {
+        // Oversized entries are rejected before they are proposed.
+        if let Err(e) = self.check_mutations(&mutations) {
+            return box future::err(e);
+        }
+        hot_keys::record_txn_write_keys(mutations.iter().map(|m| m.key()));
         let cmd = Command::Prewrite {
             ctx: ctx,
             mutations: mutations,
@@ -632,10 +1142,31 @@ impl Storage {
             start_ts: start_ts,
             options: options,
         };
-        let tag = cmd.tag();
-        try!(self.send(cmd, StorageCb::Booleans(callback)));
-        KV_COMMAND_COUNTER_VEC.with_label_values(&[tag]).inc();
-        Ok(())
+        self.send_future(cmd, StorageCb::Booleans)
+    }
+
+    /// Prewrites the mutations like `async_prewrite`, the transaction is committed by
+    /// the prewrite if `options.try_one_pc` is set.
+    pub fn async_prewrite_with_result(
+        &self,
+        ctx: Context,
+        mutations: Vec<Mutation>,
+        primary: Vec<u8>,
+        start_ts: u64,
+        options: Options,
+    ) -> StorageFuture<PrewriteResult> {
+        if let Err(e) = self.check_mutations(&mutations) {
+            return box future::err(e);
+        }
+        hot_keys::record_txn_write_keys(mutations.iter().map(|m| m.key()));
+        let cmd = Command::Prewrite {
+            ctx: ctx,
+            mutations: mutations,
+            primary: primary,
+            start_ts: start_ts,
+            options: options,
+        };
+        self.send_future(cmd, StorageCb::Prewrite)
     }
 
     pub fn async_commit(
@@ -644,18 +1175,14 @@ impl Storage {
         keys: Vec<Key>,
         lock_ts: u64,
         commit_ts: u64,
-        callback: Callback<()>,
-    ) -> Result<()> {
+    ) -> StorageFuture<()> {
         let cmd = Command::Commit {
             ctx: ctx,
             keys: keys,
             lock_ts: lock_ts,
             commit_ts: commit_ts,
         };
-        let tag = cmd.tag();
-        try!(self.send(cmd, StorageCb::Boolean(callback)));
-        KV_COMMAND_COUNTER_VEC.with_label_values(&[tag]).inc();
-        Ok(())
+        self.send_future(cmd, StorageCb::Boolean)
     }
 
     pub fn async_delete_range(
@@ -663,8 +1190,7 @@ impl Storage {
         ctx: Context,
         start_key: Key,
         end_key: Key,
-        callback: Callback<()>,
-    ) -> Result<()> {
+    ) -> StorageFuture<()> {
         let mut modifies = Vec::with_capacity(DATA_CFS.len());
         for cf in DATA_CFS {
             // We enable memtable prefix bloom for CF_WRITE column family, for delete_range
@@ -680,150 +1206,359 @@ impl Storage {
             modifies.push(Modify::DeleteRange(cf, s, end_key.clone()));
         }
 
-        try!(self.engine.async_write(
-            &ctx,
-            modifies,
-            box |(_, res): (_, engine::Result<_>)| { callback(res.map_err(Error::from)) }
-        ));
+        let future = self.write_future(&ctx, modifies);
         KV_COMMAND_COUNTER_VEC
             .with_label_values(&["delete_range"])
             .inc();
-        Ok(())
+        future
     }
 
-    pub fn async_cleanup(
-        &self,
-        ctx: Context,
-        key: Key,
-        start_ts: u64,
-        callback: Callback<()>,
-    ) -> Result<()> {
+    pub fn async_cleanup(&self, ctx: Context, key: Key, start_ts: u64) -> StorageFuture<()> {
         let cmd = Command::Cleanup {
             ctx: ctx,
             key: key,
             start_ts: start_ts,
         };
-        let tag = cmd.tag();
-        try!(self.send(cmd, StorageCb::Boolean(callback)));
-        KV_COMMAND_COUNTER_VEC.with_label_values(&[tag]).inc();
-        Ok(())
+        self.send_future(cmd, StorageCb::Boolean)
+    }
+
+    pub fn async_rollback(&self, ctx: Context, keys: Vec<Key>, start_ts: u64) -> StorageFuture<()> {
+        let cmd = Command::Rollback {
+            ctx: ctx,
+            keys: keys,
+            start_ts: start_ts,
+        };
+        self.send_future(cmd, StorageCb::Boolean)
     }
 
-    pub fn async_rollback(
+    /// Locks the keys for a pessimistic transaction before they are prewritten, the
+    /// keys written after `for_update_ts` fail with write conflicts.
+    pub fn async_acquire_pessimistic_lock(
         &self,
         ctx: Context,
         keys: Vec<Key>,
+        primary: Vec<u8>,
         start_ts: u64,
-        callback: Callback<()>,
-    ) -> Result<()> {
-        let cmd = Command::Rollback {
+        for_update_ts: u64,
+        options: Options,
+    ) -> StorageFuture<Vec<Result<()>>> {
+        hot_keys::record_txn_write_keys(keys.iter());
+        let cmd = Command::AcquirePessimisticLock {
             ctx: ctx,
             keys: keys,
+            primary: primary,
             start_ts: start_ts,
+            for_update_ts: for_update_ts,
+            options: options,
         };
-        let tag = cmd.tag();
-        try!(self.send(cmd, StorageCb::Boolean(callback)));
-        KV_COMMAND_COUNTER_VEC.with_label_values(&[tag]).inc();
-        Ok(())
+        self.send_future(cmd, StorageCb::Booleans)
     }
 
-    pub fn async_scan_lock(
+    /// Locks the keys like `async_acquire_pessimistic_lock`, the latest committed values
+    /// of the keys are returned if `options.return_values` is set, so the client doesn't
+    /// have to read them again after locking.
+    pub fn async_acquire_pessimistic_lock_with_result(
         &self,
         ctx: Context,
-        max_ts: u64,
-        callback: Callback<Vec<LockInfo>>,
-    ) -> Result<()> {
-        let cmd = Command::ScanLock {
+        keys: Vec<Key>,
+        primary: Vec<u8>,
+        start_ts: u64,
+        for_update_ts: u64,
+        options: Options,
+    ) -> StorageFuture<PessimisticLockResult> {
+        hot_keys::record_txn_write_keys(keys.iter());
+        let cmd = Command::AcquirePessimisticLock {
             ctx: ctx,
-            max_ts: max_ts,
+            keys: keys,
+            primary: primary,
+            start_ts: start_ts,
+            for_update_ts: for_update_ts,
+            options: options,
         };
-        let tag = cmd.tag();
-        try!(self.send(cmd, StorageCb::Locks(callback)));
-        KV_COMMAND_COUNTER_VEC.with_label_values(&[tag]).inc();
-        Ok(())
+        self.send_future(cmd, StorageCb::PessimisticLock)
     }
 
-    pub fn async_resolve_lock(
+    /// Releases the pessimistic locks acquired at or before `for_update_ts`.
+    pub fn async_pessimistic_rollback(
         &self,
         ctx: Context,
+        keys: Vec<Key>,
         start_ts: u64,
-        commit_ts: Option<u64>,
-        callback: Callback<()>,
-    ) -> Result<()> {
-        let cmd = Command::ResolveLock {
+        for_update_ts: u64,
+    ) -> StorageFuture<()> {
+        let cmd = Command::PessimisticRollback {
             ctx: ctx,
+            keys: keys,
             start_ts: start_ts,
-            commit_ts: commit_ts,
-            scan_key: None,
-            keys: vec![],
+            for_update_ts: for_update_ts,
         };
-        let tag = cmd.tag();
-        try!(self.send(cmd, StorageCb::Boolean(callback)));
-        KV_COMMAND_COUNTER_VEC.with_label_values(&[tag]).inc();
-        Ok(())
+        self.send_future(cmd, StorageCb::Boolean)
     }
 
-    pub fn async_gc(&self, ctx: Context, safe_point: u64, callback: Callback<()>) -> Result<()> {
-        let cmd = Command::Gc {
+    /// Checks whether the transaction of `lock_ts` is committed, rolled back or still
+    /// alive by its primary key, the transaction is rolled back if its lock has expired.
+    /// The min_commit_ts of an alive transaction is pushed beyond `caller_start_ts`, so
+    /// the reader can bypass its locks.
+    pub fn async_check_txn_status(
+        &self,
+        ctx: Context,
+        primary_key: Key,
+        lock_ts: u64,
+        caller_start_ts: u64,
+        current_ts: u64,
+    ) -> StorageFuture<TxnStatus> {
+        let cmd = Command::CheckTxnStatus {
             ctx: ctx,
-            safe_point: safe_point,
-            ratio_threshold: self.gc_ratio_threshold,
-            scan_key: None,
-            keys: vec![],
+            primary_key: primary_key,
+            lock_ts: lock_ts,
+            caller_start_ts: caller_start_ts,
+            current_ts: current_ts,
         };
-        let tag = cmd.tag();
-        try!(self.send(cmd, StorageCb::Boolean(callback)));
-        KV_COMMAND_COUNTER_VEC.with_label_values(&[tag]).inc();
-        Ok(())
+        self.send_future(cmd, StorageCb::TxnStatus)
     }
 
-    pub fn async_raw_get(
+    /// Extends the ttl of the primary lock of a transaction, returns the ttl of the lock.
+    pub fn async_txn_heart_beat(
         &self,
         ctx: Context,
-        key: Vec<u8>,
-        callback: Callback<Option<Vec<u8>>>,
-    ) -> Result<()> {
+        primary_key: Key,
+        start_ts: u64,
+        advise_ttl: u64,
+    ) -> StorageFuture<u64> {
+        let cmd = Command::TxnHeartBeat {
+            ctx: ctx,
+            primary_key: primary_key,
+            start_ts: start_ts,
+            advise_ttl: advise_ttl,
+        };
+        self.send_future(cmd, StorageCb::Ttl)
+    }
+
+    /// Checks the secondary keys of an async commit transaction, the transaction is
+    /// rolled back if any of the keys isn't prewritten.
+    pub fn async_check_secondary_locks(
+        &self,
+        ctx: Context,
+        keys: Vec<Key>,
+        start_ts: u64,
+    ) -> StorageFuture<SecondaryLocksStatus> {
+        let cmd = Command::CheckSecondaryLocks {
+            ctx: ctx,
+            keys: keys,
+            start_ts: start_ts,
+        };
+        self.send_future(cmd, StorageCb::SecondaryLocks)
+    }
+
+    pub fn async_scan_lock(&self, ctx: Context, max_ts: u64) -> StorageFuture<Vec<LockInfo>> {
+        let cmd = Command::ScanLock {
+            ctx: ctx,
+            max_ts: max_ts,
+        };
+        self.send_future(cmd, StorageCb::Locks)
+    }
+
+    pub fn async_resolve_lock(
+        &self,
+        ctx: Context,
+        start_ts: u64,
+        commit_ts: Option<u64>,
+    ) -> StorageFuture<()> {
+        let cmd = Command::ResolveLock {
+            ctx: ctx,
+            start_ts: start_ts,
+            commit_ts: commit_ts,
+            scan_key: None,
+            keys: vec![],
+        };
+        self.send_future(cmd, StorageCb::Boolean)
+    }
+
+    /// Resolves the locks of the transaction on `keys` only, instead of scanning
+    /// the whole region for them.
+    pub fn async_resolve_lock_lite(
+        &self,
+        ctx: Context,
+        start_ts: u64,
+        commit_ts: Option<u64>,
+        keys: Vec<Key>,
+    ) -> StorageFuture<()> {
+        let cmd = Command::ResolveLockLite {
+            ctx: ctx,
+            start_ts: start_ts,
+            commit_ts: commit_ts,
+            resolve_keys: keys,
+        };
+        self.send_future(cmd, StorageCb::Boolean)
+    }
+
+    pub fn async_gc(&self, ctx: Context, safe_point: u64) -> StorageFuture<()> {
+        // The compactions drop most of the stale versions, the regions are skipped
+        // by the gc command if few versions are left in their mvcc properties.
+        if self.gc_by_compaction_filter {
+            compaction_filter::set_gc_safe_point(safe_point);
+        }
+        let cmd = Command::Gc {
+            ctx: ctx,
+            safe_point: safe_point,
+            ratio_threshold: *self.gc_ratio_threshold.read().unwrap(),
+            scan_key: None,
+            keys: vec![],
+        };
+        self.send_future(cmd, StorageCb::Boolean)
+    }
+
+    pub fn async_raw_get(&self, ctx: Context, key: Vec<u8>) -> StorageFuture<Option<Vec<u8>>> {
+        hot_keys::record_read_keys(Some(key.as_slice()));
         let cmd = Command::RawGet {
             ctx: ctx,
             key: Key::from_encoded(key),
         };
-        try!(self.send(cmd, StorageCb::SingleValue(callback)));
+        let (cb, future) = paired_future_callback();
+        if let Err(e) = self.send(cmd, StorageCb::SingleValue(cb)) {
+            return box future::err(e);
+        }
         RAWKV_COMMAND_COUNTER_VEC.with_label_values(&["get"]).inc();
-        Ok(())
+        future
     }
 
-    pub fn async_raw_put(
+    pub fn async_raw_batch_get(
         &self,
         ctx: Context,
-        key: Vec<u8>,
-        value: Vec<u8>,
-        callback: Callback<()>,
-    ) -> Result<()> {
-        try!(self.engine
-            .async_write(&ctx,
-                         vec![Modify::Put(CF_DEFAULT, Key::from_encoded(key), value)],
-                         box |(_, res): (_, engine::Result<_>)| {
-                             callback(res.map_err(Error::from))
-                         }));
+        keys: Vec<Vec<u8>>,
+    ) -> StorageFuture<Vec<Result<KvPair>>> {
+        hot_keys::record_read_keys(keys.iter().map(|k| k.as_slice()));
+        let cmd = Command::RawBatchGet {
+            ctx: ctx,
+            keys: keys.into_iter().map(Key::from_encoded).collect(),
+        };
+        let (cb, future) = paired_future_callback();
+        if let Err(e) = self.send(cmd, StorageCb::KvPairs(cb)) {
+            return box future::err(e);
+        }
+        RAWKV_COMMAND_COUNTER_VEC
+            .with_label_values(&["batch_get"])
+            .inc();
+        future
+    }
+
+    pub fn async_raw_put(&self, ctx: Context, key: Vec<u8>, value: Vec<u8>) -> StorageFuture<()> {
+        if let Err(e) = self.check_key_size(&key)
+            .and_then(|_| self.check_value_size(&value))
+        {
+            return box future::err(e);
+        }
+        hot_keys::record_write_keys(Some(key.as_slice()));
+        if let Some(tracker) = tracker::current() {
+            tracker.add_write_bytes((key.len() + value.len()) as u64);
+        }
+        let future = self.raw_write_future(
+            &ctx,
+            vec![Modify::Put(CF_DEFAULT, Key::from_encoded(key), value)],
+        );
         RAWKV_COMMAND_COUNTER_VEC.with_label_values(&["put"]).inc();
-        Ok(())
+        future
     }
 
-    pub fn async_raw_delete(
-        &self,
-        ctx: Context,
-        key: Vec<u8>,
-        callback: Callback<()>,
-    ) -> Result<()> {
-        try!(self.engine.async_write(
+    pub fn async_raw_delete(&self, ctx: Context, key: Vec<u8>) -> StorageFuture<()> {
+        if let Err(e) = self.check_key_size(&key) {
+            return box future::err(e);
+        }
+        hot_keys::record_write_keys(Some(key.as_slice()));
+        if let Some(tracker) = tracker::current() {
+            tracker.add_write_bytes(key.len() as u64);
+        }
+        let future = self.raw_write_future(
             &ctx,
             vec![Modify::Delete(CF_DEFAULT, Key::from_encoded(key))],
-            box |(_, res): (_, engine::Result<_>)| { callback(res.map_err(Error::from)) }
-        ));
+        );
         RAWKV_COMMAND_COUNTER_VEC
             .with_label_values(&["delete"])
             .inc();
-        Ok(())
+        future
+    }
+
+    /// Deletes all the keys in `[start_key, end_key)` in one write.
+    pub fn async_raw_delete_range(
+        &self,
+        ctx: Context,
+        start_key: Vec<u8>,
+        end_key: Vec<u8>,
+    ) -> StorageFuture<()> {
+        if let Err(e) = self.check_key_size(&start_key)
+            .and_then(|_| self.check_key_size(&end_key))
+        {
+            return box future::err(e);
+        }
+        if start_key >= end_key {
+            let e = format!(
+                "invalid range, start key {} is not smaller than end key {}",
+                escape(&start_key),
+                escape(&end_key)
+            );
+            return box future::err(Error::Other(e.into()));
+        }
+        let future = self.raw_write_future(
+            &ctx,
+            vec![
+                Modify::DeleteRange(
+                    CF_DEFAULT,
+                    Key::from_encoded(start_key),
+                    Key::from_encoded(end_key),
+                ),
+            ],
+        );
+        RAWKV_COMMAND_COUNTER_VEC
+            .with_label_values(&["delete_range"])
+            .inc();
+        future
+    }
+
+    /// Puts all the pairs in one write, so they are proposed to raft together.
+    pub fn async_raw_batch_put(&self, ctx: Context, pairs: Vec<KvPair>) -> StorageFuture<()> {
+        for &(ref key, ref value) in &pairs {
+            if let Err(e) = self.check_key_size(key)
+                .and_then(|_| self.check_value_size(value))
+            {
+                return box future::err(e);
+            }
+        }
+        hot_keys::record_write_keys(pairs.iter().map(|&(ref k, _)| k.as_slice()));
+        if let Some(tracker) = tracker::current() {
+            let bytes: usize = pairs.iter().map(|&(ref k, ref v)| k.len() + v.len()).sum();
+            tracker.add_write_bytes(bytes as u64);
+        }
+        let modifies = pairs
+            .into_iter()
+            .map(|(k, v)| Modify::Put(CF_DEFAULT, Key::from_encoded(k), v))
+            .collect();
+        let future = self.raw_write_future(&ctx, modifies);
+        RAWKV_COMMAND_COUNTER_VEC
+            .with_label_values(&["batch_put"])
+            .inc();
+        future
+    }
+
+    /// Deletes all the keys in one write, so they are proposed to raft together.
+    pub fn async_raw_batch_delete(&self, ctx: Context, keys: Vec<Vec<u8>>) -> StorageFuture<()> {
+        for key in &keys {
+            if let Err(e) = self.check_key_size(key) {
+                return box future::err(e);
+            }
+        }
+        hot_keys::record_write_keys(keys.iter().map(|k| k.as_slice()));
+        if let Some(tracker) = tracker::current() {
+            let bytes: usize = keys.iter().map(|k| k.len()).sum();
+            tracker.add_write_bytes(bytes as u64);
+        }
+        let modifies = keys.into_iter()
+            .map(|k| Modify::Delete(CF_DEFAULT, Key::from_encoded(k)))
+            .collect();
+        let future = self.raw_write_future(&ctx, modifies);
+        RAWKV_COMMAND_COUNTER_VEC
+            .with_label_values(&["batch_delete"])
+            .inc();
+        future
     }
 
     pub fn async_raw_scan(
@@ -831,45 +1566,38 @@ impl Storage {
         ctx: Context,
         key: Vec<u8>,
         limit: usize,
-        callback: Callback<Vec<Result<KvPair>>>,
-    ) -> Result<()> {
+        key_only: bool,
+    ) -> StorageFuture<Vec<Result<KvPair>>> {
+        hot_keys::record_read_keys(Some(key.as_slice()));
         let cmd = Command::RawScan {
             ctx: ctx,
             start_key: Key::from_encoded(key),
             limit: limit,
+            key_only: key_only,
         };
-        try!(self.send(cmd, StorageCb::KvPairs(callback)));
+        let (cb, future) = paired_future_callback();
+        if let Err(e) = self.send(cmd, StorageCb::KvPairs(cb)) {
+            return box future::err(e);
+        }
         RAWKV_COMMAND_COUNTER_VEC.with_label_values(&["scan"]).inc();
-        Ok(())
+        future
     }
 
-    pub fn async_mvcc_by_key(
-        &self,
-        ctx: Context,
-        key: Key,
-        callback: Callback<MvccInfo>,
-    ) -> Result<()> {
+    pub fn async_mvcc_by_key(&self, ctx: Context, key: Key) -> StorageFuture<MvccInfo> {
         let cmd = Command::MvccByKey { ctx: ctx, key: key };
-        let tag = cmd.tag();
-        try!(self.send(cmd, StorageCb::MvccInfoByKey(callback)));
-        KV_COMMAND_COUNTER_VEC.with_label_values(&[tag]).inc();
-        Ok(())
+        self.send_future(cmd, StorageCb::MvccInfoByKey)
     }
 
     pub fn async_mvcc_by_start_ts(
         &self,
         ctx: Context,
         start_ts: u64,
-        callback: Callback<Option<(Key, MvccInfo)>>,
-    ) -> Result<()> {
+    ) -> StorageFuture<Option<(Key, MvccInfo)>> {
         let cmd = Command::MvccByStartTs {
             ctx: ctx,
             start_ts: start_ts,
         };
-        let tag = cmd.tag();
-        try!(self.send(cmd, StorageCb::MvccInfoByStartTs(callback)));
-        KV_COMMAND_COUNTER_VEC.with_label_values(&[tag]).inc();
-        Ok(())
+        self.send_future(cmd, StorageCb::MvccInfoByStartTs)
     }
 }
 
@@ -879,7 +1607,16 @@ impl Clone for Storage {
             engine: self.engine.clone(),
             sendch: self.sendch.clone(),
             handle: self.handle.clone(),
-            gc_ratio_threshold: self.gc_ratio_threshold,
+            gc_ratio_threshold: self.gc_ratio_threshold.clone(),
+            write_limiter: self.write_limiter.clone(),
+            max_key_size: self.max_key_size,
+            max_value_size: self.max_value_size,
+            gc_by_compaction_filter: self.gc_by_compaction_filter,
+            causal_ts: self.causal_ts.clone(),
+            lock_observer: self.lock_observer.clone(),
+            max_ts: self.max_ts.clone(),
+            pessimistic_locks: self.pessimistic_locks.clone(),
+            detector: self.detector.clone(),
         }
     }
 }
@@ -915,8 +1652,17 @@ quick_error! {
             cause(err)
             description(err.description())
         }
-        SchedTooBusy {
+        SchedTooBusy(backoff_ms: u64) {
             description("scheduler is too busy")
+            display("scheduler is too busy, retry after {}ms", backoff_ms)
+        }
+        KeyTooLarge(size: usize, limit: usize) {
+            description("key is too large")
+            display("key size {} exceeds the limit {}", size, limit)
+        }
+        ValueTooLarge(size: usize, limit: usize) {
+            description("value is too large")
+            display("value size {} exceeds the limit {}", size, limit)
         }
     }
 }
@@ -934,6 +1680,10 @@ pub fn get_tag_from_header(header: &errorpb::Error) -> &'static str {
         "stale_epoch"
     } else if header.has_server_is_busy() {
         "server_is_busy"
+    } else if header.has_stale_command() {
+        "stale_command"
+    } else if header.has_store_not_match() {
+        "store_not_match"
     } else {
         "other"
     }
@@ -944,6 +1694,18 @@ mod tests {
     use super::*;
     use std::sync::mpsc::{channel, Sender};
     use kvproto::kvrpcpb::Context;
+    use kvproto::metapb::Region;
+    use raft::StateRole;
+    use raftstore::coprocessor::{ObserverContext, RegionObserver};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use util::config::{ReadableDuration, ReadableSize};
+
+    // Waits for `f` in the background and passes its result to `cb`, so that
+    // several commands can be in flight at the same time.
+    fn on_done<T: Send + 'static>(f: StorageFuture<T>, cb: Callback<T>) {
+        thread::spawn(move || cb(f.wait()));
+    }
 
     fn expect_get_none(done: Sender<i32>, id: i32) -> Callback<Option<Value>> {
         Box::new(move |x: Result<Option<Value>>| {
@@ -977,7 +1739,7 @@ mod tests {
         Box::new(move |x: Result<T>| {
             assert!(x.is_err());
             match x {
-                Err(Error::SchedTooBusy) => {}
+                Err(Error::SchedTooBusy(_)) => {}
                 _ => panic!("expect too busy"),
             }
             done.send(id).unwrap();
@@ -1014,53 +1776,36 @@ mod tests {
         let mut storage = Storage::new(&config).unwrap();
         storage.start(&config).unwrap();
         let (tx, rx) = channel();
-        storage
-            .async_get(
-                Context::new(),
-                make_key(b"x"),
-                100,
-                expect_get_none(tx.clone(), 0),
-            )
-            .unwrap();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"x"), 100),
+            expect_get_none(tx.clone(), 0),
+        );
         rx.recv().unwrap();
-        storage
-            .async_prewrite(
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
                 vec![Mutation::Put((make_key(b"x"), b"100".to_vec()))],
                 b"x".to_vec(),
                 100,
                 Options::default(),
-                expect_ok(tx.clone(), 1),
-            )
-            .unwrap();
+            ),
+            expect_ok(tx.clone(), 1),
+        );
         rx.recv().unwrap();
-        storage
-            .async_commit(
-                Context::new(),
-                vec![make_key(b"x")],
-                100,
-                101,
-                expect_ok(tx.clone(), 2),
-            )
-            .unwrap();
+        on_done(
+            storage.async_commit(Context::new(), vec![make_key(b"x")], 100, 101),
+            expect_ok(tx.clone(), 2),
+        );
         rx.recv().unwrap();
-        storage
-            .async_get(
-                Context::new(),
-                make_key(b"x"),
-                100,
-                expect_get_none(tx.clone(), 3),
-            )
-            .unwrap();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"x"), 100),
+            expect_get_none(tx.clone(), 3),
+        );
         rx.recv().unwrap();
-        storage
-            .async_get(
-                Context::new(),
-                make_key(b"x"),
-                101,
-                expect_get_val(tx.clone(), b"100".to_vec(), 4),
-            )
-            .unwrap();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"x"), 101),
+            expect_get_val(tx.clone(), b"100".to_vec(), 4),
+        );
         rx.recv().unwrap();
         storage.stop().unwrap();
     }
@@ -1073,8 +1818,8 @@ mod tests {
         let mut storage = Storage::from_engine(engine, &config).unwrap();
         storage.start(&config).unwrap();
         let (tx, rx) = channel();
-        storage
-            .async_prewrite(
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
                 vec![
                     Mutation::Put((make_key(b"a"), b"aa".to_vec())),
@@ -1084,9 +1829,9 @@ mod tests {
                 b"a".to_vec(),
                 1,
                 Options::default(),
-                expect_fail(tx.clone(), 0),
-            )
-            .unwrap();
+            ),
+            expect_fail(tx.clone(), 0),
+        );
         rx.recv().unwrap();
         storage.stop().unwrap();
     }
@@ -1097,8 +1842,8 @@ mod tests {
         let mut storage = Storage::new(&config).unwrap();
         storage.start(&config).unwrap();
         let (tx, rx) = channel();
-        storage
-            .async_prewrite(
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
                 vec![
                     Mutation::Put((make_key(b"a"), b"aa".to_vec())),
@@ -1108,38 +1853,46 @@ mod tests {
                 b"a".to_vec(),
                 1,
                 Options::default(),
-                expect_ok(tx.clone(), 0),
-            )
-            .unwrap();
+            ),
+            expect_ok(tx.clone(), 0),
+        );
         rx.recv().unwrap();
-        storage
-            .async_commit(
+        on_done(
+            storage.async_commit(
                 Context::new(),
                 vec![make_key(b"a"), make_key(b"b"), make_key(b"c")],
                 1,
                 2,
-                expect_ok(tx.clone(), 1),
-            )
-            .unwrap();
+            ),
+            expect_ok(tx.clone(), 1),
+        );
         rx.recv().unwrap();
-        storage
-            .async_scan(
-                Context::new(),
-                make_key(b"\x00"),
-                1000,
-                5,
-                Options::default(),
-                expect_scan(
-                    tx.clone(),
-                    vec![
-                        Some((b"a".to_vec(), b"aa".to_vec())),
-                        Some((b"b".to_vec(), b"bb".to_vec())),
-                        Some((b"c".to_vec(), b"cc".to_vec())),
-                    ],
-                    2,
-                ),
-            )
-            .unwrap();
+        on_done(
+            storage.async_scan(Context::new(), make_key(b"\x00"), 1000, 5, Options::default()),
+            expect_scan(
+                tx.clone(),
+                vec![
+                    Some((b"a".to_vec(), b"aa".to_vec())),
+                    Some((b"b".to_vec(), b"bb".to_vec())),
+                    Some((b"c".to_vec(), b"cc".to_vec())),
+                ],
+                2,
+            ),
+        );
+        rx.recv().unwrap();
+        let mut options = Options::default();
+        options.reverse_scan = true;
+        on_done(
+            storage.async_scan(Context::new(), make_key(b"c"), 1000, 5, options),
+            expect_scan(
+                tx.clone(),
+                vec![
+                    Some((b"b".to_vec(), b"bb".to_vec())),
+                    Some((b"a".to_vec(), b"aa".to_vec())),
+                ],
+                3,
+            ),
+        );
         rx.recv().unwrap();
         storage.stop().unwrap();
     }
@@ -1150,8 +1903,8 @@ mod tests {
         let mut storage = Storage::new(&config).unwrap();
         storage.start(&config).unwrap();
         let (tx, rx) = channel();
-        storage
-            .async_prewrite(
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
                 vec![
                     Mutation::Put((make_key(b"a"), b"aa".to_vec())),
@@ -1161,36 +1914,36 @@ mod tests {
                 b"a".to_vec(),
                 1,
                 Options::default(),
-                expect_ok(tx.clone(), 0),
-            )
-            .unwrap();
+            ),
+            expect_ok(tx.clone(), 0),
+        );
         rx.recv().unwrap();
-        storage
-            .async_commit(
+        on_done(
+            storage.async_commit(
                 Context::new(),
                 vec![make_key(b"a"), make_key(b"b"), make_key(b"c")],
                 1,
                 2,
-                expect_ok(tx.clone(), 1),
-            )
-            .unwrap();
+            ),
+            expect_ok(tx.clone(), 1),
+        );
         rx.recv().unwrap();
-        storage
-            .async_batch_get(
+        on_done(
+            storage.async_batch_get(
                 Context::new(),
                 vec![make_key(b"a"), make_key(b"b"), make_key(b"c")],
                 5,
-                expect_batch_get_vals(
-                    tx.clone(),
-                    vec![
-                        Some((b"a".to_vec(), b"aa".to_vec())),
-                        Some((b"b".to_vec(), b"bb".to_vec())),
-                        Some((b"c".to_vec(), b"cc".to_vec())),
-                    ],
-                    2,
-                ),
-            )
-            .unwrap();
+            ),
+            expect_batch_get_vals(
+                tx.clone(),
+                vec![
+                    Some((b"a".to_vec(), b"aa".to_vec())),
+                    Some((b"b".to_vec(), b"bb".to_vec())),
+                    Some((b"c".to_vec(), b"cc".to_vec())),
+                ],
+                2,
+            ),
+        );
         rx.recv().unwrap();
         storage.stop().unwrap();
     }
@@ -1201,128 +1954,150 @@ mod tests {
         let mut storage = Storage::new(&config).unwrap();
         storage.start(&config).unwrap();
         let (tx, rx) = channel();
-        storage
-            .async_prewrite(
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
                 vec![Mutation::Put((make_key(b"x"), b"100".to_vec()))],
                 b"x".to_vec(),
                 100,
                 Options::default(),
-                expect_ok(tx.clone(), 0),
-            )
-            .unwrap();
-        storage
-            .async_prewrite(
+            ),
+            expect_ok(tx.clone(), 0),
+        );
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
                 vec![Mutation::Put((make_key(b"y"), b"101".to_vec()))],
                 b"y".to_vec(),
                 101,
                 Options::default(),
-                expect_ok(tx.clone(), 1),
-            )
-            .unwrap();
+            ),
+            expect_ok(tx.clone(), 1),
+        );
         rx.recv().unwrap();
         rx.recv().unwrap();
-        storage
-            .async_commit(
-                Context::new(),
-                vec![make_key(b"x")],
-                100,
-                110,
-                expect_ok(tx.clone(), 2),
-            )
-            .unwrap();
-        storage
-            .async_commit(
-                Context::new(),
-                vec![make_key(b"y")],
-                101,
-                111,
-                expect_ok(tx.clone(), 3),
-            )
-            .unwrap();
+        on_done(
+            storage.async_commit(Context::new(), vec![make_key(b"x")], 100, 110),
+            expect_ok(tx.clone(), 2),
+        );
+        on_done(
+            storage.async_commit(Context::new(), vec![make_key(b"y")], 101, 111),
+            expect_ok(tx.clone(), 3),
+        );
         rx.recv().unwrap();
         rx.recv().unwrap();
-        storage
-            .async_get(
-                Context::new(),
-                make_key(b"x"),
-                120,
-                expect_get_val(tx.clone(), b"100".to_vec(), 4),
-            )
-            .unwrap();
-        storage
-            .async_get(
-                Context::new(),
-                make_key(b"y"),
-                120,
-                expect_get_val(tx.clone(), b"101".to_vec(), 5),
-            )
-            .unwrap();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"x"), 120),
+            expect_get_val(tx.clone(), b"100".to_vec(), 4),
+        );
+        on_done(
+            storage.async_get(Context::new(), make_key(b"y"), 120),
+            expect_get_val(tx.clone(), b"101".to_vec(), 5),
+        );
         rx.recv().unwrap();
         rx.recv().unwrap();
-        storage
-            .async_prewrite(
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
                 vec![Mutation::Put((make_key(b"x"), b"105".to_vec()))],
                 b"x".to_vec(),
                 105,
                 Options::default(),
-                expect_fail(tx.clone(), 6),
-            )
-            .unwrap();
+            ),
+            expect_fail(tx.clone(), 6),
+        );
         rx.recv().unwrap();
         storage.stop().unwrap();
     }
 
     #[test]
-    fn test_sched_too_busy() {
+    fn test_sched_pending_write_bytes() {
         let mut config = Config::default();
-        config.scheduler_too_busy_threshold = 1;
+        config.scheduler_pending_write_threshold = ReadableSize(64);
         let mut storage = Storage::new(&config).unwrap();
         storage.start(&config).unwrap();
         let (tx, rx) = channel();
-        storage
-            .async_get(
+        // A large write is allowed when nothing is pending.
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
-                make_key(b"x"),
+                vec![Mutation::Put((make_key(b"x"), vec![0; 128]))],
+                b"x".to_vec(),
                 100,
-                expect_get_none(tx.clone(), 0),
-            )
-            .unwrap();
-        storage
-            .async_prewrite(
+                Options::default(),
+            ),
+            expect_ok(tx.clone(), 0),
+        );
+        on_done(
+            storage.async_prewrite(
+                Context::new(),
+                vec![Mutation::Put((make_key(b"y"), b"101".to_vec()))],
+                b"y".to_vec(),
+                101,
+                Options::default(),
+            ),
+            expect_too_busy(tx.clone(), 1),
+        );
+        rx.recv().unwrap();
+        rx.recv().unwrap();
+        on_done(
+            storage.async_prewrite(
+                Context::new(),
+                vec![Mutation::Put((make_key(b"z"), b"102".to_vec()))],
+                b"z".to_vec(),
+                102,
+                Options::default(),
+            ),
+            expect_ok(tx.clone(), 2),
+        );
+        rx.recv().unwrap();
+        storage.stop().unwrap();
+    }
+
+    #[test]
+    fn test_sched_too_busy() {
+        let mut config = Config::default();
+        config.scheduler_too_busy_threshold = 1;
+        let mut storage = Storage::new(&config).unwrap();
+        storage.start(&config).unwrap();
+        let (tx, rx) = channel();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"x"), 100),
+            expect_get_none(tx.clone(), 0),
+        );
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
                 vec![Mutation::Put((make_key(b"x"), b"100".to_vec()))],
                 b"x".to_vec(),
                 100,
                 Options::default(),
-                expect_ok(tx.clone(), 1),
-            )
-            .unwrap();
-        storage
-            .async_prewrite(
+            ),
+            expect_ok(tx.clone(), 1),
+        );
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
                 vec![Mutation::Put((make_key(b"y"), b"101".to_vec()))],
                 b"y".to_vec(),
                 101,
                 Options::default(),
-                expect_too_busy(tx.clone(), 2),
-            )
-            .unwrap();
+            ),
+            expect_too_busy(tx.clone(), 2),
+        );
         rx.recv().unwrap();
         rx.recv().unwrap();
         rx.recv().unwrap();
-        storage
-            .async_prewrite(
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
                 vec![Mutation::Put((make_key(b"z"), b"102".to_vec()))],
                 b"y".to_vec(),
                 102,
                 Options::default(),
-                expect_ok(tx.clone(), 3),
-            )
-            .unwrap();
+            ),
+            expect_ok(tx.clone(), 3),
+        );
         rx.recv().unwrap();
         storage.stop().unwrap();
     }
@@ -1333,34 +2108,26 @@ mod tests {
         let mut storage = Storage::new(&config).unwrap();
         storage.start(&config).unwrap();
         let (tx, rx) = channel();
-        storage
-            .async_prewrite(
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
                 vec![Mutation::Put((make_key(b"x"), b"100".to_vec()))],
                 b"x".to_vec(),
                 100,
                 Options::default(),
-                expect_ok(tx.clone(), 0),
-            )
-            .unwrap();
+            ),
+            expect_ok(tx.clone(), 0),
+        );
         rx.recv().unwrap();
-        storage
-            .async_cleanup(
-                Context::new(),
-                make_key(b"x"),
-                100,
-                expect_ok(tx.clone(), 1),
-            )
-            .unwrap();
+        on_done(
+            storage.async_cleanup(Context::new(), make_key(b"x"), 100),
+            expect_ok(tx.clone(), 1),
+        );
         rx.recv().unwrap();
-        storage
-            .async_get(
-                Context::new(),
-                make_key(b"x"),
-                105,
-                expect_get_none(tx.clone(), 2),
-            )
-            .unwrap();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"x"), 105),
+            expect_get_none(tx.clone(), 2),
+        );
         rx.recv().unwrap();
         storage.stop().unwrap();
     }
@@ -1373,51 +2140,38 @@ mod tests {
         let (tx, rx) = channel();
         let mut ctx = Context::new();
         ctx.set_priority(CommandPri::High);
-        storage
-            .async_get(ctx, make_key(b"x"), 100, expect_get_none(tx.clone(), 0))
-            .unwrap();
+        on_done(storage.async_get(ctx, make_key(b"x"), 100), expect_get_none(tx.clone(), 0));
         rx.recv().unwrap();
         let mut ctx = Context::new();
         ctx.set_priority(CommandPri::High);
-        storage
-            .async_prewrite(
+        on_done(
+            storage.async_prewrite(
                 ctx,
                 vec![Mutation::Put((make_key(b"x"), b"100".to_vec()))],
                 b"x".to_vec(),
                 100,
                 Options::default(),
-                expect_ok(tx.clone(), 1),
-            )
-            .unwrap();
+            ),
+            expect_ok(tx.clone(), 1),
+        );
         rx.recv().unwrap();
         let mut ctx = Context::new();
         ctx.set_priority(CommandPri::High);
-        storage
-            .async_commit(
-                ctx,
-                vec![make_key(b"x")],
-                100,
-                101,
-                expect_ok(tx.clone(), 2),
-            )
-            .unwrap();
+        on_done(
+            storage.async_commit(ctx, vec![make_key(b"x")], 100, 101),
+            expect_ok(tx.clone(), 2),
+        );
         rx.recv().unwrap();
         let mut ctx = Context::new();
         ctx.set_priority(CommandPri::High);
-        storage
-            .async_get(ctx, make_key(b"x"), 100, expect_get_none(tx.clone(), 3))
-            .unwrap();
+        on_done(storage.async_get(ctx, make_key(b"x"), 100), expect_get_none(tx.clone(), 3));
         rx.recv().unwrap();
         let mut ctx = Context::new();
         ctx.set_priority(CommandPri::High);
-        storage
-            .async_get(
-                ctx,
-                make_key(b"x"),
-                101,
-                expect_get_val(tx.clone(), b"100".to_vec(), 4),
-            )
-            .unwrap();
+        on_done(
+            storage.async_get(ctx, make_key(b"x"), 101),
+            expect_get_val(tx.clone(), b"100".to_vec(), 4),
+        );
         rx.recv().unwrap();
         storage.stop().unwrap();
     }
@@ -1429,50 +2183,35 @@ mod tests {
         let mut storage = Storage::new(&config).unwrap();
         storage.start(&config).unwrap();
         let (tx, rx) = channel();
-        storage
-            .async_get(
-                Context::new(),
-                make_key(b"x"),
-                100,
-                expect_get_none(tx.clone(), 0),
-            )
-            .unwrap();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"x"), 100),
+            expect_get_none(tx.clone(), 0),
+        );
         rx.recv().unwrap();
-        storage
-            .async_prewrite(
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
                 vec![Mutation::Put((make_key(b"x"), b"100".to_vec()))],
                 b"x".to_vec(),
                 100,
                 Options::default(),
-                expect_ok(tx.clone(), 1),
-            )
-            .unwrap();
+            ),
+            expect_ok(tx.clone(), 1),
+        );
         rx.recv().unwrap();
-        storage
-            .async_commit(
-                Context::new(),
-                vec![make_key(b"x")],
-                100,
-                101,
-                expect_ok(tx.clone(), 2),
-            )
-            .unwrap();
+        on_done(
+            storage.async_commit(Context::new(), vec![make_key(b"x")], 100, 101),
+            expect_ok(tx.clone(), 2),
+        );
         rx.recv().unwrap();
 
-        storage
-            .async_pause(Context::new(), 1000, expect_ok(tx.clone(), 3))
-            .unwrap();
+        on_done(storage.async_pause(Context::new(), 1000), expect_ok(tx.clone(), 3));
         let mut ctx = Context::new();
         ctx.set_priority(CommandPri::High);
-        storage
-            .async_get(
-                ctx,
-                make_key(b"x"),
-                101,
-                expect_get_val(tx.clone(), b"100".to_vec(), 4),
-            )
-            .unwrap();
+        on_done(
+            storage.async_get(ctx, make_key(b"x"), 101),
+            expect_get_val(tx.clone(), b"100".to_vec(), 4),
+        );
         // Command Get with high priority not block by command Pause.
         assert_eq!(rx.recv().unwrap(), 4);
         assert_eq!(rx.recv().unwrap(), 3);
@@ -1487,8 +2226,8 @@ mod tests {
         storage.start(&config).unwrap();
         let (tx, rx) = channel();
         // Write x and y.
-        storage
-            .async_prewrite(
+        on_done(
+            storage.async_prewrite(
                 Context::new(),
                 vec![
                     Mutation::Put((make_key(b"x"), b"100".to_vec())),
@@ -1498,105 +2237,1051 @@ mod tests {
                 b"x".to_vec(),
                 100,
                 Options::default(),
-                expect_ok(tx.clone(), 0),
-            )
-            .unwrap();
+            ),
+            expect_ok(tx.clone(), 0),
+        );
         rx.recv().unwrap();
-        storage
-            .async_commit(
+        on_done(
+            storage.async_commit(
                 Context::new(),
                 vec![make_key(b"x"), make_key(b"y"), make_key(b"z")],
                 100,
                 101,
-                expect_ok(tx.clone(), 1),
-            )
-            .unwrap();
+            ),
+            expect_ok(tx.clone(), 1),
+        );
         rx.recv().unwrap();
-        storage
-            .async_get(
-                Context::new(),
-                make_key(b"x"),
-                101,
-                expect_get_val(tx.clone(), b"100".to_vec(), 2),
-            )
-            .unwrap();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"x"), 101),
+            expect_get_val(tx.clone(), b"100".to_vec(), 2),
+        );
         rx.recv().unwrap();
-        storage
-            .async_get(
-                Context::new(),
-                make_key(b"y"),
-                101,
-                expect_get_val(tx.clone(), b"100".to_vec(), 3),
-            )
-            .unwrap();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"y"), 101),
+            expect_get_val(tx.clone(), b"100".to_vec(), 3),
+        );
         rx.recv().unwrap();
-        storage
-            .async_get(
-                Context::new(),
-                make_key(b"z"),
-                101,
-                expect_get_val(tx.clone(), b"100".to_vec(), 4),
-            )
-            .unwrap();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"z"), 101),
+            expect_get_val(tx.clone(), b"100".to_vec(), 4),
+        );
         rx.recv().unwrap();
 
         // Delete range [x, z)
-        storage
-            .async_delete_range(
-                Context::new(),
-                make_key(b"x"),
-                make_key(b"z"),
-                expect_ok(tx.clone(), 5),
-            )
-            .unwrap();
+        on_done(
+            storage.async_delete_range(Context::new(), make_key(b"x"), make_key(b"z")),
+            expect_ok(tx.clone(), 5),
+        );
+        rx.recv().unwrap();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"x"), 101),
+            expect_get_none(tx.clone(), 6),
+        );
+        rx.recv().unwrap();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"y"), 101),
+            expect_get_none(tx.clone(), 7),
+        );
+        rx.recv().unwrap();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"z"), 101),
+            expect_get_val(tx.clone(), b"100".to_vec(), 8),
+        );
         rx.recv().unwrap();
-        storage
-            .async_get(
+
+        // Delete range ["", ""), it means delete all
+        on_done(
+            storage.async_delete_range(Context::new(), make_key(b""), make_key(b"")),
+            expect_ok(tx.clone(), 9),
+        );
+        rx.recv().unwrap();
+        on_done(
+            storage.async_get(Context::new(), make_key(b"z"), 101),
+            expect_get_none(tx.clone(), 10),
+        );
+        rx.recv().unwrap();
+        storage.stop().unwrap();
+    }
+
+    #[test]
+    fn test_command_priority() {
+        let mut ctx = Context::new();
+        ctx.set_priority(CommandPri::High);
+        let get = Command::Get {
+            ctx: ctx.clone(),
+            key: make_key(b"k"),
+            start_ts: 1,
+        };
+        assert_eq!(get.priority(), CommandPri::High);
+        assert_eq!(get.priority_tag(), "high");
+
+        // Maintenance commands always run at low priority.
+        let gc = Command::Gc {
+            ctx: ctx.clone(),
+            safe_point: 1,
+            ratio_threshold: 1.1,
+            scan_key: None,
+            keys: vec![],
+        };
+        assert_eq!(gc.priority(), CommandPri::Low);
+        let resolve = Command::ResolveLock {
+            ctx: ctx,
+            start_ts: 1,
+            commit_ts: None,
+            scan_key: None,
+            keys: vec![],
+        };
+        assert_eq!(resolve.priority(), CommandPri::Low);
+    }
+
+    fn expect_prewrite_locked(done: Sender<i32>, id: i32) -> Callback<Vec<Result<()>>> {
+        Box::new(move |x: Result<Vec<Result<()>>>| {
+            let results = x.unwrap();
+            assert_eq!(results.len(), 1);
+            assert!(results[0].is_err());
+            done.send(id).unwrap();
+        })
+    }
+
+    #[test]
+    fn test_prewrite_wait_for_lock() {
+        let mut config = Config::default();
+        config.wait_for_lock_timeout = ReadableDuration::millis(500);
+        let mut storage = Storage::new(&config).unwrap();
+        storage.start(&config).unwrap();
+        let (tx, rx) = channel();
+        fn prewrite(storage: &Storage, start_ts: u64, cb: Callback<Vec<Result<()>>>) {
+            on_done(
+                storage.async_prewrite(
+                    Context::new(),
+                    vec![Mutation::Put((make_key(b"x"), b"x".to_vec()))],
+                    b"x".to_vec(),
+                    start_ts,
+                    Options::default(),
+                ),
+                cb,
+            );
+        }
+        prewrite(&storage, 100, expect_ok(tx.clone(), 0));
+        assert_eq!(rx.recv().unwrap(), 0);
+
+        // Waits for the lock of 100 to be rolled back.
+        let tx1 = tx.clone();
+        prewrite(
+            &storage,
+            101,
+            box move |x: Result<Vec<Result<()>>>| {
+                assert!(x.unwrap().is_empty());
+                tx1.send(1).unwrap();
+            },
+        );
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        on_done(
+            storage.async_rollback(Context::new(), vec![make_key(b"x")], 100),
+            expect_ok(tx.clone(), 2),
+        );
+        assert_eq!(rx.recv().unwrap(), 2);
+        assert_eq!(rx.recv().unwrap(), 1);
+
+        // Returns the lock of 101 on timeout.
+        prewrite(&storage, 102, expect_prewrite_locked(tx.clone(), 3));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)).unwrap(), 3);
+        storage.stop().unwrap();
+    }
+
+    #[test]
+    fn test_pessimistic_lock() {
+        let config = Config::default();
+        let mut storage = Storage::new(&config).unwrap();
+        storage.start(&config).unwrap();
+        let (tx, rx) = channel();
+        let keys = vec![make_key(b"x"), make_key(b"y")];
+        on_done(
+            storage.async_acquire_pessimistic_lock(
                 Context::new(),
-                make_key(b"x"),
+                keys.clone(),
+                b"x".to_vec(),
+                100,
+                100,
+                Options::default(),
+            ),
+            expect_ok(tx.clone(), 0),
+        );
+        assert_eq!(rx.recv().unwrap(), 0);
+        // Locked by 100.
+        on_done(
+            storage.async_acquire_pessimistic_lock(
+                Context::new(),
+                vec![make_key(b"y")],
+                b"y".to_vec(),
                 101,
-                expect_get_none(tx.clone(), 6),
-            )
-            .unwrap();
-        rx.recv().unwrap();
-        storage
-            .async_get(
+                101,
+                Options::default(),
+            ),
+            expect_prewrite_locked(tx.clone(), 1),
+        );
+        assert_eq!(rx.recv().unwrap(), 1);
+        // The pessimistic lock doesn't block reads.
+        on_done(
+            storage.async_get(Context::new(), make_key(b"x"), 110),
+            expect_get_none(tx.clone(), 2),
+        );
+        assert_eq!(rx.recv().unwrap(), 2);
+
+        on_done(
+            storage.async_prewrite(
+                Context::new(),
+                vec![Mutation::Put((make_key(b"x"), b"100".to_vec()))],
+                b"x".to_vec(),
+                100,
+                Options::default(),
+            ),
+            expect_ok(tx.clone(), 3),
+        );
+        assert_eq!(rx.recv().unwrap(), 3);
+        // Only the lock of y which isn't prewritten is rolled back.
+        on_done(
+            storage.async_pessimistic_rollback(Context::new(), keys, 100, 100),
+            expect_ok(tx.clone(), 4),
+        );
+        assert_eq!(rx.recv().unwrap(), 4);
+        on_done(
+            storage.async_commit(Context::new(), vec![make_key(b"x")], 100, 110),
+            expect_ok(tx.clone(), 5),
+        );
+        assert_eq!(rx.recv().unwrap(), 5);
+        on_done(
+            storage.async_acquire_pessimistic_lock(
                 Context::new(),
-                make_key(b"y"),
+                vec![make_key(b"y")],
+                b"y".to_vec(),
                 101,
-                expect_get_none(tx.clone(), 7),
-            )
-            .unwrap();
-        rx.recv().unwrap();
-        storage
-            .async_get(
+                111,
+                Options::default(),
+            ),
+            expect_ok(tx.clone(), 6),
+        );
+        assert_eq!(rx.recv().unwrap(), 6);
+        // x is written after the for_update_ts.
+        on_done(
+            storage.async_acquire_pessimistic_lock(
                 Context::new(),
-                make_key(b"z"),
+                vec![make_key(b"x")],
+                b"y".to_vec(),
                 101,
-                expect_get_val(tx.clone(), b"100".to_vec(), 8),
-            )
-            .unwrap();
-        rx.recv().unwrap();
+                101,
+                Options::default(),
+            ),
+            expect_fail(tx.clone(), 7),
+        );
+        assert_eq!(rx.recv().unwrap(), 7);
+        storage.stop().unwrap();
+    }
 
-        // Delete range ["", ""), it means delete all
-        storage
-            .async_delete_range(
+    #[test]
+    fn test_in_memory_pessimistic_lock() {
+        let config = Config::default();
+        let mut storage = Storage::new(&config).unwrap();
+        storage.start(&config).unwrap();
+        let table = storage.get_pessimistic_locks();
+        let region = Region::new();
+        table.on_role_change(&mut ObserverContext::new(&region), StateRole::Leader);
+        let (tx, rx) = channel();
+        on_done(
+            storage.async_acquire_pessimistic_lock(
                 Context::new(),
-                make_key(b""),
-                make_key(b""),
-                expect_ok(tx.clone(), 9),
-            )
-            .unwrap();
-        rx.recv().unwrap();
-        storage
-            .async_get(
+                vec![make_key(b"x"), make_key(b"y")],
+                b"x".to_vec(),
+                100,
+                100,
+                Options::default(),
+            ),
+            expect_ok(tx.clone(), 0),
+        );
+        assert_eq!(rx.recv().unwrap(), 0);
+        // The locks are kept in memory only.
+        let snapshot = storage.get_engine().snapshot(&Context::new()).unwrap();
+        assert_eq!(snapshot.get_cf(CF_LOCK, &make_key(b"x")).unwrap(), None);
+        assert!(table.get(0, &make_key(b"x")).is_some());
+        on_done(
+            storage.async_acquire_pessimistic_lock(
                 Context::new(),
-                make_key(b"z"),
+                vec![make_key(b"x")],
+                b"x".to_vec(),
                 101,
-                expect_get_none(tx.clone(), 10),
+                101,
+                Options::default(),
+            ),
+            expect_prewrite_locked(tx.clone(), 1),
+        );
+        assert_eq!(rx.recv().unwrap(), 1);
+        on_done(
+            storage.async_prewrite(
+                Context::new(),
+                vec![Mutation::Put((make_key(b"x"), b"100".to_vec()))],
+                b"x".to_vec(),
+                100,
+                Options::default(),
+            ),
+            expect_ok(tx.clone(), 2),
+        );
+        assert_eq!(rx.recv().unwrap(), 2);
+        let snapshot = storage.get_engine().snapshot(&Context::new()).unwrap();
+        assert!(snapshot.get_cf(CF_LOCK, &make_key(b"x")).unwrap().is_some());
+        assert!(table.get(0, &make_key(b"x")).is_none());
+
+        // The lock of y is lost after the leader changes, the prewrite checks
+        // the write conflicts instead.
+        table.on_role_change(&mut ObserverContext::new(&region), StateRole::Follower);
+        table.on_role_change(&mut ObserverContext::new(&region), StateRole::Leader);
+        on_done(
+            storage.async_prewrite(
+                Context::new(),
+                vec![Mutation::Put((make_key(b"y"), b"102".to_vec()))],
+                b"y".to_vec(),
+                102,
+                Options::default(),
+            ),
+            expect_ok(tx.clone(), 3),
+        );
+        assert_eq!(rx.recv().unwrap(), 3);
+        on_done(
+            storage.async_commit(Context::new(), vec![make_key(b"y")], 102, 105),
+            expect_ok(tx.clone(), 4),
+        );
+        assert_eq!(rx.recv().unwrap(), 4);
+        on_done(
+            storage.async_prewrite(
+                Context::new(),
+                vec![Mutation::Put((make_key(b"y"), b"100".to_vec()))],
+                b"x".to_vec(),
+                100,
+                Options::default(),
+            ),
+            expect_fail(tx.clone(), 5),
+        );
+        assert_eq!(rx.recv().unwrap(), 5);
+
+        // The locks kept in memory are scanned and resolved.
+        on_done(
+            storage.async_acquire_pessimistic_lock(
+                Context::new(),
+                vec![make_key(b"z")],
+                b"z".to_vec(),
+                200,
+                200,
+                Options::default(),
+            ),
+            expect_ok(tx.clone(), 6),
+        );
+        assert_eq!(rx.recv().unwrap(), 6);
+        assert!(table.get(0, &make_key(b"z")).is_some());
+        let locks = storage.async_scan_lock(Context::new(), 300).wait().unwrap();
+        let keys: Vec<_> = locks.iter().map(|l| l.get_key()).collect();
+        assert_eq!(keys, vec![&b"x"[..], b"z"]);
+        on_done(
+            storage.async_resolve_lock(Context::new(), 200, None),
+            expect_ok(tx.clone(), 7),
+        );
+        assert_eq!(rx.recv().unwrap(), 7);
+        assert!(table.get(0, &make_key(b"z")).is_none());
+        let locks = storage.async_scan_lock(Context::new(), 300).wait().unwrap();
+        assert_eq!(locks.len(), 1);
+        storage.stop().unwrap();
+    }
+
+    // Finds the deadlocks of two transactions waiting for each other.
+    #[derive(Default)]
+    struct MockDetector {
+        // (txn_ts, lock_ts, key_hash)
+        wait_for: Mutex<Vec<(u64, u64, u64)>>,
+    }
+
+    impl DeadlockDetector for MockDetector {
+        fn detect(&self, txn_ts: u64, lock_ts: u64, key_hash: u64, cb: DeadlockCallback) {
+            let mut wait_for = self.wait_for.lock().unwrap();
+            match wait_for
+                .iter()
+                .find(|e| e.0 == lock_ts && e.1 == txn_ts)
+                .map(|e| e.2)
+            {
+                Some(deadlock_key_hash) => cb(deadlock_key_hash),
+                None => wait_for.push((txn_ts, lock_ts, key_hash)),
+            }
+        }
+
+        fn clean_up_wait_for(&self, txn_ts: u64, lock_ts: u64, key_hash: u64) {
+            let mut wait_for = self.wait_for.lock().unwrap();
+            wait_for.retain(|e| *e != (txn_ts, lock_ts, key_hash));
+        }
+
+        fn clean_up(&self, txn_ts: u64) {
+            self.wait_for.lock().unwrap().retain(|e| e.0 != txn_ts);
+        }
+    }
+
+    #[test]
+    fn test_pessimistic_lock_deadlock() {
+        let mut config = Config::default();
+        config.wait_for_lock_timeout = ReadableDuration::secs(3);
+        let mut storage = Storage::new(&config).unwrap();
+        storage.set_deadlock_detector(Arc::new(MockDetector::default()));
+        storage.start(&config).unwrap();
+        let (tx, rx) = channel();
+        let lock = |key: &[u8], ts: u64, cb| {
+            on_done(
+                storage.async_acquire_pessimistic_lock(
+                    Context::new(),
+                    vec![make_key(key)],
+                    key.to_vec(),
+                    ts,
+                    ts,
+                    Options::default(),
+                ),
+                cb,
             )
-            .unwrap();
-        rx.recv().unwrap();
+        };
+        lock(b"x", 100, expect_ok(tx.clone(), 0));
+        assert_eq!(rx.recv().unwrap(), 0);
+        lock(b"y", 101, expect_ok(tx.clone(), 1));
+        assert_eq!(rx.recv().unwrap(), 1);
+
+        // 101 waits for 100 on x.
+        lock(b"x", 101, expect_ok(tx.clone(), 2));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        // 100 waiting for 101 on y is a deadlock.
+        let tx1 = tx.clone();
+        lock(
+            b"y",
+            100,
+            box move |x: Result<Vec<Result<()>>>| {
+                let results = x.unwrap();
+                assert_eq!(results.len(), 1);
+                match results[0] {
+                    Err(Error::Txn(txn::Error::Mvcc(mvcc::Error::Deadlock {
+                        start_ts: 100,
+                        lock_ts: 101,
+                        deadlock_key_hash,
+                        ..
+                    }))) => assert_eq!(deadlock_key_hash, txn::key_hash(b"x")),
+                    ref r => panic!("unexpected result {:?}", r),
+                }
+                tx1.send(3).unwrap();
+            },
+        );
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), 3);
+
+        // 101 gets the lock once 100 is rolled back.
+        on_done(
+            storage.async_pessimistic_rollback(Context::new(), vec![make_key(b"x")], 100, 100),
+            expect_ok(tx.clone(), 4),
+        );
+        let mut ids = vec![rx.recv().unwrap(), rx.recv().unwrap()];
+        ids.sort();
+        assert_eq!(ids, vec![2, 4]);
+        storage.stop().unwrap();
+    }
+
+    #[test]
+    fn test_check_txn_status() {
+        let config = Config::default();
+        let mut storage = Storage::new(&config).unwrap();
+        storage.start(&config).unwrap();
+        let (tx, rx) = channel();
+        let expect_status = |status: TxnStatus, id: i32| -> Callback<TxnStatus> {
+            let tx = tx.clone();
+            box move |x: Result<TxnStatus>| {
+                assert_eq!(x.unwrap(), status);
+                tx.send(id).unwrap();
+            }
+        };
+        // The physical parts of the timestamps are 10ms and 30ms.
+        let (ts10, ts30) = (10 << 18, 30 << 18);
+        let mut options = Options::default();
+        options.lock_ttl = 100;
+        on_done(
+            storage.async_prewrite(
+                Context::new(),
+                vec![Mutation::Put((make_key(b"x"), b"100".to_vec()))],
+                b"x".to_vec(),
+                ts10,
+                options,
+            ),
+            expect_ok(tx.clone(), 0),
+        );
+        assert_eq!(rx.recv().unwrap(), 0);
+        on_done(
+            storage.async_check_txn_status(Context::new(), make_key(b"x"), ts10, 0, ts30),
+            expect_status(TxnStatus::Alive { ttl: 80 }, 1),
+        );
+        assert_eq!(rx.recv().unwrap(), 1);
+        on_done(
+            storage.async_commit(Context::new(), vec![make_key(b"x")], ts10, ts30),
+            expect_ok(tx.clone(), 2),
+        );
+        assert_eq!(rx.recv().unwrap(), 2);
+        on_done(
+            storage.async_check_txn_status(Context::new(), make_key(b"x"), ts10, 0, ts30 + 1),
+            expect_status(TxnStatus::Committed { commit_ts: ts30 }, 3),
+        );
+        assert_eq!(rx.recv().unwrap(), 3);
+        // The transaction which hasn't prewritten its primary key is rolled back.
+        on_done(
+            storage.async_check_txn_status(Context::new(), make_key(b"y"), ts10, 0, ts30),
+            expect_status(TxnStatus::RolledBack, 4),
+        );
+        assert_eq!(rx.recv().unwrap(), 4);
+        storage.stop().unwrap();
+    }
+
+    #[test]
+    fn test_read_bypass_pushed_locks() {
+        let config = Config::default();
+        let mut storage = Storage::new(&config).unwrap();
+        storage.start(&config).unwrap();
+        let (tx, rx) = channel();
+        let (ts10, ts20) = (10 << 18, 20 << 18);
+        let mut options = Options::default();
+        options.lock_ttl = 100;
+        on_done(
+            storage.async_prewrite(
+                Context::new(),
+                vec![
+                    Mutation::Put((make_key(b"x"), b"100".to_vec())),
+                    Mutation::Put((make_key(b"y"), b"101".to_vec())),
+                ],
+                b"x".to_vec(),
+                ts10,
+                options,
+            ),
+            expect_ok(tx.clone(), 0),
+        );
+        assert_eq!(rx.recv().unwrap(), 0);
+        on_done(
+            storage.async_get(Context::new(), make_key(b"y"), ts20),
+            expect_fail(tx.clone(), 1),
+        );
+        assert_eq!(rx.recv().unwrap(), 1);
+        // Pushes the min_commit_ts of the primary lock beyond ts20.
+        on_done(
+            storage.async_check_txn_status(Context::new(), make_key(b"x"), ts10, ts20, ts20),
+            expect_ok(tx.clone(), 2),
+        );
+        assert_eq!(rx.recv().unwrap(), 2);
+        on_done(
+            storage.async_get(Context::new(), make_key(b"x"), ts20),
+            expect_get_none(tx.clone(), 3),
+        );
+        assert_eq!(rx.recv().unwrap(), 3);
+        // The secondary locks are bypassed by the resolved locks in the context.
+        let mut ctx = Context::new();
+        ctx.set_resolved_locks(vec![ts10]);
+        on_done(
+            storage.async_get(ctx, make_key(b"y"), ts20),
+            expect_get_none(tx.clone(), 4),
+        );
+        assert_eq!(rx.recv().unwrap(), 4);
+        on_done(
+            storage.async_commit(Context::new(), vec![make_key(b"x")], ts10, ts20),
+            expect_fail(tx.clone(), 5),
+        );
+        assert_eq!(rx.recv().unwrap(), 5);
+        storage.stop().unwrap();
+    }
+
+    #[test]
+    fn test_txn_heart_beat() {
+        let config = Config::default();
+        let mut storage = Storage::new(&config).unwrap();
+        storage.start(&config).unwrap();
+        let (tx, rx) = channel();
+        let expect_ttl = |ttl: u64, id: i32| -> Callback<u64> {
+            let tx = tx.clone();
+            box move |x: Result<u64>| {
+                assert_eq!(x.unwrap(), ttl);
+                tx.send(id).unwrap();
+            }
+        };
+        on_done(
+            storage.async_txn_heart_beat(Context::new(), make_key(b"x"), 10, 100),
+            expect_fail(tx.clone(), 0),
+        );
+        assert_eq!(rx.recv().unwrap(), 0);
+        let mut options = Options::default();
+        options.lock_ttl = 10;
+        on_done(
+            storage.async_prewrite(
+                Context::new(),
+                vec![Mutation::Put((make_key(b"x"), b"100".to_vec()))],
+                b"x".to_vec(),
+                10,
+                options,
+            ),
+            expect_ok(tx.clone(), 1),
+        );
+        assert_eq!(rx.recv().unwrap(), 1);
+        on_done(
+            storage.async_txn_heart_beat(Context::new(), make_key(b"x"), 10, 100),
+            expect_ttl(100, 2),
+        );
+        assert_eq!(rx.recv().unwrap(), 2);
+        on_done(
+            storage.async_txn_heart_beat(Context::new(), make_key(b"x"), 10, 50),
+            expect_ttl(100, 3),
+        );
+        assert_eq!(rx.recv().unwrap(), 3);
+        storage.stop().unwrap();
+    }
+
+    #[test]
+    fn test_resolve_lock_lite() {
+        let config = Config::default();
+        let mut storage = Storage::new(&config).unwrap();
+        storage.start(&config).unwrap();
+        let (tx, rx) = channel();
+        on_done(
+            storage.async_prewrite(
+                Context::new(),
+                vec![
+                    Mutation::Put((make_key(b"x"), b"100".to_vec())),
+                    Mutation::Put((make_key(b"y"), b"101".to_vec())),
+                    Mutation::Put((make_key(b"z"), b"102".to_vec())),
+                ],
+                b"x".to_vec(),
+                100,
+                Options::default(),
+            ),
+            expect_ok(tx.clone(), 0),
+        );
+        assert_eq!(rx.recv().unwrap(), 0);
+        // Only the given keys are resolved.
+        on_done(
+            storage.async_resolve_lock_lite(Context::new(), 100, Some(110), vec![make_key(b"y")]),
+            expect_ok(tx.clone(), 1),
+        );
+        assert_eq!(rx.recv().unwrap(), 1);
+        on_done(
+            storage.async_get(Context::new(), make_key(b"y"), 120),
+            expect_get_val(tx.clone(), b"101".to_vec(), 2),
+        );
+        assert_eq!(rx.recv().unwrap(), 2);
+        on_done(
+            storage.async_get(Context::new(), make_key(b"z"), 120),
+            expect_fail(tx.clone(), 3),
+        );
+        assert_eq!(rx.recv().unwrap(), 3);
+        on_done(
+            storage.async_resolve_lock_lite(
+                Context::new(),
+                100,
+                None,
+                vec![make_key(b"x"), make_key(b"z")],
+            ),
+            expect_ok(tx.clone(), 4),
+        );
+        assert_eq!(rx.recv().unwrap(), 4);
+        on_done(
+            storage.async_get(Context::new(), make_key(b"z"), 120),
+            expect_get_none(tx.clone(), 5),
+        );
+        assert_eq!(rx.recv().unwrap(), 5);
+        storage.stop().unwrap();
+    }
+
+    #[test]
+    fn test_one_pc() {
+        let config = Config::default();
+        let mut storage = Storage::new(&config).unwrap();
+        storage.start(&config).unwrap();
+        let (tx, rx) = channel();
+        // Reads at 20 push the commit ts after it.
+        on_done(
+            storage.async_get(Context::new(), make_key(b"x"), 20),
+            expect_get_none(tx.clone(), 0),
+        );
+        assert_eq!(rx.recv().unwrap(), 0);
+        let mut options = Options::default();
+        options.try_one_pc = true;
+        let tx1 = tx.clone();
+        on_done(
+            storage.async_prewrite_with_result(
+                Context::new(),
+                vec![
+                    Mutation::Put((make_key(b"x"), b"100".to_vec())),
+                    Mutation::Put((make_key(b"y"), b"101".to_vec())),
+                ],
+                b"x".to_vec(),
+                10,
+                options,
+            ),
+            box move |x: Result<PrewriteResult>| {
+                let res = x.unwrap();
+                assert!(res.locks.is_empty());
+                assert_eq!(res.one_pc_commit_ts, 21);
+                tx1.send(1).unwrap();
+            },
+        );
+        assert_eq!(rx.recv().unwrap(), 1);
+        on_done(
+            storage.async_get(Context::new(), make_key(b"y"), 20),
+            expect_get_none(tx.clone(), 2),
+        );
+        assert_eq!(rx.recv().unwrap(), 2);
+        on_done(
+            storage.async_get(Context::new(), make_key(b"y"), 21),
+            expect_get_val(tx.clone(), b"101".to_vec(), 3),
+        );
+        assert_eq!(rx.recv().unwrap(), 3);
+
+        // It falls back to 2PC until the max ts is synced.
+        let sync_id = storage.get_max_ts().start_sync(0);
+        let mut options = Options::default();
+        options.try_one_pc = true;
+        let tx1 = tx.clone();
+        on_done(
+            storage.async_prewrite_with_result(
+                Context::new(),
+                vec![Mutation::Put((make_key(b"z"), b"102".to_vec()))],
+                b"z".to_vec(),
+                30,
+                options,
+            ),
+            box move |x: Result<PrewriteResult>| {
+                let res = x.unwrap();
+                assert!(res.locks.is_empty());
+                assert_eq!(res.one_pc_commit_ts, 0);
+                tx1.send(4).unwrap();
+            },
+        );
+        assert_eq!(rx.recv().unwrap(), 4);
+        storage.get_max_ts().finish_sync(0, sync_id, 40);
+        // The retry stays in 2PC, the lock written before isn't committed by it.
+        let mut options = Options::default();
+        options.try_one_pc = true;
+        let tx1 = tx.clone();
+        on_done(
+            storage.async_prewrite_with_result(
+                Context::new(),
+                vec![Mutation::Put((make_key(b"z"), b"102".to_vec()))],
+                b"z".to_vec(),
+                30,
+                options,
+            ),
+            box move |x: Result<PrewriteResult>| {
+                let res = x.unwrap();
+                assert!(res.locks.is_empty());
+                assert_eq!(res.one_pc_commit_ts, 0);
+                tx1.send(8).unwrap();
+            },
+        );
+        assert_eq!(rx.recv().unwrap(), 8);
+        on_done(
+            storage.async_get(Context::new(), make_key(b"z"), 50),
+            expect_fail(tx.clone(), 5),
+        );
+        assert_eq!(rx.recv().unwrap(), 5);
+        on_done(
+            storage.async_commit(Context::new(), vec![make_key(b"z")], 30, 45),
+            expect_ok(tx.clone(), 6),
+        );
+        assert_eq!(rx.recv().unwrap(), 6);
+        on_done(
+            storage.async_get(Context::new(), make_key(b"z"), 50),
+            expect_get_val(tx.clone(), b"102".to_vec(), 7),
+        );
+        assert_eq!(rx.recv().unwrap(), 7);
+        storage.stop().unwrap();
+    }
+
+    #[test]
+    fn test_async_commit_min_commit_ts() {
+        let config = Config::default();
+        let mut storage = Storage::new(&config).unwrap();
+        storage.start(&config).unwrap();
+        let (tx, rx) = channel();
+        let expect_min_commit_ts = |ts: u64, id: i32| -> Callback<PrewriteResult> {
+            let tx = tx.clone();
+            box move |x: Result<PrewriteResult>| {
+                let res = x.unwrap();
+                assert!(res.locks.is_empty());
+                assert_eq!(res.min_commit_ts, ts);
+                tx.send(id).unwrap();
+            }
+        };
+        fn prewrite(storage: &Storage, key: &[u8], start_ts: u64) -> StorageFuture<PrewriteResult> {
+            let mut options = Options::default();
+            options.use_async_commit = true;
+            storage.async_prewrite_with_result(
+                Context::new(),
+                vec![Mutation::Put((make_key(key), b"v".to_vec()))],
+                key.to_vec(),
+                start_ts,
+                options,
+            )
+        }
+        // Reads at 30 push the min commit ts after it.
+        on_done(
+            storage.async_get(Context::new(), make_key(b"x"), 30),
+            expect_get_none(tx.clone(), 0),
+        );
+        assert_eq!(rx.recv().unwrap(), 0);
+        on_done(prewrite(&storage, b"x", 10), expect_min_commit_ts(31, 1));
+        assert_eq!(rx.recv().unwrap(), 1);
+
+        // It falls back to 2PC until the max ts is synced.
+        let max_ts = storage.get_max_ts();
+        let sync_id = max_ts.start_sync(0);
+        on_done(prewrite(&storage, b"y", 40), expect_min_commit_ts(0, 2));
+        assert_eq!(rx.recv().unwrap(), 2);
+        max_ts.finish_sync(0, sync_id, 50);
+        on_done(prewrite(&storage, b"z", 45), expect_min_commit_ts(51, 3));
+        assert_eq!(rx.recv().unwrap(), 3);
+
+        // The reads from the min commit ts of a prewrite being written fail on the
+        // keys it has locked in memory.
+        let lock = mvcc::Lock::new(mvcc::LockType::Put, b"w".to_vec(), 60, 3000, None);
+        assert_eq!(max_ts.lock_keys(0, u64::MAX, &[make_key(b"w")], lock), Some(61));
+        on_done(
+            storage.async_get(Context::new(), make_key(b"w"), 60),
+            expect_get_none(tx.clone(), 4),
+        );
+        assert_eq!(rx.recv().unwrap(), 4);
+        on_done(
+            storage.async_get(Context::new(), make_key(b"w"), 61),
+            expect_fail(tx.clone(), 5),
+        );
+        assert_eq!(rx.recv().unwrap(), 5);
+        on_done(
+            storage.async_batch_get(Context::new(), vec![make_key(b"v"), make_key(b"w")], 61),
+            expect_fail(tx.clone(), 6),
+        );
+        assert_eq!(rx.recv().unwrap(), 6);
+        on_done(
+            storage.async_scan(Context::new(), make_key(b"v"), 10, 61, Options::default()),
+            expect_fail(tx.clone(), 7),
+        );
+        assert_eq!(rx.recv().unwrap(), 7);
+        max_ts.unlock_keys(u64::MAX);
+        on_done(
+            storage.async_get(Context::new(), make_key(b"w"), 61),
+            expect_get_none(tx.clone(), 8),
+        );
+        assert_eq!(rx.recv().unwrap(), 8);
+        storage.stop().unwrap();
+    }
+
+    #[test]
+    fn test_check_secondary_locks() {
+        let config = Config::default();
+        let mut storage = Storage::new(&config).unwrap();
+        storage.start(&config).unwrap();
+        let (tx, rx) = channel();
+        let expect_status = |s: SecondaryLocksStatus, id: i32| -> Callback<SecondaryLocksStatus> {
+            let tx = tx.clone();
+            box move |x: Result<SecondaryLocksStatus>| {
+                assert_eq!(x.unwrap(), s);
+                tx.send(id).unwrap();
+            }
+        };
+        let mut options = Options::default();
+        options.use_async_commit = true;
+        options.secondary_keys = vec![b"y".to_vec()];
+        on_done(
+            storage.async_prewrite(
+                Context::new(),
+                vec![
+                    Mutation::Put((make_key(b"x"), b"100".to_vec())),
+                    Mutation::Put((make_key(b"y"), b"101".to_vec())),
+                ],
+                b"x".to_vec(),
+                10,
+                options,
+            ),
+            expect_ok(tx.clone(), 0),
+        );
+        assert_eq!(rx.recv().unwrap(), 0);
+        let tx1 = tx.clone();
+        on_done(
+            storage.async_check_secondary_locks(Context::new(), vec![make_key(b"y")], 10),
+            box move |x: Result<SecondaryLocksStatus>| {
+                match x.unwrap() {
+                    SecondaryLocksStatus::Locked(locks) => {
+                        assert_eq!(locks.len(), 1);
+                        assert_eq!(locks[0].0, make_key(b"y"));
+                        assert_eq!(locks[0].1.primary, b"x".to_vec());
+                    }
+                    status => panic!("unexpected status {:?}", status),
+                }
+                tx1.send(1).unwrap();
+            },
+        );
+        assert_eq!(rx.recv().unwrap(), 1);
+        on_done(
+            storage.async_commit(Context::new(), vec![make_key(b"x"), make_key(b"y")], 10, 20),
+            expect_ok(tx.clone(), 2),
+        );
+        assert_eq!(rx.recv().unwrap(), 2);
+        on_done(
+            storage.async_check_secondary_locks(Context::new(), vec![make_key(b"y")], 10),
+            expect_status(SecondaryLocksStatus::Committed(20), 3),
+        );
+        assert_eq!(rx.recv().unwrap(), 3);
+        // The transaction which misses a secondary key is rolled back.
+        on_done(
+            storage.async_check_secondary_locks(Context::new(), vec![make_key(b"z")], 30),
+            expect_status(SecondaryLocksStatus::RolledBack, 4),
+        );
+        assert_eq!(rx.recv().unwrap(), 4);
+        storage.stop().unwrap();
+    }
+
+    #[test]
+    fn test_resize_worker_pool() {
+        let config = Config::default();
+        let mut storage = Storage::new(&config).unwrap();
+        storage.start(&config).unwrap();
+        let handler = storage.config_handler();
+        assert!(handler.update("scheduler-worker-pool-size", "0").is_err());
+        assert!(handler.update("scheduler-worker-pool-size", "x").is_err());
+        let (tx, rx) = channel();
+        for (i, size) in vec!["1", "8"].into_iter().enumerate() {
+            handler.update("scheduler-worker-pool-size", size).unwrap();
+            // The commands are still processed by the resized pool.
+            on_done(
+                storage.async_prewrite(
+                    Context::new(),
+                    vec![Mutation::Put((make_key(size.as_bytes()), b"v".to_vec()))],
+                    size.as_bytes().to_vec(),
+                    100,
+                    Options::default(),
+                ),
+                expect_ok(tx.clone(), i as i32),
+            );
+            assert_eq!(rx.recv().unwrap(), i as i32);
+        }
+        storage.stop().unwrap();
+    }
+
+    #[test]
+    fn test_entry_size_limits() {
+        let mut config = Config::default();
+        config.max_key_size = ReadableSize(16);
+        config.max_value_size = ReadableSize(32);
+        let mut storage = Storage::new(&config).unwrap();
+        storage.start(&config).unwrap();
+        let (tx, rx) = channel();
+        fn expect_too_large<T>(done: Sender<i32>, id: i32) -> Callback<T> {
+            Box::new(move |x: Result<T>| {
+                match x {
+                    Err(Error::KeyTooLarge(..)) | Err(Error::ValueTooLarge(..)) => {}
+                    _ => panic!("expect too large"),
+                }
+                done.send(id).unwrap();
+            })
+        }
+
+        // The encoded key is larger than the raw key.
+        on_done(
+            storage.async_prewrite(
+                Context::new(),
+                vec![Mutation::Put((make_key(&[b'k'; 16]), b"v".to_vec()))],
+                b"k".to_vec(),
+                100,
+                Options::default(),
+            ),
+            expect_too_large(tx.clone(), 0),
+        );
+        assert_eq!(rx.recv().unwrap(), 0);
+        on_done(
+            storage.async_prewrite(
+                Context::new(),
+                vec![Mutation::Put((make_key(b"k"), vec![b'v'; 33]))],
+                b"k".to_vec(),
+                100,
+                Options::default(),
+            ),
+            expect_too_large(tx.clone(), 1),
+        );
+        assert_eq!(rx.recv().unwrap(), 1);
+        on_done(
+            storage.async_raw_put(Context::new(), vec![b'k'; 17], b"v".to_vec()),
+            expect_too_large(tx.clone(), 2),
+        );
+        assert_eq!(rx.recv().unwrap(), 2);
+        on_done(
+            storage.async_raw_put(Context::new(), b"k".to_vec(), vec![b'v'; 33]),
+            expect_too_large(tx.clone(), 3),
+        );
+        assert_eq!(rx.recv().unwrap(), 3);
+        on_done(
+            storage.async_raw_delete(Context::new(), vec![b'k'; 17]),
+            expect_too_large(tx.clone(), 4),
+        );
+        assert_eq!(rx.recv().unwrap(), 4);
+
+        on_done(
+            storage.async_raw_put(Context::new(), vec![b'k'; 16], vec![b'v'; 32]),
+            expect_ok(tx.clone(), 5),
+        );
+        assert_eq!(rx.recv().unwrap(), 5);
+        storage.stop().unwrap();
+    }
+
+    #[test]
+    fn test_raw_get_causal_ts() {
+        let config = Config::default();
+        let mut storage = Storage::new(&config).unwrap();
+        assert!(storage.raw_get_causal_ts().is_err());
+
+        let provider = BatchTsoProvider::new(2, Duration::from_secs(60), |count| {
+            Ok(100 + u64::from(count))
+        });
+        storage.set_causal_ts_provider(Arc::new(provider));
+        let cloned = storage.clone();
+        assert_eq!(storage.raw_get_causal_ts().unwrap(), 101);
+        assert_eq!(cloned.raw_get_causal_ts().unwrap(), 102);
+    }
+
+    #[test]
+    fn test_raw_write_causal_ts() {
+        let config = Config::default();
+        let mut storage = Storage::new(&config).unwrap();
+        let tso = Arc::new(AtomicUsize::new(100));
+        let tso1 = tso.clone();
+        let provider = BatchTsoProvider::new(10, Duration::from_secs(60), move |count| {
+            Ok((tso1.fetch_add(count as usize, Ordering::SeqCst) + count as usize) as u64)
+        });
+        storage.set_causal_ts_provider(Arc::new(provider));
+        storage.start(&config).unwrap();
+        let (tx, rx) = channel();
+
+        // The raw writes take the causal timestamps before being proposed.
+        on_done(
+            storage.async_raw_put(Context::new(), b"a".to_vec(), b"aa".to_vec()),
+            expect_ok(tx.clone(), 0),
+        );
+        assert_eq!(rx.recv().unwrap(), 0);
+        on_done(
+            storage.async_raw_delete(Context::new(), b"b".to_vec()),
+            expect_ok(tx.clone(), 1),
+        );
+        assert_eq!(rx.recv().unwrap(), 1);
+        assert_eq!(storage.raw_get_causal_ts().unwrap(), 103);
+        storage.stop().unwrap();
+
+        // The raw writes fail if no causal timestamp can be taken.
+        let mut storage = Storage::new(&config).unwrap();
+        let provider = BatchTsoProvider::new(10, Duration::from_secs(60), |_| {
+            Err(box_err!("tso is unavailable"))
+        });
+        storage.set_causal_ts_provider(Arc::new(provider));
+        storage.start(&config).unwrap();
+        on_done(
+            storage.async_raw_put(Context::new(), b"a".to_vec(), b"aa".to_vec()),
+            expect_fail(tx.clone(), 2),
+        );
+        assert_eq!(rx.recv().unwrap(), 2);
         storage.stop().unwrap();
     }
 }
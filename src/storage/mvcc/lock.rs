@@ -23,16 +23,24 @@ pub enum LockType {
     Put,
     Delete,
     Lock,
+    // Acquired by a pessimistic transaction before prewrite, it holds no value
+    // and doesn't block reads.
+    Pessimistic,
 }
 
 const FLAG_PUT: u8 = b'P';
 const FLAG_DELETE: u8 = b'D';
 const FLAG_LOCK: u8 = b'L';
+const FLAG_PESSIMISTIC: u8 = b'S';
+
+const FOR_UPDATE_TS_PREFIX: u8 = b'f';
+const MIN_COMMIT_TS_PREFIX: u8 = b'c';
+const ASYNC_COMMIT_PREFIX: u8 = b'a';
 
 impl LockType {
     pub fn from_mutation(mutation: &Mutation) -> LockType {
         match *mutation {
-            Mutation::Put(_) => LockType::Put,
+            Mutation::Put(_) | Mutation::Insert(_) => LockType::Put,
             Mutation::Delete(_) => LockType::Delete,
             Mutation::Lock(_) => LockType::Lock,
         }
@@ -43,6 +51,7 @@ impl LockType {
             FLAG_PUT => Some(LockType::Put),
             FLAG_DELETE => Some(LockType::Delete),
             FLAG_LOCK => Some(LockType::Lock),
+            FLAG_PESSIMISTIC => Some(LockType::Pessimistic),
             _ => None,
         }
     }
@@ -52,6 +61,7 @@ impl LockType {
             LockType::Put => FLAG_PUT,
             LockType::Delete => FLAG_DELETE,
             LockType::Lock => FLAG_LOCK,
+            LockType::Pessimistic => FLAG_PESSIMISTIC,
         }
     }
 }
@@ -63,6 +73,14 @@ pub struct Lock {
     pub ts: u64,
     pub ttl: u64,
     pub short_value: Option<Value>,
+    // The `for_update_ts` of a pessimistic lock, 0 for the other locks.
+    pub for_update_ts: u64,
+    // The transaction can't be committed before it, 0 means no limit.
+    pub min_commit_ts: u64,
+    // The transaction is committed once all its keys are prewritten, the primary
+    // lock records the secondary keys so the commit can be decided by the locks.
+    pub use_async_commit: bool,
+    pub secondaries: Vec<Vec<u8>>,
 }
 
 impl Lock {
@@ -79,12 +97,33 @@ impl Lock {
             ts: ts,
             ttl: ttl,
             short_value: short_value,
+            for_update_ts: 0,
+            min_commit_ts: 0,
+            use_async_commit: false,
+            secondaries: vec![],
         }
     }
 
+    pub fn with_for_update_ts(mut self, for_update_ts: u64) -> Lock {
+        self.for_update_ts = for_update_ts;
+        self
+    }
+
+    pub fn with_min_commit_ts(mut self, min_commit_ts: u64) -> Lock {
+        self.min_commit_ts = min_commit_ts;
+        self
+    }
+
+    pub fn use_async_commit(mut self, secondaries: Vec<Vec<u8>>) -> Lock {
+        self.use_async_commit = true;
+        self.secondaries = secondaries;
+        self
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut b = Vec::with_capacity(
-            1 + MAX_VAR_U64_LEN + self.primary.len() + MAX_VAR_U64_LEN + SHORT_VALUE_MAX_LEN + 2,
+            1 + MAX_VAR_U64_LEN + self.primary.len() + MAX_VAR_U64_LEN + SHORT_VALUE_MAX_LEN + 2 +
+                1 + 8,
         );
         b.push(self.lock_type.to_u8());
         b.encode_compact_bytes(&self.primary).unwrap();
@@ -95,6 +134,21 @@ impl Lock {
             b.push(v.len() as u8);
             b.extend_from_slice(v);
         }
+        if self.for_update_ts > 0 {
+            b.push(FOR_UPDATE_TS_PREFIX);
+            b.encode_u64(self.for_update_ts).unwrap();
+        }
+        if self.min_commit_ts > 0 {
+            b.push(MIN_COMMIT_TS_PREFIX);
+            b.encode_u64(self.min_commit_ts).unwrap();
+        }
+        if self.use_async_commit {
+            b.push(ASYNC_COMMIT_PREFIX);
+            b.encode_var_u64(self.secondaries.len() as u64).unwrap();
+            for k in &self.secondaries {
+                b.encode_compact_bytes(k).unwrap();
+            }
+        }
         b
     }
 
@@ -111,28 +165,45 @@ impl Lock {
             try!(b.decode_var_u64())
         };
 
-        if b.is_empty() {
-            return Ok(Lock::new(lock_type, primary, ts, ttl, None));
+        let mut short_value = None;
+        let mut for_update_ts = 0;
+        let mut min_commit_ts = 0;
+        let mut secondaries = None;
+        while !b.is_empty() {
+            match try!(b.read_u8()) {
+                SHORT_VALUE_PREFIX => {
+                    let len = try!(b.read_u8()) as usize;
+                    if len > b.len() {
+                        panic!(
+                            "short value len [{}] is larger than content len [{}]",
+                            len,
+                            b.len()
+                        );
+                    }
+                    short_value = Some(b[..len].to_vec());
+                    b = &b[len..];
+                }
+                FOR_UPDATE_TS_PREFIX => for_update_ts = try!(b.decode_u64()),
+                MIN_COMMIT_TS_PREFIX => min_commit_ts = try!(b.decode_u64()),
+                ASYNC_COMMIT_PREFIX => {
+                    let count = try!(b.decode_var_u64()) as usize;
+                    let mut keys = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        keys.push(try!(b.decode_compact_bytes()));
+                    }
+                    secondaries = Some(keys);
+                }
+                flag => panic!("invalid flag [{:?}] in lock", flag),
+            }
         }
 
-        let flag = try!(b.read_u8());
-        assert_eq!(
-            flag,
-            SHORT_VALUE_PREFIX,
-            "invalid flag [{:?}] in write",
-            flag
-        );
-
-        let len = try!(b.read_u8());
-        if len as usize != b.len() {
-            panic!(
-                "short value len [{}] not equal to content len [{}]",
-                len,
-                b.len()
-            );
+        let mut lock = Lock::new(lock_type, primary, ts, ttl, short_value)
+            .with_for_update_ts(for_update_ts)
+            .with_min_commit_ts(min_commit_ts);
+        if let Some(secondaries) = secondaries {
+            lock = lock.use_async_commit(secondaries);
         }
-
-        Ok(Lock::new(lock_type, primary, ts, ttl, Some(b.to_vec())))
+        Ok(lock)
     }
 }
 
@@ -157,6 +228,8 @@ mod tests {
             ),
             (Mutation::Lock(make_key(key)), LockType::Lock, FLAG_LOCK),
         ];
+        assert_eq!(LockType::from_u8(FLAG_PESSIMISTIC), Some(LockType::Pessimistic));
+        assert_eq!(LockType::Pessimistic.to_u8(), FLAG_PESSIMISTIC);
         for (i, (mutation, lock_type, flag)) in tests.drain(..).enumerate() {
             let lt = LockType::from_mutation(&mutation);
             assert_eq!(
@@ -203,6 +276,20 @@ mod tests {
                 10,
                 Some(b"short_value".to_vec()),
             ),
+            Lock::new(LockType::Pessimistic, b"pk".to_vec(), 1, 10, None).with_for_update_ts(5),
+            Lock::new(
+                LockType::Put,
+                b"pk".to_vec(),
+                1,
+                10,
+                Some(b"short_value".to_vec()),
+            ).with_for_update_ts(5),
+            Lock::new(LockType::Put, b"pk".to_vec(), 1, 10, None)
+                .with_min_commit_ts(2)
+                .use_async_commit(vec![b"k1".to_vec(), b"k2".to_vec()]),
+            Lock::new(LockType::Lock, b"pk".to_vec(), 1, 10, None)
+                .with_min_commit_ts(2)
+                .use_async_commit(vec![]),
         ];
         for (i, lock) in locks.drain(..).enumerate() {
             let v = lock.to_bytes();
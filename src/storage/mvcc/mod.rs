@@ -19,7 +19,7 @@ mod metrics;
 
 use std::io;
 use std::error;
-pub use self::txn::{MvccTxn, MAX_TXN_WRITE_SIZE};
+pub use self::txn::{MvccTxn, SecondaryLockStatus, TxnStatus, MAX_TXN_WRITE_SIZE};
 pub use self::reader::MvccReader;
 pub use self::lock::{Lock, LockType};
 pub use self::write::{Write, WriteType};
@@ -66,6 +66,24 @@ quick_error! {
             display("write conflict {} with {}, key:{:?}, primary:{:?}",
              start_ts, conflict_ts, key, primary)
         }
+        CommitTsExpired {start_ts: u64, commit_ts: u64, key: Vec<u8>, min_commit_ts: u64} {
+            description("commit ts is smaller than min commit ts")
+            display("commit ts {} of txn {} is smaller than min commit ts {}, key:{:?}",
+             commit_ts, start_ts, min_commit_ts, key)
+        }
+        AlreadyExist {key: Vec<u8>} {
+            description("already exists")
+            display("key {:?} already exists", escape(key))
+        }
+        PessimisticLockRolledBack {start_ts: u64, key: Vec<u8>} {
+            description("pessimistic lock already rolled back")
+            display("pessimistic lock already rolled back, start_ts:{}, key:{:?}", start_ts, key)
+        }
+        Deadlock {start_ts: u64, lock_ts: u64, key: Vec<u8>, deadlock_key_hash: u64} {
+            description("deadlock")
+            display("deadlock is found when txn {} waits for the lock {} on key {:?}",
+             start_ts, lock_ts, escape(key))
+        }
         KeyVersion {description("bad format key(version)")}
         Other(err: Box<error::Error + Sync + Send>) {
             from()
@@ -114,6 +132,35 @@ impl Error {
                 key: key.to_owned(),
                 primary: primary.to_owned(),
             }),
+            Error::CommitTsExpired {
+                start_ts,
+                commit_ts,
+                ref key,
+                min_commit_ts,
+            } => Some(Error::CommitTsExpired {
+                start_ts: start_ts,
+                commit_ts: commit_ts,
+                key: key.to_owned(),
+                min_commit_ts: min_commit_ts,
+            }),
+            Error::AlreadyExist { ref key } => Some(Error::AlreadyExist { key: key.clone() }),
+            Error::PessimisticLockRolledBack { start_ts, ref key } => {
+                Some(Error::PessimisticLockRolledBack {
+                    start_ts: start_ts,
+                    key: key.to_owned(),
+                })
+            }
+            Error::Deadlock {
+                start_ts,
+                lock_ts,
+                ref key,
+                deadlock_key_hash,
+            } => Some(Error::Deadlock {
+                start_ts: start_ts,
+                lock_ts: lock_ts,
+                key: key.to_owned(),
+                deadlock_key_hash: deadlock_key_hash,
+            }),
             Error::KeyVersion => Some(Error::KeyVersion),
             Error::Committed { commit_ts } => Some(Error::Committed {
                 commit_ts: commit_ts,
@@ -14,11 +14,12 @@
 use storage::engine::{Cursor, ScanMode, Snapshot, Statistics};
 use storage::{Key, Value, CF_LOCK, CF_WRITE};
 use super::{Error, Result};
-use super::lock::Lock;
+use super::lock::{Lock, LockType};
 use super::write::{Write, WriteType};
 use raftstore::store::engine::IterOption;
 use std::u64;
 use kvproto::kvrpcpb::IsolationLevel;
+use util::collections::HashSet;
 use util::properties::MvccProperties;
 
 const GC_MAX_ROW_VERSIONS_THRESHOLD: u64 = 100;
@@ -37,6 +38,9 @@ pub struct MvccReader<'a> {
     fill_cache: bool,
     upper_bound: Option<Vec<u8>>,
     isolation_level: IsolationLevel,
+    // The start ts of the transactions whose locks are ignored by the reads, their
+    // min_commit_ts has been pushed beyond the read ts.
+    bypass_locks: HashSet<u64>,
 }
 
 impl<'a> MvccReader<'a> {
@@ -59,6 +63,7 @@ impl<'a> MvccReader<'a> {
             key_only: false,
             fill_cache: fill_cache,
             upper_bound: upper_bound,
+            bypass_locks: HashSet::default(),
         }
     }
 
@@ -70,6 +75,10 @@ impl<'a> MvccReader<'a> {
         self.key_only = key_only;
     }
 
+    pub fn set_bypass_locks(&mut self, bypass_locks: HashSet<u64>) {
+        self.bypass_locks = bypass_locks;
+    }
+
     pub fn load_data(&mut self, key: &Key, ts: u64) -> Result<Value> {
         if self.key_only {
             return Ok(vec![]);
@@ -190,7 +199,12 @@ impl<'a> MvccReader<'a> {
 
     fn check_lock(&mut self, key: &Key, mut ts: u64) -> Result<Option<u64>> {
         if let Some(lock) = try!(self.load_lock(key)) {
-            if lock.ts <= ts {
+            // Pessimistic locks hold no values, they don't block reads. Neither do the
+            // locks which must be committed after `ts`, or the locks of the transactions
+            // that have been pushed by the reader.
+            if lock.ts <= ts && lock.lock_type != LockType::Pessimistic &&
+                lock.min_commit_ts <= ts && !self.bypass_locks.contains(&lock.ts)
+            {
                 if ts == u64::MAX && try!(key.raw()) == lock.primary {
                     // when ts==u64::MAX(which means to get latest committed version for
                     // primary key),and current key is the primary key, returns the latest
@@ -218,6 +232,11 @@ impl<'a> MvccReader<'a> {
             },
             IsolationLevel::RC => {}
         }
+        self.get_committed(key, ts)
+    }
+
+    /// Gets the value committed at or before `ts`, the lock of the key is ignored.
+    pub fn get_committed(&mut self, key: &Key, mut ts: u64) -> Result<Option<Value>> {
         loop {
             match try!(self.seek_write(key, ts)) {
                 Some((commit_ts, mut write)) => match write.write_type {
@@ -316,7 +335,9 @@ impl<'a> MvccReader<'a> {
         try!(self.create_write_cursor());
         try!(self.create_lock_cursor());
 
-        let (mut write_valid, mut lock_valid) = (true, true);
+        // The locks don't block the reads of read committed, no need to walk them.
+        let mut write_valid = true;
+        let mut lock_valid = self.isolation_level == IsolationLevel::SI;
 
         loop {
             key = {
@@ -362,7 +383,8 @@ impl<'a> MvccReader<'a> {
         try!(self.create_write_cursor());
         try!(self.create_lock_cursor());
 
-        let (mut write_valid, mut lock_valid) = (true, true);
+        let mut write_valid = true;
+        let mut lock_valid = self.isolation_level == IsolationLevel::SI;
 
         loop {
             key = {
@@ -11,7 +11,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
 use std::fmt;
+use std::u64;
 use storage::{is_short_value, Key, Mutation, Options, Statistics, Value, CF_DEFAULT, CF_LOCK,
               CF_WRITE};
 use storage::engine::{Modify, ScanMode, Snapshot};
@@ -21,14 +23,43 @@ use super::write::{Write, WriteType};
 use super::{Error, Result};
 use super::metrics::*;
 use kvproto::kvrpcpb::IsolationLevel;
+use pd::extract_physical;
 
 pub const MAX_TXN_WRITE_SIZE: usize = 32 * 1024;
 
+/// The status of a transaction, which is found by its primary key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxnStatus {
+    /// The primary key is still locked, it expires after `ttl` milliseconds.
+    Alive { ttl: u64 },
+    /// The primary lock of an async commit transaction has expired, the status is
+    /// decided by the locks of the secondary keys.
+    AsyncCommitLocked {
+        min_commit_ts: u64,
+        secondaries: Vec<Vec<u8>>,
+    },
+    Committed { commit_ts: u64 },
+    RolledBack,
+}
+
+/// The status of a secondary key of an async commit transaction.
+#[derive(Debug, PartialEq)]
+pub enum SecondaryLockStatus {
+    Locked(Lock),
+    Committed(u64),
+    RolledBack,
+}
+
 pub struct MvccTxn<'a> {
     reader: MvccReader<'a>,
     start_ts: u64,
     writes: Vec<Modify>,
     write_size: usize,
+    // The locks of a one-phase commit, they are committed by `commit_one_pc`
+    // instead of being written.
+    one_pc_locks: Vec<(Key, Lock)>,
+    // Set if some keys have been prewritten by the transaction before.
+    has_duplicated_prewrite: bool,
 }
 
 impl<'a> fmt::Debug for MvccTxn<'a> {
@@ -59,6 +90,8 @@ impl<'a> MvccTxn<'a> {
             start_ts: start_ts,
             writes: vec![],
             write_size: 0,
+            one_pc_locks: vec![],
+            has_duplicated_prewrite: false,
         }
     }
 
@@ -70,6 +103,12 @@ impl<'a> MvccTxn<'a> {
         self.write_size
     }
 
+    /// Returns whether some keys have been locked by an earlier prewrite of the
+    /// transaction, it can't be committed by 1PC then.
+    pub fn has_duplicated_prewrite(&self) -> bool {
+        self.has_duplicated_prewrite
+    }
+
     fn lock_key(
         &mut self,
         key: Key,
@@ -77,8 +116,15 @@ impl<'a> MvccTxn<'a> {
         primary: Vec<u8>,
         ttl: u64,
         short_value: Option<Value>,
+        for_update_ts: u64,
     ) {
-        let lock = Lock::new(lock_type, primary, self.start_ts, ttl, short_value).to_bytes();
+        let lock = Lock::new(lock_type, primary, self.start_ts, ttl, short_value)
+            .with_for_update_ts(for_update_ts);
+        self.put_lock(key, &lock);
+    }
+
+    fn put_lock(&mut self, key: Key, lock: &Lock) {
+        let lock = lock.to_bytes();
         self.write_size += CF_LOCK.len() + key.encoded().len() + lock.len();
         self.writes.push(Modify::Put(CF_LOCK, key, lock));
     }
@@ -122,8 +168,20 @@ impl<'a> MvccTxn<'a> {
         primary: &[u8],
         options: &Options,
     ) -> Result<()> {
+        fail_point!("mvcc_prewrite", |_| Err(box_err!("prewrite is failed by fail point")));
         let key = mutation.key();
-        if !options.skip_constraint_check {
+        let lock = try!(self.reader.load_lock(key));
+        // The key is locked by the pessimistic lock of the transaction, no one else
+        // could have written it since the lock was acquired.
+        let for_update_ts = match lock {
+            Some(ref lock) if lock.ts == self.start_ts &&
+                lock.lock_type == LockType::Pessimistic =>
+            {
+                Some(lock.for_update_ts)
+            }
+            _ => None,
+        };
+        if !options.skip_constraint_check && for_update_ts.is_none() {
             if let Some((commit, _)) = try!(self.reader.seek_write(key, u64::max_value())) {
                 // Abort on writes after our start timestamp ...
                 if commit >= self.start_ts {
@@ -137,7 +195,7 @@ impl<'a> MvccTxn<'a> {
             }
         }
         // ... or locks at any timestamp.
-        if let Some(lock) = try!(self.reader.load_lock(key)) {
+        if let Some(lock) = lock {
             if lock.ts != self.start_ts {
                 return Err(Error::KeyIsLocked {
                     key: try!(key.raw()),
@@ -146,45 +204,184 @@ impl<'a> MvccTxn<'a> {
                     ttl: lock.ttl,
                 });
             }
-            // No need to overwrite the lock and data.
-            // If we use single delete, we can't put a key multiple times.
-            info!(
-                "duplicated prewrite with start_ts {}, ignore it.",
-                self.start_ts
-            );
-            return Ok(());
+            if for_update_ts.is_none() {
+                // No need to overwrite the lock and data.
+                // If we use single delete, we can't put a key multiple times.
+                info!(
+                    "duplicated prewrite with start_ts {}, ignore it.",
+                    self.start_ts
+                );
+                self.has_duplicated_prewrite = true;
+                return Ok(());
+            }
+        }
+        if let Mutation::Insert(_) = mutation {
+            if !options.skip_constraint_check {
+                try!(self.check_data_not_exist(key));
+            }
         }
 
-        let short_value = if let Mutation::Put((_, ref value)) = mutation {
-            if is_short_value(value) {
-                Some(value.clone())
-            } else {
-                None
+        let short_value = match mutation {
+            Mutation::Put((_, ref value)) | Mutation::Insert((_, ref value)) => {
+                if is_short_value(value) {
+                    Some(value.clone())
+                } else {
+                    None
+                }
             }
-        } else {
-            None
+            _ => None,
         };
 
-        self.lock_key(
-            key.clone(),
+        let mut lock = Lock::new(
             LockType::from_mutation(&mutation),
             primary.to_vec(),
+            self.start_ts,
             options.lock_ttl,
             short_value,
+        ).with_for_update_ts(for_update_ts.unwrap_or(0));
+        if options.use_async_commit {
+            let secondaries = if try!(key.raw()).as_slice() == primary {
+                options.secondary_keys.clone()
+            } else {
+                vec![]
+            };
+            lock = lock.with_min_commit_ts(cmp::max(options.min_commit_ts, self.start_ts + 1))
+                .use_async_commit(secondaries);
+        }
+        if options.try_one_pc {
+            self.one_pc_locks.push((key.clone(), lock));
+        } else {
+            self.put_lock(key.clone(), &lock);
+        }
+
+        match mutation {
+            Mutation::Put((_, ref value)) | Mutation::Insert((_, ref value)) => {
+                if !is_short_value(value) {
+                    let ts = self.start_ts;
+                    self.put_value(key, ts, value.clone());
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Fails with `AlreadyExist` if the latest version of the key is a put.
+    fn check_data_not_exist(&mut self, key: &Key) -> Result<()> {
+        let mut ts = u64::max_value();
+        while let Some((commit_ts, write)) = try!(self.reader.seek_write(key, ts)) {
+            match write.write_type {
+                WriteType::Put => return Err(Error::AlreadyExist { key: try!(key.raw()) }),
+                WriteType::Delete => break,
+                WriteType::Lock | WriteType::Rollback => ts = commit_ts - 1,
+            }
+        }
+        Ok(())
+    }
+
+    /// Locks the key before prewrite for a pessimistic transaction, it fails if the key
+    /// has been written after `for_update_ts`. Returns the latest committed value of the
+    /// key if `options.return_values` is set.
+    pub fn acquire_pessimistic_lock(
+        &mut self,
+        key: Key,
+        primary: &[u8],
+        for_update_ts: u64,
+        options: &Options,
+    ) -> Result<Option<Value>> {
+        if let Some(lock) = try!(self.reader.load_lock(&key)) {
+            if lock.ts != self.start_ts {
+                return Err(Error::KeyIsLocked {
+                    key: try!(key.raw()),
+                    primary: lock.primary,
+                    ts: lock.ts,
+                    ttl: lock.ttl,
+                });
+            }
+            // The key has been prewritten, or locked with a newer `for_update_ts`.
+            if lock.lock_type != LockType::Pessimistic || lock.for_update_ts >= for_update_ts {
+                return self.latest_value(&key, options);
+            }
+        } else {
+            if let Some((commit, _)) = try!(self.reader.seek_write(&key, u64::max_value())) {
+                if commit > for_update_ts {
+                    return Err(Error::WriteConflict {
+                        start_ts: self.start_ts,
+                        conflict_ts: commit,
+                        key: key.encoded().to_owned(),
+                        primary: primary.to_vec(),
+                    });
+                }
+            }
+            match try!(self.reader.get_txn_commit_info(&key, self.start_ts)) {
+                Some((_, WriteType::Rollback)) => {
+                    return Err(Error::PessimisticLockRolledBack {
+                        start_ts: self.start_ts,
+                        key: key.encoded().to_owned(),
+                    });
+                }
+                Some((commit_ts, _)) => return Err(Error::Committed { commit_ts: commit_ts }),
+                None => {}
+            }
+        }
+
+        let value = try!(self.latest_value(&key, options));
+        self.lock_key(
+            key,
+            LockType::Pessimistic,
+            primary.to_vec(),
+            options.lock_ttl,
+            None,
+            for_update_ts,
         );
+        Ok(value)
+    }
 
-        if let Mutation::Put((_, ref value)) = mutation {
-            if !is_short_value(value) {
-                let ts = self.start_ts;
-                self.put_value(key, ts, value.clone());
+    // The key is locked by the transaction, so no one else can commit it, the
+    // latest committed value is read without checking the lock.
+    fn latest_value(&mut self, key: &Key, options: &Options) -> Result<Option<Value>> {
+        if !options.return_values {
+            return Ok(None);
+        }
+        self.reader.get_committed(key, u64::max_value())
+    }
+
+    /// Releases the pessimistic lock acquired at or before `for_update_ts`, the key
+    /// isn't touched if it has been prewritten.
+    pub fn pessimistic_rollback(&mut self, key: Key, for_update_ts: u64) -> Result<()> {
+        if let Some(lock) = try!(self.reader.load_lock(&key)) {
+            if lock.lock_type == LockType::Pessimistic && lock.ts == self.start_ts &&
+                lock.for_update_ts <= for_update_ts
+            {
+                self.unlock_key(key);
             }
         }
         Ok(())
     }
 
     pub fn commit(&mut self, key: &Key, commit_ts: u64) -> Result<()> {
+        fail_point!("mvcc_commit", |_| Err(box_err!("commit is failed by fail point")));
         let (lock_type, short_value) = match try!(self.reader.load_lock(key)) {
-            Some(ref mut lock) if lock.ts == self.start_ts => {
+            // A pessimistic lock which hasn't been prewritten can't be committed.
+            Some(ref mut lock)
+                if lock.ts == self.start_ts && lock.lock_type != LockType::Pessimistic =>
+            {
+                if commit_ts < lock.min_commit_ts {
+                    info!(
+                        "commit ts is smaller than min commit ts, key:{}, start_ts:{}, \
+                         commit_ts:{}, min_commit_ts:{}",
+                        key,
+                        self.start_ts,
+                        commit_ts,
+                        lock.min_commit_ts
+                    );
+                    return Err(Error::CommitTsExpired {
+                        start_ts: self.start_ts,
+                        commit_ts: commit_ts,
+                        key: key.encoded().to_owned(),
+                        min_commit_ts: lock.min_commit_ts,
+                    });
+                }
                 (lock.lock_type, lock.short_value.take())
             }
             _ => {
@@ -221,6 +418,30 @@ impl<'a> MvccTxn<'a> {
         Ok(())
     }
 
+    /// Commits the keys prewritten with `try_one_pc` at `commit_ts`, the pessimistic
+    /// locks of the keys are released as well.
+    pub fn commit_one_pc(&mut self, commit_ts: u64) {
+        for (key, lock) in ::std::mem::replace(&mut self.one_pc_locks, vec![]) {
+            let write = Write::new(
+                WriteType::from_lock_type(lock.lock_type),
+                self.start_ts,
+                lock.short_value,
+            );
+            self.put_write(&key, commit_ts, write.to_bytes());
+            if lock.for_update_ts > 0 {
+                self.unlock_key(key);
+            }
+        }
+    }
+
+    /// Puts the locks of the keys prewritten with `try_one_pc`, the transaction is
+    /// committed by 2PC then.
+    pub fn fall_back_to_two_pc(&mut self) {
+        for (key, lock) in ::std::mem::replace(&mut self.one_pc_locks, vec![]) {
+            self.put_lock(key, &lock);
+        }
+    }
+
     pub fn rollback(&mut self, key: &Key) -> Result<()> {
         match try!(self.reader.load_lock(key)) {
             Some(ref lock) if lock.ts == self.start_ts => {
@@ -262,6 +483,109 @@ impl<'a> MvccTxn<'a> {
         Ok(())
     }
 
+    /// Checks the status of the transaction by its primary key. The transaction is
+    /// rolled back if its lock has expired at `current_ts`, or the primary key hasn't
+    /// been prewritten, so it can't be committed later.
+    ///
+    /// If the transaction is alive, the min_commit_ts of its primary lock is pushed
+    /// beyond `caller_start_ts`, so the reader at `caller_start_ts` can ignore the locks
+    /// of the transaction instead of waiting for it. 0 means not to push it.
+    pub fn check_txn_status(
+        &mut self,
+        primary: &Key,
+        caller_start_ts: u64,
+        current_ts: u64,
+    ) -> Result<TxnStatus> {
+        match try!(self.reader.load_lock(primary)) {
+            Some(mut lock) if lock.ts == self.start_ts => {
+                let expire = extract_physical(lock.ts) + lock.ttl;
+                let now = extract_physical(current_ts);
+                if expire > now {
+                    // The commit ts of an async commit transaction is decided by all its
+                    // locks, pushing the primary lock alone doesn't work.
+                    if caller_start_ts > 0 && caller_start_ts != u64::MAX &&
+                        lock.lock_type != LockType::Pessimistic &&
+                        !lock.use_async_commit &&
+                        lock.min_commit_ts <= caller_start_ts
+                    {
+                        lock.min_commit_ts = caller_start_ts + 1;
+                        self.put_lock(primary.clone(), &lock);
+                    }
+                    return Ok(TxnStatus::Alive { ttl: expire - now });
+                }
+                // The transaction may have been committed by the locks of all its keys, it's
+                // up to the caller to check the secondary locks.
+                if lock.use_async_commit {
+                    return Ok(TxnStatus::AsyncCommitLocked {
+                        min_commit_ts: lock.min_commit_ts,
+                        secondaries: lock.secondaries.clone(),
+                    });
+                }
+                info!(
+                    "txn lock expired, key:{}, start_ts:{}, current_ts:{}",
+                    primary,
+                    self.start_ts,
+                    current_ts
+                );
+            }
+            _ => match try!(self.reader.get_txn_commit_info(primary, self.start_ts)) {
+                Some((_, WriteType::Rollback)) => return Ok(TxnStatus::RolledBack),
+                Some((commit_ts, _)) => {
+                    return Ok(TxnStatus::Committed {
+                        commit_ts: commit_ts,
+                    })
+                }
+                None => {}
+            },
+        }
+        try!(self.rollback(primary));
+        Ok(TxnStatus::RolledBack)
+    }
+
+    /// Extends the ttl of the primary lock to `advise_ttl` so a long transaction isn't
+    /// rolled back by the others, and returns the ttl of the lock.
+    pub fn txn_heart_beat(&mut self, primary: Key, advise_ttl: u64) -> Result<u64> {
+        match try!(self.reader.load_lock(&primary)) {
+            Some(ref mut lock) if lock.ts == self.start_ts => {
+                if lock.ttl < advise_ttl {
+                    lock.ttl = advise_ttl;
+                    self.put_lock(primary, lock);
+                }
+                Ok(lock.ttl)
+            }
+            _ => {
+                info!(
+                    "txn heart beat on missing lock, key:{}, start_ts:{}",
+                    primary,
+                    self.start_ts
+                );
+                Err(Error::TxnLockNotFound {
+                    start_ts: self.start_ts,
+                    commit_ts: 0,
+                    key: primary.encoded().to_owned(),
+                })
+            }
+        }
+    }
+
+    /// Checks a secondary key of an async commit transaction. The key is rolled back
+    /// if it's neither locked nor committed, so the transaction can't be committed later.
+    pub fn check_secondary_lock(&mut self, key: &Key) -> Result<SecondaryLockStatus> {
+        if let Some(lock) = try!(self.reader.load_lock(key)) {
+            if lock.ts == self.start_ts {
+                return Ok(SecondaryLockStatus::Locked(lock));
+            }
+        }
+        match try!(self.reader.get_txn_commit_info(key, self.start_ts)) {
+            Some((_, WriteType::Rollback)) => Ok(SecondaryLockStatus::RolledBack),
+            Some((commit_ts, _)) => Ok(SecondaryLockStatus::Committed(commit_ts)),
+            None => {
+                try!(self.rollback(key));
+                Ok(SecondaryLockStatus::RolledBack)
+            }
+        }
+    }
+
     pub fn gc(&mut self, key: &Key, safe_point: u64) -> Result<()> {
         let mut remove_older = false;
         let mut ts: u64 = u64::max_value();
@@ -328,8 +652,8 @@ impl<'a> MvccTxn<'a> {
 mod tests {
     use tempdir::TempDir;
     use kvproto::kvrpcpb::{Context, IsolationLevel};
-    use super::MvccTxn;
-    use super::super::MvccReader;
+    use super::{MvccTxn, SecondaryLockStatus, TxnStatus};
+    use super::super::{Error, Lock, LockType, MvccReader, Result};
     use super::super::write::{Write, WriteType};
     use storage::{make_key, Mutation, Options, ScanMode, Statistics, ALL_CFS, CF_WRITE,
                   SHORT_VALUE_MAX_LEN};
@@ -436,6 +760,300 @@ mod tests {
         must_rollback(engine.as_ref(), k, 15);
     }
 
+    #[test]
+    fn test_pessimistic_lock() {
+        let engine = engine::new_local_engine(TEMP_DIR, ALL_CFS).unwrap();
+
+        let (k, v) = (b"k1", b"v1");
+        assert_eq!(must_acquire_pessimistic_lock(engine.as_ref(), k, k, 1, 1), None);
+        must_pessimistic_locked(engine.as_ref(), k, 1, 1);
+        // Reads are not blocked by the pessimistic lock.
+        must_get_none(engine.as_ref(), k, 2);
+        // Lock again with a newer for_update_ts.
+        must_acquire_pessimistic_lock(engine.as_ref(), k, k, 1, 2);
+        must_pessimistic_locked(engine.as_ref(), k, 1, 2);
+        // Locked by the other transaction.
+        must_acquire_pessimistic_lock_err(engine.as_ref(), k, k, 3, 3);
+        // Can't commit before prewrite.
+        must_commit_err(engine.as_ref(), k, 1, 4);
+        must_prewrite_put(engine.as_ref(), k, v, k, 1);
+        must_locked(engine.as_ref(), k, 1);
+        must_commit(engine.as_ref(), k, 1, 4);
+        must_get(engine.as_ref(), k, 5, v);
+
+        // Written after for_update_ts.
+        must_acquire_pessimistic_lock_err(engine.as_ref(), k, k, 3, 3);
+        must_unlocked(engine.as_ref(), k);
+        // The latest committed value is returned.
+        let value = must_acquire_pessimistic_lock(engine.as_ref(), k, k, 3, 5);
+        assert_eq!(value, Some(v.to_vec()));
+        // The write conflict check is skipped by the prewrite of a locked key.
+        must_prewrite_delete(engine.as_ref(), k, k, 3);
+        must_commit(engine.as_ref(), k, 3, 6);
+        must_get_none(engine.as_ref(), k, 7);
+
+        assert_eq!(must_acquire_pessimistic_lock(engine.as_ref(), k, k, 8, 8), None);
+        // Stale pessimistic rollback is ignored.
+        must_pessimistic_rollback(engine.as_ref(), k, 8, 7);
+        must_pessimistic_locked(engine.as_ref(), k, 8, 8);
+        must_pessimistic_rollback(engine.as_ref(), k, 8, 8);
+        must_unlocked(engine.as_ref(), k);
+
+        // Can't lock after the transaction is rolled back.
+        must_acquire_pessimistic_lock(engine.as_ref(), k, k, 9, 9);
+        must_rollback(engine.as_ref(), k, 9);
+        must_unlocked(engine.as_ref(), k);
+        must_acquire_pessimistic_lock_err(engine.as_ref(), k, k, 9, 10);
+    }
+
+    #[test]
+    fn test_check_txn_status() {
+        let engine = engine::new_local_engine(TEMP_DIR, ALL_CFS).unwrap();
+
+        // The physical parts of the timestamps are 10ms, 15ms and 30ms.
+        let (ts10, ts15, ts30) = (10 << 18, 15 << 18, 30 << 18);
+        let (k, v) = (b"k1", b"v1");
+        must_prewrite_put_ttl(engine.as_ref(), k, v, k, ts10, 10);
+        assert_eq!(
+            check_txn_status(engine.as_ref(), k, ts10, 0, ts15),
+            TxnStatus::Alive { ttl: 5 }
+        );
+        must_locked(engine.as_ref(), k, ts10);
+        must_commit(engine.as_ref(), k, ts10, ts15);
+        assert_eq!(
+            check_txn_status(engine.as_ref(), k, ts10, 0, ts30),
+            TxnStatus::Committed { commit_ts: ts15 }
+        );
+
+        // The expired lock is rolled back.
+        let ts20 = 20 << 18;
+        must_prewrite_put_ttl(engine.as_ref(), k, v, k, ts20, 5);
+        assert_eq!(check_txn_status(engine.as_ref(), k, ts20, 0, ts30), TxnStatus::RolledBack);
+        must_unlocked(engine.as_ref(), k);
+        must_written(engine.as_ref(), k, ts20, ts20, WriteType::Rollback);
+        assert_eq!(check_txn_status(engine.as_ref(), k, ts20, 0, ts30), TxnStatus::RolledBack);
+
+        // The transaction can't prewrite the primary key after it's checked.
+        let ts25 = 25 << 18;
+        assert_eq!(check_txn_status(engine.as_ref(), k, ts25, 0, ts30), TxnStatus::RolledBack);
+        must_written(engine.as_ref(), k, ts25, ts25, WriteType::Rollback);
+        must_prewrite_lock_err(engine.as_ref(), k, k, ts25);
+    }
+
+    #[test]
+    fn test_check_txn_status_push_min_commit_ts() {
+        let engine = engine::new_local_engine(TEMP_DIR, ALL_CFS).unwrap();
+
+        let (ts10, ts15, ts20, ts30) = (10 << 18, 15 << 18, 20 << 18, 30 << 18);
+        let (k, v) = (b"k1", b"v1");
+        must_prewrite_put_ttl(engine.as_ref(), k, v, k, ts10, 100);
+        must_get_err(engine.as_ref(), k, ts15);
+        assert_eq!(
+            check_txn_status(engine.as_ref(), k, ts10, ts15, ts20),
+            TxnStatus::Alive { ttl: 90 }
+        );
+        assert_eq!(must_load_lock(engine.as_ref(), k).min_commit_ts, ts15 + 1);
+        // The read at ts15 isn't blocked any more.
+        must_get_none(engine.as_ref(), k, ts15);
+        must_get_err(engine.as_ref(), k, ts20);
+
+        // A smaller caller_start_ts doesn't move it backward.
+        check_txn_status(engine.as_ref(), k, ts10, ts10 + 1, ts20);
+        assert_eq!(must_load_lock(engine.as_ref(), k).min_commit_ts, ts15 + 1);
+        // Reading the latest version doesn't push it.
+        check_txn_status(engine.as_ref(), k, ts10, ::std::u64::MAX, ts20);
+        assert_eq!(must_load_lock(engine.as_ref(), k).min_commit_ts, ts15 + 1);
+
+        // The transaction can't be committed before the pushed min_commit_ts.
+        must_commit_err(engine.as_ref(), k, ts10, ts15);
+        must_commit(engine.as_ref(), k, ts10, ts30);
+        must_get(engine.as_ref(), k, ts30, v);
+        must_get_none(engine.as_ref(), k, ts20);
+    }
+
+    #[test]
+    fn test_txn_heart_beat() {
+        let engine = engine::new_local_engine(TEMP_DIR, ALL_CFS).unwrap();
+
+        let (k, v) = (b"k1", b"v1");
+        // No lock to extend.
+        assert!(txn_heart_beat(engine.as_ref(), k, 5, 100).is_err());
+        must_prewrite_put_ttl(engine.as_ref(), k, v, k, 5, 10);
+        assert_eq!(txn_heart_beat(engine.as_ref(), k, 5, 100).unwrap(), 100);
+        // The ttl is never shortened.
+        assert_eq!(txn_heart_beat(engine.as_ref(), k, 5, 50).unwrap(), 100);
+        // The lock of the other transaction isn't touched.
+        assert!(txn_heart_beat(engine.as_ref(), k, 6, 200).is_err());
+        must_commit(engine.as_ref(), k, 5, 10);
+        must_get(engine.as_ref(), k, 11, v);
+        assert!(txn_heart_beat(engine.as_ref(), k, 5, 200).is_err());
+    }
+
+    #[test]
+    fn test_async_commit() {
+        let engine = engine::new_local_engine(TEMP_DIR, ALL_CFS).unwrap();
+
+        let (pk, k, v) = (b"pk", b"k1", b"v1");
+        let mut options = Options::default();
+        options.use_async_commit = true;
+        options.min_commit_ts = 8;
+        options.secondary_keys = vec![k.to_vec()];
+        must_prewrite_put_options(engine.as_ref(), pk, v, pk, 5, &options);
+        must_prewrite_put_options(engine.as_ref(), k, v, pk, 5, &options);
+        let lock = must_load_lock(engine.as_ref(), pk);
+        assert!(lock.use_async_commit);
+        assert_eq!(lock.min_commit_ts, 8);
+        assert_eq!(lock.secondaries, vec![k.to_vec()]);
+        let lock = must_load_lock(engine.as_ref(), k);
+        assert!(lock.use_async_commit);
+        assert!(lock.secondaries.is_empty());
+
+        // The locks are invisible to the reads before min_commit_ts.
+        must_get_none(engine.as_ref(), k, 7);
+        must_get_err(engine.as_ref(), k, 8);
+        assert_eq!(
+            check_secondary_lock(engine.as_ref(), k, 5),
+            SecondaryLockStatus::Locked(lock)
+        );
+        // Can't commit before min_commit_ts.
+        must_commit_err(engine.as_ref(), pk, 5, 7);
+        must_commit(engine.as_ref(), pk, 5, 8);
+        must_commit(engine.as_ref(), k, 5, 8);
+        assert_eq!(
+            check_secondary_lock(engine.as_ref(), k, 5),
+            SecondaryLockStatus::Committed(8)
+        );
+
+        // The key which isn't prewritten is rolled back.
+        assert_eq!(
+            check_secondary_lock(engine.as_ref(), k, 10),
+            SecondaryLockStatus::RolledBack
+        );
+        must_written(engine.as_ref(), k, 10, 10, WriteType::Rollback);
+        must_prewrite_lock_err(engine.as_ref(), k, pk, 10);
+    }
+
+    #[test]
+    fn test_one_pc() {
+        let engine = engine::new_local_engine(TEMP_DIR, ALL_CFS).unwrap();
+
+        let (k1, k2, v) = (b"k1", b"k2", b"v");
+        must_acquire_pessimistic_lock(engine.as_ref(), k2, k1, 5, 5);
+        let mut options = Options::default();
+        options.try_one_pc = true;
+        let ctx = Context::new();
+        let snapshot = engine.snapshot(&ctx).unwrap();
+        let mut statistics = Statistics::default();
+        let mut txn = MvccTxn::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            5,
+            None,
+            IsolationLevel::SI,
+            true,
+        );
+        txn.prewrite(Mutation::Put((make_key(k1), v.to_vec())), k1, &options)
+            .unwrap();
+        txn.prewrite(Mutation::Put((make_key(k2), v.to_vec())), k1, &options)
+            .unwrap();
+        txn.commit_one_pc(10);
+        engine.write(&ctx, txn.modifies()).unwrap();
+
+        must_unlocked(engine.as_ref(), k1);
+        must_unlocked(engine.as_ref(), k2);
+        must_written(engine.as_ref(), k1, 5, 10, WriteType::Put);
+        must_get_none(engine.as_ref(), k2, 9);
+        must_get(engine.as_ref(), k2, 10, v);
+    }
+
+    #[test]
+    fn test_one_pc_fall_back() {
+        let engine = engine::new_local_engine(TEMP_DIR, ALL_CFS).unwrap();
+
+        let (k1, k2, v) = (b"k1", b"k2", b"v");
+        let mut options = Options::default();
+        options.try_one_pc = true;
+        let ctx = Context::new();
+        let snapshot = engine.snapshot(&ctx).unwrap();
+        let mut statistics = Statistics::default();
+        let mut txn = MvccTxn::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            5,
+            None,
+            IsolationLevel::SI,
+            true,
+        );
+        txn.prewrite(Mutation::Put((make_key(k1), v.to_vec())), k1, &options)
+            .unwrap();
+        txn.prewrite(Mutation::Put((make_key(k2), v.to_vec())), k1, &options)
+            .unwrap();
+        txn.fall_back_to_two_pc();
+        engine.write(&ctx, txn.modifies()).unwrap();
+
+        must_locked(engine.as_ref(), k1, 5);
+        must_locked(engine.as_ref(), k2, 5);
+        must_commit(engine.as_ref(), k1, 5, 10);
+        must_commit(engine.as_ref(), k2, 5, 10);
+        must_get(engine.as_ref(), k2, 10, v);
+    }
+
+    #[test]
+    fn test_one_pc_after_two_pc() {
+        let engine = engine::new_local_engine(TEMP_DIR, ALL_CFS).unwrap();
+
+        let (k1, k2, v) = (b"k1", b"k2", b"v");
+        // The first prewrite has fallen back to 2PC.
+        must_prewrite_put(engine.as_ref(), k1, v, k1, 5);
+        let mut options = Options::default();
+        options.try_one_pc = true;
+        let ctx = Context::new();
+        let snapshot = engine.snapshot(&ctx).unwrap();
+        let mut statistics = Statistics::default();
+        let mut txn = MvccTxn::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            5,
+            None,
+            IsolationLevel::SI,
+            true,
+        );
+        txn.prewrite(Mutation::Put((make_key(k1), v.to_vec())), k1, &options)
+            .unwrap();
+        txn.prewrite(Mutation::Put((make_key(k2), v.to_vec())), k1, &options)
+            .unwrap();
+        assert!(txn.has_duplicated_prewrite());
+        txn.fall_back_to_two_pc();
+        engine.write(&ctx, txn.modifies()).unwrap();
+
+        must_locked(engine.as_ref(), k1, 5);
+        must_locked(engine.as_ref(), k2, 5);
+        must_commit(engine.as_ref(), k1, 5, 10);
+        must_commit(engine.as_ref(), k2, 5, 10);
+        must_get(engine.as_ref(), k1, 10, v);
+        must_get(engine.as_ref(), k2, 10, v);
+    }
+
+    #[test]
+    fn test_prewrite_insert() {
+        let engine = engine::new_local_engine(TEMP_DIR, ALL_CFS).unwrap();
+
+        let (k, v) = (b"k", b"v");
+        must_prewrite_insert(engine.as_ref(), k, v, k, 5);
+        must_commit(engine.as_ref(), k, 5, 10);
+        must_prewrite_insert_err(engine.as_ref(), k, v, k, 15);
+
+        // The key can be inserted after it's deleted, the locks and rollbacks are skipped.
+        must_prewrite_delete(engine.as_ref(), k, k, 20);
+        must_commit(engine.as_ref(), k, 20, 25);
+        must_prewrite_lock(engine.as_ref(), k, k, 30);
+        must_commit(engine.as_ref(), k, 30, 35);
+        must_rollback(engine.as_ref(), k, 40);
+        must_prewrite_insert(engine.as_ref(), k, v, k, 45);
+        must_commit(engine.as_ref(), k, 45, 50);
+        must_get(engine.as_ref(), k, 55, v);
+    }
+
     #[test]
     fn test_mvcc_txn_prewrite() {
         test_mvcc_txn_prewrite_imp(b"k1", b"v1");
@@ -826,6 +1444,24 @@ mod tests {
             txn.prewrite(Mutation::Put((make_key(key), value.to_vec())), key, &opt)
                 .is_ok()
         );
+
+        // The existence check of the inserted key is skipped too.
+        let ctx = Context::new();
+        let snapshot = engine.snapshot(&ctx).unwrap();
+        let mut statistics = Statistics::default();
+        let mut txn = MvccTxn::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            15,
+            None,
+            IsolationLevel::SI,
+            true,
+        );
+        assert!(
+            txn.prewrite(Mutation::Insert((make_key(key), value.to_vec())), key, &opt)
+                .is_ok()
+        );
+        must_prewrite_insert_err(engine.as_ref(), key, value, key, 15);
     }
 
     #[test]
@@ -921,6 +1557,154 @@ mod tests {
         engine.write(&ctx, txn.modifies()).unwrap();
     }
 
+    fn must_prewrite_put_ttl(
+        engine: &Engine,
+        key: &[u8],
+        value: &[u8],
+        pk: &[u8],
+        ts: u64,
+        ttl: u64,
+    ) {
+        let ctx = Context::new();
+        let snapshot = engine.snapshot(&ctx).unwrap();
+        let mut statistics = Statistics::default();
+        let mut txn = MvccTxn::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            ts,
+            None,
+            IsolationLevel::SI,
+            true,
+        );
+        let mut options = Options::default();
+        options.lock_ttl = ttl;
+        txn.prewrite(Mutation::Put((make_key(key), value.to_vec())), pk, &options)
+            .unwrap();
+        engine.write(&ctx, txn.modifies()).unwrap();
+    }
+
+    fn check_txn_status(
+        engine: &Engine,
+        key: &[u8],
+        lock_ts: u64,
+        caller_start_ts: u64,
+        current_ts: u64,
+    ) -> TxnStatus {
+        let ctx = Context::new();
+        let snapshot = engine.snapshot(&ctx).unwrap();
+        let mut statistics = Statistics::default();
+        let mut txn = MvccTxn::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            lock_ts,
+            None,
+            IsolationLevel::SI,
+            true,
+        );
+        let status = txn.check_txn_status(&make_key(key), caller_start_ts, current_ts).unwrap();
+        engine.write(&ctx, txn.modifies()).unwrap();
+        status
+    }
+
+    fn txn_heart_beat(engine: &Engine, key: &[u8], start_ts: u64, advise_ttl: u64) -> Result<u64> {
+        let ctx = Context::new();
+        let snapshot = engine.snapshot(&ctx).unwrap();
+        let mut statistics = Statistics::default();
+        let mut txn = MvccTxn::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            start_ts,
+            None,
+            IsolationLevel::SI,
+            true,
+        );
+        let ttl = try!(txn.txn_heart_beat(make_key(key), advise_ttl));
+        engine.write(&ctx, txn.modifies()).unwrap();
+        Ok(ttl)
+    }
+
+    fn must_prewrite_put_options(
+        engine: &Engine,
+        key: &[u8],
+        value: &[u8],
+        pk: &[u8],
+        ts: u64,
+        options: &Options,
+    ) {
+        let ctx = Context::new();
+        let snapshot = engine.snapshot(&ctx).unwrap();
+        let mut statistics = Statistics::default();
+        let mut txn = MvccTxn::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            ts,
+            None,
+            IsolationLevel::SI,
+            true,
+        );
+        txn.prewrite(Mutation::Put((make_key(key), value.to_vec())), pk, options)
+            .unwrap();
+        engine.write(&ctx, txn.modifies()).unwrap();
+    }
+
+    fn check_secondary_lock(engine: &Engine, key: &[u8], start_ts: u64) -> SecondaryLockStatus {
+        let ctx = Context::new();
+        let snapshot = engine.snapshot(&ctx).unwrap();
+        let mut statistics = Statistics::default();
+        let mut txn = MvccTxn::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            start_ts,
+            None,
+            IsolationLevel::SI,
+            true,
+        );
+        let status = txn.check_secondary_lock(&make_key(key)).unwrap();
+        engine.write(&ctx, txn.modifies()).unwrap();
+        status
+    }
+
+    fn must_prewrite_insert(engine: &Engine, key: &[u8], value: &[u8], pk: &[u8], ts: u64) {
+        let ctx = Context::new();
+        let snapshot = engine.snapshot(&ctx).unwrap();
+        let mut statistics = Statistics::default();
+        let mut txn = MvccTxn::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            ts,
+            None,
+            IsolationLevel::SI,
+            true,
+        );
+        txn.prewrite(
+            Mutation::Insert((make_key(key), value.to_vec())),
+            pk,
+            &Options::default(),
+        ).unwrap();
+        engine.write(&ctx, txn.modifies()).unwrap();
+    }
+
+    fn must_prewrite_insert_err(engine: &Engine, key: &[u8], value: &[u8], pk: &[u8], ts: u64) {
+        let snapshot = engine.snapshot(&Context::new()).unwrap();
+        let mut statistics = Statistics::default();
+        let mut txn = MvccTxn::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            ts,
+            None,
+            IsolationLevel::SI,
+            true,
+        );
+        match txn.prewrite(
+            Mutation::Insert((make_key(key), value.to_vec())),
+            pk,
+            &Options::default(),
+        ) {
+            Err(Error::AlreadyExist { .. }) => {}
+            res => panic!("expect already exist, got {:?}", res),
+        }
+    }
+
     fn must_prewrite_delete(engine: &Engine, key: &[u8], pk: &[u8], ts: u64) {
         let ctx = Context::new();
         let snapshot = engine.snapshot(&ctx).unwrap();
@@ -973,6 +1757,67 @@ mod tests {
         );
     }
 
+    fn acquire_pessimistic_lock(
+        engine: &Engine,
+        key: &[u8],
+        pk: &[u8],
+        start_ts: u64,
+        for_update_ts: u64,
+    ) -> Result<Option<Value>> {
+        let ctx = Context::new();
+        let snapshot = engine.snapshot(&ctx).unwrap();
+        let mut statistics = Statistics::default();
+        let mut txn = MvccTxn::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            start_ts,
+            None,
+            IsolationLevel::SI,
+            true,
+        );
+        let mut options = Options::default();
+        options.return_values = true;
+        let value = try!(txn.acquire_pessimistic_lock(make_key(key), pk, for_update_ts, &options));
+        engine.write(&ctx, txn.modifies()).unwrap();
+        Ok(value)
+    }
+
+    fn must_acquire_pessimistic_lock(
+        engine: &Engine,
+        key: &[u8],
+        pk: &[u8],
+        start_ts: u64,
+        for_update_ts: u64,
+    ) -> Option<Value> {
+        acquire_pessimistic_lock(engine, key, pk, start_ts, for_update_ts).unwrap()
+    }
+
+    fn must_acquire_pessimistic_lock_err(
+        engine: &Engine,
+        key: &[u8],
+        pk: &[u8],
+        start_ts: u64,
+        for_update_ts: u64,
+    ) {
+        assert!(acquire_pessimistic_lock(engine, key, pk, start_ts, for_update_ts).is_err());
+    }
+
+    fn must_pessimistic_rollback(engine: &Engine, key: &[u8], start_ts: u64, for_update_ts: u64) {
+        let ctx = Context::new();
+        let snapshot = engine.snapshot(&ctx).unwrap();
+        let mut statistics = Statistics::default();
+        let mut txn = MvccTxn::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            start_ts,
+            None,
+            IsolationLevel::SI,
+            true,
+        );
+        txn.pessimistic_rollback(make_key(key), for_update_ts).unwrap();
+        engine.write(&ctx, txn.modifies()).unwrap();
+    }
+
     fn must_commit(engine: &Engine, key: &[u8], start_ts: u64, commit_ts: u64) {
         let ctx = Context::new();
         let snapshot = engine.snapshot(&ctx).unwrap();
@@ -1066,6 +1911,37 @@ mod tests {
         assert_eq!(lock.ts, start_ts);
     }
 
+    fn must_pessimistic_locked(engine: &Engine, key: &[u8], start_ts: u64, for_update_ts: u64) {
+        let snapshot = engine.snapshot(&Context::new()).unwrap();
+        let mut statistics = Statistics::default();
+        let mut reader = MvccReader::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            None,
+            true,
+            None,
+            IsolationLevel::SI,
+        );
+        let lock = reader.load_lock(&make_key(key)).unwrap().unwrap();
+        assert_eq!(lock.ts, start_ts);
+        assert_eq!(lock.lock_type, LockType::Pessimistic);
+        assert_eq!(lock.for_update_ts, for_update_ts);
+    }
+
+    fn must_load_lock(engine: &Engine, key: &[u8]) -> Lock {
+        let snapshot = engine.snapshot(&Context::new()).unwrap();
+        let mut statistics = Statistics::default();
+        let mut reader = MvccReader::new(
+            snapshot.as_ref(),
+            &mut statistics,
+            None,
+            true,
+            None,
+            IsolationLevel::SI,
+        );
+        reader.load_lock(&make_key(key)).unwrap().unwrap()
+    }
+
     fn must_unlocked(engine: &Engine, key: &[u8]) {
         let snapshot = engine.snapshot(&Context::new()).unwrap();
         let mut statistics = Statistics::default();
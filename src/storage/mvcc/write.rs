@@ -37,6 +37,8 @@ impl WriteType {
             LockType::Put => WriteType::Put,
             LockType::Delete => WriteType::Delete,
             LockType::Lock => WriteType::Lock,
+            // A pessimistic lock must be prewritten before it's committed.
+            LockType::Pessimistic => panic!("pessimistic lock can't be committed"),
         }
     }
 
@@ -0,0 +1,554 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, Mutex};
+
+use kvproto::metapb::Region;
+use kvproto::raft_cmdpb::{AdminCmdType, AdminRequest, CmdType, PutRequest, Request};
+use raft::StateRole;
+use rocksdb::TablePropertiesCollection;
+
+use raftstore::coprocessor::{Coprocessor, ObserverContext, RegionObserver};
+use raftstore::store::engine::IterOption;
+use util::collections::HashMap;
+use super::engine::{Cursor, Modify, Result, ScanMode, Snapshot};
+use super::mvcc::{Lock, LockType, Result as MvccResult};
+use super::{CfName, Key, Value, CF_LOCK};
+
+// The locks acquired after the table of a region is full are written through
+// raft, it bounds the memory and the size of the proposal made before the
+// leadership is transferred.
+const MAX_LOCKS_PER_REGION: usize = 4096;
+
+struct RegionLocks {
+    // Changed each time the region becomes the leader.
+    generation: u64,
+    // encoded key -> lock.
+    locks: HashMap<Vec<u8>, Value>,
+}
+
+#[derive(Default)]
+struct State {
+    next_generation: u64,
+    regions: HashMap<u64, RegionLocks>,
+}
+
+/// `PessimisticLockTable` keeps the pessimistic locks of the leader regions
+/// in memory instead of writing them through raft. The locks are replicated
+/// to the new leader by a proposal made before the leadership is transferred,
+/// and are lost if the leader changes otherwise, e.g. it crashes. It's safe
+/// because a prewrite checks the write conflicts as an optimistic one if its
+/// pessimistic lock is missing.
+///
+/// The point lookups of the write commands read the table before the lock
+/// cf, and the lock scans merge the locks in it with the ones scanned.
+#[derive(Clone, Default)]
+pub struct PessimisticLockTable {
+    state: Arc<Mutex<State>>,
+}
+
+impl PessimisticLockTable {
+    pub fn new() -> PessimisticLockTable {
+        PessimisticLockTable::default()
+    }
+
+    /// Returns the generation of the table of the region, or `None` if the
+    /// region isn't the leader on the store.
+    pub fn generation(&self, region_id: u64) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        state.regions.get(&region_id).map(|r| r.generation)
+    }
+
+    /// Keeps the pessimistic locks written by `modifies` in memory and returns
+    /// the rest to be written. `generation` is taken before the command reads
+    /// its snapshot, the locks are written through raft if the region isn't
+    /// the same leader since then.
+    pub fn apply_modifies(
+        &self,
+        region_id: u64,
+        generation: Option<u64>,
+        modifies: Vec<Modify>,
+    ) -> Vec<Modify> {
+        let mut state = self.state.lock().unwrap();
+        let region = match state.regions.get_mut(&region_id) {
+            Some(region) => region,
+            None => return modifies,
+        };
+        let is_same_leader = Some(region.generation) == generation;
+        let mut to_be_write = Vec::with_capacity(modifies.len());
+        for m in modifies {
+            match m {
+                Modify::Put(cf, key, value) => {
+                    if cf == CF_LOCK {
+                        if is_same_leader && is_pessimistic_lock(&value) &&
+                            (region.locks.len() < MAX_LOCKS_PER_REGION ||
+                                region.locks.contains_key(key.encoded()))
+                        {
+                            region.locks.insert(key.encoded().to_owned(), value);
+                            continue;
+                        }
+                        region.locks.remove(key.encoded());
+                    }
+                    to_be_write.push(Modify::Put(cf, key, value));
+                }
+                // The lock may have been written through raft when the
+                // leadership was transferred, so it's still deleted.
+                Modify::Delete(cf, key) => {
+                    if cf == CF_LOCK {
+                        region.locks.remove(key.encoded());
+                    }
+                    to_be_write.push(Modify::Delete(cf, key));
+                }
+                Modify::DeleteRange(cf, start_key, end_key) => {
+                    if cf == CF_LOCK {
+                        region.locks.retain(|k, _| {
+                            k.as_slice() < start_key.encoded().as_slice() ||
+                                k.as_slice() >= end_key.encoded().as_slice()
+                        });
+                    }
+                    to_be_write.push(Modify::DeleteRange(cf, start_key, end_key));
+                }
+            }
+        }
+        to_be_write
+    }
+
+    /// Returns the lock of `key` kept in memory.
+    pub fn get(&self, region_id: u64, key: &Key) -> Option<Value> {
+        let state = self.state.lock().unwrap();
+        state
+            .regions
+            .get(&region_id)
+            .and_then(|r| r.locks.get(key.encoded()).cloned())
+    }
+
+    /// Returns the locks kept in memory which pass `filter` and whose keys are
+    /// in `[start_key, end_key)` in the key order, an empty `end_key` means no
+    /// upper bound. The locks of all the regions are returned if `region_id`
+    /// is `None`.
+    pub fn scan_locks<F>(
+        &self,
+        region_id: Option<u64>,
+        start_key: &[u8],
+        end_key: &[u8],
+        filter: F,
+    ) -> MvccResult<Vec<(Key, Lock)>>
+    where
+        F: Fn(&Lock) -> bool,
+    {
+        let state = self.state.lock().unwrap();
+        let mut locks = vec![];
+        for (id, region) in &state.regions {
+            if region_id.map_or(false, |region_id| region_id != *id) {
+                continue;
+            }
+            for (key, value) in &region.locks {
+                if key.as_slice() < start_key ||
+                    (!end_key.is_empty() && key.as_slice() >= end_key)
+                {
+                    continue;
+                }
+                let lock = try!(Lock::parse(value));
+                if filter(&lock) {
+                    locks.push((Key::from_encoded(key.clone()), lock));
+                }
+            }
+        }
+        locks.sort_by(|l, r| l.0.encoded().cmp(r.0.encoded()));
+        Ok(locks)
+    }
+
+    /// Returns a snapshot which sees the locks kept in memory if the region is
+    /// the leader, otherwise `snapshot` is returned.
+    pub fn wrap_snapshot(&self, region_id: u64, snapshot: Box<Snapshot>) -> Box<Snapshot> {
+        if self.generation(region_id).is_none() {
+            return snapshot;
+        }
+        box PessimisticLockSnapshot {
+            snapshot: snapshot,
+            table: self.clone(),
+            region_id: region_id,
+        }
+    }
+
+    fn on_leader(&self, region_id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.next_generation += 1;
+        let region = RegionLocks {
+            generation: state.next_generation,
+            locks: HashMap::default(),
+        };
+        state.regions.insert(region_id, region);
+    }
+
+    fn remove_region(&self, region_id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.regions.remove(&region_id);
+    }
+
+    // The locks out of the range of the region after a split are dropped.
+    fn on_split(&self, region: &Region) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(r) = state.regions.get_mut(&region.get_id()) {
+            r.locks.retain(|k, _| {
+                k.as_slice() >= region.get_start_key() &&
+                    (region.get_end_key().is_empty() || k.as_slice() < region.get_end_key())
+            });
+        }
+    }
+
+    fn requests_to_transfer(&self, region_id: u64) -> Vec<Request> {
+        let state = self.state.lock().unwrap();
+        let region = match state.regions.get(&region_id) {
+            Some(region) => region,
+            None => return vec![],
+        };
+        region
+            .locks
+            .iter()
+            .map(|(key, lock)| {
+                let mut put = PutRequest::new();
+                put.set_cf(CF_LOCK.to_owned());
+                put.set_key(key.clone());
+                put.set_value(lock.clone());
+                let mut req = Request::new();
+                req.set_cmd_type(CmdType::Put);
+                req.set_put(put);
+                req
+            })
+            .collect()
+    }
+}
+
+/// Merges the locks scanned from the lock cf with the ones kept in memory, both
+/// in the key order. The one in memory is newer if a key is in both.
+pub fn merge_locks(scanned: Vec<(Key, Lock)>, in_memory: Vec<(Key, Lock)>) -> Vec<(Key, Lock)> {
+    let mut locks = Vec::with_capacity(scanned.len() + in_memory.len());
+    let mut scanned = scanned.into_iter().peekable();
+    for (key, lock) in in_memory {
+        while scanned
+            .peek()
+            .map_or(false, |&(ref k, _)| k.encoded() < key.encoded())
+        {
+            locks.push(scanned.next().unwrap());
+        }
+        if scanned
+            .peek()
+            .map_or(false, |&(ref k, _)| k.encoded() == key.encoded())
+        {
+            scanned.next();
+        }
+        locks.push((key, lock));
+    }
+    locks.extend(scanned);
+    locks
+}
+
+fn is_pessimistic_lock(value: &[u8]) -> bool {
+    match Lock::parse(value) {
+        Ok(lock) => lock.lock_type == LockType::Pessimistic,
+        Err(e) => {
+            error!("failed to parse lock {:?}: {:?}", value, e);
+            false
+        }
+    }
+}
+
+impl Coprocessor for PessimisticLockTable {}
+
+impl RegionObserver for PessimisticLockTable {
+    fn on_role_change(&self, ctx: &mut ObserverContext, role: StateRole) {
+        let region_id = ctx.region().get_id();
+        if role == StateRole::Leader {
+            self.on_leader(region_id);
+        } else {
+            self.remove_region(region_id);
+        }
+    }
+
+    fn post_apply_admin(&self, ctx: &mut ObserverContext, req: &AdminRequest) {
+        if req.get_cmd_type() == AdminCmdType::Split {
+            self.on_split(ctx.region());
+        }
+    }
+
+    // The locks are still kept in memory, the leader serves the writes until
+    // the leadership is transferred.
+    fn pre_transfer_leader(&self, ctx: &mut ObserverContext) -> Vec<Request> {
+        self.requests_to_transfer(ctx.region().get_id())
+    }
+}
+
+/// `PessimisticLockSnapshot` reads the locks kept in memory before the ones in
+/// the snapshot.
+struct PessimisticLockSnapshot {
+    snapshot: Box<Snapshot>,
+    table: PessimisticLockTable,
+    region_id: u64,
+}
+
+impl Snapshot for PessimisticLockSnapshot {
+    fn get(&self, key: &Key) -> Result<Option<Value>> {
+        self.snapshot.get(key)
+    }
+
+    fn get_cf(&self, cf: CfName, key: &Key) -> Result<Option<Value>> {
+        if cf == CF_LOCK {
+            if let Some(lock) = self.table.get(self.region_id, key) {
+                return Ok(Some(lock));
+            }
+        }
+        self.snapshot.get_cf(cf, key)
+    }
+
+    #[allow(needless_lifetimes)]
+    fn iter<'a>(&'a self, iter_opt: IterOption, mode: ScanMode) -> Result<Cursor<'a>> {
+        self.snapshot.iter(iter_opt, mode)
+    }
+
+    #[allow(needless_lifetimes)]
+    fn iter_cf<'a>(
+        &'a self,
+        cf: CfName,
+        iter_opt: IterOption,
+        mode: ScanMode,
+    ) -> Result<Cursor<'a>> {
+        self.snapshot.iter_cf(cf, iter_opt, mode)
+    }
+
+    fn get_properties_cf(&self, cf: CfName) -> Result<TablePropertiesCollection> {
+        self.snapshot.get_properties_cf(cf)
+    }
+
+    fn clone(&self) -> Box<Snapshot> {
+        box PessimisticLockSnapshot {
+            snapshot: self.snapshot.clone(),
+            table: self.table.clone(),
+            region_id: self.region_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kvproto::kvrpcpb::Context;
+
+    use storage::make_key;
+    use storage::engine::{self, TEMP_DIR};
+    use storage::{CF_DEFAULT, CF_WRITE};
+    use super::*;
+
+    fn lock(lock_type: LockType, ts: u64) -> Value {
+        Lock::new(lock_type, b"pk".to_vec(), ts, 0, None).to_bytes()
+    }
+
+    fn put_lock(key: &[u8], lock: Value) -> Modify {
+        Modify::Put(CF_LOCK, make_key(key), lock)
+    }
+
+    fn new_region(id: u64, start_key: &[u8], end_key: &[u8]) -> Region {
+        let mut region = Region::new();
+        region.set_id(id);
+        region.set_start_key(make_key(start_key).encoded().to_owned());
+        region.set_end_key(make_key(end_key).encoded().to_owned());
+        region
+    }
+
+    #[test]
+    fn test_apply_modifies() {
+        let table = PessimisticLockTable::new();
+        let pessimistic = lock(LockType::Pessimistic, 10);
+        let prewrite = lock(LockType::Put, 10);
+
+        // The locks are written if the region isn't the leader.
+        let modifies = vec![put_lock(b"k1", pessimistic.clone())];
+        assert_eq!(table.apply_modifies(1, None, modifies).len(), 1);
+        let region = new_region(1, b"", b"");
+        table.on_role_change(&mut ObserverContext::new(&region), StateRole::Leader);
+        let generation = table.generation(1);
+        assert!(generation.is_some());
+        let modifies = vec![put_lock(b"k1", pessimistic.clone())];
+        assert_eq!(table.apply_modifies(1, None, modifies).len(), 1);
+        assert_eq!(table.get(1, &make_key(b"k1")), None);
+
+        // Only the pessimistic locks are kept in memory.
+        let modifies = vec![
+            put_lock(b"k1", pessimistic.clone()),
+            put_lock(b"k2", prewrite.clone()),
+            Modify::Put(CF_DEFAULT, make_key(b"k2"), b"v".to_vec()),
+        ];
+        assert_eq!(table.apply_modifies(1, generation, modifies).len(), 2);
+        assert_eq!(table.get(1, &make_key(b"k1")), Some(pessimistic.clone()));
+        assert_eq!(table.get(1, &make_key(b"k2")), None);
+        assert_eq!(table.get(2, &make_key(b"k1")), None);
+
+        // The lock is removed once it's overwritten or deleted.
+        let modifies = vec![put_lock(b"k1", prewrite.clone())];
+        assert_eq!(table.apply_modifies(1, generation, modifies).len(), 1);
+        assert_eq!(table.get(1, &make_key(b"k1")), None);
+        let modifies = vec![put_lock(b"k1", pessimistic.clone())];
+        assert!(table.apply_modifies(1, generation, modifies).is_empty());
+        let modifies = vec![
+            Modify::Delete(CF_WRITE, make_key(b"k1")),
+            Modify::Delete(CF_LOCK, make_key(b"k1")),
+        ];
+        assert_eq!(table.apply_modifies(1, generation, modifies).len(), 2);
+        assert_eq!(table.get(1, &make_key(b"k1")), None);
+        let modifies = vec![put_lock(b"k1", pessimistic.clone())];
+        assert!(table.apply_modifies(1, generation, modifies).is_empty());
+        let modifies = vec![
+            Modify::DeleteRange(CF_LOCK, make_key(b"k0"), make_key(b"k2")),
+        ];
+        assert_eq!(table.apply_modifies(1, generation, modifies).len(), 1);
+        assert_eq!(table.get(1, &make_key(b"k1")), None);
+
+        // The locks are written if the region has become the leader again.
+        table.on_role_change(&mut ObserverContext::new(&region), StateRole::Follower);
+        assert_eq!(table.generation(1), None);
+        table.on_role_change(&mut ObserverContext::new(&region), StateRole::Leader);
+        assert!(table.generation(1).is_some());
+        assert_ne!(table.generation(1), generation);
+        let modifies = vec![put_lock(b"k1", pessimistic.clone())];
+        assert_eq!(table.apply_modifies(1, generation, modifies).len(), 1);
+
+        // The locks are written once the table is full.
+        let generation = table.generation(1);
+        for i in 0..MAX_LOCKS_PER_REGION {
+            let key = format!("k{}", i);
+            let modifies = vec![put_lock(key.as_bytes(), pessimistic.clone())];
+            assert!(table.apply_modifies(1, generation, modifies).is_empty());
+        }
+        let modifies = vec![put_lock(b"k", pessimistic.clone())];
+        assert_eq!(table.apply_modifies(1, generation, modifies).len(), 1);
+        let modifies = vec![put_lock(b"k0", pessimistic.clone())];
+        assert!(table.apply_modifies(1, generation, modifies).is_empty());
+    }
+
+    #[test]
+    fn test_scan_and_merge_locks() {
+        let table = PessimisticLockTable::new();
+        for region in vec![new_region(1, b"", b"k3"), new_region(2, b"k3", b"")] {
+            table.on_role_change(&mut ObserverContext::new(&region), StateRole::Leader);
+        }
+        let (g1, g2) = (table.generation(1), table.generation(2));
+        let modifies = vec![
+            put_lock(b"k1", lock(LockType::Pessimistic, 10)),
+            put_lock(b"k2", lock(LockType::Pessimistic, 20)),
+        ];
+        assert!(table.apply_modifies(1, g1, modifies).is_empty());
+        let modifies = vec![put_lock(b"k4", lock(LockType::Pessimistic, 10))];
+        assert!(table.apply_modifies(2, g2, modifies).is_empty());
+
+        let check = |region_id, start_key: &[u8], end_key: &[u8], max_ts, expect: Vec<&[u8]>| {
+            let start_key = make_key(start_key).encoded().to_owned();
+            let end_key = if end_key.is_empty() {
+                vec![]
+            } else {
+                make_key(end_key).encoded().to_owned()
+            };
+            let locks = table
+                .scan_locks(region_id, &start_key, &end_key, |l| l.ts <= max_ts)
+                .unwrap();
+            let keys: Vec<_> = locks.into_iter().map(|(k, _)| k).collect();
+            let expect: Vec<_> = expect.into_iter().map(make_key).collect();
+            assert_eq!(keys, expect);
+        };
+        check(None, b"", b"", 20, vec![&b"k1"[..], b"k2", b"k4"]);
+        check(None, b"", b"", 10, vec![&b"k1"[..], b"k4"]);
+        check(Some(1), b"", b"", 20, vec![&b"k1"[..], b"k2"]);
+        check(Some(1), b"k2", b"", 20, vec![&b"k2"[..]]);
+        check(None, b"k1", b"k4", 20, vec![&b"k1"[..], b"k2"]);
+        check(Some(3), b"", b"", 20, vec![]);
+
+        // The locks in memory take the place of the scanned ones.
+        let scanned = vec![
+            (make_key(b"k0"), Lock::new(LockType::Put, b"pk".to_vec(), 5, 0, None)),
+            (make_key(b"k2"), Lock::new(LockType::Pessimistic, b"pk".to_vec(), 5, 0, None)),
+            (make_key(b"k5"), Lock::new(LockType::Put, b"pk".to_vec(), 5, 0, None)),
+        ];
+        let in_memory = table.scan_locks(None, b"", b"", |_| true).unwrap();
+        let locks = merge_locks(scanned, in_memory);
+        let locks: Vec<_> = locks.into_iter().map(|(k, l)| (k, l.ts)).collect();
+        let expect = vec![
+            (make_key(b"k0"), 5),
+            (make_key(b"k1"), 10),
+            (make_key(b"k2"), 20),
+            (make_key(b"k4"), 10),
+            (make_key(b"k5"), 5),
+        ];
+        assert_eq!(locks, expect);
+    }
+
+    #[test]
+    fn test_split_and_transfer_leader() {
+        let table = PessimisticLockTable::new();
+        let pessimistic = lock(LockType::Pessimistic, 10);
+        let region = new_region(1, b"", b"");
+        table.on_role_change(&mut ObserverContext::new(&region), StateRole::Leader);
+        let generation = table.generation(1);
+        let modifies = vec![
+            put_lock(b"k1", pessimistic.clone()),
+            put_lock(b"k3", pessimistic.clone()),
+        ];
+        assert!(table.apply_modifies(1, generation, modifies).is_empty());
+
+        // The locks out of the region are dropped after a split.
+        let mut split = AdminRequest::new();
+        split.set_cmd_type(AdminCmdType::Split);
+        let region = new_region(1, b"", b"k2");
+        table.post_apply_admin(&mut ObserverContext::new(&region), &split);
+        assert!(table.get(1, &make_key(b"k1")).is_some());
+        assert!(table.get(1, &make_key(b"k3")).is_none());
+
+        // The locks are proposed before the transfer and still kept.
+        let requests = table.pre_transfer_leader(&mut ObserverContext::new(&region));
+        assert_eq!(requests.len(), 1);
+        let put = requests[0].get_put();
+        assert_eq!(requests[0].get_cmd_type(), CmdType::Put);
+        assert_eq!(put.get_cf(), CF_LOCK);
+        assert_eq!(put.get_key(), make_key(b"k1").encoded().as_slice());
+        assert_eq!(put.get_value(), pessimistic.as_slice());
+        assert!(table.get(1, &make_key(b"k1")).is_some());
+        let region = new_region(2, b"k2", b"");
+        assert!(
+            table
+                .pre_transfer_leader(&mut ObserverContext::new(&region))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_pessimistic_lock_snapshot() {
+        let engine = engine::new_local_engine(TEMP_DIR, &[CF_DEFAULT, CF_LOCK]).unwrap();
+        let prewrite = lock(LockType::Put, 10);
+        let modifies = vec![put_lock(b"k2", prewrite.clone())];
+        engine.write(&Context::new(), modifies).unwrap();
+
+        let table = PessimisticLockTable::new();
+        let snapshot = engine.snapshot(&Context::new()).unwrap();
+        let snapshot = table.wrap_snapshot(1, snapshot);
+        assert_eq!(snapshot.get_cf(CF_LOCK, &make_key(b"k2")).unwrap(), Some(prewrite.clone()));
+
+        let pessimistic = lock(LockType::Pessimistic, 10);
+        let region = new_region(1, b"", b"");
+        table.on_role_change(&mut ObserverContext::new(&region), StateRole::Leader);
+        let generation = table.generation(1);
+        let modifies = vec![put_lock(b"k1", pessimistic.clone())];
+        assert!(table.apply_modifies(1, generation, modifies).is_empty());
+        let snapshot = engine.snapshot(&Context::new()).unwrap();
+        let snapshot = table.wrap_snapshot(1, snapshot).clone();
+        assert_eq!(snapshot.get_cf(CF_LOCK, &make_key(b"k1")).unwrap(), Some(pessimistic));
+        assert_eq!(snapshot.get_cf(CF_LOCK, &make_key(b"k2")).unwrap(), Some(prewrite));
+        assert_eq!(snapshot.get_cf(CF_DEFAULT, &make_key(b"k1")).unwrap(), None);
+    }
+}
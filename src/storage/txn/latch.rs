@@ -23,8 +23,10 @@ use std::usize;
 /// Latches are indexed by slot IDs. The keys of a command are hashed to slot IDs, then the command
 /// is added to the waiting queues of the latches.
 ///
-/// If command A is ahead of command B in one latch, it must be ahead of command B in all the
-/// overlapping latches. This is an invariant ensured by the `gen_lock`, `acquire` and `release`.
+/// A command is added to the waiting queues of all its latches when it arrives, so if command A is
+/// ahead of command B in one latch, it must be ahead of command B in all the overlapping latches,
+/// and the commands are woken up strictly in the order they arrive. This is an invariant ensured
+/// by the `gen_lock`, `acquire` and `release`.
 #[derive(Clone)]
 struct Latch {
     // store waiting commands
@@ -48,6 +50,9 @@ pub struct Lock {
 
     /// The number of latches that the command has acquired.
     pub owned_count: usize,
+
+    /// Whether the command has been added to the waiting queues of all the required latches.
+    pub enqueued: bool,
 }
 
 impl Lock {
@@ -56,6 +61,7 @@ impl Lock {
         Lock {
             required_slots: required_slots,
             owned_count: 0,
+            enqueued: false,
         }
     }
 
@@ -104,45 +110,54 @@ impl Latches {
 
     /// Tries to acquire the latches specified by the `lock` for command with ID `who`.
     ///
-    /// This method will enqueue the command ID into the waiting queues of the latches. A latch is
+    /// The first time it's called, this method will enqueue the command ID into the waiting queues
+    /// of all the latches, so the later commands can't get ahead of it in any latch. A latch is
     /// considered acquired if the command ID is at the front of the queue. Returns true if all the
     /// Latches are acquired, false otherwise.
     pub fn acquire(&mut self, lock: &mut Lock, who: u64) -> bool {
+        if !lock.enqueued {
+            for i in &lock.required_slots {
+                self.slots[*i].waiting.push_back(who);
+            }
+            lock.enqueued = true;
+        }
+
         let mut acquired_count: usize = 0;
         for i in &lock.required_slots[lock.owned_count..] {
-            let latch = &mut self.slots[*i];
-
-            let front = latch.waiting.front().cloned();
-            match front {
-                Some(cid) => if cid == who {
-                    acquired_count += 1;
-                } else {
-                    latch.waiting.push_back(who);
-                    break;
-                },
-                None => {
-                    latch.waiting.push_back(who);
-                    acquired_count += 1;
-                }
+            if self.slots[*i].waiting.front() != Some(&who) {
+                break;
             }
+            acquired_count += 1;
         }
 
         lock.owned_count += acquired_count;
         lock.acquired()
     }
 
-    /// Releases all latches owned by the `lock` of command with ID `who`, returns the wakeup list.
+    /// Releases all latches owned by the `lock` of command with ID `who`, and removes it from the
+    /// waiting queues of the others. Returns the wakeup list, which are the commands at the front
+    /// of the released latches, each of them appears once.
     ///
-    /// Preconditions: the caller must ensure the command is at the front of the latches.
+    /// Preconditions: the caller must ensure the command is at the front of the owned latches.
     pub fn release(&mut self, lock: &Lock, who: u64) -> Vec<u64> {
         let mut wakeup_list: Vec<u64> = vec![];
-        for i in &lock.required_slots[..lock.owned_count] {
+        if !lock.enqueued {
+            return wakeup_list;
+        }
+        for (idx, i) in lock.required_slots.iter().enumerate() {
             let latch = &mut self.slots[*i];
-            let front = latch.waiting.pop_front().unwrap();
-            assert_eq!(front, who);
-
+            let pos = latch.waiting.iter().position(|cid| *cid == who).unwrap();
+            if idx < lock.owned_count {
+                assert_eq!(pos, 0);
+            }
+            latch.waiting.remove(pos);
+            if pos != 0 {
+                continue;
+            }
             if let Some(wakeup) = latch.waiting.front() {
-                wakeup_list.push(*wakeup);
+                if !wakeup_list.contains(wakeup) {
+                    wakeup_list.push(*wakeup);
+                }
             }
         }
         wakeup_list
@@ -234,4 +249,49 @@ mod tests {
         assert_eq!(acquired_c, true);
 
     }
+
+    #[test]
+    fn test_wakeup_in_arrival_order() {
+        let mut latches = Latches::new(256);
+
+        let mut lock_a = Lock::new(vec![1]);
+        let mut lock_b = Lock::new(vec![1, 2]);
+        let mut lock_c = Lock::new(vec![2]);
+        let mut lock_d = Lock::new(vec![2, 3]);
+        let (cid_a, cid_b, cid_c, cid_d) = (1, 2, 3, 4);
+
+        assert!(latches.acquire(&mut lock_a, cid_a));
+        // b waits for a on slot 1.
+        assert!(!latches.acquire(&mut lock_b, cid_b));
+        // c and d arrive later than b, they can't get slot 2 ahead of b.
+        assert!(!latches.acquire(&mut lock_c, cid_c));
+        assert!(!latches.acquire(&mut lock_d, cid_d));
+
+        assert_eq!(latches.release(&lock_a, cid_a), vec![cid_b]);
+        assert!(latches.acquire(&mut lock_b, cid_b));
+        assert_eq!(latches.release(&lock_b, cid_b), vec![cid_c]);
+        assert!(latches.acquire(&mut lock_c, cid_c));
+        // d is still behind c.
+        assert!(!latches.acquire(&mut lock_d, cid_d));
+        assert_eq!(latches.release(&lock_c, cid_c), vec![cid_d]);
+        assert!(latches.acquire(&mut lock_d, cid_d));
+        assert!(latches.release(&lock_d, cid_d).is_empty());
+    }
+
+    #[test]
+    fn test_release_waiting_lock() {
+        let mut latches = Latches::new(256);
+
+        let mut lock_a = Lock::new(vec![1]);
+        let mut lock_b = Lock::new(vec![1, 2]);
+        let mut lock_c = Lock::new(vec![2]);
+        assert!(latches.acquire(&mut lock_a, 1));
+        assert!(!latches.acquire(&mut lock_b, 2));
+        assert!(!latches.acquire(&mut lock_c, 3));
+
+        // b gives up waiting, c is at the front of slot 2 then.
+        assert_eq!(latches.release(&lock_b, 2), vec![3]);
+        assert!(latches.acquire(&mut lock_c, 3));
+        assert!(latches.release(&lock_a, 1).is_empty());
+    }
 }
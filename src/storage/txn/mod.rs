@@ -14,11 +14,16 @@
 mod store;
 mod scheduler;
 mod latch;
+mod waiter_manager;
+mod write_limiter;
 
 use std::error;
 use std::io::Error as IoError;
 
-pub use self::scheduler::{Msg, Scheduler, GC_BATCH_SIZE, RESOLVE_LOCK_BATCH_SIZE};
+pub use self::scheduler::{Msg, Scheduler, SlowLogThresholds, GC_BATCH_SIZE,
+                          RESOLVE_LOCK_BATCH_SIZE};
+pub use self::waiter_manager::{key_hash, DeadlockCallback, DeadlockDetector, WaitPolicy};
+pub use self::write_limiter::WriteLimiter;
 pub use self::store::{SnapshotStore, StoreScanner};
 
 quick_error! {
@@ -31,33 +31,43 @@
 //! is ensured by the transaction protocol implemented in the client library, which is transparent
 //! to the scheduler.
 
+use std::cmp;
 use std::fmt::{self, Debug, Formatter};
-use std::sync::mpsc::Receiver;
-use std::time::Duration;
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
 use std::thread;
 use std::hash::{Hash, Hasher};
 use std::u64;
 
 use prometheus::HistogramTimer;
-use kvproto::kvrpcpb::{CommandPri, Context, LockInfo};
+use kvproto::kvrpcpb::{CommandPri, Context, IsolationLevel, LockInfo};
 
-use storage::{Command, Engine, Error as StorageError, Result as StorageResult, ScanMode, Snapshot,
-              Statistics, StatisticsSummary, StorageCb};
-use storage::mvcc::{Error as MvccError, Lock as MvccLock, MvccReader, MvccTxn, Write, WriteType,
+use storage::{Command, Config, Engine, Error as StorageError, Result as StorageResult, ScanMode,
+              Snapshot, Statistics, StatisticsSummary, StorageCb};
+use storage::mvcc::{Error as MvccError, Lock as MvccLock, LockType, MvccReader, MvccTxn,
+                    Result as MvccResult, SecondaryLockStatus, TxnStatus, Write, WriteType,
                     MAX_TXN_WRITE_SIZE};
-use storage::{Key, KvPair, MvccInfo, Value, CMD_TAG_GC};
+use storage::{Key, KvPair, MaxTsTracker, MvccInfo, PessimisticLockResult, PessimisticLockTable,
+              PrewriteResult, SecondaryLocksStatus, Value, CMD_TAG_GC};
+use storage::pessimistic_locks::merge_locks;
 use storage::engine::{self, Callback as EngineCallback, CbContext, Error as EngineError, Modify,
                       Result as EngineResult};
 use raftstore::store::engine::IterOption;
 use util::transport::{Error as TransportError, SyncSendCh};
 use util::threadpool::{Context as ThreadContext, ThreadPool, ThreadPoolBuilder};
-use util::time::SlowTimer;
+use util::time::{duration_to_sec, thread_cpu_time, SlowTimer};
 use util::collections::HashMap;
+use util::memory;
+use util::rocksdb::engine_metrics;
+use util::tracker::{TimeDetail, Tracker};
 
 use super::Result;
 use super::Error;
 use super::store::SnapshotStore;
 use super::latch::{Latches, Lock};
+use super::waiter_manager::{self, DeadlockDetector, WaitPolicy, WaiterManager};
+use super::write_limiter::WriteLimiter;
 use super::super::metrics::*;
 
 // TODO: make it configurable.
@@ -65,6 +75,14 @@ pub const GC_BATCH_SIZE: usize = 512;
 
 pub const RESOLVE_LOCK_BATCH_SIZE: usize = 512;
 
+// The backoffs suggested to the clients when the writes are rejected as busy.
+const SCHED_BUSY_BACKOFF_MS: u64 = 100;
+// Stalls and memory pressure last longer, it takes time to flush or compact.
+const ENGINE_BUSY_BACKOFF_MS: u64 = 1000;
+
+// How often the lock waiters are checked for wake-up and timeout.
+const LOCK_WAIT_POLL_INTERVAL_MS: u64 = 10;
+
 /// Process result of a command.
 pub enum ProcessResult {
     Res,
@@ -74,6 +92,12 @@ pub enum ProcessResult {
     MvccStartTs { mvcc: Option<(Key, MvccInfo)> },
     Value { value: Option<Value> },
     Locks { locks: Vec<LockInfo> },
+    TxnStatus { status: TxnStatus },
+    Ttl { ttl: u64 },
+    SecondaryLocks { status: SecondaryLocksStatus },
+    AsyncCommitPrewritten { min_commit_ts: u64 },
+    OnePcCommitted { commit_ts: u64 },
+    PessimisticLocked { values: Vec<Option<Value>> },
     NextCommand { cmd: Command },
     Failed { err: StorageError },
 }
@@ -83,7 +107,11 @@ type SnapshotResult = (Vec<u64>, CbContext, EngineResult<Box<Snapshot>>);
 /// Message types for the scheduler event loop.
 pub enum Msg {
     Quit,
-    RawCmd { cmd: Command, cb: StorageCb },
+    RawCmd {
+        cmd: Command,
+        cb: StorageCb,
+        tracker: Arc<Tracker>,
+    },
     RetryGetSnapshots(Vec<(Context, Vec<u64>)>),
     SnapshotFinished {
         cids: Vec<u64>,
@@ -105,6 +133,15 @@ pub enum Msg {
         cb_ctx: CbContext,
         result: EngineResult<()>,
     },
+    ResizeWorkerPool(usize),
+    // The pessimistic lock of `start_ts` waiting for the lock of `lock_ts` on
+    // `key` causes a deadlock.
+    Deadlock {
+        key: Vec<u8>,
+        start_ts: u64,
+        lock_ts: u64,
+        deadlock_key_hash: u64,
+    },
 }
 
 /// Debug for messages.
@@ -129,6 +166,10 @@ impl Debug for Msg {
                 write!(f, "WritePrepareFailed [cid={}, err={:?}]", cid, err)
             }
             Msg::WriteFinished { cid, .. } => write!(f, "WriteFinished [cid={}]", cid),
+            Msg::ResizeWorkerPool(size) => write!(f, "ResizeWorkerPool [size={}]", size),
+            Msg::Deadlock {
+                start_ts, lock_ts, ..
+            } => write!(f, "Deadlock [start_ts={}, lock_ts={}]", start_ts, lock_ts),
         }
     }
 }
@@ -143,6 +184,9 @@ fn execute_callback(callback: StorageCb, pr: ProcessResult) {
         },
         StorageCb::Booleans(cb) => match pr {
             ProcessResult::MultiRes { results } => cb(Ok(results)),
+            ProcessResult::AsyncCommitPrewritten { .. } |
+            ProcessResult::OnePcCommitted { .. } |
+            ProcessResult::PessimisticLocked { .. } => cb(Ok(vec![])),
             ProcessResult::Failed { err } => cb(Err(err)),
             _ => panic!("process result mismatch"),
         },
@@ -171,6 +215,82 @@ fn execute_callback(callback: StorageCb, pr: ProcessResult) {
             ProcessResult::Failed { err } => cb(Err(err)),
             _ => panic!("process result mismatch"),
         },
+        StorageCb::TxnStatus(cb) => match pr {
+            ProcessResult::TxnStatus { status } => cb(Ok(status)),
+            ProcessResult::Failed { err } => cb(Err(err)),
+            _ => panic!("process result mismatch"),
+        },
+        StorageCb::Ttl(cb) => match pr {
+            ProcessResult::Ttl { ttl } => cb(Ok(ttl)),
+            ProcessResult::Failed { err } => cb(Err(err)),
+            _ => panic!("process result mismatch"),
+        },
+        StorageCb::SecondaryLocks(cb) => match pr {
+            ProcessResult::SecondaryLocks { status } => cb(Ok(status)),
+            ProcessResult::Failed { err } => cb(Err(err)),
+            _ => panic!("process result mismatch"),
+        },
+        StorageCb::Prewrite(cb) => match pr {
+            ProcessResult::MultiRes { results } => cb(Ok(PrewriteResult {
+                locks: results,
+                min_commit_ts: 0,
+                one_pc_commit_ts: 0,
+            })),
+            ProcessResult::AsyncCommitPrewritten { min_commit_ts } => cb(Ok(PrewriteResult {
+                locks: vec![],
+                min_commit_ts: min_commit_ts,
+                one_pc_commit_ts: 0,
+            })),
+            ProcessResult::OnePcCommitted { commit_ts } => cb(Ok(PrewriteResult {
+                locks: vec![],
+                min_commit_ts: 0,
+                one_pc_commit_ts: commit_ts,
+            })),
+            ProcessResult::Failed { err } => cb(Err(err)),
+            _ => panic!("process result mismatch"),
+        },
+        StorageCb::PessimisticLock(cb) => match pr {
+            ProcessResult::MultiRes { results } => cb(Ok(PessimisticLockResult {
+                locks: results,
+                values: vec![],
+            })),
+            ProcessResult::PessimisticLocked { values } => cb(Ok(PessimisticLockResult {
+                locks: vec![],
+                values: values,
+            })),
+            ProcessResult::Failed { err } => cb(Err(err)),
+            _ => panic!("process result mismatch"),
+        },
+    }
+}
+
+/// The thresholds beyond which commands are logged as slow, by the kinds of
+/// the commands.
+#[derive(Clone, Copy, Debug)]
+pub struct SlowLogThresholds {
+    pub point_read: Duration,
+    pub scan: Duration,
+    pub write: Duration,
+}
+
+impl SlowLogThresholds {
+    pub fn new(cfg: &Config) -> SlowLogThresholds {
+        SlowLogThresholds {
+            point_read: cfg.slow_point_read_threshold.0,
+            scan: cfg.slow_scan_threshold.0,
+            write: cfg.slow_write_threshold.0,
+        }
+    }
+
+    fn get(&self, cmd: &Command) -> (&'static str, Duration) {
+        match *cmd {
+            Command::Get { .. } |
+            Command::BatchGet { .. } |
+            Command::RawGet { .. } |
+            Command::RawBatchGet { .. } => ("point_read", self.point_read),
+            _ if cmd.readonly() => ("scan", self.scan),
+            _ => ("write", self.write),
+        }
     }
 }
 
@@ -181,55 +301,221 @@ pub struct RunningCtx {
     lock: Lock,
     callback: Option<StorageCb>,
     tag: &'static str,
+    // The kind of the command in the slow log.
+    kind: &'static str,
     ts: u64,
     region_id: u64,
+    // The generation of the pessimistic lock table of the region when the
+    // command is scheduled, the locks it writes are kept in memory only if the
+    // region is still the same leader.
+    locks_generation: Option<u64>,
     latch_timer: Option<HistogramTimer>,
+    // Observes the duration of the current stage when the command enters the
+    // next one or finishes.
+    stage_timer: HistogramTimer,
+    stage: &'static str,
+    stage_start: Instant,
+    // Shared with the other threads handling the request, the durations of
+    // the stages are accumulated in it.
+    tracker: Arc<Tracker>,
+    // The approximate bytes the command writes, counted as pending until
+    // the command is finished.
+    write_bytes: usize,
+    // The raw keys whose locks are released by the command, the waiters of
+    // them are woken up when the command is finished.
+    released_keys: Vec<Vec<u8>>,
     _timer: HistogramTimer,
     slow_timer: SlowTimer,
 }
 
 impl RunningCtx {
     /// Creates a context for a running command.
-    pub fn new(cid: u64, cmd: Command, lock: Lock, cb: StorageCb) -> RunningCtx {
+    pub fn new(
+        cid: u64,
+        cmd: Command,
+        lock: Lock,
+        cb: StorageCb,
+        tracker: Arc<Tracker>,
+        slow_log_thresholds: &SlowLogThresholds,
+    ) -> RunningCtx {
         let tag = cmd.tag();
+        let (kind, slow_time) = slow_log_thresholds.get(&cmd);
         let ts = cmd.ts();
         let region_id = cmd.get_context().get_region_id();
+        let write_bytes = cmd.write_bytes();
+        tracker.record_event("latch_wait");
         RunningCtx {
             cid: cid,
             cmd: Some(cmd),
             lock: lock,
             callback: Some(cb),
             tag: tag,
+            kind: kind,
             ts: ts,
             region_id: region_id,
+            locks_generation: None,
             latch_timer: Some(
                 SCHED_LATCH_HISTOGRAM_VEC
                     .with_label_values(&[tag])
                     .start_coarse_timer(),
             ),
+            stage_timer: SCHED_STAGE_HISTOGRAM_VEC
+                .with_label_values(&[tag, "latch_wait"])
+                .start_coarse_timer(),
+            stage: "latch_wait",
+            stage_start: Instant::now(),
+            tracker: tracker,
+            write_bytes: write_bytes,
+            released_keys: vec![],
             _timer: SCHED_HISTOGRAM_VEC
                 .with_label_values(&[tag])
                 .start_coarse_timer(),
-            slow_timer: SlowTimer::new(),
+            slow_timer: SlowTimer::from(slow_time),
         }
     }
+
+    /// Ends the current stage of the command and starts timing `stage`, which
+    /// is one of:
+    ///
+    /// - `latch_wait`: waiting for the latches of the keys.
+    /// - `snapshot`: getting a snapshot from the engine.
+    /// - `process`: waiting for a worker and processing on it.
+    /// - `write`: proposing the writes through raft and applying them.
+    fn enter_stage(&mut self, stage: &'static str) {
+        self.finish_stage();
+        self.tracker.record_event(stage);
+        self.stage = stage;
+        self.stage_timer = SCHED_STAGE_HISTOGRAM_VEC
+            .with_label_values(&[self.tag, stage])
+            .start_coarse_timer();
+    }
+
+    fn finish_stage(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.stage_start);
+        self.stage_start = now;
+        let stage = self.stage;
+        self.tracker.update_time_detail(|d| match stage {
+            "latch_wait" | "snapshot" => d.wait += elapsed,
+            "process" => d.process += elapsed,
+            _ => d.commit += elapsed,
+        });
+    }
+
+    /// Returns the durations of the stages when the command finishes.
+    fn finish(&mut self) -> TimeDetail {
+        self.finish_stage();
+        self.tracker.record_event("finish");
+        self.tracker.time_detail().unwrap_or_default()
+    }
 }
 
 impl Drop for RunningCtx {
     fn drop(&mut self) {
         slow_log!(
             self.slow_timer,
-            "[region {}] scheduler handle command: {}, ts: {}",
+            "[region {}] slow command, kind: {}, type: {}, cid: {}, ts: {}, {}",
             self.region_id,
+            self.kind,
             self.tag,
-            self.ts
+            self.cid,
+            self.ts,
+            self.tracker
         );
     }
 }
 
+/// Returns the size of the keys and values read by a command.
+fn read_bytes(pr: &ProcessResult) -> u64 {
+    match *pr {
+        ProcessResult::Value { value: Some(ref v) } => v.len() as u64,
+        ProcessResult::MultiKvpairs { ref pairs } => pairs
+            .iter()
+            .map(|p| match *p {
+                Ok((ref k, ref v)) => (k.len() + v.len()) as u64,
+                Err(_) => 0,
+            })
+            .sum(),
+        _ => 0,
+    }
+}
+
+/// Returns the size of the modifications proposed by a command.
+fn write_bytes(modifies: &[Modify]) -> u64 {
+    modifies
+        .iter()
+        .map(|m| match *m {
+            Modify::Delete(_, ref k) => k.encoded().len() as u64,
+            Modify::Put(_, ref k, ref v) => (k.encoded().len() + v.len()) as u64,
+            Modify::DeleteRange(_, ref start, ref end) => {
+                (start.encoded().len() + end.encoded().len()) as u64
+            }
+        })
+        .sum()
+}
+
+/// Returns the first key which is locked by the other transactions in the
+/// results of a prewrite or a pessimistic lock, and the ts of the lock.
+fn locked_key(pr: &ProcessResult) -> Option<(Vec<u8>, u64)> {
+    if let ProcessResult::MultiRes { ref results } = *pr {
+        for r in results {
+            if let Err(StorageError::Txn(Error::Mvcc(MvccError::KeyIsLocked {
+                ref key, ts, ..
+            }))) = *r
+            {
+                return Some((key.clone(), ts));
+            }
+        }
+    }
+    None
+}
+
+/// Returns the raw keys whose locks are released by a command.
+fn released_keys(cmd: &Command) -> Vec<Vec<u8>> {
+    let keys: Vec<&Key> = match *cmd {
+        Command::Commit { ref keys, .. } |
+        Command::Rollback { ref keys, .. } |
+        Command::PessimisticRollback { ref keys, .. } |
+        Command::CheckSecondaryLocks { ref keys, .. } |
+        Command::ResolveLock { ref keys, .. } |
+        Command::ResolveLockLite {
+            resolve_keys: ref keys,
+            ..
+        } => keys.iter().collect(),
+        Command::Cleanup { ref key, .. } |
+        Command::CheckTxnStatus {
+            primary_key: ref key,
+            ..
+        } => vec![key],
+        // The pessimistic locks are released by a one-phase commit.
+        Command::Prewrite {
+            ref mutations,
+            ref options,
+            ..
+        } if options.try_one_pc =>
+        {
+            mutations.iter().map(|m| m.key()).collect()
+        }
+        _ => vec![],
+    };
+    keys.into_iter().filter_map(|k| k.raw().ok()).collect()
+}
+
+/// A prewrite or a pessimistic lock waiting for the lock on a key. It's scheduled
+/// again when the lock is released, or finished with the locked result on timeout.
+struct LockWaiter {
+    cmd: Command,
+    callback: StorageCb,
+    tracker: Arc<Tracker>,
+    pr: ProcessResult,
+    // (lock_ts, key_hash) if the wait is registered to the deadlock detector.
+    wait_for: Option<(u64, u64)>,
+}
+
 /// Creates a callback to receive async results of write prepare from the storage engine.
 fn make_engine_cb(cid: u64, pr: ProcessResult, ch: SyncSendCh<Msg>) -> EngineCallback<()> {
     Box::new(move |(cb_ctx, result)| {
+        fail_point!("scheduler_async_write_finish");
         match ch.send(Msg::WriteFinished {
             cid: cid,
             pr: pr,
@@ -295,6 +581,9 @@ pub struct Scheduler {
     latches: Latches,
 
     sched_too_busy_threshold: usize,
+    sched_pending_write_threshold: usize,
+
+    slow_log_thresholds: SlowLogThresholds,
 
     // worker pool
     worker_pool: ThreadPool<ScheContext>,
@@ -302,10 +591,30 @@ pub struct Scheduler {
     // high priority commands will be delivered to this pool
     high_priority_pool: ThreadPool<ScheContext>,
 
+    // low priority commands, like gc and resolve lock, will be delivered to
+    // this pool, so they won't queue before the normal ones.
+    low_priority_pool: ThreadPool<ScheContext>,
+
     has_gc_command: bool,
 
     // used to control write flow
     running_write_count: usize,
+    running_write_bytes: usize,
+
+    // prewrites and pessimistic locks waiting for the conflicting locks to be released
+    waiter_mgr: WaiterManager<LockWaiter>,
+
+    // finds the deadlocks caused by the waiting pessimistic locks
+    detector: Option<Arc<DeadlockDetector>>,
+
+    // throttles the foreground writes before they are proposed
+    write_limiter: Arc<WriteLimiter>,
+
+    // the max ts of the reads served by the store, shared with the coprocessor
+    max_ts: MaxTsTracker,
+
+    // keeps the pessimistic locks of the leader regions in memory
+    pessimistic_locks: PessimisticLockTable,
 }
 
 // Make clippy happy.
@@ -345,6 +654,12 @@ fn find_mvcc_infos_by_key(
     Ok((lock, writes, values))
 }
 
+// The low priority pool is smaller, so the low priority commands take a small
+// part of the cpu even if there are a lot of them.
+fn low_priority_pool_size(worker_pool_size: usize) -> usize {
+    cmp::max(1, worker_pool_size / 4)
+}
+
 impl Scheduler {
     /// Creates a scheduler.
     pub fn new(
@@ -353,6 +668,13 @@ impl Scheduler {
         concurrency: usize,
         worker_pool_size: usize,
         sched_too_busy_threshold: usize,
+        sched_pending_write_threshold: usize,
+        slow_log_thresholds: SlowLogThresholds,
+        wait_policy: WaitPolicy,
+        write_limiter: Arc<WriteLimiter>,
+        detector: Option<Arc<DeadlockDetector>>,
+        max_ts: MaxTsTracker,
+        pessimistic_locks: PessimisticLockTable,
     ) -> Scheduler {
         Scheduler {
             engine: engine,
@@ -365,18 +687,67 @@ impl Scheduler {
             id_alloc: 0,
             latches: Latches::new(concurrency),
             sched_too_busy_threshold: sched_too_busy_threshold,
+            sched_pending_write_threshold: sched_pending_write_threshold,
+            slow_log_thresholds: slow_log_thresholds,
             worker_pool: ThreadPoolBuilder::with_default_factory(thd_name!("sched-worker-pool"))
                 .thread_count(worker_pool_size)
                 .build(),
             high_priority_pool: ThreadPoolBuilder::with_default_factory(
                 thd_name!("sched-high-pri-pool"),
             ).build(),
+            low_priority_pool: ThreadPoolBuilder::with_default_factory(
+                thd_name!("sched-low-pri-pool"),
+            ).thread_count(low_priority_pool_size(worker_pool_size))
+                .build(),
             has_gc_command: false,
             running_write_count: 0,
+            running_write_bytes: 0,
+            waiter_mgr: WaiterManager::new(wait_policy),
+            detector: detector,
+            write_limiter: write_limiter,
+            max_ts: max_ts,
+            pessimistic_locks: pessimistic_locks,
         }
     }
 }
 
+/// Records the ts of a read command, it fails if the keys it reads are locked in
+/// memory by the prewrites being written.
+fn read_memory_locks(max_ts: &MaxTsTracker, cmd: &Command) -> MvccResult<()> {
+    let (ctx, start_ts) = match *cmd {
+        Command::Get {
+            ref ctx, start_ts, ..
+        } |
+        Command::BatchGet {
+            ref ctx, start_ts, ..
+        } |
+        Command::Scan {
+            ref ctx, start_ts, ..
+        } => (ctx, start_ts),
+        _ => return Ok(()),
+    };
+    // The locks are ignored by the reads of RC.
+    if ctx.get_isolation_level() == IsolationLevel::RC {
+        max_ts.update(start_ts);
+        return Ok(());
+    }
+    let bypass_locks = ctx.get_resolved_locks();
+    match *cmd {
+        Command::Get { ref key, .. } => max_ts.read_keys(start_ts, &[key.clone()], bypass_locks),
+        Command::BatchGet { ref keys, .. } => max_ts.read_keys(start_ts, keys, bypass_locks),
+        Command::Scan {
+            ref start_key,
+            ref options,
+            ..
+        } => if options.reverse_scan {
+            max_ts.read_range(start_ts, None, Some(start_key), bypass_locks)
+        } else {
+            max_ts.read_range(start_ts, Some(start_key), None, bypass_locks)
+        },
+        _ => unreachable!(),
+    }
+}
+
 /// Processes a read command within a worker thread, then posts `ReadFinished` message back to the
 /// event loop.
 fn process_read(
@@ -384,8 +755,11 @@ fn process_read(
     mut cmd: Command,
     ch: SyncSendCh<Msg>,
     snapshot: Box<Snapshot>,
+    tracker: &Tracker,
+    pessimistic_locks: &PessimisticLockTable,
 ) -> Statistics {
     debug!("process read cmd(cid={}) in worker pool.", cid);
+    let cpu_start = thread_cpu_time();
     SCHED_WORKER_COUNTER_VEC
         .with_label_values(&[cmd.tag(), "read"])
         .inc();
@@ -404,12 +778,13 @@ fn process_read(
             KV_COMMAND_KEYREAD_HISTOGRAM_VEC
                 .with_label_values(&[tag])
                 .observe(1f64);
-            let snap_store = SnapshotStore::new(
+            let mut snap_store = SnapshotStore::new(
                 snapshot.as_ref(),
                 start_ts,
                 ctx.get_isolation_level(),
                 !ctx.get_not_fill_cache(),
             );
+            snap_store.set_bypass_locks(ctx.get_resolved_locks());
             let res = snap_store.get(key, &mut statistics);
             match res {
                 Ok(val) => ProcessResult::Value { value: val },
@@ -428,12 +803,13 @@ fn process_read(
             KV_COMMAND_KEYREAD_HISTOGRAM_VEC
                 .with_label_values(&[tag])
                 .observe(keys.len() as f64);
-            let snap_store = SnapshotStore::new(
+            let mut snap_store = SnapshotStore::new(
                 snapshot.as_ref(),
                 start_ts,
                 ctx.get_isolation_level(),
                 !ctx.get_not_fill_cache(),
             );
+            snap_store.set_bypass_locks(ctx.get_resolved_locks());
             match snap_store.batch_get(keys, &mut statistics) {
                 Ok(results) => {
                     let mut res = vec![];
@@ -460,15 +836,25 @@ fn process_read(
             ref options,
             ..
         } => {
-            let snap_store = SnapshotStore::new(
+            let mut snap_store = SnapshotStore::new(
                 snapshot.as_ref(),
                 start_ts,
                 ctx.get_isolation_level(),
                 !ctx.get_not_fill_cache(),
             );
+            snap_store.set_bypass_locks(ctx.get_resolved_locks());
+            let mode = if options.reverse_scan {
+                ScanMode::Backward
+            } else {
+                ScanMode::Forward
+            };
             let res = snap_store
-                .scanner(ScanMode::Forward, options.key_only, None, &mut statistics)
-                .and_then(|mut scanner| scanner.scan(start_key.clone(), limit))
+                .scanner(mode, options.key_only, None, &mut statistics)
+                .and_then(|mut scanner| if options.reverse_scan {
+                    scanner.reverse_scan(start_key.clone(), limit)
+                } else {
+                    scanner.scan(start_key.clone(), limit)
+                })
                 .and_then(|mut results| {
                     KV_COMMAND_KEYREAD_HISTOGRAM_VEC
                         .with_label_values(&[tag])
@@ -549,8 +935,17 @@ fn process_read(
             );
             let res = reader
                 .scan_lock(None, |lock| lock.ts <= max_ts, None)
-                .map_err(Error::from)
                 .and_then(|(v, _)| {
+                    let in_memory = try!(pessimistic_locks.scan_locks(
+                        Some(ctx.get_region_id()),
+                        b"",
+                        b"",
+                        |lock| lock.ts <= max_ts,
+                    ));
+                    Ok(merge_locks(v, in_memory))
+                })
+                .map_err(Error::from)
+                .and_then(|v| {
                     let mut locks = vec![];
                     for (key, lock) in v {
                         let mut lock_info = LockInfo::new();
@@ -586,12 +981,24 @@ fn process_read(
                 None,
                 ctx.get_isolation_level(),
             );
+            let start_key = scan_key.take();
             let res = reader
                 .scan_lock(
-                    scan_key.take(),
+                    start_key.clone(),
                     |lock| lock.ts == start_ts,
                     Some(RESOLVE_LOCK_BATCH_SIZE),
                 )
+                .and_then(|(v, next_scan_key)| {
+                    // The locks kept in memory before the next batch are
+                    // resolved with the scanned ones.
+                    let in_memory = try!(pessimistic_locks.scan_locks(
+                        Some(ctx.get_region_id()),
+                        start_key.as_ref().map_or(&[][..], |k| k.encoded().as_slice()),
+                        next_scan_key.as_ref().map_or(&[][..], |k| k.encoded().as_slice()),
+                        |lock| lock.ts == start_ts,
+                    ));
+                    Ok((merge_locks(v, in_memory), next_scan_key))
+                })
                 .map_err(Error::from)
                 .and_then(|(v, next_scan_key)| {
                     let keys: Vec<Key> = v.into_iter().map(|x| x.0).collect();
@@ -685,11 +1092,26 @@ fn process_read(
                 },
             }
         }
+        Command::RawBatchGet { ref keys, .. } => {
+            KV_COMMAND_KEYREAD_HISTOGRAM_VEC
+                .with_label_values(&[tag])
+                .observe(keys.len() as f64);
+            let mut pairs = vec![];
+            for k in keys {
+                match snapshot.get(k) {
+                    Ok(Some(v)) => pairs.push(Ok((k.encoded().clone(), v))),
+                    Ok(None) => {}
+                    Err(e) => pairs.push(Err(StorageError::from(e))),
+                }
+            }
+            ProcessResult::MultiKvpairs { pairs: pairs }
+        }
         Command::RawScan {
             ref start_key,
             limit,
+            key_only,
             ..
-        } => match process_rawscan(snapshot, start_key, limit, &mut statistics) {
+        } => match process_rawscan(snapshot, start_key, limit, key_only, &mut statistics) {
             Ok(val) => ProcessResult::MultiKvpairs { pairs: val },
             Err(e) => ProcessResult::Failed {
                 err: StorageError::from(e),
@@ -702,6 +1124,9 @@ fn process_read(
         _ => panic!("unsupported read command"),
     };
 
+    // Recorded before the result is sent, so it's counted in the response.
+    tracker.add_cpu_time(thread_cpu_time() - cpu_start);
+    tracker.add_statistics(&statistics);
     if let Err(e) = ch.send(Msg::ReadFinished { cid: cid, pr: pr }) {
         // Todo: if this happens we need to clean up command's context
         panic!("send read finished failed, cid={}, err={:?}", cid, e);
@@ -713,6 +1138,7 @@ fn process_rawscan(
     snapshot: Box<Snapshot>,
     start_key: &Key,
     limit: usize,
+    key_only: bool,
     stats: &mut Statistics,
 ) -> Result<Vec<StorageResult<KvPair>>> {
     let mut cursor = try!(snapshot.iter(IterOption::default(), ScanMode::Forward));
@@ -721,7 +1147,12 @@ fn process_rawscan(
     }
     let mut pairs = vec![];
     while cursor.valid() && pairs.len() < limit {
-        pairs.push(Ok((cursor.key().to_owned(), cursor.value().to_owned())));
+        let value = if key_only {
+            vec![]
+        } else {
+            cursor.value().to_owned()
+        };
+        pairs.push(Ok((cursor.key().to_owned(), value)));
         cursor.next(&mut stats.data);
     }
     Ok(pairs)
@@ -734,12 +1165,29 @@ fn process_write(
     cmd: Command,
     ch: SyncSendCh<Msg>,
     snapshot: Box<Snapshot>,
+    tracker: &Tracker,
+    write_limiter: &WriteLimiter,
+    max_ts: &MaxTsTracker,
 ) -> Statistics {
     let mut statistics = Statistics::default();
     SCHED_WORKER_COUNTER_VEC
         .with_label_values(&[cmd.tag(), "write"])
         .inc();
-    if let Err(e) = process_write_impl(cid, cmd, ch.clone(), snapshot.as_ref(), &mut statistics) {
+    let cpu_start = thread_cpu_time();
+    let res = process_write_impl(
+        cid,
+        cmd,
+        ch.clone(),
+        snapshot.as_ref(),
+        &mut statistics,
+        write_limiter,
+        max_ts,
+    );
+    // The response waits for the writes to be applied, it won't miss the cpu
+    // time recorded after the result is sent.
+    tracker.add_cpu_time(thread_cpu_time() - cpu_start);
+    tracker.add_statistics(&statistics);
+    if let Err(e) = res {
         if let Err(err) = ch.send(Msg::WritePrepareFailed { cid: cid, err: e }) {
             // Todo: if this happens, lock will hold for ever
             panic!(
@@ -758,16 +1206,44 @@ fn process_write_impl(
     ch: SyncSendCh<Msg>,
     snapshot: &Snapshot,
     statistics: &mut Statistics,
+    write_limiter: &WriteLimiter,
+    max_ts: &MaxTsTracker,
 ) -> Result<()> {
+    fail_point!("scheduler_process_write", |_| {
+        Err(box_err!("process write is failed by fail point"))
+    });
     let (pr, modifies) = match cmd {
         Command::Prewrite {
             ref ctx,
             ref mutations,
             ref primary,
             start_ts,
-            ref options,
+            ref mut options,
             ..
         } => {
+            // The reads which have missed the locks or the writes must be before the
+            // commit, the keys are locked in memory before the max ts is taken, so the
+            // reads after it won't miss them. It falls back to 2PC if the max ts of the
+            // region is unknown.
+            let mut min_commit_ts = None;
+            if options.use_async_commit || options.try_one_pc {
+                let keys: Vec<_> = mutations.iter().map(|m| m.key().clone()).collect();
+                let lock = MvccLock::new(
+                    LockType::Put,
+                    primary.clone(),
+                    start_ts,
+                    options.lock_ttl,
+                    None,
+                ).with_min_commit_ts(options.min_commit_ts);
+                min_commit_ts = max_ts.lock_keys(ctx.get_region_id(), cid, &keys, lock);
+                match min_commit_ts {
+                    Some(ts) => options.min_commit_ts = ts,
+                    None => {
+                        options.use_async_commit = false;
+                        options.try_one_pc = false;
+                    }
+                }
+            }
             let mut txn = MvccTxn::new(
                 snapshot,
                 statistics,
@@ -787,10 +1263,33 @@ fn process_write_impl(
                 }
             }
             if locks.is_empty() {
-                let pr = ProcessResult::MultiRes { results: vec![] };
+                // The memory locks are released after the prewrite is written. A retry
+                // of a prewrite which has fallen back to 2PC stays in 2PC, the locks
+                // written before must be committed by the client.
+                let pr = match min_commit_ts {
+                    Some(commit_ts) if options.try_one_pc && !txn.has_duplicated_prewrite() => {
+                        txn.commit_one_pc(commit_ts);
+                        ProcessResult::OnePcCommitted {
+                            commit_ts: commit_ts,
+                        }
+                    }
+                    _ => {
+                        if options.try_one_pc {
+                            txn.fall_back_to_two_pc();
+                        }
+                        if options.use_async_commit {
+                            ProcessResult::AsyncCommitPrewritten {
+                                min_commit_ts: options.min_commit_ts,
+                            }
+                        } else {
+                            ProcessResult::MultiRes { results: vec![] }
+                        }
+                    }
+                };
                 (pr, txn.modifies())
             } else {
                 // Skip write stage if some keys are locked.
+                max_ts.unlock_keys(cid);
                 let pr = ProcessResult::MultiRes { results: locks };
                 (pr, vec![])
             }
@@ -863,6 +1362,145 @@ fn process_write_impl(
             let pr = ProcessResult::Res;
             (pr, txn.modifies())
         }
+        Command::AcquirePessimisticLock {
+            ref ctx,
+            ref keys,
+            ref primary,
+            start_ts,
+            for_update_ts,
+            ref options,
+        } => {
+            let mut txn = MvccTxn::new(
+                snapshot,
+                statistics,
+                start_ts,
+                None,
+                ctx.get_isolation_level(),
+                !ctx.get_not_fill_cache(),
+            );
+            let mut locks = vec![];
+            let mut values = vec![];
+            for k in keys {
+                match txn.acquire_pessimistic_lock(k.clone(), primary, for_update_ts, options) {
+                    Ok(value) => if options.return_values {
+                        values.push(value);
+                    },
+                    e @ Err(MvccError::KeyIsLocked { .. }) => {
+                        locks.push(e.map_err(Error::from).map_err(StorageError::from));
+                    }
+                    Err(e) => return Err(Error::from(e)),
+                }
+            }
+            if locks.is_empty() {
+                let pr = ProcessResult::PessimisticLocked { values: values };
+                (pr, txn.modifies())
+            } else {
+                // Skip write stage if some keys are locked, so the keys are locked all or none.
+                let pr = ProcessResult::MultiRes { results: locks };
+                (pr, vec![])
+            }
+        }
+        Command::PessimisticRollback {
+            ref ctx,
+            ref keys,
+            start_ts,
+            for_update_ts,
+        } => {
+            let mut txn = MvccTxn::new(
+                snapshot,
+                statistics,
+                start_ts,
+                None,
+                ctx.get_isolation_level(),
+                !ctx.get_not_fill_cache(),
+            );
+            for k in keys {
+                try!(txn.pessimistic_rollback(k.clone(), for_update_ts));
+            }
+
+            let pr = ProcessResult::Res;
+            (pr, txn.modifies())
+        }
+        Command::CheckTxnStatus {
+            ref ctx,
+            ref primary_key,
+            lock_ts,
+            caller_start_ts,
+            current_ts,
+        } => {
+            let mut txn = MvccTxn::new(
+                snapshot,
+                statistics,
+                lock_ts,
+                None,
+                ctx.get_isolation_level(),
+                !ctx.get_not_fill_cache(),
+            );
+            let status = try!(txn.check_txn_status(primary_key, caller_start_ts, current_ts));
+
+            let pr = ProcessResult::TxnStatus { status: status };
+            (pr, txn.modifies())
+        }
+        Command::TxnHeartBeat {
+            ref ctx,
+            ref primary_key,
+            start_ts,
+            advise_ttl,
+        } => {
+            let mut txn = MvccTxn::new(
+                snapshot,
+                statistics,
+                start_ts,
+                None,
+                ctx.get_isolation_level(),
+                !ctx.get_not_fill_cache(),
+            );
+            let ttl = try!(txn.txn_heart_beat(primary_key.clone(), advise_ttl));
+
+            let pr = ProcessResult::Ttl { ttl: ttl };
+            (pr, txn.modifies())
+        }
+        Command::CheckSecondaryLocks {
+            ref ctx,
+            ref keys,
+            start_ts,
+        } => {
+            let mut txn = MvccTxn::new(
+                snapshot,
+                statistics,
+                start_ts,
+                None,
+                ctx.get_isolation_level(),
+                !ctx.get_not_fill_cache(),
+            );
+            let mut locks = vec![];
+            let mut commit_ts = None;
+            let mut rolled_back = false;
+            for k in keys {
+                match try!(txn.check_secondary_lock(k)) {
+                    SecondaryLockStatus::Locked(lock) => locks.push((k.clone(), lock)),
+                    SecondaryLockStatus::Committed(ts) => {
+                        commit_ts = Some(ts);
+                        break;
+                    }
+                    SecondaryLockStatus::RolledBack => rolled_back = true,
+                }
+            }
+            let status = if let Some(ts) = commit_ts {
+                SecondaryLocksStatus::Committed(ts)
+            } else if rolled_back {
+                // The transaction can't be committed, roll back the locked keys as well.
+                for &(ref k, _) in &locks {
+                    try!(txn.rollback(k));
+                }
+                SecondaryLocksStatus::RolledBack
+            } else {
+                SecondaryLocksStatus::Locked(locks)
+            };
+
+            let pr = ProcessResult::SecondaryLocks { status: status };
+            (pr, txn.modifies())
+        }
         Command::ResolveLock {
             ref ctx,
             start_ts,
@@ -912,6 +1550,29 @@ fn process_write_impl(
                 (pr, txn.modifies())
             }
         }
+        Command::ResolveLockLite {
+            ref ctx,
+            start_ts,
+            commit_ts,
+            ref resolve_keys,
+        } => {
+            let mut txn = MvccTxn::new(
+                snapshot,
+                statistics,
+                start_ts,
+                None,
+                ctx.get_isolation_level(),
+                !ctx.get_not_fill_cache(),
+            );
+            for k in resolve_keys {
+                match commit_ts {
+                    Some(ts) => try!(txn.commit(k, ts)),
+                    None => try!(txn.rollback(k)),
+                }
+            }
+
+            (ProcessResult::Res, txn.modifies())
+        }
         Command::Gc {
             ref ctx,
             safe_point,
@@ -953,6 +1614,17 @@ fn process_write_impl(
         _ => panic!("unsupported write command"),
     };
 
+    // Only the foreground writes are throttled, the low priority ones are
+    // limited by their own pool.
+    if cmd.priority() != CommandPri::Low {
+        let throttled = write_limiter.consume(write_bytes(&modifies));
+        if throttled > Duration::from_secs(0) {
+            SCHED_THROTTLE_HISTOGRAM_VEC
+                .with_label_values(&[cmd.tag()])
+                .observe(duration_to_sec(throttled));
+        }
+    }
+
     box_try!(ch.send(Msg::WritePrepareFinished {
         cid: cid,
         cmd: cmd,
@@ -999,6 +1671,8 @@ impl Scheduler {
     fn insert_ctx(&mut self, ctx: RunningCtx) {
         if ctx.lock.is_write_lock() {
             self.running_write_count += 1;
+            self.running_write_bytes += ctx.write_bytes;
+            SCHED_WRITING_BYTES_GAUGE.set(self.running_write_bytes as f64);
         }
         if ctx.tag == CMD_TAG_GC {
             self.has_gc_command = true;
@@ -1015,6 +1689,8 @@ impl Scheduler {
         assert_eq!(ctx.cid, cid);
         if ctx.lock.is_write_lock() {
             self.running_write_count -= 1;
+            self.running_write_bytes -= ctx.write_bytes;
+            SCHED_WRITING_BYTES_GAUGE.set(self.running_write_bytes as f64);
         }
         if ctx.tag == CMD_TAG_GC {
             self.has_gc_command = false;
@@ -1030,7 +1706,8 @@ impl Scheduler {
 
     fn fetch_worker_pool(&self, priority: CommandPri) -> &ThreadPool<ScheContext> {
         match priority {
-            CommandPri::Low | CommandPri::Normal => &self.worker_pool,
+            CommandPri::Low => &self.low_priority_pool,
+            CommandPri::Normal => &self.worker_pool,
             CommandPri::High => &self.high_priority_pool,
         }
     }
@@ -1045,10 +1722,11 @@ impl Scheduler {
             cid,
             cb_ctx
         );
-        let mut cmd = {
+        let (mut cmd, tracker) = {
             let ctx = &mut self.cmd_ctxs.get_mut(&cid).unwrap();
             assert_eq!(ctx.cid, cid);
-            ctx.cmd.take().unwrap()
+            ctx.enter_stage("process");
+            (ctx.cmd.take().unwrap(), ctx.tracker.clone())
         };
         if let Some(term) = cb_ctx.term {
             cmd.mut_context().set_term(term);
@@ -1058,13 +1736,26 @@ impl Scheduler {
         let worker_pool = self.fetch_worker_pool(cmd.priority());
         let tag = cmd.tag();
         if readcmd {
+            let pessimistic_locks = self.pessimistic_locks.clone();
             worker_pool.execute(move |ctx: &mut ScheContext| {
-                let s = process_read(cid, cmd, ch, snapshot);
+                let s = process_read(cid, cmd, ch, snapshot, &tracker, &pessimistic_locks);
                 ctx.add_statistics(tag, &s);
             });
         } else {
+            let snapshot = self.pessimistic_locks
+                .wrap_snapshot(cmd.get_context().get_region_id(), snapshot);
+            let write_limiter = self.write_limiter.clone();
+            let max_ts = self.max_ts.clone();
             worker_pool.execute(move |ctx: &mut ScheContext| {
-                let s = process_write(cid, cmd, ch, snapshot);
+                let s = process_write(
+                    cid,
+                    cmd,
+                    ch,
+                    snapshot,
+                    &tracker,
+                    &write_limiter,
+                    &max_ts,
+                );
                 ctx.add_statistics(tag, &s);
             });
         }
@@ -1076,12 +1767,14 @@ impl Scheduler {
         SCHED_STAGE_COUNTER_VEC
             .with_label_values(&[self.get_ctx_tag(cid), "error"])
             .inc();
+        self.max_ts.unlock_keys(cid);
 
         let mut ctx = self.remove_ctx(cid);
         let cb = ctx.callback.take().unwrap();
         let pr = ProcessResult::Failed {
             err: StorageError::from(err),
         };
+        ctx.finish();
         execute_callback(cb, pr);
 
         self.release_lock(&ctx.lock, cid);
@@ -1104,7 +1797,7 @@ impl Scheduler {
     /// Note that once a command is ready to execute, the snapshot is always up-to-date during the
     /// execution because 1) all the conflicting commands (if any) must be in the waiting queues;
     /// 2) there may be non-conflicitng commands running concurrently, but it doesn't matter.
-    fn schedule_command(&mut self, cmd: Command, callback: StorageCb) {
+    fn schedule_command(&mut self, cmd: Command, callback: StorageCb, tracker: Arc<Tracker>) {
         SCHED_STAGE_COUNTER_VEC
             .with_label_values(&[cmd.tag(), "new"])
             .inc();
@@ -1113,29 +1806,74 @@ impl Scheduler {
             .inc();
         let cid = self.gen_id();
         debug!("received new command, cid={}, cmd={}", cid, cmd);
+        // The reads are checked before the snapshots are taken, which may miss the
+        // prewrites being written.
+        if let Err(e) = read_memory_locks(&self.max_ts, &cmd) {
+            SCHED_STAGE_COUNTER_VEC
+                .with_label_values(&[cmd.tag(), "memory_locked"])
+                .inc();
+            let pr = ProcessResult::Failed {
+                err: StorageError::from(Error::from(e)),
+            };
+            execute_callback(callback, pr);
+            return;
+        }
         let lock = gen_command_lock(&self.latches, &cmd);
-        let ctx = RunningCtx::new(cid, cmd, lock, callback);
+        let mut ctx = RunningCtx::new(
+            cid,
+            cmd,
+            lock,
+            callback,
+            tracker,
+            &self.slow_log_thresholds,
+        );
+        ctx.locks_generation = self.pessimistic_locks.generation(ctx.region_id);
         self.insert_ctx(ctx);
         self.lock_and_register_get_snapshot(cid);
     }
 
-    fn too_busy(&self) -> bool {
-        self.running_write_count >= self.sched_too_busy_threshold
+    /// Checks whether a write of `write_bytes` should be rejected, and returns
+    /// the suggested backoff if so. Writes are rejected early when too many of
+    /// them are pending, or the engine can't keep up with them, instead of
+    /// being queued until timeout.
+    fn too_busy(&self, write_bytes: usize) -> Option<(&'static str, u64)> {
+        if self.running_write_count >= self.sched_too_busy_threshold {
+            return Some(("pending_count", SCHED_BUSY_BACKOFF_MS));
+        }
+        // A large write is still allowed when nothing is pending, otherwise it
+        // would never succeed.
+        if self.running_write_bytes > 0 &&
+            self.running_write_bytes + write_bytes > self.sched_pending_write_threshold
+        {
+            return Some(("pending_bytes", SCHED_BUSY_BACKOFF_MS));
+        }
+        if engine_metrics::is_kv_write_stalled() {
+            return Some(("write_stall", ENGINE_BUSY_BACKOFF_MS));
+        }
+        if memory::is_exceeded() {
+            return Some(("memory", ENGINE_BUSY_BACKOFF_MS));
+        }
+        None
     }
 
-    fn on_receive_new_cmd(&mut self, cmd: Command, callback: StorageCb) {
+    fn on_receive_new_cmd(&mut self, cmd: Command, callback: StorageCb, tracker: Arc<Tracker>) {
         // write flow control
-        if cmd.need_flow_control() && self.too_busy() {
-            SCHED_TOO_BUSY_COUNTER_VEC
-                .with_label_values(&[cmd.tag()])
-                .inc();
-            execute_callback(
-                callback,
-                ProcessResult::Failed {
-                    err: StorageError::SchedTooBusy,
-                },
-            );
-            return;
+        if cmd.need_flow_control() {
+            if let Some((reason, backoff_ms)) = self.too_busy(cmd.write_bytes()) {
+                SCHED_TOO_BUSY_COUNTER_VEC
+                    .with_label_values(&[cmd.tag()])
+                    .inc();
+                SCHED_TOO_BUSY_REASON_COUNTER_VEC
+                    .with_label_values(&[reason])
+                    .inc();
+                execute_callback(
+                    callback,
+                    ProcessResult::Failed {
+                        err: StorageError::SchedTooBusy(backoff_ms),
+                    },
+                );
+                return;
+            }
         }
         // Allow 1 GC command at the same time.
         if cmd.tag() == CMD_TAG_GC && self.has_gc_command {
@@ -1145,13 +1883,13 @@ impl Scheduler {
             execute_callback(
                 callback,
                 ProcessResult::Failed {
-                    err: StorageError::SchedTooBusy,
+                    err: StorageError::SchedTooBusy(SCHED_BUSY_BACKOFF_MS),
                 },
             );
             return;
 
         }
-        self.schedule_command(cmd, callback);
+        self.schedule_command(cmd, callback, tracker);
     }
 
     /// Tries to acquire all the required latches for a command.
@@ -1163,6 +1901,7 @@ impl Scheduler {
         let ok = self.latches.acquire(&mut ctx.lock, cid);
         if ok {
             ctx.latch_timer.take();
+            ctx.enter_stage("snapshot");
         }
         ok
     }
@@ -1272,6 +2011,7 @@ impl Scheduler {
         cb_ctx: CbContext,
         snapshot: EngineResult<Box<Snapshot>>,
     ) {
+        fail_point!("scheduler_async_snapshot_finish");
         debug!(
             "receive snapshot finish msg for cids={:?}, cb_ctx={:?}",
             cids,
@@ -1306,6 +2046,7 @@ impl Scheduler {
     fn on_read_finished(&mut self, cid: u64, pr: ProcessResult) {
         debug!("read command(cid={}) finished", cid);
         let mut ctx = self.remove_ctx(cid);
+        ctx.tracker.add_read_bytes(read_bytes(&pr));
         SCHED_STAGE_COUNTER_VEC
             .with_label_values(&[ctx.tag, "read_finish"])
             .inc();
@@ -1314,8 +2055,10 @@ impl Scheduler {
             SCHED_STAGE_COUNTER_VEC
                 .with_label_values(&[ctx.tag, "next_cmd"])
                 .inc();
-            self.schedule_command(cmd, cb);
+            let tracker = ctx.tracker.clone();
+            self.schedule_command(cmd, cb, tracker);
         } else {
+            ctx.finish();
             execute_callback(cb, pr);
         }
 
@@ -1348,7 +2091,27 @@ impl Scheduler {
         SCHED_STAGE_COUNTER_VEC
             .with_label_values(&[self.get_ctx_tag(cid), "write"])
             .inc();
+        // The pessimistic locks kept in memory are done once the command gets
+        // here, its latches are still held.
+        let to_be_write = {
+            let ctx = &self.cmd_ctxs[&cid];
+            self.pessimistic_locks
+                .apply_modifies(ctx.region_id, ctx.locks_generation, to_be_write)
+        };
+        {
+            let ctx = self.cmd_ctxs.get_mut(&cid).unwrap();
+            ctx.enter_stage("write");
+            ctx.tracker.add_write_bytes(write_bytes(&to_be_write));
+            if self.waiter_mgr.enabled() {
+                ctx.released_keys = released_keys(&cmd);
+            }
+        }
         if to_be_write.is_empty() {
+            if self.waiter_mgr.enabled() {
+                if let Some((key, lock_ts)) = locked_key(&pr) {
+                    return self.wait_for_lock(cid, key, lock_ts, cmd, pr);
+                }
+            }
             return self.on_write_finished(cid, pr, Ok(()));
         }
         let engine_cb = make_engine_cb(cid, pr, self.schedch.clone());
@@ -1368,24 +2131,185 @@ impl Scheduler {
             .with_label_values(&[self.get_ctx_tag(cid), "write_finish"])
             .inc();
         debug!("write finished for command, cid={}", cid);
+        // The prewrite is written, the reads won't miss it any more.
+        self.max_ts.unlock_keys(cid);
         let mut ctx = self.remove_ctx(cid);
         let cb = ctx.callback.take().unwrap();
         let pr = match result {
             Ok(()) => pr,
-            Err(e) => ProcessResult::Failed {
-                err: ::storage::Error::from(e),
-            },
+            Err(e) => {
+                ctx.released_keys.clear();
+                ProcessResult::Failed {
+                    err: ::storage::Error::from(e),
+                }
+            }
         };
         if let ProcessResult::NextCommand { cmd } = pr {
             SCHED_STAGE_COUNTER_VEC
                 .with_label_values(&[ctx.tag, "next_cmd"])
                 .inc();
-            self.schedule_command(cmd, cb);
+            let tracker = ctx.tracker.clone();
+            self.schedule_command(cmd, cb, tracker);
         } else {
+            ctx.finish();
             execute_callback(cb, pr);
         }
 
         self.release_lock(&ctx.lock, cid);
+        for key in &ctx.released_keys {
+            if let Some(w) = self.waiter_mgr.notify(key) {
+                self.wake_up_waiter(w);
+            }
+        }
+        // The transaction doesn't wait for any lock once its locks are released.
+        if !ctx.released_keys.is_empty() {
+            if let Some(ref detector) = self.detector {
+                detector.clean_up(ctx.ts);
+            }
+        }
+    }
+
+    /// Makes a command wait for the lock of `lock_ts` on `key` instead of
+    /// returning the lock to the client at once, the latches are released
+    /// while waiting. The waits of the pessimistic locks are sent to the
+    /// deadlock detector.
+    fn wait_for_lock(
+        &mut self,
+        cid: u64,
+        key: Vec<u8>,
+        lock_ts: u64,
+        cmd: Command,
+        pr: ProcessResult,
+    ) {
+        let mut ctx = self.remove_ctx(cid);
+        let wait_for = match cmd {
+            Command::AcquirePessimisticLock { .. } if self.detector.is_some() => {
+                Some((lock_ts, waiter_manager::key_hash(&key)))
+            }
+            _ => None,
+        };
+        let waiter = LockWaiter {
+            cmd: cmd,
+            callback: ctx.callback.take().unwrap(),
+            tracker: ctx.tracker.clone(),
+            pr: pr,
+            wait_for: wait_for,
+        };
+        match self.waiter_mgr.wait_for(key.clone(), waiter, ctx.tracker.elapsed()) {
+            Ok(()) => {
+                SCHED_STAGE_COUNTER_VEC
+                    .with_label_values(&[ctx.tag, "lock_wait"])
+                    .inc();
+                ctx.finish_stage();
+                ctx.tracker.record_event("lock_wait");
+                if let Some((lock_ts, key_hash)) = wait_for {
+                    let ch = self.schedch.clone();
+                    let start_ts = ctx.ts;
+                    let cb = box move |deadlock_key_hash| {
+                        if let Err(e) = ch.send(Msg::Deadlock {
+                            key: key,
+                            start_ts: start_ts,
+                            lock_ts: lock_ts,
+                            deadlock_key_hash: deadlock_key_hash,
+                        }) {
+                            error!("failed to send deadlock of txn {}: {:?}", start_ts, e);
+                        }
+                    };
+                    self.detector
+                        .as_ref()
+                        .unwrap()
+                        .detect(start_ts, lock_ts, key_hash, cb);
+                }
+            }
+            Err(waiter) => {
+                ctx.finish();
+                execute_callback(waiter.callback, waiter.pr);
+            }
+        }
+        SCHED_LOCK_WAITERS_GAUGE.set(self.waiter_mgr.len() as f64);
+        self.release_lock(&ctx.lock, cid);
+    }
+
+    fn wake_up_waiter(&mut self, waiter: LockWaiter) {
+        SCHED_STAGE_COUNTER_VEC
+            .with_label_values(&[waiter.cmd.tag(), "lock_wake_up"])
+            .inc();
+        self.clean_up_wait_for(&waiter);
+        SCHED_LOCK_WAITERS_GAUGE.set(self.waiter_mgr.len() as f64);
+        self.schedule_command(waiter.cmd, waiter.callback, waiter.tracker);
+    }
+
+    /// Resizes the worker pools, the high priority pool is left unchanged. Both pools are
+    /// resized even if one fails, so their sizes stay in ratio.
+    fn resize_worker_pool(&mut self, size: usize) {
+        let mut failed = false;
+        if let Err(e) = self.worker_pool.resize(size) {
+            error!("failed to resize scheduler worker pool: {}", e);
+            failed = true;
+        }
+        if let Err(e) = self.low_priority_pool.resize(low_priority_pool_size(size)) {
+            error!("failed to resize scheduler low priority pool: {}", e);
+            failed = true;
+        }
+        if !failed {
+            info!("scheduler worker pool is resized to {} threads", size);
+        }
+    }
+
+    /// Wakes up the delayed lock waiters, and finishes the timed out ones with
+    /// the locks they are waiting for.
+    fn poll_lock_waiters(&mut self) {
+        if self.waiter_mgr.is_empty() {
+            return;
+        }
+        let (woken, timed_out) = self.waiter_mgr.poll(Instant::now());
+        for w in woken {
+            self.wake_up_waiter(w);
+        }
+        for w in timed_out {
+            SCHED_STAGE_COUNTER_VEC
+                .with_label_values(&[w.cmd.tag(), "lock_wait_timeout"])
+                .inc();
+            self.clean_up_wait_for(&w);
+            w.tracker.record_event("finish");
+            execute_callback(w.callback, w.pr);
+        }
+        SCHED_LOCK_WAITERS_GAUGE.set(self.waiter_mgr.len() as f64);
+    }
+
+    fn clean_up_wait_for(&self, waiter: &LockWaiter) {
+        let detector = match self.detector {
+            Some(ref detector) => detector,
+            None => return,
+        };
+        if let Some((lock_ts, key_hash)) = waiter.wait_for {
+            detector.clean_up_wait_for(waiter.cmd.ts(), lock_ts, key_hash);
+        }
+    }
+
+    /// Finishes the pessimistic lock of `start_ts` waiting on `key` with the
+    /// deadlock error, so the transaction can be rolled back by the client.
+    fn on_deadlock(&mut self, key: Vec<u8>, start_ts: u64, lock_ts: u64, deadlock_key_hash: u64) {
+        let w = match self.waiter_mgr.remove(&key, |w| w.cmd.ts() == start_ts) {
+            Some(w) => w,
+            // It's woken up or timed out already.
+            None => return,
+        };
+        SCHED_STAGE_COUNTER_VEC
+            .with_label_values(&[w.cmd.tag(), "deadlock"])
+            .inc();
+        SCHED_LOCK_WAITERS_GAUGE.set(self.waiter_mgr.len() as f64);
+        w.tracker.record_event("finish");
+        let err = MvccError::Deadlock {
+            start_ts: start_ts,
+            lock_ts: lock_ts,
+            key: key,
+            deadlock_key_hash: deadlock_key_hash,
+        };
+        let pr = ProcessResult::MultiRes {
+            results: vec![Err(StorageError::Txn(Error::Mvcc(err)))],
+        };
+        execute_callback(w.callback, pr);
     }
 
     /// Releases all the latches held by a command.
@@ -1412,20 +2336,34 @@ impl Scheduler {
 
     pub fn run(&mut self, receiver: Receiver<Msg>) -> Result<()> {
         let mut msgs = Vec::with_capacity(CMD_BATCH_SIZE);
+        let poll_interval = Duration::from_millis(LOCK_WAIT_POLL_INTERVAL_MS);
         loop {
-            let msg = box_try!(receiver.recv());
-            msgs.push(msg);
-            while let Ok(msg) = receiver.try_recv() {
+            // Wakes up periodically to check the lock waiters.
+            let msg = if self.waiter_mgr.is_empty() {
+                Some(box_try!(receiver.recv()))
+            } else {
+                match receiver.recv_timeout(poll_interval) {
+                    Ok(msg) => Some(msg),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(e) => return Err(Error::Other(box_err!("{:?}", e))),
+                }
+            };
+            if let Some(msg) = msg {
                 msgs.push(msg);
-                if msgs.len() >= CMD_BATCH_SIZE {
-                    break;
+                while let Ok(msg) = receiver.try_recv() {
+                    msgs.push(msg);
+                    if msgs.len() >= CMD_BATCH_SIZE {
+                        break;
+                    }
                 }
             }
 
             for msg in msgs.drain(..) {
                 match msg {
                     Msg::Quit => return self.shutdown(),
-                    Msg::RawCmd { cmd, cb } => self.on_receive_new_cmd(cmd, cb),
+                    Msg::RawCmd { cmd, cb, tracker } => {
+                        self.on_receive_new_cmd(cmd, cb, tracker)
+                    }
                     Msg::RetryGetSnapshots(tasks) => for (ctx, cids) in tasks {
                         self.get_snapshot(&ctx, cids);
                     },
@@ -1448,8 +2386,16 @@ impl Scheduler {
                     Msg::WriteFinished {
                         cid, pr, result, ..
                     } => self.on_write_finished(cid, pr, result),
+                    Msg::ResizeWorkerPool(size) => self.resize_worker_pool(size),
+                    Msg::Deadlock {
+                        key,
+                        start_ts,
+                        lock_ts,
+                        deadlock_key_hash,
+                    } => self.on_deadlock(key, start_ts, lock_ts, deadlock_key_hash),
                 }
             }
+            self.poll_lock_waiters();
 
             if self.grouped_cmds.as_ref().unwrap().is_empty() {
                 continue;
@@ -1478,6 +2424,9 @@ impl Scheduler {
         if let Err(e) = self.high_priority_pool.stop() {
             return Err(Error::Other(box_err!("{:?}", e)));
         }
+        if let Err(e) = self.low_priority_pool.stop() {
+            return Err(Error::Other(box_err!("{:?}", e)));
+        }
         Ok(())
     }
 }
@@ -1496,8 +2445,23 @@ pub fn gen_command_lock(latches: &Latches, cmd: &Command) -> Lock {
         }
         Command::Commit { ref keys, .. } |
         Command::Rollback { ref keys, .. } |
-        Command::ResolveLock { ref keys, .. } => latches.gen_lock(keys),
-        Command::Cleanup { ref key, .. } => latches.gen_lock(&[key]),
+        Command::AcquirePessimisticLock { ref keys, .. } |
+        Command::PessimisticRollback { ref keys, .. } |
+        Command::CheckSecondaryLocks { ref keys, .. } |
+        Command::ResolveLock { ref keys, .. } |
+        Command::ResolveLockLite {
+            resolve_keys: ref keys,
+            ..
+        } => latches.gen_lock(keys),
+        Command::Cleanup { ref key, .. } |
+        Command::CheckTxnStatus {
+            primary_key: ref key,
+            ..
+        } |
+        Command::TxnHeartBeat {
+            primary_key: ref key,
+            ..
+        } => latches.gen_lock(&[key]),
         _ => Lock::new(vec![]),
     }
 }
@@ -1507,7 +2471,7 @@ mod tests {
     use super::*;
     use kvproto::kvrpcpb::Context;
     use storage::txn::latch::*;
-    use storage::{make_key, Command, Mutation, Options};
+    use storage::{make_key, Command, Mutation, Options, CF_DEFAULT};
 
     #[test]
     fn test_command_latches() {
@@ -1580,6 +2544,20 @@ mod tests {
                 keys: vec![make_key(b"k")],
                 start_ts: 10,
             },
+            Command::AcquirePessimisticLock {
+                ctx: Context::new(),
+                keys: vec![make_key(b"k")],
+                primary: b"k".to_vec(),
+                start_ts: 30,
+                for_update_ts: 30,
+                options: Options::default(),
+            },
+            Command::PessimisticRollback {
+                ctx: Context::new(),
+                keys: vec![make_key(b"k")],
+                start_ts: 30,
+                for_update_ts: 30,
+            },
             Command::ResolveLock {
                 ctx: Context::new(),
                 start_ts: 10,
@@ -1621,4 +2599,70 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_slow_log_thresholds() {
+        let thresholds = SlowLogThresholds {
+            point_read: Duration::from_millis(1),
+            scan: Duration::from_millis(2),
+            write: Duration::from_millis(3),
+        };
+        let get = Command::Get {
+            ctx: Context::new(),
+            key: make_key(b"k"),
+            start_ts: 25,
+        };
+        assert_eq!(thresholds.get(&get), ("point_read", Duration::from_millis(1)));
+        let scan = Command::ScanLock {
+            ctx: Context::new(),
+            max_ts: 5,
+        };
+        assert_eq!(thresholds.get(&scan), ("scan", Duration::from_millis(2)));
+        let commit = Command::Commit {
+            ctx: Context::new(),
+            keys: vec![make_key(b"k")],
+            lock_ts: 10,
+            commit_ts: 20,
+        };
+        assert_eq!(thresholds.get(&commit), ("write", Duration::from_millis(3)));
+    }
+
+    #[test]
+    fn test_running_ctx_tracker() {
+        let latches = Latches::new(16);
+        let thresholds = SlowLogThresholds::new(&Config::default());
+        let get = Command::Get {
+            ctx: Context::new(),
+            key: make_key(b"k"),
+            start_ts: 25,
+        };
+        let lock = gen_command_lock(&latches, &get);
+        let cb = StorageCb::SingleValue(box |_| {});
+        let tracker = Tracker::new("kv_get");
+        let mut ctx = RunningCtx::new(1, get, lock, cb, tracker.clone(), &thresholds);
+
+        thread::sleep(Duration::from_millis(10));
+        ctx.enter_stage("snapshot");
+        thread::sleep(Duration::from_millis(10));
+        ctx.enter_stage("process");
+        thread::sleep(Duration::from_millis(10));
+        let detail = ctx.finish();
+        assert!(detail.wait >= Duration::from_millis(20));
+        assert!(detail.process >= Duration::from_millis(10));
+        assert_eq!(detail.commit, Duration::from_millis(0));
+        assert_eq!(tracker.time_detail(), Some(detail));
+        let events: Vec<_> = tracker.events().into_iter().map(|(e, _)| e).collect();
+        assert_eq!(events, vec!["latch_wait", "snapshot", "process", "finish"]);
+
+        let pr = ProcessResult::Value {
+            value: Some(b"value".to_vec()),
+        };
+        assert_eq!(read_bytes(&pr), 5);
+        let modifies = vec![
+            Modify::Put(CF_DEFAULT, make_key(b"k"), b"v".to_vec()),
+            Modify::Delete(CF_DEFAULT, make_key(b"k")),
+        ];
+        let key_len = make_key(b"k").encoded().len() as u64;
+        assert_eq!(write_bytes(&modifies), key_len * 2 + 1);
+    }
 }
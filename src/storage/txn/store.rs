@@ -15,12 +15,14 @@ use storage::{Key, KvPair, ScanMode, Snapshot, Statistics, Value};
 use storage::mvcc::{Error as MvccError, MvccReader};
 use super::{Error, Result};
 use kvproto::kvrpcpb::IsolationLevel;
+use util::collections::HashSet;
 
 pub struct SnapshotStore<'a> {
     snapshot: &'a Snapshot,
     start_ts: u64,
     isolation_level: IsolationLevel,
     fill_cache: bool,
+    bypass_locks: HashSet<u64>,
 }
 
 impl<'a> SnapshotStore<'a> {
@@ -35,9 +37,16 @@ impl<'a> SnapshotStore<'a> {
             start_ts: start_ts,
             isolation_level: isolation_level,
             fill_cache: fill_cache,
+            bypass_locks: HashSet::default(),
         }
     }
 
+    /// Sets the start ts of the transactions whose locks don't block the reads, the
+    /// reader has pushed their min_commit_ts by `CheckTxnStatus`.
+    pub fn set_bypass_locks(&mut self, bypass_locks: &[u64]) {
+        self.bypass_locks = bypass_locks.iter().cloned().collect();
+    }
+
     pub fn get(&self, key: &Key, statistics: &mut Statistics) -> Result<Option<Value>> {
         let mut reader = MvccReader::new(
             self.snapshot,
@@ -47,6 +56,7 @@ impl<'a> SnapshotStore<'a> {
             None,
             self.isolation_level,
         );
+        reader.set_bypass_locks(self.bypass_locks.clone());
         let v = try!(reader.get(key, self.start_ts));
         Ok(v)
     }
@@ -65,6 +75,7 @@ impl<'a> SnapshotStore<'a> {
             None,
             self.isolation_level,
         );
+        reader.set_bypass_locks(self.bypass_locks.clone());
         let mut results = Vec::with_capacity(keys.len());
         for k in keys {
             results.push(reader.get(k, self.start_ts).map_err(Error::from));
@@ -90,6 +101,7 @@ impl<'a> SnapshotStore<'a> {
             self.isolation_level,
         );
         reader.set_key_only(key_only);
+        reader.set_bypass_locks(self.bypass_locks.clone());
         Ok(StoreScanner {
             reader: reader,
             start_ts: self.start_ts,
@@ -383,4 +395,66 @@ mod test {
         let expect = Some((make_key(expect_key.as_bytes()), expect_value as Value));
         assert_eq!(result, expect, "expect {:?}, but got {:?}", expect, result);
     }
+
+    #[test]
+    fn test_snapshot_store_read_committed() {
+        let key_num = 10;
+        let mut store = TestStore::new(key_num);
+        let pk = format!("{}{}", KEY_PREFIX, START_ID);
+        let lock_ts = COMMIT_TS + 10;
+        {
+            let mut statistics = Statistics::default();
+            let mut txn = MvccTxn::new(
+                store.snapshot.as_ref(),
+                &mut statistics,
+                lock_ts,
+                None,
+                IsolationLevel::SI,
+                true,
+            );
+            for key in &store.keys {
+                let key = key.as_bytes();
+                txn.prewrite(Mutation::Delete(make_key(key)), pk.as_bytes(), &Options::default())
+                    .unwrap();
+            }
+            store.engine.write(&store.ctx, txn.modifies()).unwrap();
+        }
+        store.refresh_snapshot();
+
+        let mut statistics = Statistics::default();
+        let si_store = SnapshotStore::new(
+            store.snapshot.as_ref(),
+            lock_ts + 1,
+            IsolationLevel::SI,
+            true,
+        );
+        assert!(si_store.get(&make_key(pk.as_bytes()), &mut statistics).is_err());
+        let mut scanner = si_store
+            .scanner(ScanMode::Forward, false, None, &mut statistics)
+            .unwrap();
+        let result = scanner.scan(make_key(b""), 1).unwrap();
+        assert!(result[0].is_err());
+
+        // The reads of read committed ignore the locks.
+        let mut statistics = Statistics::default();
+        let rc_store = SnapshotStore::new(
+            store.snapshot.as_ref(),
+            lock_ts + 1,
+            IsolationLevel::RC,
+            true,
+        );
+        let data = rc_store.get(&make_key(pk.as_bytes()), &mut statistics).unwrap();
+        assert_eq!(data, Some(pk.clone().into_bytes()));
+        let mut scanner = rc_store
+            .scanner(ScanMode::Forward, false, None, &mut statistics)
+            .unwrap();
+        let result = scanner.scan(make_key(b""), key_num as usize).unwrap();
+        let result: Vec<Option<KvPair>> = result.into_iter().map(Result::ok).collect();
+        let expect: Vec<Option<KvPair>> = store
+            .keys
+            .iter()
+            .map(|k| Some((k.clone().into_bytes(), k.clone().into_bytes())))
+            .collect();
+        assert_eq!(result, expect);
+    }
 }
@@ -0,0 +1,278 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::boxed::FnBox;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher, SipHasher as DefaultHasher};
+use std::mem;
+use std::time::{Duration, Instant};
+
+use rand::{self, Rng};
+
+use storage::Config;
+use util::collections::HashMap;
+use util::time::duration_to_ms;
+
+/// How long the writes wait for the conflicting locks to be released.
+#[derive(Clone, Copy, Debug)]
+pub struct WaitPolicy {
+    // 0 disables waiting.
+    pub timeout: Duration,
+    pub wake_up_delay: Duration,
+    pub max_waiters_per_key: usize,
+}
+
+impl WaitPolicy {
+    pub fn new(cfg: &Config) -> WaitPolicy {
+        WaitPolicy {
+            timeout: cfg.wait_for_lock_timeout.0,
+            wake_up_delay: cfg.wake_up_delay_duration.0,
+            max_waiters_per_key: cfg.max_waiters_per_key,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.timeout > Duration::from_secs(0)
+    }
+}
+
+/// Called with the hash of a key in the deadlock if a wait causes one.
+pub type DeadlockCallback = Box<FnBox(u64) + Send>;
+
+/// `DeadlockDetector` finds the deadlocks among the transactions waiting for
+/// the locks of each other, possibly on the other stores.
+pub trait DeadlockDetector: Send + Sync {
+    /// `txn_ts` starts to wait for the lock of `lock_ts` on the key of
+    /// `key_hash`, `cb` is called if it causes a deadlock.
+    fn detect(&self, txn_ts: u64, lock_ts: u64, key_hash: u64, cb: DeadlockCallback);
+
+    /// `txn_ts` doesn't wait for the lock any more.
+    fn clean_up_wait_for(&self, txn_ts: u64, lock_ts: u64, key_hash: u64);
+
+    /// `txn_ts` is finished, it doesn't wait for any lock.
+    fn clean_up(&self, txn_ts: u64);
+}
+
+/// The hash identifying a key in the deadlock detection.
+pub fn key_hash(key: &[u8]) -> u64 {
+    let mut s = DefaultHasher::new();
+    key.hash(&mut s);
+    s.finish()
+}
+
+struct Waiter<T> {
+    task: T,
+    deadline: Instant,
+    // Set when the lock is released but the waiter is delayed.
+    wake_up_at: Option<Instant>,
+}
+
+/// `WaiterManager` keeps the tasks waiting for the locks on keys. When a lock
+/// is released, the first waiter of the key is woken up at once, the others
+/// are woken up after the wake-up delay and a random jitter, so they don't
+/// conflict with the first one and each other again. Waiters which can't be
+/// woken up before their deadlines time out.
+pub struct WaiterManager<T> {
+    policy: WaitPolicy,
+    waiters: HashMap<Vec<u8>, VecDeque<Waiter<T>>>,
+    count: usize,
+}
+
+impl<T> WaiterManager<T> {
+    pub fn new(policy: WaitPolicy) -> WaiterManager<T> {
+        WaiterManager {
+            policy: policy,
+            waiters: HashMap::default(),
+            count: 0,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.policy.enabled()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Makes `task` wait for the lock on `key`, `waited` is how long the task
+    /// has waited before, which is counted in the timeout. Returns the task
+    /// back if it can't wait any more or there are too many waiters.
+    pub fn wait_for(&mut self, key: Vec<u8>, task: T, waited: Duration) -> Result<(), T> {
+        if !self.enabled() || waited >= self.policy.timeout {
+            return Err(task);
+        }
+        let queue = self.waiters.entry(key).or_insert_with(VecDeque::new);
+        if queue.len() >= self.policy.max_waiters_per_key {
+            return Err(task);
+        }
+        queue.push_back(Waiter {
+            task: task,
+            deadline: Instant::now() + (self.policy.timeout - waited),
+            wake_up_at: None,
+        });
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Notifies that the lock on `key` is released, returns the task to be
+    /// woken up at once.
+    pub fn notify(&mut self, key: &[u8]) -> Option<T> {
+        let (first, empty) = match self.waiters.get_mut(key) {
+            None => return None,
+            Some(queue) => {
+                let first = queue.pop_front();
+                let delay = duration_to_ms(self.policy.wake_up_delay);
+                let mut rng = rand::thread_rng();
+                for w in queue.iter_mut().filter(|w| w.wake_up_at.is_none()) {
+                    let jitter = rng.gen_range(0, delay + 1);
+                    w.wake_up_at = Some(Instant::now() + Duration::from_millis(delay + jitter));
+                }
+                (first, queue.is_empty())
+            }
+        };
+        if empty {
+            self.waiters.remove(key);
+        }
+        if first.is_some() {
+            self.count -= 1;
+        }
+        first.map(|w| w.task)
+    }
+
+    /// Takes the first waiter of `key` matching `f` out, it's finished by the
+    /// caller instead of waiting any more.
+    pub fn remove<F: Fn(&T) -> bool>(&mut self, key: &[u8], f: F) -> Option<T> {
+        let (task, empty) = match self.waiters.get_mut(key) {
+            None => return None,
+            Some(queue) => {
+                let task = queue
+                    .iter()
+                    .position(|w| f(&w.task))
+                    .and_then(|i| queue.remove(i))
+                    .map(|w| w.task);
+                (task, queue.is_empty())
+            }
+        };
+        if empty {
+            self.waiters.remove(key);
+        }
+        if task.is_some() {
+            self.count -= 1;
+        }
+        task
+    }
+
+    /// Takes the waiters which should be woken up and which time out by `now`.
+    pub fn poll(&mut self, now: Instant) -> (Vec<T>, Vec<T>) {
+        let (mut woken, mut timed_out) = (vec![], vec![]);
+        for queue in self.waiters.values_mut() {
+            for w in mem::replace(queue, VecDeque::new()) {
+                if w.wake_up_at.map_or(false, |t| t <= now) {
+                    woken.push(w.task);
+                } else if w.deadline <= now {
+                    timed_out.push(w.task);
+                } else {
+                    queue.push_back(w);
+                }
+            }
+        }
+        self.waiters.retain(|_, queue| !queue.is_empty());
+        self.count -= woken.len() + timed_out.len();
+        (woken, timed_out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn new_policy(timeout_ms: u64, delay_ms: u64, max_waiters: usize) -> WaitPolicy {
+        WaitPolicy {
+            timeout: Duration::from_millis(timeout_ms),
+            wake_up_delay: Duration::from_millis(delay_ms),
+            max_waiters_per_key: max_waiters,
+        }
+    }
+
+    #[test]
+    fn test_wait_for() {
+        let mut mgr = WaiterManager::new(new_policy(0, 0, 10));
+        assert_eq!(mgr.wait_for(b"k".to_vec(), 1, Duration::from_secs(0)), Err(1));
+
+        let mut mgr = WaiterManager::new(new_policy(1000, 0, 2));
+        assert_eq!(mgr.wait_for(b"k".to_vec(), 1, Duration::from_secs(1)), Err(1));
+        mgr.wait_for(b"k".to_vec(), 1, Duration::from_secs(0)).unwrap();
+        mgr.wait_for(b"k".to_vec(), 2, Duration::from_secs(0)).unwrap();
+        assert_eq!(mgr.wait_for(b"k".to_vec(), 3, Duration::from_secs(0)), Err(3));
+        mgr.wait_for(b"k2".to_vec(), 3, Duration::from_secs(0)).unwrap();
+        assert_eq!(mgr.len(), 3);
+
+        assert_eq!(mgr.notify(b"k3"), None);
+        assert_eq!(mgr.notify(b"k2"), Some(3));
+        assert_eq!(mgr.notify(b"k"), Some(1));
+        assert_eq!(mgr.len(), 1);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut mgr = WaiterManager::new(new_policy(1000, 0, 10));
+        for i in 0..3 {
+            mgr.wait_for(b"k".to_vec(), i, Duration::from_secs(0)).unwrap();
+        }
+        assert_eq!(mgr.remove(b"k2", |_| true), None);
+        assert_eq!(mgr.remove(b"k", |t| *t == 3), None);
+        assert_eq!(mgr.remove(b"k", |t| *t == 1), Some(1));
+        assert_eq!(mgr.len(), 2);
+        assert_eq!(mgr.notify(b"k"), Some(0));
+        assert_eq!(mgr.remove(b"k", |_| true), Some(2));
+        assert!(mgr.is_empty());
+    }
+
+    #[test]
+    fn test_wake_up_delay() {
+        let mut mgr = WaiterManager::new(new_policy(10000, 50, 10));
+        for i in 0..3 {
+            mgr.wait_for(b"k".to_vec(), i, Duration::from_secs(0)).unwrap();
+        }
+        assert_eq!(mgr.notify(b"k"), Some(0));
+        // The others are delayed.
+        let (woken, timed_out) = mgr.poll(Instant::now());
+        assert!(woken.is_empty() && timed_out.is_empty());
+        thread::sleep(Duration::from_millis(100));
+        let (mut woken, timed_out) = mgr.poll(Instant::now());
+        woken.sort();
+        assert_eq!(woken, vec![1, 2]);
+        assert!(timed_out.is_empty());
+        assert!(mgr.is_empty());
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let mut mgr = WaiterManager::new(new_policy(50, 0, 10));
+        mgr.wait_for(b"k".to_vec(), 1, Duration::from_millis(30)).unwrap();
+        mgr.wait_for(b"k".to_vec(), 2, Duration::from_secs(0)).unwrap();
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(mgr.poll(Instant::now()), (vec![], vec![1]));
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(mgr.poll(Instant::now()), (vec![], vec![2]));
+        assert!(mgr.is_empty());
+        assert_eq!(mgr.notify(b"k"), None);
+    }
+}
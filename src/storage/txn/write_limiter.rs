@@ -0,0 +1,96 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// `WriteLimiter` caps the bytes per second written by the foreground
+/// commands of the store. The writes are throttled on the scheduler workers
+/// before they are proposed, so when the limit is reached, the pending writes
+/// pile up in the scheduler and the new ones are rejected as busy.
+pub struct WriteLimiter {
+    // 0 means no limit.
+    bytes_per_sec: AtomicUsize,
+    // The time when the bytes written so far are paid off.
+    next_free: Mutex<Instant>,
+}
+
+impl WriteLimiter {
+    pub fn new(bytes_per_sec: u64) -> WriteLimiter {
+        WriteLimiter {
+            bytes_per_sec: AtomicUsize::new(bytes_per_sec as usize),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec.load(Ordering::Relaxed) as u64
+    }
+
+    pub fn set_bytes_per_sec(&self, bytes_per_sec: u64) {
+        info!(
+            "scheduler write bandwidth limit is changed to {} bytes/s",
+            bytes_per_sec
+        );
+        self.bytes_per_sec
+            .store(bytes_per_sec as usize, Ordering::Relaxed);
+    }
+
+    /// Records that `bytes` are going to be written, and blocks until the
+    /// bytes written before are paid off. Returns the time blocked.
+    pub fn consume(&self, bytes: u64) -> Duration {
+        let limit = self.bytes_per_sec();
+        if limit == 0 || bytes == 0 {
+            return Duration::from_secs(0);
+        }
+        let secs = bytes as f64 / limit as f64;
+        let cost = Duration::new(secs as u64, (secs.fract() * 1e9) as u32);
+        let wait = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let now = Instant::now();
+            if *next_free < now {
+                *next_free = now;
+            }
+            let wait = *next_free - now;
+            *next_free += cost;
+            wait
+        };
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_limiter() {
+        let limiter = WriteLimiter::new(0);
+        assert_eq!(limiter.consume(1024 * 1024), Duration::from_secs(0));
+
+        limiter.set_bytes_per_sec(1024 * 1024);
+        let start = Instant::now();
+        let mut throttled = Duration::from_secs(0);
+        for _ in 0..3 {
+            throttled += limiter.consume(100 * 1024);
+        }
+        // The first write is free, the following two wait about 100ms each.
+        assert!(throttled >= Duration::from_millis(180));
+        assert!(start.elapsed() >= throttled);
+    }
+}
@@ -43,6 +43,15 @@ pub struct MvccInfo {
     pub values: Vec<(u64, bool, Value)>,
 }
 
+/// The status of the secondary keys of an async commit transaction.
+#[derive(Debug, PartialEq)]
+pub enum SecondaryLocksStatus {
+    /// All the keys are locked by the transaction.
+    Locked(Vec<(Key, Lock)>),
+    Committed(u64),
+    RolledBack,
+}
+
 /// Key type.
 ///
 /// Keys have 2 types of binary representation - raw and encoded. The raw
@@ -537,7 +537,7 @@ impl<'de> Deserialize<'de> for ReadableDuration {
             type Value = ReadableDuration;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("valid duration")
+                formatter.write_str("valid duration like \"30s\"")
             }
 
             fn visit_str<E>(self, dur_str: &str) -> Result<ReadableDuration, E>
@@ -0,0 +1,217 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use toml;
+
+use util::collections::HashMap;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        UnknownModule(module: String) {
+            description("unknown config module")
+            display("unknown config module {:?}", module)
+        }
+        Invalid(msg: String) {
+            description(msg)
+            display("{}", msg)
+        }
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description(err.description())
+        }
+        Format(msg: String) {
+            description(msg)
+            display("bad last config: {}", msg)
+        }
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// The config changes applied at runtime, by module and then by name.
+pub type ConfigChanges = BTreeMap<String, BTreeMap<String, String>>;
+
+/// `ConfigHandler` applies config changes to a running component.
+pub trait ConfigHandler: Send + Sync {
+    /// Validates `value` and applies it to the config `name`, the component
+    /// should be left unchanged on errors.
+    fn update(&self, name: &str, value: &str) -> ::std::result::Result<(), String>;
+}
+
+impl<T: ConfigHandler + ?Sized> ConfigHandler for Arc<T> {
+    fn update(&self, name: &str, value: &str) -> ::std::result::Result<(), String> {
+        (**self).update(name, value)
+    }
+}
+
+/// `ConfigManager` dispatches config changes to the handlers registered by
+/// modules. The applied changes are persisted to the last config file once
+/// it's loaded, so they are kept across restarts.
+#[derive(Default)]
+pub struct ConfigManager {
+    handlers: RwLock<HashMap<String, Box<ConfigHandler>>>,
+    changes: Mutex<ConfigChanges>,
+    path: Mutex<Option<PathBuf>>,
+}
+
+impl ConfigManager {
+    pub fn new() -> ConfigManager {
+        ConfigManager::default()
+    }
+
+    pub fn register(&self, module: &str, handler: Box<ConfigHandler>) {
+        let mut handlers = self.handlers.write().unwrap();
+        if handlers.insert(module.to_owned(), handler).is_some() {
+            warn!("config handler of {} is replaced", module);
+        }
+    }
+
+    /// Applies a change to `module`, and persists it if the last config file
+    /// is loaded.
+    pub fn update(&self, module: &str, name: &str, value: &str) -> Result<()> {
+        try!(self.apply(module, name, value));
+        info!("config {}.{} is changed to {:?}", module, name, value);
+        let mut changes = self.changes.lock().unwrap();
+        changes
+            .entry(module.to_owned())
+            .or_insert_with(BTreeMap::new)
+            .insert(name.to_owned(), value.to_owned());
+        if let Some(ref path) = *self.path.lock().unwrap() {
+            try!(persist(path, &changes));
+        }
+        Ok(())
+    }
+
+    fn apply(&self, module: &str, name: &str, value: &str) -> Result<()> {
+        let handlers = self.handlers.read().unwrap();
+        match handlers.get(module) {
+            Some(handler) => handler.update(name, value).map_err(Error::Invalid),
+            None => Err(Error::UnknownModule(module.to_owned())),
+        }
+    }
+
+    /// Gets the changes applied since started, including the loaded ones.
+    pub fn changes(&self) -> ConfigChanges {
+        self.changes.lock().unwrap().clone()
+    }
+
+    /// Applies the changes in the last config file at `path` if it exists,
+    /// and persists the following changes to it. Changes which can't be
+    /// applied any more are dropped.
+    pub fn load_last_config<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let last = if path.exists() {
+            let mut content = String::new();
+            try!(File::open(path).and_then(|mut f| f.read_to_string(&mut content)));
+            try!(
+                toml::from_str::<ConfigChanges>(&content)
+                    .map_err(|e| Error::Format(e.to_string()))
+            )
+        } else {
+            ConfigChanges::new()
+        };
+        let mut changes = self.changes.lock().unwrap();
+        for (module, configs) in last {
+            for (name, value) in configs {
+                if let Err(e) = self.apply(&module, &name, &value) {
+                    warn!("failed to apply last config {}.{}: {}", module, name, e);
+                    continue;
+                }
+                info!("last config {}.{} = {:?} is applied", module, name, value);
+                changes
+                    .entry(module.clone())
+                    .or_insert_with(BTreeMap::new)
+                    .insert(name, value);
+            }
+        }
+        try!(persist(path, &changes));
+        *self.path.lock().unwrap() = Some(path.to_owned());
+        Ok(())
+    }
+}
+
+// Writes to a temporary file first, so the last config file is never partial.
+fn persist(path: &Path, changes: &ConfigChanges) -> Result<()> {
+    let content = try!(toml::to_string(changes).map_err(|e| Error::Format(e.to_string())));
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut f = try!(File::create(&tmp_path));
+        try!(f.write_all(content.as_bytes()));
+        try!(f.sync_all());
+    }
+    try!(fs::rename(&tmp_path, path));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    struct CountHandler {
+        count: AtomicUsize,
+    }
+
+    impl ConfigHandler for CountHandler {
+        fn update(&self, name: &str, value: &str) -> ::std::result::Result<(), String> {
+            if name != "count" {
+                return Err(format!("unknown config {}", name));
+            }
+            let count = try!(value.parse().map_err(|e| format!("{:?}", e)));
+            self.count.store(count, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_config_manager() {
+        let dir = TempDir::new("test_config_manager").unwrap();
+        let path = dir.path().join("last_tikv.toml");
+        let handler = Arc::new(CountHandler {
+            count: AtomicUsize::new(0),
+        });
+
+        let manager = ConfigManager::new();
+        manager.register("test", box handler.clone());
+        assert!(manager.update("unknown", "count", "1").is_err());
+        assert!(manager.update("test", "count", "x").is_err());
+        assert!(manager.changes().is_empty());
+        manager.update("test", "count", "1").unwrap();
+        assert_eq!(handler.count.load(Ordering::SeqCst), 1);
+        // Not persisted before the last config is loaded.
+        assert!(!path.exists());
+
+        manager.load_last_config(&path).unwrap();
+        assert!(path.exists());
+        manager.update("test", "count", "2").unwrap();
+
+        // Restarts.
+        handler.count.store(0, Ordering::SeqCst);
+        let manager = ConfigManager::new();
+        manager.register("test", box handler.clone());
+        manager.load_last_config(&path).unwrap();
+        assert_eq!(handler.count.load(Ordering::SeqCst), 2);
+        assert_eq!(manager.changes()["test"]["count"], "2");
+    }
+}
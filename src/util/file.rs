@@ -11,10 +11,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::{self, ErrorKind};
-use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::fs::{self, OpenOptions};
 use std::path::{Path, PathBuf};
 
+use crc::crc32::{self, Digest, Hasher32};
+
+const DIGEST_BUFFER_SIZE: usize = 10240;
+
 pub fn get_file_size(path: &PathBuf) -> io::Result<u64> {
     let meta = try!(fs::metadata(path));
     Ok(meta.len())
@@ -35,6 +39,24 @@ pub fn delete_file_if_exist(file: &PathBuf) {
     }
 }
 
+pub fn calc_crc32(path: &PathBuf) -> io::Result<u32> {
+    let mut digest = Digest::new(crc32::IEEE);
+    let mut f = try!(OpenOptions::new().read(true).open(path));
+    let mut buf = vec![0; DIGEST_BUFFER_SIZE];
+    loop {
+        match f.read(&mut buf[..]) {
+            Ok(0) => {
+                return Ok(digest.sum32());
+            }
+            Ok(n) => {
+                digest.write(&buf[..n]);
+            }
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::io::Write;
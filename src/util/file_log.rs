@@ -15,10 +15,13 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use time::{self, Timespec, Tm};
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Write};
-use std::fmt::Arguments;
+use std::fmt::{self, Arguments};
 use std::path::Path;
 use std::sync::Mutex;
 
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
 use super::logger::LogWriter;
 
 const ONE_DAY_SECONDS: u64 = 60 * 60 * 24;
@@ -57,14 +60,63 @@ fn open_log_file(path: &str) -> io::Result<File> {
     OpenOptions::new().append(true).create(true).open(path)
 }
 
+// Compresses the file by gzip, the compressed file is named with a ".gz"
+// suffix and the original one is removed.
+fn compress_file(path: &str) -> io::Result<()> {
+    let mut src = try!(File::open(path));
+    let dst = try!(File::create(format!("{}.gz", path)));
+    let mut encoder = GzEncoder::new(dst, Compression::Default);
+    try!(io::copy(&mut src, &mut encoder));
+    try!(try!(encoder.finish()).sync_all());
+    fs::remove_file(path)
+}
+
+// Removes the oldest rotated files of `path` until at most `max_backups` are
+// left.
+fn purge_rotated_files(path: &str, max_backups: usize) -> io::Result<()> {
+    let p = Path::new(path);
+    let prefix = format!("{}.", p.file_name().unwrap().to_str().unwrap());
+    let mut rotated = vec![];
+    for entry in try!(fs::read_dir(p.parent().unwrap())) {
+        let entry = try!(entry);
+        let name = entry.file_name().into_string().unwrap_or_default();
+        if name.starts_with(&prefix) {
+            let modified = try!(try!(entry.metadata()).modified());
+            rotated.push((modified, name));
+        }
+    }
+    if rotated.len() <= max_backups {
+        return Ok(());
+    }
+    rotated.sort();
+    let n = rotated.len() - max_backups;
+    for &(_, ref name) in &rotated[..n] {
+        try!(fs::remove_file(p.with_file_name(name)));
+    }
+    Ok(())
+}
+
+/// Rotation policies of the log file besides the daily rotation.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RotationConfig {
+    /// Rotates the file once it grows larger than `max_size`, 0 means no limit.
+    pub max_size: u64,
+    /// Compresses the rotated files by gzip.
+    pub compress: bool,
+    /// The number of rotated files to keep, 0 means all of them are kept.
+    pub max_backups: usize,
+}
+
 struct RotatingFileLoggerCore {
     rollover_time: Tm,
     file_path: String,
     file: File,
+    file_size: u64,
+    rotation: RotationConfig,
 }
 
 impl RotatingFileLoggerCore {
-    fn new(path: &str) -> io::Result<RotatingFileLoggerCore> {
+    fn new(path: &str, rotation: RotationConfig) -> io::Result<RotatingFileLoggerCore> {
         let file = try!(open_log_file(path));
         let file_attr = fs::metadata(path).unwrap();
         let file_modified_time = file_attr.modified().unwrap();
@@ -73,29 +125,58 @@ impl RotatingFileLoggerCore {
             rollover_time: rollover_time,
             file_path: path.to_string(),
             file: file,
+            file_size: file_attr.len(),
+            rotation: rotation,
         };
         Ok(ret)
     }
 
     fn open(&mut self) {
-        self.file = open_log_file(&self.file_path).unwrap()
+        self.file = open_log_file(&self.file_path).unwrap();
+        self.file_size = 0;
     }
 
     fn should_rollover(&mut self) -> bool {
         time::now() > self.rollover_time
     }
 
+    fn should_rollover_by_size(&self) -> bool {
+        self.rotation.max_size > 0 && self.file_size >= self.rotation.max_size
+    }
+
     fn do_rollover(&mut self) {
+        let suffix = time::strftime("%Y%m%d", &one_day_before(self.rollover_time)).unwrap();
+        self.rotate(&suffix);
+        self.update_rollover_time();
+    }
+
+    fn do_rollover_by_size(&mut self) {
+        let suffix = time::strftime("%Y%m%d%H%M%S", &time::now()).unwrap();
+        self.rotate(&suffix);
+    }
+
+    fn rotate(&mut self, suffix: &str) {
         self.close();
-        let mut s = self.file_path.clone();
-        s.push_str(".");
-        s.push_str(&time::strftime(
-            "%Y%m%d",
-            &one_day_before(self.rollover_time),
-        ).unwrap());
+        let mut s = format!("{}.{}", self.file_path, suffix);
+        // The file may be rotated by size several times in a second.
+        let mut i = 1;
+        while Path::new(&s).exists() || Path::new(&format!("{}.gz", s)).exists() {
+            s = format!("{}.{}.{}", self.file_path, suffix, i);
+            i += 1;
+        }
         fs::rename(&self.file_path, &s).unwrap();
-        self.update_rollover_time();
-        self.open()
+        self.open();
+
+        if self.rotation.compress {
+            if let Err(e) = compress_file(&s) {
+                let _ = writeln!(self.file, "failed to compress log file {}: {:?}", s, e);
+            }
+        }
+        if self.rotation.max_backups > 0 {
+            if let Err(e) = purge_rotated_files(&self.file_path, self.rotation.max_backups) {
+                let _ = writeln!(self.file, "failed to purge rotated log files: {:?}", e);
+            }
+        }
     }
 
     fn update_rollover_time(&mut self) {
@@ -108,14 +189,22 @@ impl RotatingFileLoggerCore {
     }
 }
 
-/// A log implemetation which writes to file and rotates by day.
+/// A log implemetation which writes to file and rotates by day, and by size if
+/// it's configured.
 pub struct RotatingFileLogger {
     core: Mutex<RotatingFileLoggerCore>,
 }
 
 impl RotatingFileLogger {
     pub fn new(file_path: &str) -> io::Result<RotatingFileLogger> {
-        let core = try!(RotatingFileLoggerCore::new(file_path));
+        RotatingFileLogger::with_rotation(file_path, RotationConfig::default())
+    }
+
+    pub fn with_rotation(
+        file_path: &str,
+        rotation: RotationConfig,
+    ) -> io::Result<RotatingFileLogger> {
+        let core = try!(RotatingFileLoggerCore::new(file_path, rotation));
         let ret = RotatingFileLogger {
             core: Mutex::new(core),
         };
@@ -128,8 +217,20 @@ impl LogWriter for RotatingFileLogger {
         let mut core = self.core.lock().unwrap();
         if core.should_rollover() {
             core.do_rollover()
-        };
-        let _ = core.file.write_fmt(args);
+        } else if core.should_rollover_by_size() {
+            core.do_rollover_by_size()
+        }
+        let s = fmt::format(args);
+        if core.file.write_all(s.as_bytes()).is_ok() {
+            core.file_size += s.len() as u64;
+        }
+    }
+
+    fn flush(&self) {
+        // The lock may be held by a panicked thread.
+        if let Ok(core) = self.core.try_lock() {
+            let _ = core.file.sync_all();
+        }
     }
 }
 
@@ -145,13 +246,13 @@ impl Drop for RotatingFileLogger {
 mod tests {
     use time::{self, Timespec};
     use std::io::prelude::*;
-    use std::fs::OpenOptions;
+    use std::fs::{self, OpenOptions};
     use std::path::Path;
 
     use tempdir::TempDir;
     use utime;
 
-    use super::{RotatingFileLoggerCore, ONE_DAY_SECONDS};
+    use super::{RotatingFileLoggerCore, RotationConfig, ONE_DAY_SECONDS};
 
     #[test]
     fn test_one_day_before() {
@@ -188,7 +289,7 @@ mod tests {
         let time_in_sec = one_day_ago.sec as u64;
         utime::set_file_times(&log_file, time_in_sec, time_in_sec).unwrap();
         // initialize the logger
-        let mut core = RotatingFileLoggerCore::new(&log_file).unwrap();
+        let mut core = RotatingFileLoggerCore::new(&log_file, RotationConfig::default()).unwrap();
         assert!(core.should_rollover());
         core.do_rollover();
         // check the rotated file exist
@@ -200,4 +301,42 @@ mod tests {
         assert!(file_exists(&rotated_file));
         assert!(!core.should_rollover());
     }
+
+    #[test]
+    fn test_rotating_file_logger_by_size() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let log_file = tmp_dir
+            .path()
+            .join("test_rotating_file_logger_by_size.log")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let rotation = RotationConfig {
+            max_size: 10,
+            compress: true,
+            max_backups: 2,
+        };
+        let mut core = RotatingFileLoggerCore::new(&log_file, rotation).unwrap();
+        assert!(!core.should_rollover_by_size());
+        for _ in 0..3 {
+            core.file.write_all(b"hello world!").unwrap();
+            core.file_size += 12;
+            assert!(core.should_rollover_by_size());
+            core.do_rollover_by_size();
+            assert!(!core.should_rollover_by_size());
+        }
+
+        let mut rotated = vec![];
+        for entry in fs::read_dir(tmp_dir.path()).unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            if name != "test_rotating_file_logger_by_size.log" {
+                rotated.push(name);
+            }
+        }
+        // Only the latest 2 rotated files are kept, and they are compressed.
+        assert_eq!(rotated.len(), 2);
+        for name in rotated {
+            assert!(name.ends_with(".gz"), "{}", name);
+        }
+    }
 }
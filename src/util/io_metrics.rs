@@ -0,0 +1,163 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{self, Read, Write};
+
+use prometheus::CounterVec;
+
+lazy_static! {
+    pub static ref IO_BYTES_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_io_bytes_total",
+            "Total number of bytes read or written by io type",
+            &["type", "op"]
+        ).unwrap();
+
+    pub static ref IO_OPS_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_io_ops_total",
+            "Total number of io operations by io type",
+            &["type", "op"]
+        ).unwrap();
+}
+
+/// The purpose of an IO, so that a busy disk can be attributed to the
+/// responsible subsystem.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IOType {
+    ForegroundWrite,
+    Compaction,
+    RaftLog,
+    Snapshot,
+    Backup,
+}
+
+impl IOType {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            IOType::ForegroundWrite => "foreground_write",
+            IOType::Compaction => "compaction",
+            IOType::RaftLog => "raft_log",
+            IOType::Snapshot => "snapshot",
+            IOType::Backup => "backup",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IOOp {
+    Read,
+    Write,
+}
+
+impl IOOp {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            IOOp::Read => "read",
+            IOOp::Write => "write",
+        }
+    }
+}
+
+/// Records an IO operation of `bytes`.
+pub fn record_io(io_type: IOType, op: IOOp, bytes: u64) {
+    let labels = [io_type.as_str(), op.as_str()];
+    IO_OPS_VEC.with_label_values(&labels).inc();
+    if bytes > 0 {
+        IO_BYTES_VEC
+            .with_label_values(&labels)
+            .inc_by(bytes as f64)
+            .unwrap();
+    }
+}
+
+/// Records the bytes of IO which are not issued by TiKV directly, like the
+/// compactions of RocksDB, whose number of operations is unknown.
+pub fn record_io_bytes(io_type: IOType, op: IOOp, bytes: u64) {
+    if bytes > 0 {
+        IO_BYTES_VEC
+            .with_label_values(&[io_type.as_str(), op.as_str()])
+            .inc_by(bytes as f64)
+            .unwrap();
+    }
+}
+
+/// `IORecorder` wraps a reader or a writer, and records the IO on it with the
+/// given type.
+pub struct IORecorder<T> {
+    inner: T,
+    io_type: IOType,
+}
+
+impl<T> IORecorder<T> {
+    pub fn new(inner: T, io_type: IOType) -> IORecorder<T> {
+        IORecorder {
+            inner: inner,
+            io_type: io_type,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for IORecorder<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        record_io(self.io_type, IOOp::Read, n as u64);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for IORecorder<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        record_io(self.io_type, IOOp::Write, n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+
+    #[test]
+    fn test_io_recorder() {
+        let labels = [IOType::Snapshot.as_str(), IOOp::Write.as_str()];
+        let bytes = IO_BYTES_VEC.with_label_values(&labels).get();
+        let ops = IO_OPS_VEC.with_label_values(&labels).get();
+        let mut w = IORecorder::new(vec![], IOType::Snapshot);
+        w.write_all(b"abc").unwrap();
+        w.write_all(b"de").unwrap();
+        let content = w.into_inner();
+        assert_eq!(content, b"abcde");
+        // Other tests may record snapshot IO concurrently.
+        assert!(IO_BYTES_VEC.with_label_values(&labels).get() >= bytes + 5.0);
+        assert!(IO_OPS_VEC.with_label_values(&labels).get() >= ops + 2.0);
+
+        let labels = [IOType::Backup.as_str(), IOOp::Read.as_str()];
+        let bytes = IO_BYTES_VEC.with_label_values(&labels).get();
+        let mut r = IORecorder::new(content.as_slice(), IOType::Backup);
+        let mut buf = vec![];
+        r.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, content);
+        assert!(IO_BYTES_VEC.with_label_values(&labels).get() >= bytes + 5.0);
+    }
+}
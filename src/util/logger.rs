@@ -11,26 +11,106 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{cmp, fmt, thread};
 use std::io::{self, Write};
 use std::fmt::Arguments;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
 
 use time;
-use log::{self, Log, LogMetadata, LogRecord, SetLoggerError};
+use log::{self, Log, LogMetadata, LogRecord, MaxLogLevelFilter, SetLoggerError};
+use prometheus::Counter;
+use serde_json;
+
+use util::collections::HashMap;
 
 pub use log::LogLevelFilter;
 
 const ENABLED_TARGETS: &[&'static str] = &["tikv::", "tests::", "benches::"];
 
+/// The format of the logs.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    Text,
+    // One JSON object per line, with the fields like region id extracted
+    // from the message, so the logs can be indexed without parsing.
+    Json,
+}
+
+struct LogLevels {
+    // The max level of the log crate, logs above it are filtered out before
+    // they're formatted.
+    max_filter: Option<MaxLogLevelFilter>,
+    global: LogLevelFilter,
+    // The levels of modules, which are matched by the prefixes of the targets.
+    modules: HashMap<String, LogLevelFilter>,
+}
+
+impl LogLevels {
+    fn level_of(&self, target: &str) -> LogLevelFilter {
+        self.modules
+            .iter()
+            .filter(|&(m, _)| target.starts_with(m.as_str()))
+            .max_by_key(|&(m, _)| m.len())
+            .map_or(self.global, |(_, l)| *l)
+    }
+
+    fn set_level(&mut self, module: &str, level: LogLevelFilter) {
+        if module.is_empty() {
+            self.global = level;
+        } else {
+            self.modules.insert(module.to_owned(), level);
+        }
+        if let Some(ref filter) = self.max_filter {
+            let max = self.modules
+                .values()
+                .fold(self.global, |max, &l| cmp::max(max, l));
+            filter.set(max);
+        }
+    }
+}
+
+lazy_static! {
+    static ref LOG_LEVELS: RwLock<LogLevels> = RwLock::new(LogLevels {
+        max_filter: None,
+        global: LogLevelFilter::Info,
+        modules: HashMap::default(),
+    });
+}
+
+/// Changes the log level of `module` at runtime, or the global level if
+/// `module` is empty. The module is a prefix of the log targets, like
+/// `tikv::raftstore`.
+pub fn set_log_level(module: &str, level: LogLevelFilter) {
+    info!("log level of {:?} is changed to {}", module, level);
+    LOG_LEVELS.write().unwrap().set_level(module, level);
+}
+
 pub fn init_log<W: LogWriter + Sync + Send + 'static>(
     writer: W,
     level: LogLevelFilter,
+) -> Result<(), SetLoggerError> {
+    init_log_with_format(writer, level, LogFormat::Text)
+}
+
+pub fn init_log_with_format<W: LogWriter + Sync + Send + 'static>(
+    writer: W,
+    level: LogLevelFilter,
+    format: LogFormat,
 ) -> Result<(), SetLoggerError> {
     log::set_logger(|filter| {
         filter.set(level);
+        let mut levels = LOG_LEVELS.write().unwrap();
+        levels.global = level;
+        levels.max_filter = Some(filter);
+        let writer = Arc::new(writer);
+        *LOG_WRITER.lock().unwrap() = Some(writer.clone() as Arc<LogWriter + Sync + Send>);
         Box::new(Logger {
-            level: level,
             writer: writer,
             tikv_only: false,
+            format: format,
         })
     })
 }
@@ -41,27 +121,102 @@ pub fn init_log_for_tikv_only<W: LogWriter + Sync + Send + 'static>(
 ) -> Result<(), SetLoggerError> {
     log::set_logger(|filter| {
         filter.set(level);
+        let mut levels = LOG_LEVELS.write().unwrap();
+        levels.global = level;
+        levels.max_filter = Some(filter);
+        let writer = Arc::new(writer);
+        *LOG_WRITER.lock().unwrap() = Some(writer.clone() as Arc<LogWriter + Sync + Send>);
         Box::new(Logger {
-            level: level,
             writer: writer,
             tikv_only: true,
+            format: LogFormat::Text,
         })
     })
 }
 
 pub trait LogWriter {
     fn write(&self, args: Arguments);
+
+    /// Makes sure the logs written are persisted.
+    fn flush(&self) {}
+}
+
+lazy_static! {
+    // The writer of the global logger, so the logs can be flushed before the
+    // process exits.
+    static ref LOG_WRITER: Mutex<Option<Arc<LogWriter + Sync + Send>>> = Mutex::new(None);
+}
+
+/// Flushes the global logger. It's called on panic, so it gives up instead of
+/// blocking if the writer is in use.
+pub fn flush_log() {
+    if let Ok(writer) = LOG_WRITER.try_lock() {
+        if let Some(ref w) = *writer {
+            w.flush();
+        }
+    }
 }
 
 struct Logger<W: LogWriter> {
-    level: LogLevelFilter,
-    writer: W,
+    writer: Arc<W>,
     tikv_only: bool,
+    format: LogFormat,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Fields {
+    region_id: Option<u64>,
+    peer_id: Option<u64>,
+    store_id: Option<u64>,
+    // The id of the command in the scheduler.
+    cid: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    time: &'a str,
+    level: String,
+    module: &'a str,
+    file: &'a str,
+    line: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    region_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    store_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cid: Option<u64>,
+    message: &'a str,
+}
+
+// Parses the leading number of `s`.
+fn parse_id(s: &str) -> Option<u64> {
+    let end = s.find(|c: char| !c.is_digit(10)).unwrap_or_else(|| s.len());
+    s[..end].parse().ok()
+}
+
+// Extracts the fields from the tag like "[region 1] 2" at the beginning of the
+// message, and the ones like "cid: 3" in it.
+fn extract_fields(msg: &str) -> Fields {
+    let mut fields = Fields::default();
+    if msg.starts_with("[region ") {
+        fields.region_id = parse_id(&msg["[region ".len()..]);
+        if let Some(pos) = msg.find("] ") {
+            fields.peer_id = parse_id(&msg[pos + 2..]);
+        }
+    } else if msg.starts_with("[store ") {
+        fields.store_id = parse_id(&msg["[store ".len()..]);
+    }
+    if let Some(pos) = msg.find("cid: ") {
+        fields.cid = parse_id(&msg[pos + "cid: ".len()..]);
+    }
+    fields
 }
 
 impl<W: LogWriter + Sync + Send> Log for Logger<W> {
     fn enabled(&self, meta: &LogMetadata) -> bool {
-        meta.level() <= self.level
+        meta.level() <= LOG_LEVELS.read().unwrap().level_of(meta.target())
     }
 
     fn log(&self, record: &LogRecord) {
@@ -75,11 +230,30 @@ impl<W: LogWriter + Sync + Send> Log for Logger<W> {
         if self.enabled(record.metadata()) {
             let t = time::now();
             let time_str = time::strftime("%Y/%m/%d %H:%M:%S.%f", &t).unwrap();
-            // TODO allow formatter to be configurable.
+            let file = record.location().file().rsplit('/').nth(0).unwrap();
+            if self.format == LogFormat::Json {
+                let message = format!("{}", record.args());
+                let fields = extract_fields(&message);
+                let json = JsonRecord {
+                    time: &time_str[..time_str.len() - 6],
+                    level: format!("{}", record.level()),
+                    module: record.location().module_path(),
+                    file: file,
+                    line: record.location().line(),
+                    region_id: fields.region_id,
+                    peer_id: fields.peer_id,
+                    store_id: fields.store_id,
+                    cid: fields.cid,
+                    message: &message,
+                };
+                self.writer
+                    .write(format_args!("{}\n", serde_json::to_string(&json).unwrap()));
+                return;
+            }
             self.writer.write(format_args!(
                 "{} {}:{}: [{}] {}\n",
                 &time_str[..time_str.len() - 6],
-                record.location().file().rsplit('/').nth(0).unwrap(),
+                file,
                 record.location().line(),
                 record.level(),
                 record.args()
@@ -88,6 +262,86 @@ impl<W: LogWriter + Sync + Send> Log for Logger<W> {
     }
 }
 
+lazy_static! {
+    pub static ref LOG_DROPPED_COUNTER: Counter =
+        register_counter!(
+            "tikv_log_dropped_total",
+            "Total number of logs dropped because the log queue is full"
+        ).unwrap();
+}
+
+// How long to wait for the queued logs to be written when flushing.
+const ASYNC_LOG_FLUSH_TIMEOUT_MS: u64 = 3000;
+
+enum AsyncLogMsg {
+    Log(String),
+    Flush(mpsc::Sender<()>),
+}
+
+/// `AsyncLogWriter` writes the logs by the inner writer in a dedicated thread,
+/// so the threads logging won't be blocked by a stalled disk. The logs are
+/// queued in a bounded queue, and dropped if the queue is full.
+pub struct AsyncLogWriter {
+    sender: Mutex<SyncSender<AsyncLogMsg>>,
+}
+
+impl AsyncLogWriter {
+    pub fn new<W: LogWriter + Send + 'static>(writer: W, queue_size: usize) -> AsyncLogWriter {
+        let (tx, rx) = mpsc::sync_channel(queue_size);
+        thread::Builder::new()
+            .name(thd_name!("log-writer"))
+            .spawn(move || for msg in rx {
+                match msg {
+                    AsyncLogMsg::Log(s) => writer.write(format_args!("{}", s)),
+                    AsyncLogMsg::Flush(cb) => {
+                        writer.flush();
+                        let _ = cb.send(());
+                    }
+                }
+            })
+            .unwrap();
+        AsyncLogWriter {
+            sender: Mutex::new(tx),
+        }
+    }
+}
+
+impl LogWriter for AsyncLogWriter {
+    fn write(&self, args: Arguments) {
+        let msg = AsyncLogMsg::Log(fmt::format(args));
+        if let Err(TrySendError::Full(_)) = self.sender.lock().unwrap().try_send(msg) {
+            LOG_DROPPED_COUNTER.inc();
+        }
+    }
+
+    /// Waits until the queued logs are written and flushed, or timeout.
+    fn flush(&self) {
+        let deadline = Instant::now() + Duration::from_millis(ASYNC_LOG_FLUSH_TIMEOUT_MS);
+        let (tx, rx) = mpsc::channel();
+        let mut msg = AsyncLogMsg::Flush(tx);
+        loop {
+            // The lock may be held by a panicked thread.
+            let res = match self.sender.try_lock() {
+                Ok(sender) => sender.try_send(msg),
+                Err(_) => Err(TrySendError::Full(msg)),
+            };
+            match res {
+                Ok(()) => break,
+                Err(TrySendError::Full(m)) => msg = m,
+                Err(TrySendError::Disconnected(_)) => return,
+            }
+            if Instant::now() >= deadline {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let now = Instant::now();
+        if now < deadline {
+            let _ = rx.recv_timeout(deadline - now);
+        }
+    }
+}
+
 pub struct StderrLogger;
 
 impl LogWriter for StderrLogger {
@@ -95,6 +349,10 @@ impl LogWriter for StderrLogger {
     fn write(&self, args: Arguments) {
         let _ = io::stderr().write_fmt(args);
     }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
 }
 
 pub fn get_level_by_string(lv: &str) -> LogLevelFilter {
@@ -109,3 +367,81 @@ pub fn get_level_by_string(lv: &str) -> LogLevelFilter {
         _ => LogLevelFilter::Info,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Arguments;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    struct VecLogWriter(Arc<Mutex<Vec<String>>>);
+
+    impl LogWriter for VecLogWriter {
+        fn write(&self, args: Arguments) {
+            self.0.lock().unwrap().push(format!("{}", args));
+        }
+    }
+
+    #[test]
+    fn test_async_log_writer() {
+        let logs = Arc::new(Mutex::new(vec![]));
+        let writer = AsyncLogWriter::new(VecLogWriter(logs.clone()), 1024);
+        for i in 0..10 {
+            writer.write(format_args!("log {}", i));
+        }
+        writer.flush();
+        let expected: Vec<_> = (0..10).map(|i| format!("log {}", i)).collect();
+        assert_eq!(*logs.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_log_levels() {
+        let mut levels = LogLevels {
+            max_filter: None,
+            global: LogLevelFilter::Info,
+            modules: HashMap::default(),
+        };
+        assert_eq!(levels.level_of("tikv::raftstore::store"), LogLevelFilter::Info);
+
+        levels.set_level("tikv::raftstore", LogLevelFilter::Debug);
+        levels.set_level("tikv::raftstore::store::peer", LogLevelFilter::Trace);
+        levels.set_level("", LogLevelFilter::Warn);
+        assert_eq!(levels.level_of("tikv::storage"), LogLevelFilter::Warn);
+        assert_eq!(levels.level_of("tikv::raftstore::store"), LogLevelFilter::Debug);
+        assert_eq!(
+            levels.level_of("tikv::raftstore::store::peer"),
+            LogLevelFilter::Trace
+        );
+
+        levels.set_level("tikv::raftstore", LogLevelFilter::Warn);
+        assert_eq!(levels.level_of("tikv::raftstore::store"), LogLevelFilter::Warn);
+    }
+
+    #[test]
+    fn test_extract_fields() {
+        let cases = vec![
+            ("[region 1] 2 begin to apply snapshot", Some(1), Some(2), None, None),
+            ("[region 10] 20", Some(10), Some(20), None, None),
+            ("[store 3] starts with 1 regions", None, None, Some(3), None),
+            (
+                "[region 4] slow command, kind: write, type: prewrite, cid: 5, ts: 6",
+                Some(4),
+                None,
+                None,
+                Some(5),
+            ),
+            ("no fields", None, None, None, None),
+            ("[region x] 1", None, Some(1), None, None),
+        ];
+        for (msg, region_id, peer_id, store_id, cid) in cases {
+            let expect = Fields {
+                region_id: region_id,
+                peer_id: peer_id,
+                store_id: store_id,
+                cid: cid,
+            };
+            assert_eq!(extract_fields(msg), expect, "{}", msg);
+        }
+    }
+}
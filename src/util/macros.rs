@@ -216,3 +216,13 @@ macro_rules! try_opt {
         }
     });
 }
+
+/// A no-op stand-in for `fail::fail_point!` when the `failpoints` feature is
+/// disabled, so fail points cost nothing in normal builds.
+#[cfg(not(feature = "failpoints"))]
+#[macro_export]
+macro_rules! fail_point {
+    ($name:expr) => {{}};
+    ($name:expr, $e:expr) => {{}};
+    ($name:expr, $cond:expr, $e:expr) => {{}};
+}
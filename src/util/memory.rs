@@ -0,0 +1,240 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use prometheus::{Gauge, GaugeVec};
+
+lazy_static! {
+    pub static ref MEMORY_USAGE_GAUGE_VEC: GaugeVec =
+        register_gauge_vec!(
+            "tikv_memory_usage_bytes",
+            "Bytes of memory used by components",
+            &["component"]
+        ).unwrap();
+
+    pub static ref MEMORY_ROOT: Arc<MemoryTracker> = MemoryTracker::new_root("total");
+    pub static ref RAFT_ENTRIES_MEMORY: Arc<MemoryTracker> =
+        MemoryTracker::new_child(&MEMORY_ROOT, "raft_entries");
+    pub static ref APPLY_MEMORY: Arc<MemoryTracker> =
+        MemoryTracker::new_child(&MEMORY_ROOT, "apply");
+    pub static ref COPROCESSOR_MEMORY: Arc<MemoryTracker> =
+        MemoryTracker::new_child(&MEMORY_ROOT, "coprocessor");
+    pub static ref GRPC_MEMORY: Arc<MemoryTracker> =
+        MemoryTracker::new_child(&MEMORY_ROOT, "grpc");
+    pub static ref BLOCK_CACHE_MEMORY: Arc<MemoryTracker> =
+        MemoryTracker::new_child(&MEMORY_ROOT, "block_cache");
+}
+
+// 0 means no limit.
+static SOFT_LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the soft limit of the memory tracked by `MEMORY_ROOT`, new expensive
+/// work should be rejected once it's exceeded. 0 disables the limit.
+pub fn set_soft_limit(limit: usize) {
+    SOFT_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+pub fn soft_limit() -> usize {
+    SOFT_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Checks whether the tracked memory exceeds the soft limit.
+pub fn is_exceeded() -> bool {
+    exceeds(&MEMORY_ROOT, soft_limit())
+}
+
+fn exceeds(tracker: &MemoryTracker, limit: usize) -> bool {
+    limit > 0 && tracker.consumed() > limit
+}
+
+/// `MemoryTracker` counts the memory used by a component. The memory consumed
+/// by a tracker is also counted by all its ancestors.
+pub struct MemoryTracker {
+    label: &'static str,
+    consumed: AtomicUsize,
+    parent: Option<Arc<MemoryTracker>>,
+    gauge: Gauge,
+}
+
+impl MemoryTracker {
+    pub fn new_root(label: &'static str) -> Arc<MemoryTracker> {
+        Arc::new(MemoryTracker::new(label, None))
+    }
+
+    pub fn new_child(parent: &Arc<MemoryTracker>, label: &'static str) -> Arc<MemoryTracker> {
+        Arc::new(MemoryTracker::new(label, Some(parent.clone())))
+    }
+
+    fn new(label: &'static str, parent: Option<Arc<MemoryTracker>>) -> MemoryTracker {
+        MemoryTracker {
+            label: label,
+            consumed: AtomicUsize::new(0),
+            parent: parent,
+            gauge: MEMORY_USAGE_GAUGE_VEC.with_label_values(&[label]),
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        self.label
+    }
+
+    pub fn consumed(&self) -> usize {
+        self.consumed.load(Ordering::Relaxed)
+    }
+
+    pub fn consume(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        let prev = self.consumed.fetch_add(bytes, Ordering::Relaxed);
+        self.gauge.set((prev + bytes) as f64);
+        if let Some(ref parent) = self.parent {
+            parent.consume(bytes);
+        }
+    }
+
+    pub fn release(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        let prev = self.consumed.fetch_sub(bytes, Ordering::Relaxed);
+        assert!(
+            prev >= bytes,
+            "{} releases {} bytes but only {} consumed",
+            self.label,
+            bytes,
+            prev
+        );
+        self.gauge.set((prev - bytes) as f64);
+        if let Some(ref parent) = self.parent {
+            parent.release(bytes);
+        }
+    }
+
+    /// Sets the consumed memory directly, it's used for the components whose
+    /// usage is sampled instead of counted, like the block cache.
+    pub fn set(&self, bytes: usize) {
+        let prev = self.consumed();
+        if bytes > prev {
+            self.consume(bytes - prev);
+        } else {
+            self.release(prev - bytes);
+        }
+    }
+}
+
+/// `MemoryTrace` consumes memory from a tracker and releases all of it when
+/// dropped, so the memory won't leak on early returns.
+pub struct MemoryTrace {
+    tracker: Arc<MemoryTracker>,
+    consumed: usize,
+}
+
+impl MemoryTrace {
+    pub fn new(tracker: &Arc<MemoryTracker>) -> MemoryTrace {
+        MemoryTrace {
+            tracker: tracker.clone(),
+            consumed: 0,
+        }
+    }
+
+    pub fn consume(&mut self, bytes: usize) {
+        self.tracker.consume(bytes);
+        self.consumed += bytes;
+    }
+
+    pub fn release(&mut self, bytes: usize) {
+        let bytes = ::std::cmp::min(bytes, self.consumed);
+        self.tracker.release(bytes);
+        self.consumed -= bytes;
+    }
+
+    pub fn set(&mut self, bytes: usize) {
+        if bytes > self.consumed {
+            let delta = bytes - self.consumed;
+            self.consume(delta);
+        } else {
+            let delta = self.consumed - bytes;
+            self.release(delta);
+        }
+    }
+
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+}
+
+impl Drop for MemoryTrace {
+    fn drop(&mut self) {
+        self.tracker.release(self.consumed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::usize;
+
+    use super::*;
+
+    #[test]
+    fn test_memory_tracker() {
+        let root = MemoryTracker::new_root("test_root");
+        let a = MemoryTracker::new_child(&root, "test_a");
+        let b = MemoryTracker::new_child(&root, "test_b");
+
+        a.consume(10);
+        b.consume(20);
+        assert_eq!(a.consumed(), 10);
+        assert_eq!(b.consumed(), 20);
+        assert_eq!(root.consumed(), 30);
+        assert_eq!(
+            MEMORY_USAGE_GAUGE_VEC.with_label_values(&["test_root"]).get(),
+            30.0
+        );
+
+        a.release(5);
+        assert_eq!(root.consumed(), 25);
+        b.set(5);
+        assert_eq!(b.consumed(), 5);
+        assert_eq!(root.consumed(), 10);
+        b.set(15);
+        assert_eq!(root.consumed(), 20);
+
+        {
+            let mut trace = MemoryTrace::new(&a);
+            trace.consume(100);
+            assert_eq!(root.consumed(), 120);
+            trace.release(200);
+            assert_eq!(trace.consumed(), 0);
+            trace.consume(50);
+            trace.set(30);
+            assert_eq!(a.consumed(), 35);
+        }
+        assert_eq!(a.consumed(), 5);
+        assert_eq!(root.consumed(), 20);
+    }
+
+    #[test]
+    fn test_soft_limit() {
+        let root = MemoryTracker::new_root("test_limit_root");
+        let child = MemoryTracker::new_child(&root, "test_limit_child");
+        let mut trace = MemoryTrace::new(&child);
+        trace.consume(1024);
+        assert!(!exceeds(&root, 0));
+        assert!(exceeds(&root, 1023));
+        assert!(!exceeds(&root, 1024));
+        assert!(!exceeds(&root, usize::MAX));
+    }
+}
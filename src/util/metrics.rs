@@ -11,7 +11,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use prometheus::CounterVec;
+use prometheus::{self, CounterVec, Encoder, TextEncoder};
 
 lazy_static! {
     pub static ref CHANNEL_FULL_COUNTER_VEC: CounterVec =
@@ -20,4 +20,20 @@ lazy_static! {
             "Total number of channel full errors.",
             &["type"]
         ).unwrap();
+
+    pub static ref REQUEST_ERROR_COUNTER_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_request_error_total",
+            "Total number of request failures by the module and the error code.",
+            &["module", "code"]
+        ).unwrap();
+}
+
+/// Dumps all registered metrics in the prometheus text format.
+pub fn dump() -> String {
+    let mut buffer = vec![];
+    let metric_families = prometheus::gather();
+    let encoder = TextEncoder::new();
+    encoder.encode(&metric_families, &mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
 }
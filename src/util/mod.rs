@@ -26,6 +26,8 @@ use prometheus;
 use rand::{self, ThreadRng};
 use protobuf::Message;
 
+use self::collections::HashMap;
+
 #[macro_use]
 pub mod macros;
 pub mod logger;
@@ -42,8 +44,13 @@ pub mod metrics;
 pub mod threadpool;
 pub mod collections;
 pub mod time;
+pub mod io_metrics;
+pub mod memory;
+pub mod tracker;
+pub mod dynamic_config;
 
 pub use self::rocksdb::properties;
+pub use self::rocksdb::compaction_filter;
 
 #[cfg(target_os = "linux")]
 mod thread_metrics;
@@ -357,11 +364,13 @@ pub fn print_tikv_info() {
     info!("Rustc Version:     {}", rustc);
 }
 
-/// `run_prometheus` runs a background prometheus client.
+/// `run_prometheus` runs a background prometheus client, which pushes the
+/// metrics to the pushgateway grouped by the instance and `labels`.
 pub fn run_prometheus(
     interval: Duration,
     address: &str,
     job: &str,
+    labels: &HashMap<String, String>,
 ) -> Option<thread::JoinHandle<()>> {
     if interval == Duration::from_secs(0) {
         return None;
@@ -369,6 +378,10 @@ pub fn run_prometheus(
 
     let job = job.to_owned();
     let address = address.to_owned();
+    let mut grouping = prometheus::hostname_grouping_key();
+    for (k, v) in labels {
+        grouping.insert(k.clone(), v.clone());
+    }
     let handler = thread::Builder::new()
         .name("promepusher".to_owned())
         .spawn(move || loop {
@@ -376,7 +389,7 @@ pub fn run_prometheus(
 
             let res = prometheus::push_metrics(
                 &job,
-                prometheus::hostname_grouping_key(),
+                grouping.clone(),
                 &address,
                 metric_familys,
             );
@@ -392,13 +405,29 @@ pub fn run_prometheus(
 }
 
 #[cfg(target_os = "linux")]
-pub use self::thread_metrics::monitor_threads;
+pub use self::thread_metrics::{monitor_threads, ThreadGroupMonitor};
 
 #[cfg(not(target_os = "linux"))]
 pub fn monitor_threads<S: Into<String>>(_: S) -> io::Result<()> {
     Ok(())
 }
 
+#[cfg(not(target_os = "linux"))]
+pub struct ThreadGroupMonitor;
+
+#[cfg(not(target_os = "linux"))]
+impl ThreadGroupMonitor {
+    pub fn new(_: Duration) -> ThreadGroupMonitor {
+        ThreadGroupMonitor
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {}
+}
+
 /// A simple ring queue with fixed capacity.
 pub struct RingQueue<T> {
     buf: VecDeque<T>,
@@ -13,12 +13,20 @@
 
 
 use std::panic::{self, PanicInfo};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Once, ONCE_INIT};
 use std::{process, thread};
 
 use backtrace::Backtrace;
 use log::LogLevel;
+use time;
+
+use util::logger;
+
+const PANIC_MARK_FILE: &'static str = "panic_mark";
 
 
 /// A simple panic hook that allows skiping printing stacktrace conditionaly.
@@ -28,7 +36,57 @@ static INIT: Once = ONCE_INIT;
 static mut DEFAULT_HOOK: Option<*mut (Fn(&PanicInfo) + 'static + Sync + Send)> = None;
 
 thread_local! {
-    static MUTED: RefCell<bool> = RefCell::new(false)
+    static MUTED: RefCell<bool> = RefCell::new(false);
+    // The region and the command being processed by the thread, which are
+    // reported when panicking.
+    static CONTEXT: Cell<Option<(u64, &'static str)>> = Cell::new(None);
+}
+
+/// Records the region and the command being processed by current thread.
+pub fn set_context(region_id: u64, cmd: &'static str) {
+    CONTEXT.with(|c| c.set(Some((region_id, cmd))));
+}
+
+pub fn clear_context() {
+    CONTEXT.with(|c| c.set(None));
+}
+
+fn context_desc() -> String {
+    match CONTEXT.with(|c| c.get()) {
+        Some((region_id, cmd)) => format!("region {}, command {}", region_id, cmd),
+        None => "<none>".to_owned(),
+    }
+}
+
+fn panic_mark_path<P: AsRef<Path>>(data_dir: P) -> PathBuf {
+    data_dir.as_ref().join(PANIC_MARK_FILE)
+}
+
+fn write_panic_mark(path: &Path, content: &str) {
+    let res = File::create(path).and_then(|mut f| {
+        try!(f.write_all(content.as_bytes()));
+        f.sync_all()
+    });
+    if let Err(e) = res {
+        error!("failed to write panic mark {}: {:?}", path.display(), e);
+    }
+}
+
+/// Takes the panic mark left by the last panic in `data_dir`, it's removed so
+/// it's only reported once.
+pub fn take_panic_mark<P: AsRef<Path>>(data_dir: P) -> Option<String> {
+    let path = panic_mark_path(data_dir);
+    let mut content = String::new();
+    if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut content)) {
+        if path.exists() {
+            warn!("failed to read panic mark {}: {:?}", path.display(), e);
+        }
+        return None;
+    }
+    if let Err(e) = fs::remove_file(&path) {
+        warn!("failed to remove panic mark {}: {:?}", path.display(), e);
+    }
+    Some(content)
 }
 
 /// Replace the default hook if we haven't.
@@ -64,8 +122,10 @@ fn track_hook(p: &PanicInfo) {
     });
 }
 
-/// Exit the whole process when panic.
-pub fn set_exit_hook() {
+/// Exit the whole process when panic. The panic is logged with the backtrace
+/// and recorded in the panic mark in `data_dir`, then the process is aborted if
+/// `abort` is true, or exits otherwise.
+pub fn set_exit_hook(abort: bool, data_dir: &str) {
     // HACK! New a backtrace ahead for caching necessary elf sections of this
     // tikv-server, in case it can not open more files during panicking
     // which leads to no stack info (0x5648bdfe4ff2 - <no info>).
@@ -81,31 +141,77 @@ pub fn set_exit_hook() {
         .spawn(Backtrace::new)
         .unwrap();
 
+    let mark_path = panic_mark_path(data_dir);
     let orig_hook = panic::take_hook();
     panic::set_hook(box move |info: &PanicInfo| {
+        let msg = match info.payload().downcast_ref::<&'static str>() {
+            Some(s) => *s,
+            None => match info.payload().downcast_ref::<String>() {
+                Some(s) => &s[..],
+                None => "Box<Any>",
+            },
+        };
+        let thread = thread::current();
+        let name = thread.name().unwrap_or("<unnamed>");
+        let loc = info.location()
+            .map(|l| format!("{}:{}", l.file(), l.line()))
+            .unwrap_or_else(|| "<unknown>".to_owned());
+        let context = context_desc();
+        let bt = Backtrace::new();
         if log_enabled!(LogLevel::Error) {
-            let msg = match info.payload().downcast_ref::<&'static str>() {
-                Some(s) => *s,
-                None => match info.payload().downcast_ref::<String>() {
-                    Some(s) => &s[..],
-                    None => "Box<Any>",
-                },
-            };
-            let thread = thread::current();
-            let name = thread.name().unwrap_or("<unnamed>");
-            let loc = info.location()
-                .map(|l| format!("{}:{}", l.file(), l.line()));
-            let bt = Backtrace::new();
             error!(
-                "thread '{}' panicked '{}' at {:?}\n{:?}",
+                "thread '{}' panicked '{}' at {:?}, context: {}\n{:?}",
                 name,
                 msg,
-                loc.unwrap_or_else(|| "<unknown>".to_owned()),
+                loc,
+                context,
                 bt
             );
         } else {
             orig_hook(info);
         }
+
+        let now = time::strftime("%Y/%m/%d %H:%M:%S", &time::now()).unwrap();
+        write_panic_mark(
+            &mark_path,
+            &format!(
+                "time: {}\nthread: {}\nmessage: {}\nlocation: {}\ncontext: {}\n{:?}\n",
+                now,
+                name,
+                msg,
+                loc,
+                context,
+                bt
+            ),
+        );
+        logger::flush_log();
+
+        if abort {
+            process::abort();
+        }
         process::exit(1);
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_panic_mark() {
+        let dir = TempDir::new("test_panic_mark").unwrap();
+        assert!(take_panic_mark(dir.path()).is_none());
+
+        set_context(1, "put");
+        let desc = context_desc();
+        assert_eq!(desc, "region 1, command put");
+        clear_context();
+        assert_eq!(context_desc(), "<none>");
+
+        write_panic_mark(&panic_mark_path(dir.path()), &desc);
+        assert_eq!(take_panic_mark(dir.path()).unwrap(), desc);
+        assert!(take_panic_mark(dir.path()).is_none());
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use storage::mvcc::{Write, WriteType};
+use storage::types;
+use raftstore::store::keys;
+use rocksdb::{CompactionFilter, CompactionFilterContext, CompactionFilterFactory};
+
+// The safe point of the compaction filters, 0 means nothing is filtered.
+static GC_SAFE_POINT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Updates the safe point used by the compactions started later, it never
+/// moves backward.
+pub fn set_gc_safe_point(safe_point: u64) {
+    let safe_point = safe_point as usize;
+    let mut current = GC_SAFE_POINT.load(Ordering::SeqCst);
+    while current < safe_point {
+        let prev = GC_SAFE_POINT.compare_and_swap(current, safe_point, Ordering::SeqCst);
+        if prev == current {
+            return;
+        }
+        current = prev;
+    }
+}
+
+pub fn gc_safe_point() -> u64 {
+    GC_SAFE_POINT.load(Ordering::SeqCst) as u64
+}
+
+/// `WriteCompactionFilter` drops the versions in the write cf which can't be
+/// read after the safe point, when they are compacted. The versions of a key
+/// come from the newest to the oldest, all the versions older than the latest
+/// put or delete before the safe point are stale.
+///
+/// The puts whose values are in the default cf and the latest deletes are left
+/// to the gc command, which deletes the values along with them.
+pub struct WriteCompactionFilter {
+    safe_point: u64,
+    last_key: Vec<u8>,
+    // The older versions of `last_key` are stale.
+    remove_older: bool,
+}
+
+impl WriteCompactionFilter {
+    fn new(safe_point: u64) -> WriteCompactionFilter {
+        WriteCompactionFilter {
+            safe_point: safe_point,
+            last_key: vec![],
+            remove_older: false,
+        }
+    }
+}
+
+impl CompactionFilter for WriteCompactionFilter {
+    fn filter(&mut self, _: usize, key: &[u8], value: &[u8]) -> bool {
+        if self.safe_point == 0 || !keys::validate_data_key(key) {
+            return false;
+        }
+        let (k, commit_ts) = match types::split_encoded_key_on_ts(key) {
+            Ok((k, ts)) => (k, ts),
+            Err(_) => return false,
+        };
+        if k != self.last_key.as_slice() {
+            self.last_key.clear();
+            self.last_key.extend_from_slice(k);
+            self.remove_older = false;
+        }
+        if commit_ts > self.safe_point {
+            return false;
+        }
+        let write = match Write::parse(value) {
+            Ok(write) => write,
+            Err(_) => return false,
+        };
+        if self.remove_older {
+            return write.write_type != WriteType::Put || write.short_value.is_some();
+        }
+        match write.write_type {
+            WriteType::Put | WriteType::Delete => {
+                self.remove_older = true;
+                false
+            }
+            WriteType::Lock | WriteType::Rollback => true,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct WriteCompactionFilterFactory {}
+
+impl CompactionFilterFactory for WriteCompactionFilterFactory {
+    fn create_compaction_filter(&self, _: &CompactionFilterContext) -> Box<CompactionFilter> {
+        Box::new(WriteCompactionFilter::new(gc_safe_point()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocksdb::CompactionFilter;
+    use storage::Key;
+    use storage::mvcc::{Write, WriteType};
+    use raftstore::store::keys;
+
+    #[test]
+    fn test_write_compaction_filter() {
+        let long_value = None;
+        let short_value = Some(b"v".to_vec());
+        // (key, commit_ts, write_type, short_value, filtered)
+        let cases = vec![
+            ("a", 12, WriteType::Put, short_value.clone(), false),
+            ("a", 9, WriteType::Rollback, None, true),
+            ("a", 8, WriteType::Put, short_value.clone(), false),
+            ("a", 7, WriteType::Put, short_value.clone(), true),
+            ("a", 6, WriteType::Put, long_value.clone(), false),
+            ("a", 5, WriteType::Lock, None, true),
+            ("b", 8, WriteType::Delete, None, false),
+            ("b", 7, WriteType::Delete, None, true),
+            ("c", 8, WriteType::Lock, None, true),
+            ("c", 7, WriteType::Put, long_value.clone(), false),
+            ("c", 6, WriteType::Rollback, None, true),
+        ];
+        let mut filter = WriteCompactionFilter::new(10);
+        for (key, ts, write_type, short_value, filtered) in cases {
+            let k = Key::from_raw(key.as_bytes()).append_ts(ts);
+            let k = keys::data_key(k.encoded());
+            let v = Write::new(write_type, ts - 1, short_value).to_bytes();
+            assert_eq!(filter.filter(0, &k, &v), filtered, "{} {}", key, ts);
+        }
+
+        // Nothing is filtered before the safe point is known.
+        let mut filter = WriteCompactionFilter::new(0);
+        let k = Key::from_raw(b"a").append_ts(5);
+        let v = Write::new(WriteType::Lock, 4, None).to_bytes();
+        assert!(!filter.filter(0, &keys::data_key(k.encoded()), &v));
+    }
+
+    #[test]
+    fn test_set_gc_safe_point() {
+        set_gc_safe_point(100);
+        assert!(gc_safe_point() >= 100);
+        set_gc_safe_point(50);
+        assert!(gc_safe_point() >= 100);
+    }
+}
@@ -11,10 +11,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
 use prometheus::{exponential_buckets, CounterVec, GaugeVec, HistogramVec};
 use rocksdb::{DBStatisticsHistogramType as HistType, DBStatisticsTickerType as TickerType,
               HistogramData, DB};
 use util::rocksdb;
+use util::io_metrics::{record_io_bytes, IOOp, IOType};
 
 pub const ROCKSDB_TOTAL_SST_FILES_SIZE: &'static str = "rocksdb.total-sst-files-size";
 pub const ROCKSDB_TABLE_READERS_MEM: &'static str = "rocksdb.estimate-table-readers-mem";
@@ -23,6 +26,33 @@ pub const ROCKSDB_ESTIMATE_NUM_KEYS: &'static str = "rocksdb.estimate-num-keys";
 pub const ROCKSDB_PENDING_COMPACTION_BYTES: &'static str = "rocksdb.\
                                                             estimate-pending-compaction-bytes";
 pub const ROCKSDB_COMPRESSION_RATIO_AT_LEVEL: &'static str = "rocksdb.compression-ratio-at-level";
+pub const ROCKSDB_DB_STATS_KEY: &'static str = "rocksdb.dbstats";
+pub const ROCKSDB_CF_STATS_KEY: &'static str = "rocksdb.cfstats";
+pub const ROCKSDB_IS_WRITE_STOPPED: &'static str = "rocksdb.is-write-stopped";
+pub const ROCKSDB_ACTUAL_DELAYED_WRITE_RATE: &'static str = "rocksdb.actual-delayed-write-rate";
+
+// Whether the writes of the kv engine are stopped or delayed by rocksdb, it's
+// updated by the metrics flusher.
+static KV_WRITE_STALLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Checks whether the writes of the engine are stopped or delayed because the
+/// flushes or compactions can't catch up.
+pub fn is_engine_write_stalled(engine: &DB) -> bool {
+    engine.get_property_int(ROCKSDB_IS_WRITE_STOPPED).unwrap_or(0) > 0 ||
+        engine
+            .get_property_int(ROCKSDB_ACTUAL_DELAYED_WRITE_RATE)
+            .unwrap_or(0) > 0
+}
+
+pub fn set_kv_write_stalled(stalled: bool) {
+    KV_WRITE_STALLED.store(stalled, Ordering::Relaxed);
+}
+
+/// Checks whether the writes of the kv engine were stalled when the metrics
+/// were flushed last time.
+pub fn is_kv_write_stalled() -> bool {
+    KV_WRITE_STALLED.load(Ordering::Relaxed)
+}
 
 pub const ENGINE_TICKER_TYPES: &'static [TickerType] = &[
     TickerType::BlockCacheMiss,
@@ -372,12 +402,14 @@ pub fn flush_engine_ticker_metrics(t: TickerType, value: u64, name: &str) {
                 .with_label_values(&[name, "bytes_read"])
                 .inc_by(value as f64)
                 .unwrap();
+            record_io_bytes(IOType::Compaction, IOOp::Read, value);
         }
         TickerType::CompactWriteBytes => {
             STORE_ENGINE_COMPACTION_FLOW_VEC
                 .with_label_values(&[name, "bytes_written"])
                 .inc_by(value as f64)
                 .unwrap();
+            record_io_bytes(IOType::Compaction, IOOp::Write, value);
         }
         TickerType::FlushWriteBytes => {
             STORE_ENGINE_FLOW_VEC
@@ -800,7 +832,23 @@ pub fn flush_engine_histogram_metrics(t: HistType, value: HistogramData, name: &
     }
 }
 
+/// Gets the total usage of the block caches of all column families.
+pub fn get_engine_block_cache_usage(engine: &DB) -> u64 {
+    engine
+        .cf_names()
+        .into_iter()
+        .map(|cf| {
+            let handle = rocksdb::get_cf_handle(engine, cf).unwrap();
+            engine.get_block_cache_usage_cf(handle)
+        })
+        .sum()
+}
+
 pub fn flush_engine_properties(engine: &DB, name: &str) {
+    let stalled = if is_engine_write_stalled(engine) { 1.0 } else { 0.0 };
+    STORE_ENGINE_WRITE_STALLED_GAUGE_VEC
+        .with_label_values(&[name])
+        .set(stalled);
     for cf in engine.cf_names() {
         let handle = rocksdb::get_cf_handle(engine, cf).unwrap();
         // It is important to monitor each cf's size, especially the "raft" and "lock" column
@@ -1105,6 +1153,13 @@ lazy_static!{
             &["db", "type"]
         ).unwrap();
 
+    pub static ref STORE_ENGINE_WRITE_STALLED_GAUGE_VEC: GaugeVec =
+        register_gauge_vec!(
+            "tikv_engine_write_stalled",
+            "Whether the writes are stopped or delayed",
+            &["db"]
+        ).unwrap();
+
     pub static ref STORE_ENGINE_PENDING_COMACTION_BYTES_VEC: GaugeVec =
         register_gauge_vec!(
             "tikv_engine_pending_compaction_bytes",
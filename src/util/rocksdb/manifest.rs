@@ -0,0 +1,469 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A minimal reader of RocksDB MANIFEST files, so that the SST files of a db
+//! can be inspected without a matching external `ldb` binary.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crc::crc32::{self, Digest, Hasher32};
+
+use util::codec::number::NumberDecoder;
+use util::escape;
+
+const BLOCK_SIZE: usize = 32768;
+// Checksum (4 bytes), length (2 bytes) and type (1 byte).
+const HEADER_SIZE: usize = 7;
+const CRC_MASK_DELTA: u32 = 0xa282_ead8;
+
+const ZERO_TYPE: u8 = 0;
+const FULL_TYPE: u8 = 1;
+const FIRST_TYPE: u8 = 2;
+const MIDDLE_TYPE: u8 = 3;
+const LAST_TYPE: u8 = 4;
+
+// Tags of the fields in a version edit.
+const TAG_COMPARATOR: u64 = 1;
+const TAG_LOG_NUMBER: u64 = 2;
+const TAG_NEXT_FILE_NUMBER: u64 = 3;
+const TAG_LAST_SEQUENCE: u64 = 4;
+const TAG_COMPACT_POINTER: u64 = 5;
+const TAG_DELETED_FILE: u64 = 6;
+const TAG_NEW_FILE: u64 = 7;
+const TAG_PREV_LOG_NUMBER: u64 = 9;
+const TAG_MIN_LOG_NUMBER_TO_KEEP: u64 = 10;
+const TAG_NEW_FILE2: u64 = 100;
+const TAG_NEW_FILE3: u64 = 102;
+const TAG_NEW_FILE4: u64 = 103;
+const TAG_COLUMN_FAMILY: u64 = 200;
+const TAG_COLUMN_FAMILY_ADD: u64 = 201;
+const TAG_COLUMN_FAMILY_DROP: u64 = 202;
+const TAG_MAX_COLUMN_FAMILY: u64 = 203;
+// Fields with this bit set in their tags can be skipped by old versions.
+const TAG_SAFE_IGNORE_MASK: u64 = 1 << 13;
+
+// Tags of the custom fields in `TAG_NEW_FILE4`.
+const CUSTOM_TAG_TERMINATE: u64 = 1;
+const CUSTOM_TAG_PATH_ID: u64 = 65;
+const CUSTOM_TAG_NON_SAFE_IGNORE_MASK: u64 = 1 << 6;
+
+const DEFAULT_CF_ID: u32 = 0;
+const DEFAULT_CF_NAME: &'static str = "default";
+
+/// The meta of an SST file recorded in the MANIFEST.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FileMeta {
+    pub level: u32,
+    pub number: u64,
+    pub path_id: u32,
+    pub size: u64,
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+    pub smallest_seqno: u64,
+    pub largest_seqno: u64,
+}
+
+impl FileMeta {
+    pub fn file_name(&self) -> String {
+        format!("{:06}.sst", self.number)
+    }
+}
+
+impl Display for FileMeta {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "level: {}, file: {}, size: {}, seqno: [{}, {}], range: [{}, {}]",
+            self.level,
+            self.file_name(),
+            self.size,
+            self.smallest_seqno,
+            self.largest_seqno,
+            format_internal_key(&self.smallest),
+            format_internal_key(&self.largest)
+        )
+    }
+}
+
+// An internal key is the user key followed by 8 bytes of packed sequence number
+// and value type.
+fn format_internal_key(key: &[u8]) -> String {
+    if key.len() < 8 {
+        return escape(key);
+    }
+    let (user_key, mut trailer) = key.split_at(key.len() - 8);
+    let packed = trailer.decode_u64_le().unwrap();
+    format!("{} @ {} : {}", escape(user_key), packed >> 8, packed & 0xff)
+}
+
+/// A version edit is a record of the MANIFEST, which describes the changes
+/// of the live files of a column family.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VersionEdit {
+    pub column_family: u32,
+    pub column_family_add: Option<String>,
+    pub column_family_drop: bool,
+    pub comparator: Option<String>,
+    pub log_number: Option<u64>,
+    pub prev_log_number: Option<u64>,
+    pub next_file_number: Option<u64>,
+    pub last_sequence: Option<u64>,
+    pub max_column_family: Option<u32>,
+    /// Level and number of the deleted files.
+    pub deleted_files: Vec<(u32, u64)>,
+    pub new_files: Vec<FileMeta>,
+}
+
+impl Display for VersionEdit {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        try!(writeln!(f, "VersionEdit {{"));
+        try!(writeln!(f, "  column family: {}", self.column_family));
+        if let Some(ref name) = self.column_family_add {
+            try!(writeln!(f, "  add column family: {}", name));
+        }
+        if self.column_family_drop {
+            try!(writeln!(f, "  drop column family"));
+        }
+        if let Some(ref comparator) = self.comparator {
+            try!(writeln!(f, "  comparator: {}", comparator));
+        }
+        if let Some(n) = self.log_number {
+            try!(writeln!(f, "  log number: {}", n));
+        }
+        if let Some(n) = self.prev_log_number {
+            try!(writeln!(f, "  prev log number: {}", n));
+        }
+        if let Some(n) = self.next_file_number {
+            try!(writeln!(f, "  next file number: {}", n));
+        }
+        if let Some(n) = self.last_sequence {
+            try!(writeln!(f, "  last sequence: {}", n));
+        }
+        if let Some(n) = self.max_column_family {
+            try!(writeln!(f, "  max column family: {}", n));
+        }
+        for &(level, number) in &self.deleted_files {
+            try!(writeln!(f, "  delete file: level: {}, file: {:06}.sst", level, number));
+        }
+        for file in &self.new_files {
+            try!(writeln!(f, "  add file: {}", file));
+        }
+        write!(f, "}}")
+    }
+}
+
+/// Gets the path of the MANIFEST file in use by the db at `db_path`.
+pub fn current_manifest_path<P: AsRef<Path>>(db_path: P) -> Result<PathBuf, String> {
+    let db_path = db_path.as_ref();
+    let mut current = String::new();
+    try!(
+        File::open(db_path.join("CURRENT"))
+            .and_then(|mut f| f.read_to_string(&mut current))
+            .map_err(|e| format!("read CURRENT of {}: {:?}", db_path.display(), e))
+    );
+    let name = current.trim();
+    if !name.starts_with("MANIFEST-") {
+        return Err(format!("invalid CURRENT content {:?}", current));
+    }
+    Ok(db_path.join(name))
+}
+
+/// Reads all version edits in the MANIFEST file at `path`.
+pub fn read_manifest<P: AsRef<Path>>(path: P) -> Result<Vec<VersionEdit>, String> {
+    let path = path.as_ref();
+    let mut data = vec![];
+    try!(
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(|e| format!("read {}: {:?}", path.display(), e))
+    );
+    let records = try!(read_records(&data));
+    records.iter().map(|r| decode_version_edit(r)).collect()
+}
+
+/// Replays `edits` and returns the live files of each column family, sorted
+/// by level and file number.
+pub fn live_files(edits: &[VersionEdit]) -> BTreeMap<String, Vec<FileMeta>> {
+    let mut cf_names = map![DEFAULT_CF_ID => DEFAULT_CF_NAME.to_owned()];
+    let mut cf_files: HashMap<u32, BTreeMap<u64, FileMeta>> = HashMap::new();
+    for edit in edits {
+        let cf = edit.column_family;
+        if let Some(ref name) = edit.column_family_add {
+            cf_names.insert(cf, name.clone());
+        }
+        if edit.column_family_drop {
+            cf_names.remove(&cf);
+            cf_files.remove(&cf);
+            continue;
+        }
+        let files = cf_files.entry(cf).or_insert_with(BTreeMap::new);
+        for &(_, number) in &edit.deleted_files {
+            files.remove(&number);
+        }
+        for file in &edit.new_files {
+            files.insert(file.number, file.clone());
+        }
+    }
+
+    cf_names
+        .into_iter()
+        .map(|(cf, name)| {
+            let mut files: Vec<FileMeta> = cf_files
+                .remove(&cf)
+                .map(|files| files.into_iter().map(|(_, f)| f).collect())
+                .unwrap_or_default();
+            files.sort_by_key(|f| (f.level, f.number));
+            (name, files)
+        })
+        .collect()
+}
+
+fn unmask_crc(masked: u32) -> u32 {
+    let rot = masked.wrapping_sub(CRC_MASK_DELTA);
+    (rot >> 17) | (rot << 15)
+}
+
+// Splits the content of a RocksDB log file into records.
+fn read_records(data: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut records = vec![];
+    let mut fragments: Option<Vec<u8>> = None;
+    let mut offset = 0;
+    while offset < data.len() {
+        let left_in_block = BLOCK_SIZE - offset % BLOCK_SIZE;
+        if left_in_block < HEADER_SIZE {
+            // The trailer of a block is padded with zeros.
+            offset += left_in_block;
+            continue;
+        }
+        if data.len() - offset < HEADER_SIZE {
+            return Err(format!("truncated record header at {}", offset));
+        }
+        let mut header = &data[offset..offset + HEADER_SIZE];
+        let checksum = header.decode_u32_le().unwrap();
+        let len = header.decode_u16_le().unwrap() as usize;
+        let record_type = header[0];
+        if record_type == ZERO_TYPE && len == 0 {
+            // The rest of the file is preallocated.
+            break;
+        }
+        let end = offset + HEADER_SIZE + len;
+        if len > left_in_block - HEADER_SIZE || end > data.len() {
+            return Err(format!("truncated record at {}", offset));
+        }
+        let mut digest = Digest::new(crc32::CASTAGNOLI);
+        digest.write(&data[offset + HEADER_SIZE - 1..end]);
+        if digest.sum32() != unmask_crc(checksum) {
+            return Err(format!("checksum mismatch of record at {}", offset));
+        }
+        let payload = &data[offset + HEADER_SIZE..end];
+
+        match record_type {
+            FULL_TYPE => records.push(payload.to_vec()),
+            FIRST_TYPE => fragments = Some(payload.to_vec()),
+            MIDDLE_TYPE | LAST_TYPE => {
+                match fragments {
+                    Some(ref mut record) => record.extend_from_slice(payload),
+                    None => return Err(format!("missing first fragment of record at {}", offset)),
+                }
+                if record_type == LAST_TYPE {
+                    records.push(fragments.take().unwrap());
+                }
+            }
+            t => return Err(format!("unknown record type {} at {}", t, offset)),
+        }
+        offset = end;
+    }
+    Ok(records)
+}
+
+fn decode_varint(data: &mut &[u8]) -> Result<u64, String> {
+    data.decode_var_u64()
+        .map_err(|e| format!("decode varint: {:?}", e))
+}
+
+fn decode_slice(data: &mut &[u8]) -> Result<Vec<u8>, String> {
+    let len = try!(decode_varint(data)) as usize;
+    if data.len() < len {
+        return Err(format!("slice length {} exceeds {} bytes left", len, data.len()));
+    }
+    let (slice, left) = data.split_at(len);
+    *data = left;
+    Ok(slice.to_vec())
+}
+
+fn decode_string(data: &mut &[u8]) -> Result<String, String> {
+    let s = try!(decode_slice(data));
+    Ok(String::from_utf8_lossy(&s).into_owned())
+}
+
+fn decode_version_edit(mut data: &[u8]) -> Result<VersionEdit, String> {
+    let data = &mut data;
+    let mut edit = VersionEdit::default();
+    while !data.is_empty() {
+        let tag = try!(decode_varint(data));
+        match tag {
+            TAG_COMPARATOR => edit.comparator = Some(try!(decode_string(data))),
+            TAG_LOG_NUMBER => edit.log_number = Some(try!(decode_varint(data))),
+            TAG_PREV_LOG_NUMBER => edit.prev_log_number = Some(try!(decode_varint(data))),
+            TAG_NEXT_FILE_NUMBER => edit.next_file_number = Some(try!(decode_varint(data))),
+            TAG_LAST_SEQUENCE => edit.last_sequence = Some(try!(decode_varint(data))),
+            TAG_MIN_LOG_NUMBER_TO_KEEP => {
+                try!(decode_varint(data));
+            }
+            TAG_COMPACT_POINTER => {
+                try!(decode_varint(data));
+                try!(decode_slice(data));
+            }
+            TAG_DELETED_FILE => {
+                let level = try!(decode_varint(data)) as u32;
+                let number = try!(decode_varint(data));
+                edit.deleted_files.push((level, number));
+            }
+            TAG_NEW_FILE | TAG_NEW_FILE2 | TAG_NEW_FILE3 | TAG_NEW_FILE4 => {
+                let file = try!(decode_new_file(tag, data));
+                edit.new_files.push(file);
+            }
+            TAG_COLUMN_FAMILY => edit.column_family = try!(decode_varint(data)) as u32,
+            TAG_COLUMN_FAMILY_ADD => edit.column_family_add = Some(try!(decode_string(data))),
+            TAG_COLUMN_FAMILY_DROP => edit.column_family_drop = true,
+            TAG_MAX_COLUMN_FAMILY => {
+                edit.max_column_family = Some(try!(decode_varint(data)) as u32)
+            }
+            _ if tag & TAG_SAFE_IGNORE_MASK != 0 => {
+                try!(decode_slice(data));
+            }
+            _ => return Err(format!("unknown tag {} in version edit", tag)),
+        }
+    }
+    Ok(edit)
+}
+
+fn decode_new_file(tag: u64, data: &mut &[u8]) -> Result<FileMeta, String> {
+    let mut file = FileMeta::default();
+    file.level = try!(decode_varint(data)) as u32;
+    file.number = try!(decode_varint(data));
+    if tag == TAG_NEW_FILE3 {
+        file.path_id = try!(decode_varint(data)) as u32;
+    }
+    file.size = try!(decode_varint(data));
+    file.smallest = try!(decode_slice(data));
+    file.largest = try!(decode_slice(data));
+    if tag == TAG_NEW_FILE {
+        return Ok(file);
+    }
+    file.smallest_seqno = try!(decode_varint(data));
+    file.largest_seqno = try!(decode_varint(data));
+    if tag != TAG_NEW_FILE4 {
+        return Ok(file);
+    }
+
+    loop {
+        let custom_tag = try!(decode_varint(data));
+        if custom_tag == CUSTOM_TAG_TERMINATE {
+            break;
+        }
+        let field = try!(decode_slice(data));
+        if custom_tag == CUSTOM_TAG_PATH_ID {
+            if field.len() != 1 {
+                return Err(format!("invalid path id {:?}", field));
+            }
+            file.path_id = field[0] as u32;
+        } else if custom_tag & CUSTOM_TAG_NON_SAFE_IGNORE_MASK != 0 {
+            return Err(format!("unknown custom tag {} in new file", custom_tag));
+        }
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use rocksdb::Writable;
+    use storage::{ALL_CFS, CF_DEFAULT, CF_WRITE};
+    use util::codec::number::NumberEncoder;
+    use util::rocksdb::{get_cf_handle, new_engine};
+    use super::*;
+
+    fn encode_record(record_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut digest = Digest::new(crc32::CASTAGNOLI);
+        digest.write(&[record_type]);
+        digest.write(payload);
+        let crc = digest.sum32();
+        let masked = ((crc >> 15) | (crc << 17)).wrapping_add(CRC_MASK_DELTA);
+
+        let mut buf = vec![];
+        buf.encode_u32_le(masked).unwrap();
+        buf.encode_u16_le(payload.len() as u16).unwrap();
+        buf.push(record_type);
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn test_read_records() {
+        // The first two records leave 6 bytes in the first block, which are padded.
+        let first = vec![1; BLOCK_SIZE - HEADER_SIZE * 2 - 2 - 6];
+        let mut data = encode_record(FULL_TYPE, &first);
+        data.extend_from_slice(&encode_record(FIRST_TYPE, b"ab"));
+        data.extend_from_slice(&[0; 6]);
+        data.extend_from_slice(&encode_record(MIDDLE_TYPE, b"cd"));
+        data.extend_from_slice(&encode_record(LAST_TYPE, b"ef"));
+        data.extend_from_slice(&[0; 20]);
+        let records = read_records(&data).unwrap();
+        assert_eq!(records, vec![first, b"abcdef".to_vec()]);
+
+        // Corrupt the payload of the last record.
+        let len = data.len();
+        data[len - 21] = b'x';
+        read_records(&data).unwrap_err();
+
+        let data = encode_record(LAST_TYPE, b"ab");
+        read_records(&data).unwrap_err();
+    }
+
+    #[test]
+    fn test_read_manifest() {
+        let path = TempDir::new("test_read_manifest").unwrap();
+        let db = new_engine(path.path().to_str().unwrap(), ALL_CFS).unwrap();
+        for cf in &[CF_DEFAULT, CF_WRITE] {
+            let handle = get_cf_handle(&db, cf).unwrap();
+            db.put_cf(handle, b"k1", b"v1").unwrap();
+            db.put_cf(handle, b"k2", b"v2").unwrap();
+            db.flush_cf(handle, true).unwrap();
+        }
+
+        let manifest = current_manifest_path(path.path()).unwrap();
+        let edits = read_manifest(&manifest).unwrap();
+        assert!(edits.iter().any(|e| e.comparator.is_some()));
+
+        let files = live_files(&edits);
+        assert_eq!(files.len(), ALL_CFS.len());
+        for cf in ALL_CFS {
+            let cf_files = &files[*cf];
+            if *cf != CF_DEFAULT && *cf != CF_WRITE {
+                assert!(cf_files.is_empty());
+                continue;
+            }
+            assert_eq!(cf_files.len(), 1);
+            let file = &cf_files[0];
+            assert!(path.path().join(file.file_name()).exists());
+            assert_eq!(&file.smallest[..2], b"k1");
+            assert_eq!(&file.largest[..2], b"k2");
+        }
+    }
+}
@@ -14,6 +14,7 @@
 use rocksdb::DB;
 use raftstore::store::Engines;
 use util::rocksdb::engine_metrics::*;
+use util::memory::BLOCK_CACHE_MEMORY;
 use std::thread::{Builder, JoinHandle};
 use std::io;
 use std::sync::mpsc::{self, Sender};
@@ -50,7 +51,11 @@ impl MetricsFlusher {
                 .spawn(move || {
                     while let Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
                         flush_metrics(&db, "kv");
+                        set_kv_write_stalled(is_engine_write_stalled(&db));
                         flush_metrics(&raft_db, "raft");
+                        let usage = get_engine_block_cache_usage(&db) +
+                            get_engine_block_cache_usage(&raft_db);
+                        BLOCK_CACHE_MEMORY.set(usage as usize);
                     }
                 })
         );
@@ -12,9 +12,11 @@
 // limitations under the License.
 
 pub mod properties;
+pub mod compaction_filter;
 pub mod event_listener;
 pub mod engine_metrics;
 pub mod metrics_flusher;
+pub mod manifest;
 
 pub use self::event_listener::EventListener;
 pub use self::metrics_flusher::MetricsFlusher;
@@ -28,8 +30,9 @@ use storage::{ALL_CFS, CF_DEFAULT, CF_LOCK};
 use rocksdb::{ColumnFamilyOptions, DBCompressionType, DBOptions, ReadOptions, SliceTransform,
               Writable, WriteBatch, DB};
 use rocksdb::rocksdb::supported_compression;
-use util::rocksdb::engine_metrics::{ROCKSDB_COMPRESSION_RATIO_AT_LEVEL,
-                                    ROCKSDB_CUR_SIZE_ALL_MEM_TABLES, ROCKSDB_TOTAL_SST_FILES_SIZE};
+use util::rocksdb::engine_metrics::{ROCKSDB_CF_STATS_KEY, ROCKSDB_COMPRESSION_RATIO_AT_LEVEL,
+                                    ROCKSDB_CUR_SIZE_ALL_MEM_TABLES, ROCKSDB_DB_STATS_KEY,
+                                    ROCKSDB_TOTAL_SST_FILES_SIZE};
 use util::rocksdb;
 
 pub use rocksdb::CFHandle;
@@ -223,6 +226,27 @@ pub fn get_engine_used_size(engine: Arc<DB>) -> u64 {
     used_size
 }
 
+/// Dumps the stats of all column families and the db, as well as the detailed
+/// statistics if `enable_statistics` is on.
+pub fn dump_stats(engine: &DB) -> String {
+    let mut stats = String::new();
+    for name in engine.cf_names() {
+        let handle = engine.cf_handle(name).unwrap();
+        if let Some(v) = engine.get_property_value_cf(handle, ROCKSDB_CF_STATS_KEY) {
+            stats.push_str(&v);
+        }
+    }
+
+    if let Some(v) = engine.get_property_value(ROCKSDB_DB_STATS_KEY) {
+        stats.push_str(&v);
+    }
+
+    if let Some(v) = engine.get_statistics() {
+        stats.push_str(&v);
+    }
+    stats
+}
+
 pub fn get_engine_compression_ratio_at_level(
     engine: &DB,
     handle: &CFHandle,
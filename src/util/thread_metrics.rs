@@ -14,11 +14,17 @@
 use std::fs;
 use std::io::{Error, ErrorKind, Read, Result};
 use std::sync::Mutex;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{Builder, JoinHandle};
+use std::time::{Duration, Instant};
 
-use prometheus::{self, proto, Collector, CounterVec, Desc, Opts};
+use prometheus::{self, proto, Collector, CounterVec, Desc, GaugeVec, Opts};
 
 use libc::{self, pid_t};
 
+use util::collections::HashMap;
+use util::time::duration_to_sec;
+
 /// monitor current process's threads.
 pub fn monitor_threads<S: Into<String>>(namespace: S) -> Result<()> {
     let pid = unsafe { libc::getpid() };
@@ -80,6 +86,142 @@ impl Collector for ThreadsColletcor {
     }
 }
 
+/// The thread groups whose CPU usage are exported, a thread belongs to the first
+/// group that its name starts with one of the prefixes.
+const THREAD_GROUPS: &'static [(&'static str, &'static [&'static str])] = &[
+    ("grpc", &["grpc"]),
+    ("raftstore", &["raftstore"]),
+    ("apply", &["apply"]),
+    ("rocksdb", &["rocksdb"]),
+    ("coprocessor", &["endpoint", "end_point"]),
+];
+
+fn thread_group(name: &str) -> &'static str {
+    for &(group, prefixes) in THREAD_GROUPS {
+        if prefixes.iter().any(|p| name.starts_with(p)) {
+            return group;
+        }
+    }
+    "other"
+}
+
+/// `ThreadGroupMonitor` reads the stats of the threads from `/proc/<pid>/task`
+/// periodically in the background, and exports the CPU usage of the thread
+/// groups, so a saturated thread pool can be found out easily.
+pub struct ThreadGroupMonitor {
+    pid: pid_t,
+    handle: Option<JoinHandle<()>>,
+    sender: Option<Sender<bool>>,
+    interval: Duration,
+}
+
+impl ThreadGroupMonitor {
+    pub fn new(interval: Duration) -> ThreadGroupMonitor {
+        ThreadGroupMonitor {
+            pid: unsafe { libc::getpid() },
+            handle: None,
+            sender: None,
+            interval: interval,
+        }
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let pid = self.pid;
+        let interval = self.interval;
+        self.sender = Some(tx);
+        let h = try!(
+            Builder::new()
+                .name(thd_name!("thread-group-monitor"))
+                .spawn(move || {
+                    let mut collector = ThreadGroupCollector::new(pid);
+                    collector.collect();
+                    while let Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                        collector.collect();
+                    }
+                })
+        );
+
+        self.handle = Some(h);
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {
+        let h = self.handle.take();
+        if h.is_none() {
+            return;
+        }
+        drop(self.sender.take().unwrap());
+        if let Err(e) = h.unwrap().join() {
+            error!("join thread group monitor failed {:?}", e);
+        }
+    }
+}
+
+struct ThreadGroupCollector {
+    pid: pid_t,
+    // The CPU time of the threads in the last collection.
+    cpu_totals: HashMap<pid_t, f64>,
+    last_collect: Option<Instant>,
+}
+
+impl ThreadGroupCollector {
+    fn new(pid: pid_t) -> ThreadGroupCollector {
+        ThreadGroupCollector {
+            pid: pid,
+            cpu_totals: HashMap::default(),
+            last_collect: None,
+        }
+    }
+
+    fn collect(&mut self) {
+        let tids = match get_thread_ids(self.pid) {
+            Ok(tids) => tids,
+            Err(e) => {
+                error!("failed to get threads of {}: {:?}", self.pid, e);
+                return;
+            }
+        };
+        let mut deltas: HashMap<&'static str, f64> = HashMap::default();
+        let mut cpu_totals = HashMap::default();
+        for tid in tids {
+            // The thread may exit before its stat is read.
+            if let Ok((tname, utime, stime)) = get_thread_stat(self.pid, tid) {
+                let total = (utime + stime) / *CLK_TCK;
+                let past = self.cpu_totals.get(&tid).cloned().unwrap_or(0.0);
+                if total > past {
+                    *deltas.entry(thread_group(&tname)).or_insert(0.0) += total - past;
+                }
+                cpu_totals.insert(tid, total);
+            }
+        }
+        self.cpu_totals = cpu_totals;
+
+        let now = Instant::now();
+        let elapsed = self.last_collect.map(|t| duration_to_sec(now.duration_since(t)));
+        self.last_collect = Some(now);
+        let groups = THREAD_GROUPS.iter().map(|&(group, _)| group).chain(Some("other"));
+        for group in groups {
+            let delta = deltas.get(group).cloned().unwrap_or(0.0);
+            if delta > 0.0 {
+                THREAD_GROUP_CPU_COUNTER_VEC
+                    .with_label_values(&[group])
+                    .inc_by(delta)
+                    .unwrap();
+            }
+            // The first collection counts the CPU time since the threads
+            // started, which can't be used to calculate the usage.
+            if let Some(elapsed) = elapsed {
+                if elapsed > 0.0 {
+                    THREAD_GROUP_CPU_USAGE_GAUGE_VEC
+                        .with_label_values(&[group])
+                        .set(delta / elapsed);
+                }
+            }
+        }
+    }
+}
+
 fn get_thread_ids(pid: pid_t) -> Result<Vec<pid_t>> {
     let mut tids = Vec::new();
     let dirs = try!(fs::read_dir(format!("/proc/{}/task", pid)));
@@ -175,6 +317,20 @@ fn get_thread_stat_internal(tid: pid_t, stat: &str) -> Result<(String, f64, f64)
 }
 
 lazy_static! {
+    static ref THREAD_GROUP_CPU_COUNTER_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_thread_group_cpu_seconds_total",
+            "Total user and system CPU time spent in seconds by thread groups",
+            &["group"]
+        ).unwrap();
+
+    static ref THREAD_GROUP_CPU_USAGE_GAUGE_VEC: GaugeVec =
+        register_gauge_vec!(
+            "tikv_thread_group_cpu_usage",
+            "CPU usage of thread groups, 1.0 means a core is fully used",
+            &["group"]
+        ).unwrap();
+
     // getconf CLK_TCK
     static ref CLK_TCK: f64 = {
         unsafe {
@@ -187,6 +343,7 @@ lazy_static! {
 mod tests {
     use std::thread;
     use std::sync;
+    use std::time::Duration;
 
     use libc;
 
@@ -220,6 +377,36 @@ mod tests {
         h.join().unwrap();
     }
 
+    #[test]
+    fn test_thread_group() {
+        let cases = [
+            ("grpc_server_0", "grpc"),
+            ("raftstore_1", "raftstore"),
+            ("apply_worker", "apply"),
+            ("rocksdb:bg0", "rocksdb"),
+            ("endpoint_normal", "coprocessor"),
+            ("end_point_worke", "coprocessor"),
+            ("pd_worker", "other"),
+        ];
+        for &(name, group) in &cases {
+            assert_eq!(super::thread_group(name), group);
+        }
+    }
+
+    #[test]
+    fn test_thread_group_collector() {
+        let pid = unsafe { libc::getpid() };
+        let mut collector = super::ThreadGroupCollector::new(pid);
+        collector.collect();
+        assert!(collector.last_collect.is_some());
+        assert!(!collector.cpu_totals.is_empty());
+
+        let mut monitor = super::ThreadGroupMonitor::new(Duration::from_millis(10));
+        monitor.start().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        monitor.stop();
+    }
+
     #[test]
     fn test_get_thread_name() {
         let cases = [
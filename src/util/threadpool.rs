@@ -18,7 +18,7 @@ use std::thread::{Builder, JoinHandle};
 use std::marker::PhantomData;
 use std::boxed::FnBox;
 use std::collections::VecDeque;
-use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
 use std::fmt::Write;
 
 pub const DEFAULT_TASKS_PER_TICK: usize = 10000;
@@ -106,7 +106,7 @@ impl<C: Context + Default + 'static> ThreadPoolBuilder<C, DefaultContextFactory>
     }
 }
 
-impl<C: Context + 'static, F: ContextFactory<C>> ThreadPoolBuilder<C, F> {
+impl<C: Context + 'static, F: ContextFactory<C> + Send + 'static> ThreadPoolBuilder<C, F> {
     pub fn new(name: String, factory: F) -> ThreadPoolBuilder<C, F> {
         ThreadPoolBuilder {
             name: name,
@@ -137,6 +137,14 @@ impl<C: Context + 'static, F: ContextFactory<C>> ThreadPoolBuilder<C, F> {
     }
 }
 
+struct WorkerThread {
+    handle: JoinHandle<()>,
+    // Tells the thread to quit after its running task.
+    quit: Arc<AtomicBool>,
+    // Set when the thread exits.
+    exited: Arc<AtomicBool>,
+}
+
 struct ScheduleState<Ctx> {
     queue: FifoQueue<Ctx>,
     stopped: bool,
@@ -147,8 +155,14 @@ struct ScheduleState<Ctx> {
 /// is ready to process a task, it will get a task from the pool
 /// according to the `ScheduleQueue` provided in initialization.
 pub struct ThreadPool<Ctx> {
+    name: String,
+    tasks_per_tick: usize,
+    factory: Box<ContextFactory<Ctx> + Send>,
     state: Arc<(Mutex<ScheduleState<Ctx>>, Condvar)>,
-    threads: Vec<JoinHandle<()>>,
+    threads: Vec<WorkerThread>,
+    // The threads removed by `resize`. The exited ones are joined by the next
+    // `resize`, the others when the pool stops.
+    retired_threads: Vec<WorkerThread>,
     task_count: Arc<AtomicUsize>,
 }
 
@@ -156,7 +170,7 @@ impl<Ctx> ThreadPool<Ctx>
 where
     Ctx: Context + 'static,
 {
-    fn new<C: ContextFactory<Ctx>>(
+    fn new<C: ContextFactory<Ctx> + Send + 'static>(
         name: String,
         num_threads: usize,
         tasks_per_tick: usize,
@@ -167,29 +181,91 @@ where
             queue: FifoQueue::new(),
             stopped: false,
         };
-        let state = Arc::new((Mutex::new(state), Condvar::new()));
-        let mut threads = Vec::with_capacity(num_threads);
-        let task_count = Arc::new(AtomicUsize::new(0));
-        // Threadpool threads
-        for _ in 0..num_threads {
-            let state = state.clone();
-            let task_num = task_count.clone();
-            let ctx = f.create();
-            let thread = Builder::new()
-                .name(name.clone())
+        let mut pool = ThreadPool {
+            name: name,
+            tasks_per_tick: tasks_per_tick,
+            factory: box f,
+            state: Arc::new((Mutex::new(state), Condvar::new())),
+            threads: Vec::with_capacity(num_threads),
+            retired_threads: vec![],
+            task_count: Arc::new(AtomicUsize::new(0)),
+        };
+        pool.spawn_threads(num_threads);
+        pool
+    }
+
+    fn spawn_threads(&mut self, num_threads: usize) {
+        for _ in self.threads.len()..num_threads {
+            let state = self.state.clone();
+            let task_num = self.task_count.clone();
+            let tasks_per_tick = self.tasks_per_tick;
+            let ctx = self.factory.create();
+            let quit = Arc::new(AtomicBool::new(false));
+            let exited = Arc::new(AtomicBool::new(false));
+            let mut worker = Worker::new(quit.clone(), state, task_num, tasks_per_tick, ctx);
+            let thread_exited = exited.clone();
+            let handle = Builder::new()
+                .name(self.name.clone())
                 .spawn(move || {
-                    let mut worker = Worker::new(state, task_num, tasks_per_tick, ctx);
                     worker.run();
+                    thread_exited.store(true, AtomicOrdering::SeqCst);
                 })
                 .unwrap();
-            threads.push(thread);
+            self.threads.push(WorkerThread {
+                handle: handle,
+                quit: quit,
+                exited: exited,
+            });
         }
+    }
 
-        ThreadPool {
-            state: state,
-            threads: threads,
-            task_count: task_count,
+    #[inline]
+    pub fn thread_count(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Changes the number of threads. When shrinking, the extra threads are
+    /// told to quit after their running tasks without being waited for, so it
+    /// never blocks the caller. The queued tasks are left to the others.
+    pub fn resize(&mut self, num_threads: usize) -> Result<(), String> {
+        assert!(num_threads >= 1);
+        {
+            let &(ref lock, ref cvar) = &*self.state;
+            let state = lock.lock().unwrap();
+            if state.stopped {
+                return Err(format!("thread pool {} is stopped", self.name));
+            }
+            if num_threads < self.threads.len() {
+                // The flags are set with the lock held, so the idle threads can't
+                // miss the notification.
+                for t in self.threads.drain(num_threads..) {
+                    t.quit.store(true, AtomicOrdering::SeqCst);
+                    self.retired_threads.push(t);
+                }
+                cvar.notify_all();
+            }
         }
+        self.join_exited_threads();
+        self.spawn_threads(num_threads);
+        Ok(())
+    }
+
+    // Joins the retired threads which have exited, joining them doesn't block.
+    fn join_exited_threads(&mut self) {
+        let (exited, running): (Vec<_>, Vec<_>) = self.retired_threads
+            .drain(..)
+            .partition(|t| t.exited.load(AtomicOrdering::SeqCst));
+        self.retired_threads = running;
+        for t in exited {
+            if let Err(e) = t.handle.join() {
+                error!("thread pool {} failed to join thread: {:?}", self.name, e);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn retired_thread_count(&self) -> usize {
+        self.retired_threads.len()
     }
 
     pub fn execute<F>(&self, job: F)
@@ -223,8 +299,8 @@ where
             cvar.notify_all();
         }
         let mut err_msg = String::new();
-        for t in self.threads.drain(..) {
-            if let Err(e) = t.join() {
+        for t in self.threads.drain(..).chain(self.retired_threads.drain(..)) {
+            if let Err(e) = t.handle.join() {
                 write!(&mut err_msg, "Failed to join thread with err: {:?};", e).unwrap();
             }
         }
@@ -237,6 +313,7 @@ where
 
 // Each thread has a worker.
 struct Worker<C> {
+    quit: Arc<AtomicBool>,
     state: Arc<(Mutex<ScheduleState<C>>, Condvar)>,
     task_count: Arc<AtomicUsize>,
     tasks_per_tick: usize,
@@ -249,12 +326,14 @@ where
     C: Context,
 {
     fn new(
+        quit: Arc<AtomicBool>,
         state: Arc<(Mutex<ScheduleState<C>>, Condvar)>,
         task_count: Arc<AtomicUsize>,
         tasks_per_tick: usize,
         ctx: C,
     ) -> Worker<C> {
         Worker {
+            quit: quit,
             state: state,
             task_count: task_count,
             tasks_per_tick: tasks_per_tick,
@@ -268,7 +347,7 @@ where
         let mut state = lock.lock().unwrap();
         let mut timeout = Some(Duration::from_secs(NAP_SECS));
         loop {
-            if state.stopped {
+            if state.stopped || self.quit.load(AtomicOrdering::SeqCst) {
                 return None;
             }
             match state.queue.pop() {
@@ -314,9 +393,10 @@ where
 mod test {
     use super::*;
 
-    use std::time::Duration;
+    use std::thread;
+    use std::time::{Duration, Instant};
     use std::sync::mpsc::{channel, Sender};
-    use std::sync::{Arc, Mutex};
+    use std::sync::{Arc, Barrier, Mutex};
     use std::sync::atomic::{AtomicIsize, Ordering};
 
     #[test]
@@ -465,4 +545,88 @@ mod test {
         // `on_tick` may be called even if there is no task.
         assert!(ctx.counter.load(Ordering::SeqCst) >= 10);
     }
+
+    #[test]
+    fn test_resize() {
+        let name = thd_name!("test_resize");
+        let mut task_pool = ThreadPoolBuilder::with_default_factory(name)
+            .thread_count(2)
+            .build();
+        let (tx, rx) = channel();
+        let run_tasks = |task_pool: &ThreadPool<DefaultContext>, count: usize| {
+            let barrier = Arc::new(Barrier::new(count));
+            for _ in 0..count {
+                let (barrier, tx) = (barrier.clone(), tx.clone());
+                task_pool.execute(move |_: &mut DefaultContext| {
+                    // Blocks until all the tasks run concurrently.
+                    barrier.wait();
+                    tx.send(()).unwrap();
+                });
+            }
+            for _ in 0..count {
+                rx.recv_timeout(Duration::from_secs(2)).unwrap();
+            }
+        };
+
+        task_pool.resize(4).unwrap();
+        assert_eq!(task_pool.thread_count(), 4);
+        run_tasks(&task_pool, 4);
+
+        task_pool.resize(1).unwrap();
+        assert_eq!(task_pool.thread_count(), 1);
+        for _ in 0..3 {
+            let tx = tx.clone();
+            task_pool.execute(move |_: &mut DefaultContext| tx.send(()).unwrap());
+        }
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        }
+
+        task_pool.resize(3).unwrap();
+        run_tasks(&task_pool, 3);
+        task_pool.stop().unwrap();
+        assert!(task_pool.resize(2).is_err());
+    }
+
+    #[test]
+    fn test_shrink_without_waiting() {
+        let name = thd_name!("test_shrink_without_waiting");
+        let mut task_pool = ThreadPoolBuilder::with_default_factory(name)
+            .thread_count(2)
+            .build();
+        let (tx, rx) = channel();
+        let rx = Arc::new(Mutex::new(rx));
+        let (started_tx, started_rx) = channel();
+        for _ in 0..2 {
+            let (rx, started_tx) = (rx.clone(), started_tx.clone());
+            task_pool.execute(move |_: &mut DefaultContext| {
+                started_tx.send(()).unwrap();
+                rx.lock().unwrap().recv().unwrap();
+            });
+        }
+        for _ in 0..2 {
+            started_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        }
+
+        // Returns while the removed thread is still running its task.
+        task_pool.resize(1).unwrap();
+        assert_eq!(task_pool.thread_count(), 1);
+        assert_eq!(task_pool.retired_thread_count(), 1);
+        for _ in 0..2 {
+            tx.send(()).unwrap();
+        }
+
+        // The removed threads are joined by the next resize once they exit.
+        for _ in 0..10 {
+            task_pool.resize(2).unwrap();
+            task_pool.resize(1).unwrap();
+        }
+        let timer = Instant::now();
+        while task_pool.retired_thread_count() > 0 {
+            assert!(timer.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(10));
+            task_pool.resize(1).unwrap();
+        }
+        task_pool.stop().unwrap();
+    }
 }
@@ -11,12 +11,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant as StdInstant, SystemTime};
 use std::thread::{self, Builder, JoinHandle};
 use std::sync::mpsc::{self, Sender};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 use std::ops::{Add, AddAssign, Sub, SubAssign};
 use std::cmp::Ordering;
 
+use prometheus::CounterVec;
 use time::{Duration as TimeDuration, Timespec};
 
 /// Convert Duration to milliseconds.
@@ -86,7 +88,44 @@ impl Default for SlowTimer {
 }
 
 const DEFAULT_WAIT_MS: u64 = 100;
+// The system time is considered leaping forward if it goes faster than the
+// monotonic clock by more than it in one round of checking.
+const MAX_FORWARD_JUMP_MS: u64 = 1000;
+
+// The monotonic raw time in milliseconds when the system time jumped last time,
+// 0 means it never jumped.
+static LAST_CLOCK_JUMP_MS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    pub static ref CLOCK_JUMP_COUNTER_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_clock_jump_total",
+            "Total number of the system time jumping backward or leaping forward",
+            &["type"]
+        ).unwrap();
+}
+
+fn monotonic_raw_now_ms() -> usize {
+    let now = monotonic_raw_now();
+    now.sec as usize * MILLISECOND_PER_SECOND as usize +
+        now.nsec as usize / NANOSECONDS_PER_MILLISECOND as usize
+}
+
+fn record_clock_jump(tp: &str) {
+    CLOCK_JUMP_COUNTER_VEC.with_label_values(&[tp]).inc();
+    // Make sure it's not 0 even the monotonic clock starts from 0.
+    LAST_CLOCK_JUMP_MS.store(monotonic_raw_now_ms() + 1, AtomicOrdering::SeqCst);
+}
+
+/// Checks whether the system time jumped backward or leaped forward within `d`.
+pub fn clock_jumped_within(d: Duration) -> bool {
+    let last = LAST_CLOCK_JUMP_MS.load(AtomicOrdering::SeqCst);
+    last != 0 && monotonic_raw_now_ms() + 1 <= last + duration_to_ms(d) as usize
+}
 
+/// `Monitor` checks the system time periodically, and reports it if the time
+/// jumps backward or leaps forward, comparing with the monotonic clock. Things
+/// depending on the system time, like TSO and GC, can go wrong in such cases.
 pub struct Monitor {
     tx: Sender<bool>,
     handle: Option<JoinHandle<()>>,
@@ -103,17 +142,34 @@ impl Monitor {
             .name(thd_name!("time-monitor-worker"))
             .spawn(move || while let Err(_) = rx.try_recv() {
                 let before = now();
+                let mono_before = StdInstant::now();
                 thread::sleep(Duration::from_millis(DEFAULT_WAIT_MS));
 
                 let after = now();
-                if let Err(e) = after.duration_since(before) {
-                    error!(
-                        "system time jumped back, {:?} -> {:?}, err {:?}",
-                        before,
-                        after,
-                        e
-                    );
-                    on_jumped()
+                let mono_elapsed = mono_before.elapsed();
+                match after.duration_since(before) {
+                    Err(e) => {
+                        error!(
+                            "system time jumped back, {:?} -> {:?}, err {:?}",
+                            before,
+                            after,
+                            e
+                        );
+                        record_clock_jump("backward");
+                        on_jumped()
+                    }
+                    Ok(elapsed) => if elapsed > mono_elapsed &&
+                        duration_to_ms(elapsed - mono_elapsed) > MAX_FORWARD_JUMP_MS
+                    {
+                        error!(
+                            "system time leaped forward, {:?} -> {:?}, but only {:?} elapsed",
+                            before,
+                            after,
+                            mono_elapsed
+                        );
+                        record_clock_jump("forward");
+                        on_jumped()
+                    },
                 }
             })
             .unwrap();
@@ -154,6 +210,14 @@ impl Drop for Monitor {
 pub use self::inner::monotonic_raw_now;
 use self::inner::monotonic_now;
 use self::inner::monotonic_coarse_now;
+use self::inner::thread_cpu_now;
+
+/// Returns the cpu time consumed by the current thread, it's always 0 on the
+/// platforms other than linux.
+pub fn thread_cpu_time() -> Duration {
+    let t = thread_cpu_now();
+    Duration::new(t.sec as u64, t.nsec as u32)
+}
 
 const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
 const MILLISECOND_PER_SECOND: i64 = 1_000;
@@ -182,6 +246,10 @@ mod inner {
         // TODO Add monotonic coarse clock time impl for macos and windows
         monotonic_raw_now()
     }
+
+    pub fn thread_cpu_now() -> Timespec {
+        Timespec::new(0, 0)
+    }
 }
 
 #[cfg(target_os = "linux")]
@@ -202,6 +270,10 @@ mod inner {
         get_time(libc::CLOCK_MONOTONIC_COARSE)
     }
 
+    pub fn thread_cpu_now() -> Timespec {
+        get_time(libc::CLOCK_THREAD_CPUTIME_ID)
+    }
+
     fn get_time(clock: libc::clockid_t) -> Timespec {
         let mut t = libc::timespec {
             tv_sec: 0,
@@ -372,7 +444,7 @@ impl Sub<Instant> for Instant {
 mod tests {
     use std::time::{Duration, SystemTime};
     use std::thread;
-    use std::ops::Sub;
+    use std::ops::{Add, Sub};
     use std::f64;
     use super::*;
 
@@ -397,6 +469,30 @@ mod tests {
         thread::sleep(Duration::from_secs(1));
 
         assert_eq!(jumped.load(Ordering::SeqCst), true);
+        assert!(clock_jumped_within(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_time_monitor_forward() {
+        let jumped = Arc::new(AtomicBool::new(false));
+        let triggered = AtomicBool::new(false);
+        let now = move || if !triggered.load(Ordering::SeqCst) {
+            triggered.store(true, Ordering::SeqCst);
+            SystemTime::now()
+        } else {
+            SystemTime::now().add(Duration::from_secs(10))
+        };
+
+        let jumped2 = jumped.clone();
+        let on_jumped = move || { jumped2.store(true, Ordering::SeqCst); };
+
+        let forward = CLOCK_JUMP_COUNTER_VEC.with_label_values(&["forward"]).get();
+        let _m = Monitor::new(on_jumped, now);
+        thread::sleep(Duration::from_secs(1));
+
+        assert_eq!(jumped.load(Ordering::SeqCst), true);
+        assert!(CLOCK_JUMP_COUNTER_VEC.with_label_values(&["forward"]).get() > forward);
+        assert!(clock_jumped_within(Duration::from_secs(60)));
     }
 
     #[test]
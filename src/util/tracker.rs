@@ -0,0 +1,234 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cell::RefCell;
+use std::fmt::{self, Display, Formatter};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use kvproto::kvrpcpb::ScanDetail;
+use storage::Statistics;
+
+/// The durations a request spent in the stages of the scheduler.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TimeDetail {
+    // Waiting for the latches and the snapshot.
+    pub wait: Duration,
+    // Waiting for a worker and processing on it.
+    pub process: Duration,
+    // Proposing the writes through raft and applying them.
+    pub commit: Duration,
+}
+
+#[derive(Default)]
+struct TrackerInner {
+    // The events and when they happen since the request is received.
+    events: Vec<(&'static str, Duration)>,
+    time_detail: Option<TimeDetail>,
+    read_bytes: u64,
+    write_bytes: u64,
+    // The cpu time spent on the worker threads.
+    cpu_time: Duration,
+    // The keys and the cursor operations taken to read the data.
+    statistics: Statistics,
+}
+
+/// `Tracker` follows a request from its entry, like a gRPC handler, to where
+/// it's finished. It's shared by all the threads handling the request, which
+/// record the timestamps and the byte counts in it, so slow logs and exec
+/// details can read them from one place.
+pub struct Tracker {
+    tag: &'static str,
+    begin: Instant,
+    inner: Mutex<TrackerInner>,
+}
+
+impl Tracker {
+    pub fn new(tag: &'static str) -> Arc<Tracker> {
+        Arc::new(Tracker {
+            tag: tag,
+            begin: Instant::now(),
+            inner: Mutex::new(TrackerInner::default()),
+        })
+    }
+
+    pub fn tag(&self) -> &'static str {
+        self.tag
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.begin.elapsed()
+    }
+
+    /// Records that `event` happens now.
+    pub fn record_event(&self, event: &'static str) {
+        let elapsed = self.elapsed();
+        self.inner.lock().unwrap().events.push((event, elapsed));
+    }
+
+    pub fn events(&self) -> Vec<(&'static str, Duration)> {
+        self.inner.lock().unwrap().events.clone()
+    }
+
+    /// Updates the time detail, which is `None` until the request enters the
+    /// scheduler.
+    pub fn update_time_detail<F: FnOnce(&mut TimeDetail)>(&self, f: F) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut detail = inner.time_detail.unwrap_or_default();
+        f(&mut detail);
+        inner.time_detail = Some(detail);
+    }
+
+    pub fn time_detail(&self) -> Option<TimeDetail> {
+        self.inner.lock().unwrap().time_detail
+    }
+
+    pub fn add_read_bytes(&self, bytes: u64) {
+        self.inner.lock().unwrap().read_bytes += bytes;
+    }
+
+    pub fn add_write_bytes(&self, bytes: u64) {
+        self.inner.lock().unwrap().write_bytes += bytes;
+    }
+
+    pub fn add_cpu_time(&self, cpu_time: Duration) {
+        self.inner.lock().unwrap().cpu_time += cpu_time;
+    }
+
+    pub fn add_statistics(&self, statistics: &Statistics) {
+        self.inner.lock().unwrap().statistics.add(statistics);
+    }
+
+    pub fn scan_detail(&self) -> ScanDetail {
+        self.inner.lock().unwrap().statistics.scan_detail()
+    }
+
+    pub fn read_bytes(&self) -> u64 {
+        self.inner.lock().unwrap().read_bytes
+    }
+
+    pub fn write_bytes(&self) -> u64 {
+        self.inner.lock().unwrap().write_bytes
+    }
+
+    pub fn cpu_time(&self) -> Duration {
+        self.inner.lock().unwrap().cpu_time
+    }
+}
+
+impl Display for Tracker {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        try!(write!(f, "tracker [tag: {}, elapsed: {:?}, events: [", self.tag, self.elapsed()));
+        for (i, &(event, d)) in inner.events.iter().enumerate() {
+            if i > 0 {
+                try!(write!(f, ", "));
+            }
+            try!(write!(f, "{}@{:?}", event, d));
+        }
+        write!(
+            f,
+            "], read_bytes: {}, write_bytes: {}, cpu_time: {:?}]",
+            inner.read_bytes,
+            inner.write_bytes,
+            inner.cpu_time
+        )
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Tracker>>> = RefCell::new(None);
+}
+
+/// Returns the tracker of the request being handled by the current thread.
+/// It's used to pass the tracker to where the request is queued for other
+/// threads, which then carry it along with the request.
+pub fn current() -> Option<Arc<Tracker>> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+/// `TrackerGuard` restores the previous tracker of the thread when dropped.
+pub struct TrackerGuard {
+    prev: Option<Arc<Tracker>>,
+}
+
+impl Drop for TrackerGuard {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CURRENT.with(|c| *c.borrow_mut() = prev);
+    }
+}
+
+/// Makes `tracker` the current tracker of the thread until the returned guard
+/// is dropped.
+pub fn enter(tracker: &Arc<Tracker>) -> TrackerGuard {
+    let prev = CURRENT.with(|c| mem::replace(&mut *c.borrow_mut(), Some(tracker.clone())));
+    TrackerGuard { prev: prev }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_tracker() {
+        let tracker = Tracker::new("test");
+        assert_eq!(tracker.time_detail(), None);
+        tracker.record_event("a");
+        thread::sleep(Duration::from_millis(10));
+        tracker.record_event("b");
+        let events = tracker.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].0, "b");
+        assert!(events[1].1 >= events[0].1 + Duration::from_millis(10));
+
+        let t = tracker.clone();
+        thread::spawn(move || {
+            t.add_read_bytes(10);
+            t.add_write_bytes(20);
+            t.update_time_detail(|d| d.process += Duration::from_millis(5));
+            t.add_cpu_time(Duration::from_millis(3));
+        }).join()
+            .unwrap();
+        tracker.add_read_bytes(1);
+        assert_eq!(tracker.read_bytes(), 11);
+        assert_eq!(tracker.write_bytes(), 20);
+        assert_eq!(tracker.cpu_time(), Duration::from_millis(3));
+        assert_eq!(
+            tracker.time_detail().unwrap().process,
+            Duration::from_millis(5)
+        );
+        let s = format!("{}", tracker);
+        assert!(s.contains("read_bytes: 11"), "{}", s);
+    }
+
+    #[test]
+    fn test_current_tracker() {
+        assert!(current().is_none());
+        let t1 = Tracker::new("t1");
+        let t2 = Tracker::new("t2");
+        {
+            let _g1 = enter(&t1);
+            assert_eq!(current().unwrap().tag(), "t1");
+            {
+                let _g2 = enter(&t2);
+                assert_eq!(current().unwrap().tag(), "t2");
+            }
+            assert_eq!(current().unwrap().tag(), "t1");
+        }
+        assert!(current().is_none());
+    }
+}
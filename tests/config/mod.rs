@@ -21,7 +21,9 @@ use tikv::server::Config as ServerConfig;
 use tikv::raftstore::store::Config as RaftstoreConfig;
 use tikv::config::*;
 use tikv::storage::Config as StorageConfig;
+use tikv::backup::Config as BackupConfig;
 use tikv::util::config::{ReadableDuration, ReadableSize};
+use tikv::util::logger::LogFormat;
 
 use toml;
 
@@ -50,24 +52,39 @@ fn test_serde_custom_tikv_config() {
     let mut value = TiKvConfig::default();
     value.log_level = LogLevelFilter::Debug;
     value.log_file = "foo".to_owned();
+    value.log_format = LogFormat::Json;
+    value.log_rotation_size = ReadableSize::mb(100);
+    value.log_rotation_compress = true;
+    value.log_max_backups = 10;
+    value.log_queue_size = 1024;
+    value.abort_on_panic = true;
     value.server = ServerConfig {
         cluster_id: 0, // KEEP IT ZERO, it is skipped by serde.
         addr: "example.com:443".to_owned(),
         labels: map!{ "a".to_owned() => "b".to_owned() },
         advertise_addr: "example.com:443".to_owned(),
+        status_addr: "example.com:444".to_owned(),
         notify_capacity: 12_345,
         messages_per_tick: 123,
         grpc_concurrency: 123,
         grpc_concurrent_stream: 1_234,
         grpc_raft_conn_num: 123,
         grpc_stream_initial_window_size: ReadableSize(12_345),
+        snap_stream_initial_window_size: ReadableSize(23_456),
+        snap_max_write_bytes_per_sec: ReadableSize::mb(10),
         end_point_concurrency: 12,
         end_point_max_tasks: 12,
+        end_point_slow_log_threshold: ReadableDuration::millis(500),
+        memory_usage_soft_limit: ReadableSize::gb(12),
+        foreground_cpu_time: 1500,
+        foreground_write_bandwidth: ReadableSize::mb(12),
+        foreground_quota_max_delay: ReadableDuration::secs(1),
     };
     value.metric = MetricConfig {
         interval: ReadableDuration::secs(12),
         address: "example.com:443".to_owned(),
         job: "tikv_1".to_owned(),
+        labels: map!{ "zone".to_owned() => "us-west-1".to_owned() },
     };
     value.raft_store = RaftstoreConfig {
         sync_log: false,
@@ -103,8 +120,10 @@ fn test_serde_custom_tikv_config() {
         consistency_check_interval: ReadableDuration::secs(12),
         report_region_flow_interval: ReadableDuration::minutes(12),
         raft_store_max_leader_lease: ReadableDuration::secs(12),
+        block_lease_read_on_clock_jump: true,
         right_derive_when_split: false,
         allow_remove_leader: true,
+        raft_election_seed: None,
     };
     value.pd = PdConfig {
         endpoints: vec!["example.com:443".to_owned()],
@@ -112,7 +131,7 @@ fn test_serde_custom_tikv_config() {
     value.rocksdb = DbConfig {
         wal_recovery_mode: DBRecoveryMode::AbsoluteConsistency,
         wal_dir: "/var".to_owned(),
-        wal_ttl_seconds: 1,
+        wal_ttl: ReadableDuration::secs(1),
         wal_size_limit: ReadableSize::kb(1),
         max_total_wal_size: ReadableSize::gb(1),
         max_background_jobs: 12,
@@ -251,7 +270,7 @@ fn test_serde_custom_tikv_config() {
     value.raftdb = RaftDbConfig {
         wal_recovery_mode: DBRecoveryMode::SkipAnyCorruptedRecords,
         wal_dir: "/var".to_owned(),
-        wal_ttl_seconds: 1,
+        wal_ttl: ReadableDuration::secs(1),
         wal_size_limit: ReadableSize::kb(12),
         max_total_wal_size: ReadableSize::gb(1),
         max_manifest_file_size: ReadableSize::mb(12),
@@ -301,12 +320,33 @@ fn test_serde_custom_tikv_config() {
     value.storage = StorageConfig {
         data_dir: "/var".to_owned(),
         gc_ratio_threshold: 1.2,
+        gc_by_compaction_filter: true,
+        gc_poll_safe_point_interval: ReadableDuration::secs(30),
         scheduler_notify_capacity: 123,
 
         scheduler_messages_per_tick: 123,
         scheduler_concurrency: 123,
         scheduler_worker_pool_size: 1,
         scheduler_too_busy_threshold: 123,
+        scheduler_pending_write_threshold: ReadableSize::kb(123),
+        scheduler_write_bandwidth_limit: ReadableSize::mb(10),
+        slow_point_read_threshold: ReadableDuration::millis(10),
+        slow_scan_threshold: ReadableDuration::millis(100),
+        slow_write_threshold: ReadableDuration::millis(200),
+        wait_for_lock_timeout: ReadableDuration::secs(3),
+        wake_up_delay_duration: ReadableDuration::millis(100),
+        max_waiters_per_key: 123,
+        in_memory_pessimistic_locks: true,
+        max_key_size: ReadableSize::kb(8),
+        max_value_size: ReadableSize::mb(10),
+        causal_ts_batch_size: 256,
+        causal_ts_renew_interval: ReadableDuration::millis(50),
+    };
+    value.backup = BackupConfig {
+        concurrency: 2,
+        rate_limit: ReadableSize::mb(100),
+        encryption_method: "aes256-ctr".to_owned(),
+        encryption_key_path: "/var/master.key".to_owned(),
     };
 
     let custom = read_file_in_project_dir("tests/config/test-custom.toml");
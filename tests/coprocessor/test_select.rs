@@ -23,7 +23,7 @@ use tikv::coprocessor;
 use kvproto::kvrpcpb::Context;
 use tikv::coprocessor::codec::{datum, table, Datum};
 use tikv::util::codec::number::*;
-use tikv::storage::{Key, Mutation, ALL_CFS};
+use tikv::storage::{Key, MaxTsTracker, Mutation, ALL_CFS};
 use tikv::server::Config;
 use tikv::storage::engine::{self, Engine, TEMP_DIR};
 use tikv::util::worker::Worker;
@@ -619,7 +619,12 @@ fn init_data_with_engine_and_commit(
     let mut end_point = Worker::new("test select worker");
     let mut cfg = Config::default();
     cfg.end_point_concurrency = 1;
-    let runner = EndPointHost::new(store.get_engine(), end_point.scheduler(), &cfg);
+    let runner = EndPointHost::new(
+        store.get_engine(),
+        MaxTsTracker::new(),
+        end_point.scheduler(),
+        &cfg,
+    );
     end_point.start_batch(runner, 5).unwrap();
 
     (store, end_point)
@@ -0,0 +1,42 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The fail points are global, so the tests are in a separate binary which
+// should be run with `--test-threads 1`.
+
+#![feature(plugin)]
+#![feature(box_syntax)]
+#![feature(mpsc_recv_timeout)]
+#![cfg_attr(feature = "dev", plugin(clippy))]
+#![cfg_attr(not(feature = "dev"), allow(unknown_lints))]
+
+extern crate fail;
+extern crate futures;
+extern crate kvproto;
+extern crate tikv;
+
+mod test_storage;
+
+/// Turns off all the fail points when a test finishes, even if it fails.
+pub struct FailPointsGuard;
+
+impl Drop for FailPointsGuard {
+    fn drop(&mut self) {
+        fail::teardown();
+    }
+}
+
+pub fn setup() -> FailPointsGuard {
+    fail::setup();
+    FailPointsGuard
+}
@@ -0,0 +1,86 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use fail;
+use futures::Future;
+use kvproto::kvrpcpb::Context;
+use tikv::storage::{make_key, Config, Mutation, Options, Result, Storage};
+
+fn new_storage() -> Storage {
+    let config = Config::default();
+    let mut storage = Storage::new(&config).unwrap();
+    storage.start(&config).unwrap();
+    storage
+}
+
+fn prewrite(storage: &Storage, key: &[u8], start_ts: u64) -> Receiver<Result<Vec<Result<()>>>> {
+    let f = storage.async_prewrite(
+        Context::new(),
+        vec![Mutation::Put((make_key(key), b"v".to_vec()))],
+        key.to_vec(),
+        start_ts,
+        Options::default(),
+    );
+    // Waits in the background so that the tests can wait with timeouts.
+    let (tx, rx) = channel();
+    thread::spawn(move || tx.send(f.wait()).unwrap());
+    rx
+}
+
+fn must_prewrite_ok(storage: &Storage, key: &[u8], start_ts: u64) {
+    let res = prewrite(storage, key, start_ts).recv().unwrap().unwrap();
+    assert!(res.iter().all(|r| r.is_ok()), "{:?}", res);
+}
+
+#[test]
+fn test_mvcc_prewrite_fail_point() {
+    let _guard = ::setup();
+    let mut storage = new_storage();
+
+    fail::cfg("mvcc_prewrite", "return").unwrap();
+    assert!(prewrite(&storage, b"k", 10).recv().unwrap().is_err());
+    fail::remove("mvcc_prewrite");
+    must_prewrite_ok(&storage, b"k", 10);
+    storage.stop().unwrap();
+}
+
+#[test]
+fn test_scheduler_process_write_fail_point() {
+    let _guard = ::setup();
+    let mut storage = new_storage();
+
+    // Only the first write fails.
+    fail::cfg("scheduler_process_write", "1*return").unwrap();
+    assert!(prewrite(&storage, b"k", 10).recv().unwrap().is_err());
+    must_prewrite_ok(&storage, b"k", 10);
+    storage.stop().unwrap();
+}
+
+#[test]
+fn test_scheduler_pause_on_async_write() {
+    let _guard = ::setup();
+    let mut storage = new_storage();
+
+    fail::cfg("scheduler_async_write_finish", "pause").unwrap();
+    let rx = prewrite(&storage, b"k", 10);
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    // The paused write goes on once the fail point is removed.
+    fail::remove("scheduler_async_write_finish");
+    let res = rx.recv_timeout(Duration::from_secs(3)).unwrap().unwrap();
+    assert!(res.iter().all(|r| r.is_ok()), "{:?}", res);
+    storage.stop().unwrap();
+}
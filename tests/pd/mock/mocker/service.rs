@@ -73,7 +73,7 @@ impl PdMocker for Service {
 
     fn bootstrap(&self, req: &BootstrapRequest) -> Option<Result<BootstrapResponse>> {
         let store = req.get_store();
-        let store_path = make_region_key(store.get_id());
+        let store_path = make_store_key(store.get_id());
         let store_value = store.write_to_bytes().unwrap();
 
         let region = req.get_region();
@@ -124,7 +124,7 @@ impl PdMocker for Service {
     fn get_store(&self, req: &GetStoreRequest) -> Option<Result<GetStoreResponse>> {
         let mut resp = GetStoreResponse::new();
         let mut store = Store::new();
-        let store_path = make_region_key(req.get_store_id());
+        let store_path = make_store_key(req.get_store_id());
 
         let storage = self.storage.lock().unwrap();
         match storage.get(&store_path) {
@@ -146,6 +146,22 @@ impl PdMocker for Service {
         }
     }
 
+    fn get_all_stores(&self, _: &GetAllStoresRequest) -> Option<Result<GetAllStoresResponse>> {
+        let mut resp = GetAllStoresResponse::new();
+        resp.set_header(Service::header());
+
+        let prefix = make_store_key_prefix();
+        let storage = self.storage.lock().unwrap();
+        for (key, value) in storage.iter() {
+            if key.starts_with(&prefix) {
+                let mut store = Store::new();
+                store.merge_from_bytes(value).unwrap();
+                resp.mut_stores().push(store);
+            }
+        }
+        Some(Ok(resp))
+    }
+
     fn get_region_by_id(&self, req: &GetRegionByIDRequest) -> Option<Result<GetRegionResponse>> {
         let mut resp = GetRegionResponse::new();
         let mut region = Region::new();
@@ -215,3 +231,11 @@ const CLUSTER_ROOT_PATH: &'static str = "raft";
 fn make_region_key(region_id: u64) -> String {
     return format!("{}/r/{}", CLUSTER_ROOT_PATH, region_id);
 }
+
+fn make_store_key_prefix() -> String {
+    format!("{}/s/", CLUSTER_ROOT_PATH)
+}
+
+fn make_store_key(store_id: u64) -> String {
+    format!("{}{}", make_store_key_prefix(), store_id)
+}
@@ -66,6 +66,10 @@ fn test_rpc_client() {
     let tmp_store = client.get_store(store_id).unwrap();
     assert_eq!(tmp_store.get_id(), store.get_id());
 
+    let stores = client.get_all_stores().unwrap();
+    assert_eq!(stores.len(), 1);
+    assert_eq!(stores[0], store);
+
     let tmp_region = client.get_region_by_id(region_id).wait().unwrap().unwrap();
     assert_eq!(tmp_region.get_id(), region.get_id());
 
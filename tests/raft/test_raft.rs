@@ -1455,6 +1455,28 @@ fn test_pass_election_timeout() {
     }
 }
 
+#[test]
+fn test_seeded_election_timeout() {
+    let timeouts = |id: u64, seed: u64| {
+        let mut config = new_test_config(id, vec![1, 2], 10, 1);
+        config.election_seed = Some(seed);
+        let mut sm = new_test_raft_with_config(&config, new_storage());
+        let mut res = vec![];
+        for _ in 0..100 {
+            sm.reset_randomized_election_timeout();
+            let timeout = sm.get_randomized_election_timeout();
+            assert!(timeout >= 10 && timeout < 20);
+            res.push(timeout);
+        }
+        res
+    };
+
+    assert_eq!(timeouts(1, 0), timeouts(1, 0));
+    assert_eq!(timeouts(1, 42), timeouts(1, 42));
+    assert_ne!(timeouts(1, 42), timeouts(2, 42));
+    assert_ne!(timeouts(1, 42), timeouts(1, 43));
+}
+
 // ensure that the Step function ignores the message from old term and does not pass it to the
 // actual stepX function.
 #[test]
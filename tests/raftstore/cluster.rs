@@ -17,6 +17,7 @@ use std::sync::{self, Arc, RwLock};
 use std::time::*;
 use std::{result, thread};
 use std::path::Path;
+use std::ops::Range;
 
 use rocksdb::DB;
 use tempdir::TempDir;
@@ -693,6 +694,10 @@ impl<T: Simulator> Cluster<T> {
         }
     }
 
+    pub fn add_send_filter_on_node(&self, node_id: u64, filter: SendFilter) {
+        self.sim.wl().add_send_filter(node_id, filter);
+    }
+
     pub fn transfer_leader(&mut self, region_id: u64, leader: metapb::Peer) {
         let epoch = self.get_region_epoch(region_id);
         let transfer_leader = new_admin_request(region_id, &epoch, new_transfer_leader_cmd(leader));
@@ -777,6 +782,70 @@ impl<T: Simulator> Cluster<T> {
         }
     }
 
+    /// Splits the region containing `split_key` at it, returns the left and
+    /// the right regions.
+    pub fn must_split_at(&mut self, split_key: &[u8]) -> (metapb::Region, metapb::Region) {
+        let region = self.get_region(split_key);
+        self.must_split(&region, split_key);
+        let left = self.get_region(region.get_start_key());
+        let right = self.get_region(split_key);
+        assert_eq!(left.get_end_key(), split_key);
+        (left, right)
+    }
+
+    /// Splits the regions at all the `split_keys`, returns the regions ordered
+    /// by their start keys.
+    pub fn must_split_at_keys(&mut self, split_keys: &[&[u8]]) -> Vec<metapb::Region> {
+        for key in split_keys {
+            self.must_split_at(key);
+        }
+        let mut regions = vec![self.get_region(b"")];
+        for key in split_keys {
+            regions.push(self.get_region(key));
+        }
+        regions.sort_by(|a, b| a.get_start_key().cmp(b.get_start_key()));
+        regions.dedup_by_key(|r| r.get_id());
+        regions
+    }
+
+    /// Waits until pd knows `count` regions.
+    pub fn wait_for_regions(&self, count: usize) {
+        let timer = Instant::now();
+        loop {
+            let n = self.pd_client.get_regions_number();
+            if n == count {
+                return;
+            }
+            if timer.elapsed() > Duration::from_secs(5) {
+                panic!("expect {} regions, but got {} after 5s", count, n);
+            }
+            sleep_ms(20);
+        }
+    }
+
+    /// Transfers the leader of the region to its peer on `store_id`.
+    pub fn must_transfer_leader_to_store(&mut self, region_id: u64, store_id: u64) {
+        let region = self.pd_client
+            .get_region_by_id(region_id)
+            .wait()
+            .unwrap()
+            .unwrap();
+        let peer = region
+            .get_peers()
+            .iter()
+            .find(|p| p.get_store_id() == store_id)
+            .cloned()
+            .unwrap_or_else(|| panic!("region {} has no peer on store {}", region_id, store_id));
+        self.must_transfer_leader(region_id, peer);
+    }
+
+    /// Puts `gen_key(prefix, id)` -> `value` for every id in `ids`.
+    pub fn must_put_keys(&mut self, prefix: &[u8], ids: Range<u64>, value: &[u8]) {
+        for id in ids {
+            self.must_put(&gen_key(prefix, id), value);
+        }
+    }
+
     /// Make sure region exists on that store.
     pub fn must_region_exist(&mut self, region_id: u64, store_id: u64) {
         let mut try_cnt = 0;
@@ -828,8 +897,19 @@ impl<T: Simulator> Cluster<T> {
     }
 
     // it's so common that we provide an API for it
-    pub fn partition(&self, s1: Vec<u64>, s2: Vec<u64>) {
-        self.add_send_filter(PartitionFilterFactory::new(s1, s2));
+    pub fn partition(&self, s1: Vec<u64>, s2: Vec<u64>) -> PartitionHandle {
+        let factory = PartitionFilterFactory::new(s1, s2);
+        let handle = factory.handle();
+        self.add_send_filter(factory);
+        handle
+    }
+
+    /// Partitions the stores into `groups`, stores not in any group are isolated.
+    pub fn partition_groups(&self, groups: Vec<Vec<u64>>) -> PartitionHandle {
+        let factory = GroupPartitionFilterFactory::new(groups);
+        let handle = factory.handle();
+        self.add_send_filter(factory);
+        handle
     }
 }
 
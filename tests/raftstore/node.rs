@@ -18,11 +18,14 @@ use std::sync::mpsc::Sender;
 use std::time::Duration;
 use std::boxed::FnBox;
 use std::ops::Deref;
+use std::path::Path;
 
 use tempdir::TempDir;
 
 use super::cluster::{Cluster, Simulator};
 use tikv::server::Node;
+use tikv::import::SSTImporter;
+use tikv::raftstore::coprocessor::CoprocessorHost;
 use tikv::raftstore::store::*;
 use kvproto::metapb;
 use kvproto::raft_cmdpb::*;
@@ -185,11 +188,15 @@ impl Simulator for NodeCluster {
             (snap_mgr.clone(), None)
         };
 
+        let import_path = Path::new(engines.kv_engine.path()).join("import");
+        let importer = Arc::new(SSTImporter::new(import_path).unwrap());
         node.start(
             event_loop,
             engines.clone(),
             simulate_trans.clone(),
             snap_mgr.clone(),
+            importer,
+            CoprocessorHost::new(),
             snap_status_receiver,
         ).unwrap();
         assert!(
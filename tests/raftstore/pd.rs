@@ -71,9 +71,12 @@ struct Cluster {
 
     store_stats: HashMap<u64, pdpb::StoreStats>,
     split_count: usize,
+    scattered_regions: HashSet<u64>,
 
     down_peers: HashMap<u64, pdpb::PeerStats>,
     pending_peers: HashMap<u64, metapb::Peer>,
+    // region id -> the leader in the last heartbeat.
+    leaders: HashMap<u64, metapb::Peer>,
     is_bootstraped: bool,
 }
 
@@ -92,8 +95,10 @@ impl Cluster {
             rule: None,
             store_stats: HashMap::new(),
             split_count: 0,
+            scattered_regions: HashSet::new(),
             down_peers: HashMap::new(),
             pending_peers: HashMap::new(),
+            leaders: HashMap::new(),
             is_bootstraped: false,
         }
     }
@@ -354,6 +359,7 @@ impl Cluster {
         for p in region_stat.pending_peers {
             self.pending_peers.insert(p.get_id(), p);
         }
+        self.leaders.insert(region.get_id(), leader.clone());
 
         try!(self.handle_heartbeat_version(region.clone()));
         self.handle_heartbeat_conf_ver(region, leader)
@@ -417,6 +423,8 @@ pub fn bootstrap_with_first_region(pd_client: Arc<TestPdClient>) -> Result<()> {
 pub struct TestPdClient {
     cluster_id: u64,
     cluster: RwLock<Cluster>,
+    tso: AtomicUsize,
+    gc_safe_point: AtomicUsize,
 }
 
 impl TestPdClient {
@@ -424,6 +432,8 @@ impl TestPdClient {
         TestPdClient {
             cluster_id: cluster_id,
             cluster: RwLock::new(Cluster::new(cluster_id)),
+            tso: AtomicUsize::new(0),
+            gc_safe_point: AtomicUsize::new(0),
         }
     }
 
@@ -580,6 +590,10 @@ impl TestPdClient {
         self.cluster.rl().split_count
     }
 
+    pub fn is_region_scattered(&self, region_id: u64) -> bool {
+        self.cluster.rl().scattered_regions.contains(&region_id)
+    }
+
     pub fn get_down_peers(&self) -> HashMap<u64, pdpb::PeerStats> {
         self.cluster.rl().down_peers.clone()
     }
@@ -591,6 +605,10 @@ impl TestPdClient {
     pub fn set_bootstrap(&self, is_bootstraped: bool) {
         self.cluster.wl().set_bootstrap(is_bootstraped);
     }
+
+    pub fn set_gc_safe_point(&self, safe_point: u64) {
+        self.gc_safe_point.store(safe_point as usize, Ordering::SeqCst);
+    }
 }
 
 impl PdClient for TestPdClient {
@@ -627,6 +645,11 @@ impl PdClient for TestPdClient {
         self.cluster.rl().get_store(store_id)
     }
 
+    fn get_all_stores(&self) -> Result<Vec<metapb::Store>> {
+        try!(self.check_bootstrap());
+        Ok(self.cluster.rl().get_stores())
+    }
+
 
     fn get_region(&self, key: &[u8]) -> Result<metapb::Region> {
         try!(self.check_bootstrap());
@@ -639,6 +662,11 @@ impl PdClient for TestPdClient {
         Err(box_err!("no region contains key {:?}", escape(key)))
     }
 
+    fn get_region_leader(&self, key: &[u8]) -> Result<Option<metapb::Peer>> {
+        let region = try!(self.get_region(key));
+        Ok(self.cluster.rl().leaders.get(&region.get_id()).cloned())
+    }
+
     fn get_region_by_id(&self, region_id: u64) -> PdFuture<Option<metapb::Region>> {
         if let Err(e) = self.check_bootstrap() {
             return err(e).boxed();
@@ -741,4 +769,29 @@ impl PdClient for TestPdClient {
         self.cluster.wl().split_count += 1;
         ok(()).boxed()
     }
+
+    fn scatter_region(&self, region: metapb::Region) -> PdFuture<()> {
+        // The regions are not moved actually, only the requests are recorded.
+        if let Err(e) = self.check_bootstrap() {
+            return err(e).boxed();
+        }
+        self.cluster
+            .wl()
+            .scattered_regions
+            .insert(region.get_id());
+        ok(()).boxed()
+    }
+
+    fn get_tso(&self) -> PdFuture<u64> {
+        self.batch_get_tso(1)
+    }
+
+    fn batch_get_tso(&self, count: u32) -> PdFuture<u64> {
+        let ts = self.tso.fetch_add(count as usize, Ordering::SeqCst) + count as usize;
+        ok(ts as u64).boxed()
+    }
+
+    fn get_gc_safe_point(&self) -> PdFuture<u64> {
+        ok(self.gc_safe_point.load(Ordering::SeqCst) as u64).boxed()
+    }
 }
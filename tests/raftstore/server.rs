@@ -13,6 +13,7 @@
 
 use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::{mpsc, Arc, RwLock};
 use std::time::Duration;
 use std::boxed::FnBox;
@@ -22,6 +23,12 @@ use tempdir::TempDir;
 
 use super::cluster::{Cluster, Simulator};
 use tikv::config::TiKvConfig;
+use tikv::import::SSTImporter;
+use tikv::cdc::{CdcObserver, Endpoint as CdcEndpoint, Task as CdcTask};
+use tikv::deadlock::{DeadlockObserver, Detector, DetectorScheduler, Task as DeadlockTask};
+use tikv::raftstore::coprocessor::CoprocessorHost;
+use tikv::resolved_ts::{Endpoint as ResolvedTsEndpoint, RegionsResolvedTs, ResolvedTsObserver,
+                        Task as ResolvedTsTask};
 use tikv::server::{Server, ServerTransport};
 use tikv::server::{create_raft_storage, Config, Node, PdStoreAddrResolver, RaftClient};
 use tikv::server::resolve::{self, Task as ResolveTask};
@@ -30,8 +37,8 @@ use tikv::server::transport::RaftStoreRouter;
 use tikv::raftstore::{store, Error, Result};
 use tikv::raftstore::store::{Engines, Msg as StoreMsg, SnapManager};
 use tikv::util::transport::SendCh;
-use tikv::util::worker::Worker;
-use tikv::storage::{CfName, Engine};
+use tikv::util::worker::{FutureWorker, Worker};
+use tikv::storage::{CfName, Engine, LockObserver, MaxTsObserver};
 use kvproto::raft_serverpb::{self, RaftMessage};
 use kvproto::raft_cmdpb::*;
 
@@ -51,12 +58,16 @@ struct ServerMeta {
     sim_trans: SimulateServerTransport,
     store_ch: SendCh<StoreMsg>,
     worker: Worker<ResolveTask>,
+    cdc_worker: FutureWorker<CdcTask>,
+    resolved_ts_worker: FutureWorker<ResolvedTsTask>,
+    deadlock_worker: FutureWorker<DeadlockTask>,
 }
 
 pub struct ServerCluster {
     metas: HashMap<u64, ServerMeta>,
     addrs: HashMap<u64, SocketAddr>,
     pub storages: HashMap<u64, Box<Engine>>,
+    pub resolved_ts: HashMap<u64, Arc<RegionsResolvedTs>>,
     snap_paths: HashMap<u64, TempDir>,
     pd_client: Arc<TestPdClient>,
     raft_client: RaftClient,
@@ -75,6 +86,7 @@ impl ServerCluster {
             addrs: HashMap::new(),
             pd_client: pd_client,
             storages: HashMap::new(),
+            resolved_ts: HashMap::new(),
             snap_paths: HashMap::new(),
             raft_client: RaftClient::new(env, Config::default()),
         }
@@ -115,12 +127,46 @@ impl Simulator for ServerCluster {
         let mut store =
             create_raft_storage(sim_router.clone(), engines.kv_engine.clone(), &cfg.storage)
                 .unwrap();
+        let mut deadlock_worker = FutureWorker::new("deadlock-detector");
+        let deadlock_scheduler = DetectorScheduler::new(deadlock_worker.scheduler());
+        store.set_deadlock_detector(Arc::new(deadlock_scheduler.clone()));
         store.start(&cfg.storage).unwrap();
         self.storages.insert(node_id, store.get_engine());
 
         // Create pd client, snapshot manager, server.
         let (worker, resolver) = resolve::new_resolver(self.pd_client.clone()).unwrap();
         let snap_mgr = SnapManager::new(tmp_str, Some(store_sendch));
+        let import_path = Path::new(engines.kv_engine.path()).join("import");
+        let importer = Arc::new(SSTImporter::new(import_path).unwrap());
+        let mut cdc_worker = FutureWorker::new("cdc");
+        let cdc_observer = CdcObserver::new(cdc_worker.scheduler());
+        let mut coprocessor_host = CoprocessorHost::new();
+        coprocessor_host
+            .registry
+            .register_observer(200, Box::new(cdc_observer.clone()));
+        let mut resolved_ts_worker = FutureWorker::new("resolved-ts");
+        let resolved_ts_observer = ResolvedTsObserver::new(resolved_ts_worker.scheduler());
+        coprocessor_host
+            .registry
+            .register_observer(300, Box::new(resolved_ts_observer));
+        let lock_observer = LockObserver::new();
+        store.set_lock_observer(lock_observer.clone());
+        coprocessor_host
+            .registry
+            .register_observer(400, Box::new(lock_observer));
+        coprocessor_host.registry.register_observer(
+            500,
+            Box::new(DeadlockObserver::new(deadlock_scheduler.clone())),
+        );
+        coprocessor_host.registry.register_observer(
+            600,
+            Box::new(MaxTsObserver::new(store.get_max_ts(), self.pd_client.clone())),
+        );
+        if cfg.storage.in_memory_pessimistic_locks {
+            coprocessor_host
+                .registry
+                .register_observer(700, Box::new(store.get_pessimistic_locks()));
+        }
         let mut server = Server::new(
             &cfg.server,
             cfg.raft_store.region_split_size.0 as usize,
@@ -129,6 +175,11 @@ impl Simulator for ServerCluster {
             snap_status_sender,
             resolver,
             snap_mgr.clone(),
+            importer.clone(),
+            &cfg.backup,
+            cdc_worker.scheduler(),
+            deadlock_scheduler.clone(),
+            Some(engines.clone()),
         ).unwrap();
         let addr = server.listening_addr();
         cfg.server.addr = format!("{}", addr);
@@ -142,11 +193,14 @@ impl Simulator for ServerCluster {
             &cfg.raft_store,
             self.pd_client.clone(),
         );
+        let kv_engine = engines.kv_engine.clone();
         node.start(
             event_loop,
             engines,
             simulate_trans.clone(),
             snap_mgr.clone(),
+            importer,
+            coprocessor_host,
             snap_status_receiver,
         ).unwrap();
         assert!(node_id == 0 || node_id == node.id());
@@ -155,6 +209,33 @@ impl Simulator for ServerCluster {
             self.snap_paths.insert(node_id, tmp);
         }
 
+        let cdc_endpoint = CdcEndpoint::new(
+            self.pd_client.clone(),
+            cdc_worker.scheduler(),
+            kv_engine.clone(),
+            store.get_engine(),
+            store.get_max_ts(),
+            cdc_observer,
+        );
+        cdc_worker.start(cdc_endpoint).unwrap();
+        let resolved_ts_endpoint = ResolvedTsEndpoint::new(
+            self.pd_client.clone(),
+            resolved_ts_worker.scheduler(),
+            kv_engine,
+            store.get_engine(),
+            store.get_max_ts(),
+        );
+        self.resolved_ts
+            .insert(node_id, resolved_ts_endpoint.resolved_ts());
+        resolved_ts_worker.start(resolved_ts_endpoint).unwrap();
+        let detector = Detector::new(
+            node_id,
+            self.pd_client.clone(),
+            deadlock_scheduler,
+            cfg.storage.wait_for_lock_timeout.0,
+        );
+        deadlock_worker.start(detector).unwrap();
+
         server.start(&cfg.server).unwrap();
 
         self.metas.insert(
@@ -166,6 +247,9 @@ impl Simulator for ServerCluster {
                 router: sim_router,
                 sim_trans: simulate_trans,
                 worker: worker,
+                cdc_worker: cdc_worker,
+                resolved_ts_worker: resolved_ts_worker,
+                deadlock_worker: deadlock_worker,
             },
         );
         self.addrs.insert(node_id, addr);
@@ -186,6 +270,9 @@ impl Simulator for ServerCluster {
             meta.server.stop().unwrap();
             meta.node.stop().unwrap();
             meta.worker.stop().unwrap().join().unwrap();
+            meta.cdc_worker.stop().unwrap().join().unwrap();
+            meta.resolved_ts_worker.stop().unwrap().join().unwrap();
+            meta.deadlock_worker.stop().unwrap().join().unwrap();
         }
     }
 
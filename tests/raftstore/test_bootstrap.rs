@@ -16,6 +16,8 @@ use std::path::Path;
 use tikv::raftstore::store::{bootstrap_store, create_event_loop, keys, Engines, Peekable,
                              SnapManager};
 use tikv::server::Node;
+use tikv::import::SSTImporter;
+use tikv::raftstore::coprocessor::CoprocessorHost;
 use tikv::storage::{ALL_CFS, CF_RAFT};
 use tikv::util::rocksdb;
 use tempdir::TempDir;
@@ -69,6 +71,7 @@ fn test_node_bootstrap_with_prepared_data() {
     );
     let snap_mgr = SnapManager::new(tmp_mgr.path().to_str().unwrap(), Some(node.get_sendch()));
     let (_, snapshot_status_receiver) = mpsc::channel();
+    let importer = Arc::new(SSTImporter::new(tmp_mgr.path().join("import")).unwrap());
 
 
     // assume there is a node has bootstrapped the cluster and add region in pd successfully
@@ -98,6 +101,8 @@ fn test_node_bootstrap_with_prepared_data() {
         engines,
         simulate_trans,
         snap_mgr,
+        importer,
+        CoprocessorHost::new(),
         snapshot_status_receiver,
     ).unwrap();
     assert!(
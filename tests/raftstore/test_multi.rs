@@ -25,7 +25,6 @@ use super::node::new_node_cluster;
 use super::server::new_server_cluster;
 use super::transport_simulate::*;
 
-use rand;
 use rand::Rng;
 use std::time::Duration;
 use std::sync::*;
@@ -161,7 +160,7 @@ fn test_multi_random_restart<T: Simulator>(
 ) {
     cluster.run();
 
-    let mut rng = rand::thread_rng();
+    let mut rng = new_test_rng();
     let mut value = [0u8; 5];
 
     for i in 1..restart_count {
@@ -11,21 +11,40 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::fs;
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use grpc::{ChannelBuilder, Environment};
+use grpc::{ChannelBuilder, Environment, Error, RpcStatusCode, WriteFlags};
+use rocksdb::Writable;
+use tempdir::TempDir;
 use tikv::util::HandyRwLock;
+use tikv::raftstore::store::{keys, Mutable, Peekable};
+use tikv::storage::{CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
+use tikv::storage::mvcc::{Lock, LockType};
+use tikv::util::rocksdb::get_cf_handle;
 
 use kvproto::tikvpb_grpc::TikvClient;
+use kvproto::debugpb_grpc::DebugClient;
+use kvproto::backup::BackupRequest;
+use kvproto::backup_grpc::BackupClient;
+use kvproto::cdcpb::{ChangeDataRequest, Event, Event_LogType, Event_Row_OpType};
+use kvproto::cdcpb_grpc::ChangeDataClient;
+use kvproto::import_sstpb::SplitAndScatterRequest;
+use kvproto::import_sstpb_grpc::ImportSstClient;
+use kvproto::debugpb;
+use kvproto::eraftpb;
+use kvproto::metapb;
 use kvproto::kvrpcpb::*;
 use kvproto::raft_serverpb::*;
-use futures::{Future, Sink};
+use futures::{Future, Sink, Stream};
 use kvproto::coprocessor::*;
 
 use super::server::*;
 use super::cluster::Cluster;
 
-fn must_new_cluster_and_client() -> (Cluster<ServerCluster>, TikvClient, Context) {
+fn must_new_cluster() -> (Cluster<ServerCluster>, metapb::Peer, Context) {
     let count = 1;
     let mut cluster = new_server_cluster(0, count);
     cluster.run();
@@ -38,6 +57,12 @@ fn must_new_cluster_and_client() -> (Cluster<ServerCluster>, TikvClient, Context
     ctx.set_peer(leader.clone());
     ctx.set_region_epoch(epoch);
 
+    (cluster, leader, ctx)
+}
+
+fn must_new_cluster_and_client() -> (Cluster<ServerCluster>, TikvClient, Context) {
+    let (cluster, leader, ctx) = must_new_cluster();
+
     let addr = cluster.sim.rl().get_addr(leader.get_store_id());
     let env = Arc::new(Environment::new(1));
     let channel = ChannelBuilder::new(env).connect(&format!("{}", addr));
@@ -92,6 +117,93 @@ fn test_rawkv() {
     assert!(delete_resp.error.is_empty());
 }
 
+#[test]
+fn test_raw_delete_range() {
+    let (_cluster, client, ctx) = must_new_cluster_and_client();
+    let v = b"value".to_vec();
+    for k in &[b"k1", b"k2", b"k3"] {
+        let mut put_req = RawPutRequest::new();
+        put_req.set_context(ctx.clone());
+        put_req.key = k.to_vec();
+        put_req.value = v.clone();
+        let put_resp = client.raw_put(put_req).unwrap();
+        assert!(!put_resp.has_region_error());
+        assert!(put_resp.error.is_empty());
+    }
+
+    let mut delete_req = RawDeleteRangeRequest::new();
+    delete_req.set_context(ctx.clone());
+    delete_req.start_key = b"k1".to_vec();
+    delete_req.end_key = b"k3".to_vec();
+    let delete_resp = client.raw_delete_range(delete_req).unwrap();
+    assert!(!delete_resp.has_region_error());
+    assert!(delete_resp.error.is_empty());
+
+    let mut scan_req = RawScanRequest::new();
+    scan_req.set_context(ctx.clone());
+    scan_req.limit = 10;
+    let scan_resp = client.raw_scan(scan_req).unwrap();
+    assert!(!scan_resp.has_region_error());
+    assert_eq!(scan_resp.kvs.len(), 1);
+    assert_eq!(scan_resp.kvs[0].key, b"k3".to_vec());
+
+    // The range is invalid.
+    let mut delete_req = RawDeleteRangeRequest::new();
+    delete_req.set_context(ctx.clone());
+    delete_req.start_key = b"k3".to_vec();
+    delete_req.end_key = b"k1".to_vec();
+    let delete_resp = client.raw_delete_range(delete_req).unwrap();
+    assert!(!delete_resp.error.is_empty());
+}
+
+#[test]
+fn test_raw_batch() {
+    let (_cluster, client, ctx) = must_new_cluster_and_client();
+    let keys = vec![b"k1".to_vec(), b"k2".to_vec()];
+    let values = vec![b"v1".to_vec(), b"v2".to_vec()];
+
+    // Raw batch put
+    let mut put_req = RawBatchPutRequest::new();
+    put_req.set_context(ctx.clone());
+    let pairs = keys.iter().zip(&values).map(|(k, v)| {
+        let mut pair = KvPair::new();
+        pair.set_key(k.clone());
+        pair.set_value(v.clone());
+        pair
+    });
+    put_req.set_pairs(pairs.collect());
+    let put_resp = client.raw_batch_put(put_req).unwrap();
+    assert!(!put_resp.has_region_error());
+    assert!(put_resp.error.is_empty());
+
+    // Raw batch get
+    let mut get_req = RawBatchGetRequest::new();
+    get_req.set_context(ctx.clone());
+    get_req.set_keys(keys.clone().into());
+    let get_resp = client.raw_batch_get(get_req).unwrap();
+    assert!(!get_resp.has_region_error());
+    assert_eq!(get_resp.pairs.len(), 2);
+    for (pair, (k, v)) in get_resp.pairs.into_iter().zip(keys.iter().zip(&values)) {
+        assert!(!pair.has_error());
+        assert_eq!(&pair.key, k);
+        assert_eq!(&pair.value, v);
+    }
+
+    // Raw batch delete
+    let mut delete_req = RawBatchDeleteRequest::new();
+    delete_req.set_context(ctx.clone());
+    delete_req.set_keys(keys.clone().into());
+    let delete_resp = client.raw_batch_delete(delete_req).unwrap();
+    assert!(!delete_resp.has_region_error());
+    assert!(delete_resp.error.is_empty());
+
+    let mut get_req = RawBatchGetRequest::new();
+    get_req.set_context(ctx.clone());
+    get_req.set_keys(keys.into());
+    let get_resp = client.raw_batch_get(get_req).unwrap();
+    assert!(get_resp.pairs.is_empty());
+}
+
 fn must_kv_prewrite(client: &TikvClient, ctx: Context, muts: Vec<Mutation>, pk: Vec<u8>, ts: u64) {
     let mut prewrite_req = PrewriteRequest::new();
     prewrite_req.set_context(ctx);
@@ -470,3 +582,684 @@ fn test_coprocessor() {
     // SQL push down commands
     client.coprocessor(Request::new()).unwrap();
 }
+
+fn must_new_cluster_and_debug_client() -> (Cluster<ServerCluster>, DebugClient, u64) {
+    let (cluster, leader, _) = must_new_cluster();
+
+    let env = Arc::new(Environment::new(1));
+    let channel =
+        ChannelBuilder::new(env).connect(&cluster.sim.rl().get_addr(leader.get_store_id()));
+    let client = DebugClient::new(channel);
+
+    (cluster, client, leader.get_store_id())
+}
+
+#[test]
+fn test_debug_get() {
+    let (cluster, debug_client, store_id) = must_new_cluster_and_debug_client();
+    let (k, v) = (b"key", b"value");
+
+    // Put some data.
+    let engine = cluster.get_engine(store_id);
+    let key = keys::data_key(k);
+    engine.put(&key, v).unwrap();
+    assert_eq!(&*engine.get(&key).unwrap().unwrap(), v);
+
+    // Debug get
+    let mut req = debugpb::GetRequest::new();
+    req.set_cf(CF_DEFAULT.to_owned());
+    req.set_db(debugpb::DB::KV);
+    req.set_key(key);
+    let mut resp = debug_client.get(req.clone()).unwrap();
+    assert_eq!(resp.take_value(), v);
+
+    req.set_key(b"foo".to_vec());
+    match debug_client.get(req).unwrap_err() {
+        Error::RpcFailure(status) => {
+            assert_eq!(status.status, RpcStatusCode::NotFound);
+        }
+        _ => panic!("expect NotFound"),
+    }
+}
+
+#[test]
+fn test_debug_raft_log() {
+    let (cluster, debug_client, store_id) = must_new_cluster_and_debug_client();
+
+    // Put some data.
+    let engine = cluster.get_raft_engine(store_id);
+    let (region_id, log_index) = (200, 200);
+    let key = keys::raft_log_key(region_id, log_index);
+    let mut entry = eraftpb::Entry::new();
+    entry.set_term(1);
+    entry.set_index(1);
+    entry.set_entry_type(eraftpb::EntryType::EntryNormal);
+    entry.set_data(vec![42]);
+    engine.put_msg(&key, &entry).unwrap();
+    assert_eq!(
+        engine.get_msg::<eraftpb::Entry>(&key).unwrap().unwrap(),
+        entry
+    );
+
+    // Debug raft_log
+    let mut req = debugpb::RaftLogRequest::new();
+    req.set_region_id(region_id);
+    req.set_log_index(log_index);
+    let resp = debug_client.raft_log(req).unwrap();
+    assert_ne!(resp.get_entry(), &eraftpb::Entry::new());
+
+    let mut req = debugpb::RaftLogRequest::new();
+    req.set_region_id(region_id + 1);
+    req.set_log_index(region_id + 1);
+    match debug_client.raft_log(req).unwrap_err() {
+        Error::RpcFailure(status) => {
+            assert_eq!(status.status, RpcStatusCode::NotFound);
+        }
+        _ => panic!("expect NotFound"),
+    }
+}
+
+#[test]
+fn test_debug_region_info() {
+    let (cluster, debug_client, store_id) = must_new_cluster_and_debug_client();
+
+    let raft_engine = cluster.get_raft_engine(store_id);
+    let kv_engine = cluster.get_engine(store_id);
+
+    let region_id = 100;
+    let raft_state_key = keys::raft_state_key(region_id);
+    let mut raft_state = RaftLocalState::new();
+    raft_state.set_last_index(42);
+    raft_engine.put_msg(&raft_state_key, &raft_state).unwrap();
+
+    let apply_state_key = keys::apply_state_key(region_id);
+    let mut apply_state = RaftApplyState::new();
+    apply_state.set_applied_index(42);
+    let raft_cf = get_cf_handle(&kv_engine, CF_RAFT).unwrap();
+    kv_engine
+        .put_msg_cf(raft_cf, &apply_state_key, &apply_state)
+        .unwrap();
+
+    let region_state_key = keys::region_state_key(region_id);
+    let mut region_state = RegionLocalState::new();
+    region_state.set_state(PeerState::Tombstone);
+    kv_engine
+        .put_msg_cf(raft_cf, &region_state_key, &region_state)
+        .unwrap();
+
+    // Debug region_info
+    let mut req = debugpb::RegionInfoRequest::new();
+    req.set_region_id(region_id);
+    let mut resp = debug_client.region_info(req.clone()).unwrap();
+    assert_eq!(resp.take_raft_local_state(), raft_state);
+    assert_eq!(resp.take_raft_apply_state(), apply_state);
+    assert_eq!(resp.take_region_local_state(), region_state);
+
+    req.set_region_id(region_id + 1);
+    match debug_client.region_info(req).unwrap_err() {
+        Error::RpcFailure(status) => {
+            assert_eq!(status.status, RpcStatusCode::NotFound);
+        }
+        _ => panic!("expect NotFound"),
+    }
+}
+
+#[test]
+fn test_debug_region_size() {
+    let (cluster, debug_client, store_id) = must_new_cluster_and_debug_client();
+    let engine = cluster.get_engine(store_id);
+
+    let region_id = 100;
+    let region_state_key = keys::region_state_key(region_id);
+    let mut region = metapb::Region::new();
+    region.set_id(region_id);
+    region.set_start_key(b"a".to_vec());
+    region.set_end_key(b"z".to_vec());
+    let mut state = RegionLocalState::new();
+    state.set_region(region);
+    let cf_raft = get_cf_handle(&engine, CF_RAFT).unwrap();
+    engine
+        .put_msg_cf(cf_raft, &region_state_key, &state)
+        .unwrap();
+
+    let (k, v) = (keys::data_key(b"key"), b"value");
+    let cf_lock = get_cf_handle(&engine, CF_LOCK).unwrap();
+    engine.put_cf(cf_lock, &k, v).unwrap();
+
+    let mut req = debugpb::RegionSizeRequest::new();
+    req.set_region_id(region_id);
+    req.set_cfs(vec![CF_LOCK.to_owned()].into());
+    let entries = debug_client
+        .region_size(req.clone())
+        .unwrap()
+        .take_entries()
+        .into_vec();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].get_cf(), CF_LOCK);
+    assert_eq!(entries[0].get_size(), (k.len() + v.len()) as u64);
+
+    req.set_region_id(region_id + 1);
+    match debug_client.region_size(req).unwrap_err() {
+        Error::RpcFailure(status) => {
+            assert_eq!(status.status, RpcStatusCode::NotFound);
+        }
+        _ => panic!("expect NotFound"),
+    }
+}
+
+#[test]
+fn test_debug_scan_mvcc() {
+    let (cluster, debug_client, store_id) = must_new_cluster_and_debug_client();
+    let engine = cluster.get_engine(store_id);
+
+    // Put some data.
+    let lock_keys = [
+        keys::data_key(b"meta_lock_1"),
+        keys::data_key(b"meta_lock_2"),
+    ];
+    for k in &lock_keys {
+        let v = Lock::new(LockType::Put, b"pk".to_vec(), 1, 10, None).to_bytes();
+        let cf_handle = get_cf_handle(&engine, CF_LOCK).unwrap();
+        engine.put_cf(cf_handle, k.as_slice(), &v).unwrap();
+    }
+
+    let mut req = debugpb::ScanMvccRequest::new();
+    req.set_from_key(keys::data_key(b"m"));
+    req.set_to_key(keys::data_key(b"n"));
+    req.set_limit(1);
+
+    let receiver = debug_client.scan_mvcc(req);
+    let future = receiver.fold(Vec::new(), |mut keys, mut resp| {
+        let key = resp.take_key();
+        keys.push(key);
+        Ok::<_, Error>(keys)
+    });
+    let keys = future.wait().unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0], b"meta_lock_1");
+}
+
+#[test]
+fn test_debug_compact() {
+    let (_cluster, debug_client, _) = must_new_cluster_and_debug_client();
+
+    let mut req = debugpb::CompactRequest::new();
+    req.set_db(debugpb::DB::KV);
+    req.set_cf(CF_DEFAULT.to_owned());
+    debug_client.compact(req.clone()).unwrap();
+
+    req.set_db(debugpb::DB::RAFT);
+    req.set_cf(CF_LOCK.to_owned());
+    match debug_client.compact(req).unwrap_err() {
+        Error::RpcFailure(status) => {
+            assert_eq!(status.status, RpcStatusCode::InvalidArgument);
+        }
+        _ => panic!("expect InvalidArgument"),
+    }
+}
+
+#[test]
+fn test_debug_modify_tikv_config() {
+    let (_cluster, debug_client, _) = must_new_cluster_and_debug_client();
+
+    let mut req = debugpb::ModifyTikvConfigRequest::new();
+    req.set_module(debugpb::MODULE::KVDB);
+    req.set_config_name("default.disable_auto_compactions".to_owned());
+    req.set_config_value("true".to_owned());
+    debug_client.modify_tikv_config(req.clone()).unwrap();
+
+    req.set_module(debugpb::MODULE::STORAGE);
+    match debug_client.modify_tikv_config(req).unwrap_err() {
+        Error::RpcFailure(status) => {
+            assert_eq!(status.status, RpcStatusCode::InvalidArgument);
+        }
+        _ => panic!("expect InvalidArgument"),
+    }
+}
+
+#[test]
+fn test_debug_get_metrics() {
+    let (_cluster, debug_client, _) = must_new_cluster_and_debug_client();
+
+    let mut req = debugpb::GetMetricsRequest::new();
+    let resp = debug_client.get_metrics(req.clone()).unwrap();
+    assert!(!resp.get_prometheus().is_empty());
+    assert!(resp.get_rocksdb_kv().is_empty());
+
+    req.set_all(true);
+    let resp = debug_client.get_metrics(req).unwrap();
+    assert!(!resp.get_prometheus().is_empty());
+    assert!(!resp.get_rocksdb_kv().is_empty());
+    assert!(!resp.get_rocksdb_raft().is_empty());
+}
+
+#[test]
+fn test_debug_check_region_consistency() {
+    let (_cluster, debug_client, _) = must_new_cluster_and_debug_client();
+
+    let mut req = debugpb::RegionConsistencyCheckRequest::new();
+    req.set_region_id(1);
+    debug_client.check_region_consistency(req.clone()).unwrap();
+
+    req.set_region_id(1000);
+    debug_client.check_region_consistency(req).unwrap_err();
+}
+
+#[cfg(feature = "failpoints")]
+#[test]
+fn test_debug_fail_point() {
+    let (_cluster, debug_client, _) = must_new_cluster_and_debug_client();
+
+    let (name, actions) = ("test_debug_fail_point", "return");
+    let mut inject_req = debugpb::InjectFailPointRequest::new();
+    inject_req.set_name(name.to_owned());
+    inject_req.set_actions(actions.to_owned());
+    debug_client.inject_fail_point(inject_req).unwrap();
+
+    let resp = debug_client
+        .list_fail_points(debugpb::ListFailPointsRequest::new())
+        .unwrap();
+    assert!(
+        resp.get_entries()
+            .iter()
+            .any(|e| e.get_name() == name && e.get_actions() == actions)
+    );
+
+    let mut recover_req = debugpb::RecoverFailPointRequest::new();
+    recover_req.set_name(name.to_owned());
+    debug_client.recover_fail_point(recover_req).unwrap();
+
+    let resp = debug_client
+        .list_fail_points(debugpb::ListFailPointsRequest::new())
+        .unwrap();
+    assert!(resp.get_entries().iter().all(|e| e.get_name() != name));
+}
+
+#[test]
+fn test_debug_store_and_cluster_info() {
+    let (cluster, debug_client, store_id) = must_new_cluster_and_debug_client();
+
+    let resp = debug_client
+        .get_store_info(debugpb::GetStoreInfoRequest::new())
+        .unwrap();
+    assert_eq!(resp.get_store_id(), store_id);
+    assert!(resp.get_start_time() > 0);
+
+    let resp = debug_client
+        .get_cluster_info(debugpb::GetClusterInfoRequest::new())
+        .unwrap();
+    assert_eq!(resp.get_cluster_id(), cluster.id());
+}
+
+#[test]
+fn test_backup() {
+    let (cluster, client, ctx) = must_new_cluster_and_client();
+    let leader = cluster.leader_of_region(1).unwrap();
+    let addr = cluster.sim.rl().get_addr(leader.get_store_id());
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env).connect(&format!("{}", addr));
+    let backup_client = BackupClient::new(channel);
+
+    // A short value is kept in CF_WRITE, while a long one goes to CF_DEFAULT.
+    let kvs = vec![
+        (b"a".to_vec(), b"v".to_vec()),
+        (b"b".to_vec(), vec![b'v'; 1024]),
+    ];
+    let mut muts = vec![];
+    for &(ref k, ref v) in &kvs {
+        let mut mutation = Mutation::new();
+        mutation.op = Op::Put;
+        mutation.key = k.clone();
+        mutation.value = v.clone();
+        muts.push(mutation);
+    }
+    must_kv_prewrite(&client, ctx.clone(), muts, b"a".to_vec(), 1);
+    must_kv_commit(&client, ctx.clone(), vec![b"a".to_vec(), b"b".to_vec()], 1, 2);
+    // Leave a lock on "c".
+    let mut mutation = Mutation::new();
+    mutation.op = Op::Put;
+    mutation.key = b"c".to_vec();
+    mutation.value = b"v".to_vec();
+    must_kv_prewrite(&client, ctx.clone(), vec![mutation], b"c".to_vec(), 5);
+
+    let dir = TempDir::new("test_backup").unwrap();
+    let backup = |ts: u64| {
+        let mut req = BackupRequest::new();
+        req.set_start_key(b"a".to_vec());
+        req.set_end_key(b"d".to_vec());
+        req.set_end_version(ts);
+        req.set_path(dir.path().to_str().unwrap().to_owned());
+        let receiver = backup_client.backup(req);
+        receiver.collect().wait().unwrap()
+    };
+
+    // The lock is after the backup ts, so it's ignored.
+    let resps = backup(3);
+    assert_eq!(resps.len(), 1);
+    assert!(!resps[0].has_error(), "{:?}", resps[0].get_error());
+    assert_eq!(resps[0].get_start_key(), b"a");
+    assert_eq!(resps[0].get_end_key(), b"d");
+    let files = resps[0].get_files();
+    assert_eq!(files.len(), 2);
+    for f in files {
+        let expected_kvs = if f.get_cf() == CF_WRITE { 2 } else { 1 };
+        assert_eq!(f.get_total_kvs(), expected_kvs);
+        let path = dir.path().join(f.get_name());
+        assert_eq!(fs::metadata(&path).unwrap().len(), f.get_size());
+    }
+
+    // Verifying the backup only computes the checksums of the data.
+    let mut req = BackupRequest::new();
+    req.set_start_key(b"a".to_vec());
+    req.set_end_key(b"d".to_vec());
+    req.set_end_version(3);
+    req.set_checksum_only(true);
+    let verify_resps = backup_client.backup(req).collect().wait().unwrap();
+    assert_eq!(verify_resps.len(), 1);
+    assert!(!verify_resps[0].has_error(), "{:?}", verify_resps[0].get_error());
+    let mut checksums: Vec<_> = verify_resps[0]
+        .get_files()
+        .iter()
+        .map(|f| {
+            assert!(f.get_name().is_empty());
+            (f.get_cf().to_owned(), f.get_total_kvs(), f.get_crc64xor())
+        })
+        .collect();
+    let mut expected: Vec<_> = files
+        .iter()
+        .map(|f| (f.get_cf().to_owned(), f.get_total_kvs(), f.get_crc64xor()))
+        .collect();
+    checksums.sort();
+    expected.sort();
+    assert_eq!(checksums, expected);
+
+    // The lock may be committed before the backup ts.
+    let resps = backup(6);
+    assert_eq!(resps.len(), 1);
+    let lock_info = resps[0].get_error().get_kv_error().get_locked();
+    assert_eq!(lock_info.get_key(), b"c");
+    assert_eq!(lock_info.get_lock_version(), 5);
+}
+
+#[test]
+fn test_incremental_backup() {
+    let (cluster, client, ctx) = must_new_cluster_and_client();
+    let leader = cluster.leader_of_region(1).unwrap();
+    let addr = cluster.sim.rl().get_addr(leader.get_store_id());
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env).connect(&format!("{}", addr));
+    let backup_client = BackupClient::new(channel);
+
+    let must_write = |op: Op, key: &[u8], value: Vec<u8>, start_ts: u64, commit_ts: u64| {
+        let mut mutation = Mutation::new();
+        mutation.op = op;
+        mutation.key = key.to_vec();
+        mutation.value = value;
+        must_kv_prewrite(&client, ctx.clone(), vec![mutation], key.to_vec(), start_ts);
+        must_kv_commit(&client, ctx.clone(), vec![key.to_vec()], start_ts, commit_ts);
+    };
+    must_write(Op::Put, b"a", b"v".to_vec(), 1, 2);
+    must_write(Op::Put, b"b", vec![b'v'; 1024], 3, 4);
+    must_write(Op::Del, b"a", vec![], 5, 6);
+    must_write(Op::Put, b"b", vec![b'w'; 1024], 7, 8);
+
+    let backup = |start_ts: u64, end_ts: u64| {
+        let dir = TempDir::new("test_incremental_backup").unwrap();
+        let mut req = BackupRequest::new();
+        req.set_start_key(b"a".to_vec());
+        req.set_end_key(b"c".to_vec());
+        req.set_start_version(start_ts);
+        req.set_end_version(end_ts);
+        req.set_path(dir.path().to_str().unwrap().to_owned());
+        let resps = backup_client.backup(req).collect().wait().unwrap();
+        assert_eq!(resps.len(), 1);
+        assert!(!resps[0].has_error(), "{:?}", resps[0].get_error());
+        let mut kvs: Vec<_> = resps[0]
+            .get_files()
+            .iter()
+            .map(|f| (f.get_cf().to_owned(), f.get_total_kvs()))
+            .collect();
+        kvs.sort();
+        kvs
+    };
+
+    // The delete of "a" and both puts of "b".
+    let kvs = backup(2, 8);
+    assert_eq!(kvs, vec![(CF_DEFAULT.to_owned(), 2), (CF_WRITE.to_owned(), 3)]);
+    // Only the delete of "a".
+    let kvs = backup(4, 6);
+    assert_eq!(kvs, vec![(CF_WRITE.to_owned(), 1)]);
+    // Nothing changes.
+    assert!(backup(8, 10).is_empty());
+
+    let mut req = BackupRequest::new();
+    req.set_start_version(10);
+    req.set_end_version(8);
+    req.set_path("/tmp".to_owned());
+    let resps = backup_client.backup(req).collect().wait().unwrap();
+    assert!(resps[0].has_error());
+}
+
+#[test]
+fn test_raw_backup() {
+    let (cluster, client, ctx) = must_new_cluster_and_client();
+    let leader = cluster.leader_of_region(1).unwrap();
+    let addr = cluster.sim.rl().get_addr(leader.get_store_id());
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env).connect(&format!("{}", addr));
+    let backup_client = BackupClient::new(channel);
+
+    for k in &[b"a", b"b", b"c"] {
+        let mut put_req = RawPutRequest::new();
+        put_req.set_context(ctx.clone());
+        put_req.key = k.to_vec();
+        put_req.value = b"v".to_vec();
+        let put_resp = client.raw_put(put_req).unwrap();
+        assert!(!put_resp.has_region_error());
+        assert!(put_resp.error.is_empty());
+    }
+
+    let dir = TempDir::new("test_raw_backup").unwrap();
+    let mut req = BackupRequest::new();
+    req.set_start_key(b"a".to_vec());
+    req.set_end_key(b"c".to_vec());
+    req.set_is_raw_kv(true);
+    req.set_path(dir.path().to_str().unwrap().to_owned());
+    let resps = backup_client.backup(req).collect().wait().unwrap();
+    assert_eq!(resps.len(), 1);
+    assert!(!resps[0].has_error(), "{:?}", resps[0].get_error());
+    assert_eq!(resps[0].get_start_key(), b"a");
+    assert_eq!(resps[0].get_end_key(), b"c");
+    let files = resps[0].get_files();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].get_cf(), CF_DEFAULT);
+    assert_eq!(files[0].get_total_kvs(), 2);
+
+    let mut req = BackupRequest::new();
+    req.set_is_raw_kv(true);
+    req.set_cf(CF_RAFT.to_owned());
+    req.set_path(dir.path().to_str().unwrap().to_owned());
+    let resps = backup_client.backup(req).collect().wait().unwrap();
+    assert!(resps[0].has_error());
+}
+
+#[test]
+fn test_import_split_and_scatter() {
+    let (cluster, leader, ctx) = must_new_cluster();
+    let addr = cluster.sim.rl().get_addr(leader.get_store_id());
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env).connect(&format!("{}", addr));
+    let client = ImportSstClient::new(channel);
+
+    let mut req = SplitAndScatterRequest::new();
+    req.set_context(ctx.clone());
+    req.set_split_keys(vec![b"k3".to_vec(), b"k1".to_vec(), b"k3".to_vec()].into());
+    let resp = client.split_and_scatter(req).unwrap();
+    assert!(!resp.has_error(), "{:?}", resp.get_error());
+    let regions = resp.get_regions();
+    assert_eq!(regions.len(), 3);
+    assert_eq!(regions[0].get_start_key(), b"");
+    assert_eq!(regions[0].get_end_key(), b"k1");
+    assert_eq!(regions[1].get_start_key(), b"k1");
+    assert_eq!(regions[1].get_end_key(), b"k3");
+    assert_eq!(regions[2].get_start_key(), b"k3");
+    assert_eq!(regions[2].get_end_key(), b"");
+    assert_eq!(cluster.get_region(b"k2"), regions[1]);
+    for region in regions {
+        let mut scattered = false;
+        for _ in 0..50 {
+            if cluster.pd_client.is_region_scattered(region.get_id()) {
+                scattered = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(scattered, "region {} is not scattered", region.get_id());
+    }
+
+    // The epoch of the original region is stale now.
+    let mut req = SplitAndScatterRequest::new();
+    req.set_context(ctx);
+    req.set_split_keys(vec![b"k0".to_vec()].into());
+    let resp = client.split_and_scatter(req).unwrap();
+    assert!(resp.get_error().has_stale_epoch());
+}
+
+#[test]
+fn test_cdc() {
+    let (cluster, client, ctx) = must_new_cluster_and_client();
+    let leader = cluster.leader_of_region(1).unwrap();
+    let addr = cluster.sim.rl().get_addr(leader.get_store_id());
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env).connect(&format!("{}", addr));
+    let cdc_client = ChangeDataClient::new(channel);
+
+    let (req_tx, event_rx) = cdc_client.event_feed();
+    let mut req = ChangeDataRequest::new();
+    req.set_region_id(1);
+    req.set_region_epoch(ctx.get_region_epoch().clone());
+    req.set_request_id(7);
+    let _req_tx = req_tx.send((req, WriteFlags::default())).wait().unwrap();
+    let mut events = event_rx.wait().flat_map(|e| e.unwrap().take_events().into_vec());
+    let mut resolved_ts = 0;
+    let mut next_entries = |events: &mut Iterator<Item = Event>| loop {
+        let mut event = events.next().unwrap();
+        assert_eq!(event.get_region_id(), 1);
+        assert_eq!(event.get_request_id(), 7);
+        assert!(!event.has_error(), "{:?}", event.get_error());
+        if event.has_resolved_ts() {
+            // The resolved ts never goes back.
+            assert!(event.get_resolved_ts() >= resolved_ts);
+            resolved_ts = event.get_resolved_ts();
+            continue;
+        }
+        return event.take_entries().take_entries().into_vec();
+    };
+
+    // The resolved ts is sent once the region is observed.
+    let event = events.next().unwrap();
+    assert!(event.has_resolved_ts(), "{:?}", event);
+
+    let (k, v) = (b"key".to_vec(), b"value".to_vec());
+    let mut mutation = Mutation::new();
+    mutation.op = Op::Put;
+    mutation.key = k.clone();
+    mutation.value = v.clone();
+    must_kv_prewrite(&client, ctx.clone(), vec![mutation], k.clone(), 100);
+    let rows = next_entries(&mut events);
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].get_field_type(), Event_LogType::PREWRITE);
+    assert_eq!(rows[0].get_op_type(), Event_Row_OpType::PUT);
+    assert_eq!(rows[0].get_start_ts(), 100);
+    assert_eq!(rows[0].get_key(), k.as_slice());
+    assert_eq!(rows[0].get_value(), v.as_slice());
+
+    must_kv_commit(&client, ctx.clone(), vec![k.clone()], 100, 101);
+    let rows = next_entries(&mut events);
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].get_field_type(), Event_LogType::COMMIT);
+    assert_eq!(rows[0].get_commit_ts(), 101);
+    assert_eq!(rows[0].get_key(), k.as_slice());
+}
+
+#[test]
+fn test_cdc_incremental_scan() {
+    let (cluster, client, ctx) = must_new_cluster_and_client();
+    let (k, v) = (b"key".to_vec(), b"value".to_vec());
+    for &(start_ts, commit_ts) in &[(10, 11), (20, 21)] {
+        let mut mutation = Mutation::new();
+        mutation.op = Op::Put;
+        mutation.key = k.clone();
+        mutation.value = v.clone();
+        must_kv_prewrite(&client, ctx.clone(), vec![mutation], k.clone(), start_ts);
+        must_kv_commit(&client, ctx.clone(), vec![k.clone()], start_ts, commit_ts);
+    }
+    let mut mutation = Mutation::new();
+    mutation.op = Op::Del;
+    mutation.key = k.clone();
+    must_kv_prewrite(&client, ctx.clone(), vec![mutation], k.clone(), 30);
+
+    let leader = cluster.leader_of_region(1).unwrap();
+    let addr = cluster.sim.rl().get_addr(leader.get_store_id());
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env).connect(&format!("{}", addr));
+    let cdc_client = ChangeDataClient::new(channel);
+    let (req_tx, event_rx) = cdc_client.event_feed();
+    let mut req = ChangeDataRequest::new();
+    req.set_region_id(1);
+    req.set_region_epoch(ctx.get_region_epoch().clone());
+    req.set_checkpoint_ts(15);
+    let _req_tx = req_tx.send((req, WriteFlags::default())).wait().unwrap();
+    let mut events = event_rx.wait().flat_map(|e| e.unwrap().take_events().into_vec());
+
+    // Only the commit after the checkpoint and the pending prewrite are scanned.
+    let mut event = events.next().unwrap();
+    assert!(!event.has_error(), "{:?}", event.get_error());
+    let rows = event.take_entries().take_entries().into_vec();
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[0].get_field_type(), Event_LogType::COMMITTED);
+    assert_eq!(rows[0].get_op_type(), Event_Row_OpType::PUT);
+    assert_eq!(rows[0].get_start_ts(), 20);
+    assert_eq!(rows[0].get_commit_ts(), 21);
+    assert_eq!(rows[0].get_key(), k.as_slice());
+    assert_eq!(rows[0].get_value(), v.as_slice());
+    assert_eq!(rows[1].get_field_type(), Event_LogType::PREWRITE);
+    assert_eq!(rows[1].get_op_type(), Event_Row_OpType::DELETE);
+    assert_eq!(rows[1].get_start_ts(), 30);
+    assert_eq!(rows[2].get_field_type(), Event_LogType::INITIALIZED);
+}
+
+#[test]
+fn test_resolved_ts() {
+    let (cluster, client, ctx) = must_new_cluster_and_client();
+    let leader = cluster.leader_of_region(1).unwrap();
+    let resolved_ts = cluster.sim.rl().resolved_ts[&leader.get_store_id()].clone();
+    let wait_resolved_ts = |cond: &Fn(u64) -> bool| {
+        for _ in 0..100 {
+            if let Some(ts) = resolved_ts.get(1) {
+                if cond(ts) {
+                    return ts;
+                }
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("resolved ts is {:?}", resolved_ts.get(1));
+    };
+    let ts = wait_resolved_ts(&|_| true);
+
+    // The resolved ts can't exceed the start ts of the lock.
+    let (k, start_ts) = (b"key".to_vec(), ts + 2);
+    let mut mutation = Mutation::new();
+    mutation.op = Op::Put;
+    mutation.key = k.clone();
+    mutation.value = b"value".to_vec();
+    must_kv_prewrite(&client, ctx.clone(), vec![mutation], k.clone(), start_ts);
+    wait_resolved_ts(&|ts| ts >= start_ts);
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(resolved_ts.get(1), Some(start_ts));
+    assert!(resolved_ts.is_resolved(1, start_ts));
+    assert!(!resolved_ts.is_resolved(1, start_ts + 1));
+
+    must_kv_commit(&client, ctx.clone(), vec![k], start_ts, start_ts + 1);
+    wait_resolved_ts(&|ts| ts > start_ts);
+}
@@ -13,7 +13,6 @@
 
 use std::time::Duration;
 use std::{fs, thread};
-use rand::{self, Rng};
 
 use kvproto::eraftpb::MessageType;
 
@@ -125,42 +124,6 @@ fn test_server_base_split_region_right_derive() {
     test_base_split_region(&mut cluster, true);
 }
 
-/// Keep puting random kvs until specified size limit is reached.
-fn put_till_size<T: Simulator>(
-    cluster: &mut Cluster<T>,
-    limit: u64,
-    range: &mut Iterator<Item = u64>,
-) -> Vec<u8> {
-    put_cf_till_size(cluster, CF_DEFAULT, limit, range)
-}
-
-fn put_cf_till_size<T: Simulator>(
-    cluster: &mut Cluster<T>,
-    cf: &'static str,
-    limit: u64,
-    range: &mut Iterator<Item = u64>,
-) -> Vec<u8> {
-    assert!(limit > 0);
-    let mut len = 0;
-    let mut rng = rand::thread_rng();
-    let mut key = vec![];
-    while len < limit {
-        let key_id = range.next().unwrap();
-        let key_str = format!("{:09}", key_id);
-        key = key_str.into_bytes();
-        let mut value = vec![0; 64];
-        rng.fill_bytes(&mut value);
-        cluster.must_put_cf(cf, &key, &value);
-        // plus 1 for the extra encoding prefix
-        len += key.len() as u64 + 1;
-        len += value.len() as u64;
-    }
-    // Approximate size of memtable is inaccurate for small data,
-    // we flush it to SST so we can use the size properties instead.
-    cluster.must_flush(true);
-    key
-}
-
 fn test_auto_split_region<T: Simulator>(cluster: &mut Cluster<T>) {
     cluster.cfg.raft_store.split_region_check_tick_interval = ReadableDuration::millis(100);
     cluster.cfg.raft_store.region_max_size = ReadableSize(REGION_MAX_SIZE);
@@ -175,7 +138,7 @@ fn test_auto_split_region<T: Simulator>(cluster: &mut Cluster<T>) {
 
     let region = pd_client.get_region(b"").unwrap();
 
-    let last_key = put_till_size(cluster, REGION_SPLIT_SIZE, &mut range);
+    let last_key = util::put_till_size(cluster, REGION_SPLIT_SIZE, &mut range);
 
     // it should be finished in millis if split.
     thread::sleep(Duration::from_secs(1));
@@ -184,7 +147,7 @@ fn test_auto_split_region<T: Simulator>(cluster: &mut Cluster<T>) {
 
     assert_eq!(region, target);
 
-    let max_key = put_cf_till_size(
+    let max_key = util::put_cf_till_size(
         cluster,
         CF_WRITE,
         REGION_MAX_SIZE - REGION_SPLIT_SIZE + check_size_diff,
@@ -529,7 +492,7 @@ fn test_split_region_diff_check<T: Simulator>(cluster: &mut Cluster<T>) {
     // The default size index distance is too large for small data,
     // we flush multiple times to generate more size index handles.
     for _ in 0..10 {
-        put_till_size(cluster, region_max_size, &mut range);
+        util::put_till_size(cluster, region_max_size, &mut range);
     }
 
     // Peer will split when size of region meet region_max_size,
@@ -674,3 +637,38 @@ fn test_server_quick_election_after_split() {
     let mut cluster = new_server_cluster(0, 3);
     test_quick_election_after_split(&mut cluster);
 }
+
+fn test_split_at_keys<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_put_keys(b"k", 0..30, b"v");
+
+    let (k1, k2) = (util::gen_key(b"k", 10), util::gen_key(b"k", 20));
+    let regions = cluster.must_split_at_keys(&[&k1, &k2]);
+    cluster.wait_for_regions(3);
+    assert_eq!(regions.len(), 3);
+    assert_eq!(regions[0].get_end_key(), k1.as_slice());
+    assert_eq!(regions[1].get_start_key(), k1.as_slice());
+    assert_eq!(regions[1].get_end_key(), k2.as_slice());
+    assert_eq!(regions[2].get_start_key(), k2.as_slice());
+
+    // Spreads the leaders over the stores, the data is still readable.
+    for (i, region) in regions.iter().enumerate() {
+        cluster.must_transfer_leader_to_store(region.get_id(), i as u64 + 1);
+    }
+    for id in 0..30 {
+        let key = util::gen_key(b"k", id);
+        assert_eq!(cluster.must_get(&key), Some(b"v".to_vec()));
+    }
+}
+
+#[test]
+fn test_node_split_at_keys() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_split_at_keys(&mut cluster);
+}
+
+#[test]
+fn test_server_split_at_keys() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_split_at_keys(&mut cluster);
+}
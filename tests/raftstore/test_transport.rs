@@ -14,7 +14,10 @@
 use super::cluster::{Cluster, Simulator};
 use super::node::new_node_cluster;
 use super::server::new_server_cluster;
-use super::util::{must_get_equal, new_peer};
+use super::transport_simulate::{CloneFilterFactory, FilterChain, PeerPacketFilter, ReorderFilter};
+use super::util::{must_get_equal, must_get_none, new_peer, sleep_ms};
+use kvproto::eraftpb::MessageType;
+use std::time::Duration;
 
 fn test_partition_write<T: Simulator>(cluster: &mut Cluster<T>) {
     cluster.run();
@@ -61,3 +64,168 @@ fn test_server_partition_write() {
     let mut cluster = new_server_cluster(0, 5);
     test_partition_write(&mut cluster);
 }
+
+fn test_partition_groups_heal<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+
+    let region_id = cluster.get_region_id(b"");
+    cluster.must_transfer_leader(region_id, new_peer(1, 1));
+    cluster.must_put(b"k1", b"v1");
+
+    // store 5 is isolated, and the leader is in the minority group.
+    let handle = cluster.partition_groups(vec![vec![1, 2], vec![3, 4]]);
+    sleep_ms(500);
+    cluster.must_put(b"k2", b"v2");
+    let leader = cluster.leader_of_region(region_id).unwrap();
+    assert!(leader.get_store_id() == 3 || leader.get_store_id() == 4);
+    must_get_none(&cluster.get_engine(1), b"k2");
+    must_get_none(&cluster.get_engine(5), b"k2");
+
+    handle.heal();
+    cluster.must_put(b"k3", b"v3");
+    for id in 1..6 {
+        must_get_equal(&cluster.get_engine(id), b"k2", b"v2");
+        must_get_equal(&cluster.get_engine(id), b"k3", b"v3");
+    }
+}
+
+#[test]
+fn test_node_partition_groups_heal() {
+    let mut cluster = new_node_cluster(0, 5);
+    test_partition_groups_heal(&mut cluster);
+}
+
+#[test]
+fn test_server_partition_groups_heal() {
+    let mut cluster = new_server_cluster(0, 5);
+    test_partition_groups_heal(&mut cluster);
+}
+
+fn test_peer_packet_drop<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+
+    let region_id = cluster.get_region_id(b"");
+    cluster.must_transfer_leader(region_id, new_peer(1, 1));
+
+    cluster.add_send_filter(CloneFilterFactory(
+        PeerPacketFilter::new(1, 3).msg_type(MessageType::MsgAppend),
+    ));
+    cluster.must_put(b"k1", b"v1");
+    must_get_equal(&cluster.get_engine(2), b"k1", b"v1");
+    must_get_none(&cluster.get_engine(3), b"k1");
+
+    cluster.clear_send_filters();
+    must_get_equal(&cluster.get_engine(3), b"k1", b"v1");
+}
+
+#[test]
+fn test_node_peer_packet_drop() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_peer_packet_drop(&mut cluster);
+}
+
+#[test]
+fn test_server_peer_packet_drop() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_peer_packet_drop(&mut cluster);
+}
+
+fn test_peer_packet_reorder<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+
+    let region_id = cluster.get_region_id(b"");
+    cluster.must_transfer_leader(region_id, new_peer(1, 1));
+
+    cluster.add_send_filter(CloneFilterFactory(ReorderFilter::new(1, 2, 3)));
+    for i in 0..10 {
+        let (k, v) = (format!("k{}", i), format!("v{}", i));
+        cluster.must_put(k.as_bytes(), v.as_bytes());
+    }
+    for i in 0..10 {
+        let (k, v) = (format!("k{}", i), format!("v{}", i));
+        must_get_equal(&cluster.get_engine(2), k.as_bytes(), v.as_bytes());
+    }
+}
+
+#[test]
+fn test_node_peer_packet_reorder() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_peer_packet_reorder(&mut cluster);
+}
+
+#[test]
+fn test_server_peer_packet_reorder() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_peer_packet_reorder(&mut cluster);
+}
+
+fn test_snapshot_lost_in_transfer<T: Simulator>(cluster: &mut Cluster<T>) {
+    let pd_client = cluster.pd_client.clone();
+    pd_client.disable_default_rule();
+    let r1 = cluster.run_conf_change();
+    cluster.must_put(b"k1", b"v1");
+    pd_client.must_add_peer(r1, new_peer(2, 2));
+    must_get_equal(&cluster.get_engine(2), b"k1", b"v1");
+
+    let chain = FilterChain::new()
+        .drop_msg(MessageType::MsgSnapshot)
+        .delay_msg(MessageType::MsgAppend, Duration::from_millis(10));
+    cluster.add_send_filter_on_node(1, box chain);
+    pd_client.must_add_peer(r1, new_peer(3, 3));
+    cluster.must_put(b"k2", b"v2");
+    must_get_equal(&cluster.get_engine(2), b"k2", b"v2");
+    must_get_none(&cluster.get_engine(3), b"k1");
+
+    cluster.clear_send_filters();
+    must_get_equal(&cluster.get_engine(3), b"k1", b"v1");
+    must_get_equal(&cluster.get_engine(3), b"k2", b"v2");
+}
+
+#[test]
+fn test_node_snapshot_lost_in_transfer() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_snapshot_lost_in_transfer(&mut cluster);
+}
+
+#[test]
+fn test_server_snapshot_lost_in_transfer() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_snapshot_lost_in_transfer(&mut cluster);
+}
+
+fn test_duplicated_and_corrupted_append<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+
+    let region_id = cluster.get_region_id(b"");
+    cluster.must_transfer_leader(region_id, new_peer(1, 1));
+
+    // appends to store 2 are sent 3 times, and appends to store 3 carry a
+    // wrong log term so they are always rejected.
+    let chain = FilterChain::new()
+        .duplicate_msg(MessageType::MsgAppend, 2)
+        .corrupt_msg(MessageType::MsgAppend, |m| if m.get_to_peer().get_store_id() == 3 {
+            m.mut_message().set_log_term(0);
+        });
+    cluster.add_send_filter_on_node(1, box chain);
+    for i in 0..10 {
+        let (k, v) = (format!("k{}", i), format!("v{}", i));
+        cluster.must_put(k.as_bytes(), v.as_bytes());
+        must_get_equal(&cluster.get_engine(2), k.as_bytes(), v.as_bytes());
+    }
+    must_get_none(&cluster.get_engine(3), b"k9");
+
+    cluster.clear_send_filters();
+    must_get_equal(&cluster.get_engine(3), b"k9", b"v9");
+}
+
+#[test]
+fn test_node_duplicated_and_corrupted_append() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_duplicated_and_corrupted_append(&mut cluster);
+}
+
+#[test]
+fn test_server_duplicated_and_corrupted_append() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_duplicated_and_corrupted_append(&mut cluster);
+}
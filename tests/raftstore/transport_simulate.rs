@@ -19,7 +19,9 @@ use tikv::server::transport::*;
 use tikv::server::StoreAddrResolver;
 use tikv::util::{transport, Either, HandyRwLock};
 
-use rand;
+use rand::{Rng, XorShiftRng};
+
+use super::util::new_test_rng;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex, RwLock};
 use std::sync::mpsc::Sender;
@@ -79,17 +81,22 @@ pub type RecvFilter = Box<Filter<StoreMsg>>;
 #[derive(Clone)]
 pub struct DropPacketFilter {
     rate: u32,
+    rng: Arc<Mutex<XorShiftRng>>,
 }
 
 impl DropPacketFilter {
     pub fn new(rate: u32) -> DropPacketFilter {
-        DropPacketFilter { rate: rate }
+        DropPacketFilter {
+            rate: rate,
+            rng: Arc::new(Mutex::new(new_test_rng())),
+        }
     }
 }
 
 impl<M> Filter<M> for DropPacketFilter {
     fn before(&self, msgs: &mut Vec<M>) -> Result<()> {
-        msgs.retain(|_| rand::random::<u32>() % 100u32 >= self.rate);
+        let mut rng = self.rng.lock().unwrap();
+        msgs.retain(|_| rng.gen::<u32>() % 100u32 >= self.rate);
         check_messages(msgs)
     }
 }
@@ -212,12 +219,31 @@ impl<F: Filter<RaftMessage> + Clone + 'static> FilterFactory for CloneFilterFact
     }
 }
 
+/// `PartitionHandle` heals the partition made by the filters sharing it, the
+/// other filters are kept.
+#[derive(Clone, Default)]
+pub struct PartitionHandle(Arc<AtomicBool>);
+
+impl PartitionHandle {
+    pub fn heal(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_healed(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
 struct PartitionFilter {
     node_ids: Vec<u64>,
+    handle: PartitionHandle,
 }
 
 impl Filter<RaftMessage> for PartitionFilter {
     fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        if self.handle.is_healed() {
+            return Ok(());
+        }
         msgs.retain(|m| !self.node_ids.contains(&m.get_to_peer().get_store_id()));
         check_messages(msgs)
     }
@@ -226,11 +252,20 @@ impl Filter<RaftMessage> for PartitionFilter {
 pub struct PartitionFilterFactory {
     s1: Vec<u64>,
     s2: Vec<u64>,
+    handle: PartitionHandle,
 }
 
 impl PartitionFilterFactory {
     pub fn new(s1: Vec<u64>, s2: Vec<u64>) -> PartitionFilterFactory {
-        PartitionFilterFactory { s1: s1, s2: s2 }
+        PartitionFilterFactory {
+            s1: s1,
+            s2: s2,
+            handle: PartitionHandle::default(),
+        }
+    }
+
+    pub fn handle(&self) -> PartitionHandle {
+        self.handle.clone()
     }
 }
 
@@ -240,17 +275,77 @@ impl FilterFactory for PartitionFilterFactory {
             return vec![
                 box PartitionFilter {
                     node_ids: self.s2.clone(),
+                    handle: self.handle.clone(),
                 },
             ];
         }
         return vec![
             box PartitionFilter {
                 node_ids: self.s1.clone(),
+                handle: self.handle.clone(),
             },
         ];
     }
 }
 
+/// `GroupPartitionFilterFactory` splits the stores into groups, a store can
+/// only talk to the stores in the same group. Stores in no group are isolated.
+pub struct GroupPartitionFilterFactory {
+    groups: Vec<Vec<u64>>,
+    handle: PartitionHandle,
+}
+
+impl GroupPartitionFilterFactory {
+    pub fn new(groups: Vec<Vec<u64>>) -> GroupPartitionFilterFactory {
+        GroupPartitionFilterFactory {
+            groups: groups,
+            handle: PartitionHandle::default(),
+        }
+    }
+
+    pub fn handle(&self) -> PartitionHandle {
+        self.handle.clone()
+    }
+}
+
+impl FilterFactory for GroupPartitionFilterFactory {
+    fn generate(&self, node_id: u64) -> Vec<SendFilter> {
+        let node_ids = self.groups
+            .iter()
+            .filter(|g| !g.contains(&node_id))
+            .flat_map(|g| g.iter().cloned())
+            .collect();
+        vec![
+            box IsolatedPartitionFilter {
+                node_id: node_id,
+                in_group: self.groups.iter().any(|g| g.contains(&node_id)),
+                filter: PartitionFilter {
+                    node_ids: node_ids,
+                    handle: self.handle.clone(),
+                },
+            },
+        ]
+    }
+}
+
+// Drops all the messages sent by a store in no group, and the messages sent
+// to the stores in the other groups otherwise.
+struct IsolatedPartitionFilter {
+    node_id: u64,
+    in_group: bool,
+    filter: PartitionFilter,
+}
+
+impl Filter<RaftMessage> for IsolatedPartitionFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        if self.in_group || self.filter.handle.is_healed() {
+            return self.filter.before(msgs);
+        }
+        msgs.retain(|m| m.get_to_peer().get_store_id() == self.node_id);
+        check_messages(msgs)
+    }
+}
+
 pub struct IsolationFilterFactory {
     node_id: u64,
 }
@@ -264,11 +359,12 @@ impl IsolationFilterFactory {
 impl FilterFactory for IsolationFilterFactory {
     fn generate(&self, node_id: u64) -> Vec<SendFilter> {
         if node_id == self.node_id {
-            return vec![box DropPacketFilter { rate: 100 }];
+            return vec![box DropPacketFilter::new(100)];
         }
         vec![
             box PartitionFilter {
                 node_ids: vec![self.node_id],
+                handle: PartitionHandle::default(),
             },
         ]
     }
@@ -584,6 +680,7 @@ impl Filter<StoreMsg> for LeadingDuplicatedSnapshotFilter {
 pub struct RandomLatencyFilter {
     delay_rate: u32,
     delayed_msgs: Mutex<Vec<RaftMessage>>,
+    rng: Mutex<XorShiftRng>,
 }
 
 impl RandomLatencyFilter {
@@ -591,11 +688,12 @@ impl RandomLatencyFilter {
         RandomLatencyFilter {
             delay_rate: rate,
             delayed_msgs: Mutex::new(vec![]),
+            rng: Mutex::new(new_test_rng()),
         }
     }
 
     fn will_delay(&self, _: &RaftMessage) -> bool {
-        rand::random::<u32>() % 100u32 >= self.delay_rate
+        self.rng.lock().unwrap().gen::<u32>() % 100u32 >= self.delay_rate
     }
 }
 
@@ -625,6 +723,290 @@ impl Clone for RandomLatencyFilter {
         RandomLatencyFilter {
             delay_rate: self.delay_rate,
             delayed_msgs: Mutex::new(delayed_msgs.clone()),
+            rng: Mutex::new(self.rng.lock().unwrap().clone()),
         }
     }
 }
+
+/// `PeerPacketFilter` drops the messages sent from `from_peer` to `to_peer`,
+/// optionally only the messages of the given type.
+#[derive(Clone)]
+pub struct PeerPacketFilter {
+    from_peer: u64,
+    to_peer: u64,
+    msg_type: Option<MessageType>,
+    block: Either<Arc<AtomicUsize>, Arc<AtomicBool>>,
+}
+
+impl PeerPacketFilter {
+    pub fn new(from_peer: u64, to_peer: u64) -> PeerPacketFilter {
+        PeerPacketFilter {
+            from_peer: from_peer,
+            to_peer: to_peer,
+            msg_type: None,
+            block: Either::Right(Arc::new(AtomicBool::new(true))),
+        }
+    }
+
+    #[must_use]
+    pub fn msg_type(mut self, m_type: MessageType) -> PeerPacketFilter {
+        self.msg_type = Some(m_type);
+        self
+    }
+
+    #[must_use]
+    pub fn allow(mut self, number: usize) -> PeerPacketFilter {
+        self.block = Either::Left(Arc::new(AtomicUsize::new(number)));
+        self
+    }
+
+    #[must_use]
+    pub fn when(mut self, value: Arc<AtomicBool>) -> PeerPacketFilter {
+        self.block = Either::Right(value);
+        self
+    }
+
+    fn is_matched(&self, m: &RaftMessage) -> bool {
+        m.get_from_peer().get_id() == self.from_peer && m.get_to_peer().get_id() == self.to_peer
+            && self.msg_type
+                .map_or(true, |t| t == m.get_message().get_msg_type())
+    }
+}
+
+impl Filter<RaftMessage> for PeerPacketFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        msgs.retain(|m| {
+            if !self.is_matched(m) {
+                return true;
+            }
+            match self.block {
+                Either::Left(ref count) => loop {
+                    let left = count.load(Ordering::SeqCst);
+                    if left == 0 {
+                        return false;
+                    }
+                    if count.compare_and_swap(left, left - 1, Ordering::SeqCst) == left {
+                        return true;
+                    }
+                },
+                Either::Right(ref block) => !block.load(Ordering::SeqCst),
+            }
+        });
+        check_messages(msgs)
+    }
+}
+
+/// `ReorderFilter` holds back the messages sent from `from_peer` to `to_peer`
+/// until `count` of them are held, then sends them in the reverse order.
+#[derive(Clone)]
+pub struct ReorderFilter {
+    from_peer: u64,
+    to_peer: u64,
+    count: usize,
+    held_msgs: Arc<Mutex<Vec<RaftMessage>>>,
+}
+
+impl ReorderFilter {
+    pub fn new(from_peer: u64, to_peer: u64, count: usize) -> ReorderFilter {
+        ReorderFilter {
+            from_peer: from_peer,
+            to_peer: to_peer,
+            count: count,
+            held_msgs: Arc::new(Mutex::new(vec![])),
+        }
+    }
+}
+
+impl Filter<RaftMessage> for ReorderFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut held_msgs = self.held_msgs.lock().unwrap();
+        let mut to_send = vec![];
+        for m in msgs.drain(..) {
+            if m.get_from_peer().get_id() != self.from_peer
+                || m.get_to_peer().get_id() != self.to_peer
+            {
+                to_send.push(m);
+                continue;
+            }
+            held_msgs.push(m);
+            if held_msgs.len() >= self.count {
+                to_send.extend(held_msgs.drain(..).rev());
+            }
+        }
+        msgs.extend(to_send);
+        Ok(())
+    }
+}
+
+/// `DropMessageFilter` drops all the messages of the given type.
+#[derive(Clone)]
+pub struct DropMessageFilter {
+    msg_type: MessageType,
+}
+
+impl DropMessageFilter {
+    pub fn new(msg_type: MessageType) -> DropMessageFilter {
+        DropMessageFilter { msg_type: msg_type }
+    }
+}
+
+impl Filter<RaftMessage> for DropMessageFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        msgs.retain(|m| m.get_message().get_msg_type() != self.msg_type);
+        check_messages(msgs)
+    }
+}
+
+/// `DelayMessageFilter` delays the sending when there is any message of the given type.
+#[derive(Clone)]
+pub struct DelayMessageFilter {
+    msg_type: MessageType,
+    duration: time::Duration,
+}
+
+impl DelayMessageFilter {
+    pub fn new(msg_type: MessageType, duration: time::Duration) -> DelayMessageFilter {
+        DelayMessageFilter {
+            msg_type: msg_type,
+            duration: duration,
+        }
+    }
+}
+
+impl Filter<RaftMessage> for DelayMessageFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        if msgs.iter()
+            .any(|m| m.get_message().get_msg_type() == self.msg_type)
+        {
+            thread::sleep(self.duration);
+        }
+        Ok(())
+    }
+}
+
+/// `DuplicateMessageFilter` sends every message of the given type `count` more times.
+#[derive(Clone)]
+pub struct DuplicateMessageFilter {
+    msg_type: MessageType,
+    count: usize,
+}
+
+impl DuplicateMessageFilter {
+    pub fn new(msg_type: MessageType, count: usize) -> DuplicateMessageFilter {
+        DuplicateMessageFilter {
+            msg_type: msg_type,
+            count: count,
+        }
+    }
+}
+
+impl Filter<RaftMessage> for DuplicateMessageFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut to_send = Vec::with_capacity(msgs.len());
+        for m in msgs.drain(..) {
+            if m.get_message().get_msg_type() == self.msg_type {
+                for _ in 0..self.count {
+                    to_send.push(m.clone());
+                }
+            }
+            to_send.push(m);
+        }
+        *msgs = to_send;
+        Ok(())
+    }
+}
+
+/// `CorruptMessageFilter` rewrites every message of the given type with `f`.
+#[derive(Clone)]
+pub struct CorruptMessageFilter {
+    msg_type: MessageType,
+    f: Arc<Fn(&mut RaftMessage) + Send + Sync>,
+}
+
+impl CorruptMessageFilter {
+    pub fn new<F>(msg_type: MessageType, f: F) -> CorruptMessageFilter
+    where
+        F: Fn(&mut RaftMessage) + Send + Sync + 'static,
+    {
+        CorruptMessageFilter {
+            msg_type: msg_type,
+            f: Arc::new(f),
+        }
+    }
+}
+
+impl Filter<RaftMessage> for CorruptMessageFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        for m in msgs.iter_mut() {
+            if m.get_message().get_msg_type() == self.msg_type {
+                (self.f)(m);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `FilterChain` runs the filters in the order they are added, the messages
+/// left by a filter are passed to the next one. For example:
+///
+/// ```ignore
+/// let chain = FilterChain::new()
+///     .drop_msg(MessageType::MsgSnapshot)
+///     .delay_msg(MessageType::MsgAppend, Duration::from_millis(100));
+/// cluster.add_send_filter_on_node(1, box chain);
+/// ```
+#[derive(Clone, Default)]
+pub struct FilterChain {
+    filters: Vec<Arc<Filter<RaftMessage>>>,
+}
+
+impl FilterChain {
+    pub fn new() -> FilterChain {
+        FilterChain::default()
+    }
+
+    #[must_use]
+    pub fn add<F: Filter<RaftMessage> + 'static>(mut self, filter: F) -> FilterChain {
+        self.filters.push(Arc::new(filter));
+        self
+    }
+
+    #[must_use]
+    pub fn drop_msg(self, msg_type: MessageType) -> FilterChain {
+        self.add(DropMessageFilter::new(msg_type))
+    }
+
+    #[must_use]
+    pub fn delay_msg(self, msg_type: MessageType, duration: time::Duration) -> FilterChain {
+        self.add(DelayMessageFilter::new(msg_type, duration))
+    }
+
+    #[must_use]
+    pub fn duplicate_msg(self, msg_type: MessageType, count: usize) -> FilterChain {
+        self.add(DuplicateMessageFilter::new(msg_type, count))
+    }
+
+    #[must_use]
+    pub fn corrupt_msg<F>(self, msg_type: MessageType, f: F) -> FilterChain
+    where
+        F: Fn(&mut RaftMessage) + Send + Sync + 'static,
+    {
+        self.add(CorruptMessageFilter::new(msg_type, f))
+    }
+}
+
+impl Filter<RaftMessage> for FilterChain {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        for filter in &self.filters {
+            try!(filter.before(msgs));
+        }
+        Ok(())
+    }
+
+    fn after(&self, mut res: Result<()>) -> Result<()> {
+        for filter in self.filters.iter().rev() {
+            res = filter.after(res);
+        }
+        res
+    }
+}
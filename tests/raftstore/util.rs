@@ -12,10 +12,13 @@
 // limitations under the License.
 
 
-use std::sync::Arc;
+use std::env;
+use std::sync::{Arc, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use std::time::Duration;
 use std::thread;
 
+use rand::{self, Rng, SeedableRng, XorShiftRng};
 use rocksdb::DB;
 use protobuf;
 
@@ -31,6 +34,9 @@ use tikv::storage::Config as StorageConfig;
 use tikv::util::escape;
 use tikv::util::config::*;
 use tikv::config::TiKvConfig;
+use tikv::storage::CF_DEFAULT;
+
+use super::cluster::{Cluster, Simulator};
 
 pub use tikv::raftstore::store::util::find_peer;
 
@@ -78,6 +84,32 @@ pub fn must_get_cf_none(engine: &Arc<DB>, cf: &str, key: &[u8]) {
     must_get(engine, cf, key, None);
 }
 
+/// Returns the seed all the randomness of the test clusters derives from. It's
+/// read from `TIKV_TEST_SEED` or generated once per process, and printed so that
+/// a failed test can be rerun with the same seed.
+pub fn test_seed() -> u64 {
+    static INIT: Once = ONCE_INIT;
+    static SEED: AtomicUsize = ATOMIC_USIZE_INIT;
+    INIT.call_once(|| {
+        let seed = match env::var("TIKV_TEST_SEED") {
+            Ok(s) => s.parse().expect("TIKV_TEST_SEED should be an integer"),
+            Err(_) => rand::random::<usize>(),
+        };
+        println!(
+            "test seed: {}, rerun with TIKV_TEST_SEED={} to reproduce",
+            seed,
+            seed
+        );
+        SEED.store(seed, Ordering::SeqCst);
+    });
+    SEED.load(Ordering::SeqCst) as u64
+}
+
+pub fn new_test_rng() -> XorShiftRng {
+    let seed = test_seed();
+    XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32 | 1, 0x9e37_79b9, 0x7f4a_7c15])
+}
+
 pub fn new_store_cfg() -> Config {
     Config {
         sync_log: false,
@@ -95,6 +127,7 @@ pub fn new_store_cfg() -> Config {
         report_region_flow_interval: ReadableDuration::millis(100),
         raft_store_max_leader_lease: ReadableDuration::millis(MAX_LEADER_LEASE),
         allow_remove_leader: true,
+        raft_election_seed: Some(test_seed()),
         ..Config::default()
     }
 }
@@ -301,3 +334,46 @@ pub fn new_pd_transfer_leader(peer: metapb::Peer) -> Option<RegionHeartbeatRespo
     resp.set_transfer_leader(transfer_leader);
     Some(resp)
 }
+
+/// Generates `prefix` followed by `id` padded to 9 digits, so the keys are
+/// ordered by `id`.
+pub fn gen_key(prefix: &[u8], id: u64) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    key.extend_from_slice(format!("{:09}", id).as_bytes());
+    key
+}
+
+/// Keep puting random kvs until specified size limit is reached.
+pub fn put_till_size<T: Simulator>(
+    cluster: &mut Cluster<T>,
+    limit: u64,
+    range: &mut Iterator<Item = u64>,
+) -> Vec<u8> {
+    put_cf_till_size(cluster, CF_DEFAULT, limit, range)
+}
+
+pub fn put_cf_till_size<T: Simulator>(
+    cluster: &mut Cluster<T>,
+    cf: &'static str,
+    limit: u64,
+    range: &mut Iterator<Item = u64>,
+) -> Vec<u8> {
+    assert!(limit > 0);
+    let mut len = 0;
+    let mut rng = new_test_rng();
+    let mut key = vec![];
+    while len < limit {
+        let key_id = range.next().unwrap();
+        key = gen_key(b"", key_id);
+        let mut value = vec![0; 64];
+        rng.fill_bytes(&mut value);
+        cluster.must_put_cf(cf, &key, &value);
+        // plus 1 for the extra encoding prefix
+        len += key.len() as u64 + 1;
+        len += value.len() as u64;
+    }
+    // Approximate size of memtable is inaccurate for small data,
+    // we flush it to SST so we can use the size properties instead.
+    cluster.must_flush(true);
+    key
+}
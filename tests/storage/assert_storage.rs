@@ -429,9 +429,51 @@ impl AssertionStorage {
         self.store.raw_delete(self.ctx.clone(), key).unwrap()
     }
 
+    pub fn raw_delete_range_ok(&self, start_key: &[u8], end_key: &[u8]) {
+        self.store
+            .raw_delete_range(self.ctx.clone(), start_key.to_vec(), end_key.to_vec())
+            .unwrap();
+    }
+
+    pub fn raw_delete_range_err(&self, start_key: &[u8], end_key: &[u8]) {
+        assert!(
+            self.store
+                .raw_delete_range(self.ctx.clone(), start_key.to_vec(), end_key.to_vec())
+                .is_err()
+        );
+    }
+
+    pub fn raw_batch_get_ok(&self, keys: Vec<&[u8]>, expect: Vec<(&[u8], &[u8])>) {
+        let keys = keys.into_iter().map(|k| k.to_vec()).collect();
+        let result: Vec<KvPair> = self.store
+            .raw_batch_get(self.ctx.clone(), keys)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.unwrap())
+            .collect();
+        let expect: Vec<KvPair> = expect
+            .into_iter()
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect();
+        assert_eq!(result, expect);
+    }
+
+    pub fn raw_batch_put_ok(&self, pairs: Vec<(&[u8], &[u8])>) {
+        let pairs = pairs
+            .into_iter()
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect();
+        self.store.raw_batch_put(self.ctx.clone(), pairs).unwrap();
+    }
+
+    pub fn raw_batch_delete_ok(&self, keys: Vec<&[u8]>) {
+        let keys = keys.into_iter().map(|k| k.to_vec()).collect();
+        self.store.raw_batch_delete(self.ctx.clone(), keys).unwrap();
+    }
+
     pub fn raw_scan_ok(&self, start_key: Vec<u8>, limit: usize, expect: Vec<(&[u8], &[u8])>) {
         let result: Vec<KvPair> = self.store
-            .raw_scan(self.ctx.clone(), start_key, limit)
+            .raw_scan(self.ctx.clone(), start_key, limit, false)
             .unwrap()
             .into_iter()
             .map(|x| x.unwrap())
@@ -443,6 +485,17 @@ impl AssertionStorage {
         assert_eq!(result, expect);
     }
 
+    pub fn raw_scan_key_only_ok(&self, start_key: Vec<u8>, limit: usize, expect: Vec<&[u8]>) {
+        let result: Vec<KvPair> = self.store
+            .raw_scan(self.ctx.clone(), start_key, limit, true)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.unwrap())
+            .collect();
+        let expect: Vec<KvPair> = expect.into_iter().map(|k| (k.to_vec(), vec![])).collect();
+        assert_eq!(result, expect);
+    }
+
     pub fn test_txn_store_gc(&self, key: &str) {
         let key_bytes = key.as_bytes();
         self.put_ok(key_bytes, b"v1", 5, 10);
@@ -14,6 +14,8 @@
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
+use futures::Future;
+
 use tikv::storage::{Engine, Key, KvPair, Mutation, Options, Result, Storage, Value};
 use tikv::storage::config::Config;
 use kvproto::kvrpcpb::{Context, LockInfo};
@@ -62,11 +64,7 @@ impl SyncStorage {
     }
 
     pub fn get(&self, ctx: Context, key: &Key, start_ts: u64) -> Result<Option<Value>> {
-        wait_op!(|cb| {
-            self.store
-                .async_get(ctx, key.to_owned(), start_ts, cb)
-                .unwrap()
-        }).unwrap()
+        self.store.async_get(ctx, key.to_owned(), start_ts).wait()
     }
 
     #[allow(dead_code)]
@@ -76,11 +74,9 @@ impl SyncStorage {
         keys: &[Key],
         start_ts: u64,
     ) -> Result<Vec<Result<KvPair>>> {
-        wait_op!(|cb| {
-            self.store
-                .async_batch_get(ctx, keys.to_owned(), start_ts, cb)
-                .unwrap()
-        }).unwrap()
+        self.store
+            .async_batch_get(ctx, keys.to_owned(), start_ts)
+            .wait()
     }
 
     pub fn scan(
@@ -91,18 +87,9 @@ impl SyncStorage {
         key_only: bool,
         start_ts: u64,
     ) -> Result<Vec<Result<KvPair>>> {
-        wait_op!(|cb| {
-            self.store
-                .async_scan(
-                    ctx,
-                    key,
-                    limit,
-                    start_ts,
-                    Options::new(0, false, key_only),
-                    cb,
-                )
-                .unwrap()
-        }).unwrap()
+        self.store
+            .async_scan(ctx, key, limit, start_ts, Options::new(0, false, key_only))
+            .wait()
     }
 
     pub fn prewrite(
@@ -112,11 +99,9 @@ impl SyncStorage {
         primary: Vec<u8>,
         start_ts: u64,
     ) -> Result<Vec<Result<()>>> {
-        wait_op!(|cb| {
-            self.store
-                .async_prewrite(ctx, mutations, primary, start_ts, Options::default(), cb)
-                .unwrap()
-        }).unwrap()
+        self.store
+            .async_prewrite(ctx, mutations, primary, start_ts, Options::default())
+            .wait()
     }
 
     pub fn commit(
@@ -126,51 +111,66 @@ impl SyncStorage {
         start_ts: u64,
         commit_ts: u64,
     ) -> Result<()> {
-        wait_op!(|cb| {
-            self.store
-                .async_commit(ctx, keys, start_ts, commit_ts, cb)
-                .unwrap()
-        }).unwrap()
+        self.store
+            .async_commit(ctx, keys, start_ts, commit_ts)
+            .wait()
     }
 
     pub fn cleanup(&self, ctx: Context, key: Key, start_ts: u64) -> Result<()> {
-        wait_op!(|cb| {
-            self.store.async_cleanup(ctx, key, start_ts, cb).unwrap()
-        }).unwrap()
+        self.store.async_cleanup(ctx, key, start_ts).wait()
     }
 
     pub fn rollback(&self, ctx: Context, keys: Vec<Key>, start_ts: u64) -> Result<()> {
-        wait_op!(|cb| {
-            self.store.async_rollback(ctx, keys, start_ts, cb).unwrap()
-        }).unwrap()
+        self.store.async_rollback(ctx, keys, start_ts).wait()
     }
 
     pub fn scan_lock(&self, ctx: Context, max_ts: u64) -> Result<Vec<LockInfo>> {
-        wait_op!(|cb| self.store.async_scan_lock(ctx, max_ts, cb).unwrap()).unwrap()
+        self.store.async_scan_lock(ctx, max_ts).wait()
     }
 
     pub fn resolve_lock(&self, ctx: Context, start_ts: u64, commit_ts: Option<u64>) -> Result<()> {
-        wait_op!(|cb| {
-            self.store
-                .async_resolve_lock(ctx, start_ts, commit_ts, cb)
-                .unwrap()
-        }).unwrap()
+        self.store
+            .async_resolve_lock(ctx, start_ts, commit_ts)
+            .wait()
     }
 
     pub fn gc(&self, ctx: Context, safe_point: u64) -> Result<()> {
-        wait_op!(|cb| self.store.async_gc(ctx, safe_point, cb).unwrap()).unwrap()
+        self.store.async_gc(ctx, safe_point).wait()
     }
 
     pub fn raw_get(&self, ctx: Context, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
-        wait_op!(|cb| self.store.async_raw_get(ctx, key, cb).unwrap()).unwrap()
+        self.store.async_raw_get(ctx, key).wait()
     }
 
     pub fn raw_put(&self, ctx: Context, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        wait_op!(|cb| self.store.async_raw_put(ctx, key, value, cb).unwrap()).unwrap()
+        self.store.async_raw_put(ctx, key, value).wait()
     }
 
     pub fn raw_delete(&self, ctx: Context, key: Vec<u8>) -> Result<()> {
-        wait_op!(|cb| self.store.async_raw_delete(ctx, key, cb).unwrap()).unwrap()
+        self.store.async_raw_delete(ctx, key).wait()
+    }
+
+    pub fn raw_delete_range(
+        &self,
+        ctx: Context,
+        start_key: Vec<u8>,
+        end_key: Vec<u8>,
+    ) -> Result<()> {
+        self.store
+            .async_raw_delete_range(ctx, start_key, end_key)
+            .wait()
+    }
+
+    pub fn raw_batch_get(&self, ctx: Context, keys: Vec<Vec<u8>>) -> Result<Vec<Result<KvPair>>> {
+        self.store.async_raw_batch_get(ctx, keys).wait()
+    }
+
+    pub fn raw_batch_put(&self, ctx: Context, pairs: Vec<KvPair>) -> Result<()> {
+        self.store.async_raw_batch_put(ctx, pairs).wait()
+    }
+
+    pub fn raw_batch_delete(&self, ctx: Context, keys: Vec<Vec<u8>>) -> Result<()> {
+        self.store.async_raw_batch_delete(ctx, keys).wait()
     }
 
     pub fn raw_scan(
@@ -178,12 +178,11 @@ impl SyncStorage {
         ctx: Context,
         start_key: Vec<u8>,
         limit: usize,
+        key_only: bool,
     ) -> Result<Vec<Result<KvPair>>> {
-        wait_op!(|cb| {
-            self.store
-                .async_raw_scan(ctx, start_key, limit, cb)
-                .unwrap()
-        }).unwrap()
+        self.store
+            .async_raw_scan(ctx, start_key, limit, key_only)
+            .wait()
     }
 }
 
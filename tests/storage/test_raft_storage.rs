@@ -15,8 +15,10 @@ use std::thread;
 use std::sync::mpsc::channel;
 use std::time::Duration;
 
+use futures::Future;
+
 use tikv::util::HandyRwLock;
-use tikv::storage::{self, make_key, Engine, Mutation, Options, Storage};
+use tikv::storage::{self, make_key, Engine, GcManager, Mutation, Options, Storage};
 use tikv::storage::{engine, mvcc, txn};
 use tikv::storage::config::Config;
 use kvproto::kvrpcpb::Context;
@@ -88,6 +90,46 @@ fn test_raft_storage_get_after_lease() {
     );
 }
 
+#[test]
+fn test_raft_storage_gc_manager() {
+    let (cluster, storage, ctx) = new_raft_storage();
+    let key = make_key(b"key");
+    for &(start_ts, commit_ts) in &[(10, 15), (20, 25)] {
+        storage
+            .prewrite(
+                ctx.clone(),
+                vec![Mutation::Put((key.clone(), b"value".to_vec()))],
+                b"key".to_vec(),
+                start_ts,
+            )
+            .unwrap();
+        storage
+            .commit(ctx.clone(), vec![key.clone()], start_ts, commit_ts)
+            .unwrap();
+    }
+    assert!(storage.get(ctx.clone(), &key, 20).unwrap().is_some());
+
+    let mut gc_manager = GcManager::new(
+        ctx.get_peer().get_store_id(),
+        Duration::from_millis(50),
+        storage.get_storage(),
+        cluster.pd_client.clone(),
+    );
+    gc_manager.start().unwrap();
+    cluster.pd_client.set_gc_safe_point(30);
+    let mut collected = false;
+    for _ in 0..100 {
+        if storage.get(ctx.clone(), &key, 20).unwrap().is_none() {
+            collected = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    gc_manager.stop();
+    assert!(collected);
+    assert!(storage.get(ctx.clone(), &key, 30).unwrap().is_some());
+}
+
 #[test]
 fn test_raft_storage_rollback_before_prewrite() {
     let (_cluster, storage, ctx) = new_raft_storage();
@@ -208,29 +250,15 @@ fn test_scheduler_leader_change_twice() {
     ctx0.set_region_id(region0.get_id());
     ctx0.set_region_epoch(region0.get_region_epoch().clone());
     ctx0.set_peer(peers[0].clone());
-    let (prewrite_tx, prewrite_rx) = channel();
     let (stx, srx) = channel();
     engine0.block_snapshot(stx.clone());
-    storage0.async_prewrite(ctx0,
-                        vec![Mutation::Put((make_key(b"k"), b"v".to_vec()))],
-                        b"k".to_vec(),
-                        10,
-                        Options::default(),
-                        box move |res: storage::Result<_>| {
-            match res {
-                Err(storage::Error::Engine(engine::Error::Request(ref e))) => {
-                    assert!(e.has_stale_command());
-                    prewrite_tx.send(false).unwrap();
-                }
-                Ok(_) => {
-                    prewrite_tx.send(true).unwrap();
-                }
-                _ => {
-                    panic!("expect stale command, but got {:?}", res);
-                }
-            }
-        })
-        .unwrap();
+    let prewrite = storage0.async_prewrite(
+        ctx0,
+        vec![Mutation::Put((make_key(b"k"), b"v".to_vec()))],
+        b"k".to_vec(),
+        10,
+        Options::default(),
+    );
     // wait for the message, the prewrite should be blocked at snapshot stage.
     srx.recv_timeout(Duration::from_secs(2)).unwrap();
     // Transfer leader twice, then unblock snapshot.
@@ -239,7 +267,14 @@ fn test_scheduler_leader_change_twice() {
     engine0.unblock_snapshot();
 
     // the snapshot request may meet read index, scheduler will retry the request.
-    let ok = prewrite_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let ok = match prewrite.wait() {
+        Err(storage::Error::Engine(engine::Error::Request(ref e))) => {
+            assert!(e.has_stale_command());
+            false
+        }
+        Ok(_) => true,
+        Err(e) => panic!("expect stale command, but got {:?}", e),
+    };
     if ok {
         let region1 = cluster.get_region(b"");
         cluster.must_transfer_leader(region1.get_id(), peers[1].clone());
@@ -252,18 +287,10 @@ fn test_scheduler_leader_change_twice() {
         ctx1.set_region_epoch(region1.get_region_epoch().clone());
         ctx1.set_peer(peers[1].clone());
 
-        let (commit_tx, commit_rx) = channel();
-        storage1
-            .async_commit(
-                ctx1,
-                vec![make_key(b"k")],
-                10,
-                11,
-                box move |res: storage::Result<_>| { commit_tx.send(res).unwrap(); },
-            )
-            .unwrap();
         // wait for the commit result.
-        let res = commit_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let res = storage1
+            .async_commit(ctx1, vec![make_key(b"k")], 10, 11)
+            .wait();
         if res.as_ref().is_err() {
             panic!("expect Ok(_), but got {:?}", res);
         }
@@ -30,6 +30,8 @@ use super::assert_storage::AssertionStorage;
 use storage::util;
 use std::u64;
 
+use futures::Future;
+
 #[test]
 fn test_txn_store_get() {
     let store = AssertionStorage::default();
@@ -543,6 +545,36 @@ fn test_txn_store_rawkv() {
     );
     store.raw_scan_ok(b"".to_vec(), 0, vec![]);
     store.raw_scan_ok(b"k5".to_vec(), 1, vec![]);
+    store.raw_scan_key_only_ok(b"k2".to_vec(), 5, vec![b"k2", b"k3"]);
+}
+
+#[test]
+fn test_txn_store_raw_delete_range() {
+    let store = AssertionStorage::default();
+    store.raw_batch_put_ok(vec![(b"a", b"v"), (b"b", b"v"), (b"c", b"v"), (b"d", b"v")]);
+    store.raw_delete_range_ok(b"b", b"d");
+    store.raw_scan_key_only_ok(b"".to_vec(), 5, vec![b"a", b"d"]);
+    // The empty or reversed ranges are rejected.
+    store.raw_delete_range_err(b"d", b"a");
+    store.raw_delete_range_err(b"a", b"a");
+    store.raw_scan_key_only_ok(b"".to_vec(), 5, vec![b"a", b"d"]);
+    store.raw_delete_range_ok(b"", b"z");
+    store.raw_scan_key_only_ok(b"".to_vec(), 5, vec![]);
+}
+
+#[test]
+fn test_txn_store_raw_batch() {
+    let store = AssertionStorage::default();
+    store.raw_batch_put_ok(vec![(b"k1", b"v1"), (b"k2", b"v2"), (b"k3", b"v3")]);
+    store.raw_get_ok(b"k2".to_vec(), Some(b"v2".to_vec()));
+    // The missing keys are skipped.
+    store.raw_batch_get_ok(
+        vec![b"k1", b"k4", b"k3"],
+        vec![(b"k1", b"v1"), (b"k3", b"v3")],
+    );
+    store.raw_batch_delete_ok(vec![b"k1", b"k3"]);
+    store.raw_batch_get_ok(vec![b"k1", b"k2", b"k3"], vec![(b"k2", b"v2")]);
+    store.raw_batch_get_ok(vec![], vec![]);
 }
 
 #[test]
@@ -785,30 +817,18 @@ fn test_storage_1gc_with_engine(engine: Box<Engine>, ctx: Context) {
     storage.start(&config).unwrap();
     let (stx, srx) = channel();
     engine.block_snapshot(stx);
-    let (tx1, rx1) = channel();
-    storage
-        .async_gc(ctx.clone(), 1, box move |res: storage::Result<()>| {
-            assert!(res.is_ok());
-            tx1.send(1).unwrap();
-        })
-        .unwrap();
+    let gc1 = storage.async_gc(ctx.clone(), 1);
 
     // Old GC command is blocked at snapshot stage, the other one will get ServerIsBusy error.
-    let (tx2, rx2) = channel();
-    storage
-        .async_gc(Context::new(), 1, box move |res: storage::Result<()>| {
-            match res {
-                Err(storage::Error::SchedTooBusy) => {}
-                _ => panic!("expect too busy"),
-            }
-            tx2.send(1).unwrap();
-        })
-        .unwrap();
+    let gc2 = storage.async_gc(Context::new(), 1);
 
     srx.recv_timeout(Duration::from_secs(2)).unwrap();
-    rx2.recv().unwrap();
+    match gc2.wait() {
+        Err(storage::Error::SchedTooBusy(_)) => {}
+        _ => panic!("expect too busy"),
+    }
     engine.unblock_snapshot();
-    rx1.recv().unwrap();
+    gc1.wait().unwrap();
 }
 
 #[test]
@@ -835,35 +855,21 @@ fn test_conflict_commands_on_fault_engine() {
     let start_ts = 10;
     let commit_ts = 20;
 
-    let (tx, rx) = channel();
-    async_storage.async_prewrite(
+    let prewrite = async_storage.async_prewrite(
         storage.ctx.clone(),
         vec![Mutation::Put((make_key(&k), v.clone()))],
         k.clone(),
         start_ts,
         Default::default(),
-        box move |res| { tx.send(res).unwrap(); },
-    ).unwrap();
-    async_storage
-        .async_commit(
-            storage.ctx.clone(),
-            vec![make_key(&k)],
-            start_ts,
-            commit_ts,
-            box |_| {},
-        )
-        .unwrap();
-    async_storage
-        .async_cleanup(storage.ctx.clone(), make_key(&k), start_ts, box |_| {})
-        .unwrap();
-    async_storage
-        .async_rollback(
-            storage.ctx.clone(),
-            vec![make_key(&k)],
-            start_ts,
-            box |_| {},
-        )
-        .unwrap();
+    );
+    let _ = async_storage.async_commit(
+        storage.ctx.clone(),
+        vec![make_key(&k)],
+        start_ts,
+        commit_ts,
+    );
+    let _ = async_storage.async_cleanup(storage.ctx.clone(), make_key(&k), start_ts);
+    let _ = async_storage.async_rollback(storage.ctx.clone(), vec![make_key(&k)], start_ts);
 
     // Stop the engine,
     engine.stop();
@@ -871,5 +877,5 @@ fn test_conflict_commands_on_fault_engine() {
     store.start(&config);
 
     // The first command should return an error and the other will be re-scheduled.
-    rx.recv().unwrap().unwrap_err();
+    prewrite.wait().unwrap_err();
 }